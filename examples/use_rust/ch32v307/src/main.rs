@@ -19,7 +19,7 @@ use embassy_executor::Spawner;
 use embassy_time::Timer;
 use logger::set_logger;
 use rmk::config::{KeyboardUsbConfig, RmkConfig, VialConfig};
-use rmk::{k, run_rmk};
+use rmk::{k, run_rmk, Ch32Flash};
 use static_cell::StaticCell;
 use vial::{VIAL_KEYBOARD_DEF, VIAL_KEYBOARD_ID};
 bind_interrupts!(struct Irq {
@@ -94,7 +94,7 @@ async fn main(spawner: Spawner) -> ! {
         i,
         o,
         driver,
-        // rmk::EmptyFlashWrapper::new(),
+        Ch32Flash::new(),
         &mut default_keymap,
         keyboard_config,
         spawner,
@@ -1,9 +1,15 @@
 //! Initialize behavior config boilerplate of RMK
 //!
 
-use crate::config::{OneShotConfig, TapHoldConfig, TriLayerConfig};
-use crate::keyboard_config::KeyboardConfig;
-use quote::quote;
+use crate::config::{
+    AutoLockConfig, ComboConfig, DurationMillis, KeyOverrideConfig, MacroHostLayoutConfig,
+    OneShotConfig, SleepConfig, SocdConfig, SoftOffConfig, TapDanceConfig, TapHoldConfig,
+    TriLayerConfig,
+};
+use crate::keyboard_config::{BoardConfig, KeyboardConfig};
+use crate::layout::{parse_key, parse_modifiers};
+use crate::ChipSeries;
+use quote::{format_ident, quote};
 
 fn expand_tri_layer(tri_layer: &Option<TriLayerConfig>) -> proc_macro2::TokenStream {
     match tri_layer {
@@ -38,6 +44,22 @@ fn expand_one_shot(one_shot: &Option<OneShotConfig>) -> proc_macro2::TokenStream
     }
 }
 
+/// Get `TapHoldMode` of the mode name in `keyboard.toml`
+fn tap_hold_mode(name: &str) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    match name {
+        "hold_on_other_key_press" => Ok(quote! {::rmk::config::TapHoldMode::HoldOnOtherKeyPress}),
+        "permissive_hold" => Ok(quote! {::rmk::config::TapHoldMode::PermissiveHold}),
+        "tap_preferred" => Ok(quote! {::rmk::config::TapHoldMode::TapPreferred}),
+        other => {
+            let message = format!(
+                "keyboard.toml: unknown tap hold mode {}, available modes are hold_on_other_key_press, permissive_hold and tap_preferred",
+                other
+            );
+            Err(quote! {compile_error!(#message);})
+        }
+    }
+}
+
 fn expand_tap_hold(tap_hold: &Option<TapHoldConfig>) -> proc_macro2::TokenStream {
     let default = quote! {::rmk::config::TapHoldConfig::default()};
     match tap_hold {
@@ -68,12 +90,375 @@ fn expand_tap_hold(tap_hold: &Option<TapHoldConfig>) -> proc_macro2::TokenStream
                 None => quote! {},
             };
 
+            let mode = match &tap_hold.mode {
+                Some(m) => match tap_hold_mode(m) {
+                    Ok(mode) => quote! { mode: #mode, },
+                    Err(e) => return e,
+                },
+                None => quote! {},
+            };
+            let per_key = match &tap_hold.per_key {
+                Some(keys) => {
+                    let mut per_key = vec![];
+                    for k in keys {
+                        let (row, col) = (k.row, k.col);
+                        let hold_timeout = match &k.hold_timeout {
+                            Some(t) => {
+                                let timeout = t.0;
+                                quote! { Some(::embassy_time::Duration::from_millis(#timeout)) }
+                            }
+                            None => quote! { None },
+                        };
+                        let mode = match &k.mode {
+                            Some(m) => match tap_hold_mode(m) {
+                                Ok(mode) => quote! { Some(#mode) },
+                                Err(e) => return e,
+                            },
+                            None => quote! { None },
+                        };
+                        per_key.push(quote! {
+                            ::rmk::config::PerKeyTapHold {
+                                row: #row,
+                                col: #col,
+                                hold_timeout: #hold_timeout,
+                                mode: #mode,
+                            }
+                        });
+                    }
+                    // `Duration::from_millis` isn't promoted to a static, so the list is put in a const
+                    quote! {
+                        per_key: {
+                            const PER_KEY: &[::rmk::config::PerKeyTapHold] = &[#(#per_key),*];
+                            PER_KEY
+                        },
+                    }
+                }
+                None => quote! {},
+            };
+
             quote! {
                 ::rmk::config::TapHoldConfig {
                     #enable_hrm
                     #prior_idle_time
                     #post_wait_time
                     #hold_timeout
+                    #mode
+                    #per_key
+                    ..Default::default()
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+/// Convert nRF pin name like "P1_05" to `32 * port + pin`
+fn nrf_pin_number(pin: &str) -> Option<u8> {
+    let (port, pin) = pin.strip_prefix('P')?.split_once('_')?;
+    Some(port.parse::<u8>().ok()? * 32 + pin.parse::<u8>().ok()?)
+}
+
+fn expand_pin_number(pin: &Option<String>) -> proc_macro2::TokenStream {
+    match pin {
+        Some(name) => match nrf_pin_number(name) {
+            Some(num) => quote! {::core::option::Option::Some(#num)},
+            None => {
+                let message = format!(
+                    "keyboard.toml: invalid soft off pin {}, it should be like P0_10",
+                    name
+                );
+                quote! {compile_error!(#message)}
+            }
+        },
+        None => quote! {::core::option::Option::None},
+    }
+}
+
+fn expand_soft_off(soft_off: &Option<SoftOffConfig>) -> proc_macro2::TokenStream {
+    let default = quote! {::rmk::config::SoftOffConfig::default()};
+    match soft_off {
+        Some(soft_off) => {
+            let hold_time = match &soft_off.hold_time {
+                Some(t) => {
+                    let millis = t.0;
+                    quote! { hold_time: ::embassy_time::Duration::from_millis(#millis), }
+                }
+                None => quote! {},
+            };
+            let wake_high = match soft_off.wake_high {
+                Some(high) => quote! { wake_high: #high, },
+                None => quote! {},
+            };
+            let wake_pin = expand_pin_number(&soft_off.wake_pin);
+            let drive_pin = expand_pin_number(&soft_off.drive_pin);
+
+            quote! {
+                ::rmk::config::SoftOffConfig {
+                    #hold_time
+                    wake_pin: #wake_pin,
+                    drive_pin: #drive_pin,
+                    #wake_high
+                    ..Default::default()
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+fn expand_combo(combo: &Option<ComboConfig>) -> proc_macro2::TokenStream {
+    let default = quote! {::rmk::config::ComboConfig::default()};
+    match combo {
+        Some(combo) => {
+            let timeout = match &combo.timeout {
+                Some(t) => {
+                    let timeout = t.0;
+                    quote! { timeout: ::embassy_time::Duration::from_millis(#timeout), }
+                }
+                None => quote! {},
+            };
+            let combos = combo.combos.iter().map(|c| {
+                let keys = c.keys.iter().map(|[row, col]| quote! { (#row, #col) });
+                let action = parse_key(c.action.clone());
+                let layer = match c.layer {
+                    Some(l) => quote! { ::core::option::Option::Some(#l) },
+                    None => quote! { ::core::option::Option::None },
+                };
+                quote! { ::rmk::combo::Combo::new(&[#(#keys),*], #action, #layer) }
+            });
+
+            quote! {
+                ::rmk::config::ComboConfig {
+                    #timeout
+                    combos: {
+                        const COMBOS: &[::rmk::combo::Combo] = &[#(#combos),*];
+                        COMBOS
+                    },
+                    ..Default::default()
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+fn expand_tap_dance(tap_dance: &Option<TapDanceConfig>) -> proc_macro2::TokenStream {
+    let default = quote! {::rmk::config::TapDanceConfig::default()};
+    match tap_dance {
+        Some(tap_dance) => {
+            let tapping_term = match &tap_dance.tapping_term {
+                Some(t) => {
+                    let term = t.0;
+                    quote! { tapping_term: ::embassy_time::Duration::from_millis(#term), }
+                }
+                None => quote! {},
+            };
+            // Unused actions are `No`
+            let action = |a: &Option<String>| match a {
+                Some(key) => parse_key(key.clone()),
+                None => quote! { ::rmk::a!(No) },
+            };
+            let tap_dances = match &tap_dance.tap_dances {
+                Some(tap_dances) => {
+                    let tap_dances = tap_dances.iter().map(|td| {
+                        let tap = action(&td.tap);
+                        let double_tap = action(&td.double_tap);
+                        let triple_tap = action(&td.triple_tap);
+                        let hold = action(&td.hold);
+                        let hold_after_tap = action(&td.hold_after_tap);
+                        quote! {
+                            ::rmk::tap_dance::TapDance::new(#tap, #double_tap, #triple_tap, #hold, #hold_after_tap)
+                        }
+                    });
+                    quote! {
+                        tap_dances: {
+                            const TAP_DANCES: &[::rmk::tap_dance::TapDance] = &[#(#tap_dances),*];
+                            TAP_DANCES
+                        },
+                    }
+                }
+                None => quote! {},
+            };
+            let double_tap_shift = match tap_dance.double_tap_shift.as_deref() {
+                Some("none") => quote! { double_tap_shift: ::rmk::config::DoubleTapShift::None, },
+                Some("caps_word") => {
+                    quote! { double_tap_shift: ::rmk::config::DoubleTapShift::CapsWord, }
+                }
+                Some("caps_lock") => {
+                    quote! { double_tap_shift: ::rmk::config::DoubleTapShift::CapsLock, }
+                }
+                Some(other) => {
+                    let message = format!(
+                        "keyboard.toml: unknown double_tap_shift {}, available values are none, caps_word and caps_lock",
+                        other
+                    );
+                    return quote! {compile_error!(#message);};
+                }
+                None => quote! {},
+            };
+            let double_tap_layer_lock = match tap_dance.double_tap_layer_lock {
+                Some(lock) => quote! { double_tap_layer_lock: #lock, },
+                None => quote! {},
+            };
+            let tapping_toggle = match tap_dance.tapping_toggle {
+                Some(taps) => quote! { tapping_toggle: #taps, },
+                None => quote! {},
+            };
+
+            quote! {
+                ::rmk::config::TapDanceConfig {
+                    #tapping_term
+                    #tap_dances
+                    #double_tap_shift
+                    #double_tap_layer_lock
+                    #tapping_toggle
+                    ..Default::default()
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+fn expand_socd(socd: &Option<SocdConfig>) -> proc_macro2::TokenStream {
+    let default = quote! {::rmk::config::SocdConfig::default()};
+    match socd {
+        Some(socd) => {
+            let pairs = socd.pairs.iter().map(|[a, b]| {
+                let a = format_ident!("{}", a);
+                let b = format_ident!("{}", b);
+                quote! { (::rmk::keycode::KeyCode::#a, ::rmk::keycode::KeyCode::#b) }
+            });
+            let restore = match socd.restore {
+                Some(restore) => quote! { restore: #restore, },
+                None => quote! {},
+            };
+
+            quote! {
+                ::rmk::config::SocdConfig {
+                    pairs: &[#(#pairs),*],
+                    #restore
+                    ..Default::default()
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+fn expand_key_override(key_override: &Option<KeyOverrideConfig>) -> proc_macro2::TokenStream {
+    let default = quote! {::rmk::config::KeyOverrideConfig::default()};
+    match key_override {
+        Some(key_override) => {
+            let overrides = key_override.overrides.iter().map(|o| {
+                let trigger = format_ident!("{}", o.trigger);
+                let replacement = format_ident!("{}", o.replacement);
+                let trigger_mods = parse_modifiers(o.trigger_mods.as_deref().unwrap_or_default());
+                let replacement_mods =
+                    parse_modifiers(o.replacement_mods.as_deref().unwrap_or_default());
+                let layers = match &o.layers {
+                    Some(layers) => layers
+                        .iter()
+                        .filter(|&&l| l < 32)
+                        .fold(0u32, |mask, &l| mask | (1 << l)),
+                    None => u32::MAX,
+                };
+                quote! {
+                    ::rmk::key_override::KeyOverride::new(
+                        #trigger_mods,
+                        ::rmk::keycode::KeyCode::#trigger,
+                        #replacement_mods,
+                        ::rmk::keycode::KeyCode::#replacement,
+                        #layers,
+                    )
+                }
+            });
+
+            quote! {
+                ::rmk::config::KeyOverrideConfig {
+                    overrides: {
+                        const KEY_OVERRIDES: &[::rmk::key_override::KeyOverride] = &[#(#overrides),*];
+                        KEY_OVERRIDES
+                    },
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+/// Pins of the matrix which wake nRF52 chips up from deep sleep
+fn expand_deep_sleep_pins(keyboard_config: &KeyboardConfig) -> proc_macro2::TokenStream {
+    if keyboard_config.chip.series != ChipSeries::Nrf52 {
+        return quote! {};
+    }
+    let matrix = match &keyboard_config.board {
+        BoardConfig::Normal(matrix) => matrix,
+        BoardConfig::Split(split) => &split.central.matrix,
+        BoardConfig::DirectPin(_) => {
+            return quote! {compile_error!("keyboard.toml: deep sleep isn't supported by direct pin matrices");};
+        }
+    };
+    let pin_numbers = |pins: &Option<Vec<String>>| -> Result<Vec<u8>, String> {
+        pins.iter()
+            .flatten()
+            .map(|pin| {
+                nrf_pin_number(pin).ok_or(format!(
+                    "keyboard.toml: invalid matrix pin {}, it should be like P0_10",
+                    pin
+                ))
+            })
+            .collect()
+    };
+    match (
+        pin_numbers(&matrix.input_pins),
+        pin_numbers(&matrix.output_pins),
+    ) {
+        (Ok(wake_pins), Ok(drive_pins)) => quote! {
+            wake_pins: &[#(#wake_pins),*],
+            drive_pins: &[#(#drive_pins),*],
+        },
+        (Err(message), _) | (_, Err(message)) => quote! {compile_error!(#message);},
+    }
+}
+
+fn expand_sleep(
+    sleep: &Option<SleepConfig>,
+    keyboard_config: &KeyboardConfig,
+) -> proc_macro2::TokenStream {
+    let default = quote! {::rmk::config::SleepConfig::default()};
+    // A timeout of 0 disables it
+    let timeout = |name: &str, t: &Option<DurationMillis>| {
+        let name = format_ident!("{}", name);
+        match t {
+            Some(DurationMillis(0)) => quote! { #name: ::core::option::Option::None, },
+            Some(DurationMillis(millis)) => quote! {
+                #name: ::core::option::Option::Some(::embassy_time::Duration::from_millis(#millis)),
+            },
+            None => quote! {},
+        }
+    };
+    match sleep {
+        Some(sleep) => {
+            let idle_timeout = timeout("idle_timeout", &sleep.idle_timeout);
+            let host_suspend_timeout = timeout("host_suspend_timeout", &sleep.host_suspend_timeout);
+            let ble_idle_timeout = timeout("ble_idle_timeout", &sleep.ble_idle_timeout);
+            let deep_sleep_timeout = timeout("deep_sleep_timeout", &sleep.deep_sleep_timeout);
+            // Any key of the matrix wakes the keyboard up
+            let deep_sleep_pins = match sleep.deep_sleep_timeout {
+                Some(DurationMillis(millis)) if millis > 0 => {
+                    expand_deep_sleep_pins(keyboard_config)
+                }
+                _ => quote! {},
+            };
+
+            quote! {
+                ::rmk::config::SleepConfig {
+                    #idle_timeout
+                    #host_suspend_timeout
+                    #ble_idle_timeout
+                    #deep_sleep_timeout
+                    #deep_sleep_pins
                     ..Default::default()
                 }
             }
@@ -82,16 +467,131 @@ fn expand_tap_hold(tap_hold: &Option<TapHoldConfig>) -> proc_macro2::TokenStream
     }
 }
 
+fn expand_auto_lock(auto_lock: &Option<AutoLockConfig>) -> proc_macro2::TokenStream {
+    let default = quote! {::rmk::config::AutoLockConfig::default()};
+    match auto_lock {
+        Some(auto_lock) => {
+            let timeout = auto_lock.timeout.0;
+            let os = match auto_lock.os.as_deref() {
+                None | Some("windows") => quote! {Windows},
+                Some("macos") => quote! {MacOs},
+                Some("linux") => quote! {Linux},
+                Some(other) => {
+                    let message = format!(
+                        "keyboard.toml: unknown auto lock os {}, available values are windows, macos and linux",
+                        other
+                    );
+                    return quote! {compile_error!(#message);};
+                }
+            };
+
+            quote! {
+                ::rmk::config::AutoLockConfig {
+                    timeout: ::core::option::Option::Some(::embassy_time::Duration::from_millis(#timeout)),
+                    os: ::rmk::auto_lock::HostOs::#os,
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+/// Get the variant of `HostLayout` of the layout name in `keyboard.toml`
+fn host_layout_variant(name: &str) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    match name {
+        "us" => Ok(quote! {Us}),
+        "de" => Ok(quote! {German}),
+        "fr" => Ok(quote! {French}),
+        "colemak" => Ok(quote! {Colemak}),
+        other => {
+            let message = format!(
+                "keyboard.toml: unknown host layout {}, available layouts are us, de, fr and colemak",
+                other
+            );
+            Err(quote! {compile_error!(#message);})
+        }
+    }
+}
+
+fn expand_host_layout(
+    host_layout: &Option<String>,
+    macro_host_layouts: &Option<Vec<MacroHostLayoutConfig>>,
+) -> proc_macro2::TokenStream {
+    let mut expanded = quote! {};
+    if let Some(name) = host_layout {
+        let layout = match host_layout_variant(name) {
+            Ok(layout) => layout,
+            Err(e) => return e,
+        };
+        expanded.extend(quote! {
+            ::rmk::host_layout::set_host_layout(::rmk::host_layout::HostLayout::#layout);
+        });
+    }
+    for macro_layout in macro_host_layouts.iter().flatten() {
+        let layout = match host_layout_variant(&macro_layout.layout) {
+            Ok(layout) => layout,
+            Err(e) => return e,
+        };
+        let index = macro_layout.index;
+        expanded.extend(quote! {
+            ::rmk::host_layout::set_macro_layout(#index, Some(::rmk::host_layout::HostLayout::#layout));
+        });
+    }
+    expanded
+}
+
+fn expand_unicode_mode(unicode_mode: &Option<String>) -> proc_macro2::TokenStream {
+    let mode = match unicode_mode.as_deref() {
+        Some("linux") => quote! {Linux},
+        Some("macos") => quote! {MacOs},
+        Some("windows") => quote! {Windows},
+        Some("wincompose") => quote! {WinCompose},
+        Some(other) => {
+            let message = format!(
+                "keyboard.toml: unknown unicode mode {}, available modes are linux, macos, windows and wincompose",
+                other
+            );
+            return quote! {compile_error!(#message);};
+        }
+        None => return quote! {},
+    };
+    quote! {
+        ::rmk::unicode::set_unicode_mode(::rmk::unicode::UnicodeMode::#mode);
+    }
+}
+
 pub(crate) fn expand_behavior_config(keyboard_config: &KeyboardConfig) -> proc_macro2::TokenStream {
     let tri_layer = expand_tri_layer(&keyboard_config.behavior.tri_layer);
     let tap_hold = expand_tap_hold(&keyboard_config.behavior.tap_hold);
     let one_shot = expand_one_shot(&keyboard_config.behavior.one_shot);
+    let soft_off = expand_soft_off(&keyboard_config.behavior.soft_off);
+    let combo = expand_combo(&keyboard_config.behavior.combo);
+    let auto_lock = expand_auto_lock(&keyboard_config.behavior.auto_lock);
+    let tap_dance = expand_tap_dance(&keyboard_config.behavior.tap_dance);
+    let socd = expand_socd(&keyboard_config.behavior.socd);
+    let key_override = expand_key_override(&keyboard_config.behavior.key_override);
+    let sleep = expand_sleep(&keyboard_config.behavior.sleep, keyboard_config);
+    let host_layout = expand_host_layout(
+        &keyboard_config.behavior.host_layout,
+        &keyboard_config.behavior.macro_host_layouts,
+    );
+    let unicode_mode = expand_unicode_mode(&keyboard_config.behavior.unicode_mode);
 
     quote! {
         let behavior_config = ::rmk::config::BehaviorConfig {
             tri_layer: #tri_layer,
             tap_hold: #tap_hold,
             one_shot: #one_shot,
+            soft_off: #soft_off,
+            combo: #combo,
+            auto_lock: #auto_lock,
+            tap_dance: #tap_dance,
+            socd: #socd,
+            sleep: #sleep,
+            key_override: #key_override,
+            user_keys: ::rmk::config::UserKeyConfig::default(),
         };
+        #host_layout
+        #unicode_mode
     }
 }
@@ -87,7 +87,7 @@ pub(crate) fn bind_interrupt_default(keyboard_config: &KeyboardConfig) -> TokenS
                     });
                 }
             }
-            crate::ChipSeries::Rp2040 => {
+            crate::ChipSeries::Rp2040 | crate::ChipSeries::Rp2350 => {
                 quote! {
                     use ::embassy_rp::bind_interrupts;
                     bind_interrupts!(struct Irqs {
@@ -95,6 +95,23 @@ pub(crate) fn bind_interrupt_default(keyboard_config: &KeyboardConfig) -> TokenS
                     });
                 }
             }
+            crate::ChipSeries::Ch32 => {
+                if usb_info.peripheral_name == "USBD" {
+                    quote! {
+                        use ::ch32_hal::bind_interrupts;
+                        bind_interrupts!(struct Irqs {
+                            #interrupt_name => ::ch32_hal::usbd::InterruptHandler<::ch32_hal::peripherals::#peripheral_name>;
+                        });
+                    }
+                } else {
+                    quote! {
+                        use ::ch32_hal::bind_interrupts;
+                        bind_interrupts!(struct Irqs {
+                            #interrupt_name => ::ch32_hal::otg_fs::InterruptHandler<::ch32_hal::peripherals::#peripheral_name>;
+                        });
+                    }
+                }
+            }
             crate::ChipSeries::Esp32 => quote! {},
         }
     } else {
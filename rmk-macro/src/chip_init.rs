@@ -1,17 +1,21 @@
 use darling::FromMeta;
 use proc_macro2::TokenStream as TokenStream2;
-use quote::{quote, ToTokens};
+use quote::{format_ident, quote, ToTokens};
 use syn::{ItemFn, ItemMod};
 
 use crate::{keyboard::Overwritten, keyboard_config::KeyboardConfig, ChipModel, ChipSeries};
 
 // Default implementations of chip initialization
-pub(crate) fn chip_init_default(chip: &ChipModel) -> TokenStream2 {
+pub(crate) fn chip_init_default(chip: &ChipModel, hse_freq: Option<u32>) -> TokenStream2 {
     match chip.series {
-        ChipSeries::Stm32 => quote! {
-                let config = ::embassy_stm32::Config::default();
+        ChipSeries::Stm32 => {
+            let rcc_config = stm32_rcc_config(chip, hse_freq);
+            quote! {
+                let mut config = ::embassy_stm32::Config::default();
+                #rcc_config
                 let mut p = ::embassy_stm32::init(config);
-        },
+            }
+        }
         ChipSeries::Nrf52 => {
             let usb_related_config = if chip.has_usb() {
                 quote! {
@@ -36,12 +40,27 @@ pub(crate) fn chip_init_default(chip: &ChipModel) -> TokenStream2 {
                     // while ::embassy_nrf::pac::CLOCK.events_hfclkstarted().read() != 1 {}
             }
         }
-        ChipSeries::Rp2040 => {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
             quote! {
                 let config = ::embassy_rp::config::Config::default();
                 let p = ::embassy_rp::init(config);
             }
         }
+        ChipSeries::Ch32 => {
+            // USB needs a 48MHz clock, which is divided from PLL
+            let rcc = if chip.chip == "ch32v203" {
+                quote! { ::ch32_hal::rcc::Config::SYSCLK_FREQ_96MHZ_HSI }
+            } else {
+                quote! { ::ch32_hal::rcc::Config::SYSCLK_FREQ_144MHZ_HSI }
+            };
+            quote! {
+                let config = ::ch32_hal::Config {
+                    rcc: #rcc,
+                    ..Default::default()
+                };
+                let p = ::ch32_hal::init(config);
+            }
+        }
         ChipSeries::Esp32 => quote! {
             ::esp_idf_svc::sys::link_patches();
             ::esp_idf_svc::log::EspLogger::initialize_default();
@@ -70,9 +89,93 @@ pub(crate) fn expand_chip_init(
                 }
                 None
             })
-            .unwrap_or(chip_init_default(&keyboard_config.chip))
+            .unwrap_or(chip_init_default(
+                &keyboard_config.chip,
+                keyboard_config.hse_freq,
+            ))
+    } else {
+        chip_init_default(&keyboard_config.chip, keyboard_config.hse_freq)
+    }
+}
+
+/// Clock setup of STM32F4 and STM32G4, which provides the 48MHz clock for USB.
+///
+/// Other STM32 series use the default clock config of embassy-stm32.
+fn stm32_rcc_config(chip: &ChipModel, hse_freq: Option<u32>) -> TokenStream2 {
+    let chip_name = chip.chip.to_lowercase();
+    if chip_name.starts_with("stm32f4") {
+        // PLL input is 1MHz, then VCO = 336MHz, sysclk = VCO / 4 = 84MHz and USB clock = VCO / 7 = 48MHz.
+        // USB requires an accurate clock, HSI is used only if there's no HSE
+        let (pll_src, prediv, hse) = match hse_freq {
+            Some(freq) => {
+                if freq % 1_000_000 != 0 || !(4_000_000..=26_000_000).contains(&freq) {
+                    panic!("hse_freq of STM32F4 should be whole MHz between 4MHz and 26MHz");
+                }
+                (
+                    format_ident!("HSE"),
+                    format_ident!("DIV{}", freq / 1_000_000),
+                    quote! {
+                        config.rcc.hse = Some(::embassy_stm32::rcc::Hse {
+                            freq: ::embassy_stm32::time::Hertz(#freq),
+                            mode: ::embassy_stm32::rcc::HseMode::Oscillator,
+                        });
+                    },
+                )
+            }
+            None => (format_ident!("HSI"), format_ident!("DIV16"), quote! {}),
+        };
+        // Chips which have a separate 48MHz clock mux
+        let clk48_mux = if [
+            "stm32f412",
+            "stm32f413",
+            "stm32f423",
+            "stm32f446",
+            "stm32f469",
+            "stm32f479",
+        ]
+        .iter()
+        .any(|c| chip_name.starts_with(c))
+        {
+            quote! {
+                config.rcc.mux.clk48sel = ::embassy_stm32::rcc::mux::Clk48sel::PLL1_Q;
+            }
+        } else {
+            quote! {}
+        };
+        quote! {
+            #hse
+            config.rcc.pll_src = ::embassy_stm32::rcc::PllSource::#pll_src;
+            config.rcc.pll = Some(::embassy_stm32::rcc::Pll {
+                prediv: ::embassy_stm32::rcc::PllPreDiv::#prediv,
+                mul: ::embassy_stm32::rcc::PllMul::MUL336,
+                divp: Some(::embassy_stm32::rcc::PllPDiv::DIV4),
+                divq: Some(::embassy_stm32::rcc::PllQDiv::DIV7),
+                divr: None,
+            });
+            config.rcc.sys = ::embassy_stm32::rcc::Sysclk::PLL1_P;
+            config.rcc.ahb_pre = ::embassy_stm32::rcc::AHBPrescaler::DIV1;
+            config.rcc.apb1_pre = ::embassy_stm32::rcc::APBPrescaler::DIV2;
+            config.rcc.apb2_pre = ::embassy_stm32::rcc::APBPrescaler::DIV1;
+            #clk48_mux
+        }
+    } else if chip_name.starts_with("stm32g4") {
+        // Sysclk = HSI / 4 * 85 / 2 = 170MHz, USB uses HSI48 which is trimmed by USB SOF(CRS), so no crystal is needed
+        quote! {
+            config.rcc.pll = Some(::embassy_stm32::rcc::Pll {
+                source: ::embassy_stm32::rcc::PllSource::HSI,
+                prediv: ::embassy_stm32::rcc::PllPreDiv::DIV4,
+                mul: ::embassy_stm32::rcc::PllMul::MUL85,
+                divp: None,
+                divq: None,
+                divr: Some(::embassy_stm32::rcc::PllRDiv::DIV2),
+            });
+            config.rcc.sys = ::embassy_stm32::rcc::Sysclk::PLL1_R;
+            config.rcc.boost = true;
+            config.rcc.hsi48 = Some(::embassy_stm32::rcc::Hsi48Config { sync_from_usb: true });
+            config.rcc.mux.clk48sel = ::embassy_stm32::rcc::mux::Clk48sel::HSI48;
+        }
     } else {
-        chip_init_default(&keyboard_config.chip)
+        quote! {}
     }
 }
 
@@ -88,9 +191,12 @@ fn override_chip_init(chip: &ChipModel, item_fn: &ItemFn) -> TokenStream2 {
         ChipSeries::Nrf52 => initialization_tokens.extend(quote! {
             let mut p = ::embassy_nrf::init(config);
         }),
-        ChipSeries::Rp2040 => initialization_tokens.extend(quote! {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => initialization_tokens.extend(quote! {
             let mut p = ::embassy_rp::init(config);
         }),
+        ChipSeries::Ch32 => initialization_tokens.extend(quote! {
+            let p = ::ch32_hal::init(config);
+        }),
         ChipSeries::Esp32 => initialization_tokens.extend(quote! {
             let p = ::esp_idf_svc::hal::peripherals::Peripherals::take().unwrap();
         }),
@@ -80,9 +80,24 @@ pub(crate) fn usb_config_default(keyboard_config: &KeyboardConfig) -> TokenStrea
                     }
                 }
             }
-            ChipSeries::Rp2040 => quote! {
+            ChipSeries::Rp2040 | ChipSeries::Rp2350 => quote! {
                 let driver = ::embassy_rp::usb::Driver::new(p.#peripheral_name, Irqs);
             },
+            ChipSeries::Ch32 => {
+                let dp = format_ident!("{}", usb_info.dp);
+                let dm = format_ident!("{}", usb_info.dm);
+                // OTG_FS driver doesn't take the interrupt binding
+                let driver = if usb_info.peripheral_name == "USBD" {
+                    quote! { ::ch32_hal::usbd::Driver::new(p.#peripheral_name, Irqs, p.#dp, p.#dm, ep_buffer) }
+                } else {
+                    quote! { ::ch32_hal::otg_fs::Driver::new(p.#peripheral_name, p.#dp, p.#dm, ep_buffer) }
+                };
+                quote! {
+                    static EP_BUFFER: ::static_cell::StaticCell<[::ch32_hal::usb::EndpointDataBuffer; 8]> = ::static_cell::StaticCell::new();
+                    let ep_buffer = EP_BUFFER.init(::core::array::from_fn(|_| ::ch32_hal::usb::EndpointDataBuffer::default()));
+                    let driver = #driver;
+                }
+            }
             ChipSeries::Esp32 => quote! {},
         }
     } else {
@@ -26,6 +26,10 @@ pub struct KeyboardTomlConfig {
     pub split: Option<SplitConfig>,
     /// Input device config
     pub input_device: Option<InputDeviceConfig>,
+    /// Shared buses used by peripherals
+    pub bus: Option<BusConfig>,
+    /// Peripherals like displays, LEDs, pointing devices and fuel gauges
+    pub peripheral: Option<Vec<PeripheralConfig>>,
 }
 
 /// Configurations for keyboard info
@@ -47,6 +51,8 @@ pub struct KeyboardInfo {
     pub board: Option<String>,
     /// Chip model
     pub chip: Option<String>,
+    /// Frequency of the external crystal(HSE) in Hz, used by STM32F4 clock setup
+    pub hse_freq: Option<u32>,
     /// enable usb
     pub usb_enable: Option<bool>,
 }
@@ -59,6 +65,17 @@ pub enum MatrixType {
     direct_pin,
 }
 
+/// Transform of key positions reported by the matrix
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum MatrixTransformConfig {
+    #[default]
+    none,
+    mirror,
+    rotate_180,
+    swap,
+}
+
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct MatrixConfig {
     #[serde(default)]
@@ -70,6 +87,8 @@ pub struct MatrixConfig {
     pub direct_pin_low_active: bool,
     #[serde(default = "default_false")]
     pub row2col: bool,
+    #[serde(default)]
+    pub transform: MatrixTransformConfig,
 }
 
 /// Config for storage
@@ -84,6 +103,10 @@ pub struct StorageConfig {
     pub enabled: bool,
     // Clear on the storage at reboot, set this to true if you want to reset the keymap
     pub clear_storage: Option<bool>,
+    /// [row, col] of the key which boots the keyboard into safe mode if it's held at power up
+    pub safe_mode_key: Option<[u8; 2]>,
+    /// Size of the external flash in bytes, used by RP2040/RP2350 whose flash is outside the chip
+    pub flash_size: Option<usize>,
 }
 
 #[derive(Clone, Default, Debug, Deserialize)]
@@ -132,6 +155,15 @@ pub struct LayoutConfig {
     pub cols: u8,
     pub layers: u8,
     pub keymap: Vec<Vec<Vec<String>>>,
+    /// Keys whose positions in the keymap differ from their positions in the matrix
+    pub remap: Option<Vec<KeyRemapConfig>>,
+}
+
+/// A key of the matrix at `physical` which triggers the action at `logical` of the keymap, both are `[row, col]`
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeyRemapConfig {
+    pub physical: [u8; 2],
+    pub logical: [u8; 2],
 }
 
 /// Configurations for actions behavior
@@ -140,6 +172,28 @@ pub struct BehaviorConfig {
     pub tri_layer: Option<TriLayerConfig>,
     pub tap_hold: Option<TapHoldConfig>,
     pub one_shot: Option<OneShotConfig>,
+    pub soft_off: Option<SoftOffConfig>,
+    pub combo: Option<ComboConfig>,
+    pub auto_lock: Option<AutoLockConfig>,
+    pub tap_dance: Option<TapDanceConfig>,
+    pub socd: Option<SocdConfig>,
+    pub sleep: Option<SleepConfig>,
+    pub key_override: Option<KeyOverrideConfig>,
+    /// Keyboard layout of the host, "us", "de", "fr" or "colemak"
+    pub host_layout: Option<String>,
+    /// Host layout overrides of macros
+    pub macro_host_layouts: Option<Vec<MacroHostLayoutConfig>>,
+    /// Unicode input mode of the host, "linux", "macos", "windows" or "wincompose"
+    pub unicode_mode: Option<String>,
+}
+
+/// Host layout used to type the text of a macro
+#[derive(Clone, Debug, Deserialize)]
+pub struct MacroHostLayoutConfig {
+    /// Index of the macro
+    pub index: u8,
+    /// Keyboard layout, "us", "de", "fr" or "colemak"
+    pub layout: String,
 }
 
 /// Configurations for tap hold
@@ -149,6 +203,18 @@ pub struct TapHoldConfig {
     pub prior_idle_time: Option<DurationMillis>,
     pub post_wait_time: Option<DurationMillis>,
     pub hold_timeout: Option<DurationMillis>,
+    /// "hold_on_other_key_press", "permissive_hold" or "tap_preferred"
+    pub mode: Option<String>,
+    pub per_key: Option<Vec<PerKeyTapHoldConfig>>,
+}
+
+/// Tap hold settings of a single key
+#[derive(Clone, Debug, Deserialize)]
+pub struct PerKeyTapHoldConfig {
+    pub row: u8,
+    pub col: u8,
+    pub hold_timeout: Option<DurationMillis>,
+    pub mode: Option<String>,
 }
 
 /// Configurations for tri layer
@@ -165,6 +231,99 @@ pub struct OneShotConfig {
     pub timeout: Option<DurationMillis>,
 }
 
+/// Configurations for combos
+#[derive(Clone, Debug, Deserialize)]
+pub struct ComboConfig {
+    pub timeout: Option<DurationMillis>,
+    pub combos: Vec<ComboItemConfig>,
+}
+
+/// A single combo
+#[derive(Clone, Debug, Deserialize)]
+pub struct ComboItemConfig {
+    /// [row, col] of the keys
+    pub keys: Vec<[u8; 2]>,
+    /// Action of the combo, in the same format as the keymap
+    pub action: String,
+    /// The layer on which the combo works, the combo works on all layers if it's not set
+    pub layer: Option<u8>,
+}
+
+/// Configurations for tap dances
+#[derive(Clone, Debug, Deserialize)]
+pub struct TapDanceConfig {
+    pub tapping_term: Option<DurationMillis>,
+    pub tap_dances: Option<Vec<TapDanceItemConfig>>,
+    /// "none", "caps_word" or "caps_lock"
+    pub double_tap_shift: Option<String>,
+    pub double_tap_layer_lock: Option<bool>,
+    /// Number of taps of a `TT(n)` key which toggle the layer
+    pub tapping_toggle: Option<u8>,
+}
+
+/// Actions of a single tap dance, in the same format as the keymap. Only single actions like `A` or `MO(1)` are allowed
+#[derive(Clone, Debug, Deserialize)]
+pub struct TapDanceItemConfig {
+    pub tap: Option<String>,
+    pub double_tap: Option<String>,
+    pub triple_tap: Option<String>,
+    pub hold: Option<String>,
+    pub hold_after_tap: Option<String>,
+}
+
+/// Configurations for instant release of opposing keys
+#[derive(Clone, Debug, Deserialize)]
+pub struct SocdConfig {
+    /// Pairs of opposing keycodes, like `["A", "D"]`
+    pub pairs: Vec<[String; 2]>,
+    pub restore: Option<bool>,
+}
+
+/// Configurations for key overrides
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeyOverrideConfig {
+    pub overrides: Vec<KeyOverrideItemConfig>,
+}
+
+/// A key override, modifiers are in the format of `WM`, like "LShift | LCtrl"
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeyOverrideItemConfig {
+    pub trigger: String,
+    pub trigger_mods: Option<String>,
+    pub replacement: String,
+    pub replacement_mods: Option<String>,
+    /// Layers where the override is enabled, all layers if it's not set
+    pub layers: Option<Vec<u8>>,
+}
+
+/// Configurations for the keyboard sleep, "0s" disables a timeout
+#[derive(Clone, Debug, Deserialize)]
+pub struct SleepConfig {
+    pub idle_timeout: Option<DurationMillis>,
+    pub host_suspend_timeout: Option<DurationMillis>,
+    pub ble_idle_timeout: Option<DurationMillis>,
+    pub deep_sleep_timeout: Option<DurationMillis>,
+}
+
+/// Configurations for locking the host when the keyboard is idle
+#[derive(Clone, Debug, Deserialize)]
+pub struct AutoLockConfig {
+    pub timeout: DurationMillis,
+    /// "windows", "macos" or "linux"
+    pub os: Option<String>,
+}
+
+/// Configurations for the `SoftOff` action
+#[derive(Clone, Debug, Deserialize)]
+pub struct SoftOffConfig {
+    pub hold_time: Option<DurationMillis>,
+    /// Pin which wakes the keyboard up, like "P0_10"
+    pub wake_pin: Option<String>,
+    /// Pin which is driven to the wake level while the keyboard is off
+    pub drive_pin: Option<String>,
+    pub wake_high: Option<bool>,
+}
+
 /// Configurations for split keyboards
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct SplitConfig {
@@ -242,6 +401,7 @@ fn parse_duration_millis<'de, D: de::Deserializer<'de>>(deserializer: D) -> Resu
 #[allow(unused)]
 pub struct InputDeviceConfig {
     pub encoder: Option<Vec<EncoderConfig>>,
+    pub joystick: Option<Vec<JoystickConfig>>,
     pub pointing: Option<Vec<PointingDeviceConfig>>,
 }
 
@@ -261,6 +421,33 @@ pub struct EncoderConfig {
     pub counter_clockwise_pos: (u8, u8),
 }
 
+/// Analog joystick config
+#[derive(Clone, Debug, Default, Deserialize)]
+#[allow(unused)]
+pub struct JoystickConfig {
+    // ADC pin of the x axis
+    pub pin_x: String,
+    // ADC pin of the y axis
+    pub pin_y: String,
+    // "mouse", "keys" or "gamepad"
+    pub mode: String,
+    // Max motion per poll in mouse mode
+    pub speed: Option<u8>,
+    // Positions in the keyboard matrix of up, down, left and right in keys mode
+    pub up_pos: Option<(u8, u8)>,
+    pub down_pos: Option<(u8, u8)>,
+    pub left_pos: Option<(u8, u8)>,
+    pub right_pos: Option<(u8, u8)>,
+    // Deadzone and range in ADC value
+    pub deadzone: Option<u16>,
+    pub range: Option<u16>,
+    // "linear" or "quadratic"
+    pub curve: Option<String>,
+    pub swap_xy: Option<bool>,
+    pub invert_x: Option<bool>,
+    pub invert_y: Option<bool>,
+}
+
 /// Pointing device config
 #[derive(Clone, Debug, Default, Deserialize)]
 #[allow(unused)]
@@ -296,3 +483,76 @@ pub struct I2cConfig {
     pub scl: String,
     pub address: u8,
 }
+
+/// Shared buses, which are referenced by peripherals by name
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BusConfig {
+    #[serde(default)]
+    pub i2c: Vec<I2cBusConfig>,
+    #[serde(default)]
+    pub spi: Vec<SpiBusConfig>,
+}
+
+/// Shared I2C bus config
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct I2cBusConfig {
+    /// Name of the bus, used in `peripheral.bus`
+    pub name: String,
+    pub instance: String,
+    pub sda: String,
+    pub scl: String,
+    /// Interrupt of the instance, only needed if RMK cannot infer it
+    pub interrupt: Option<String>,
+}
+
+/// Shared SPI bus config
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SpiBusConfig {
+    /// Name of the bus, used in `peripheral.bus`
+    pub name: String,
+    pub instance: String,
+    pub sck: String,
+    pub mosi: String,
+    pub miso: String,
+    /// Interrupt of the instance, only needed if RMK cannot infer it
+    pub interrupt: Option<String>,
+    /// DMA channels, required by rp2040
+    pub tx_dma: Option<String>,
+    pub rx_dma: Option<String>,
+}
+
+/// Kind of a peripheral, which decides the task RMK runs for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeripheralKind {
+    Display,
+    Rgb,
+    Pointing,
+    FuelGauge,
+}
+
+/// Peripheral config
+#[derive(Clone, Debug, Deserialize)]
+pub struct PeripheralConfig {
+    pub kind: PeripheralKind,
+    /// Path of the function which creates the driver, for example `crate::oled::new`.
+    ///
+    /// It's called with `(I2cDevice, address)` for I2C peripherals and with `(SpiDevice)` for SPI peripherals.
+    pub driver: String,
+    /// Name of the bus
+    pub bus: String,
+    /// Address of I2C peripherals
+    pub address: Option<u8>,
+    /// CS pin of SPI peripherals
+    pub cs: Option<String>,
+    /// Number of LEDs, required by RGB peripherals
+    pub led_num: Option<usize>,
+    /// Refresh interval of displays
+    pub refresh_interval: Option<DurationMillis>,
+    /// Animation interval of displays
+    pub animation_interval: Option<DurationMillis>,
+    /// Interval of reading the fuel gauge
+    pub poll_interval: Option<DurationMillis>,
+    /// Core which runs the peripheral, 0 or 1. Core 1 is available only on RP2040/RP2350
+    pub core: Option<u8>,
+}
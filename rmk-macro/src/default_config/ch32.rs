@@ -0,0 +1,29 @@
+use crate::config::StorageConfig;
+
+use crate::{
+    keyboard_config::{CommunicationConfig, KeyboardConfig},
+    usb_interrupt_map::UsbInfo,
+    ChipModel,
+};
+
+// Default config for ch32v
+pub(crate) fn default_ch32(chip: ChipModel) -> KeyboardConfig {
+    // CH32V203 uses USBD, CH32V305/307 use OTG_FS, both are on PA11/PA12
+    let usb_info = if chip.chip == "ch32v203" {
+        UsbInfo::new("PA11", "PA12", "USBD", "USB_LP_CAN1_RX0")
+    } else {
+        UsbInfo::new("PA11", "PA12", "OTG_FS", "OTG_FS")
+    };
+    KeyboardConfig {
+        chip,
+        communication: CommunicationConfig::Usb(usb_info),
+        // The last two 4KB pages of the internal flash
+        storage: StorageConfig {
+            start_addr: Some(0),
+            num_sectors: Some(2),
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
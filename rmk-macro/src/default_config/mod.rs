@@ -1,6 +1,8 @@
+pub(crate) mod ch32;
 pub(crate) mod esp32;
 pub(crate) mod nrf52810;
 pub(crate) mod nrf52832;
 pub(crate) mod nrf52840;
 pub(crate) mod rp2040;
+pub(crate) mod rp2350;
 pub(crate) mod stm32;
@@ -14,6 +14,7 @@ pub(crate) fn default_rp2040(chip: ChipModel) -> KeyboardConfig {
         storage: StorageConfig {
             start_addr: Some(0),
             num_sectors: Some(16),
+            flash_size: Some(2 * 1024 * 1024),
             enabled: true,
             ..Default::default()
         },
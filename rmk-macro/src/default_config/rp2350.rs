@@ -0,0 +1,23 @@
+use crate::config::StorageConfig;
+
+use crate::{
+    keyboard_config::{CommunicationConfig, KeyboardConfig},
+    usb_interrupt_map::get_usb_info,
+    ChipModel,
+};
+
+// Default config for rp2350, the flash size is the 4MB of Raspberry Pi Pico 2
+pub(crate) fn default_rp2350(chip: ChipModel) -> KeyboardConfig {
+    KeyboardConfig {
+        chip,
+        communication: CommunicationConfig::Usb(get_usb_info("rp2350").unwrap()),
+        storage: StorageConfig {
+            start_addr: Some(0),
+            num_sectors: Some(16),
+            flash_size: Some(4 * 1024 * 1024),
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
@@ -8,15 +8,51 @@ use crate::{
 // Default config for stm32
 pub(crate) fn default_stm32(chip: ChipModel) -> KeyboardConfig {
     let chip_name = chip.chip.clone();
+    // WeAct BlackPill boards have a 25MHz crystal
+    let hse_freq = match chip.board.as_deref() {
+        Some("blackpill_f401") | Some("blackpill_f411") => Some(25_000_000),
+        _ => None,
+    };
     KeyboardConfig {
         chip,
+        hse_freq,
         communication: CommunicationConfig::Usb(get_usb_info(&chip_name).unwrap()),
-        storage: StorageConfig {
+        storage: default_stm32_storage(&chip_name),
+        ..Default::default()
+    }
+}
+
+/// Select the flash region used for storage.
+///
+/// The last sectors of STM32F4 are 128KB, chips with <= 256KB flash cannot spare two of them, so storage is disabled by default.
+/// STM32G4 uses 2KB pages, more pages are used so that the keymap fits.
+fn default_stm32_storage(chip_name: &str) -> StorageConfig {
+    let chip_name = chip_name.to_lowercase();
+    if chip_name.starts_with("stm32f4") {
+        // Flash size code is the 11th character, for example `c` in `stm32f401cc`
+        let small_flash = matches!(chip_name.chars().nth(10), Some('8' | 'b' | 'c'));
+        if small_flash {
+            eprintln!("Storage is disabled by default for {}, because its last flash sectors are too large, add a `[storage]` section with `start_addr` and `num_sectors` to enable it", chip_name);
+        }
+        StorageConfig {
             start_addr: Some(0),
             num_sectors: Some(2),
+            enabled: !small_flash,
+            ..Default::default()
+        }
+    } else if chip_name.starts_with("stm32g4") {
+        StorageConfig {
+            start_addr: Some(0),
+            num_sectors: Some(16),
             enabled: true,
             ..Default::default()
-        },
-        ..Default::default()
+        }
+    } else {
+        StorageConfig {
+            start_addr: Some(0),
+            num_sectors: Some(2),
+            enabled: true,
+            ..Default::default()
+        }
     }
 }
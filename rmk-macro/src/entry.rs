@@ -107,7 +107,7 @@ pub(crate) fn rmk_entry_direct_pin(keyboard_config: &KeyboardConfig) -> TokenStr
             },
             CommunicationConfig::None => quote! {},
         },
-        ChipSeries::Rp2040 => quote! {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => quote! {
             ::rmk::direct_pin::run_rmk_direct_pin_with_async_flash::<_, ::embassy_rp::gpio::Output, _, _, ROW, COL, SIZE, NUM_LAYER>(
                 direct_pins,
                 driver,
@@ -119,6 +119,18 @@ pub(crate) fn rmk_entry_direct_pin(keyboard_config: &KeyboardConfig) -> TokenStr
             )
             .await;
         },
+        ChipSeries::Ch32 => quote! {
+            ::rmk::direct_pin::run_rmk_direct_pin::<_, ::ch32_hal::gpio::Output, _, _, ROW, COL, SIZE, NUM_LAYER>(
+                direct_pins,
+                driver,
+                f,
+                &mut get_default_keymap(),
+                keyboard_config,
+                low_active,
+                spawner,
+            )
+            .await;
+        },
         ChipSeries::Esp32 => quote! {
             ::esp_idf_svc::hal::task::block_on(::rmk::direct_pin::run_rmk_direct_pin::<_, ::esp_idf_svc::hal::gpio::Output, ROW, COL, SIZE, NUM_LAYER>(
                 direct_pins,
@@ -184,7 +196,7 @@ pub(crate) fn rmk_entry_default(keyboard_config: &KeyboardConfig) -> TokenStream
             },
             CommunicationConfig::None => quote! {},
         },
-        ChipSeries::Rp2040 => quote! {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => quote! {
             ::rmk::run_rmk_with_async_flash(
                 input_pins,
                 output_pins,
@@ -196,6 +208,18 @@ pub(crate) fn rmk_entry_default(keyboard_config: &KeyboardConfig) -> TokenStream
             )
             .await;
         },
+        ChipSeries::Ch32 => quote! {
+            ::rmk::run_rmk(
+                input_pins,
+                output_pins,
+                driver,
+                f,
+                &mut get_default_keymap(),
+                keyboard_config,
+                spawner,
+            )
+            .await;
+        },
         ChipSeries::Esp32 => quote! {
             ::esp_idf_svc::hal::task::block_on(::rmk::run_rmk(
                 input_pins,
@@ -32,11 +32,18 @@ pub(crate) fn expand_flash_init(keyboard_config: &KeyboardConfig) -> TokenStream
                     quote! {}
                 }
             }
-            ChipSeries::Rp2040 => quote! {
-                const FLASH_SIZE: usize = 2 * 1024 * 1024;
-                let flash = ::embassy_rp::flash::Flash::<_, ::embassy_rp::flash::Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0);
-            },
+            ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
+                let flash_size = keyboard_config.storage.flash_size.unwrap_or(2 * 1024 * 1024);
+                quote! {
+                    const FLASH_SIZE: usize = #flash_size;
+                    let flash = ::embassy_rp::flash::Flash::<_, ::embassy_rp::flash::Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0);
+                }
+            }
             ChipSeries::Esp32 => quote! {}, // RMK manages ESP storage internally
+            // ch32-hal has no flash driver yet, RMK provides one
+            ChipSeries::Ch32 => quote! {
+                let f = ::rmk::Ch32Flash::new();
+            },
         }
     );
 
@@ -47,11 +54,16 @@ fn get_storage_config(storage_config: &StorageConfig) -> TokenStream2 {
     let num_sectors = storage_config.num_sectors.unwrap_or(2);
     let start_addr = storage_config.start_addr.unwrap_or(0);
     let clear_storage = storage_config.clear_storage.unwrap_or(false);
+    let safe_mode_key = match storage_config.safe_mode_key {
+        Some([row, col]) => quote! { ::core::option::Option::Some((#row, #col)) },
+        None => quote! { ::core::option::Option::None },
+    };
     quote! {
         let storage_config = ::rmk::config::StorageConfig {
             num_sectors: #num_sectors,
             start_addr: #start_addr,
-            clear_storage: #clear_storage
+            clear_storage: #clear_storage,
+            safe_mode_key: #safe_mode_key,
         };
     }
 }
@@ -107,11 +107,16 @@ pub(crate) fn convert_gpio_str_to_output_pin(
                 ::embassy_nrf::gpio::Output::new(::embassy_nrf::gpio::AnyPin::from(p.#gpio_ident), ::embassy_nrf::gpio::Level::#default_level_ident, ::embassy_nrf::gpio::OutputDrive::Standard)
             }
         }
-        ChipSeries::Rp2040 => {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
             quote! {
                 ::embassy_rp::gpio::Output::new(::embassy_rp::gpio::AnyPin::from(p.#gpio_ident), ::embassy_rp::gpio::Level::#default_level_ident)
             }
         }
+        ChipSeries::Ch32 => {
+            quote! {
+                ::ch32_hal::gpio::Output::new(p.#gpio_ident, ::ch32_hal::gpio::Level::#default_level_ident, ::ch32_hal::gpio::Speed::High)
+            }
+        }
         ChipSeries::Esp32 => {
             quote! {
                 ::esp_idf_svc::hal::gpio::PinDriver::output(p.pins.#gpio_ident.downgrade_output()).unwrap()
@@ -159,11 +164,21 @@ pub(crate) fn convert_gpio_str_to_input_pin(
                 ::embassy_nrf::gpio::Input::new(::embassy_nrf::gpio::AnyPin::from(p.#gpio_ident), ::embassy_nrf::gpio::Pull::#default_pull_ident)
             }
         }
-        ChipSeries::Rp2040 => {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
             quote! {
                 ::embassy_rp::gpio::Input::new(::embassy_rp::gpio::AnyPin::from(p.#gpio_ident), ::embassy_rp::gpio::Pull::#default_pull_ident)
             }
         }
+        ChipSeries::Ch32 => {
+            if async_matrix {
+                // ch32-hal doesn't support async gpio yet
+                quote! { compile_error!("async_matrix is not supported on CH32V"); }
+            } else {
+                quote! {
+                    ::ch32_hal::gpio::Input::new(p.#gpio_ident, ::ch32_hal::gpio::Pull::#default_pull_ident)
+                }
+            }
+        }
         ChipSeries::Esp32 => {
             quote! {
                 {
@@ -9,16 +9,20 @@ use crate::{
     ble::expand_ble_config,
     chip_init::expand_chip_init,
     comm::expand_usb_init,
+    config::MatrixTransformConfig,
     entry::expand_rmk_entry,
     feature::{get_rmk_features, is_feature_enabled},
     flash::expand_flash_init,
     import::expand_imports,
     keyboard_config::{
-        expand_keyboard_info, expand_vial_config, read_keyboard_toml_config, KeyboardConfig,
+        expand_keyboard_info, expand_vial_config, read_keyboard_toml_config, BoardConfig,
+        KeyboardConfig,
     },
-    layout::expand_layout_init,
+    layout::{expand_key_remap, expand_layout_init},
     light::expand_light_config,
-    matrix::expand_matrix_config,
+    matrix::{expand_matrix_config, expand_matrix_transform},
+    memory::{print_memory_estimate, print_memory_report},
+    peripheral::{expand_peripheral_init, join_peripheral_tasks},
     ChipSeries,
 };
 
@@ -51,6 +55,13 @@ pub(crate) fn parse_keyboard_mod(item_mod: ItemMod) -> TokenStream2 {
         Err(e) => return e,
     };
 
+    let low_ram = is_feature_enabled(&rmk_features, "low_ram");
+    if is_feature_enabled(&rmk_features, "memory_report") {
+        print_memory_report(&keyboard_config, low_ram);
+    } else if low_ram {
+        print_memory_estimate(&keyboard_config);
+    }
+
     let imports = gen_imports(&keyboard_config);
 
     // Expanded main function
@@ -74,6 +85,20 @@ pub(crate) fn gen_imports(config: &KeyboardConfig) -> TokenStream2 {
 
     let imports = match config.chip.series {
         ChipSeries::Esp32 => quote! {}, // For ESP32s, no panic handler and defmt logger are used
+        // There's no RTT defmt logger for CH32V, use an empty one
+        ChipSeries::Ch32 => quote! {
+            use panic_halt as _;
+
+            #[::defmt::global_logger]
+            struct Logger;
+
+            unsafe impl ::defmt::Logger for Logger {
+                fn acquire() {}
+                unsafe fn flush() {}
+                unsafe fn release() {}
+                unsafe fn write(_bytes: &[u8]) {}
+            }
+        },
         _ => {
             // If defmt_log is disabled, add an empty defmt logger impl
             if config.dependency.defmt_log {
@@ -122,8 +147,21 @@ fn expand_main(
     let flash_init = expand_flash_init(keyboard_config);
     let light_config = expand_light_config(keyboard_config);
     let behavior_config = expand_behavior_config(keyboard_config);
+    let key_remap = expand_key_remap(keyboard_config);
+    let matrix_transform = match &keyboard_config.board {
+        BoardConfig::Normal(matrix) | BoardConfig::DirectPin(matrix) => expand_matrix_transform(
+            matrix.transform,
+            keyboard_config.layout.rows as usize,
+            keyboard_config.layout.cols as usize,
+        ),
+        BoardConfig::Split(_) => expand_matrix_transform(MatrixTransformConfig::none, 0, 0),
+    };
     let matrix_config = expand_matrix_config(keyboard_config, async_matrix);
-    let run_rmk = expand_rmk_entry(keyboard_config, &item_mod);
+    let (peripheral_init, peripheral_tasks) = expand_peripheral_init(keyboard_config);
+    let run_rmk = join_peripheral_tasks(
+        expand_rmk_entry(keyboard_config, &item_mod),
+        peripheral_tasks,
+    );
     let (ble_config, set_ble_config) = expand_ble_config(keyboard_config);
 
     let main_function_sig = if keyboard_config.chip.series == ChipSeries::Esp32 {
@@ -132,6 +170,12 @@ fn expand_main(
             use esp_println as _;
             fn main()
         }
+    } else if keyboard_config.chip.series == ChipSeries::Ch32 {
+        // CH32V uses the entry of qingke-rt
+        quote! {
+            #[::embassy_executor::main(entry = "qingke_rt::entry")]
+            async fn main(spawner: ::embassy_executor::Spawner)
+        }
     } else {
         quote! {
             #[::embassy_executor::main]
@@ -173,10 +217,15 @@ fn expand_main(
                 light_config,
                 storage_config,
                 behavior_config,
+                key_remap: #key_remap,
+                matrix_transform: #matrix_transform,
                 #set_ble_config
                 ..Default::default()
             };
 
+            // Initialize shared buses and peripherals
+            #peripheral_init
+
             // Start serving
             #run_rmk
         }
@@ -4,13 +4,15 @@ use serde::Deserialize;
 use std::fs;
 
 use crate::config::{
-    BehaviorConfig, BleConfig, DependencyConfig, KeyboardInfo, KeyboardTomlConfig, LayoutConfig,
-    LightConfig, MatrixConfig, MatrixType, SplitConfig, StorageConfig,
+    BehaviorConfig, BleConfig, BusConfig, DependencyConfig, KeyboardInfo, KeyboardTomlConfig,
+    LayoutConfig, LightConfig, MatrixConfig, MatrixType, PeripheralConfig, PeripheralKind,
+    SplitConfig, StorageConfig,
 };
 use crate::{
     default_config::{
-        esp32::default_esp32, nrf52810::default_nrf52810, nrf52832::default_nrf52832,
-        nrf52840::default_nrf52840, rp2040::default_rp2040, stm32::default_stm32,
+        ch32::default_ch32, esp32::default_esp32, nrf52810::default_nrf52810,
+        nrf52832::default_nrf52832, nrf52840::default_nrf52840, rp2040::default_rp2040,
+        rp2350::default_rp2350, stm32::default_stm32,
     },
     usb_interrupt_map::{get_usb_info, UsbInfo},
     ChipModel, ChipSeries,
@@ -67,6 +69,8 @@ pub(crate) struct KeyboardConfig {
     pub(crate) communication: CommunicationConfig,
     // Chip model
     pub(crate) chip: ChipModel,
+    // Frequency of the external crystal, STM32 only
+    pub(crate) hse_freq: Option<u32>,
     // Board config, normal or split
     pub(crate) board: BoardConfig,
     // Layout config
@@ -79,6 +83,10 @@ pub(crate) struct KeyboardConfig {
     pub(crate) storage: StorageConfig,
     // Dependency config
     pub(crate) dependency: DependencyConfig,
+    // Shared buses
+    pub(crate) bus: BusConfig,
+    // Peripherals on the shared buses
+    pub(crate) peripherals: Vec<PeripheralConfig>,
 }
 
 #[derive(Clone, Debug)]
@@ -157,6 +165,9 @@ impl KeyboardConfig {
             &config.chip,
         )?;
 
+        // External crystal of STM32
+        config.hse_freq = toml_config.keyboard.hse_freq.or(config.hse_freq);
+
         // Update basic info
         config.basic = Self::get_basic_info(config.basic, toml_config.keyboard);
 
@@ -179,6 +190,14 @@ impl KeyboardConfig {
         // Dependency config
         config.dependency = toml_config.dependency.unwrap_or_default();
 
+        // Bus and peripheral config
+        config.bus = toml_config.bus.unwrap_or_default();
+        config.peripherals = Self::get_peripherals_from_toml(
+            &config.chip,
+            &config.bus,
+            toml_config.peripheral.unwrap_or_default(),
+        )?;
+
         Ok(config)
     }
 
@@ -201,6 +220,16 @@ impl KeyboardConfig {
                     chip: "nrf52840".to_string(),
                     board: Some(board.clone()),
                 }),
+                "blackpill_f401" => Some(ChipModel {
+                    series: ChipSeries::Stm32,
+                    chip: "stm32f401cc".to_string(),
+                    board: Some(board.clone()),
+                }),
+                "blackpill_f411" => Some(ChipModel {
+                    series: ChipSeries::Stm32,
+                    chip: "stm32f411ce".to_string(),
+                    board: Some(board.clone()),
+                }),
                 _ => None,
             }
         } else if let Some(chip) = config.keyboard.chip.clone() {
@@ -222,12 +251,24 @@ impl KeyboardConfig {
                     chip,
                     board: None,
                 })
+            } else if chip.to_lowercase().starts_with("rp2350") {
+                Some(ChipModel {
+                    series: ChipSeries::Rp2350,
+                    chip,
+                    board: None,
+                })
             } else if chip.to_lowercase().starts_with("esp32") {
                 Some(ChipModel {
                     series: ChipSeries::Esp32,
                     chip,
                     board: None,
                 })
+            } else if chip.to_lowercase().starts_with("ch32v") {
+                Some(ChipModel {
+                    series: ChipSeries::Ch32,
+                    chip,
+                    board: None,
+                })
             } else {
                 None
             }
@@ -260,8 +301,10 @@ impl KeyboardConfig {
             "nrf52832" => default_nrf52832(chip),
             "nrf52810" | "nrf52811" => default_nrf52810(chip),
             "rp2040" => default_rp2040(chip),
+            "rp2350" => default_rp2350(chip),
             s if s.starts_with("stm32") => default_stm32(chip),
             s if s.starts_with("esp32") => default_esp32(chip),
+            "ch32v203" | "ch32v305" | "ch32v307" => default_ch32(chip),
             _ => {
                 let message = format!("No default chip config for {}, please report at https://github.com/HaoboGu/rmk/issues", chip.chip);
                 return rmk_compile_error!(message);
@@ -408,6 +451,16 @@ impl KeyboardConfig {
                 "keyboard.toml: Col number in keymap doesn't match with [layout.col]".to_string()
             );
         }
+        // Remapped positions
+        if layout.remap.iter().flatten().any(|r| {
+            [r.physical, r.logical]
+                .iter()
+                .any(|[row, col]| *row >= layout.rows || *col >= layout.cols)
+        }) {
+            return rmk_compile_error!(
+                "keyboard.toml: Position in [layout.remap] is out of the keymap".to_string()
+            );
+        }
 
         Ok(layout)
     }
@@ -442,6 +495,11 @@ impl KeyboardConfig {
 
                 behavior.tap_hold = behavior.tap_hold.or(default.tap_hold);
                 behavior.one_shot = behavior.one_shot.or(default.one_shot);
+                behavior.soft_off = behavior.soft_off.or(default.soft_off);
+                behavior.host_layout = behavior.host_layout.or(default.host_layout);
+                behavior.macro_host_layouts =
+                    behavior.macro_host_layouts.or(default.macro_host_layouts);
+                behavior.unicode_mode = behavior.unicode_mode.or(default.unicode_mode);
 
                 Ok(behavior)
             }
@@ -468,11 +526,65 @@ impl KeyboardConfig {
             storage.start_addr = storage.start_addr.or(default.start_addr);
             storage.num_sectors = storage.num_sectors.or(default.num_sectors);
             storage.clear_storage = storage.clear_storage.or(default.clear_storage);
+            storage.flash_size = storage.flash_size.or(default.flash_size);
             storage
         } else {
             default
         }
     }
+
+    /// Check that every peripheral refers to a declared bus and has the fields its bus and kind need
+    fn get_peripherals_from_toml(
+        chip: &ChipModel,
+        bus: &BusConfig,
+        peripherals: Vec<PeripheralConfig>,
+    ) -> Result<Vec<PeripheralConfig>, TokenStream2> {
+        for p in peripherals.iter() {
+            let on_i2c = bus.i2c.iter().any(|b| b.name == p.bus);
+            let on_spi = bus.spi.iter().any(|b| b.name == p.bus);
+            if on_i2c && on_spi {
+                return rmk_compile_error!(format!(
+                    "Bus name \"{}\" is used by both an I2C bus and a SPI bus",
+                    p.bus
+                ));
+            }
+            if !on_i2c && !on_spi {
+                return rmk_compile_error!(format!(
+                    "Peripheral {} uses bus \"{}\", which is not defined in [bus] section",
+                    p.driver, p.bus
+                ));
+            }
+            if on_i2c && p.address.is_none() {
+                return rmk_compile_error!(format!(
+                    "Peripheral {} is on I2C bus \"{}\", \"address\" is required",
+                    p.driver, p.bus
+                ));
+            }
+            if on_spi && p.cs.is_none() {
+                return rmk_compile_error!(format!(
+                    "Peripheral {} is on SPI bus \"{}\", \"cs\" is required",
+                    p.driver, p.bus
+                ));
+            }
+            match p.core {
+                None | Some(0) => (),
+                Some(1) if matches!(chip.series, ChipSeries::Rp2040 | ChipSeries::Rp2350) => (),
+                Some(core) => {
+                    return rmk_compile_error!(format!(
+                        "Peripheral {} cannot run on core {} of {}",
+                        p.driver, core, chip.chip
+                    ));
+                }
+            }
+            if p.kind == PeripheralKind::Rgb && p.led_num.is_none() {
+                return rmk_compile_error!(format!(
+                    "RGB peripheral {} requires \"led_num\"",
+                    p.driver
+                ));
+            }
+        }
+        Ok(peripherals)
+    }
 }
 
 pub(crate) fn read_keyboard_toml_config() -> Result<KeyboardTomlConfig, TokenStream2> {
@@ -526,6 +638,9 @@ pub(crate) fn expand_vial_config() -> proc_macro2::TokenStream {
         static VIAL_CONFIG: ::rmk::config::VialConfig = ::rmk::config::VialConfig {
             vial_keyboard_id: &VIAL_KEYBOARD_ID,
             vial_keyboard_def: &VIAL_KEYBOARD_DEF,
+            auth_secret: None,
+            auth_seed: None,
+            app_layers: &[],
         };
     }
 }
@@ -19,6 +19,26 @@ pub(crate) fn expand_layout_init(keyboard_config: &KeyboardConfig) -> TokenStrea
     };
 }
 
+/// Expand `remap` of `[layout]` to the key remap of `RmkConfig`
+pub(crate) fn expand_key_remap(keyboard_config: &KeyboardConfig) -> TokenStream2 {
+    let remap = keyboard_config.layout.remap.iter().flatten().map(|r| {
+        let [physical_row, physical_col] = r.physical;
+        let [logical_row, logical_col] = r.logical;
+        quote! {
+            ::rmk::config::KeyRemap {
+                physical: (#physical_row, #physical_col),
+                logical: (#logical_row, #logical_col),
+            }
+        }
+    });
+    quote! {
+        {
+            const KEY_REMAP: &[::rmk::config::KeyRemap] = &[#(#remap),*];
+            KEY_REMAP
+        }
+    }
+}
+
 /// Push rows in the layer
 fn expand_layer(layer: Vec<Vec<String>>) -> TokenStream2 {
     let mut rows = vec![];
@@ -37,7 +57,7 @@ fn expand_row(row: Vec<String>) -> TokenStream2 {
     quote! { [#(#keys), *] }
 }
 
-struct ModifierCombinationMacro {
+pub(crate) struct ModifierCombinationMacro {
     right: bool,
     gui: bool,
     alt: bool,
@@ -74,7 +94,7 @@ impl quote::ToTokens for ModifierCombinationMacro {
 }
 
 /// Get modifier combination, in types of mod1 | mod2 | ...
-fn parse_modifiers(modifiers_str: &str) -> ModifierCombinationMacro {
+pub(crate) fn parse_modifiers(modifiers_str: &str) -> ModifierCombinationMacro {
     let mut combination = ModifierCombinationMacro::new();
     let tokens = modifiers_str.split_terminator("|");
     tokens.for_each(|w| {
@@ -106,8 +126,50 @@ fn parse_modifiers(modifiers_str: &str) -> ModifierCombinationMacro {
     combination
 }
 
+/// QMK's short names of consumer(media) and system control keys
+fn media_key_alias(key: &str) -> Option<&'static str> {
+    let name = match key {
+        "MUTE" => "AudioMute",
+        "VOLU" => "AudioVolUp",
+        "VOLD" => "AudioVolDown",
+        "MNXT" => "MediaNextTrack",
+        "MPRV" => "MediaPrevTrack",
+        "MSTP" => "MediaStop",
+        "MPLY" => "MediaPlayPause",
+        "MSEL" => "MediaSelect",
+        "EJCT" => "MediaEject",
+        "MFFD" => "MediaFastForward",
+        "MRWD" => "MediaRewind",
+        "BRIU" => "BrightnessUp",
+        "BRID" => "BrightnessDown",
+        "MAIL" => "Mail",
+        "CALC" => "Calculator",
+        "MYCM" => "MyComputer",
+        "WSCH" => "WwwSearch",
+        "WHOM" => "WwwHome",
+        "WBAK" => "WwwBack",
+        "WFWD" => "WwwForward",
+        "WSTP" => "WwwStop",
+        "WREF" => "WwwRefresh",
+        "WFAV" => "WwwFavorites",
+        "CPNL" => "ControlPanel",
+        "ASST" => "Assistant",
+        "MCTL" => "MissionControl",
+        "LPAD" => "Launchpad",
+        "PWR" => "SystemPower",
+        "SLEP" => "SystemSleep",
+        "WAKE" => "SystemWake",
+        _ => return None,
+    };
+    Some(name)
+}
+
 /// Parse the key string at a single position
-fn parse_key(key: String) -> TokenStream2 {
+pub(crate) fn parse_key(key: String) -> TokenStream2 {
+    if let Some(name) = media_key_alias(&key) {
+        let ident = format_ident!("{}", name);
+        return quote! { ::rmk::k!(#ident) };
+    }
     if key.len() < 5 {
         return if key.len() > 0 && key.trim_start_matches("_").len() == 0 {
             quote! { ::rmk::a!(No) }
@@ -116,6 +178,9 @@ fn parse_key(key: String) -> TokenStream2 {
             quote! { ::rmk::k!(#ident) }
         };
     }
+    if key == "SoftOff" {
+        return quote! { ::rmk::soft_off!() };
+    }
     match &key[0..3] {
         "WM(" => {
             if let Some(internal) = key.trim_start_matches("WM(").strip_suffix(")") {
@@ -251,6 +316,12 @@ fn parse_key(key: String) -> TokenStream2 {
                 ::rmk::df!(#layer)
             }
         }
+        "TD(" => {
+            let index = get_layer(key, "TD(", ")");
+            quote! {
+                ::rmk::td!(#index)
+            }
+        }
         "MT(" => {
             if let Some(internal) = key.trim_start_matches("MT(").strip_suffix(")") {
                 let keys: Vec<&str> = internal
@@ -304,6 +375,56 @@ fn parse_key(key: String) -> TokenStream2 {
                 };
             }
         }
+        "UC(" => match key
+            .strip_prefix("UC(")
+            .and_then(|k| k.strip_suffix(")"))
+            .and_then(parse_unicode_char)
+        {
+            Some(c) => quote! { ::rmk::uc!(#c) },
+            None => quote! {
+                compile_error!("keyboard.toml: UC(char) invalid, use a character like UC(é), or its code point like UC(0x00E9) or UC(U+00E9)");
+            },
+        },
+        "UC_" => {
+            // Unicode mode keys
+            let name = match key.as_str() {
+                "UC_NEXT" => "UnicodeModeNext",
+                "UC_PREV" => "UnicodeModePrevious",
+                "UC_LINX" => "UnicodeModeLinux",
+                "UC_MAC" => "UnicodeModeMacos",
+                "UC_WIN" => "UnicodeModeWindows",
+                "UC_WINC" => "UnicodeModeWincompose",
+                _ => {
+                    return quote! {
+                        compile_error!("keyboard.toml: Unicode mode key invalid, use UC_NEXT, UC_PREV, UC_LINX, UC_MAC, UC_WIN or UC_WINC");
+                    };
+                }
+            };
+            let ident = format_ident!("{}", name);
+            quote! {::rmk::k!(#ident) }
+        }
+        "BT_" => {
+            // BLE profile keys are aliases of `User0` ~ `User10`
+            let user = match key.as_str() {
+                "BT_NEXT" => 8,
+                "BT_PREV" => 9,
+                "BT_CLEAR" => 10,
+                _ => match key
+                    .strip_prefix("BT_SEL(")
+                    .and_then(|k| k.strip_suffix(")"))
+                    .and_then(|n| n.trim().parse::<u8>().ok())
+                {
+                    Some(profile) if profile < 8 => profile,
+                    _ => {
+                        return quote! {
+                            compile_error!("keyboard.toml: BLE profile key invalid, use BT_SEL(0) ~ BT_SEL(7), BT_NEXT, BT_PREV or BT_CLEAR");
+                        };
+                    }
+                },
+            };
+            let ident = format_ident!("User{}", user);
+            quote! {::rmk::k!(#ident) }
+        }
         _ => {
             let ident = format_ident!("{}", key);
             quote! {::rmk::k!(#ident) }
@@ -311,6 +432,17 @@ fn parse_key(key: String) -> TokenStream2 {
     }
 }
 
+/// Parse the character in `UC()`, which is the character itself or its code point like `0x00E9` and `U+00E9`
+fn parse_unicode_char(s: &str) -> Option<char> {
+    let s = s.trim();
+    let mut chars = s.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("U+"))?;
+    u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+}
+
 /// Parse the string literal like `MO(1)`, `OSL(1)`, get the layer number in it.
 /// The caller should pass the trimmed prefix and suffix
 fn get_layer(key: String, prefix: &str, suffix: &str) -> u8 {
@@ -15,6 +15,8 @@ mod keyboard_config;
 mod layout;
 mod light;
 mod matrix;
+mod memory;
+mod peripheral;
 mod split;
 #[rustfmt::skip]
 mod usb_interrupt_map;
@@ -32,7 +34,9 @@ pub(crate) enum ChipSeries {
     Nrf52,
     #[default]
     Rp2040,
+    Rp2350,
     Esp32,
+    Ch32,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -53,7 +57,9 @@ impl ChipModel {
                     false
                 }
             }
-            ChipSeries::Rp2040 => true,
+            ChipSeries::Rp2040 | ChipSeries::Rp2350 => true,
+            // CH32V203 has USBD, CH32V305/307 have OTG_FS
+            ChipSeries::Ch32 => true,
             ChipSeries::Esp32 => {
                 if self.chip == "esp32s3" || self.chip == "esp32s2" {
                     true
@@ -3,6 +3,7 @@
 use quote::quote;
 
 use crate::{
+    config::MatrixTransformConfig,
     gpio_config::{
         convert_direct_pins_to_initializers, convert_input_pins_to_initializers,
         convert_output_pins_to_initializers,
@@ -11,6 +12,29 @@ use crate::{
     ChipModel, ChipSeries,
 };
 
+/// Expand the transform of key positions of the matrix to `::rmk::matrix::MatrixTransform`.
+///
+/// The keymap has the size of the matrix, so swapping rows and columns requires `rows == cols`
+pub(crate) fn expand_matrix_transform(
+    transform: MatrixTransformConfig,
+    rows: usize,
+    cols: usize,
+) -> proc_macro2::TokenStream {
+    if transform == MatrixTransformConfig::swap && rows != cols {
+        let message = format!(
+            "keyboard.toml: `transform = \"swap\"` requires a square matrix, but it has {} rows and {} cols",
+            rows, cols
+        );
+        return quote! { compile_error!(#message) };
+    }
+    match transform {
+        MatrixTransformConfig::none => quote! { ::rmk::matrix::MatrixTransform::IDENTITY },
+        MatrixTransformConfig::mirror => quote! { ::rmk::matrix::MatrixTransform::MIRROR },
+        MatrixTransformConfig::rotate_180 => quote! { ::rmk::matrix::MatrixTransform::ROTATE_180 },
+        MatrixTransformConfig::swap => quote! { ::rmk::matrix::MatrixTransform::SWAP },
+    }
+}
+
 pub(crate) fn expand_matrix_config(
     keyboard_config: &KeyboardConfig,
     async_matrix: bool,
@@ -0,0 +1,183 @@
+//! Build-time estimate of RAM and flash usage.
+//!
+//! A short estimate is printed when the `low_ram` feature of RMK is enabled,
+//! and a per-subsystem report of static memory is printed when the `memory_report` feature is enabled.
+//! Sizes of RMK types below are measured on 32-bit targets, they're checked by `const` assertions in `rmk/src/lib.rs`.
+
+use crate::{config::PeripheralKind, keyboard_config::KeyboardConfig, ChipSeries};
+
+/// Size of a `KeyAction` in RAM, the largest variant is `TapHold(Action, Action)`
+const KEY_ACTION_SIZE: usize = 8;
+
+/// Size of `KeyEvent`
+const KEY_EVENT_SIZE: usize = 3;
+
+/// Size of `Event`, the largest variant is `Touchpad`
+const EVENT_SIZE: usize = 16;
+
+/// Size of `KeyboardReportMessage`, the largest variant is `NkroReport`
+const REPORT_MESSAGE_SIZE: usize = 32;
+
+/// Size of `FlashOperationMessage`, the largest variant is `WriteMacro`
+const FLASH_MESSAGE_SIZE: usize = 260;
+
+/// Size of `DebounceCounter` of the default debouncer
+const DEBOUNCE_COUNTER_SIZE: usize = 2;
+
+/// Size of `Rgb`
+const RGB_SIZE: usize = 3;
+
+/// Sector size of the internal flash of nRF52 chips
+const NRF_SECTOR_SIZE: usize = 4096;
+
+/// Memory of a chip, in KB
+struct ChipMemory {
+    flash: usize,
+    ram: usize,
+    /// Flash used by the softdevice
+    softdevice_flash: usize,
+}
+
+fn nrf_chip_memory(chip: &str) -> Option<ChipMemory> {
+    let (flash, ram, softdevice_flash) = match chip {
+        // S140
+        "nrf52840" => (1024, 256, 156),
+        "nrf52833" => (512, 128, 156),
+        // S132
+        "nrf52832" => (512, 64, 152),
+        // S112
+        "nrf52811" | "nrf52810" => (192, 24, 100),
+        _ => return None,
+    };
+    Some(ChipMemory {
+        flash,
+        ram,
+        softdevice_flash,
+    })
+}
+
+fn keymap_size(keyboard_config: &KeyboardConfig) -> usize {
+    let layout = &keyboard_config.layout;
+    layout.rows as usize * layout.cols as usize * layout.layers as usize * KEY_ACTION_SIZE
+}
+
+/// Print RAM and flash used by the keymap, storage and softdevice
+pub(crate) fn print_memory_estimate(keyboard_config: &KeyboardConfig) {
+    let layout = &keyboard_config.layout;
+    eprintln!(
+        "[low_ram] keymap: {} layers x {} rows x {} cols, ~{} bytes of RAM",
+        layout.layers,
+        layout.rows,
+        layout.cols,
+        keymap_size(keyboard_config)
+    );
+    print_chip_budget(keyboard_config, "low_ram");
+}
+
+/// Print static memory used by each subsystem, so that users of small chips can see what to disable
+pub(crate) fn print_memory_report(keyboard_config: &KeyboardConfig, low_ram: bool) {
+    let (event_channel_size, report_channel_size, pointer_channel_size) =
+        if low_ram { (8, 8, 4) } else { (32, 32, 8) };
+    let layout = &keyboard_config.layout;
+
+    let mut entries: Vec<(String, usize)> = vec![
+        ("keymap".to_string(), keymap_size(keyboard_config)),
+        (
+            "debouncer".to_string(),
+            layout.rows as usize * layout.cols as usize * DEBOUNCE_COUNTER_SIZE,
+        ),
+        (
+            "key event channel".to_string(),
+            event_channel_size * KEY_EVENT_SIZE,
+        ),
+        ("event channel".to_string(), event_channel_size * EVENT_SIZE),
+        (
+            "report channel".to_string(),
+            report_channel_size * REPORT_MESSAGE_SIZE,
+        ),
+        (
+            "pointer report channel".to_string(),
+            pointer_channel_size * REPORT_MESSAGE_SIZE,
+        ),
+        // 32 bytes input and output of vial
+        ("vial buffers".to_string(), 64),
+    ];
+    if keyboard_config.storage.enabled {
+        entries.push(("storage channel".to_string(), 4 * FLASH_MESSAGE_SIZE));
+    }
+    if let Some(usb_info) = keyboard_config.communication.get_usb_info() {
+        // Config, BOS, MS OS descriptors and control buffer
+        let mut usb_size = 256 + 256 + 128 + 128;
+        if keyboard_config.chip.series == ChipSeries::Stm32
+            && usb_info.peripheral_name.contains("OTG")
+        {
+            usb_size += 1024;
+        }
+        entries.push(("usb buffers".to_string(), usb_size));
+    }
+    for p in &keyboard_config.peripherals {
+        if p.kind == PeripheralKind::Rgb {
+            // Front, back and transmitted frames, and the brightness of key hits of each LED
+            let led_num = p.led_num.unwrap_or(0);
+            entries.push((
+                format!("rgb framebuffer `{}`", p.driver),
+                3 * led_num * RGB_SIZE + led_num,
+            ));
+        }
+    }
+    if keyboard_config
+        .peripherals
+        .iter()
+        .any(|p| p.core.unwrap_or(0) == 1)
+    {
+        entries.push(("core1 stack".to_string(), 8192));
+    }
+
+    eprintln!("[memory_report] static memory usage of RMK, in bytes:");
+    for (name, size) in &entries {
+        eprintln!("[memory_report]   {:<28}{:>8}", name, size);
+    }
+    eprintln!(
+        "[memory_report]   {:<28}{:>8}",
+        "total",
+        entries.iter().map(|(_, size)| size).sum::<usize>()
+    );
+    print_chip_budget(keyboard_config, "memory_report");
+}
+
+/// Print flash left for the firmware on nRF52 chips
+fn print_chip_budget(keyboard_config: &KeyboardConfig, prefix: &str) {
+    let storage = &keyboard_config.storage;
+    let storage_kb = if storage.enabled {
+        storage.num_sectors.unwrap_or(2) as usize * NRF_SECTOR_SIZE / 1024
+    } else {
+        0
+    };
+
+    if keyboard_config.chip.series != ChipSeries::Nrf52 {
+        eprintln!(
+            "[{}] {}: see the map file of the build for the exact usage",
+            prefix, keyboard_config.chip.chip
+        );
+        return;
+    }
+    let Some(memory) = nrf_chip_memory(&keyboard_config.chip.chip) else {
+        return;
+    };
+    eprintln!(
+        "[{}] {}: {}KB flash, {}KB RAM. Softdevice uses {}KB flash, storage uses {}KB flash, {}KB flash left for the firmware",
+        prefix,
+        keyboard_config.chip.chip,
+        memory.flash,
+        memory.ram,
+        memory.softdevice_flash,
+        storage_kb,
+        memory.flash.saturating_sub(memory.softdevice_flash + storage_kb),
+    );
+    if keymap_size(keyboard_config) > memory.ram * 1024 / 8 {
+        eprintln!(
+            "[{}] the keymap takes more than 1/8 of the RAM of {}, consider reducing the number of layers",
+            prefix, keyboard_config.chip.chip
+        );
+    }
+}
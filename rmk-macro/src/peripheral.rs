@@ -0,0 +1,364 @@
+//! Initialize shared buses and peripherals declared in `[bus]` and `[[peripheral]]` sections,
+//! and generate the task of each peripheral
+//!
+
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+
+use crate::{
+    config::{DurationMillis, I2cBusConfig, PeripheralConfig, PeripheralKind, SpiBusConfig},
+    gpio_config::convert_gpio_str_to_output_pin,
+    keyboard_config::KeyboardConfig,
+    split::central::join_all_tasks,
+    ChipModel, ChipSeries,
+};
+
+/// Expand the initialization of buses and peripherals.
+///
+/// Returns the initialization code and the tasks of peripherals on core0, which should be joined with the RMK task.
+/// Tasks of peripherals with `core = 1` are started on core1 in the initialization code.
+pub(crate) fn expand_peripheral_init(
+    keyboard_config: &KeyboardConfig,
+) -> (TokenStream2, Vec<TokenStream2>) {
+    let chip = &keyboard_config.chip;
+    let mut initializers = TokenStream2::new();
+    let mut tasks = vec![];
+    let mut core1_tasks = vec![];
+
+    // Only initialize buses which are used by peripherals
+    let used = |name: &String| keyboard_config.peripherals.iter().any(|p| &p.bus == name);
+    keyboard_config
+        .bus
+        .i2c
+        .iter()
+        .filter(|b| used(&b.name))
+        .for_each(|b| initializers.extend(expand_i2c_bus_init(chip, b)));
+    keyboard_config
+        .bus
+        .spi
+        .iter()
+        .filter(|b| used(&b.name))
+        .for_each(|b| initializers.extend(expand_spi_bus_init(chip, b)));
+
+    keyboard_config
+        .peripherals
+        .iter()
+        .enumerate()
+        .for_each(|(idx, p)| {
+            let on_i2c = keyboard_config.bus.i2c.iter().any(|b| b.name == p.bus);
+            let (init, task) = match expand_peripheral(chip, idx, p, on_i2c) {
+                Ok(expanded) => expanded,
+                Err(e) => {
+                    initializers.extend(e);
+                    return;
+                }
+            };
+            initializers.extend(init);
+            if p.core == Some(1) {
+                core1_tasks.push(task);
+            } else {
+                tasks.push(task);
+            }
+        });
+
+    if !core1_tasks.is_empty() {
+        initializers.extend(expand_core1_spawn(chip, core1_tasks));
+    }
+
+    (initializers, tasks)
+}
+
+/// Run peripheral tasks together with the RMK entry
+pub(crate) fn join_peripheral_tasks(
+    run_rmk: TokenStream2,
+    tasks: Vec<TokenStream2>,
+) -> TokenStream2 {
+    if tasks.is_empty() {
+        return run_rmk;
+    }
+    let mut all_tasks = vec![quote! { async { #run_rmk } }];
+    all_tasks.extend(tasks);
+    join_all_tasks(all_tasks)
+}
+
+/// Run tasks on core1.
+///
+/// Core1 only runs peripheral tasks, so they are polled in a loop without an executor.
+/// All channels and states in RMK are guarded by `CriticalSectionRawMutex`, which is safe across cores.
+fn expand_core1_spawn(chip: &ChipModel, tasks: Vec<TokenStream2>) -> TokenStream2 {
+    let run_tasks = join_all_tasks(tasks);
+    match chip.series {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => quote! {
+            static mut CORE1_STACK: ::embassy_rp::multicore::Stack<8192> = ::embassy_rp::multicore::Stack::new();
+            ::embassy_rp::multicore::spawn_core1(
+                p.CORE1,
+                unsafe { &mut *::core::ptr::addr_of_mut!(CORE1_STACK) },
+                move || {
+                    ::embassy_futures::block_on(async move { #run_tasks });
+                    loop {}
+                },
+            );
+        },
+        _ => panic!(
+            "Running peripherals on core1 isn't supported for chip {:?}",
+            chip.series
+        ),
+    }
+}
+
+fn expand_i2c_bus_init(chip: &ChipModel, bus: &I2cBusConfig) -> TokenStream2 {
+    let bus_name = format_ident!("{}", bus.name);
+    let instance = format_ident!("{}", bus.instance);
+    let sda = format_ident!("{}", bus.sda);
+    let scl = format_ident!("{}", bus.scl);
+    let irq_name = format_ident!("Irqs{}", bus.instance);
+    let static_name = format_ident!("SHARED_BUS_{}", bus.name.to_uppercase());
+    let (i2c, bus_type) = match chip.series {
+        ChipSeries::Nrf52 => {
+            let interrupt = format_ident!(
+                "{}",
+                bus.interrupt
+                    .clone()
+                    .or(nrf_serial_interrupt(&bus.instance))
+                    .expect(
+                        "Cannot infer the interrupt of the I2C instance, please set `interrupt`"
+                    )
+            );
+            (
+                quote! {
+                    ::embassy_nrf::bind_interrupts!(struct #irq_name {
+                        #interrupt => ::embassy_nrf::twim::InterruptHandler<::embassy_nrf::peripherals::#instance>;
+                    });
+                    let #bus_name = ::embassy_nrf::twim::Twim::new(
+                        p.#instance,
+                        #irq_name,
+                        p.#sda,
+                        p.#scl,
+                        ::embassy_nrf::twim::Config::default(),
+                    );
+                },
+                quote! { ::embassy_nrf::twim::Twim<'static, ::embassy_nrf::peripherals::#instance> },
+            )
+        }
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
+            let interrupt = format_ident!(
+                "{}",
+                bus.interrupt
+                    .clone()
+                    .unwrap_or(format!("{}_IRQ", bus.instance))
+            );
+            (
+                quote! {
+                    ::embassy_rp::bind_interrupts!(struct #irq_name {
+                        #interrupt => ::embassy_rp::i2c::InterruptHandler<::embassy_rp::peripherals::#instance>;
+                    });
+                    let #bus_name = ::embassy_rp::i2c::I2c::new_async(
+                        p.#instance,
+                        p.#scl,
+                        p.#sda,
+                        #irq_name,
+                        ::embassy_rp::i2c::Config::default(),
+                    );
+                },
+                quote! { ::embassy_rp::i2c::I2c<'static, ::embassy_rp::peripherals::#instance, ::embassy_rp::i2c::Async> },
+            )
+        }
+        _ => panic!(
+            "Shared I2C bus for chip {:?} isn't implemented yet",
+            chip.series
+        ),
+    };
+    // Buses are static, so that devices can be moved to core1
+    quote! {
+        #i2c
+        static #static_name: ::static_cell::StaticCell<::rmk::bus::SharedI2cBus<::rmk::CriticalSectionRawMutex, #bus_type>> = ::static_cell::StaticCell::new();
+        let #bus_name = &*#static_name.init(::rmk::bus::SharedI2cBus::new(#bus_name));
+    }
+}
+
+fn expand_spi_bus_init(chip: &ChipModel, bus: &SpiBusConfig) -> TokenStream2 {
+    let bus_name = format_ident!("{}", bus.name);
+    let instance = format_ident!("{}", bus.instance);
+    let sck = format_ident!("{}", bus.sck);
+    let mosi = format_ident!("{}", bus.mosi);
+    let miso = format_ident!("{}", bus.miso);
+    let irq_name = format_ident!("Irqs{}", bus.instance);
+    let static_name = format_ident!("SHARED_BUS_{}", bus.name.to_uppercase());
+    let (spi, bus_type) = match chip.series {
+        ChipSeries::Nrf52 => {
+            let interrupt = format_ident!(
+                "{}",
+                bus.interrupt
+                    .clone()
+                    .or(nrf_serial_interrupt(&bus.instance))
+                    .expect(
+                        "Cannot infer the interrupt of the SPI instance, please set `interrupt`"
+                    )
+            );
+            (
+                quote! {
+                    ::embassy_nrf::bind_interrupts!(struct #irq_name {
+                        #interrupt => ::embassy_nrf::spim::InterruptHandler<::embassy_nrf::peripherals::#instance>;
+                    });
+                    let #bus_name = ::embassy_nrf::spim::Spim::new(
+                        p.#instance,
+                        #irq_name,
+                        p.#sck,
+                        p.#miso,
+                        p.#mosi,
+                        ::embassy_nrf::spim::Config::default(),
+                    );
+                },
+                quote! { ::embassy_nrf::spim::Spim<'static, ::embassy_nrf::peripherals::#instance> },
+            )
+        }
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
+            let tx_dma = format_ident!(
+                "{}",
+                bus.tx_dma
+                    .clone()
+                    .expect("`tx_dma` is required for SPI bus on rp2040")
+            );
+            let rx_dma = format_ident!(
+                "{}",
+                bus.rx_dma
+                    .clone()
+                    .expect("`rx_dma` is required for SPI bus on rp2040")
+            );
+            (
+                quote! {
+                    let #bus_name = ::embassy_rp::spi::Spi::new(
+                        p.#instance,
+                        p.#sck,
+                        p.#mosi,
+                        p.#miso,
+                        p.#tx_dma,
+                        p.#rx_dma,
+                        ::embassy_rp::spi::Config::default(),
+                    );
+                },
+                quote! { ::embassy_rp::spi::Spi<'static, ::embassy_rp::peripherals::#instance, ::embassy_rp::spi::Async> },
+            )
+        }
+        _ => panic!(
+            "Shared SPI bus for chip {:?} isn't implemented yet",
+            chip.series
+        ),
+    };
+    quote! {
+        #spi
+        static #static_name: ::static_cell::StaticCell<::rmk::bus::SharedSpiBus<::rmk::CriticalSectionRawMutex, #bus_type>> = ::static_cell::StaticCell::new();
+        let #bus_name = &*#static_name.init(::rmk::bus::SharedSpiBus::new(#bus_name));
+    }
+}
+
+/// Create the peripheral by its driver function, and generate its task.
+/// An invalid driver function is returned as a compile error.
+fn expand_peripheral(
+    chip: &ChipModel,
+    idx: usize,
+    peripheral: &PeripheralConfig,
+    on_i2c: bool,
+) -> Result<(TokenStream2, TokenStream2), TokenStream2> {
+    let name = format_ident!("peripheral{}", idx);
+    let bus_name = format_ident!("{}", peripheral.bus);
+    let driver: syn::Path = syn::parse_str(&peripheral.driver).map_err(|e| {
+        syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!(
+                "Invalid driver function \"{}\" of peripheral: {}",
+                peripheral.driver, e
+            ),
+        )
+        .to_compile_error()
+    })?;
+
+    // Required address and CS pin are checked when reading the config
+    let device = if on_i2c {
+        let address = peripheral.address.unwrap_or_default();
+        quote! { #driver(#bus_name.device(), #address) }
+    } else {
+        let cs = peripheral.cs.clone().unwrap_or_default();
+        let cs_pin = convert_gpio_str_to_output_pin(chip, cs, true);
+        quote! { #driver(#bus_name.device(#cs_pin)) }
+    };
+
+    let task = match peripheral.kind {
+        PeripheralKind::Display => {
+            let refresh_interval = duration_or_default(
+                &peripheral.refresh_interval,
+                quote! { ::rmk::config::DisplayConfig::default().refresh_interval },
+            );
+            let animation_interval = duration_or_default(
+                &peripheral.animation_interval,
+                quote! { ::rmk::config::DisplayConfig::default().animation_interval },
+            );
+            quote! {
+                ::rmk::display::run_display(
+                    #name,
+                    ::rmk::config::DisplayConfig {
+                        refresh_interval: #refresh_interval,
+                        animation_interval: #animation_interval,
+                    },
+                )
+            }
+        }
+        PeripheralKind::Rgb => {
+            let led_num = peripheral.led_num.unwrap_or_default();
+            quote! {
+                ::rmk::rgb::run_rgb_lighting::<_, #led_num>(
+                    #name,
+                    ::rmk::config::RGBLightConfig {
+                        rgb_led_num: #led_num as u32,
+                        ..Default::default()
+                    },
+                    &[],
+                )
+            }
+        }
+        PeripheralKind::Pointing => quote! {
+            ::rmk::input_device::InputDevice::run(&mut #name)
+        },
+        PeripheralKind::FuelGauge => {
+            let poll_interval = duration_or_default(
+                &peripheral.poll_interval,
+                quote! { ::embassy_time::Duration::from_secs(60) },
+            );
+            quote! {
+                ::rmk::power::run_fuel_gauge(#name, #poll_interval)
+            }
+        }
+    };
+
+    // Pointing devices are run by `&mut self`
+    let init = if peripheral.kind == PeripheralKind::Pointing {
+        quote! { let mut #name = #device; }
+    } else {
+        quote! { let #name = #device; }
+    };
+
+    Ok((init, task))
+}
+
+fn duration_or_default(duration: &Option<DurationMillis>, default: TokenStream2) -> TokenStream2 {
+    match duration {
+        Some(d) => {
+            let millis = d.0;
+            quote! { ::embassy_time::Duration::from_millis(#millis) }
+        }
+        None => default,
+    }
+}
+
+/// Get the interrupt of a nRF52 serial instance(TWIM/SPIM)
+fn nrf_serial_interrupt(instance: &str) -> Option<String> {
+    match instance {
+        "TWISPI0" => Some("SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0".to_string()),
+        "TWISPI1" => Some("SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1".to_string()),
+        "SPI2" => Some("SPIM2_SPIS2_SPI2".to_string()),
+        "SPI3" => Some("SPIM3".to_string()),
+        "TWI0" => Some("TWIM0_TWIS0_TWI0".to_string()),
+        "SPI0" => Some("SPIM0_SPIS0_SPI0".to_string()),
+        _ => None,
+    }
+}
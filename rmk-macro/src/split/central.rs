@@ -16,8 +16,11 @@ use crate::{
     import::expand_imports,
     keyboard::gen_imports,
     keyboard_config::{read_keyboard_toml_config, BoardConfig, KeyboardConfig},
+    layout::expand_key_remap,
     light::expand_light_config,
-    matrix::{expand_matrix_direct_pins, expand_matrix_input_output_pins},
+    matrix::{expand_matrix_direct_pins, expand_matrix_input_output_pins, expand_matrix_transform},
+    memory::{print_memory_estimate, print_memory_report},
+    peripheral::{expand_peripheral_init, join_peripheral_tasks},
     ChipModel, ChipSeries,
 };
 
@@ -45,6 +48,13 @@ pub(crate) fn parse_split_central_mod(
         Err(e) => return e,
     };
 
+    let low_ram = is_feature_enabled(&rmk_features, "low_ram");
+    if is_feature_enabled(&rmk_features, "memory_report") {
+        print_memory_report(&keyboard_config, low_ram);
+    } else if low_ram {
+        print_memory_estimate(&keyboard_config);
+    }
+
     let imports = gen_imports(&keyboard_config);
 
     let main_function = expand_split_central(&keyboard_config, item_mod, async_matrix);
@@ -76,6 +86,12 @@ fn expand_split_central(
     let flash_init = expand_flash_init(keyboard_config);
     let light_config = expand_light_config(keyboard_config);
     let behavior_config = expand_behavior_config(keyboard_config);
+    let key_remap = expand_key_remap(keyboard_config);
+    let matrix_transform = expand_matrix_transform(
+        split_config.central.matrix.transform,
+        split_config.central.rows,
+        split_config.central.cols,
+    );
 
     let mut matrix_config = proc_macro2::TokenStream::new();
     match &split_config.central.matrix.matrix_type {
@@ -128,7 +144,11 @@ fn expand_split_central(
 
     let split_communication_config =
         expand_split_communication_config(&keyboard_config.chip, split_config);
-    let run_rmk = expand_split_central_entry(keyboard_config, split_config);
+    let (peripheral_init, peripheral_tasks) = expand_peripheral_init(keyboard_config);
+    let run_rmk = join_peripheral_tasks(
+        expand_split_central_entry(keyboard_config, split_config),
+        peripheral_tasks,
+    );
     let (ble_config, set_ble_config) = expand_ble_config(keyboard_config);
 
     let main_function_sig = if keyboard_config.chip.series == ChipSeries::Esp32 {
@@ -179,12 +199,17 @@ fn expand_split_central(
                 light_config,
                 storage_config,
                 behavior_config,
+                key_remap: #key_remap,
+                matrix_transform: #matrix_transform,
                 #set_ble_config
                 ..Default::default()
             };
 
             #split_communication_config
 
+            // Initialize shared buses and peripherals
+            #peripheral_init
+
             // Start serving
             #run_rmk
         }
@@ -320,7 +345,7 @@ fn expand_split_central_entry(
             });
             join_all_tasks(tasks)
         }
-        ChipSeries::Rp2040 => {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
             let low_active = split_config.central.matrix.direct_pin_low_active;
 
             let central_task = match split_config.central.matrix.matrix_type {
@@ -383,6 +408,9 @@ fn expand_split_central_entry(
 
             join_all_tasks(tasks)
         }
+        ChipSeries::Ch32 => quote! {
+            compile_error!("Split keyboard isn't supported on CH32V yet");
+        },
         ChipSeries::Esp32 => panic!("Split for esp32 isn't implemented yet"),
     }
 }
@@ -438,7 +466,7 @@ pub(crate) fn expand_serial_init(chip: &ChipModel, serial: Vec<SerialConfig>) ->
             let #rx_buf_name = &mut #rx_buf_static.init([0_u8; ::rmk::split::SPLIT_MESSAGE_MAX_SIZE])[..];
         };
         let uart_init = match chip.series {
-            ChipSeries::Rp2040 => {
+            ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
                 let uart_instance = format_ident!("{}", s.instance);
                 let uart_name = format_ident!("{}", s.instance.to_lowercase());
                 let uart_irq = format_ident!("{}_IRQ", s.instance);
@@ -470,7 +498,7 @@ pub(crate) fn expand_serial_init(chip: &ChipModel, serial: Vec<SerialConfig>) ->
     uart_initializers
 }
 
-fn join_all_tasks(tasks: Vec<TokenStream2>) -> TokenStream2 {
+pub(crate) fn join_all_tasks(tasks: Vec<TokenStream2>) -> TokenStream2 {
     let mut current_joined = quote! {};
     tasks.iter().enumerate().for_each(|(id, task)| {
         if id == 0 {
@@ -4,11 +4,11 @@ use syn::ItemMod;
 
 use crate::{
     chip_init::expand_chip_init,
-    config::{MatrixType, SplitBoardConfig},
+    config::{MatrixTransformConfig, MatrixType, SplitBoardConfig},
     feature::{get_rmk_features, is_feature_enabled},
     import::expand_imports,
     keyboard_config::{read_keyboard_toml_config, BoardConfig, KeyboardConfig},
-    matrix::{expand_matrix_direct_pins, expand_matrix_input_output_pins},
+    matrix::{expand_matrix_direct_pins, expand_matrix_input_output_pins, expand_matrix_transform},
     split::central::expand_serial_init,
     ChipModel, ChipSeries,
 };
@@ -146,11 +146,57 @@ fn expand_split_peripheral(
         #run_rmk_peripheral
     }
 }
+/// Create the matrix of the peripheral as `matrix` if it's transformed, peripherals don't have `RmkConfig`,
+/// so the matrix is run by `run_rmk_split_peripheral_with_matrix`
+fn expand_transformed_matrix(peripheral_config: &SplitBoardConfig) -> Option<TokenStream2> {
+    let matrix = &peripheral_config.matrix;
+    if matrix.transform == MatrixTransformConfig::none {
+        return None;
+    }
+    let transform = expand_matrix_transform(
+        matrix.transform,
+        peripheral_config.rows,
+        peripheral_config.cols,
+    );
+    let debounce_config = quote! { ::rmk::config::DebounceConfig::default() };
+    Some(match matrix.matrix_type {
+        MatrixType::normal => {
+            let input_num = matrix.input_pins.as_ref().map_or(0, |pins| pins.len());
+            let output_num = matrix.output_pins.as_ref().map_or(0, |pins| pins.len());
+            quote! {
+                let debouncer = <::rmk::debounce::MatrixDebouncer<#input_num, #output_num>
+                    as ::rmk::debounce::Debouncer>::new(#debounce_config);
+                let matrix = ::rmk::matrix::Matrix::<_, _, _, #input_num, #output_num>::new(
+                    input_pins,
+                    output_pins,
+                    debouncer,
+                ).with_transform(#transform);
+            }
+        }
+        MatrixType::direct_pin => {
+            let row = peripheral_config.rows;
+            let col = peripheral_config.cols;
+            let size = row * col;
+            let low_active = matrix.direct_pin_low_active;
+            quote! {
+                let debouncer = <::rmk::debounce::MatrixDebouncer<#col, #row>
+                    as ::rmk::debounce::Debouncer>::new(#debounce_config);
+                let matrix = ::rmk::direct_pin::DirectPinMatrix::<_, _, #row, #col, #size>::new(
+                    direct_pins,
+                    debouncer,
+                    #low_active,
+                ).with_transform(#transform);
+            }
+        }
+    })
+}
+
 fn expand_split_peripheral_entry(
     chip: &ChipModel,
     peripheral_config: &SplitBoardConfig,
     central_config: &SplitBoardConfig,
 ) -> TokenStream2 {
+    let transformed_matrix = expand_transformed_matrix(peripheral_config);
     match chip.series {
         ChipSeries::Stm32 => todo!(),
         ChipSeries::Nrf52 => {
@@ -163,6 +209,17 @@ fn expand_split_peripheral_entry(
                 "Peripheral should have a ble address, please check the `ble_addr` field in `keyboard.toml`",
             );
             let low_active = peripheral_config.matrix.direct_pin_low_active;
+            if let Some(matrix) = transformed_matrix {
+                return quote! {
+                    #matrix
+                    ::rmk::split::peripheral::run_rmk_split_peripheral_with_matrix::<_, #row, #col>(
+                        matrix,
+                        [#(#central_addr), *],
+                        [#(#peripheral_addr), *],
+                        spawner,
+                    ).await
+                };
+            }
             match peripheral_config.matrix.matrix_type {
                 MatrixType::direct_pin => {
                     let size = row * col;
@@ -200,7 +257,7 @@ fn expand_split_peripheral_entry(
                 }
             }
         }
-        ChipSeries::Rp2040 => {
+        ChipSeries::Rp2040 | ChipSeries::Rp2350 => {
             let peripheral_serial = peripheral_config
                 .serial
                 .clone()
@@ -212,6 +269,16 @@ fn expand_split_peripheral_entry(
 
             let row = peripheral_config.rows as usize;
             let col = peripheral_config.cols as usize;
+            if let Some(matrix) = transformed_matrix {
+                return quote! {
+                    #serial_init
+                    #matrix
+                    ::rmk::split::peripheral::run_rmk_split_peripheral_with_matrix::<_, _, #row, #col>(
+                        matrix,
+                        uart0,
+                    ).await;
+                };
+            }
             let peripheral_run = match peripheral_config.matrix.matrix_type {
                 MatrixType::normal => quote! {
                     ::rmk::split::peripheral::run_rmk_split_peripheral::<
@@ -237,6 +304,9 @@ fn expand_split_peripheral_entry(
                 #peripheral_run
             }
         }
+        ChipSeries::Ch32 => quote! {
+            compile_error!("Split keyboard isn't supported on CH32V yet");
+        },
         ChipSeries::Esp32 => todo!(),
     }
 }
@@ -13,6 +13,7 @@ static USB_INFO: Lazy<HashMap<String, UsbInfo>> = Lazy::new(|| {
     m.insert("nrf52820".to_string(), UsbInfo::new("", "", "USBD", "USBD"));
     m.insert("nrf52833".to_string(), UsbInfo::new("", "", "USBD", "USBD"));
     m.insert("rp2040".to_string(), UsbInfo::new("", "", "USB", "USBCTRL_IRQ"));
+    m.insert("rp2350".to_string(), UsbInfo::new("", "", "USB", "USBCTRL_IRQ"));
     m.insert("stm32h730vb".to_string(), UsbInfo::new("PA11", "PA12", "USB_OTG_HS", "OTG_HS"));
     m.insert("stm32g473qc".to_string(), UsbInfo::new("PA11", "PA12", "USB", "USB_LP"));
     m.insert("stm32g0c1ve".to_string(), UsbInfo::new("PA11", "PA12", "USB", "USB_UCPD1_2"));
@@ -1045,7 +1046,7 @@ impl UsbInfo {
         match chip.series {
             ChipSeries::Stm32 => UsbInfo::new("PA11", "PA12", "USB_OTG_FS", "USB_FS"),
             ChipSeries::Nrf52 => UsbInfo::new("", "", "USBD", "USBD"),
-            ChipSeries::Rp2040 => UsbInfo::new("", "", "USB", "USBCTRL_IRQ"),
+            ChipSeries::Rp2040 | ChipSeries::Rp2350 => UsbInfo::new("", "", "USB", "USBCTRL_IRQ"),
             _ => UsbInfo::new(
                 "default_dm",
                 "default_dp",
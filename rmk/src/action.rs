@@ -1,5 +1,24 @@
+use num_enum::FromPrimitive;
+
 use crate::keycode::{KeyCode, ModifierCombination};
 
+/// Number of bytes of a [`KeyAction`] in the binary format, see [`KeyAction::encode`]
+pub const KEY_ACTION_BYTES: usize = 4;
+
+/// Errors of encoding or decoding a [`KeyAction`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ActionCodecError {
+    /// Layer number of an `Action` should be less than 32
+    InvalidLayer(u8),
+    /// Unknown `KeyAction` type
+    InvalidType(u8),
+    /// The 12-bit action code doesn't represent an `Action`
+    InvalidActionCode(u16),
+    /// Reserved bits of the modifier combination are set
+    InvalidModifier(u8),
+}
+
 /// A KeyAction is the action at a keyboard position, stored in keymap.
 /// It can be a single action like triggering a key, or a composite keyboard action like tap/hold
 ///
@@ -47,9 +66,114 @@ pub enum KeyAction {
     ///
     /// Serialized as 1|BasicAction(7bits)|BasicAction(8bits).
     TapHold(Action, Action),
+    /// Tap dance, which triggers different actions by the number of taps and holding, see [`crate::tap_dance`].
+    /// The index is the index in `tap_dances` of [`TapDanceConfig`](crate::config::TapDanceConfig).
+    ///
+    /// Doesn't have an action code.
+    TapDance(u8),
+    /// Type a character by the Unicode input method of the host, see [`crate::unicode`].
+    ///
+    /// Doesn't have an action code.
+    Unicode(char),
 }
 
 impl KeyAction {
+    /// Encode the `KeyAction` to the binary format used by storage and host protocols.
+    ///
+    /// The format is stable, it takes [`KEY_ACTION_BYTES`] bytes:
+    ///
+    /// | byte 0 | bits 23..12 of byte 1..3 | bits 11..0 of byte 1..3 |
+    /// | --- | --- | --- |
+    /// | type | action code | action code, layer or modifier |
+    ///
+    /// Types: 0 `No`, 1 `Transparent`, 2 `Single`, 3 `Tap`, 4 `OneShot`, 5 `LayerTapHold`,
+    /// 6 `WithModifier`, 7 `ModifierTapHold`, 8 `TapHold`, 9 `TapDance`, 10 `Unicode`. Action codes are the 12-bit codes of [`Action`],
+    /// the code point of `Unicode` takes all 24 bits.
+    ///
+    /// Unlike VIA keycodes, every `KeyAction` can be encoded without loss.
+    pub fn encode(self) -> Result<[u8; KEY_ACTION_BYTES], ActionCodecError> {
+        let (ty, first, second) = match self {
+            KeyAction::No => (0, 0, 0),
+            KeyAction::Transparent => (1, 0, 0),
+            KeyAction::Single(a) => (2, a.encode()?, 0),
+            KeyAction::Tap(a) => (3, a.encode()?, 0),
+            KeyAction::OneShot(a) => (4, a.encode()?, 0),
+            KeyAction::LayerTapHold(a, layer) => (5, a.encode()?, layer as u16),
+            KeyAction::WithModifier(a, m) => (6, a.encode()?, m.into_bits() as u16),
+            KeyAction::ModifierTapHold(a, m) => (7, a.encode()?, m.into_bits() as u16),
+            KeyAction::TapHold(tap, hold) => (8, tap.encode()?, hold.encode()?),
+            KeyAction::TapDance(index) => (9, 0, index as u16),
+            KeyAction::Unicode(c) => (10, (c as u32 >> 12) as u16, (c as u32 & 0xFFF) as u16),
+        };
+        let payload = ((first as u32) << 12) | second as u32;
+        Ok([
+            ty,
+            (payload >> 16) as u8,
+            (payload >> 8) as u8,
+            payload as u8,
+        ])
+    }
+
+    /// Decode a `KeyAction` from the binary format, see [`KeyAction::encode`]
+    pub fn decode(bytes: [u8; KEY_ACTION_BYTES]) -> Result<Self, ActionCodecError> {
+        let payload = ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | bytes[3] as u32;
+        let first = (payload >> 12) as u16;
+        let second = (payload & 0xFFF) as u16;
+        let modifier = || {
+            if second > 0x1F {
+                Err(ActionCodecError::InvalidModifier(second as u8))
+            } else {
+                Ok(ModifierCombination::from_bits(second as u8))
+            }
+        };
+        match bytes[0] {
+            0 => Ok(KeyAction::No),
+            1 => Ok(KeyAction::Transparent),
+            2 => Ok(KeyAction::Single(Action::decode(first)?)),
+            3 => Ok(KeyAction::Tap(Action::decode(first)?)),
+            4 => Ok(KeyAction::OneShot(Action::decode(first)?)),
+            5 => {
+                if second > u8::MAX as u16 {
+                    return Err(ActionCodecError::InvalidActionCode(second));
+                }
+                Ok(KeyAction::LayerTapHold(
+                    Action::decode(first)?,
+                    second as u8,
+                ))
+            }
+            6 => Ok(KeyAction::WithModifier(Action::decode(first)?, modifier()?)),
+            7 => Ok(KeyAction::ModifierTapHold(
+                Action::decode(first)?,
+                modifier()?,
+            )),
+            8 => Ok(KeyAction::TapHold(
+                Action::decode(first)?,
+                Action::decode(second)?,
+            )),
+            9 => {
+                if second > u8::MAX as u16 {
+                    return Err(ActionCodecError::InvalidActionCode(second));
+                }
+                Ok(KeyAction::TapDance(second as u8))
+            }
+            10 => char::from_u32(payload)
+                .map(KeyAction::Unicode)
+                .ok_or(ActionCodecError::InvalidActionCode(first)),
+            ty => Err(ActionCodecError::InvalidType(ty)),
+        }
+    }
+
+    /// Whether the action triggers different actions on tap and hold
+    pub fn is_tap_hold(&self) -> bool {
+        matches!(
+            self,
+            KeyAction::LayerTapHold(_, _)
+                | KeyAction::ModifierTapHold(_, _)
+                | KeyAction::TapHold(_, _)
+                | KeyAction::TapDance(_)
+        )
+    }
+
     /// Convert a `KeyAction` to corresponding key action code.
     pub(crate) fn to_key_action_code(self) -> u16 {
         match self {
@@ -75,6 +199,14 @@ impl KeyAction {
             KeyAction::TapHold(tap, hold) => {
                 0x8000 | (hold.to_basic_action_code() << 15) | tap.to_basic_action_code()
             }
+            KeyAction::TapDance(index) => {
+                error!("TapDance {} doesn't have an action code", index);
+                0x0000
+            }
+            KeyAction::Unicode(c) => {
+                error!("Unicode {} doesn't have an action code", c);
+                0x0000
+            }
         }
     }
 }
@@ -112,6 +244,15 @@ pub enum Action {
     ///
     /// Uses 0xEA0 ~ 0xEBF. Serialized as 1110|101|layer_num(5bits)
     LayerToggleOnly(u8),
+    /// Power the keyboard down to deep sleep after the key is held for a while, see [`crate::config::SoftOffConfig`]
+    ///
+    /// Uses 0xEC0.
+    SoftOff,
+    /// Activate a layer while the key is held, tapping the key `tapping_toggle` times toggles the layer,
+    /// see [`TapDanceConfig`](crate::config::TapDanceConfig)
+    ///
+    /// Uses 0xEE0 ~ 0xEFF. Serialized as 1110|111|layer_num(5bits)
+    LayerTapToggle(u8),
 }
 
 impl Action {
@@ -125,6 +266,53 @@ impl Action {
             Action::LayerToggle(layer) => 0xE60 | (layer as u16),
             Action::DefaultLayer(layer) => 0xE80 | (layer as u16),
             Action::LayerToggleOnly(layer) => 0xEA0 | (layer as u16),
+            Action::SoftOff => 0xEC0,
+            Action::LayerTapToggle(layer) => 0xEE0 | (layer as u16),
+        }
+    }
+
+    /// Convert an `Action` to 12-bit action code, fails if the layer number can't fit in 5 bits
+    fn encode(self) -> Result<u16, ActionCodecError> {
+        match self {
+            Action::LayerOn(layer)
+            | Action::LayerOff(layer)
+            | Action::LayerToggle(layer)
+            | Action::DefaultLayer(layer)
+            | Action::LayerToggleOnly(layer)
+            | Action::LayerTapToggle(layer)
+                if layer >= 32 =>
+            {
+                Err(ActionCodecError::InvalidLayer(layer))
+            }
+            Action::Modifier(m) if m.into_bits() > 0x1F => {
+                Err(ActionCodecError::InvalidModifier(m.into_bits()))
+            }
+            _ => Ok(self.to_action_code()),
+        }
+    }
+
+    /// Convert a 12-bit action code back to `Action`
+    fn decode(code: u16) -> Result<Self, ActionCodecError> {
+        let arg = (code & 0x1F) as u8;
+        match code {
+            0x000..=0xCFF => {
+                let key = KeyCode::from_primitive(code);
+                // Unknown keycodes are converted to `KeyCode::No` by `from_primitive`
+                if key as u16 == code {
+                    Ok(Action::Key(key))
+                } else {
+                    Err(ActionCodecError::InvalidActionCode(code))
+                }
+            }
+            0xE00..=0xE1F => Ok(Action::Modifier(ModifierCombination::from_bits(arg))),
+            0xE20..=0xE3F => Ok(Action::LayerOn(arg)),
+            0xE40..=0xE5F => Ok(Action::LayerOff(arg)),
+            0xE60..=0xE7F => Ok(Action::LayerToggle(arg)),
+            0xE80..=0xE9F => Ok(Action::DefaultLayer(arg)),
+            0xEA0..=0xEBF => Ok(Action::LayerToggleOnly(arg)),
+            0xEC0 => Ok(Action::SoftOff),
+            0xEE0..=0xEFF => Ok(Action::LayerTapToggle(arg)),
+            _ => Err(ActionCodecError::InvalidActionCode(code)),
         }
     }
 
@@ -142,3 +330,100 @@ impl Action {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// All actions which can be encoded
+    fn all_actions() -> Vec<Action> {
+        // Keycodes which are not defined are skipped
+        let mut actions: Vec<Action> = (0..=0xCFF)
+            .filter(|&code| KeyCode::from_primitive(code) as u16 == code)
+            .map(|code| Action::Key(KeyCode::from_primitive(code)))
+            .collect();
+        for bits in 0..32 {
+            actions.push(Action::Modifier(ModifierCombination::from_bits(bits)));
+        }
+        for layer in 0..32 {
+            actions.push(Action::LayerOn(layer));
+            actions.push(Action::LayerOff(layer));
+            actions.push(Action::LayerToggle(layer));
+            actions.push(Action::DefaultLayer(layer));
+            actions.push(Action::LayerToggleOnly(layer));
+            actions.push(Action::LayerTapToggle(layer));
+        }
+        actions.push(Action::SoftOff);
+        actions
+    }
+
+    fn assert_round_trip(action: KeyAction) {
+        let bytes = action.encode().unwrap();
+        assert_eq!(Ok(action), KeyAction::decode(bytes), "{:?}", bytes);
+    }
+
+    #[test]
+    fn test_key_action_round_trip() {
+        let actions = all_actions();
+        assert_round_trip(KeyAction::No);
+        assert_round_trip(KeyAction::Transparent);
+        for &a in &actions {
+            assert_round_trip(KeyAction::Single(a));
+            assert_round_trip(KeyAction::Tap(a));
+            assert_round_trip(KeyAction::OneShot(a));
+            for layer in 0..=u8::MAX {
+                assert_round_trip(KeyAction::LayerTapHold(a, layer));
+            }
+            for bits in 0..32 {
+                let m = ModifierCombination::from_bits(bits);
+                assert_round_trip(KeyAction::WithModifier(a, m));
+                assert_round_trip(KeyAction::ModifierTapHold(a, m));
+            }
+            for &hold in &actions {
+                assert_round_trip(KeyAction::TapHold(a, hold));
+            }
+        }
+        for index in 0..=u8::MAX {
+            assert_round_trip(KeyAction::TapDance(index));
+        }
+    }
+
+    #[test]
+    fn test_key_action_encoding() {
+        // Stable encoding, these bytes must not change
+        assert_eq!(Ok([0, 0, 0, 0]), KeyAction::No.encode());
+        assert_eq!(Ok([1, 0, 0, 0]), KeyAction::Transparent.encode());
+        assert_eq!(
+            Ok([2, 0x00, 0x40, 0x00]),
+            KeyAction::Single(Action::Key(KeyCode::A)).encode()
+        );
+        assert_eq!(
+            Ok([5, 0x02, 0xC0, 0x03]),
+            KeyAction::LayerTapHold(Action::Key(KeyCode::Space), 3).encode()
+        );
+        assert_eq!(
+            Ok([8, 0x00, 0x4E, 0x22]),
+            KeyAction::TapHold(Action::Key(KeyCode::A), Action::LayerOn(2)).encode()
+        );
+    }
+
+    #[test]
+    fn test_invalid_key_action() {
+        assert_eq!(
+            Err(ActionCodecError::InvalidLayer(32)),
+            KeyAction::Single(Action::LayerOn(32)).encode()
+        );
+        assert_eq!(
+            Err(ActionCodecError::InvalidType(10)),
+            KeyAction::decode([10, 0, 0, 0])
+        );
+        assert_eq!(
+            Err(ActionCodecError::InvalidActionCode(0xF00)),
+            KeyAction::decode([2, 0xF0, 0x00, 0x00])
+        );
+        assert_eq!(
+            Err(ActionCodecError::InvalidModifier(0x20)),
+            KeyAction::decode([6, 0x00, 0x40, 0x20])
+        );
+    }
+}
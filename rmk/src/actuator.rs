@@ -0,0 +1,217 @@
+//! Actuators driven by output pins, such as solenoids and relays
+//!
+//! An [`Actuator`] is fired by a single pulse on its pin. `User24` ~ `User27` fire actuator 0 ~ 3, for example a relay which opens a cash drawer.
+//! Actuators with key press feedback fire on every key press, which makes a clicky solenoid. `User28` turns the feedback off and on.
+//!
+//! Solenoids and relay coils overheat when they're energized for too long, so every actuator has a duty cycle limit:
+//! pulses which would exceed `max_duty` percent of the on time in the `window` are dropped.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_futures::select::select;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+
+/// Max number of actuators
+pub const MAX_ACTUATORS: usize = 31;
+
+/// Bit of a key press in `PENDING_FIRES`, other bits are actuator ids
+const KEY_PRESS_BIT: u32 = 1 << 31;
+
+/// Actuators to be fired
+static PENDING_FIRES: AtomicU32 = AtomicU32::new(0);
+
+/// Signaled when `PENDING_FIRES` is changed
+static ACTUATOR_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static KEY_PRESS_FEEDBACK: AtomicBool = AtomicBool::new(true);
+
+/// Limits the on time of an actuator.
+///
+/// The on time is accumulated and drains at the rate of the max duty cycle, like a leaky bucket,
+/// a pulse is allowed when the accumulated on time stays below `max_duty` percent of the window.
+struct DutyLimiter {
+    /// Max duty cycle in percent
+    max_duty: u8,
+    /// Max accumulated on time in us
+    capacity: u64,
+    /// Accumulated on time in us
+    level: u64,
+    last: Instant,
+}
+
+impl DutyLimiter {
+    fn new(max_duty: u8, window: Duration) -> Self {
+        let max_duty = max_duty.min(100);
+        Self {
+            max_duty,
+            capacity: window.as_micros() * max_duty as u64 / 100,
+            level: 0,
+            last: Instant::from_ticks(0),
+        }
+    }
+
+    /// Returns whether a pulse is allowed at `now`, the pulse is counted if it is
+    fn try_fire(&mut self, now: Instant, pulse: Duration) -> bool {
+        let drained =
+            now.saturating_duration_since(self.last).as_micros() * self.max_duty as u64 / 100;
+        self.level = self.level.saturating_sub(drained);
+        self.last = now;
+        let pulse = pulse.as_micros();
+        if self.level + pulse > self.capacity {
+            return false;
+        }
+        self.level += pulse;
+        true
+    }
+}
+
+/// A solenoid, relay or other actuator driven by an output pin
+pub struct Actuator<P: OutputPin> {
+    pin: P,
+    /// How long the pin is active when fired
+    pulse: Duration,
+    active_high: bool,
+    /// Fire on every key press
+    key_press: bool,
+    limiter: DutyLimiter,
+}
+
+impl<P: OutputPin> Actuator<P> {
+    /// Create an actuator which is fired by a `pulse` of active high.
+    ///
+    /// The duty cycle is limited to 25% in 2 seconds by default.
+    pub fn new(pin: P, pulse: Duration) -> Self {
+        Self {
+            pin,
+            pulse,
+            active_high: true,
+            key_press: false,
+            limiter: DutyLimiter::new(25, Duration::from_secs(2)),
+        }
+    }
+
+    /// The pin is low when the actuator is on
+    pub fn with_active_low(mut self) -> Self {
+        self.active_high = false;
+        self
+    }
+
+    /// Fire the actuator on every key press, like a clicky solenoid
+    pub fn with_key_press_feedback(mut self) -> Self {
+        self.key_press = true;
+        self
+    }
+
+    /// Limit the on time to `max_duty` percent of `window`, check the datasheet of the solenoid or relay
+    pub fn with_duty_limit(mut self, max_duty: u8, window: Duration) -> Self {
+        self.limiter = DutyLimiter::new(max_duty, window);
+        self
+    }
+
+    fn set(&mut self, on: bool) {
+        if on == self.active_high {
+            self.pin.set_high().ok();
+        } else {
+            self.pin.set_low().ok();
+        }
+    }
+}
+
+/// Fire the actuator with index `id` of [`run_actuators`]
+pub fn fire_actuator(id: u8) {
+    if (id as usize) < MAX_ACTUATORS {
+        PENDING_FIRES.fetch_or(1 << id, Ordering::Relaxed);
+        ACTUATOR_SIGNAL.signal(());
+    }
+}
+
+/// Whether actuators with key press feedback fire on key presses
+pub fn key_press_feedback_enabled() -> bool {
+    KEY_PRESS_FEEDBACK.load(Ordering::Relaxed)
+}
+
+/// Enable or disable the key press feedback, it's enabled by default
+pub fn set_key_press_feedback(enabled: bool) {
+    KEY_PRESS_FEEDBACK.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn toggle_key_press_feedback() {
+    let enabled = !key_press_feedback_enabled();
+    info!("Key press feedback: {}", enabled);
+    set_key_press_feedback(enabled);
+}
+
+/// A key is pressed, fire actuators with key press feedback
+pub(crate) fn notify_key_press() {
+    if key_press_feedback_enabled() {
+        PENDING_FIRES.fetch_or(KEY_PRESS_BIT, Ordering::Relaxed);
+        ACTUATOR_SIGNAL.signal(());
+    }
+}
+
+/// Run actuators, this function never returns.
+///
+/// `actuators[i]` is fired by `fire_actuator(i)`, and `User24` ~ `User27` for the first 4 actuators.
+/// A fire is ignored while the actuator is on.
+pub async fn run_actuators<P: OutputPin, const N: usize>(mut actuators: [Actuator<P>; N]) -> ! {
+    let mut off_at: [Option<Instant>; N] = [None; N];
+    for actuator in actuators.iter_mut() {
+        actuator.set(false);
+    }
+    loop {
+        let next_off = off_at.iter().flatten().min().copied();
+        let timeout = async {
+            match next_off {
+                Some(t) => Timer::at(t).await,
+                None => core::future::pending().await,
+            }
+        };
+        select(ACTUATOR_SIGNAL.wait(), timeout).await;
+
+        let now = Instant::now();
+        let pending = PENDING_FIRES.swap(0, Ordering::Relaxed);
+        for (id, actuator) in actuators.iter_mut().enumerate() {
+            if off_at[id].is_some_and(|t| now >= t) {
+                actuator.set(false);
+                off_at[id] = None;
+            }
+            let fire = (id < MAX_ACTUATORS && pending & (1 << id) != 0)
+                || (pending & KEY_PRESS_BIT != 0 && actuator.key_press);
+            if !fire || off_at[id].is_some() {
+                continue;
+            }
+            if actuator.limiter.try_fire(now, actuator.pulse) {
+                actuator.set(true);
+                off_at[id] = Some(now + actuator.pulse);
+            } else {
+                warn!(
+                    "Actuator {} exceeds the duty cycle limit, the pulse is dropped",
+                    id
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_duty_limiter() {
+        // 50ms of on time in 200ms
+        let mut limiter = DutyLimiter::new(25, Duration::from_millis(200));
+        let pulse = Duration::from_millis(20);
+        let at = |ms| Instant::from_millis(ms);
+        assert!(limiter.try_fire(at(1000), pulse));
+        assert!(limiter.try_fire(at(1020), pulse));
+        // 32.5ms is left after draining 2.5ms, another pulse exceeds the limit
+        assert!(!limiter.try_fire(at(1030), pulse));
+        // Drains 27.5ms in 110ms
+        assert!(limiter.try_fire(at(1140), pulse));
+        // A pulse longer than the limit is never allowed
+        assert!(!limiter.try_fire(at(5000), Duration::from_millis(60)));
+    }
+}
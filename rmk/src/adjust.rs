@@ -0,0 +1,121 @@
+//! Encoder value adjustment
+//!
+//! Press a key with `User20` to select a runtime parameter, then turning any rotary encoder adjusts the parameter
+//! instead of triggering the actions in the encoder map. Each press selects the next parameter:
+//! RGB brightness, tapping term, pointer CPI(with `pmw33xx` feature), actuation point(with an [analog matrix](crate::analog_matrix)),
+//! then back to the normal encoder actions.
+//! The adjustment ends as well when no encoder is turned for [`ADJUST_TIMEOUT`].
+//!
+//! The selected parameter and its value are shown on the display over all pages while adjusting.
+//! Adjusted values are not saved, they are reset at next boot.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+/// The adjustment ends if no encoder is turned for this long
+pub const ADJUST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Step of the tapping term per encoder detent, in milliseconds
+pub(crate) const TAPPING_TERM_STEP: u16 = 10;
+/// Range of the tapping term, in milliseconds
+pub(crate) const TAPPING_TERM_RANGE: (u16, u16) = (50, 1000);
+/// Step of the pointer CPI per encoder detent
+pub(crate) const CPI_STEP: u16 = 100;
+/// Range of the pointer CPI
+pub(crate) const CPI_RANGE: (u16, u16) = (100, 12000);
+/// Step of the actuation point per encoder detent, in 0.01mm
+pub(crate) const ACTUATION_STEP: u16 = 10;
+/// Range of the actuation point, in 0.01mm
+pub(crate) const ACTUATION_RANGE: (u16, u16) = (10, 400);
+
+/// Runtime parameter which is adjusted by encoders
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdjustTarget {
+    /// Brightness of the active RGB zone, 0 ~ 255
+    RgbBrightness,
+    /// Hold timeout of tap/hold keys in milliseconds, see [`TapHoldConfig`](crate::config::TapHoldConfig)
+    TappingTerm,
+    /// CPI of the pointing sensor
+    PointerCpi,
+    /// Actuation point of analog keys in 0.01mm
+    ActuationPoint,
+}
+
+impl AdjustTarget {
+    /// The parameter selected after this one, `None` goes back to normal encoder actions
+    pub(crate) fn next(self) -> Option<Self> {
+        match self {
+            AdjustTarget::RgbBrightness => Some(AdjustTarget::TappingTerm),
+            #[cfg(feature = "pmw33xx")]
+            AdjustTarget::TappingTerm => Some(AdjustTarget::PointerCpi),
+            AdjustTarget::ActuationPoint => None,
+            _ if crate::analog_matrix::analog_matrix_active() => Some(AdjustTarget::ActuationPoint),
+            _ => None,
+        }
+    }
+
+    /// Name shown on the display
+    pub fn name(self) -> &'static str {
+        match self {
+            AdjustTarget::RgbBrightness => "Brightness",
+            AdjustTarget::TappingTerm => "Tapping term",
+            AdjustTarget::PointerCpi => "Pointer CPI",
+            AdjustTarget::ActuationPoint => "Actuation",
+        }
+    }
+}
+
+// Selected parameter, its current value and the time of the last adjustment
+static ADJUST: Mutex<CriticalSectionRawMutex, Cell<Option<(AdjustTarget, u16, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// The parameter being adjusted and its current value, `None` if encoders trigger their normal actions
+pub fn adjust_status() -> Option<(AdjustTarget, u16)> {
+    ADJUST
+        .lock(|a| a.get())
+        .filter(|(_, _, at)| at.elapsed() < ADJUST_TIMEOUT)
+        .map(|(target, value, _)| (target, value))
+}
+
+/// Select the parameter to adjust with its current value, or stop adjusting with `None`
+pub(crate) fn set_adjust_target(target: Option<(AdjustTarget, u16)>) {
+    ADJUST.lock(|a| a.set(target.map(|(t, value)| (t, value, Instant::now()))));
+    info!("Encoder adjustment: {:?}", target.map(|(t, _)| t));
+    crate::display::request_redraw();
+}
+
+/// Update the value of the parameter after it's adjusted, which also extends the adjustment
+pub(crate) fn update_adjust_value(value: u16) {
+    ADJUST.lock(|a| {
+        if let Some((target, _, _)) = a.get() {
+            a.set(Some((target, value, Instant::now())));
+        }
+    });
+    crate::display::request_redraw();
+}
+
+/// Add `steps` steps of `step` to `value`, clamped to `range`
+pub(crate) fn step_value(value: u16, steps: i8, step: u16, range: (u16, u16)) -> u16 {
+    let delta = step.saturating_mul(steps.unsigned_abs() as u16);
+    let value = if steps < 0 {
+        value.saturating_sub(delta)
+    } else {
+        value.saturating_add(delta)
+    };
+    value.clamp(range.0, range.1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_step_value() {
+        assert_eq!(step_value(200, 1, 10, TAPPING_TERM_RANGE), 210);
+        assert_eq!(step_value(55, -1, 10, TAPPING_TERM_RANGE), 50);
+        assert_eq!(step_value(11950, 2, CPI_STEP, CPI_RANGE), 12000);
+    }
+}
@@ -0,0 +1,331 @@
+//! Analog matrix of Hall-effect switches
+//!
+//! Analog switches report how deep they're pressed instead of a contact. [`AnalogMatrix`] samples every key by ADC through
+//! analog multiplexers such as 74HC4067 or CD4051, then converts the raw value to the travel of the key in 0.01mm,
+//! which is saved to [`KeyState::travel`].
+//!
+//! Keys are calibrated automatically. The value at rest is sampled when the scanning starts, so keys shouldn't be pressed then,
+//! and the bottom of each key is learned from the largest value seen, so the travel is accurate after the key is pressed down once.
+//! Both polarities of magnets work.
+//!
+//! A key is pressed at the [actuation point](set_actuation_point) and released when it goes back up by the hysteresis.
+//! With [rapid trigger](set_rapid_trigger), below the actuation point, the key is released as soon as it moves up by the sensitivity,
+//! and pressed again as soon as it moves down by the sensitivity, until it's released above the actuation point.
+//! Both can be changed at runtime, and the actuation point is adjustable by encoders, see [`crate::adjust`].
+//! They're not saved, the values in [`AnalogConfig`] are used at boot.
+
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::OutputPin;
+
+use crate::event::KeyEvent;
+use crate::keyboard::KEY_EVENT_CHANNEL;
+use crate::matrix::{KeyState, MatrixTrait};
+
+/// Number of samples averaged for the value of keys at rest
+const CALIBRATION_SAMPLES: u32 = 8;
+
+/// Actuation point in 0.01mm
+static ACTUATION_POINT: AtomicU16 = AtomicU16::new(150);
+/// Sensitivity of rapid trigger in 0.01mm, 0 if rapid trigger is off
+static RAPID_TRIGGER: AtomicU16 = AtomicU16::new(0);
+/// Whether an analog matrix is created, the actuation point is adjustable only then
+static ANALOG_MATRIX_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Get the actuation point of analog keys in 0.01mm
+pub fn actuation_point() -> u16 {
+    ACTUATION_POINT.load(Ordering::Relaxed)
+}
+
+/// Set the actuation point of analog keys in 0.01mm
+pub fn set_actuation_point(travel: u16) {
+    info!("Actuation point: {}", travel);
+    ACTUATION_POINT.store(travel, Ordering::Relaxed);
+}
+
+/// Get the sensitivity of rapid trigger in 0.01mm, `None` if rapid trigger is off
+pub fn rapid_trigger() -> Option<u16> {
+    Some(RAPID_TRIGGER.load(Ordering::Relaxed)).filter(|&s| s != 0)
+}
+
+/// Turn on rapid trigger with the sensitivity in 0.01mm, or turn it off by `None`
+pub fn set_rapid_trigger(sensitivity: Option<u16>) {
+    info!("Rapid trigger: {:?}", sensitivity);
+    RAPID_TRIGGER.store(sensitivity.map_or(0, |s| s.max(1)), Ordering::Relaxed);
+}
+
+pub(crate) fn analog_matrix_active() -> bool {
+    ANALOG_MATRIX_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// ADC which reads the common pins of the multiplexers
+pub trait AnalogInput {
+    /// Read the raw value of the ADC channel, `channel` is the row of the matrix
+    fn read(&mut self, channel: usize) -> impl Future<Output = u16>;
+}
+
+/// Config of [`AnalogMatrix`], all distances are in 0.01mm
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AnalogConfig {
+    /// Total travel of the switches
+    pub total_travel: u16,
+    /// Actuation point at boot
+    pub actuation_point: u16,
+    /// Sensitivity of rapid trigger at boot, `None` if it's off
+    pub rapid_trigger: Option<u16>,
+    /// A pressed key is released when it's this much above the actuation point, so that the noise doesn't toggle it
+    pub hysteresis: u16,
+    /// Minimum difference of raw values between the rest and the bottom of keys, which is used before a key is pressed down
+    pub min_range: u16,
+    /// Time for the multiplexers to settle after switching the channel
+    pub settle_time: Duration,
+}
+
+impl Default for AnalogConfig {
+    fn default() -> Self {
+        Self {
+            total_travel: 400,
+            actuation_point: 150,
+            rapid_trigger: None,
+            hysteresis: 10,
+            min_range: 500,
+            settle_time: Duration::from_micros(5),
+        }
+    }
+}
+
+/// Calibration and rapid trigger state of a key
+#[derive(Clone, Copy, Debug, Default)]
+struct AnalogKey {
+    /// Raw value at rest
+    rest: u16,
+    /// Largest difference of raw values from the rest, which is the bottom of the key
+    range: u16,
+    /// The deepest travel since the key is pressed, or the shallowest since it's released by rapid trigger.
+    /// `None` after the key is released above the actuation point
+    extreme: Option<u16>,
+}
+
+impl AnalogKey {
+    /// Convert the raw value to the travel, the bottom is extended if the key is pressed deeper
+    fn travel(&mut self, raw: u16, total_travel: u16) -> u16 {
+        let delta = raw.abs_diff(self.rest);
+        self.range = self.range.max(delta).max(1);
+        (delta as u32 * total_travel as u32 / self.range as u32) as u16
+    }
+
+    /// The pressed state of the key at the travel
+    fn update(
+        &mut self,
+        pressed: bool,
+        travel: u16,
+        actuation_point: u16,
+        rapid_trigger: Option<u16>,
+        hysteresis: u16,
+    ) -> bool {
+        if travel < actuation_point.saturating_sub(hysteresis) {
+            self.extreme = None;
+            return false;
+        }
+        if pressed {
+            let deepest = self.extreme.map_or(travel, |e| e.max(travel));
+            match rapid_trigger {
+                Some(sensitivity) if travel.saturating_add(sensitivity) <= deepest => {
+                    self.extreme = Some(travel);
+                    false
+                }
+                _ => {
+                    self.extreme = Some(deepest);
+                    true
+                }
+            }
+        } else {
+            let actuated = match (rapid_trigger, self.extreme) {
+                (Some(sensitivity), Some(shallowest)) => {
+                    let shallowest = shallowest.min(travel);
+                    self.extreme = Some(shallowest);
+                    travel >= shallowest.saturating_add(sensitivity)
+                }
+                _ => travel >= actuation_point,
+            };
+            if actuated {
+                self.extreme = Some(travel);
+            }
+            actuated
+        }
+    }
+}
+
+/// Matrix of analog keys read by ADC through multiplexers.
+///
+/// Each row is a multiplexer connected to the ADC channel of the row, and each column is a channel of multiplexers,
+/// which is selected by `SELECT` pins, the first pin is the lowest bit of the channel.
+pub struct AnalogMatrix<
+    A: AnalogInput,
+    S: OutputPin,
+    const SELECT: usize,
+    const ROW: usize,
+    const COL: usize,
+> {
+    adc: A,
+    /// Select pins of multiplexers, shared by all multiplexers
+    select_pins: [S; SELECT],
+    config: AnalogConfig,
+    keys: [[AnalogKey; COL]; ROW],
+    key_states: [[KeyState; COL]; ROW],
+}
+
+impl<A: AnalogInput, S: OutputPin, const SELECT: usize, const ROW: usize, const COL: usize>
+    AnalogMatrix<A, S, SELECT, ROW, COL>
+{
+    /// Create an analog matrix, the actuation point and rapid trigger are set by the config
+    pub fn new(adc: A, select_pins: [S; SELECT], config: AnalogConfig) -> Self {
+        assert!(
+            COL <= 1 << SELECT,
+            "Multiplexers don't have {} channels",
+            COL
+        );
+        ACTUATION_POINT.store(config.actuation_point, Ordering::Relaxed);
+        set_rapid_trigger(config.rapid_trigger);
+        ANALOG_MATRIX_ACTIVE.store(true, Ordering::Relaxed);
+        Self {
+            adc,
+            select_pins,
+            config,
+            keys: [[AnalogKey::default(); COL]; ROW],
+            key_states: [[KeyState::new(); COL]; ROW],
+        }
+    }
+
+    /// Select the channel of multiplexers and wait for them to settle
+    async fn select(&mut self, channel: usize) {
+        for (bit, pin) in self.select_pins.iter_mut().enumerate() {
+            pin.set_state(((channel >> bit) & 1 != 0).into()).ok();
+        }
+        Timer::after(self.config.settle_time).await;
+    }
+
+    /// Sample the rest values of all keys
+    async fn calibrate(&mut self) {
+        let mut sums = [[0u32; COL]; ROW];
+        for _ in 0..CALIBRATION_SAMPLES {
+            for col in 0..COL {
+                self.select(col).await;
+                for (row, row_sums) in sums.iter_mut().enumerate() {
+                    row_sums[col] += self.adc.read(row).await as u32;
+                }
+            }
+        }
+        for (keys, sums) in self.keys.iter_mut().zip(sums.iter()) {
+            for (key, sum) in keys.iter_mut().zip(sums.iter()) {
+                *key = AnalogKey {
+                    rest: (sum / CALIBRATION_SAMPLES) as u16,
+                    range: self.config.min_range,
+                    extreme: None,
+                };
+            }
+        }
+        debug!("Analog keys are calibrated");
+    }
+}
+
+impl<A: AnalogInput, S: OutputPin, const SELECT: usize, const ROW: usize, const COL: usize>
+    MatrixTrait for AnalogMatrix<A, S, SELECT, ROW, COL>
+{
+    const ROW: usize = ROW;
+    const COL: usize = COL;
+
+    // Analog keys can't wake up the chip, they're always scanned
+    #[cfg(feature = "async_matrix")]
+    async fn wait_for_key(&mut self) {}
+
+    async fn scan(&mut self) {
+        info!("Analog matrix scanning");
+        self.calibrate().await;
+        loop {
+            let actuation_point = actuation_point();
+            let rapid_trigger = rapid_trigger();
+            for col in 0..COL {
+                self.select(col).await;
+                let rows = self.keys.iter_mut().zip(self.key_states.iter_mut());
+                for (row, (keys, key_states)) in rows.enumerate() {
+                    let raw = self.adc.read(row).await;
+                    let key = &mut keys[col];
+                    let key_state = &mut key_states[col];
+                    key_state.travel = key.travel(raw, self.config.total_travel);
+                    let pressed = key.update(
+                        key_state.pressed,
+                        key_state.travel,
+                        actuation_point,
+                        rapid_trigger,
+                        self.config.hysteresis,
+                    );
+                    if pressed != key_state.pressed {
+                        key_state.pressed = pressed;
+                        KEY_EVENT_CHANNEL
+                            .send(KeyEvent {
+                                row: row as u8,
+                                col: col as u8,
+                                pressed,
+                            })
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+
+    fn get_key_state(&mut self, row: usize, col: usize) -> KeyState {
+        self.key_states[row][col]
+    }
+
+    fn update_key_state(&mut self, row: usize, col: usize, f: impl FnOnce(&mut KeyState)) {
+        f(&mut self.key_states[row][col]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_analog_key_travel() {
+        let mut key = AnalogKey {
+            rest: 2000,
+            range: 500,
+            extreme: None,
+        };
+        assert_eq!(key.travel(1750, 400), 200);
+        // The bottom is extended
+        assert_eq!(key.travel(1000, 400), 400);
+        assert_eq!(key.travel(1500, 400), 200);
+    }
+
+    #[test]
+    fn test_rapid_trigger() {
+        let mut key = AnalogKey::default();
+        let mut pressed = false;
+        let mut press = |travel: u16, rapid_trigger: Option<u16>| {
+            pressed = key.update(pressed, travel, 150, rapid_trigger, 10);
+            pressed
+        };
+        // Pressed at the actuation point and released with hysteresis
+        assert!(!press(140, None));
+        assert!(press(150, None));
+        assert!(press(145, None));
+        assert!(!press(139, None));
+        // Released and pressed again by moving 0.3mm below the actuation point
+        assert!(press(300, Some(30)));
+        assert!(press(350, Some(30)));
+        assert!(!press(320, Some(30)));
+        assert!(!press(280, Some(30)));
+        assert!(press(310, Some(30)));
+        // Above the actuation point, it's pressed at the actuation point again
+        assert!(!press(100, Some(30)));
+        assert!(!press(139, Some(30)));
+        assert!(press(150, Some(30)));
+    }
+}
@@ -0,0 +1,88 @@
+//! Idle auto-lock
+//!
+//! When no key is pressed and no pointing device is moved for `timeout` of [`AutoLockConfig`](crate::config::AutoLockConfig),
+//! the lock shortcut of the host OS is sent once, so that the computer is locked when the user walks away.
+//! It's sent again only after new activity and another `timeout`.
+//!
+//! Press a key with `User19` to suppress the auto-lock, for example while giving a presentation, press it again to resume.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::config::AutoLockConfig;
+use crate::keycode::{KeyCode, ModifierCombination};
+
+/// How often the suppressed or locked auto-lock checks whether it should be armed again
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static AUTO_LOCK_SUPPRESSED: AtomicBool = AtomicBool::new(false);
+
+// The last activity before the host is locked, the host isn't locked again until there's new activity
+static LOCKED_ACTIVITY: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Operating system of the host, which decides the lock shortcut
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostOs {
+    /// `Win + L`
+    #[default]
+    Windows,
+    /// `Ctrl + Cmd + Q`
+    MacOs,
+    /// `Super + L`, which is the default of GNOME and KDE
+    Linux,
+}
+
+impl HostOs {
+    /// Key and modifiers of the lock shortcut
+    pub fn lock_shortcut(self) -> (KeyCode, ModifierCombination) {
+        match self {
+            HostOs::Windows | HostOs::Linux => (
+                KeyCode::L,
+                ModifierCombination::new_from(false, true, false, false, false),
+            ),
+            HostOs::MacOs => (
+                KeyCode::Q,
+                ModifierCombination::new_from(false, true, false, false, true),
+            ),
+        }
+    }
+}
+
+/// Whether the auto-lock is suppressed by `User19`
+pub fn auto_lock_suppressed() -> bool {
+    AUTO_LOCK_SUPPRESSED.load(Ordering::Relaxed)
+}
+
+/// Suppress or resume the auto-lock
+pub fn set_auto_lock_suppressed(suppressed: bool) {
+    AUTO_LOCK_SUPPRESSED.store(suppressed, Ordering::Relaxed);
+    info!("Auto-lock suppressed: {}", suppressed);
+}
+
+pub(crate) fn toggle_auto_lock_suppressed() {
+    set_auto_lock_suppressed(!auto_lock_suppressed());
+}
+
+/// Wait until the keyboard is idle for `timeout`, it never returns if the auto-lock is disabled
+pub(crate) async fn wait_auto_lock(config: &AutoLockConfig) {
+    let Some(timeout) = config.timeout else {
+        return core::future::pending().await;
+    };
+    loop {
+        let last_activity = crate::display::last_key_activity();
+        if auto_lock_suppressed() || LOCKED_ACTIVITY.lock(|l| l.get()) == Some(last_activity) {
+            Timer::after(CHECK_INTERVAL).await;
+            continue;
+        }
+        Timer::at(last_activity + timeout).await;
+        if crate::display::last_key_activity() == last_activity && !auto_lock_suppressed() {
+            LOCKED_ACTIVITY.lock(|l| l.set(Some(last_activity)));
+            return;
+        }
+    }
+}
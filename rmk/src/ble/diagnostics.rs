@@ -0,0 +1,206 @@
+//! Connection diagnostics of BLE links
+//!
+//! RMK records the latest and the averaged RSSI, and the number of sent/failed packets of the BLE host link and the BLE split link.
+//! Use [`link_diagnostics`] to query the current statistics, for example in a custom task. They can also be read by
+//! RawHID command `0xF6 0x07`, see [`crate::via`], or shown by `rmk::display::render_ble_link_widget`.
+
+use core::sync::atomic::{AtomicI8, AtomicU32, Ordering};
+
+/// RSSI value which indicates that no RSSI has been measured yet
+const RSSI_UNKNOWN: i8 = i8::MIN;
+
+/// Weight of a new RSSI sample in the average, 1/4
+const RSSI_AVERAGE_SHIFT: u32 = 2;
+
+/// BLE links that RMK keeps statistics for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BleLink {
+    /// Link between the keyboard(central) and the host
+    Host,
+    /// Link between split central and split peripheral
+    Split,
+}
+
+/// Snapshot of the statistics of a BLE link
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkDiagnostics {
+    /// Latest RSSI in dBm, `None` if the link isn't connected or no RSSI is measured yet
+    pub rssi: Option<i8>,
+    /// Moving average of RSSI in dBm since the link is connected, which smooths out short fades
+    pub average_rssi: Option<i8>,
+    /// Number of packets sent successfully since the link is connected
+    pub sent: u32,
+    /// Number of packets failed to send since the link is connected
+    pub failed: u32,
+    /// Number of reconnections since power on
+    pub reconnections: u32,
+}
+
+impl LinkDiagnostics {
+    /// Packet failure rate in percent
+    pub fn failure_rate(&self) -> u8 {
+        let total = self.sent + self.failed;
+        if total == 0 {
+            0
+        } else {
+            ((self.failed as u64 * 100) / total as u64) as u8
+        }
+    }
+
+    /// Signal strength in 0~4 bars, which is convenient for rendering on a display
+    pub fn signal_bars(&self) -> u8 {
+        match self.rssi {
+            None => 0,
+            Some(r) if r >= -55 => 4,
+            Some(r) if r >= -67 => 3,
+            Some(r) if r >= -80 => 2,
+            Some(_) => 1,
+        }
+    }
+}
+
+/// Exponential moving average of RSSI, the first sample is taken as the average
+fn average_rssi(average: Option<i8>, sample: i8) -> i8 {
+    match average {
+        None => sample,
+        Some(average) => {
+            let diff = sample as i16 - average as i16;
+            // Round to the nearest, so the average follows a steady RSSI within 1dBm
+            let step =
+                (diff + (diff.signum() << (RSSI_AVERAGE_SHIFT - 1))) / (1 << RSSI_AVERAGE_SHIFT);
+            (average as i16 + step) as i8
+        }
+    }
+}
+
+fn known_rssi(rssi: i8) -> Option<i8> {
+    (rssi != RSSI_UNKNOWN).then_some(rssi)
+}
+
+pub(crate) struct LinkStats {
+    rssi: AtomicI8,
+    average_rssi: AtomicI8,
+    sent: AtomicU32,
+    failed: AtomicU32,
+    reconnections: AtomicU32,
+}
+
+impl LinkStats {
+    const fn new() -> Self {
+        Self {
+            rssi: AtomicI8::new(RSSI_UNKNOWN),
+            average_rssi: AtomicI8::new(RSSI_UNKNOWN),
+            sent: AtomicU32::new(0),
+            failed: AtomicU32::new(0),
+            reconnections: AtomicU32::new(0),
+        }
+    }
+
+    /// Reset packet counters when a new connection is established
+    pub(crate) fn on_connected(&self) {
+        self.rssi.store(RSSI_UNKNOWN, Ordering::Relaxed);
+        self.average_rssi.store(RSSI_UNKNOWN, Ordering::Relaxed);
+        self.sent.store(0, Ordering::Relaxed);
+        self.failed.store(0, Ordering::Relaxed);
+        self.reconnections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_disconnected(&self) {
+        self.rssi.store(RSSI_UNKNOWN, Ordering::Relaxed);
+    }
+
+    pub(crate) fn update_rssi(&self, rssi: i8) {
+        self.rssi.store(rssi, Ordering::Relaxed);
+        let average = known_rssi(self.average_rssi.load(Ordering::Relaxed));
+        self.average_rssi
+            .store(average_rssi(average, rssi), Ordering::Relaxed);
+    }
+
+    /// Record the result of sending a packet
+    pub(crate) fn record<T, E>(&self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => self.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn snapshot(&self) -> LinkDiagnostics {
+        let rssi = known_rssi(self.rssi.load(Ordering::Relaxed));
+        LinkDiagnostics {
+            rssi,
+            // The average is kept after disconnection, it's only shown along with the latest RSSI
+            average_rssi: rssi.and(known_rssi(self.average_rssi.load(Ordering::Relaxed))),
+            sent: self.sent.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            // The first connection isn't a reconnection
+            reconnections: self.reconnections.load(Ordering::Relaxed).saturating_sub(1),
+        }
+    }
+}
+
+pub(crate) static HOST_LINK_STATS: LinkStats = LinkStats::new();
+pub(crate) static SPLIT_LINK_STATS: LinkStats = LinkStats::new();
+
+/// Interval of sampling RSSI, in milliseconds
+pub(crate) const RSSI_SAMPLE_INTERVAL_MS: u64 = 2000;
+
+/// Get current statistics of the given BLE link
+pub fn link_diagnostics(link: BleLink) -> LinkDiagnostics {
+    match link {
+        BleLink::Host => HOST_LINK_STATS.snapshot(),
+        BleLink::Split => SPLIT_LINK_STATS.snapshot(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_average_rssi() {
+        assert_eq!(average_rssi(None, -60), -60);
+        assert_eq!(average_rssi(Some(-60), -60), -60);
+        assert_eq!(average_rssi(Some(-60), -80), -65);
+        assert_eq!(average_rssi(Some(-80), -60), -75);
+        // Rounded to the nearest, so the average follows a steady RSSI within 1dBm
+        assert_eq!(average_rssi(Some(-60), -62), -61);
+        assert_eq!(average_rssi(Some(-61), -62), -61);
+        assert_eq!(average_rssi(Some(-60), -63), -61);
+        // No overflow at the ends of the range
+        assert_eq!(average_rssi(Some(-127), 127), -63);
+    }
+
+    #[test]
+    fn test_link_stats() {
+        let stats = LinkStats::new();
+        assert_eq!(stats.snapshot(), LinkDiagnostics::default());
+
+        stats.on_connected();
+        stats.update_rssi(-50);
+        stats.update_rssi(-70);
+        stats.record::<(), ()>(&Ok(()));
+        stats.record::<(), ()>(&Ok(()));
+        stats.record::<(), ()>(&Ok(()));
+        stats.record::<(), ()>(&Err(()));
+        let diagnostics = stats.snapshot();
+        assert_eq!(diagnostics.rssi, Some(-70));
+        assert_eq!(diagnostics.average_rssi, Some(-55));
+        assert_eq!((diagnostics.sent, diagnostics.failed), (3, 1));
+        assert_eq!(diagnostics.failure_rate(), 25);
+        assert_eq!(diagnostics.signal_bars(), 2);
+        assert_eq!(diagnostics.reconnections, 0);
+
+        // RSSI is unknown while disconnected, counters are reset by the next connection
+        stats.on_disconnected();
+        let diagnostics = stats.snapshot();
+        assert_eq!((diagnostics.rssi, diagnostics.average_rssi), (None, None));
+        assert_eq!(diagnostics.signal_bars(), 0);
+        stats.on_connected();
+        let diagnostics = stats.snapshot();
+        assert_eq!((diagnostics.sent, diagnostics.failed), (0, 0));
+        assert_eq!(diagnostics.failure_rate(), 0);
+        assert_eq!(diagnostics.reconnections, 1);
+    }
+}
@@ -46,6 +46,9 @@ pub async fn initialize_esp_ble_keyboard_with_config_and_run<
 
     keyboard_config: RmkConfig<'static, Out>,
 ) -> ! {
+    crate::safe_mode::check_safe_mode(&mut matrix, keyboard_config.storage_config.safe_mode_key)
+        .await;
+
     let f = Partition::new(PartitionType::Custom, Some(c"rmk"));
     let num_sectors = (f.capacity() / Partition::SECTOR_SIZE) as u8;
     let mut storage = Storage::new(
@@ -59,7 +62,16 @@ pub async fn initialize_esp_ble_keyboard_with_config_and_run<
     )
     .await;
 
-    let keymap = RefCell::new(KeyMap::new_from_storage(default_keymap, Some(&mut storage)).await);
+    let keymap = RefCell::new(
+        KeyMap::new_from_storage(
+            default_keymap,
+            keyboard_config.encoder_map,
+            keyboard_config.macros,
+            keyboard_config.key_remap,
+            Some(&mut storage),
+        )
+        .await,
+    );
 
     let keyboard_report_sender = KEYBOARD_REPORT_CHANNEL.sender();
     let keyboard_report_receiver = KEYBOARD_REPORT_CHANNEL.receiver();
@@ -68,6 +80,7 @@ pub async fn initialize_esp_ble_keyboard_with_config_and_run<
         &keymap,
         &keyboard_report_sender,
         keyboard_config.behavior_config,
+        keyboard_config.mouse_config,
     );
     // esp32c3 doesn't have USB device, so there is no usb here
     // TODO: add usb service for other chips of esp32 which have USB device
@@ -82,6 +95,7 @@ pub async fn initialize_esp_ble_keyboard_with_config_and_run<
         ble_server.output_keyboard.lock().on_write(|args| {
             let data: &[u8] = args.recv_data();
             debug!("output_keyboard {}, {}", data.len(), data[0]);
+            crate::sleep::notify_host_activity();
         });
 
         info!("Waitting for connection..");
@@ -89,6 +103,8 @@ pub async fn initialize_esp_ble_keyboard_with_config_and_run<
 
         info!("BLE connected!");
         CONNECTION_STATE.store(true, core::sync::atomic::Ordering::Release);
+        crate::keyboard::notify_host_reconnected();
+        crate::sleep::notify_host_activity();
 
         // Create BLE HID writers
         let mut keyboard_writer = ble_server.input_keyboard;
@@ -110,6 +126,7 @@ pub async fn initialize_esp_ble_keyboard_with_config_and_run<
         ble_server.output_vial.lock().on_write(|args| {
             let data: &[u8] = args.recv_data();
             debug!("BLE received {} {=[u8]:#X}", data.len(), data);
+            crate::sleep::notify_host_activity();
             block_on(via_output.send(unsafe { *(data.as_ptr() as *const [u8; 32]) }));
         });
         let mut via_rw = VialReaderWriter {
@@ -1,5 +1,6 @@
 pub(crate) mod descriptor;
 pub(crate) mod device_info;
+pub mod diagnostics;
 
 #[cfg(feature = "_esp_ble")]
 pub mod esp;
@@ -13,7 +14,9 @@ pub use nrf::SOFTWARE_VBUS;
 
 use crate::{
     hid::HidWriterWrapper,
-    keyboard::{write_other_report_to_host, KeyboardReportMessage, REPORT_CHANNEL_SIZE},
+    keyboard::{
+        write_other_report_to_host, KeyboardReportMessage, ReportScheduler, REPORT_CHANNEL_SIZE,
+    },
     usb::descriptor::CompositeReportType,
     CONNECTION_STATE,
 };
@@ -40,8 +43,9 @@ pub(crate) async fn ble_communication_task<
 ) {
     // Wait 1 seconds, ensure that gatt server has been started
     Timer::after_secs(1).await;
+    let mut scheduler = ReportScheduler::new();
     loop {
-        let report = keyboard_report_receiver.receive().await;
+        let report = scheduler.next(keyboard_report_receiver).await;
         // Only send the report after the connection is established.
         if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) {
             match report {
@@ -55,6 +59,14 @@ pub(crate) async fn ble_communication_task<
                         Err(e) => error!("Send keyboard report error: {:?}", e),
                     };
                 }
+                KeyboardReportMessage::NkroReport(report) => {
+                    // BLE hid service has the boot keyboard report only
+                    let report = report.to_boot_report();
+                    match ble_keyboard_writer.write_serialize(&report).await {
+                        Ok(()) => {}
+                        Err(e) => error!("Send keyboard report error: {:?}", e),
+                    };
+                }
                 KeyboardReportMessage::CompositeReport(report, report_type) => {
                     match report_type {
                         CompositeReportType::Media => {
@@ -71,6 +83,9 @@ pub(crate) async fn ble_communication_task<
                             )
                             .await
                         }
+                        CompositeReportType::Gamepad => {
+                            debug!("Gamepad report isn't supported over BLE")
+                        }
                         CompositeReportType::None => (),
                     };
                 }
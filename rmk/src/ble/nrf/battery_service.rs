@@ -1,4 +1,8 @@
 use crate::config::BleBatteryConfig;
+use crate::power::{
+    battery_level, battery_percent, notify_power_loss, notify_power_restored, update_battery_level,
+    VoltageDivider, BATTERY_LEVEL_CHANGED,
+};
 use embassy_time::Timer;
 use nrf_softdevice::ble::Connection;
 
@@ -45,20 +49,23 @@ impl<'a> BatteryService {
         Timer::after_secs(1).await;
         BatteryService::check_charging_state(battery_config);
 
+        let mut cutoff_notified = false;
         loop {
             if let Some(ref mut saadc) = battery_config.saadc {
                 let mut buf = [0i16; 1];
                 saadc.sample(&mut buf).await;
                 // We only sampled one ADC channel.
                 let val: u8 = self.get_battery_percent(buf[0], battery_config);
-                match self.battery_level_notify(conn, &val) {
-                    Ok(_) => info!("Battery value: {}", val),
-                    Err(e) => match self.battery_level_set(&val) {
-                        Ok(_) => info!("Battery value set: {}", val),
-                        Err(e2) => {
-                            error!("Battery value notify error: {:?}, set error: {:?}", e, e2)
-                        }
-                    },
+                update_battery_level(val);
+                self.notify_battery_level(conn, &val);
+                if val == 0 && !cutoff_notified {
+                    // The battery is about to be cut off, save everything before the power is lost
+                    notify_power_loss();
+                    cutoff_notified = true;
+                } else if val > 0 && cutoff_notified {
+                    // The battery is charged or the load is reduced
+                    notify_power_restored();
+                    cutoff_notified = false;
                 }
                 if val < 10 {
                     // The battery is low, blink the led!
@@ -78,8 +85,12 @@ impl<'a> BatteryService {
                     }
                 }
             } else {
-                // No SAADC, skip battery check
-                Timer::after_secs(u32::MAX as u64).await;
+                // No SAADC, report the level updated by a battery monitor or a fuel gauge
+                if let Some(val) = battery_level() {
+                    self.notify_battery_level(conn, &val);
+                }
+                BATTERY_LEVEL_CHANGED.wait().await;
+                continue;
             }
 
             // Check charging state
@@ -90,52 +101,47 @@ impl<'a> BatteryService {
         }
     }
 
-    // TODO: Make battery calculation user customizable
+    fn notify_battery_level(&self, conn: &Connection, val: &u8) {
+        match self.battery_level_notify(conn, val) {
+            Ok(_) => info!("Battery value: {}", val),
+            Err(e) => match self.battery_level_set(val) {
+                Ok(_) => info!("Battery value set: {}", val),
+                Err(e2) => error!("Battery value notify error: {:?}, set error: {:?}", e, e2),
+            },
+        }
+    }
+
     fn get_battery_percent(&self, val: i16, battery_config: &BleBatteryConfig<'a>) -> u8 {
         info!("Detected adc value: {:?}", val);
-        // Avoid overflow
-        let val = val as i32;
-
         // According to nRF52840's datasheet, for single_ended saadc:
         // val = v_adc * (gain / reference) * 2^(resolution)
         //
-        // When using default setting, gain = 1/6, reference = 0.6v, resolution = 12bits, so:
-        // val = v_adc * 1137.8
+        // When using default setting, gain = 1/6, reference = 0.6v, resolution = 12bits, so the full scale is 3.6v:
+        // v_adc = val * 3600 / 4096 mV
         //
         // For example, rmk-ble-keyboard uses two resistors 820K and 2M adjusting the v_adc, then,
-        // v_adc = v_bat * measured / total => val = v_bat * 1137.8 * measured / total
-        //
-        // If the battery voltage range is 3.6v ~ 4.2v, the adc val range should be (4096 ~ 4755) * measured / total
-        let mut measured = battery_config.adc_divider_measured as i32;
-        let mut total = battery_config.adc_divider_total as i32;
-        if 500 < val && val < 1000 {
+        // v_adc = v_bat * measured / total
+        let adc_millivolts = (val.max(0) as u32 * 3600 / 4096) as u16;
+        let divider = if 500 < val && val < 1000 {
             // Thing becomes different when using vddh as reference
             // The adc value for vddh pin is actually vddh/5,
             // so we use this rough range to detect vddh
-            measured = 1;
-            total = 5;
-        }
-        if val > 4755_i32 * measured / total {
-            // 4755 ~= 4.2v * 1137.8
-            100_u8
-        } else if val < 4055_i32 * measured / total {
-            // 4096 ~= 3.6v * 1137.8
-            // To simplify the calculation, we use 4055 here
-            0_u8
+            VoltageDivider::new(1, 5)
         } else {
-            ((val * total / measured - 4055) / 7) as u8
-        }
+            VoltageDivider::new(
+                battery_config.adc_divider_measured,
+                battery_config.adc_divider_total,
+            )
+        };
+        battery_percent(
+            divider.battery_millivolts(adc_millivolts),
+            battery_config.battery_curve,
+        )
     }
 }
 
 impl BleServer {
     pub(crate) fn set_battery_value(&self, conn: &Connection, val: &u8) {
-        match self.bas.battery_level_notify(conn, val) {
-            Ok(_) => info!("Battery value: {}", val),
-            Err(e) => match self.bas.battery_level_set(val) {
-                Ok(_) => info!("Battery value set: {}", val),
-                Err(e2) => error!("Battery value notify error: {:?}, set error: {:?}", e, e2),
-            },
-        }
+        self.bas.notify_battery_level(conn, val);
     }
 }
@@ -121,6 +121,8 @@ impl SecurityHandler for MultiBonder {
         info!("on_security_update, new security mode: {:?}", security_mode);
         // Security updated, indicating that the connection is established?
         CONNECTION_STATE.store(true, Ordering::Release);
+        crate::keyboard::notify_host_reconnected();
+        crate::sleep::notify_host_activity();
     }
 
     fn on_bonded(
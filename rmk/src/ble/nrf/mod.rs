@@ -10,12 +10,22 @@ mod vial_service;
 
 use self::server::BleServer;
 use crate::config::BleBatteryConfig;
-use crate::keyboard::{KEYBOARD_REPORT_CHANNEL, REPORT_CHANNEL_SIZE};
+use crate::keyboard::{ReportScheduler, KEYBOARD_REPORT_CHANNEL, REPORT_CHANNEL_SIZE};
 use crate::matrix::MatrixTrait;
+#[cfg(not(feature = "_no_usb"))]
+use crate::output::{output_mode, OutputMode};
+use crate::power::{active_power_settings, POWER_PROFILE_CHANGED};
+use crate::ram_profile::{
+    SD_ATT_MTU, SD_CENTRAL_ROLE_COUNT, SD_CENTRAL_SEC_COUNT, SD_CONN_COUNT, SD_PERIPH_ROLE_COUNT,
+    SD_TX_QUEUE_SIZE,
+};
 use crate::storage::StorageKeys;
 use crate::{
     ble::{
         ble_communication_task,
+        diagnostics::{
+            link_diagnostics, BleLink, LinkStats, HOST_LINK_STATS, RSSI_SAMPLE_INTERVAL_MS,
+        },
         nrf::{
             advertise::{create_advertisement_data, SCAN_DATA},
             bonder::BondInfo,
@@ -32,7 +42,6 @@ use bonder::MultiBonder;
 use core::sync::atomic::{AtomicU8, Ordering};
 use core::{cell::RefCell, mem};
 use embassy_executor::Spawner;
-use embassy_futures::join::join;
 use embassy_futures::select::{select, select4, Either4};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Receiver};
 use embassy_time::Timer;
@@ -53,8 +62,9 @@ use vial_service::VialReaderWriter;
 #[cfg(not(feature = "_no_usb"))]
 use {
     crate::{
+        output::wait_for_usb_output_end,
         run_usb_keyboard,
-        usb::{wait_for_usb_enabled, wait_for_usb_suspend, UsbState, USB_STATE},
+        usb::{wait_for_usb_enabled, UsbState, USB_STATE},
         KeyboardUsbDevice,
     },
     embassy_futures::select::{select3, Either3},
@@ -77,6 +87,7 @@ pub static SOFTWARE_VBUS: OnceCell<SoftwareVbusDetect> = OnceCell::new();
 pub(crate) async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) -> ! {
     use nrf_softdevice::SocEvent;
 
+    use crate::power::{notify_power_loss, update_power_source};
     use crate::usb::{UsbState, USB_STATE};
 
     // Enable dcdc-mode, reduce power consumption
@@ -89,6 +100,8 @@ pub(crate) async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) ->
         );
     };
 
+    enable_power_failure_warning();
+
     // Enable USB event in softdevice
     unsafe {
         nrf_softdevice::raw::sd_power_usbpwrrdy_enable(1);
@@ -105,18 +118,22 @@ pub(crate) async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) ->
         software_vbus.detected(true);
         USB_STATE.store(UsbState::Enabled as u8, Ordering::Relaxed);
     }
+    update_power_source(usb_reg & 1 == 1);
 
     sd.run_with_callback(|event: SocEvent| {
         match event {
             SocEvent::PowerUsbRemoved => {
                 software_vbus.detected(false);
                 USB_STATE.store(UsbState::Disabled as u8, Ordering::Relaxed);
+                update_power_source(false);
             }
             SocEvent::PowerUsbDetected => {
                 software_vbus.detected(true);
                 USB_STATE.store(UsbState::Enabled as u8, Ordering::Relaxed);
+                update_power_source(true);
             }
             SocEvent::PowerUsbPowerReady => software_vbus.ready(),
+            SocEvent::PowerFailureWarning => notify_power_loss(),
             _ => {}
         };
     })
@@ -127,13 +144,85 @@ pub(crate) async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) ->
 #[cfg(feature = "_no_usb")]
 #[embassy_executor::task]
 pub(crate) async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) -> ! {
+    use crate::power::notify_power_loss;
+    use nrf_softdevice::SocEvent;
+
     // Enable dcdc-mode, reduce power consumption
     unsafe {
         nrf_softdevice::raw::sd_power_dcdc_mode_set(
             nrf_softdevice::raw::NRF_POWER_DCDC_MODES_NRF_POWER_DCDC_ENABLE as u8,
         );
     };
-    sd.run().await
+    enable_power_failure_warning();
+
+    sd.run_with_callback(|event: SocEvent| {
+        if let SocEvent::PowerFailureWarning = event {
+            notify_power_loss();
+        }
+    })
+    .await
+}
+
+/// Enable the power failure comparator, softdevice emits `PowerFailureWarning` when the supply drops below 2.8V
+fn enable_power_failure_warning() {
+    unsafe {
+        nrf_softdevice::raw::sd_power_pof_threshold_set(
+            nrf_softdevice::raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V28 as u8,
+        );
+        nrf_softdevice::raw::sd_power_pof_enable(1);
+    }
+}
+
+/// Power the chip off, the chip is reset when any of `wake_pins` reaches the wake level.
+///
+/// `drive_pins` are kept driven to the wake level, so that a key between a drive pin and a wake pin can pull the wake pin.
+/// Pins are numbered as `32 * port + pin`.
+pub(crate) fn system_off(wake_pins: &[u8], drive_pins: &[u8], wake_high: bool) -> ! {
+    use embassy_nrf::pac::gpio::{vals, Gpio};
+
+    fn port(pin: u8) -> Gpio {
+        #[cfg(any(feature = "nrf52840_ble", feature = "nrf52833_ble"))]
+        if pin >= 32 {
+            return embassy_nrf::pac::P1;
+        }
+        embassy_nrf::pac::P0
+    }
+
+    for &pin in drive_pins {
+        let gpio = port(pin);
+        let n = (pin % 32) as usize;
+        if wake_high {
+            gpio.outset().write(|w| w.set_pin(n, true));
+        } else {
+            gpio.outclr().write(|w| w.set_pin(n, true));
+        }
+        gpio.pin_cnf(n).write(|w| {
+            w.set_dir(vals::Dir::OUTPUT);
+            w.set_input(vals::Input::DISCONNECT);
+        });
+    }
+    for &pin in wake_pins {
+        port(pin).pin_cnf((pin % 32) as usize).write(|w| {
+            w.set_dir(vals::Dir::INPUT);
+            w.set_input(vals::Input::CONNECT);
+            if wake_high {
+                w.set_pull(vals::Pull::PULLDOWN);
+                w.set_sense(vals::Sense::HIGH);
+            } else {
+                w.set_pull(vals::Pull::PULLUP);
+                w.set_sense(vals::Sense::LOW);
+            }
+        });
+    }
+    if wake_pins.is_empty() {
+        warn!("No wake pin, the keyboard can only be woken up by reset");
+    }
+
+    unsafe { raw::sd_power_system_off() };
+    // `sd_power_system_off` doesn't return unless the softdevice is disabled
+    loop {
+        cortex_m::asm::wfe();
+    }
 }
 
 /// Helper macro for reading storage config
@@ -165,20 +254,22 @@ pub(crate) fn nrf_ble_config(keyboard_name: &str) -> Config {
             // accuracy: raw::NRF_CLOCK_LF_ACCURACY_20_PPM as u8,
         }),
         conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 6,
+            conn_count: SD_CONN_COUNT,
             event_length: 24,
         }),
-        conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 256 }),
+        conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
+            att_mtu: SD_ATT_MTU,
+        }),
         gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
             attr_tab_size: 2048,
         }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: 1,
-            periph_role_count: 4,
+            periph_role_count: SD_PERIPH_ROLE_COUNT,
             #[cfg(not(any(feature = "nrf52810_ble", feature = "nrf52811_ble")))]
-            central_role_count: 4,
+            central_role_count: SD_CENTRAL_ROLE_COUNT,
             #[cfg(not(any(feature = "nrf52810_ble", feature = "nrf52811_ble")))]
-            central_sec_count: 2,
+            central_sec_count: SD_CENTRAL_SEC_COUNT,
             #[cfg(not(any(feature = "nrf52810_ble", feature = "nrf52811_ble")))]
             _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
         }),
@@ -192,10 +283,10 @@ pub(crate) fn nrf_ble_config(keyboard_name: &str) -> Config {
             ),
         }),
         conn_gattc: Some(raw::ble_gattc_conn_cfg_t {
-            write_cmd_tx_queue_size: 4,
+            write_cmd_tx_queue_size: SD_TX_QUEUE_SIZE,
         }),
         conn_gatts: Some(raw::ble_gatts_conn_cfg_t {
-            hvn_tx_queue_size: 4,
+            hvn_tx_queue_size: SD_TX_QUEUE_SIZE,
         }),
         ..Default::default()
     }
@@ -249,10 +340,22 @@ pub async fn initialize_nrf_ble_keyboard_and_run<
             .expect("Failed to start softdevice task")
     };
 
+    crate::safe_mode::check_safe_mode(&mut matrix, keyboard_config.storage_config.safe_mode_key)
+        .await;
+
     // Flash and keymap configuration
     let flash = Flash::take(sd);
     let mut storage = Storage::new(flash, default_keymap, keyboard_config.storage_config).await;
-    let keymap = RefCell::new(KeyMap::new_from_storage(default_keymap, Some(&mut storage)).await);
+    let keymap = RefCell::new(
+        KeyMap::new_from_storage(
+            default_keymap,
+            keyboard_config.encoder_map,
+            keyboard_config.macros,
+            keyboard_config.key_remap,
+            Some(&mut storage),
+        )
+        .await,
+    );
 
     let mut buf: [u8; 128] = [0; 128];
 
@@ -308,6 +411,7 @@ pub async fn initialize_nrf_ble_keyboard_and_run<
         &keymap,
         &keyboard_report_sender,
         keyboard_config.behavior_config,
+        keyboard_config.mouse_config,
     );
     #[cfg(not(feature = "_no_usb"))]
     let mut usb_device = KeyboardUsbDevice::new(usb_driver, keyboard_config.usb_config);
@@ -346,10 +450,11 @@ pub async fn initialize_nrf_ble_keyboard_and_run<
                     &mut vial_service,
                     &keyboard_report_receiver,
                 );
-                if CONNECTION_TYPE.load(Ordering::Relaxed) == 0 {
+                if output_mode() != OutputMode::Ble {
                     info!("Running USB keyboard");
-                    // USB is connected, connection_type is USB, then run USB keyboard
-                    match select3(usb_fut, wait_for_usb_suspend(), update_profile(bonder)).await {
+                    // USB is connected, and the output isn't forced to BLE, then run USB keyboard
+                    match select3(usb_fut, wait_for_usb_output_end(), update_profile(bonder)).await
+                    {
                         Either3::Third(_) => {
                             Timer::after_millis(10).await;
                             continue;
@@ -410,6 +515,17 @@ pub async fn initialize_nrf_ble_keyboard_and_run<
                         }
                     }
                 }
+            } else if output_mode() == OutputMode::Usb {
+                // The output is forced to USB, don't advertise BLE and wait for USB connection
+                let dummy_task = run_dummy_keyboard(
+                    &mut keyboard,
+                    &mut matrix,
+                    &mut storage,
+                    &keyboard_report_receiver,
+                );
+                info!("Waiting for USB, BLE is disabled by output mode");
+                select(wait_for_status_change(bonder), dummy_task).await;
+                Timer::after_millis(10).await;
             } else {
                 // USB isn't connected, wait for any of BLE/USB connection
                 let dummy_task = run_dummy_keyboard(
@@ -522,22 +638,31 @@ pub(crate) async fn set_conn_params(conn: &Connection) {
                 },
             );
             debug!("Set conn params result: {:?}", re);
+        }
 
-            embassy_time::Timer::after_millis(5000).await;
+        embassy_time::Timer::after_millis(5000).await;
 
-            // Setting the conn param the second time ensures that we have best performance on all platforms
-            let re = sd_ble_gap_conn_param_update(
-                conn_handle,
-                &raw::ble_gap_conn_params_t {
-                    min_conn_interval: 6,
-                    max_conn_interval: 6,
-                    slave_latency: 99,
-                    conn_sup_timeout: 500, // timeout: 5s
-                },
-            );
-            debug!("Set conn params result: {:?}", re);
+        // Setting the conn param the second time ensures that we have best performance on all platforms.
+        // The interval follows the active power profile, and is updated when the profile is changed or the keyboard sleeps
+        loop {
+            let interval = active_power_settings().ble_conn_interval;
+            let re = unsafe {
+                sd_ble_gap_conn_param_update(
+                    conn_handle,
+                    &raw::ble_gap_conn_params_t {
+                        min_conn_interval: interval,
+                        max_conn_interval: interval,
+                        slave_latency: 99,
+                        conn_sup_timeout: 500, // timeout: 5s
+                    },
+                )
+            };
+            debug!("Set conn params result: {:?}, interval: {}", re, interval);
+            POWER_PROFILE_CHANGED.wait().await;
         }
     }
+    // The connection is closed, wait for the GATT server to exit
+    core::future::pending::<()>().await
 }
 
 // Dummy keyboard service is used to monitoring keys when there's no actual connection.
@@ -569,8 +694,9 @@ pub(crate) async fn run_dummy_keyboard<
     let keyboard_fut = keyboard.run();
     let storage_fut = storage.run();
     let dummy_communication = async {
+        let mut scheduler = ReportScheduler::new();
         loop {
-            keyboard_report_receiver.receive().await;
+            scheduler.next(keyboard_report_receiver).await;
             warn!("Dummy service receives")
         }
     };
@@ -588,8 +714,8 @@ pub(crate) async fn run_dummy_keyboard<
 #[cfg(not(feature = "_no_usb"))]
 // Wait for USB enabled or BLE state changed
 pub(crate) async fn wait_for_status_change(bonder: &MultiBonder) {
-    if CONNECTION_TYPE.load(Ordering::Relaxed) == 0 {
-        // Connection type is USB, USB has higher priority
+    if output_mode() != OutputMode::Ble {
+        // USB has higher priority unless the output is forced to BLE
         select(wait_for_usb_enabled(), update_profile(bonder)).await;
     } else {
         // Connection type is BLE, so we don't consider USB
@@ -651,12 +777,14 @@ pub(crate) async fn run_ble_keyboard<
     );
     let storage_fut = storage.run();
     let set_conn_param = set_conn_params(&conn);
+    let rssi_fut = sample_rssi(&conn, &HOST_LINK_STATS);
+    HOST_LINK_STATS.on_connected();
 
     // Exit if anyone of those futures exits
     match select4(
-        select(matrix_fut, join(ble_fut, set_conn_param)),
+        select(matrix_fut, select(ble_fut, set_conn_param)),
         select(ble_communication_task, keyboard_fut),
-        select(battery_fut, led_fut),
+        select(battery_fut, select(led_fut, rssi_fut)),
         select(vial_task, storage_fut),
     )
     .await
@@ -666,4 +794,23 @@ pub(crate) async fn run_ble_keyboard<
         Either4::Third(_) => error!("Battery task or led task exited"),
         Either4::Fourth(_) => error!("Storage task exited"),
     }
+
+    let stats = link_diagnostics(BleLink::Host);
+    info!(
+        "BLE host link closed, sent: {}, failed: {}, reconnections: {}",
+        stats.sent, stats.failed, stats.reconnections
+    );
+    HOST_LINK_STATS.on_disconnected();
+}
+
+/// Sample the RSSI of the connection periodically and save it to the link statistics
+pub(crate) async fn sample_rssi(conn: &Connection, stats: &LinkStats) -> ! {
+    conn.start_rssi();
+    loop {
+        Timer::after_millis(RSSI_SAMPLE_INTERVAL_MS).await;
+        if let Some(rssi) = conn.rssi() {
+            debug!("Current RSSI: {} dBm", rssi);
+            stats.update_rssi(rssi);
+        }
+    }
 }
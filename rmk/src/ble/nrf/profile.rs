@@ -8,6 +8,7 @@ use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channe
 
 use crate::{
     ble::nrf::{ACTIVE_PROFILE, BONDED_DEVICE_NUM},
+    output::OutputMode,
     storage::{FlashOperationMessage, FLASH_CHANNEL},
     CONNECTION_TYPE,
 };
@@ -24,6 +25,7 @@ pub(crate) enum BleProfileAction {
     NextProfile,
     ClearProfile,
     ToggleConnection,
+    SetOutput(OutputMode),
 }
 
 // Wait for profile switch action and update the active profile
@@ -44,9 +46,12 @@ pub(crate) async fn update_profile(bonder: &MultiBonder) {
                 info!("Switch to BLE profile: {}", profile);
             }
             BleProfileAction::PreviousProfile => {
-                // Get current profile number and plus 1
                 let mut profile = ACTIVE_PROFILE.load(Ordering::SeqCst);
-                profile = if profile == 0 { 7 } else { profile - 1 };
+                profile = if profile == 0 {
+                    BONDED_DEVICE_NUM as u8 - 1
+                } else {
+                    profile - 1
+                };
                 ACTIVE_PROFILE.store(profile, Ordering::SeqCst);
                 FLASH_CHANNEL
                     .send(FlashOperationMessage::ActiveBleProfile(profile))
@@ -72,12 +77,26 @@ pub(crate) async fn update_profile(bonder: &MultiBonder) {
             }
             BleProfileAction::ToggleConnection => {
                 let current = CONNECTION_TYPE.load(Ordering::SeqCst);
-                let updated = 1 - current;
+                let updated = if current == OutputMode::Ble as u8 {
+                    OutputMode::Auto as u8
+                } else {
+                    OutputMode::Ble as u8
+                };
                 CONNECTION_TYPE.store(updated, Ordering::SeqCst);
                 FLASH_CHANNEL
                     .send(FlashOperationMessage::ConnectionType(updated))
                     .await;
             }
+            BleProfileAction::SetOutput(mode) => {
+                if CONNECTION_TYPE.load(Ordering::SeqCst) == mode as u8 {
+                    continue;
+                }
+                CONNECTION_TYPE.store(mode as u8, Ordering::SeqCst);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::ConnectionType(mode as u8))
+                    .await;
+                info!("Switch output mode: {:?}", mode);
+            }
         }
         break;
     }
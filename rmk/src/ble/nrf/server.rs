@@ -4,6 +4,7 @@ use super::{
     hid_service::{HidService, HidServiceEvent},
     vial_service::{BleVialService, VialServiceEvent},
 };
+use crate::ble::diagnostics::HOST_LINK_STATS;
 use crate::config::KeyboardUsbConfig;
 use crate::{
     ble::device_info::{DeviceInformation, PnPID, VidSource},
@@ -42,7 +43,9 @@ impl<'a, const N: usize> HidWriterWrapper for BleHidWriter<'a, N> {
     }
 
     async fn write(&mut self, report: &[u8]) -> Result<(), HidError> {
-        gatt_server::notify_value(self.conn, self.handle, report).map_err(|e| {
+        let result = gatt_server::notify_value(self.conn, self.handle, report);
+        HOST_LINK_STATS.record(&result);
+        result.map_err(|e| {
             error!("Send ble report error: {:?}", e);
             match e {
                 gatt_server::NotifyValueError::Disconnected => HidError::BleDisconnected,
@@ -151,6 +154,8 @@ impl gatt_server::Server for BleServer {
         _offset: usize,
         data: &[u8],
     ) -> Option<Self::Event> {
+        // Any write means the host is awake
+        crate::sleep::notify_host_activity();
         if let Some(event) = self.hid.on_write(handle, data) {
             match event {
                 HidServiceEvent::InputKeyboardCccdWrite
@@ -0,0 +1,71 @@
+//! Shared buses
+//!
+//! Keyboards often have several devices on one bus, for example an OLED, a fuel gauge, an IO expander and a haptic driver on the same I2C.
+//! [`SharedI2cBus`] owns the bus peripheral and hands out [`I2cDevice`]s. Every device implements `embedded_hal_async::i2c::I2c`,
+//! and locks the bus for a whole transaction, so drivers of different devices can run in different tasks.
+//!
+//! ```rust
+//! static I2C_BUS: StaticCell<SharedI2cBus<NoopRawMutex, Twim<'static, TWISPI0>>> = StaticCell::new();
+//! let bus = I2C_BUS.init(SharedI2cBus::new(Twim::new(p.TWISPI0, Irqs, p.P0_17, p.P0_20, Default::default())));
+//! let oled = Ssd1306Async::new(I2CDisplayInterface::new(bus.device()), ...);
+//! let fuel_gauge = Max17048::new(bus.device());
+//! ```
+//!
+//! [`SharedSpiBus`] works in the same way, each [`SpiDevice`] owns its CS pin, which is asserted only during the device's transaction.
+//! If devices need different SPI settings(e.g. frequency), use [`SharedSpiBus::device_with_config`].
+
+use embassy_embedded_hal::SetConfig;
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+
+pub use embassy_embedded_hal::shared_bus::asynch::{
+    i2c::I2cDevice,
+    spi::{SpiDevice, SpiDeviceWithConfig},
+};
+
+/// An I2C bus shared by multiple devices
+pub struct SharedI2cBus<M: RawMutex, BUS> {
+    bus: Mutex<M, BUS>,
+}
+
+impl<M: RawMutex, BUS> SharedI2cBus<M, BUS> {
+    pub const fn new(bus: BUS) -> Self {
+        Self {
+            bus: Mutex::new(bus),
+        }
+    }
+
+    /// Create a device on the bus, devices should use different addresses
+    pub fn device(&self) -> I2cDevice<'_, M, BUS> {
+        I2cDevice::new(&self.bus)
+    }
+}
+
+/// A SPI bus shared by multiple devices, each device has its own CS pin
+pub struct SharedSpiBus<M: RawMutex, BUS> {
+    bus: Mutex<M, BUS>,
+}
+
+impl<M: RawMutex, BUS> SharedSpiBus<M, BUS> {
+    pub const fn new(bus: BUS) -> Self {
+        Self {
+            bus: Mutex::new(bus),
+        }
+    }
+
+    /// Create a device on the bus, which uses the current bus settings
+    pub fn device<CS>(&self, cs: CS) -> SpiDevice<'_, M, BUS, CS> {
+        SpiDevice::new(&self.bus, cs)
+    }
+
+    /// Create a device on the bus, the bus is reconfigured with `config` before each transaction of this device
+    pub fn device_with_config<CS>(
+        &self,
+        cs: CS,
+        config: BUS::Config,
+    ) -> SpiDeviceWithConfig<'_, M, BUS, CS>
+    where
+        BUS: SetConfig,
+    {
+        SpiDeviceWithConfig::new(&self.bus, cs, config)
+    }
+}
@@ -0,0 +1,275 @@
+//! Calculator overlay
+//!
+//! Press a key with `User16` to start the calculator. While it's active, digits, `.`, `+`, `-`, `*` and `/`
+//! (on the main keys or the keypad) are appended to the expression instead of being sent to the host,
+//! `Backspace` deletes the last character, and `Enter` or `=` evaluates the expression and types the result on the host.
+//! `Escape` or `User16` closes the calculator without typing anything. Other keys are ignored, modifiers and layer keys still work,
+//! so a numpad layer can be used for the input.
+//!
+//! Numbers have 6 decimal places, `*` and `/` are evaluated before `+` and `-`.
+//! The expression and evaluation errors are shown on the display, see [`calculator_state`].
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::String;
+
+use crate::keycode::KeyCode;
+
+/// Max length of the expression
+pub const EXPRESSION_LEN: usize = 32;
+
+/// Max length of the formatted result
+pub const RESULT_LEN: usize = 24;
+
+/// Scale of the fixed-point numbers, 6 decimal places
+const SCALE: i64 = 1_000_000;
+const DECIMAL_PLACES: u32 = 6;
+
+/// Error of evaluating the expression
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CalcError {
+    /// The expression is malformed, e.g. `1+` or `1..2`
+    Syntax,
+    /// Divided by zero
+    DivideByZero,
+    Overflow,
+}
+
+/// State of the calculator, which is rendered by the display
+#[derive(Clone, Debug)]
+pub struct CalculatorState {
+    pub active: bool,
+    pub expression: String<EXPRESSION_LEN>,
+    /// Error of the last evaluation, cleared when the expression is changed
+    pub error: Option<CalcError>,
+}
+
+static CALCULATOR: Mutex<CriticalSectionRawMutex, RefCell<CalculatorState>> =
+    Mutex::new(RefCell::new(CalculatorState {
+        active: false,
+        expression: String::new(),
+        error: None,
+    }));
+
+/// Whether the calculator is active
+pub fn calculator_active() -> bool {
+    CALCULATOR.lock(|c| c.borrow().active)
+}
+
+/// Get a snapshot of the calculator state
+pub fn calculator_state() -> CalculatorState {
+    CALCULATOR.lock(|c| c.borrow().clone())
+}
+
+/// Start or close the calculator, the expression is cleared
+pub(crate) fn toggle_calculator() {
+    CALCULATOR.lock(|c| {
+        let mut c = c.borrow_mut();
+        c.active = !c.active;
+        c.expression.clear();
+        c.error = None;
+        info!("Calculator active: {}", c.active);
+    });
+    crate::display::request_redraw();
+}
+
+/// Input of the calculator converted from a key
+enum CalcInput {
+    Char(char),
+    Backspace,
+    Evaluate,
+    Close,
+}
+
+fn calc_input(key: KeyCode, shifted: bool) -> Option<CalcInput> {
+    let input = match key {
+        KeyCode::Kp1 => CalcInput::Char('1'),
+        KeyCode::Kp2 => CalcInput::Char('2'),
+        KeyCode::Kp3 => CalcInput::Char('3'),
+        KeyCode::Kp4 => CalcInput::Char('4'),
+        KeyCode::Kp5 => CalcInput::Char('5'),
+        KeyCode::Kp6 => CalcInput::Char('6'),
+        KeyCode::Kp7 => CalcInput::Char('7'),
+        KeyCode::Kp8 => CalcInput::Char('8'),
+        KeyCode::Kp9 => CalcInput::Char('9'),
+        KeyCode::Kp0 => CalcInput::Char('0'),
+        KeyCode::KpDot => CalcInput::Char('.'),
+        KeyCode::KpPlus => CalcInput::Char('+'),
+        KeyCode::KpMinus => CalcInput::Char('-'),
+        KeyCode::KpAsterisk => CalcInput::Char('*'),
+        KeyCode::KpSlash => CalcInput::Char('/'),
+        KeyCode::Backspace => CalcInput::Backspace,
+        KeyCode::Enter | KeyCode::KpEnter => CalcInput::Evaluate,
+        KeyCode::Equal if !shifted => CalcInput::Evaluate,
+        KeyCode::Escape => CalcInput::Close,
+        _ => match key.to_ascii(shifted)? {
+            c @ (b'0'..=b'9' | b'.' | b'+' | b'-' | b'*' | b'/') => CalcInput::Char(c as char),
+            _ => return None,
+        },
+    };
+    Some(input)
+}
+
+/// Process a key pressed while the calculator is active.
+///
+/// Returns the result to be typed on the host if the expression is evaluated successfully, the calculator is closed then.
+pub(crate) fn process_calculator_key(key: KeyCode, shifted: bool) -> Option<String<RESULT_LEN>> {
+    let input = calc_input(key, shifted)?;
+    let result = CALCULATOR.lock(|c| {
+        let mut c = c.borrow_mut();
+        match input {
+            CalcInput::Char(ch) => {
+                c.error = None;
+                if c.expression.push(ch).is_err() {
+                    warn!("Calculator expression is too long");
+                }
+                None
+            }
+            CalcInput::Backspace => {
+                c.error = None;
+                c.expression.pop();
+                None
+            }
+            CalcInput::Evaluate => match evaluate(&c.expression) {
+                Ok(v) => {
+                    c.active = false;
+                    c.expression.clear();
+                    Some(format_number(v))
+                }
+                Err(e) => {
+                    warn!("Failed to evaluate calculator expression: {:?}", e);
+                    c.error = Some(e);
+                    None
+                }
+            },
+            CalcInput::Close => {
+                c.active = false;
+                c.expression.clear();
+                c.error = None;
+                None
+            }
+        }
+    });
+    crate::display::request_redraw();
+    result
+}
+
+/// Parse a number starting at `*i`, the result is scaled by `SCALE`
+fn parse_number(s: &[u8], i: &mut usize) -> Result<i64, CalcError> {
+    let negative = s.get(*i) == Some(&b'-');
+    if negative {
+        *i += 1;
+    }
+    let start = *i;
+    let mut int: i64 = 0;
+    while let Some(d) = s.get(*i).filter(|c| c.is_ascii_digit()) {
+        int = int
+            .checked_mul(10)
+            .and_then(|v| v.checked_add((d - b'0') as i64))
+            .ok_or(CalcError::Overflow)?;
+        *i += 1;
+    }
+    let mut frac: i64 = 0;
+    if s.get(*i) == Some(&b'.') {
+        *i += 1;
+        let mut scale = SCALE;
+        while let Some(d) = s.get(*i).filter(|c| c.is_ascii_digit()) {
+            // Digits beyond the precision are ignored
+            scale /= 10;
+            frac += (d - b'0') as i64 * scale;
+            *i += 1;
+        }
+    }
+    // No digit, or only a dot
+    if *i == start || (*i == start + 1 && s[start] == b'.') {
+        return Err(CalcError::Syntax);
+    }
+    let v = int
+        .checked_mul(SCALE)
+        .and_then(|v| v.checked_add(frac))
+        .ok_or(CalcError::Overflow)?;
+    Ok(if negative { -v } else { v })
+}
+
+/// Evaluate an expression of fixed-point numbers and `+`, `-`, `*`, `/`
+fn evaluate(expression: &str) -> Result<i64, CalcError> {
+    let s = expression.as_bytes();
+    let mut i = 0;
+    let mut sum: i64 = 0;
+    let mut term = parse_number(s, &mut i)?;
+    while let Some(&op) = s.get(i) {
+        i += 1;
+        let n = parse_number(s, &mut i)?;
+        match op {
+            b'+' => {
+                sum = sum.checked_add(term).ok_or(CalcError::Overflow)?;
+                term = n;
+            }
+            b'-' => {
+                sum = sum.checked_add(term).ok_or(CalcError::Overflow)?;
+                term = -n;
+            }
+            b'*' => {
+                term = i64::try_from(term as i128 * n as i128 / SCALE as i128)
+                    .map_err(|_| CalcError::Overflow)?;
+            }
+            b'/' => {
+                if n == 0 {
+                    return Err(CalcError::DivideByZero);
+                }
+                term = i64::try_from(term as i128 * SCALE as i128 / n as i128)
+                    .map_err(|_| CalcError::Overflow)?;
+            }
+            _ => return Err(CalcError::Syntax),
+        }
+    }
+    sum.checked_add(term).ok_or(CalcError::Overflow)
+}
+
+/// Format a fixed-point number, trailing zeros of the fraction are removed
+fn format_number(v: i64) -> String<RESULT_LEN> {
+    let mut text = String::new();
+    let abs = v.unsigned_abs();
+    let int = abs / SCALE as u64;
+    let mut frac = abs % SCALE as u64;
+    if v < 0 {
+        text.push('-').ok();
+    }
+    write!(text, "{}", int).ok();
+    if frac != 0 {
+        let mut places = DECIMAL_PLACES as usize;
+        while frac % 10 == 0 {
+            frac /= 10;
+            places -= 1;
+        }
+        write!(text, ".{:0width$}", frac, width = places).ok();
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn calc(expression: &str) -> Result<String<RESULT_LEN>, CalcError> {
+        evaluate(expression).map(format_number)
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(calc("1+2*3").unwrap().as_str(), "7");
+        assert_eq!(calc("10-4/8").unwrap().as_str(), "9.5");
+        assert_eq!(calc("-1.5*2-0.25").unwrap().as_str(), "-3.25");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(calc("1+"), Err(CalcError::Syntax));
+        assert_eq!(calc("."), Err(CalcError::Syntax));
+        assert_eq!(calc("2/0"), Err(CalcError::DivideByZero));
+        assert_eq!(calc("99999999999999*99999999"), Err(CalcError::Overflow));
+    }
+}
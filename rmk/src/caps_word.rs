@@ -0,0 +1,96 @@
+use crate::keycode::KeyCode;
+
+/// Caps Word: capitalize letters until the end of the current word.
+///
+/// While Caps Word is active, letters and `-`(which becomes `_`) are sent with shift.
+/// Digits, backspace and delete continue the word, any other key ends it.
+///
+/// Shift Word shifts the whole next word instead: every key which types a char, including digits and punctuation, is sent with shift.
+/// It ends only at a word boundary, such as space or enter. Other keys, like backspace or arrows, are sent without shift and keep it active.
+#[derive(Default)]
+pub(crate) struct CapsWord {
+    active: bool,
+    /// Whether it's Shift Word rather than Caps Word
+    shift_word: bool,
+}
+
+/// Whether the key separates words, which ends both Caps Word and Shift Word
+fn is_word_boundary(key: KeyCode) -> bool {
+    matches!(
+        key,
+        KeyCode::Space | KeyCode::Enter | KeyCode::KpEnter | KeyCode::Tab | KeyCode::Escape
+    )
+}
+
+impl CapsWord {
+    /// Whether Caps Word or Shift Word is active
+    pub(crate) fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub(crate) fn toggle(&mut self) {
+        self.active = !(self.active && !self.shift_word);
+        self.shift_word = false;
+    }
+
+    /// Toggle Shift Word, an active Caps Word is switched to Shift Word
+    pub(crate) fn toggle_shift_word(&mut self) {
+        self.active = !(self.active && self.shift_word);
+        self.shift_word = true;
+    }
+
+    pub(crate) fn deactivate(&mut self) {
+        self.active = false;
+        self.shift_word = false;
+    }
+
+    /// Whether the key should be sent with shift
+    pub(crate) fn should_shift(&self, key: KeyCode) -> bool {
+        if self.shift_word {
+            !is_word_boundary(key) && key.to_ascii(false).is_some()
+        } else {
+            (KeyCode::A <= key && key <= KeyCode::Z) || key == KeyCode::Minus
+        }
+    }
+
+    /// Whether the key ends the current word.
+    ///
+    /// Modifiers are considered as a part of a word, so that pressing shift doesn't end it.
+    pub(crate) fn ends_word(&self, key: KeyCode) -> bool {
+        if key.is_modifier() {
+            false
+        } else if is_word_boundary(key) {
+            true
+        } else if self.shift_word {
+            false
+        } else {
+            !(self.should_shift(key)
+                || (KeyCode::Kc1 <= key && key <= KeyCode::Kc0)
+                || key == KeyCode::Backspace
+                || key == KeyCode::Delete)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_word_boundary() {
+        let mut caps_word = CapsWord::default();
+        caps_word.toggle();
+        assert!(caps_word.should_shift(KeyCode::A) && !caps_word.should_shift(KeyCode::Kc1));
+        assert!(!caps_word.ends_word(KeyCode::Kc1) && !caps_word.ends_word(KeyCode::LShift));
+        assert!(caps_word.ends_word(KeyCode::Dot) && caps_word.ends_word(KeyCode::Space));
+
+        caps_word.toggle_shift_word();
+        assert!(caps_word.is_active());
+        assert!(caps_word.should_shift(KeyCode::Kc1) && caps_word.should_shift(KeyCode::Dot));
+        assert!(!caps_word.should_shift(KeyCode::Left) && !caps_word.ends_word(KeyCode::Left));
+        assert!(caps_word.ends_word(KeyCode::Space) && caps_word.ends_word(KeyCode::Enter));
+
+        caps_word.toggle_shift_word();
+        assert!(!caps_word.is_active());
+    }
+}
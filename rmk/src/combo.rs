@@ -0,0 +1,129 @@
+//! Combos: pressing several keys at the same time triggers another action
+//!
+//! Combos are defined by the (row, col) of the keys in a compile-time table, for example J+K → Escape:
+//!
+//! ```rust,ignore
+//! const COMBOS: &[Combo] = &[
+//!     Combo::new(&[(2, 6), (2, 7)], k!(Escape), None),
+//!     // Only on layer 1
+//!     Combo::new(&[(2, 1), (2, 2), (2, 3)], k!(CapsLock), Some(1)),
+//! ];
+//!
+//! let behavior_config = BehaviorConfig {
+//!     combo: ComboConfig {
+//!         timeout: Duration::from_millis(50),
+//!         combos: COMBOS,
+//!     },
+//!     ..Default::default()
+//! };
+//! ```
+//!
+//! When a key of a combo is pressed, the following key events are buffered until all keys of a combo are pressed,
+//! `timeout` expires, or a key which can't form a combo is pressed or released.
+//! If the buffered keys are exactly the keys of a combo, the action of the combo is triggered instead of the keys,
+//! otherwise the keys are processed with the keymap as usual.
+//! The action of the combo is released as soon as any key of the combo is released.
+//!
+//! `ComboOn`, `ComboOff` and `ComboToggle` keycodes enable or disable combos.
+
+use crate::action::KeyAction;
+
+/// Max number of keys in a combo
+pub const MAX_COMBO_KEYS: usize = 8;
+
+/// Keys which trigger an action when they're pressed at the same time
+#[derive(Clone, Copy, Debug)]
+pub struct Combo {
+    /// (row, col) of the keys, at most [`MAX_COMBO_KEYS`] keys
+    pub keys: &'static [(u8, u8)],
+    /// The action triggered by the combo, tap-hold actions are not supported
+    pub action: KeyAction,
+    /// The combo works only when this layer is the highest active layer, `None` for all layers
+    pub layer: Option<u8>,
+}
+
+impl Combo {
+    pub const fn new(keys: &'static [(u8, u8)], action: KeyAction, layer: Option<u8>) -> Self {
+        Self {
+            keys,
+            action,
+            layer,
+        }
+    }
+
+    /// Index of the key at (row, col) in the combo
+    pub(crate) fn key_index(&self, row: u8, col: u8) -> Option<usize> {
+        self.keys.iter().position(|&k| k == (row, col))
+    }
+
+    fn is_available(&self, layer: u8) -> bool {
+        self.keys.len() >= 2
+            && self.keys.len() <= MAX_COMBO_KEYS
+            && self.layer.is_none_or(|l| l == layer)
+    }
+}
+
+/// Result of matching buffered keys with the combo table
+pub(crate) struct ComboMatch {
+    /// The combo whose keys are exactly the buffered keys
+    pub(crate) exact: Option<usize>,
+    /// Whether there's a combo with more keys, which contains all buffered keys
+    pub(crate) partial: bool,
+}
+
+/// Match buffered keys with available combos on `layer`, the order of the keys doesn't matter
+pub(crate) fn match_combos(combos: &[Combo], layer: u8, keys: &[(u8, u8)]) -> ComboMatch {
+    let mut result = ComboMatch {
+        exact: None,
+        partial: false,
+    };
+    for (i, combo) in combos.iter().enumerate() {
+        if !combo.is_available(layer) || !keys.iter().all(|k| combo.keys.contains(k)) {
+            continue;
+        }
+        if combo.keys.len() == keys.len() {
+            result.exact = result.exact.or(Some(i));
+        } else if combo.keys.len() > keys.len() {
+            result.partial = true;
+        }
+    }
+    result
+}
+
+/// A triggered combo, whose keys are not all released
+pub(crate) struct ActiveCombo {
+    /// Index in the combo table
+    pub(crate) index: usize,
+    /// Bit `i` is set if `keys[i]` of the combo is still pressed
+    pub(crate) held: u8,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::k;
+
+    const COMBOS: &[Combo] = &[
+        Combo::new(&[(0, 0), (0, 1)], k!(Escape), None),
+        Combo::new(&[(0, 0), (0, 1), (0, 2)], k!(Tab), None),
+        Combo::new(&[(1, 0), (1, 1)], k!(Enter), Some(1)),
+    ];
+
+    #[test]
+    fn test_match_combos() {
+        let m = match_combos(COMBOS, 0, &[(0, 0)]);
+        assert!(m.exact.is_none() && m.partial);
+
+        let m = match_combos(COMBOS, 0, &[(0, 1), (0, 0)]);
+        assert!(m.exact == Some(0) && m.partial);
+
+        let m = match_combos(COMBOS, 0, &[(0, 0), (0, 1), (0, 2)]);
+        assert!(m.exact == Some(1) && !m.partial);
+
+        // Combo on another layer
+        let m = match_combos(COMBOS, 0, &[(1, 0), (1, 1)]);
+        assert!(m.exact.is_none() && !m.partial);
+        let m = match_combos(COMBOS, 1, &[(1, 0), (1, 1)]);
+        assert!(m.exact == Some(2));
+    }
+}
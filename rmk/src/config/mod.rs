@@ -11,6 +11,18 @@ pub use nrf_config::BleBatteryConfig;
 use embassy_time::Duration;
 use embedded_hal::digital::OutputPin;
 
+use crate::auto_lock::HostOs;
+use crate::combo::Combo;
+use crate::debounce::DEFAULT_DEBOUNCE_TIME;
+use crate::input_device::rotary_encoder::EncoderMap;
+use crate::key_override::KeyOverride;
+use crate::keyboard_macro::MacroOperation;
+use crate::keycode::KeyCode;
+use crate::matrix::MatrixTransform;
+use crate::rgb::{KeyLed, LedZone, Palette, RgbIndicator, BUILTIN_PALETTES};
+use crate::tap_dance::TapDance;
+use crate::user_key::{UserKeyFunction, DEFAULT_USER_KEYS};
+
 /// Internal configurations for RMK keyboard.
 pub struct RmkConfig<'a, O: OutputPin> {
     pub mouse_config: MouseConfig,
@@ -19,6 +31,17 @@ pub struct RmkConfig<'a, O: OutputPin> {
     pub light_config: LightConfig<O>,
     pub storage_config: StorageConfig,
     pub behavior_config: BehaviorConfig,
+    /// Debounce time of the matrix
+    pub debounce_config: DebounceConfig,
+    /// Layer-aware actions of rotary encoders
+    pub encoder_map: Option<EncoderMap<'a>>,
+    /// Default macros of `Macro0` ~ `Macro31`, they're replaced by macros saved by Vial
+    pub macros: &'a [&'a [MacroOperation]],
+    /// Keys whose positions in the keymap differ from their positions in the matrix
+    pub key_remap: &'a [KeyRemap],
+    /// Transform of key positions of the matrix.
+    /// The keymap has the size of the pins, so a swapped matrix has to be square
+    pub matrix_transform: MatrixTransform,
     #[cfg(feature = "_nrf_ble")]
     pub ble_battery_config: BleBatteryConfig<'a>,
     #[cfg(feature = "_esp_ble")]
@@ -34,18 +57,45 @@ impl<'a, O: OutputPin> Default for RmkConfig<'a, O> {
             light_config: LightConfig::default(),
             storage_config: StorageConfig::default(),
             behavior_config: BehaviorConfig::default(),
+            debounce_config: DebounceConfig::default(),
+            encoder_map: None,
+            macros: &[],
+            key_remap: &[],
+            matrix_transform: MatrixTransform::IDENTITY,
             #[cfg(any(feature = "_nrf_ble", feature = "_esp_ble"))]
             ble_battery_config: BleBatteryConfig::default(),
         }
     }
 }
 
+/// A key of the matrix which is moved to another position of the keymap.
+///
+/// It's useful for handwired keyboards, whose matrix wiring doesn't follow the layout,
+/// the keymap is written in the layout and the key at `physical` of the matrix triggers the action at `logical` of the keymap.
+/// Keys which aren't remapped keep their positions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyRemap {
+    /// (row, col) of the key in the matrix
+    pub physical: (u8, u8),
+    /// (row, col) of the action in the keymap
+    pub logical: (u8, u8),
+}
+
 /// Config for configurable action behavior
 #[derive(Default)]
 pub struct BehaviorConfig {
     pub tri_layer: Option<[u8; 3]>,
     pub tap_hold: TapHoldConfig,
     pub one_shot: OneShotConfig,
+    pub soft_off: SoftOffConfig,
+    pub combo: ComboConfig,
+    pub auto_lock: AutoLockConfig,
+    pub tap_dance: TapDanceConfig,
+    pub socd: SocdConfig,
+    pub sleep: SleepConfig,
+    pub key_override: KeyOverrideConfig,
+    pub user_keys: UserKeyConfig,
 }
 
 /// Configurations for tap hold behavior
@@ -54,6 +104,10 @@ pub struct TapHoldConfig {
     pub prior_idle_time: Duration,
     pub post_wait_time: Duration,
     pub hold_timeout: Duration,
+    /// How the tap hold key is resolved when other keys are pressed within `hold_timeout`
+    pub mode: TapHoldMode,
+    /// Per-key overrides of `hold_timeout` and `mode`
+    pub per_key: &'static [PerKeyTapHold],
 }
 
 impl Default for TapHoldConfig {
@@ -63,6 +117,161 @@ impl Default for TapHoldConfig {
             prior_idle_time: Duration::from_millis(120),
             post_wait_time: Duration::from_millis(50),
             hold_timeout: Duration::from_millis(250),
+            mode: TapHoldMode::default(),
+            per_key: &[],
+        }
+    }
+}
+
+/// Decision of a tap hold key when other keys are pressed before `hold_timeout`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TapHoldMode {
+    /// Trigger hold as soon as another key is pressed
+    #[default]
+    HoldOnOtherKeyPress,
+    /// Trigger hold when another key is pressed and released while the tap hold key is held.
+    /// Releasing the tap hold key first triggers tap, so rolling over keys produces taps
+    PermissiveHold,
+    /// Trigger hold only when the tap hold key is held longer than `hold_timeout`
+    TapPreferred,
+}
+
+/// Tap hold settings of the key at (row, col), `None` fields fall back to [`TapHoldConfig`]
+#[derive(Clone, Copy, Debug)]
+pub struct PerKeyTapHold {
+    pub row: u8,
+    pub col: u8,
+    pub hold_timeout: Option<Duration>,
+    pub mode: Option<TapHoldMode>,
+}
+
+/// Config for combos, see [`crate::combo`]
+pub struct ComboConfig {
+    /// Max interval between the first and the last key of a combo
+    pub timeout: Duration,
+    pub combos: &'static [Combo],
+}
+
+impl Default for ComboConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(50),
+            combos: &[],
+        }
+    }
+}
+
+/// Config for tap dances, see [`crate::tap_dance`]
+pub struct TapDanceConfig {
+    /// Max interval between a release and the next press of a tap dance key, and the time to hold the key
+    pub tapping_term: Duration,
+    pub tap_dances: &'static [TapDance],
+    /// What double-tapping a shift key does
+    pub double_tap_shift: DoubleTapShift,
+    /// Double-tapping a layer-mod key(`MO(n)` or `LM(n, modifier)`) locks the layer on, double-tap it again to unlock
+    pub double_tap_layer_lock: bool,
+    /// Number of taps of a `TT(n)` key which toggle the layer
+    pub tapping_toggle: u8,
+}
+
+impl Default for TapDanceConfig {
+    fn default() -> Self {
+        Self {
+            tapping_term: Duration::from_millis(200),
+            tap_dances: &[],
+            double_tap_shift: DoubleTapShift::default(),
+            double_tap_layer_lock: false,
+            tapping_toggle: 5,
+        }
+    }
+}
+
+/// Behavior of double-tapping `LShift` or `RShift`, the shift key is still sent as usual
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DoubleTapShift {
+    /// Nothing happens
+    #[default]
+    None,
+    /// Toggle Caps Word
+    CapsWord,
+    /// Tap Caps Lock
+    CapsLock,
+}
+
+/// Config for instant release of opposing keys, see [`crate::socd`]
+pub struct SocdConfig {
+    /// Pairs of opposing keys, such as `(KeyCode::A, KeyCode::D)`
+    pub pairs: &'static [(KeyCode, KeyCode)],
+    /// Press the released key again when the opposing key is released, if it's still held
+    pub restore: bool,
+}
+
+impl Default for SocdConfig {
+    fn default() -> Self {
+        Self {
+            pairs: &[],
+            restore: true,
+        }
+    }
+}
+
+/// Config for key overrides, see [`crate::key_override`]
+#[derive(Default)]
+pub struct KeyOverrideConfig {
+    pub overrides: &'static [KeyOverride],
+}
+
+/// Functions of user keycodes, see [`crate::user_key`]
+pub struct UserKeyConfig {
+    /// User keycodes and their functions. With BLE, `User0` ~ `User11` are used by BLE profiles, don't assign them
+    pub functions: &'static [(KeyCode, UserKeyFunction)],
+}
+
+impl Default for UserKeyConfig {
+    fn default() -> Self {
+        Self {
+            functions: DEFAULT_USER_KEYS,
+        }
+    }
+}
+
+/// Config for locking the host when the keyboard is idle, see [`crate::auto_lock`]
+#[derive(Default)]
+pub struct AutoLockConfig {
+    /// Idle time before the host is locked, `None` disables the auto-lock
+    pub timeout: Option<Duration>,
+    /// OS of the host, which decides the lock shortcut
+    pub os: HostOs,
+}
+
+/// Config for the keyboard sleep, see [`crate::sleep`]. `None` disables a timeout
+#[derive(Clone, Copy, Debug)]
+pub struct SleepConfig {
+    /// The keyboard sleeps when no key is pressed for this duration
+    pub idle_timeout: Option<Duration>,
+    /// The keyboard sleeps when the USB bus is suspended by the host for this duration
+    pub host_suspend_timeout: Option<Duration>,
+    /// The keyboard sleeps when there's no key press and no write of the BLE host for this duration
+    pub ble_idle_timeout: Option<Duration>,
+    /// The keyboard powers down to deep sleep when no key is pressed for this duration while it's running on battery
+    pub deep_sleep_timeout: Option<Duration>,
+    /// Input pins of the matrix, which wake the keyboard up from deep sleep, `32 * port + pin` for nRF52
+    pub wake_pins: &'static [u8],
+    /// Output pins of the matrix, which are driven high during deep sleep, so that any key pulls its input pin high
+    pub drive_pins: &'static [u8],
+}
+
+impl Default for SleepConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            host_suspend_timeout: Some(Duration::from_secs(2)),
+            ble_idle_timeout: Some(Duration::from_secs(300)),
+            deep_sleep_timeout: None,
+            wake_pins: &[],
+            drive_pins: &[],
         }
     }
 }
@@ -80,6 +289,32 @@ impl Default for OneShotConfig {
     }
 }
 
+/// Config for the `SoftOff` action
+#[derive(Clone, Copy, Debug)]
+pub struct SoftOffConfig {
+    /// How long the `SoftOff` key should be held before the keyboard is powered down
+    pub hold_time: Duration,
+    /// Pin which wakes the keyboard up when it reaches the wake level, `32 * port + pin` for nRF52.
+    /// For a key in the matrix, it's the input pin of the key
+    pub wake_pin: Option<u8>,
+    /// Pin which is driven to the wake level while the keyboard is off.
+    /// For a key in the matrix, it's the output pin of the key
+    pub drive_pin: Option<u8>,
+    /// Wake up on high level if true, otherwise on low level
+    pub wake_high: bool,
+}
+
+impl Default for SoftOffConfig {
+    fn default() -> Self {
+        Self {
+            hold_time: Duration::from_secs(2),
+            wake_pin: None,
+            drive_pin: None,
+            wake_high: true,
+        }
+    }
+}
+
 /// Config for storage
 #[derive(Clone, Copy, Debug)]
 pub struct StorageConfig {
@@ -89,6 +324,8 @@ pub struct StorageConfig {
     // Number of sectors used for storage, >= 2.
     pub num_sectors: u8,
     pub clear_storage: bool,
+    /// (row, col) of the key which boots the keyboard into safe mode if it's held at power up, see [`crate::safe_mode`]
+    pub safe_mode_key: Option<(u8, u8)>,
 }
 
 impl Default for StorageConfig {
@@ -97,6 +334,7 @@ impl Default for StorageConfig {
             start_addr: 0,
             num_sectors: 2,
             clear_storage: false,
+            safe_mode_key: None,
         }
     }
 }
@@ -132,6 +370,26 @@ impl<O: OutputPin> Default for LightConfig<O> {
 pub struct VialConfig<'a> {
     pub vial_keyboard_id: &'a [u8],
     pub vial_keyboard_def: &'a [u8],
+    /// Shared secret of host authentication, requires `host_auth` feature.
+    /// If it's set, hosts must authenticate before changing the keymap, macros or settings.
+    /// A secret provisioned to the storage over RawHID takes precedence
+    pub auth_secret: Option<&'a [u8]>,
+    /// Random bytes mixed into challenges of host authentication, which should be read from the hardware RNG at every boot.
+    /// Without it, challenges may repeat after a reboot, then a response recorded before the reboot can be replayed
+    pub auth_seed: Option<&'a [u8]>,
+    /// Layers activated when an application is focused on the host, see [`AppLayer`]
+    pub app_layers: &'a [AppLayer<'a>],
+}
+
+/// A layer which is activated while an application is focused on the host.
+///
+/// The focused application is announced by a helper app on the host over RawHID.
+#[derive(Clone, Copy, Debug)]
+pub struct AppLayer<'a> {
+    /// Application name sent by the host, only the first 28 bytes are compared
+    pub app: &'a str,
+    /// Layer activated while the application is focused
+    pub layer: u8,
 }
 
 impl<'a> VialConfig<'a> {
@@ -139,14 +397,40 @@ impl<'a> VialConfig<'a> {
         Self {
             vial_keyboard_id,
             vial_keyboard_def,
+            auth_secret: None,
+            auth_seed: None,
+            app_layers: &[],
         }
     }
 }
 
 /// Configuration for debouncing
+#[derive(Clone, Copy, Debug)]
 pub struct DebounceConfig {
     /// Debounce time in ms
-    pub debounce_time: u32,
+    pub debounce_time: u16,
+    /// Debounce time of releases in ms, used by [`AsymmetricDebouncer`](crate::debounce::asym_debouncer::AsymmetricDebouncer) only.
+    /// `debounce_time` is used if it's not set
+    pub release_debounce_time: Option<u16>,
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self {
+            debounce_time: DEFAULT_DEBOUNCE_TIME,
+            release_debounce_time: None,
+        }
+    }
+}
+
+/// Acceleration curve of mouse keys
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MouseKeyCurve {
+    /// The speed grows evenly until it reaches the max speed
+    #[default]
+    Linear,
+    /// The speed grows slowly at first, which makes small adjustments easier
+    Quadratic,
 }
 
 /// Configurations for mouse functionalities
@@ -156,6 +440,24 @@ pub struct MouseConfig {
     pub mouse_key_interval: u32,
     /// Time interval in ms of reporting mouse wheel states
     pub mouse_wheel_interval: u32,
+    /// Cursor movement of each report when the mouse key starts moving
+    pub move_delta: u8,
+    /// Cursor movement of each report at the max speed
+    pub max_move_delta: u8,
+    /// Time in ms before the cursor starts to accelerate
+    pub move_delay: u32,
+    /// Time in ms from the start of the acceleration to the max speed
+    pub move_time_to_max: u32,
+    /// Scroll units of each report when the mouse wheel key starts scrolling
+    pub wheel_delta: u8,
+    /// Scroll units of each report at the max speed
+    pub max_wheel_delta: u8,
+    /// Time in ms before the wheel starts to accelerate
+    pub wheel_delay: u32,
+    /// Time in ms from the start of the wheel acceleration to the max speed
+    pub wheel_time_to_max: u32,
+    /// Acceleration curve of both the cursor and the wheel
+    pub curve: MouseKeyCurve,
 }
 
 impl Default for MouseConfig {
@@ -163,6 +465,15 @@ impl Default for MouseConfig {
         Self {
             mouse_key_interval: 20,
             mouse_wheel_interval: 80,
+            move_delta: 8,
+            max_move_delta: 40,
+            move_delay: 200,
+            move_time_to_max: 1000,
+            wheel_delta: 1,
+            max_wheel_delta: 4,
+            wheel_delay: 400,
+            wheel_time_to_max: 1600,
+            curve: MouseKeyCurve::Linear,
         }
     }
 }
@@ -175,6 +486,119 @@ pub struct RGBLightConfig {
     pub rgb_hue_step: u32,
     pub rgb_val_step: u32,
     pub rgb_sat_step: u32,
+    /// Frame rate of lighting animations
+    pub fps: u8,
+    /// Speed of lighting effects, 0 ~ 255
+    pub effect_speed: u8,
+    /// Step of changing effect speed by `RgbSpi`/`RgbSpd`
+    pub rgb_speed_step: u8,
+    /// Palettes which can be switched at runtime
+    pub palettes: &'static [Palette],
+    /// LEDs which show the battery level while the battery check key(`User12`) is held
+    pub battery_gauge: Option<LedZone>,
+    /// LEDs which show the remaining time of the pomodoro timer
+    pub timer_gauge: Option<LedZone>,
+    /// LEDs under keys, which light up on key presses in the reactive effect
+    pub key_leds: &'static [KeyLed],
+    /// LEDs which show the lock state of the host or the active layer, see [`crate::indicator`]
+    pub indicators: &'static [RgbIndicator],
+    /// Turn LEDs off after no key is pressed for this time, `None` to keep them on.
+    /// LEDs are always off while the keyboard sleeps or the host is suspended
+    pub idle_timeout: Option<Duration>,
+    /// Upper limit of LED brightness while the keyboard is powered by battery, 255 means no limit
+    pub battery_brightness_limit: u8,
+}
+
+impl Default for RGBLightConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            rgb_led_num: 0,
+            rgb_hue_step: 8,
+            rgb_val_step: 17,
+            rgb_sat_step: 17,
+            fps: 60,
+            effect_speed: 128,
+            rgb_speed_step: 16,
+            palettes: &BUILTIN_PALETTES,
+            battery_gauge: None,
+            timer_gauge: None,
+            key_leds: &[],
+            indicators: &[],
+            idle_timeout: None,
+            battery_brightness_limit: u8::MAX,
+        }
+    }
+}
+
+/// Configurations for thermal guard
+///
+/// When the temperature exceeds `warning_threshold`, the LED brightness is throttled linearly,
+/// until `critical_threshold` where all LEDs are turned off. The brightness is restored after the temperature drops by `hysteresis`.
+#[derive(Clone, Copy, Debug)]
+pub struct ThermalConfig {
+    /// Temperature in °C at which the LED brightness starts to be throttled
+    pub warning_threshold: i16,
+    /// Temperature in °C at which the LEDs are turned off
+    pub critical_threshold: i16,
+    /// Temperature drop in °C before the brightness limit is raised again
+    pub hysteresis: i16,
+    /// Interval of reading the temperature sensor
+    pub poll_interval: Duration,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            warning_threshold: 45,
+            critical_threshold: 60,
+            hysteresis: 3,
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Configurations for pomodoro timer
+#[derive(Clone, Copy, Debug)]
+pub struct PomodoroConfig {
+    /// Length of a work session
+    pub work: Duration,
+    pub short_break: Duration,
+    pub long_break: Duration,
+    /// Number of work sessions before a long break, 0 to disable long breaks
+    pub long_break_interval: u8,
+    /// Start the next session automatically when a session is done, otherwise it's started by the start key(`User17`)
+    pub auto_start: bool,
+}
+
+impl Default for PomodoroConfig {
+    fn default() -> Self {
+        Self {
+            work: Duration::from_secs(25 * 60),
+            short_break: Duration::from_secs(5 * 60),
+            long_break: Duration::from_secs(15 * 60),
+            long_break_interval: 4,
+            auto_start: false,
+        }
+    }
+}
+
+/// Configurations for status display
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayConfig {
+    /// Interval of redrawing the active page
+    pub refresh_interval: Duration,
+    /// Interval of redrawing the animation page
+    pub animation_interval: Duration,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: Duration::from_secs(1),
+            animation_interval: Duration::from_millis(100),
+        }
+    }
 }
 
 /// Configurations for usb
@@ -3,6 +3,8 @@ use embassy_nrf::{
     saadc::Saadc,
 };
 
+use crate::power::LIPO_CURVE;
+
 pub struct BleBatteryConfig<'a> {
     pub charge_state_pin: Option<Input<'a>>,
     pub charge_led_pin: Option<Output<'a>>,
@@ -11,6 +13,8 @@ pub struct BleBatteryConfig<'a> {
     pub saadc: Option<Saadc<'a, 1>>,
     pub adc_divider_measured: u32,
     pub adc_divider_total: u32,
+    /// Discharge curve of the battery, see [`crate::power::battery_percent`]
+    pub battery_curve: &'static [(u16, u8)],
 }
 
 impl<'a> Default for BleBatteryConfig<'a> {
@@ -23,6 +27,7 @@ impl<'a> Default for BleBatteryConfig<'a> {
             saadc: None,
             adc_divider_measured: 1,
             adc_divider_total: 1,
+            battery_curve: LIPO_CURVE,
         }
     }
 }
@@ -45,6 +50,7 @@ impl<'a> BleBatteryConfig<'a> {
             saadc,
             adc_divider_measured,
             adc_divider_total,
+            battery_curve: LIPO_CURVE,
         }
     }
 }
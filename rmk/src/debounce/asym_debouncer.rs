@@ -0,0 +1,108 @@
+use embassy_time::Instant;
+
+use crate::{config::DebounceConfig, matrix::KeyState};
+
+use super::{DebounceState, Debouncer};
+
+/// Per-key debouncer with different debounce times of presses and releases.
+///
+/// A change is reported after the key is stable for the debounce time of the change.
+/// If the press debounce time is 0, presses are reported immediately, like QMK's asym eager defer pk debouncer,
+/// which gives low latency while the release is still debounced.
+pub struct AsymmetricDebouncer<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> {
+    /// Debounce time of presses in ms
+    press_time: u16,
+    /// Debounce time of releases in ms
+    release_time: u16,
+    /// Lower 16 bits of the time in ms when the pin changed from the key state, `None` if they're same
+    changed_at: [[Option<u16>; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+}
+
+impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize>
+    AsymmetricDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+{
+    fn update(
+        &mut self,
+        in_idx: usize,
+        out_idx: usize,
+        pin_state: bool,
+        pressed: bool,
+        now_ms: u16,
+    ) -> DebounceState {
+        let changed_at = &mut self.changed_at[out_idx][in_idx];
+        if pin_state == pressed {
+            // Bounced back before the debounce time
+            return match changed_at.take() {
+                Some(_) => DebounceState::InProgress,
+                None => DebounceState::Ignored,
+            };
+        }
+        let since = *changed_at.get_or_insert(now_ms);
+        let debounce_time = if pin_state {
+            self.press_time
+        } else {
+            self.release_time
+        };
+        if now_ms.wrapping_sub(since) >= debounce_time {
+            *changed_at = None;
+            DebounceState::Debounced
+        } else {
+            DebounceState::InProgress
+        }
+    }
+}
+
+impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> Debouncer
+    for AsymmetricDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+{
+    /// Create an asymmetric debouncer, releases use `release_debounce_time` of the config if it's set
+    fn new(config: DebounceConfig) -> Self {
+        AsymmetricDebouncer {
+            press_time: config.debounce_time,
+            release_time: config.release_debounce_time.unwrap_or(config.debounce_time),
+            changed_at: [[None; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+        }
+    }
+
+    fn detect_change_with_debounce(
+        &mut self,
+        in_idx: usize,
+        out_idx: usize,
+        pin_state: bool,
+        key_state: &KeyState,
+    ) -> DebounceState {
+        let now_ms = Instant::now().as_millis() as u16;
+        self.update(in_idx, out_idx, pin_state, key_state.pressed, now_ms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_asymmetric_debouncer() {
+        let mut debouncer = AsymmetricDebouncer::<1, 1>::new(DebounceConfig {
+            debounce_time: 0,
+            release_debounce_time: Some(5),
+        });
+        let debounced = |state: DebounceState| matches!(state, DebounceState::Debounced);
+        // The press is reported immediately
+        assert!(debounced(debouncer.update(0, 0, true, false, 100)));
+        // The release is reported after the key is released for 5ms, bounces restart the timer
+        assert!(!debounced(debouncer.update(0, 0, false, true, 110)));
+        assert!(!debounced(debouncer.update(0, 0, true, true, 112)));
+        assert!(!debounced(debouncer.update(0, 0, false, true, 113)));
+        assert!(!debounced(debouncer.update(0, 0, false, true, 117)));
+        assert!(debounced(debouncer.update(0, 0, false, true, 118)));
+        // The timer wraps around
+        assert!(!debounced(debouncer.update(
+            0,
+            0,
+            false,
+            true,
+            u16::MAX - 1
+        )));
+        assert!(debounced(debouncer.update(0, 0, false, true, 3)));
+    }
+}
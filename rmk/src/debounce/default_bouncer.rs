@@ -1,13 +1,16 @@
 use embassy_time::Instant;
 
-use crate::matrix::KeyState;
+use crate::{config::DebounceConfig, matrix::KeyState};
 
-use super::{DebounceState, DebouncerTrait, DEBOUNCE_THRESHOLD};
+use super::{DebounceState, Debouncer};
 
 /// Debounce counter info for each key.
 #[derive(Copy, Clone, Debug)]
 struct DebounceCounter(u16);
 
+// Checked by the memory report of `rmk-macro/src/memory.rs`
+const _: () = assert!(core::mem::size_of::<DebounceCounter>() == 2);
+
 impl DebounceCounter {
     fn increase(&mut self, elapsed_ms: u16) {
         // Prevent overflow
@@ -30,16 +33,19 @@ impl DebounceCounter {
 /// Default per-key debouncer. The debouncing algorithm is same as ZMK's [default debouncer](https://github.com/zmkfirmware/zmk/blob/19613128b901723f7b78c136792d72e6ca7cf4fc/app/module/lib/zmk_debounce/debounce.c)
 pub struct DefaultDebouncer<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> {
     last_ms: u32,
+    /// Debounce time in ms
+    threshold: u16,
     counters: [[DebounceCounter; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
 }
 
-impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> DebouncerTrait
+impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> Debouncer
     for DefaultDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>
 {
     /// Create a default debouncer
-    fn new() -> Self {
+    fn new(config: DebounceConfig) -> Self {
         DefaultDebouncer {
             counters: [[DebounceCounter(0); INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+            threshold: config.debounce_time,
             last_ms: 0,
         }
     }
@@ -70,7 +76,7 @@ impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> DebouncerTrait
                 } else {
                     DebounceState::Ignored
                 }
-            } else if counter.0 < DEBOUNCE_THRESHOLD {
+            } else if counter.0 < self.threshold {
                 // If debounce threshold is not exceeded, increase debounce counter
                 counter.increase(elapsed_ms);
                 DebounceState::InProgress
@@ -1,23 +1,26 @@
 use embassy_time::Instant;
 
-use crate::matrix::KeyState;
+use crate::{config::DebounceConfig, matrix::KeyState};
 
-use super::{DebounceState, DebouncerTrait, DEBOUNCE_THRESHOLD};
+use super::{DebounceState, Debouncer};
 
-/// Fast per-key debouncer.
+/// Fast per-key debouncer, which reports presses and releases immediately, then ignores the key for the debounce time.
 /// The debouncing algorithm is similar as QMK's [asym eager defer pk debouncer](https://github.com/qmk/qmk_firmware/blob/2fd56317763e8b3b73f0db7488ef42a70f5b946e/quantum/debounce/asym_eager_defer_pk.c)
 pub struct RapidDebouncer<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> {
     last_ms: Instant,
+    /// Debounce time in ms
+    threshold: u16,
     debouncing: [[bool; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
 }
 
-impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> DebouncerTrait
+impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> Debouncer
     for RapidDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>
 {
     /// Create a rapid debouncer
-    fn new() -> Self {
+    fn new(config: DebounceConfig) -> Self {
         RapidDebouncer {
             debouncing: [[false; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+            threshold: config.debounce_time,
             last_ms: Instant::now(),
         }
     }
@@ -33,8 +36,8 @@ impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> DebouncerTrait
         let debouncing = self.debouncing[out_idx][in_idx];
         if debouncing {
             // Current key is in debouncing state
-            if self.last_ms.elapsed().as_millis() as u16 > DEBOUNCE_THRESHOLD {
-                // If the elapsed time > DEBOUNCE_THRESHOLD, reset
+            if self.last_ms.elapsed().as_millis() as u16 > self.threshold {
+                // If the elapsed time > debounce time, reset
                 self.debouncing[out_idx][in_idx] = false;
                 DebounceState::Ignored
             } else {
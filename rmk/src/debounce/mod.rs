@@ -1,13 +1,37 @@
+//! Debouncers of the key matrix
+//!
+//! Matrices are generic over [`Debouncer`], built-in implementations are:
+//!
+//! - [`DefaultDebouncer`](default_bouncer::DefaultDebouncer): per-key deferred, a change is reported after the key is stable for the debounce time
+//! - [`RapidDebouncer`](fast_debouncer::RapidDebouncer): per-key eager, a change is reported immediately, then the key is ignored for the debounce time
+//! - [`AsymmetricDebouncer`](asym_debouncer::AsymmetricDebouncer): per-key deferred, with different debounce times of presses and releases
+//!
+//! Built-in matrices use the default debouncer, or the rapid debouncer if the `rapid_debouncer` feature is enabled.
+//! Other debouncers can be used by creating the matrix and running it by `run_rmk_with_async_flash_and_matrix`.
+
+use crate::config::DebounceConfig;
 use crate::matrix::KeyState;
 
+pub mod asym_debouncer;
 pub mod default_bouncer;
 pub mod fast_debouncer;
 
-/// Default DEBOUNCE_THRESHOLD in ms.
-static DEBOUNCE_THRESHOLD: u16 = 10;
+/// Default debounce time in ms
+pub const DEFAULT_DEBOUNCE_TIME: u16 = 10;
+
+/// Debouncer used by built-in matrices
+#[cfg(not(feature = "rapid_debouncer"))]
+pub type MatrixDebouncer<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> =
+    default_bouncer::DefaultDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>;
+
+/// Debouncer used by built-in matrices
+#[cfg(feature = "rapid_debouncer")]
+pub type MatrixDebouncer<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> =
+    fast_debouncer::RapidDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>;
 
-pub trait DebouncerTrait {
-    fn new() -> Self;
+pub trait Debouncer {
+    /// Create a debouncer with the debounce time of the config
+    fn new(config: DebounceConfig) -> Self;
 
     /// The `in_idx` `out_idx` can be used as two normal dimensions.
     fn detect_change_with_debounce(
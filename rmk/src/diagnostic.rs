@@ -0,0 +1,373 @@
+//! Diagnostic mode
+//!
+//! In key tester mode, key presses are not sent to the host. Instead, every key event is shown on the display
+//! and flashes the LEDs, and it's queued with its raw matrix coordinate so that a host tool can read it via RawHID.
+//! It's designed for checking all switches of a newly assembled keyboard.
+//!
+//! The key tester is toggled by `User14`, or by the host with RawHID command `0xF6`.
+//!
+//! While the key tester is running, raw pin states of the matrix are watched as well, to collect bounce statistics.
+//! Every raw transition of a key besides the actual state change is counted as a bounce,
+//! and the settle time is the time from the first to the last transition of a change.
+//! Keys with high bounce counts or long settle times usually have marginal switches or hotswap sockets.
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::event::KeyEvent;
+
+/// Max number of rows and columns tracked by the key tester
+pub const MAX_TESTER_ROWS: usize = 32;
+
+/// Number of key events queued for the host, older events are dropped when the queue is full
+pub const KEY_TESTER_QUEUE_SIZE: usize = 16;
+
+/// Max number of keys whose bounce statistics are recorded, keys which never bounce are not recorded
+pub const MAX_BOUNCE_STATS: usize = crate::ram_profile::MAX_BOUNCE_STATS;
+
+/// Max number of keys which are bouncing at the same time
+const MAX_BOUNCING_KEYS: usize = 8;
+
+/// A change is settled if there's no transition of the raw pin state in this duration
+const SETTLE_WINDOW: Duration = Duration::from_millis(20);
+
+static KEY_TESTER: AtomicBool = AtomicBool::new(false);
+
+// Key events which are not read by the host yet
+static KEY_TESTER_EVENTS: Channel<CriticalSectionRawMutex, KeyEvent, KEY_TESTER_QUEUE_SIZE> =
+    Channel::new();
+
+static KEY_TESTER_STATE: Mutex<CriticalSectionRawMutex, RefCell<KeyTesterState>> =
+    Mutex::new(RefCell::new(KeyTesterState::new()));
+
+struct KeyTesterState {
+    last_event: Option<KeyEvent>,
+    last_press_at: Option<Instant>,
+    presses: u32,
+    /// Bit `col` of `tested[row]` is set if the key has been pressed
+    tested: [u32; MAX_TESTER_ROWS],
+    /// Keys whose raw pin state is changing
+    bouncing: Vec<BounceTracker, MAX_BOUNCING_KEYS>,
+    bounce_stats: Vec<BounceStats, MAX_BOUNCE_STATS>,
+}
+
+impl KeyTesterState {
+    const fn new() -> Self {
+        Self {
+            last_event: None,
+            last_press_at: None,
+            presses: 0,
+            tested: [0; MAX_TESTER_ROWS],
+            bouncing: Vec::new(),
+            bounce_stats: Vec::new(),
+        }
+    }
+
+    /// Track the raw pin state of a key, see [`record_pin_state`]
+    fn record_pin_state(&mut self, row: u8, col: u8, pin_state: bool, pressed: bool, now: Instant) {
+        match self
+            .bouncing
+            .iter()
+            .position(|t| t.row == row && t.col == col)
+        {
+            Some(i) => {
+                let tracker = &mut self.bouncing[i];
+                if pin_state != tracker.raw {
+                    tracker.raw = pin_state;
+                    tracker.transitions = tracker.transitions.saturating_add(1);
+                    tracker.last_transition = now;
+                } else if now - tracker.last_transition > SETTLE_WINDOW {
+                    let tracker = self.bouncing.swap_remove(i);
+                    self.finish_change(tracker);
+                }
+            }
+            None if pin_state != pressed => {
+                // The first transition of a change
+                let tracker = BounceTracker {
+                    row,
+                    col,
+                    initial: pressed,
+                    raw: pin_state,
+                    transitions: 1,
+                    start: now,
+                    last_transition: now,
+                };
+                self.bouncing.push(tracker).ok();
+            }
+            None => (),
+        }
+    }
+
+    /// Record the result of a settled change
+    fn finish_change(&mut self, tracker: BounceTracker) {
+        // A change which ends at the state before the change is a glitch, all its transitions are bounces
+        let changed = tracker.raw != tracker.initial;
+        let bounces = tracker.transitions - changed as u16;
+        if bounces == 0 {
+            return;
+        }
+        let settle = (tracker.last_transition - tracker.start).as_micros();
+        let settle_us = settle.min(u16::MAX as u64) as u16;
+        match self
+            .bounce_stats
+            .iter_mut()
+            .find(|s| s.row == tracker.row && s.col == tracker.col)
+        {
+            Some(stats) => {
+                stats.bounces = stats.bounces.saturating_add(bounces);
+                stats.worst_settle_us = stats.worst_settle_us.max(settle_us);
+            }
+            None => {
+                let stats = BounceStats {
+                    row: tracker.row,
+                    col: tracker.col,
+                    bounces,
+                    worst_settle_us: settle_us,
+                };
+                if self.bounce_stats.push(stats).is_err() {
+                    warn!(
+                        "Too many bouncing keys, key ({},{}) is not recorded",
+                        tracker.row, tracker.col
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Raw pin state of a key which is changing
+struct BounceTracker {
+    row: u8,
+    col: u8,
+    /// Raw pin state before the change
+    initial: bool,
+    /// Latest raw pin state
+    raw: bool,
+    transitions: u16,
+    start: Instant,
+    last_transition: Instant,
+}
+
+/// Bounce statistics of a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BounceStats {
+    pub row: u8,
+    pub col: u8,
+    /// Number of raw transitions besides actual key state changes
+    pub bounces: u16,
+    /// Longest time from the first to the last transition of a change, in microseconds
+    pub worst_settle_us: u16,
+}
+
+/// Snapshot of the key tester, which is shown on the display
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyTesterStatus {
+    /// The latest key event
+    pub last_event: Option<KeyEvent>,
+    /// Number of key presses since the key tester is started
+    pub presses: u32,
+    /// Number of distinct keys which have been pressed
+    pub tested_keys: u16,
+    /// Total bounces of all keys
+    pub bounces: u32,
+}
+
+/// Whether the key tester is running
+pub fn key_tester_active() -> bool {
+    KEY_TESTER.load(Ordering::Relaxed)
+}
+
+/// Start or stop the key tester, results of the previous test are cleared when it's started
+pub fn set_key_tester(active: bool) {
+    if KEY_TESTER.swap(active, Ordering::Relaxed) == active {
+        return;
+    }
+    if active {
+        KEY_TESTER_STATE.lock(|s| *s.borrow_mut() = KeyTesterState::new());
+        while KEY_TESTER_EVENTS.try_receive().is_ok() {}
+    }
+    info!("Key tester: {}", active);
+    crate::display::request_redraw();
+}
+
+/// Record a key event in key tester mode
+pub(crate) fn record_key_event(event: KeyEvent) {
+    debug!("Key tester: {:?}", event);
+    KEY_TESTER_STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.last_event = Some(event);
+        if event.pressed {
+            s.last_press_at = Some(Instant::now());
+            s.presses = s.presses.wrapping_add(1);
+            if (event.row as usize) < MAX_TESTER_ROWS && event.col < 32 {
+                s.tested[event.row as usize] |= 1 << event.col;
+            }
+        }
+    });
+    if KEY_TESTER_EVENTS.try_send(event).is_err() {
+        // Drop the oldest event
+        KEY_TESTER_EVENTS.try_receive().ok();
+        KEY_TESTER_EVENTS.try_send(event).ok();
+    }
+    crate::display::request_redraw();
+}
+
+/// Get current status of the key tester
+pub fn key_tester_status() -> KeyTesterStatus {
+    KEY_TESTER_STATE.lock(|s| {
+        let s = s.borrow();
+        KeyTesterStatus {
+            last_event: s.last_event,
+            presses: s.presses,
+            tested_keys: s.tested.iter().map(|r| r.count_ones() as u16).sum(),
+            bounces: s.bounce_stats.iter().map(|b| b.bounces as u32).sum(),
+        }
+    })
+}
+
+/// Time of the latest key press in key tester mode
+pub(crate) fn last_key_press_at() -> Option<Instant> {
+    KEY_TESTER_STATE.lock(|s| s.borrow().last_press_at)
+}
+
+/// Bitmap of pressed keys of the given row, bit `col` is set if the key has been pressed
+pub(crate) fn tested_keys_of_row(row: u8) -> u32 {
+    KEY_TESTER_STATE.lock(|s| s.borrow().tested.get(row as usize).copied().unwrap_or(0))
+}
+
+/// Take the oldest key event which is not read by the host
+pub(crate) fn take_key_event() -> Option<KeyEvent> {
+    KEY_TESTER_EVENTS.try_receive().ok()
+}
+
+/// Watch the raw pin state of a key in key tester mode, to collect bounce statistics.
+///
+/// It should be called every time the key is scanned, `pressed` is the debounced state of the key.
+pub(crate) fn record_pin_state(row: u8, col: u8, pin_state: bool, pressed: bool) {
+    let now = Instant::now();
+    KEY_TESTER_STATE.lock(|s| {
+        s.borrow_mut()
+            .record_pin_state(row, col, pin_state, pressed, now)
+    });
+}
+
+/// Bounce statistics of keys which have bounced since the key tester is started
+pub fn bounce_stats() -> Vec<BounceStats, MAX_BOUNCE_STATS> {
+    KEY_TESTER_STATE.lock(|s| s.borrow().bounce_stats.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feed raw pin states of key (0, 0) at the given milliseconds, the debounced state changes after the change settles
+    fn scan(state: &mut KeyTesterState, pressed: &mut bool, samples: &[(u64, bool)]) {
+        for &(ms, pin_state) in samples {
+            state.record_pin_state(0, 0, pin_state, *pressed, Instant::from_millis(ms));
+            if state.bouncing.is_empty() {
+                *pressed = pin_state;
+            }
+        }
+    }
+
+    #[test]
+    fn test_clean_change_is_not_recorded() {
+        let mut state = KeyTesterState::new();
+        let mut pressed = false;
+        scan(
+            &mut state,
+            &mut pressed,
+            &[(0, false), (1, true), (10, true), (30, true)],
+        );
+        assert!(pressed);
+        assert!(state.bouncing.is_empty());
+        assert!(state.bounce_stats.is_empty());
+    }
+
+    #[test]
+    fn test_bouncing_press_and_release() {
+        let mut state = KeyTesterState::new();
+        let mut pressed = false;
+        // Press: 3 transitions in 4ms, 2 of them are bounces
+        scan(
+            &mut state,
+            &mut pressed,
+            &[(0, true), (2, false), (4, true), (10, true), (30, true)],
+        );
+        assert!(pressed);
+        assert_eq!(
+            state.bounce_stats.as_slice(),
+            &[BounceStats {
+                row: 0,
+                col: 0,
+                bounces: 2,
+                worst_settle_us: 4000,
+            }]
+        );
+
+        // Release with a longer settle time, bounces are accumulated
+        scan(
+            &mut state,
+            &mut pressed,
+            &[(100, false), (103, true), (109, false), (140, false)],
+        );
+        assert!(!pressed);
+        assert_eq!(state.bounce_stats[0].bounces, 4);
+        assert_eq!(state.bounce_stats[0].worst_settle_us, 9000);
+    }
+
+    #[test]
+    fn test_glitch() {
+        let mut state = KeyTesterState::new();
+        let mut pressed = false;
+        // The pin goes back to the released state, both transitions are bounces
+        scan(
+            &mut state,
+            &mut pressed,
+            &[(0, true), (1, false), (30, false)],
+        );
+        assert!(!pressed);
+        assert_eq!(state.bounce_stats[0].bounces, 2);
+        assert_eq!(state.bounce_stats[0].worst_settle_us, 1000);
+    }
+
+    #[test]
+    fn test_finish_change() {
+        let mut state = KeyTesterState::new();
+        let tracker = |row, transitions, settle_ms| BounceTracker {
+            row,
+            col: 1,
+            initial: false,
+            raw: true,
+            transitions,
+            start: Instant::from_millis(0),
+            last_transition: Instant::from_millis(settle_ms),
+        };
+        // The settle time is clamped
+        state.finish_change(tracker(0, 3, 100));
+        assert_eq!(state.bounce_stats[0].worst_settle_us, u16::MAX);
+        // Keys are recorded until the statistics are full
+        for row in 1..MAX_BOUNCE_STATS as u8 + 1 {
+            state.finish_change(tracker(row, 2, 1));
+        }
+        assert_eq!(state.bounce_stats.len(), MAX_BOUNCE_STATS);
+        assert!(state
+            .bounce_stats
+            .iter()
+            .all(|s| s.row != MAX_BOUNCE_STATS as u8));
+        // Existing keys are still updated
+        state.finish_change(tracker(1, 2, 1));
+        assert_eq!(state.bounce_stats[1].bounces, 2);
+    }
+}
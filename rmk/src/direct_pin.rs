@@ -1,13 +1,10 @@
 use crate::action::KeyAction;
-#[cfg(not(feature = "rapid_debouncer"))]
-use crate::debounce::default_bouncer::DefaultDebouncer;
-#[cfg(feature = "rapid_debouncer")]
-use crate::debounce::fast_debouncer::RapidDebouncer;
 use crate::debounce::DebounceState;
-use crate::debounce::DebouncerTrait;
+use crate::debounce::{Debouncer, MatrixDebouncer};
+use crate::diagnostic::{key_tester_active, record_pin_state};
 use crate::event::KeyEvent;
 use crate::keyboard::KEY_EVENT_CHANNEL;
-use crate::matrix::KeyState;
+use crate::matrix::{KeyState, MatrixTransform};
 use crate::MatrixTrait;
 use crate::RmkConfig;
 
@@ -164,13 +161,11 @@ pub async fn run_rmk_direct_pin_with_async_flash<
     #[cfg(not(feature = "_esp_ble"))] spawner: Spawner,
 ) -> ! {
     // Create the debouncer
-    #[cfg(feature = "rapid_debouncer")]
-    let debouncer = RapidDebouncer::<COL, ROW>::new();
-    #[cfg(not(feature = "rapid_debouncer"))]
-    let debouncer = DefaultDebouncer::<COL, ROW>::new();
+    let debouncer = MatrixDebouncer::<COL, ROW>::new(keyboard_config.debounce_config);
 
     // Keyboard matrix
-    let matrix = DirectPinMatrix::<_, _, ROW, COL, SIZE>::new(direct_pins, debouncer, low_active);
+    let matrix = DirectPinMatrix::<_, _, ROW, COL, SIZE>::new(direct_pins, debouncer, low_active)
+        .with_transform(keyboard_config.matrix_transform);
 
     // Dispatch according to chip and communication type
     #[cfg(feature = "_nrf_ble")]
@@ -206,11 +201,15 @@ pub async fn run_rmk_direct_pin_with_async_flash<
     panic!("The run_rmk should never return");
 }
 
-/// DirectPinMartex only has input pins.
-pub(crate) struct DirectPinMatrix<
+/// Matrix of keys which are connected to their own pins, for macropads and boards without a diode matrix.
+///
+/// Pins are in the (row, col) positions of the keymap, `None` if there's no key at the position.
+/// With `async_matrix`, all pins are waited for the active level while no key is pressed, `SIZE` is the max number of pins waited,
+/// `ROW * COL` is always enough.
+pub struct DirectPinMatrix<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
-    D: DebouncerTrait,
+    D: Debouncer,
     const ROW: usize,
     const COL: usize,
     const SIZE: usize,
@@ -225,37 +224,42 @@ pub(crate) struct DirectPinMatrix<
     scan_start: Option<Instant>,
     /// Pin active level
     low_active: bool,
+    /// Transform of reported key positions
+    transform: MatrixTransform,
 }
 
 impl<
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const ROW: usize,
         const COL: usize,
         const SIZE: usize,
     > DirectPinMatrix<In, D, ROW, COL, SIZE>
 {
-    /// Create a matrix from input and output pins.
-    pub(crate) fn new(
-        direct_pins: [[Option<In>; COL]; ROW],
-        debouncer: D,
-        low_active: bool,
-    ) -> Self {
+    /// Create a matrix from direct pins, `low_active` is true if pins are pulled up and pulled low by pressed keys
+    pub fn new(direct_pins: [[Option<In>; COL]; ROW], debouncer: D, low_active: bool) -> Self {
         DirectPinMatrix {
             direct_pins,
             debouncer,
             key_states: [[KeyState::new(); COL]; ROW],
             scan_start: None,
             low_active,
+            transform: MatrixTransform::IDENTITY,
         }
     }
+
+    /// Transform the positions of key events, see [`MatrixTransform`]
+    pub fn with_transform(mut self, transform: MatrixTransform) -> Self {
+        self.transform = transform;
+        self
+    }
 }
 
 impl<
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const ROW: usize,
         const COL: usize,
         const SIZE: usize,
@@ -318,6 +322,15 @@ impl<
                             direct_pin.is_high().ok().unwrap_or_default()
                         };
 
+                        let (row, col) = self.transform.apply(row_idx, col_idx, ROW, COL);
+                        if key_tester_active() {
+                            record_pin_state(
+                                row as u8,
+                                col as u8,
+                                pin_state,
+                                self.key_states[row_idx][col_idx].pressed,
+                            );
+                        }
                         let debounce_state = self.debouncer.detect_change_with_debounce(
                             col_idx,
                             row_idx,
@@ -332,8 +345,8 @@ impl<
 
                                 KEY_EVENT_CHANNEL
                                     .send(KeyEvent {
-                                        row: row_idx as u8,
-                                        col: col_idx as u8,
+                                        row: row as u8,
+                                        col: col as u8,
                                         pressed: key_state.pressed,
                                     })
                                     .await;
@@ -350,7 +363,7 @@ impl<
                 }
             }
 
-            Timer::after_micros(100).await;
+            Timer::after(crate::power::scan_interval()).await;
         }
     }
 
@@ -0,0 +1,340 @@
+//! Drivers of SSD1306 OLEDs and ST7789 LCDs, which show text in the built-in 5x7 font
+
+use embassy_time::Timer;
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::{i2c::I2c, spi::SpiDevice};
+use heapless::{String, Vec};
+
+use super::{
+    font::{glyph, CHAR_HEIGHT, CHAR_WIDTH, GLYPH_WIDTH},
+    Display, TextCanvas,
+};
+
+/// Width of SSD1306 displays
+const SSD1306_WIDTH: usize = 128;
+
+/// Max number of pages, which are rows of 8 pixels, of SSD1306 displays
+const SSD1306_MAX_PAGES: usize = 8;
+
+/// Bytes of display data sent in an I2C write
+const SSD1306_CHUNK_LEN: usize = 32;
+
+/// SSD1306 OLED on an I2C bus, 128x32 or 128x64.
+///
+/// Each text line is a page of 8 pixels, so a 128x32 display shows 4 lines of 21 characters and a 128x64 display shows 8 lines.
+/// The display is initialized at the first flush.
+pub struct Ssd1306<I: I2c> {
+    i2c: I,
+    address: u8,
+    /// Number of pages, 4 or 8
+    pages: usize,
+    buffer: [[u8; SSD1306_WIDTH]; SSD1306_MAX_PAGES],
+    initialized: bool,
+}
+
+impl<I: I2c> Ssd1306<I> {
+    /// Create a driver of a display with `height` of 32 or 64, the address is usually 0x3C
+    pub fn new(i2c: I, address: u8, height: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            pages: if height > 32 { 8 } else { 4 },
+            buffer: [[0; SSD1306_WIDTH]; SSD1306_MAX_PAGES],
+            initialized: false,
+        }
+    }
+
+    async fn commands(&mut self, commands: &[u8]) -> Result<(), I::Error> {
+        for command in commands {
+            // Control byte 0x00: a command follows
+            self.i2c.write(self.address, &[0x00, *command]).await?;
+        }
+        Ok(())
+    }
+
+    async fn init(&mut self) -> Result<(), I::Error> {
+        let (multiplex, com_pins) = if self.pages == 8 {
+            (63, 0x12)
+        } else {
+            (31, 0x02)
+        };
+        self.commands(&[
+            0xAE, // Display off
+            0xD5, 0x80, // Clock divide ratio
+            0xA8, multiplex, // Multiplex ratio, height - 1
+            0xD3, 0x00, // Display offset
+            0x40, // Start line 0
+            0x8D, 0x14, // Enable the charge pump
+            0x20, 0x00, // Horizontal addressing mode
+            0xA1, // Column 127 is mapped to SEG0
+            0xC8, // Scan from COM[N-1] to COM0
+            0xDA, com_pins, // COM pins configuration
+            0x81, 0x8F, // Contrast
+            0xD9, 0xF1, // Pre-charge period
+            0xDB, 0x40, // VCOMH deselect level
+            0xA4, // Show the RAM content
+            0xA6, // Normal, not inverted
+            0xAF, // Display on
+        ])
+        .await
+    }
+}
+
+impl<I: I2c> TextCanvas for Ssd1306<I> {
+    fn clear(&mut self) {
+        self.buffer = [[0; SSD1306_WIDTH]; SSD1306_MAX_PAGES];
+    }
+
+    fn write_line(&mut self, line: u8, text: &str) {
+        let Some(page) = self.buffer[..self.pages].get_mut(line as usize) else {
+            return;
+        };
+        page.fill(0);
+        for (cell, c) in page.chunks_exact_mut(CHAR_WIDTH).zip(text.chars()) {
+            cell[..GLYPH_WIDTH].copy_from_slice(glyph(c));
+        }
+    }
+}
+
+impl<I: I2c> Display for Ssd1306<I> {
+    type Error = I::Error;
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if !self.initialized {
+            self.init().await?;
+            self.initialized = true;
+        }
+        let last_page = self.pages as u8 - 1;
+        self.commands(&[0x21, 0, SSD1306_WIDTH as u8 - 1, 0x22, 0, last_page])
+            .await?;
+        let mut data = [0u8; SSD1306_CHUNK_LEN + 1];
+        // Control byte 0x40: display data follows
+        data[0] = 0x40;
+        for page in &self.buffer[..self.pages] {
+            for chunk in page.chunks(SSD1306_CHUNK_LEN) {
+                data[1..=chunk.len()].copy_from_slice(chunk);
+                self.i2c.write(self.address, &data[..=chunk.len()]).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Max number of text lines of ST7789 displays
+const ST7789_MAX_LINES: usize = 16;
+
+/// Max characters of a text line of ST7789 displays
+const ST7789_LINE_LEN: usize = 24;
+
+/// Pixels sent in a SPI write
+const ST7789_CHUNK_PIXELS: usize = 64;
+
+/// Errors of [`St7789`]
+#[derive(Debug)]
+pub enum St7789Error<S, P> {
+    Spi(S),
+    Pin(P),
+}
+
+/// ST7789 LCD on a SPI bus, with a data/command pin.
+///
+/// Text is drawn in white on black, every pixel of the font is scaled to `scale` x `scale` pixels.
+/// At scale 2, a 240x240 display shows 15 lines of 20 characters. Only changed lines are sent to the display at flush,
+/// so the driver doesn't need a frame buffer. The display is initialized at the first flush.
+pub struct St7789<S: SpiDevice, P: OutputPin> {
+    spi: S,
+    dc: P,
+    width: u16,
+    height: u16,
+    /// Offset of the visible area in the RAM of the display, it depends on the module, e.g. (52, 40) for 135x240 modules
+    offset: (u16, u16),
+    scale: u8,
+    /// Lines which are drawn since the last flush
+    lines: [String<ST7789_LINE_LEN>; ST7789_MAX_LINES],
+    /// Lines on the screen
+    shown: [String<ST7789_LINE_LEN>; ST7789_MAX_LINES],
+    initialized: bool,
+}
+
+impl<S: SpiDevice, P: OutputPin> St7789<S, P> {
+    pub fn new(spi: S, dc: P, width: u16, height: u16) -> Self {
+        Self {
+            spi,
+            dc,
+            width,
+            height,
+            offset: (0, 0),
+            scale: 2,
+            lines: Default::default(),
+            shown: Default::default(),
+            initialized: false,
+        }
+    }
+
+    /// Set the offset of the visible area in the RAM of the display
+    pub fn with_offset(mut self, x: u16, y: u16) -> Self {
+        self.offset = (x, y);
+        self
+    }
+
+    /// Set the scale of the font, 1 ~ 4
+    pub fn with_scale(mut self, scale: u8) -> Self {
+        self.scale = scale.clamp(1, 4);
+        self
+    }
+
+    fn num_lines(&self) -> usize {
+        (self.height as usize / (CHAR_HEIGHT * self.scale as usize)).min(ST7789_MAX_LINES)
+    }
+
+    async fn command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+    ) -> Result<(), St7789Error<S::Error, P::Error>> {
+        self.dc.set_low().map_err(St7789Error::Pin)?;
+        self.spi.write(&[command]).await.map_err(St7789Error::Spi)?;
+        if !data.is_empty() {
+            self.dc.set_high().map_err(St7789Error::Pin)?;
+            self.spi.write(data).await.map_err(St7789Error::Spi)?;
+        }
+        Ok(())
+    }
+
+    async fn init(&mut self) -> Result<(), St7789Error<S::Error, P::Error>> {
+        // Software reset
+        self.command(0x01, &[]).await?;
+        Timer::after_millis(150).await;
+        // Sleep out
+        self.command(0x11, &[]).await?;
+        Timer::after_millis(120).await;
+        // 16-bit RGB565 colors
+        self.command(0x3A, &[0x55]).await?;
+        // Memory access order, top to bottom and left to right
+        self.command(0x36, &[0x00]).await?;
+        // Most ST7789 modules are IPS panels, which need inverted colors
+        self.command(0x21, &[]).await?;
+        // Normal display mode on
+        self.command(0x13, &[]).await?;
+        // Display on
+        self.command(0x29, &[]).await
+    }
+
+    /// Draw a text line, the rest of the line is filled with the background
+    async fn draw_line(&mut self, line: usize) -> Result<(), St7789Error<S::Error, P::Error>> {
+        let scale = self.scale as usize;
+        let line_height = (CHAR_HEIGHT * scale) as u16;
+        let x0 = self.offset.0;
+        let x1 = x0 + self.width - 1;
+        let y0 = self.offset.1 + line as u16 * line_height;
+        let y1 = y0 + line_height - 1;
+        self.command(
+            0x2A,
+            &[(x0 >> 8) as u8, x0 as u8, (x1 >> 8) as u8, x1 as u8],
+        )
+        .await?;
+        self.command(
+            0x2B,
+            &[(y0 >> 8) as u8, y0 as u8, (y1 >> 8) as u8, y1 as u8],
+        )
+        .await?;
+        self.command(0x2C, &[]).await?;
+        self.dc.set_high().map_err(St7789Error::Pin)?;
+
+        let glyphs: Vec<&[u8; GLYPH_WIDTH], ST7789_LINE_LEN> =
+            self.lines[line].chars().map(glyph).collect();
+        let mut pixels: Vec<u8, { ST7789_CHUNK_PIXELS * 2 }> = Vec::new();
+        for row in 0..CHAR_HEIGHT * scale {
+            let bit = row / scale;
+            for x in 0..self.width as usize {
+                let col = x / scale;
+                let on = glyphs
+                    .get(col / CHAR_WIDTH)
+                    .and_then(|g| g.get(col % CHAR_WIDTH))
+                    .is_some_and(|column| column & (1 << bit) != 0);
+                let color: u16 = if on { 0xFFFF } else { 0x0000 };
+                pixels.extend_from_slice(&color.to_be_bytes()).ok();
+                if pixels.is_full() {
+                    self.spi.write(&pixels).await.map_err(St7789Error::Spi)?;
+                    pixels.clear();
+                }
+            }
+        }
+        if !pixels.is_empty() {
+            self.spi.write(&pixels).await.map_err(St7789Error::Spi)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: SpiDevice, P: OutputPin> TextCanvas for St7789<S, P> {
+    fn clear(&mut self) {
+        self.lines.iter_mut().for_each(|line| line.clear());
+    }
+
+    fn write_line(&mut self, line: u8, text: &str) {
+        let line = line as usize;
+        if line >= self.num_lines() {
+            return;
+        }
+        self.lines[line].clear();
+        for c in text.chars() {
+            if self.lines[line].push(c).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl<S: SpiDevice, P: OutputPin> Display for St7789<S, P> {
+    type Error = St7789Error<S::Error, P::Error>;
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        // All lines are drawn after initialized, then only changed lines
+        let redraw = !self.initialized;
+        if redraw {
+            self.init().await?;
+            self.initialized = true;
+        }
+        for line in 0..self.num_lines() {
+            if redraw || self.lines[line] != self.shown[line] {
+                self.draw_line(line).await?;
+                self.shown[line] = self.lines[line].clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct NoI2c;
+
+    impl embedded_hal_async::i2c::ErrorType for NoI2c {
+        type Error = core::convert::Infallible;
+    }
+
+    impl I2c for NoI2c {
+        async fn transaction(
+            &mut self,
+            _address: u8,
+            _operations: &mut [embedded_hal_async::i2c::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ssd1306_text() {
+        let mut display = Ssd1306::new(NoI2c, 0x3C, 32);
+        display.write_line(1, "AB");
+        assert_eq!(display.buffer[1][..5], *glyph('A'));
+        assert_eq!(display.buffer[1][5], 0);
+        assert_eq!(display.buffer[1][6..11], *glyph('B'));
+        // Out of the screen
+        display.write_line(4, "A");
+        assert!(display.buffer[4].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,97 @@
+use core::{cell::Cell, fmt::Write};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use heapless::String;
+
+use super::TextCanvas;
+
+/// Modifier state of the keyboard, published by the report builder whenever it changes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ModifierIndicator {
+    /// Hid modifier bits which are currently sent to the host
+    pub held: u8,
+    /// Hid modifier bits of pending one-shot modifiers
+    pub one_shot: u8,
+    /// Whether Caps Word is active
+    pub caps_word: bool,
+}
+
+static MODIFIER_INDICATOR: Mutex<CriticalSectionRawMutex, Cell<ModifierIndicator>> =
+    Mutex::new(Cell::new(ModifierIndicator {
+        held: 0,
+        one_shot: 0,
+        caps_word: false,
+    }));
+
+// Wakes the display task up when the modifier state is changed
+pub(crate) static MODIFIER_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Get the latest modifier state
+pub fn modifier_indicator() -> ModifierIndicator {
+    MODIFIER_INDICATOR.lock(|m| m.get())
+}
+
+pub(crate) fn publish_modifier_indicator(indicator: ModifierIndicator) {
+    MODIFIER_INDICATOR.lock(|m| m.set(indicator));
+    MODIFIER_CHANGED.signal(());
+}
+
+/// Draw the modifier widget on the given line, for example `C S - G  os:A  CW`.
+///
+/// Left and right modifiers are merged, `-` means the modifier is not held.
+pub fn render_modifier_widget(
+    indicator: &ModifierIndicator,
+    line: u8,
+    canvas: &mut dyn TextCanvas,
+) {
+    // Merge left(low 4 bits) and right(high 4 bits) modifiers
+    let held = (indicator.held | (indicator.held >> 4)) & 0x0F;
+    let one_shot = (indicator.one_shot | (indicator.one_shot >> 4)) & 0x0F;
+
+    let mut text: String<24> = String::new();
+    for (i, name) in ['C', 'S', 'A', 'G'].iter().enumerate() {
+        text.push(if held & (1 << i) != 0 { *name } else { '-' })
+            .ok();
+        text.push(' ').ok();
+    }
+    if one_shot != 0 {
+        text.push_str(" os:").ok();
+        for (i, name) in ['C', 'S', 'A', 'G'].iter().enumerate() {
+            if one_shot & (1 << i) != 0 {
+                text.push(*name).ok();
+            }
+        }
+    }
+    if indicator.caps_word {
+        write!(text, "  CW").ok();
+    }
+    canvas.write_line(line, &text);
+}
+
+/// Draw the BLE link widget on the given line, for example `###. -62dBm ~-65 3%`.
+///
+/// It shows the signal bars, the latest and the averaged RSSI and the failure rate of the link,
+/// see [`crate::ble::diagnostics::link_diagnostics`].
+#[cfg(feature = "_ble")]
+pub fn render_ble_link_widget(
+    diagnostics: &crate::ble::diagnostics::LinkDiagnostics,
+    line: u8,
+    canvas: &mut dyn TextCanvas,
+) {
+    let mut text: String<24> = String::new();
+    let bars = diagnostics.signal_bars();
+    for i in 0..4 {
+        text.push(if i < bars { '#' } else { '.' }).ok();
+    }
+    match (diagnostics.rssi, diagnostics.average_rssi) {
+        (Some(rssi), Some(average)) => write!(text, " {}dBm ~{}", rssi, average).ok(),
+        (Some(rssi), None) => write!(text, " {}dBm", rssi).ok(),
+        _ => text.push_str(" --").ok(),
+    };
+    write!(text, " {}%", diagnostics.failure_rate()).ok();
+    canvas.write_line(line, &text);
+}
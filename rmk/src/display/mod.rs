@@ -0,0 +1,321 @@
+//! Status display
+//!
+//! RMK renders text pages on a display, the display hardware is abstracted by [`TextCanvas`] and [`Display`],
+//! so that any display crate(e.g. `ssd1306` + `embedded-graphics`) can be used by implementing these traits.
+//!
+//! Built-in pages are [`DisplayPage::Status`], [`DisplayPage::Stats`], [`DisplayPage::Animation`], [`DisplayPage::Blank`] and [`DisplayPage::Health`].
+//! The status page also shows held modifiers, pending one-shot modifiers and Caps Word, see [`render_modifier_widget`].
+//! With BLE, the stats page shows the host link quality, see `render_ble_link_widget`.
+//! While the key tester is running, a key tester page is shown instead of the active page, see [`crate::diagnostic`].
+//! User code can add more pages by [`register_display_page`]. Pages are cycled by `User13`, the active page is saved in storage.
+//! The display is turned off after the display timeout of the active [`crate::power::PowerProfile`], and turned on by a key press.
+//!
+//! With the `display_drivers` feature, [`Ssd1306`] OLEDs over I2C and [`St7789`] LCDs over SPI can be used directly.
+
+#[cfg(feature = "display_drivers")]
+mod driver;
+mod font;
+mod indicator;
+mod pages;
+mod status;
+mod wpm;
+
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use embassy_futures::select::select4;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Instant, Timer};
+use heapless::Vec;
+
+#[cfg(feature = "display_drivers")]
+pub use driver::{Ssd1306, St7789, St7789Error};
+pub(crate) use indicator::publish_modifier_indicator;
+#[cfg(feature = "_ble")]
+pub use indicator::render_ble_link_widget;
+pub use indicator::{modifier_indicator, render_modifier_widget, ModifierIndicator};
+pub use status::DisplayStatus;
+#[cfg(feature = "split")]
+pub(crate) use status::{update_central_status, update_peer_battery_level, CentralStatus};
+pub use wpm::current_wpm;
+pub(crate) use wpm::record_key_press;
+
+use crate::{
+    adjust::adjust_status,
+    config::DisplayConfig,
+    diagnostic::key_tester_active,
+    generation::{bump_generation, ConfigItem},
+    indicator::INDICATOR_CHANNEL,
+    power::active_power_settings,
+    sleep::host_suspended,
+    storage::{FlashOperationMessage, FLASH_CHANNEL},
+};
+
+/// Max number of pages registered by user code
+pub const MAX_CUSTOM_PAGES: usize = crate::ram_profile::MAX_CUSTOM_PAGES;
+
+/// Number of built-in pages
+const NUM_BUILTIN_PAGES: u8 = 5;
+
+/// A display which shows lines of text
+pub trait TextCanvas {
+    /// Clear the whole screen
+    fn clear(&mut self);
+
+    /// Write a line of text, lines out of the screen should be ignored
+    fn write_line(&mut self, line: u8, text: &str);
+}
+
+/// Display driver, which sends the drawn content to the screen
+pub trait Display: TextCanvas {
+    type Error;
+
+    /// Send the drawn content to the screen
+    async fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Render function of a custom page
+pub type PageRenderer = fn(&DisplayStatus, &mut dyn TextCanvas);
+
+#[derive(Clone, Copy)]
+struct CustomPage {
+    name: &'static str,
+    render: PageRenderer,
+}
+
+/// Pages of the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisplayPage {
+    /// Layer, lock LEDs, connection, battery, modifiers and WPM
+    Status,
+    /// Uptime, power profile and link statistics
+    Stats,
+    Animation,
+    /// Nothing is shown
+    Blank,
+    /// Key presses, reconnections and errors, see [`crate::health`]
+    Health,
+    /// Page registered by user code, the value is the registration order
+    Custom(u8),
+}
+
+impl DisplayPage {
+    fn index(self) -> u8 {
+        match self {
+            DisplayPage::Status => 0,
+            DisplayPage::Stats => 1,
+            DisplayPage::Animation => 2,
+            DisplayPage::Blank => 3,
+            DisplayPage::Health => 4,
+            DisplayPage::Custom(i) => NUM_BUILTIN_PAGES + i,
+        }
+    }
+
+    fn from_index(index: u8) -> Self {
+        match index {
+            0 => DisplayPage::Status,
+            1 => DisplayPage::Stats,
+            2 => DisplayPage::Animation,
+            3 => DisplayPage::Blank,
+            4 => DisplayPage::Health,
+            i => DisplayPage::Custom(i - NUM_BUILTIN_PAGES),
+        }
+    }
+}
+
+static CUSTOM_PAGES: Mutex<CriticalSectionRawMutex, RefCell<Vec<CustomPage, MAX_CUSTOM_PAGES>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+// Index of the active page
+static ACTIVE_PAGE: AtomicU8 = AtomicU8::new(0);
+
+// Wakes the display task up when the active page is changed
+static PAGE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Time of the last key press or pointer motion
+static LAST_KEY_ACTIVITY: Mutex<CriticalSectionRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::from_ticks(0)));
+
+// Whether the display is turned off by the display timeout
+static DISPLAY_OFF: AtomicBool = AtomicBool::new(false);
+
+/// Register a custom page, which is shown after built-in pages when cycling.
+///
+/// Returns `None` if there're already [`MAX_CUSTOM_PAGES`] custom pages.
+pub fn register_display_page(name: &'static str, render: PageRenderer) -> Option<DisplayPage> {
+    CUSTOM_PAGES.lock(|pages| {
+        let mut pages = pages.borrow_mut();
+        let index = pages.len() as u8;
+        match pages.push(CustomPage { name, render }) {
+            Ok(_) => Some(DisplayPage::Custom(index)),
+            Err(_) => {
+                warn!("Too many display pages, {} is not registered", name);
+                None
+            }
+        }
+    })
+}
+
+fn num_pages() -> u8 {
+    NUM_BUILTIN_PAGES + CUSTOM_PAGES.lock(|pages| pages.borrow().len() as u8)
+}
+
+/// Get the active page
+pub fn active_display_page() -> DisplayPage {
+    let index = ACTIVE_PAGE.load(Ordering::Relaxed);
+    if index < num_pages() {
+        DisplayPage::from_index(index)
+    } else {
+        // The saved page might be a custom page which is not registered yet
+        DisplayPage::Status
+    }
+}
+
+/// Switch to the given page and save it to storage
+pub fn set_display_page(page: DisplayPage) {
+    let index = page.index();
+    if index >= num_pages() {
+        warn!("Display page {:?} is not registered", page);
+        return;
+    }
+    ACTIVE_PAGE.store(index, Ordering::Relaxed);
+    PAGE_CHANGED.signal(());
+    bump_generation(ConfigItem::Settings);
+    if FLASH_CHANNEL
+        .try_send(FlashOperationMessage::DisplayPage(index))
+        .is_err()
+    {
+        warn!("Failed to save display page, storage channel is full");
+    }
+}
+
+/// Switch to the next page, wraps around
+pub fn next_display_page() {
+    let next = (active_display_page().index() + 1) % num_pages();
+    set_display_page(DisplayPage::from_index(next));
+}
+
+/// Redraw the display immediately
+pub(crate) fn request_redraw() {
+    PAGE_CHANGED.signal(());
+}
+
+#[cfg(feature = "calculator")]
+fn calculator_active() -> bool {
+    crate::calculator::calculator_active()
+}
+
+#[cfg(not(feature = "calculator"))]
+fn calculator_active() -> bool {
+    false
+}
+
+/// Time of the last key press or pointer motion
+pub(crate) fn last_key_activity() -> Instant {
+    LAST_KEY_ACTIVITY.lock(|t| t.get())
+}
+
+/// Record a key press or pointer motion, which turns the display on if it's turned off by the display timeout
+pub(crate) fn notify_key_activity() {
+    LAST_KEY_ACTIVITY.lock(|t| t.set(Instant::now()));
+    crate::sleep::wake_up_with_host();
+    if DISPLAY_OFF.load(Ordering::Relaxed) {
+        PAGE_CHANGED.signal(());
+    }
+}
+
+/// Restore the page saved in storage
+pub(crate) fn restore_display_page(index: u8) {
+    ACTIVE_PAGE.store(index, Ordering::Relaxed);
+    PAGE_CHANGED.signal(());
+}
+
+/// Run the display, this function never returns.
+///
+/// The active page is redrawn every `refresh_interval`, or `animation_interval` for the animation page,
+/// and immediately after the active page, the layer, the lock LEDs or the modifier state is changed.
+pub async fn run_display<D: Display>(mut display: D, config: DisplayConfig) -> ! {
+    let mut frame: u32 = 0;
+    let mut indicator_changes = INDICATOR_CHANNEL.subscriber().ok();
+    if indicator_changes.is_none() {
+        warn!("No indicator subscriber left, layer changes are shown at the next refresh");
+    }
+    LAST_KEY_ACTIVITY.lock(|t| t.set(Instant::now()));
+    loop {
+        let timeout = active_power_settings().display_timeout;
+        let idle = LAST_KEY_ACTIVITY.lock(|t| t.get()).elapsed();
+        if (timeout.is_some_and(|t| idle >= t) || host_suspended()) && !key_tester_active() {
+            // Turn the display off and keep it off until a key is pressed or the host resumes
+            DISPLAY_OFF.store(true, Ordering::Relaxed);
+            display.clear();
+            if display.flush().await.is_err() {
+                error!("Flush display error");
+            }
+            PAGE_CHANGED.wait().await;
+            DISPLAY_OFF.store(false, Ordering::Relaxed);
+            continue;
+        }
+
+        let page = active_display_page();
+        let status = DisplayStatus::current();
+        display.clear();
+        // The key tester page is shown over all pages while the key tester is running, and so are the calculator and encoder adjustment
+        if key_tester_active() {
+            pages::render_key_tester(&mut display);
+        } else if calculator_active() {
+            #[cfg(feature = "calculator")]
+            pages::render_calculator(&mut display);
+        } else if adjust_status().is_some() {
+            pages::render_adjust(&mut display);
+        } else {
+            match page {
+                DisplayPage::Status => pages::render_status(&status, &mut display),
+                DisplayPage::Stats => pages::render_stats(&status, &mut display),
+                DisplayPage::Animation => pages::render_animation(frame, &mut display),
+                DisplayPage::Blank => (),
+                DisplayPage::Health => pages::render_health(&mut display),
+                DisplayPage::Custom(i) => {
+                    let custom = CUSTOM_PAGES.lock(|pages| pages.borrow().get(i as usize).copied());
+                    if let Some(custom) = custom {
+                        debug!("Render display page: {}", custom.name);
+                        (custom.render)(&status, &mut display);
+                    }
+                }
+            }
+        }
+        if display.flush().await.is_err() {
+            error!("Flush display error");
+        }
+
+        let mut interval = if page == DisplayPage::Animation {
+            config.animation_interval
+        } else {
+            config.refresh_interval
+        };
+        if let Some(timeout) = timeout {
+            // Wake up in time to turn the display off
+            interval = interval.min(timeout.checked_sub(idle).unwrap_or(interval));
+        }
+        select4(
+            Timer::after(interval),
+            PAGE_CHANGED.wait(),
+            indicator::MODIFIER_CHANGED.wait(),
+            async {
+                match indicator_changes.as_mut() {
+                    Some(sub) => {
+                        sub.next_message_pure().await;
+                    }
+                    None => core::future::pending().await,
+                }
+            },
+        )
+        .await;
+        frame = frame.wrapping_add(1);
+    }
+}
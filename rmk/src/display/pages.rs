@@ -0,0 +1,210 @@
+//! Built-in display pages
+
+use core::fmt::Write;
+
+use embassy_time::Instant;
+use heapless::String;
+
+use super::{
+    indicator::{modifier_indicator, render_modifier_widget},
+    status::DisplayStatus,
+    TextCanvas,
+};
+#[cfg(feature = "calculator")]
+use crate::calculator::{calculator_state, CalcError};
+use crate::{
+    adjust::{adjust_status, AdjustTarget},
+    diagnostic::key_tester_status,
+    health::health_status,
+    pomodoro::{pomodoro_status, PomodoroPhase},
+    power::{active_power_profile, PowerProfile, PowerSource},
+};
+
+/// Max length of a text line
+const LINE_LEN: usize = 24;
+
+/// Format a line and write it to the canvas, overlong text is truncated
+macro_rules! write_line {
+    ($canvas:expr, $line:expr, $($arg:tt)*) => {{
+        let mut text: String<LINE_LEN> = String::new();
+        write!(text, $($arg)*).ok();
+        $canvas.write_line($line, &text);
+    }};
+}
+
+/// Layer, lock LEDs, connection, battery, modifiers and WPM
+pub(crate) fn render_status(status: &DisplayStatus, canvas: &mut dyn TextCanvas) {
+    let leds = status.host_leds;
+    write_line!(
+        canvas,
+        0,
+        "Layer: {}{}{}{}",
+        status.layer,
+        if leds.capslock() { " CAPS" } else { "" },
+        if leds.numslock() { " NUM" } else { "" },
+        if leds.scrolllock() { " SCRL" } else { "" }
+    );
+    let connection = if status.connected {
+        "connected"
+    } else {
+        "waiting"
+    };
+    if status.ble {
+        write_line!(canvas, 1, "BLE {}: {}", status.ble_profile, connection);
+    } else {
+        write_line!(canvas, 1, "USB: {}", connection);
+    }
+    match (status.battery_level, status.power_source) {
+        (_, PowerSource::Usb) => canvas.write_line(2, "Battery: charging"),
+        (Some(level), _) => write_line!(canvas, 2, "Battery: {}%", level),
+        (None, _) => canvas.write_line(2, "Battery: --"),
+    }
+    if let Some(peer) = status.peer_battery_level {
+        // Split keyboard, show battery of both halves
+        match status.battery_level {
+            Some(level) => write_line!(canvas, 2, "Bat: {}% / {}%", level, peer),
+            None => write_line!(canvas, 2, "Bat: -- / {}%", peer),
+        }
+    }
+    render_modifier_widget(&modifier_indicator(), 3, canvas);
+    // Only shown on displays with more than 4 lines
+    write_line!(canvas, 4, "WPM: {}", status.wpm);
+}
+
+/// Uptime, power profile, link statistics and the pomodoro timer
+pub(crate) fn render_stats(_status: &DisplayStatus, canvas: &mut dyn TextCanvas) {
+    let uptime = Instant::now().as_secs();
+    write_line!(
+        canvas,
+        0,
+        "Up: {}:{:02}:{:02}",
+        uptime / 3600,
+        (uptime / 60) % 60,
+        uptime % 60
+    );
+    canvas.write_line(
+        3,
+        match active_power_profile() {
+            PowerProfile::Performance => "Power: performance",
+            PowerProfile::Balanced => "Power: balanced",
+            PowerProfile::Saver => "Power: saver",
+        },
+    );
+
+    #[cfg(feature = "_ble")]
+    {
+        use crate::ble::diagnostics::{link_diagnostics, BleLink};
+        let host = link_diagnostics(BleLink::Host);
+        super::render_ble_link_widget(&host, 1, canvas);
+        write_line!(
+            canvas,
+            2,
+            "Lost: {}/{}",
+            host.failed,
+            host.sent + host.failed
+        );
+    }
+
+    let timer = pomodoro_status();
+    let phase = match timer.phase {
+        PomodoroPhase::Idle => None,
+        PomodoroPhase::Work => Some("Work"),
+        PomodoroPhase::ShortBreak | PomodoroPhase::LongBreak => Some("Break"),
+    };
+    if let Some(phase) = phase {
+        let secs = timer.remaining.as_secs();
+        write_line!(
+            canvas,
+            4,
+            "{} {:02}:{:02}{}",
+            phase,
+            secs / 60,
+            secs % 60,
+            if timer.running { "" } else { " paused" }
+        );
+    }
+}
+
+/// Key presses, reconnections and errors since boot, and lifetime key presses
+pub(crate) fn render_health(canvas: &mut dyn TextCanvas) {
+    let status = health_status();
+    let boot = status.since_boot;
+    write_line!(canvas, 0, "Keys: {}", boot.key_presses);
+    write_line!(canvas, 1, "Reconnects: {}", boot.reconnects);
+    write_line!(canvas, 2, "Errors: {}", boot.errors);
+    write_line!(
+        canvas,
+        3,
+        "Total: {}h {} keys",
+        status.lifetime.uptime_secs / 3600,
+        status.lifetime.key_presses
+    );
+}
+
+/// The parameter adjusted by encoders, shown over all pages while adjusting
+pub(crate) fn render_adjust(canvas: &mut dyn TextCanvas) {
+    let Some((target, value)) = adjust_status() else {
+        return;
+    };
+    write_line!(canvas, 0, "Adjust: {}", target.name());
+    match target {
+        AdjustTarget::TappingTerm => write_line!(canvas, 1, "{} ms", value),
+        AdjustTarget::ActuationPoint => {
+            write_line!(canvas, 1, "{}.{:02} mm", value / 100, value % 100)
+        }
+        _ => write_line!(canvas, 1, "{}", value),
+    }
+    canvas.write_line(2, "Turn to adjust");
+}
+
+/// A dot bouncing between both ends of the first line
+pub(crate) fn render_animation(frame: u32, canvas: &mut dyn TextCanvas) {
+    const WIDTH: usize = 16;
+    let period = (WIDTH as u32 - 1) * 2;
+    let phase = (frame % period) as usize;
+    let pos = if phase < WIDTH {
+        phase
+    } else {
+        period as usize - phase
+    };
+
+    let mut text: String<WIDTH> = String::new();
+    for i in 0..WIDTH {
+        text.push(if i == pos { '*' } else { ' ' }).ok();
+    }
+    canvas.write_line(0, &text);
+}
+
+/// Expression and evaluation error of the calculator
+#[cfg(feature = "calculator")]
+pub(crate) fn render_calculator(canvas: &mut dyn TextCanvas) {
+    let state = calculator_state();
+    canvas.write_line(0, "Calculator");
+    canvas.write_line(1, &state.expression);
+    match state.error {
+        Some(CalcError::Syntax) => canvas.write_line(2, "Syntax error"),
+        Some(CalcError::DivideByZero) => canvas.write_line(2, "Divide by zero"),
+        Some(CalcError::Overflow) => canvas.write_line(2, "Overflow"),
+        None => canvas.write_line(2, ""),
+    }
+}
+
+/// Latest key event, number of tested keys and bounces of the key tester
+pub(crate) fn render_key_tester(canvas: &mut dyn TextCanvas) {
+    let status = key_tester_status();
+    canvas.write_line(0, "Key tester");
+    match status.last_event {
+        Some(e) => write_line!(
+            canvas,
+            1,
+            "({},{}) {}",
+            e.row,
+            e.col,
+            if e.pressed { "down" } else { "up" }
+        ),
+        None => canvas.write_line(1, "Press any key"),
+    }
+    write_line!(canvas, 2, "Keys: {}", status.tested_keys);
+    write_line!(canvas, 3, "Presses: {}", status.presses);
+    write_line!(canvas, 4, "Bounces: {}", status.bounces);
+}
@@ -0,0 +1,122 @@
+use core::sync::atomic::Ordering;
+#[cfg(feature = "split")]
+use {
+    core::{cell::Cell, sync::atomic::AtomicU8},
+    embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+};
+
+use super::wpm::current_wpm;
+use crate::{
+    indicator::LedIndicator,
+    keymap::ACTIVE_LAYER,
+    power::{battery_level, current_power_source, PowerSource},
+    CONNECTION_STATE, CONNECTION_TYPE,
+};
+
+/// Snapshot of the keyboard status, which is rendered by display pages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DisplayStatus {
+    /// Current active layer
+    pub layer: u8,
+    /// Battery level of this half in percent
+    pub battery_level: Option<u8>,
+    /// Battery level of the other half of a split keyboard in percent
+    pub peer_battery_level: Option<u8>,
+    pub power_source: PowerSource,
+    /// Whether the keyboard is connected to the host via BLE, otherwise USB
+    pub ble: bool,
+    /// Whether the connection to the host is ready
+    pub connected: bool,
+    /// Active BLE profile, it's 0 if the keyboard doesn't support multiple profiles
+    pub ble_profile: u8,
+    /// Lock LEDs set by the host
+    pub host_leds: LedIndicator,
+    /// Typing speed in words per minute
+    pub wpm: u8,
+}
+
+/// Status synced from the split central, split peripherals show it instead of their own state
+#[cfg(feature = "split")]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CentralStatus {
+    pub(crate) layer: u8,
+    pub(crate) battery_level: Option<u8>,
+    pub(crate) ble: bool,
+    pub(crate) connected: bool,
+}
+
+#[cfg(feature = "split")]
+static CENTRAL_STATUS: Mutex<CriticalSectionRawMutex, Cell<Option<CentralStatus>>> =
+    Mutex::new(Cell::new(None));
+
+// Battery level reported by the split peripheral, 0xFF if unknown
+#[cfg(feature = "split")]
+static PEER_BATTERY_LEVEL: AtomicU8 = AtomicU8::new(0xFF);
+
+/// Update the status received from the split central
+#[cfg(feature = "split")]
+pub(crate) fn update_central_status(status: CentralStatus) {
+    CENTRAL_STATUS.lock(|s| s.set(Some(status)));
+}
+
+/// Update the battery level received from the split peripheral
+#[cfg(feature = "split")]
+pub(crate) fn update_peer_battery_level(level: u8) {
+    PEER_BATTERY_LEVEL.store(level, Ordering::Relaxed);
+}
+
+#[cfg(feature = "_nrf_ble")]
+fn ble_profile() -> u8 {
+    crate::ble::nrf::ACTIVE_PROFILE.load(Ordering::Relaxed)
+}
+
+#[cfg(not(feature = "_nrf_ble"))]
+fn ble_profile() -> u8 {
+    0
+}
+
+impl DisplayStatus {
+    /// Read current status of the keyboard.
+    ///
+    /// On split peripherals, layer and connection state are the status synced from the central.
+    pub fn current() -> Self {
+        Self {
+            layer: ACTIVE_LAYER.load(Ordering::Relaxed),
+            battery_level: battery_level(),
+            peer_battery_level: None,
+            power_source: current_power_source(),
+            ble: CONNECTION_TYPE.load(Ordering::Relaxed) == 1,
+            connected: CONNECTION_STATE.load(Ordering::Relaxed),
+            ble_profile: ble_profile(),
+            host_leds: crate::indicator::indicator_state().host_leds,
+            wpm: current_wpm(),
+        }
+        .with_split_status()
+    }
+
+    #[cfg(feature = "split")]
+    fn with_split_status(self) -> Self {
+        match CENTRAL_STATUS.lock(|s| s.get()) {
+            Some(central) => Self {
+                layer: central.layer,
+                peer_battery_level: central.battery_level,
+                ble: central.ble,
+                connected: central.connected,
+                ..self
+            },
+            None => Self {
+                peer_battery_level: match PEER_BATTERY_LEVEL.load(Ordering::Relaxed) {
+                    0xFF => None,
+                    level => Some(level),
+                },
+                ..self
+            },
+        }
+    }
+
+    #[cfg(not(feature = "split"))]
+    fn with_split_status(self) -> Self {
+        self
+    }
+}
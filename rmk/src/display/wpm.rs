@@ -0,0 +1,79 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Key presses are counted in 1 second buckets over this many seconds
+const WPM_WINDOW_SECS: usize = crate::ram_profile::WPM_WINDOW_SECS;
+
+/// Typing speed counter, a word is 5 key presses
+struct WpmCounter {
+    buckets: [u16; WPM_WINDOW_SECS],
+    /// Second of the latest bucket since boot
+    current: u64,
+}
+
+impl WpmCounter {
+    const fn new() -> Self {
+        Self {
+            buckets: [0; WPM_WINDOW_SECS],
+            current: 0,
+        }
+    }
+
+    /// Move the window to `secs`, buckets which fall out of the window are cleared
+    fn advance(&mut self, secs: u64) {
+        if secs <= self.current {
+            return;
+        }
+        let stale = (secs - self.current).min(WPM_WINDOW_SECS as u64);
+        for i in 1..=stale {
+            self.buckets[((self.current + i) % WPM_WINDOW_SECS as u64) as usize] = 0;
+        }
+        self.current = secs;
+    }
+
+    fn record(&mut self, secs: u64) {
+        self.advance(secs);
+        let bucket = &mut self.buckets[(self.current % WPM_WINDOW_SECS as u64) as usize];
+        *bucket = bucket.saturating_add(1);
+    }
+
+    fn wpm(&mut self, secs: u64) -> u8 {
+        self.advance(secs);
+        let presses: u32 = self.buckets.iter().map(|&b| b as u32).sum();
+        // presses / 5 words in the window, scaled to a minute
+        (presses * 60 / (5 * WPM_WINDOW_SECS as u32)).min(u8::MAX as u32) as u8
+    }
+}
+
+static WPM: Mutex<CriticalSectionRawMutex, RefCell<WpmCounter>> =
+    Mutex::new(RefCell::new(WpmCounter::new()));
+
+/// Current typing speed in words per minute, averaged over the last 10 seconds(5 seconds with `low_ram`)
+pub fn current_wpm() -> u8 {
+    WPM.lock(|w| w.borrow_mut().wpm(Instant::now().as_secs()))
+}
+
+/// Count a key press of the matrix
+pub(crate) fn record_key_press() {
+    WPM.lock(|w| w.borrow_mut().record(Instant::now().as_secs()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wpm_counter() {
+        let mut counter = WpmCounter::new();
+        // 50 presses in 10 seconds is 10 words, which is 60 wpm
+        for i in 0..50 {
+            counter.record(i / 5);
+        }
+        assert_eq!(counter.wpm(9), 60);
+        // Presses of the first 5 seconds fall out of the window
+        assert_eq!(counter.wpm(14), 30);
+        assert_eq!(counter.wpm(100), 0);
+    }
+}
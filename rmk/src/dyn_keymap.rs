@@ -0,0 +1,401 @@
+//! Staging buffer of keymaps with runtime dimensions
+//!
+//! [`DynKeyMap`] stores actions in a [`heapless::Vec`], its number of rows, columns and layers are decided at runtime,
+//! only the total number of actions is bounded by `MAX_KEYS`. It's useful to stage a keymap which is treated as data,
+//! for example a layout loaded from a VIA definition, which might have a different number of layers than the firmware.
+//!
+//! [`DynKeyMap`] isn't used by the keyboard: `run_rmk`, Via/Vial and the storage work on the fixed-size keymap only.
+//! Use [`DynKeyMap::copy_to`] to load it into the fixed-size keymap passed to `run_rmk`, keys and layers out of the fixed-size
+//! keymap are dropped.
+//!
+//! Actions are stored layer by layer, row by row, which is the same order as the keymap sent by VIA.
+//!
+//! The layer state of [`DynKeyMap`] is its own, it doesn't change the active layer of the running keyboard.
+
+use heapless::Vec;
+
+use crate::{action::KeyAction, event::KeyEvent};
+
+/// Max number of layers of [`DynKeyMap`], limited by the bits of the layer state
+pub const MAX_DYN_LAYERS: usize = 32;
+
+/// Errors of [`DynKeyMap`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DynKeyMapError {
+    /// `rows * cols * layers` exceeds `MAX_KEYS`
+    TooManyKeys,
+    /// Number of layers exceeds [`MAX_DYN_LAYERS`]
+    TooManyLayers,
+    /// Row, column or layer is out of the keymap, or the number of actions doesn't match the dimensions
+    OutOfRange,
+}
+
+/// A keymap whose dimensions are decided at runtime, bounded by `MAX_KEYS` actions in total
+pub struct DynKeyMap<const MAX_KEYS: usize> {
+    rows: u8,
+    cols: u8,
+    /// Actions of all layers, layer-major
+    actions: Vec<KeyAction, MAX_KEYS>,
+    /// Bit `i` is set if layer `i` is activated
+    layer_state: u32,
+    /// Default layer number
+    default_layer: u8,
+    /// Layer of each pressed key, so that the key is released on the same layer
+    layer_cache: Vec<Option<u8>, MAX_KEYS>,
+}
+
+impl<const MAX_KEYS: usize> DynKeyMap<MAX_KEYS> {
+    /// Create a keymap with all keys set to [`KeyAction::No`]
+    pub fn new(rows: u8, cols: u8, layers: u8) -> Result<Self, DynKeyMapError> {
+        if layers as usize > MAX_DYN_LAYERS {
+            return Err(DynKeyMapError::TooManyLayers);
+        }
+        let num_keys = rows as usize * cols as usize;
+        let mut actions = Vec::new();
+        actions
+            .resize(num_keys * layers as usize, KeyAction::No)
+            .map_err(|_| DynKeyMapError::TooManyKeys)?;
+        let mut layer_cache = Vec::new();
+        layer_cache
+            .resize(num_keys, None)
+            .map_err(|_| DynKeyMapError::TooManyKeys)?;
+        Ok(Self {
+            rows,
+            cols,
+            actions,
+            layer_state: 0,
+            default_layer: 0,
+            layer_cache,
+        })
+    }
+
+    /// Create a keymap from actions of all layers, in the order of layer, row, column
+    pub fn from_actions(rows: u8, cols: u8, actions: &[KeyAction]) -> Result<Self, DynKeyMapError> {
+        let num_keys = rows as usize * cols as usize;
+        if num_keys == 0 || actions.len() % num_keys != 0 {
+            return Err(DynKeyMapError::OutOfRange);
+        }
+        let layers = actions.len() / num_keys;
+        if layers > MAX_DYN_LAYERS {
+            return Err(DynKeyMapError::TooManyLayers);
+        }
+        let mut keymap = Self::new(rows, cols, layers as u8)?;
+        keymap.actions.copy_from_slice(actions);
+        Ok(keymap)
+    }
+
+    /// Create a keymap from a fixed-size keymap
+    pub fn from_layers<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+        layers: &[[[KeyAction; COL]; ROW]; NUM_LAYER],
+    ) -> Result<Self, DynKeyMapError> {
+        if NUM_LAYER > MAX_DYN_LAYERS {
+            return Err(DynKeyMapError::TooManyLayers);
+        }
+        let mut keymap = Self::new(ROW as u8, COL as u8, NUM_LAYER as u8)?;
+        for (i, action) in layers.iter().flatten().flatten().enumerate() {
+            keymap.actions[i] = *action;
+        }
+        Ok(keymap)
+    }
+
+    /// Copy the keymap into a fixed-size keymap.
+    ///
+    /// Keys which are not in this keymap are set to [`KeyAction::No`], keys which don't fit are ignored.
+    pub fn copy_to<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+        &self,
+        layers: &mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+    ) {
+        for (layer_idx, layer) in layers.iter_mut().enumerate() {
+            for (row_idx, row) in layer.iter_mut().enumerate() {
+                for (col_idx, action) in row.iter_mut().enumerate() {
+                    *action = self
+                        .get_action(row_idx as u8, col_idx as u8, layer_idx as u8)
+                        .unwrap_or(KeyAction::No);
+                }
+            }
+        }
+    }
+
+    pub fn rows(&self) -> u8 {
+        self.rows
+    }
+
+    pub fn cols(&self) -> u8 {
+        self.cols
+    }
+
+    pub fn num_layers(&self) -> u8 {
+        match self.rows as usize * self.cols as usize {
+            0 => 0,
+            num_keys => (self.actions.len() / num_keys) as u8,
+        }
+    }
+
+    /// Actions of all layers, in the order of layer, row, column
+    pub fn actions(&self) -> &[KeyAction] {
+        &self.actions
+    }
+
+    /// Change the number of layers, added layers are filled with [`KeyAction::Transparent`]
+    pub fn set_num_layers(&mut self, layers: u8) -> Result<(), DynKeyMapError> {
+        if layers as usize > MAX_DYN_LAYERS {
+            return Err(DynKeyMapError::TooManyLayers);
+        }
+        let num_keys = self.rows as usize * self.cols as usize;
+        self.actions
+            .resize(num_keys * layers as usize, KeyAction::Transparent)
+            .map_err(|_| DynKeyMapError::TooManyKeys)?;
+        // Deactivate removed layers
+        self.layer_state &= 1u32.checked_shl(layers as u32).unwrap_or(0).wrapping_sub(1);
+        if self.default_layer >= layers {
+            self.default_layer = 0;
+        }
+        // Pressed keys on removed layers are released on current layers
+        for layer in self.layer_cache.iter_mut() {
+            if layer.is_some_and(|l| l >= layers) {
+                *layer = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&self, row: u8, col: u8, layer: u8) -> Option<usize> {
+        if row >= self.rows || col >= self.cols || layer >= self.num_layers() {
+            return None;
+        }
+        Some(
+            (layer as usize * self.rows as usize + row as usize) * self.cols as usize
+                + col as usize,
+        )
+    }
+
+    /// Get the action at the given position, `None` if the position is out of the keymap
+    pub fn get_action(&self, row: u8, col: u8, layer: u8) -> Option<KeyAction> {
+        self.index(row, col, layer).map(|i| self.actions[i])
+    }
+
+    /// Set the action at the given position
+    pub fn set_action(
+        &mut self,
+        row: u8,
+        col: u8,
+        layer: u8,
+        action: KeyAction,
+    ) -> Result<(), DynKeyMapError> {
+        let i = self
+            .index(row, col, layer)
+            .ok_or(DynKeyMapError::OutOfRange)?;
+        self.actions[i] = action;
+        Ok(())
+    }
+
+    /// Get the default layer number
+    pub fn default_layer(&self) -> u8 {
+        self.default_layer
+    }
+
+    /// Set the default layer number
+    pub fn set_default_layer(&mut self, layer: u8) -> Result<(), DynKeyMapError> {
+        if layer >= self.num_layers() {
+            return Err(DynKeyMapError::OutOfRange);
+        }
+        self.default_layer = layer;
+        Ok(())
+    }
+
+    /// Activate given layer
+    pub fn activate_layer(&mut self, layer: u8) {
+        if layer < self.num_layers() {
+            self.layer_state |= 1 << layer;
+        }
+    }
+
+    /// Deactivate given layer
+    pub fn deactivate_layer(&mut self, layer: u8) {
+        if layer < self.num_layers() {
+            self.layer_state &= !(1 << layer);
+        }
+    }
+
+    /// Toggle given layer
+    pub fn toggle_layer(&mut self, layer: u8) {
+        if layer < self.num_layers() {
+            self.layer_state ^= 1 << layer;
+        }
+    }
+
+    fn is_layer_active(&self, layer: u8) -> bool {
+        self.layer_state & (1 << layer) != 0 || layer == self.default_layer
+    }
+
+    /// The highest activated layer
+    pub fn active_layer(&self) -> u8 {
+        (0..self.num_layers())
+            .rev()
+            .find(|&layer| self.is_layer_active(layer))
+            .unwrap_or(self.default_layer)
+    }
+
+    /// Get the action triggered by the key event.
+    ///
+    /// Same as the fixed-size keymap, the layer of a pressed key is cached, so the release is handled on the same layer.
+    pub fn get_action_with_layer_cache(&mut self, key_event: KeyEvent) -> KeyAction {
+        if key_event.row >= self.rows || key_event.col >= self.cols {
+            return KeyAction::No;
+        }
+        let key_idx = key_event.row as usize * self.cols as usize + key_event.col as usize;
+        if !key_event.pressed {
+            // Releasing a pressed key, use cached layer and clear the cache.
+            // A key without cached layer is released on current layers
+            if let Some(layer) = self.layer_cache[key_idx].take() {
+                return self
+                    .get_action(key_event.row, key_event.col, layer)
+                    .unwrap_or(KeyAction::No);
+            }
+        }
+
+        // Iterate from higher layer to lower layer, the lowest checked layer is the default layer
+        for layer in (0..self.num_layers()).rev() {
+            if self.is_layer_active(layer) {
+                let action = self
+                    .get_action(key_event.row, key_event.col, layer)
+                    .unwrap_or(KeyAction::No);
+                if action != KeyAction::Transparent && action != KeyAction::No {
+                    if key_event.pressed {
+                        self.layer_cache[key_idx] = Some(layer);
+                    }
+                    return action;
+                }
+            }
+            if layer == self.default_layer {
+                // No action, the release is resolved on the default layer as well
+                if key_event.pressed {
+                    self.layer_cache[key_idx] = Some(layer);
+                }
+                break;
+            }
+        }
+
+        KeyAction::No
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{a, k};
+
+    fn key(row: u8, col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent { row, col, pressed }
+    }
+
+    #[test]
+    fn test_index() {
+        // 2 layers of 2x3 keys
+        let actions = [
+            k!(A),
+            k!(B),
+            k!(C),
+            k!(D),
+            k!(E),
+            k!(F),
+            k!(Kc1),
+            k!(Kc2),
+            k!(Kc3),
+            k!(Kc4),
+            k!(Kc5),
+            k!(Kc6),
+        ];
+        let mut keymap = DynKeyMap::<16>::from_actions(2, 3, &actions).unwrap();
+        assert_eq!(keymap.num_layers(), 2);
+        assert_eq!(keymap.get_action(0, 2, 0), Some(k!(C)));
+        assert_eq!(keymap.get_action(1, 0, 0), Some(k!(D)));
+        assert_eq!(keymap.get_action(1, 2, 1), Some(k!(Kc6)));
+        assert_eq!(keymap.get_action(2, 0, 0), None);
+        assert_eq!(keymap.get_action(0, 3, 0), None);
+        assert_eq!(keymap.get_action(0, 0, 2), None);
+        keymap.set_action(1, 1, 1, k!(Z)).unwrap();
+        assert_eq!(keymap.actions()[10], k!(Z));
+        assert_eq!(
+            keymap.set_action(0, 0, 2, k!(Z)),
+            Err(DynKeyMapError::OutOfRange)
+        );
+
+        assert_eq!(
+            DynKeyMap::<16>::from_actions(2, 3, &actions[..7]).err(),
+            Some(DynKeyMapError::OutOfRange)
+        );
+        assert_eq!(
+            DynKeyMap::<8>::from_actions(2, 3, &actions).err(),
+            Some(DynKeyMapError::TooManyKeys)
+        );
+    }
+
+    #[test]
+    fn test_set_num_layers() {
+        let mut keymap = DynKeyMap::<16>::new(2, 2, 2).unwrap();
+        keymap.set_action(0, 0, 1, k!(A)).unwrap();
+        keymap.set_default_layer(1).unwrap();
+        keymap.activate_layer(1);
+
+        // Added layers are transparent
+        keymap.set_num_layers(3).unwrap();
+        assert_eq!(keymap.get_action(1, 1, 2), Some(a!(Transparent)));
+        assert_eq!(keymap.get_action(0, 0, 1), Some(k!(A)));
+
+        // Removed layers are deactivated, the default layer is reset
+        keymap.set_num_layers(1).unwrap();
+        assert_eq!(keymap.num_layers(), 1);
+        assert_eq!(keymap.default_layer(), 0);
+        assert_eq!(keymap.active_layer(), 0);
+        assert_eq!(keymap.get_action(0, 0, 1), None);
+
+        assert_eq!(keymap.set_num_layers(5), Err(DynKeyMapError::TooManyKeys));
+        assert_eq!(
+            keymap.set_num_layers(33),
+            Err(DynKeyMapError::TooManyLayers)
+        );
+    }
+
+    #[test]
+    fn test_layer_cache() {
+        let actions = [k!(A), k!(C), k!(B), a!(Transparent)];
+        let mut keymap = DynKeyMap::<8>::from_actions(1, 2, &actions).unwrap();
+        assert_eq!(keymap.get_action_with_layer_cache(key(0, 0, true)), k!(A));
+
+        // Released on the layer where it's pressed
+        keymap.activate_layer(1);
+        assert_eq!(keymap.active_layer(), 1);
+        assert_eq!(keymap.get_action_with_layer_cache(key(0, 0, false)), k!(A));
+        assert_eq!(keymap.get_action_with_layer_cache(key(0, 0, true)), k!(B));
+
+        // Transparent key falls through to the default layer
+        assert_eq!(keymap.get_action_with_layer_cache(key(0, 1, true)), k!(C));
+        keymap.deactivate_layer(1);
+        assert_eq!(keymap.get_action_with_layer_cache(key(0, 0, false)), k!(B));
+        assert_eq!(keymap.get_action_with_layer_cache(key(0, 1, false)), k!(C));
+
+        // Out of the keymap
+        assert_eq!(keymap.get_action_with_layer_cache(key(1, 0, true)), a!(No));
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let actions = [k!(A), k!(B), k!(C), k!(D)];
+        let keymap = DynKeyMap::<8>::from_actions(1, 2, &actions).unwrap();
+
+        // Keys which don't fit are ignored, missing keys are set to `No`
+        let mut layers = [[[a!(Transparent); 3]; 2]; 1];
+        keymap.copy_to(&mut layers);
+        assert_eq!(layers[0][0], [k!(A), k!(B), a!(No)]);
+        assert_eq!(layers[0][1], [a!(No); 3]);
+
+        let layers = [[[k!(A), k!(B)]], [[k!(C), k!(D)]]];
+        let keymap = DynKeyMap::<8>::from_layers(&layers).unwrap();
+        assert_eq!(keymap.actions(), &actions);
+        let mut copied = [[[a!(No); 2]; 1]; 2];
+        keymap.copy_to(&mut copied);
+        assert_eq!(copied, layers);
+    }
+}
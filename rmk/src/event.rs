@@ -1,3 +1,4 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
@@ -6,8 +7,9 @@ use crate::input_device::rotary_encoder::Direction;
 /// Raw events from input devices and keyboards
 ///
 /// This should be as close to the raw output of the devices as possible.
-/// The input processors receives it, processes it,
-/// and then converts it to the final keyboard/mouse report.
+/// Events sent to `EVENT_CHANNEL` are routed by RMK: keys and rotary encoders are processed with the keymap,
+/// other events are sent to `POINTING_EVENT_CHANNEL`, the input processors receive them from there,
+/// and then convert them to the final mouse report.
 #[non_exhaustive]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Event {
@@ -15,6 +17,8 @@ pub enum Event {
     Key(KeyEvent),
     /// Rotary encoder, ec11 compatible models
     RotaryEncoder(RotaryEncoderEvent),
+    /// Relative motion of a pointing device, such as a trackball or a mouse sensor
+    Pointer(PointerEvent),
     /// Multi-touch touchpad
     Touchpad(TouchpadEvent),
     /// Joystick, suppose we have x,y,z axes for this joystick
@@ -37,6 +41,16 @@ pub struct RotaryEncoderEvent {
     pub direction: Direction,
 }
 
+/// Event for relative pointer motion
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, MaxSize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PointerEvent {
+    /// Motion along the x axis, positive to the right
+    pub x: i16,
+    /// Motion along the y axis, positive downwards
+    pub y: i16,
+}
+
 /// Event for multi-touch touchpad
 #[derive(Serialize, Deserialize, Clone, Debug, MaxSize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -86,3 +100,59 @@ pub struct KeyEvent {
     pub col: u8,
     pub pressed: bool,
 }
+
+/// Max number of subscribers of layer events
+pub const LAYER_EVENT_SUBSCRIBERS: usize = 6;
+
+/// Channel which publishes every change of the layer state.
+///
+/// Displays, lighting and split sync can subscribe it instead of polling the keymap.
+/// If a subscriber lags behind, the oldest events are dropped.
+pub static LAYER_EVENT_CHANNEL: PubSubChannel<
+    CriticalSectionRawMutex,
+    LayerEvent,
+    4,
+    LAYER_EVENT_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+
+/// A change of the layer state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LayerEvent {
+    pub change: LayerChange,
+    pub cause: LayerChangeCause,
+    /// The highest activated layer after the change
+    pub active_layer: u8,
+}
+
+/// What's changed in the layer state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LayerChange {
+    Activated(u8),
+    Deactivated(u8),
+    /// The default layer is set to the given layer
+    DefaultLayer(u8),
+}
+
+/// Who changed the layer state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LayerChangeCause {
+    /// A layer action in the keymap, such as `MO`, `TG`, `OSL` or layer tap-hold
+    KeyAction,
+    /// The tri-layer behavior
+    TriLayer,
+    /// A command from the host
+    Host,
+}
+
+/// Publish a layer event to all subscribers
+pub(crate) fn publish_layer_event(event: LayerEvent) {
+    debug!("Layer event: {:?}", event);
+    crate::indicator::notify_layer_changed(event.active_layer);
+    LAYER_EVENT_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(event);
+}
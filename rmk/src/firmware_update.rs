@@ -0,0 +1,535 @@
+//! A/B firmware update with rollback
+//!
+//! It works with the [embassy-boot](https://github.com/embassy-rs/embassy/tree/main/embassy-boot) bootloader,
+//! the flash is split into the active slot, which runs the firmware, the DFU slot, which receives the new image, and a state page.
+//! The DFU slot should be at least one page larger than the active slot, so it only fits chips with enough flash.
+//!
+//! The host writes a new image to the DFU slot by RawHID command `0xF9`. The whole DFU slot is erased when an image begins,
+//! then the image is written in blocks without erasing, so it works with any page size of the flash.
+//! After the image is complete, it's marked updated and the keyboard reboots, then the bootloader swaps the two slots.
+//!
+//! The first boot of the new image has to pass the health check in [`FirmwareValidation`], then the image is marked booted.
+//! If the check fails, or the new image crashes or hangs before it's marked booted, the bootloader swaps back to the previous image
+//! at the next reset.
+//!
+//! With the `signed_firmware_update` feature, the host sends an Ed25519 signature of the image before finishing, and the image
+//! is only marked updated if the signature is verified by `public_key` of [`FirmwareValidation`], which is built into the firmware.
+//! The signature is made over the SHA-512 digest of the image, which is the scheme of embassy-boot.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+#[cfg(feature = "signed_firmware_update")]
+use embassy_boot::FirmwareUpdaterError;
+use embassy_boot::{AlignedBuffer, FirmwareUpdater, State};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage_async::nor_flash::{NorFlash, NorFlashError};
+use heapless::Vec;
+
+use crate::{health::health_status, reboot_keyboard, CONNECTION_STATE};
+
+/// Max bytes of image data in a RawHID write
+pub(crate) const MAX_CHUNK_LEN: usize = 24;
+
+/// Length of an Ed25519 signature
+pub(crate) const SIGNATURE_LEN: usize = 64;
+
+/// Received image data is written to the DFU slot in blocks, which should be a multiple of the write size of the flash
+const BLOCK_SIZE: usize = 256;
+
+/// Padding of the last block, which is the value of erased flash
+const ERASED: u8 = 0xFF;
+
+pub(crate) static FIRMWARE_UPDATE_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    FirmwareUpdateMessage,
+    2,
+> = Channel::new();
+
+static UPDATE_STATE: AtomicU8 = AtomicU8::new(FirmwareUpdateState::Idle as u8);
+static IMAGE_SIZE: AtomicU32 = AtomicU32::new(0);
+static RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) enum FirmwareUpdateMessage {
+    /// Start receiving an image of the given size
+    Begin(u32),
+    /// Image data at the offset
+    Write(u32, Vec<u8, MAX_CHUNK_LEN>),
+    /// Signature data at the offset
+    #[cfg(feature = "signed_firmware_update")]
+    Signature(u8, Vec<u8, MAX_CHUNK_LEN>),
+    /// All data is sent, mark the image updated and reboot
+    Finish,
+    Abort,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FirmwareUpdateState {
+    /// Ready to receive a new image
+    Idle = 0,
+    /// Receiving a new image
+    Receiving = 1,
+    /// The new image is received, the keyboard is rebooting into it
+    Updated = 2,
+    /// The running image is booted for the first time, it isn't marked booted until the health check passes
+    Validating = 3,
+    /// Writing the image failed, or the image is incomplete. Begin again to retry
+    Failed = 4,
+    /// The signature of the image is missing or invalid, the image isn't marked updated. Begin again to retry
+    Rejected = 5,
+}
+
+impl From<u8> for FirmwareUpdateState {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => FirmwareUpdateState::Receiving,
+            2 => FirmwareUpdateState::Updated,
+            3 => FirmwareUpdateState::Validating,
+            4 => FirmwareUpdateState::Failed,
+            5 => FirmwareUpdateState::Rejected,
+            _ => FirmwareUpdateState::Idle,
+        }
+    }
+}
+
+/// Progress of the firmware update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareUpdateStatus {
+    pub state: FirmwareUpdateState,
+    /// Size of the image being received
+    pub image_size: u32,
+    /// Bytes of the image received
+    pub received: u32,
+}
+
+/// Get the progress of the firmware update
+pub fn firmware_update_status() -> FirmwareUpdateStatus {
+    FirmwareUpdateStatus {
+        state: UPDATE_STATE.load(Ordering::Relaxed).into(),
+        image_size: IMAGE_SIZE.load(Ordering::Relaxed),
+        received: RECEIVED.load(Ordering::Relaxed),
+    }
+}
+
+fn set_state(state: FirmwareUpdateState) {
+    UPDATE_STATE.store(state as u8, Ordering::Relaxed);
+}
+
+/// Checks of new images, the health check of the first boot, and the signature with `signed_firmware_update`
+#[derive(Clone, Copy, Debug)]
+pub struct FirmwareValidation {
+    /// The new image is rolled back if it doesn't pass the check in this time
+    pub timeout: Duration,
+    /// The new image should keep running for this time since boot
+    pub min_uptime: Duration,
+    /// The keyboard should be connected to a host, by USB or BLE
+    pub require_connection: bool,
+    /// Max number of errors of the health counters, see [`crate::health`]
+    pub max_errors: u32,
+    /// Ed25519 public key which verifies signatures of images, every image is rejected if it's `None`
+    #[cfg(feature = "signed_firmware_update")]
+    pub public_key: Option<&'static [u8; 32]>,
+}
+
+impl Default for FirmwareValidation {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            min_uptime: Duration::from_secs(10),
+            require_connection: true,
+            max_errors: 10,
+            #[cfg(feature = "signed_firmware_update")]
+            public_key: None,
+        }
+    }
+}
+
+impl FirmwareValidation {
+    /// Returns `Some(true)` if the check passes, `Some(false)` if it fails, `None` if it isn't decided yet
+    fn check(&self) -> Option<bool> {
+        if health_status().since_boot.errors > self.max_errors {
+            return Some(false);
+        }
+        let connected = !self.require_connection || CONNECTION_STATE.load(Ordering::Acquire);
+        let uptime = Instant::now().as_micros();
+        (connected && uptime >= self.min_uptime.as_micros()).then_some(true)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum ImageError {
+    /// The data is after the next offset, chunks should be sent in order
+    UnexpectedOffset(u32),
+    TooLarge,
+}
+
+impl From<ImageError> for FirmwareUpdateState {
+    fn from(_: ImageError) -> Self {
+        FirmwareUpdateState::Failed
+    }
+}
+
+/// Collects image data, which is sent in order, into blocks
+struct ImageWriter {
+    size: u32,
+    /// Offset of the next byte
+    next_offset: u32,
+    /// Offset of the block in the image
+    block_offset: u32,
+    block: AlignedBuffer<BLOCK_SIZE>,
+    block_len: usize,
+    signature: Vec<u8, SIGNATURE_LEN>,
+}
+
+impl ImageWriter {
+    fn new(size: u32) -> Self {
+        Self {
+            size,
+            next_offset: 0,
+            block_offset: 0,
+            block: AlignedBuffer([ERASED; BLOCK_SIZE]),
+            block_len: 0,
+            signature: Vec::new(),
+        }
+    }
+
+    /// Append data at `offset` to the block, returns the number of consumed bytes, which is less than `data.len()` if the block is full.
+    ///
+    /// Data before the next offset is received already, it's resent by the host, so it's consumed and ignored
+    fn push(&mut self, offset: u32, data: &[u8]) -> Result<usize, ImageError> {
+        if offset < self.next_offset {
+            return Ok(((self.next_offset - offset) as usize).min(data.len()));
+        }
+        if offset > self.next_offset {
+            return Err(ImageError::UnexpectedOffset(offset));
+        }
+        if offset as u64 + data.len() as u64 > self.size as u64 {
+            return Err(ImageError::TooLarge);
+        }
+        let n = data.len().min(BLOCK_SIZE - self.block_len);
+        self.block.0[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+        self.block_len += n;
+        self.next_offset += n as u32;
+        Ok(n)
+    }
+
+    /// The block and its offset in the image, if it's full or `last` is true and it isn't empty.
+    /// The last block is padded with the erased value
+    fn block(&self, last: bool) -> Option<(u32, &[u8])> {
+        let ready = self.block_len == BLOCK_SIZE || (last && self.block_len > 0);
+        ready.then_some((self.block_offset, &self.block.0[..]))
+    }
+
+    /// Start the next block after the current one is written
+    fn next_block(&mut self) {
+        self.block_offset += BLOCK_SIZE as u32;
+        self.block.0 = [ERASED; BLOCK_SIZE];
+        self.block_len = 0;
+    }
+
+    /// Append signature data at `offset`, it's sent in order as well, resent data is ignored
+    fn push_signature(&mut self, offset: u8, data: &[u8]) -> Result<(), ImageError> {
+        let received = self.signature.len();
+        if offset as usize > received {
+            return Err(ImageError::UnexpectedOffset(offset as u32));
+        }
+        let skip = (received - offset as usize).min(data.len());
+        self.signature
+            .extend_from_slice(&data[skip..])
+            .map_err(|_| ImageError::TooLarge)
+    }
+
+    fn complete(&self) -> bool {
+        self.next_offset == self.size
+    }
+}
+
+/// First boot of a new image, mark it booted if the health check passes, otherwise reboot to roll it back
+async fn validate_new_image<DFU: NorFlash, STATE: NorFlash>(
+    updater: &mut FirmwareUpdater<'_, DFU, STATE>,
+    validation: &FirmwareValidation,
+) {
+    warn!("First boot of the new firmware, validating");
+    set_state(FirmwareUpdateState::Validating);
+    let check = async {
+        loop {
+            if let Some(passed) = validation.check() {
+                return passed;
+            }
+            Timer::after_millis(500).await;
+        }
+    };
+    let passed = match select(check, Timer::after(validation.timeout)).await {
+        Either::First(passed) => passed,
+        Either::Second(_) => false,
+    };
+    if passed {
+        match updater.mark_booted().await {
+            Ok(_) => {
+                info!("New firmware is validated");
+                set_state(FirmwareUpdateState::Idle);
+                return;
+            }
+            Err(e) => error!("Failed to mark the new firmware booted: {:?}", e),
+        }
+    }
+    // The image isn't marked booted, the bootloader swaps back to the previous image
+    error!("New firmware fails the health check, rolling back");
+    Timer::after_millis(100).await;
+    reboot_keyboard();
+}
+
+/// Validate the first boot of a new image, then receive images from the host. This function never returns.
+///
+/// `updater` is created from the DFU and state partitions of the bootloader, see [`FirmwareUpdater`].
+pub async fn run_firmware_update<DFU: NorFlash, STATE: NorFlash>(
+    mut updater: FirmwareUpdater<'_, DFU, STATE>,
+    validation: FirmwareValidation,
+) -> ! {
+    match updater.get_state().await {
+        Ok(State::Swap) => validate_new_image(&mut updater, &validation).await,
+        Ok(_) => (),
+        Err(e) => error!("Failed to read the firmware update state: {:?}", e),
+    }
+
+    // Size of the image which begins while another image is being received
+    let mut begin = None;
+    loop {
+        let size = match begin.take() {
+            Some(size) => size,
+            None => match FIRMWARE_UPDATE_CHANNEL.receive().await {
+                FirmwareUpdateMessage::Begin(size) => size,
+                FirmwareUpdateMessage::Abort => {
+                    set_state(FirmwareUpdateState::Idle);
+                    continue;
+                }
+                // No image is being received
+                _ => continue,
+            },
+        };
+        match receive_image(&mut updater, size, &validation).await {
+            Ok(next) => begin = next,
+            Err(state) => set_state(state),
+        }
+    }
+}
+
+/// Erase the DFU slot and receive an image of `size` bytes into it, then mark it updated and reboot.
+///
+/// Returns the size of the next image if another image begins, or `None` if it's aborted
+async fn receive_image<DFU: NorFlash, STATE: NorFlash>(
+    updater: &mut FirmwareUpdater<'_, DFU, STATE>,
+    size: u32,
+    validation: &FirmwareValidation,
+) -> Result<Option<u32>, FirmwareUpdateState> {
+    info!("Receiving firmware image of {} bytes", size);
+    IMAGE_SIZE.store(size, Ordering::Relaxed);
+    RECEIVED.store(0, Ordering::Relaxed);
+    if BLOCK_SIZE % DFU::WRITE_SIZE != 0 {
+        error!(
+            "Write size {} of the DFU flash isn't supported",
+            DFU::WRITE_SIZE
+        );
+        return Err(FirmwareUpdateState::Failed);
+    }
+    // The whole DFU slot is erased at once, blocks are written without erasing then
+    let dfu = updater.prepare_update().await.map_err(|e| {
+        error!("Failed to erase the DFU slot: {:?}", e);
+        FirmwareUpdateState::Failed
+    })?;
+    if size as usize > dfu.capacity() {
+        error!(
+            "Firmware image is larger than the DFU slot of {} bytes",
+            dfu.capacity()
+        );
+        return Err(FirmwareUpdateState::Failed);
+    }
+    set_state(FirmwareUpdateState::Receiving);
+    let mut writer = ImageWriter::new(size);
+    loop {
+        match FIRMWARE_UPDATE_CHANNEL.receive().await {
+            FirmwareUpdateMessage::Begin(size) => return Ok(Some(size)),
+            FirmwareUpdateMessage::Write(offset, data) => {
+                write_chunk(dfu, &mut writer, offset, &data).await?
+            }
+            #[cfg(feature = "signed_firmware_update")]
+            FirmwareUpdateMessage::Signature(offset, data) => {
+                writer.push_signature(offset, &data).map_err(|e| {
+                    error!("Invalid firmware signature chunk at {}: {:?}", offset, e);
+                    FirmwareUpdateState::from(e)
+                })?
+            }
+            FirmwareUpdateMessage::Finish => break,
+            FirmwareUpdateMessage::Abort => {
+                info!("Firmware update is aborted");
+                set_state(FirmwareUpdateState::Idle);
+                return Ok(None);
+            }
+        }
+    }
+    if !writer.complete() {
+        error!(
+            "Firmware image is incomplete, {} of {} bytes are received",
+            writer.next_offset, writer.size
+        );
+        return Err(FirmwareUpdateState::Failed);
+    }
+    if let Some((block_offset, block)) = writer.block(true) {
+        write_block(dfu, block_offset, block).await?;
+    }
+    mark_updated(updater, &writer, validation).await?;
+    info!("Firmware image is received, rebooting into it");
+    set_state(FirmwareUpdateState::Updated);
+    // Leave some time for the host to read the status
+    Timer::after_millis(500).await;
+    reboot_keyboard();
+    Ok(None)
+}
+
+/// Write a chunk of the image, full blocks are written to the DFU slot
+async fn write_chunk<DFU: NorFlash>(
+    dfu: &mut DFU,
+    writer: &mut ImageWriter,
+    mut offset: u32,
+    mut data: &[u8],
+) -> Result<(), FirmwareUpdateState> {
+    while !data.is_empty() {
+        let n = writer.push(offset, data).map_err(|e| {
+            error!("Invalid firmware chunk at {}: {:?}", offset, e);
+            FirmwareUpdateState::from(e)
+        })?;
+        offset += n as u32;
+        data = &data[n..];
+        if let Some((block_offset, block)) = writer.block(false) {
+            write_block(dfu, block_offset, block).await?;
+            writer.next_block();
+        }
+    }
+    RECEIVED.store(writer.next_offset, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Write a block to the erased DFU slot
+async fn write_block<DFU: NorFlash>(
+    dfu: &mut DFU,
+    offset: u32,
+    block: &[u8],
+) -> Result<(), FirmwareUpdateState> {
+    dfu.write(offset, block).await.map_err(|e| {
+        error!("Failed to write firmware: {:?}", e.kind());
+        FirmwareUpdateState::Failed
+    })
+}
+
+/// Mark the received image updated
+#[cfg(not(feature = "signed_firmware_update"))]
+async fn mark_updated<DFU: NorFlash, STATE: NorFlash>(
+    updater: &mut FirmwareUpdater<'_, DFU, STATE>,
+    _writer: &ImageWriter,
+    _validation: &FirmwareValidation,
+) -> Result<(), FirmwareUpdateState> {
+    updater.mark_updated().await.map_err(|e| {
+        error!("Failed to mark the firmware updated: {:?}", e);
+        FirmwareUpdateState::Failed
+    })
+}
+
+/// Verify the signature of the received image, and mark it updated if the signature is valid
+#[cfg(feature = "signed_firmware_update")]
+async fn mark_updated<DFU: NorFlash, STATE: NorFlash>(
+    updater: &mut FirmwareUpdater<'_, DFU, STATE>,
+    writer: &ImageWriter,
+    validation: &FirmwareValidation,
+) -> Result<(), FirmwareUpdateState> {
+    let Some(public_key) = validation.public_key else {
+        error!("No public key of firmware signatures, the image is rejected");
+        return Err(FirmwareUpdateState::Rejected);
+    };
+    let Ok(signature) = <&[u8; SIGNATURE_LEN]>::try_from(&writer.signature[..]) else {
+        error!(
+            "Firmware signature is incomplete, {} of {} bytes are received",
+            writer.signature.len(),
+            SIGNATURE_LEN
+        );
+        return Err(FirmwareUpdateState::Rejected);
+    };
+    match updater
+        .verify_and_mark_updated(public_key, signature, writer.size)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(FirmwareUpdaterError::Signature(_)) => {
+            error!("Firmware signature is invalid, the image is rejected");
+            Err(FirmwareUpdateState::Rejected)
+        }
+        Err(e) => {
+            error!("Failed to mark the firmware updated: {:?}", e);
+            Err(FirmwareUpdateState::Failed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_image_writer() {
+        let mut writer = ImageWriter::new(300);
+        let chunk = [0xAB; MAX_CHUNK_LEN];
+        // Out of order
+        assert_eq!(
+            writer.push(24, &chunk),
+            Err(ImageError::UnexpectedOffset(24))
+        );
+        let mut offset = 0;
+        let mut blocks = 0;
+        while offset < 288 {
+            let mut data = &chunk[..];
+            while !data.is_empty() {
+                let n = writer.push(offset, data).unwrap();
+                offset += n as u32;
+                data = &data[n..];
+                if let Some((block_offset, block)) = writer.block(false) {
+                    assert_eq!(block_offset, 0);
+                    assert!(block.iter().all(|&b| b == 0xAB));
+                    writer.next_block();
+                    blocks += 1;
+                }
+            }
+        }
+        assert_eq!(blocks, 1);
+        // A resent chunk is ignored, and the overlapped part of a chunk is skipped
+        assert_eq!(writer.push(264, &chunk), Ok(MAX_CHUNK_LEN));
+        assert_eq!(writer.push(276, &chunk), Ok(12));
+        assert_eq!(writer.next_offset, 288);
+        // Larger than the image
+        assert_eq!(writer.push(288, &chunk), Err(ImageError::TooLarge));
+        writer.push(288, &chunk[..12]).unwrap();
+        assert!(writer.complete());
+        // The last block has 44 bytes of data, the rest is padded
+        let (block_offset, block) = writer.block(true).unwrap();
+        assert_eq!(block_offset, 256);
+        assert!(block[..44].iter().all(|&b| b == 0xAB));
+        assert!(block[44..].iter().all(|&b| b == ERASED));
+
+        // Signature chunks are sent in order, and it's at most 64 bytes
+        assert_eq!(
+            writer.push_signature(24, &chunk),
+            Err(ImageError::UnexpectedOffset(24))
+        );
+        writer.push_signature(0, &chunk).unwrap();
+        writer.push_signature(24, &chunk).unwrap();
+        assert_eq!(writer.push_signature(48, &chunk), Err(ImageError::TooLarge));
+        writer.push_signature(48, &chunk[..16]).unwrap();
+        assert_eq!(writer.signature.len(), SIGNATURE_LEN);
+        // Resent signature data is ignored
+        writer.push_signature(40, &chunk).unwrap();
+        assert_eq!(writer.signature.len(), SIGNATURE_LEN);
+    }
+}
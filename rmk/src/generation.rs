@@ -0,0 +1,101 @@
+//! Generation counters of the config
+//!
+//! The keymap, macros and settings each have a generation, which is increased whenever they're changed, by the host, by a key
+//! or by a config import. Generations start from 0 at boot, and the epoch is increased at every boot if the storage is used,
+//! so the epoch and a generation identify a version of the config. Host tools and split peripherals compare them with
+//! their copy to detect staleness, and resync only what changed.
+//!
+//! Host tools read them by RawHID command `0xF5 0x04`, split peripherals get the generations of the central by
+//! `rmk::split::peripheral::central_config_generations`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+/// Part of the config which has its own generation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigItem {
+    /// Keys and encoders
+    Keymap = 0,
+    /// Macros and dynamic macros
+    Macros = 1,
+    /// Default layer, NKRO, layout options, RGB lighting and the display page
+    Settings = 2,
+}
+
+/// Epoch and generations of the config
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, MaxSize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigGenerations {
+    pub epoch: u32,
+    pub keymap: u32,
+    pub macros: u32,
+    pub settings: u32,
+}
+
+impl ConfigGenerations {
+    /// Generation of the given item
+    pub fn get(&self, item: ConfigItem) -> u32 {
+        match item {
+            ConfigItem::Keymap => self.keymap,
+            ConfigItem::Macros => self.macros,
+            ConfigItem::Settings => self.settings,
+        }
+    }
+
+    /// Items which are changed since `old`, all items are changed if the epoch is different
+    pub fn changed_since(&self, old: &ConfigGenerations) -> impl Iterator<Item = ConfigItem> + '_ {
+        let epoch_changed = self.epoch != old.epoch;
+        let old = *old;
+        [ConfigItem::Keymap, ConfigItem::Macros, ConfigItem::Settings]
+            .into_iter()
+            .filter(move |&item| epoch_changed || self.get(item) != old.get(item))
+    }
+}
+
+static EPOCH: AtomicU32 = AtomicU32::new(0);
+
+static GENERATIONS: [AtomicU32; 3] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Get current epoch and generations of the config
+pub fn config_generations() -> ConfigGenerations {
+    ConfigGenerations {
+        epoch: EPOCH.load(Ordering::Relaxed),
+        keymap: GENERATIONS[ConfigItem::Keymap as usize].load(Ordering::Relaxed),
+        macros: GENERATIONS[ConfigItem::Macros as usize].load(Ordering::Relaxed),
+        settings: GENERATIONS[ConfigItem::Settings as usize].load(Ordering::Relaxed),
+    }
+}
+
+/// The item is changed, returns the new generation
+pub(crate) fn bump_generation(item: ConfigItem) -> u32 {
+    GENERATIONS[item as usize].fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Set the epoch of this boot, it's read from the storage
+pub(crate) fn set_config_epoch(epoch: u32) {
+    EPOCH.store(epoch, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changed_since() {
+        let old = ConfigGenerations {
+            epoch: 3,
+            keymap: 10,
+            macros: 2,
+            settings: 5,
+        };
+        let new = ConfigGenerations { keymap: 11, ..old };
+        assert!(new.changed_since(&old).eq([ConfigItem::Keymap]));
+        assert_eq!(old.changed_since(&old).count(), 0);
+        // Generations of another boot can't be compared
+        let rebooted = ConfigGenerations { epoch: 4, ..old };
+        assert_eq!(rebooted.changed_since(&old).count(), 3);
+    }
+}
@@ -0,0 +1,112 @@
+//! Uptime and event counters of the keyboard
+//!
+//! Key presses, reconnections to the host and errors are counted in RAM since boot.
+//! Errors are failures of sending reports to the host and storage errors.
+//!
+//! Lifetime totals are the counters since boot plus the totals of previous boots, which are restored from the storage.
+//! They're saved only if [`run_health_checkpoint`] is running, every checkpoint writes the storage once, so use a long interval.
+//!
+//! The counters are read by [`health_status`], by the host with RawHID command `0xF6`, and shown on [`crate::display::DisplayPage::Health`].
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::storage::{FlashOperationMessage, FLASH_CHANNEL};
+
+static KEY_PRESSES: AtomicU32 = AtomicU32::new(0);
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+static ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// Totals of previous boots
+static SAVED: Mutex<CriticalSectionRawMutex, Cell<HealthCounters>> =
+    Mutex::new(Cell::new(HealthCounters {
+        uptime_secs: 0,
+        key_presses: 0,
+        reconnects: 0,
+        errors: 0,
+    }));
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthCounters {
+    pub uptime_secs: u32,
+    /// Key presses of the matrix
+    pub key_presses: u32,
+    /// Reconnections after a USB resume or a BLE reconnection
+    pub reconnects: u32,
+    pub errors: u32,
+}
+
+impl HealthCounters {
+    fn saturating_add(self, other: Self) -> Self {
+        Self {
+            uptime_secs: self.uptime_secs.saturating_add(other.uptime_secs),
+            key_presses: self.key_presses.saturating_add(other.key_presses),
+            reconnects: self.reconnects.saturating_add(other.reconnects),
+            errors: self.errors.saturating_add(other.errors),
+        }
+    }
+}
+
+/// Counters since boot and lifetime totals
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthStatus {
+    pub since_boot: HealthCounters,
+    /// Totals including previous boots, which are restored from the storage
+    pub lifetime: HealthCounters,
+}
+
+/// Get the counters since boot and the lifetime totals
+pub fn health_status() -> HealthStatus {
+    let since_boot = HealthCounters {
+        uptime_secs: Instant::now().as_secs() as u32,
+        key_presses: KEY_PRESSES.load(Ordering::Relaxed),
+        reconnects: RECONNECTS.load(Ordering::Relaxed),
+        errors: ERRORS.load(Ordering::Relaxed),
+    };
+    HealthStatus {
+        since_boot,
+        lifetime: SAVED.lock(|s| s.get()).saturating_add(since_boot),
+    }
+}
+
+pub(crate) fn record_key_press() {
+    KEY_PRESSES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_reconnect() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_error() {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Restore totals of previous boots from the storage
+pub(crate) fn restore_health_counters(saved: HealthCounters) {
+    SAVED.lock(|s| s.set(saved));
+}
+
+/// Save the lifetime totals to the storage every `interval`, this function never returns.
+///
+/// Counters since the last checkpoint are lost when the power is cut, an interval of an hour or longer is recommended
+/// to reduce the flash wear.
+pub async fn run_health_checkpoint(interval: Duration) -> ! {
+    loop {
+        Timer::after(interval).await;
+        let lifetime = health_status().lifetime;
+        debug!("Health checkpoint: {:?}", lifetime);
+        if FLASH_CHANNEL
+            .try_send(FlashOperationMessage::HealthCounters(lifetime))
+            .is_err()
+        {
+            warn!("Flash channel is full, health checkpoint is skipped");
+        }
+    }
+}
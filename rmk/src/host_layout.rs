@@ -0,0 +1,337 @@
+//! Host keyboard layout translation
+//!
+//! Keycodes in the keymap are characters on the US layout. When the host uses another layout(e.g. German),
+//! the same keycode types a different character. With a [`HostLayout`] set, character keys are translated in the report path
+//! to the key and modifiers which type the same character on the host layout, so `k!(Y)` always types `y`.
+//!
+//! Only printable ASCII characters are translated, other keys are sent unchanged.
+//! Characters which are dead keys on the host layout(like `^` on German) need to be followed by a space to be typed.
+//!
+//! Text in macros is typed on the host layout as well. A macro can override the host layout by [`set_macro_layout`],
+//! for example, when it's used in an application or VM whose layout is different from the host.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{keyboard_macro::NUM_MACRO, keycode::KeyCode};
+
+/// Modifier bit of left shift
+pub(crate) const SHIFT: u8 = 0x02;
+/// Modifier bit of right shift
+pub(crate) const RSHIFT: u8 = 0x20;
+/// Modifier bit of right alt, aka AltGr
+pub(crate) const ALTGR: u8 = 0x40;
+
+static HOST_LAYOUT: AtomicU8 = AtomicU8::new(HostLayout::Us as u8);
+
+/// Macros which follow the host layout
+const NO_OVERRIDE: u8 = u8::MAX;
+
+/// Host layout override of each macro
+static MACRO_LAYOUTS: [AtomicU8; NUM_MACRO] = [const { AtomicU8::new(NO_OVERRIDE) }; NUM_MACRO];
+
+/// Keyboard layout used by the host
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostLayout {
+    /// US QWERTY, keycodes are sent unchanged
+    #[default]
+    Us = 0,
+    /// German QWERTZ
+    German = 1,
+    /// French AZERTY
+    French = 2,
+    /// Colemak
+    Colemak = 3,
+}
+
+impl From<u8> for HostLayout {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => HostLayout::German,
+            2 => HostLayout::French,
+            3 => HostLayout::Colemak,
+            _ => HostLayout::Us,
+        }
+    }
+}
+
+/// Get current host layout
+pub fn host_layout() -> HostLayout {
+    HOST_LAYOUT.load(Ordering::Relaxed).into()
+}
+
+/// Set the host layout, it's applied to keys pressed after the change
+pub fn set_host_layout(layout: HostLayout) {
+    info!("Host layout: {:?}", layout);
+    HOST_LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// Set the host layout used to type the text of the macro at `macro_idx`, `None` to follow the host layout
+pub fn set_macro_layout(macro_idx: u8, layout: Option<HostLayout>) {
+    if let Some(l) = MACRO_LAYOUTS.get(macro_idx as usize) {
+        l.store(layout.map_or(NO_OVERRIDE, |l| l as u8), Ordering::Relaxed);
+    }
+}
+
+/// Get the host layout used to type the text of the macro at `macro_idx`
+pub fn macro_layout(macro_idx: u8) -> HostLayout {
+    match MACRO_LAYOUTS
+        .get(macro_idx as usize)
+        .map(|l| l.load(Ordering::Relaxed))
+    {
+        Some(layout) if layout != NO_OVERRIDE => layout.into(),
+        _ => host_layout(),
+    }
+}
+
+impl HostLayout {
+    /// Get the key and modifiers which type the ASCII character `c` on this layout
+    pub fn key_for_char(self, c: u8) -> Option<(KeyCode, u8)> {
+        if c.is_ascii_uppercase() {
+            return self
+                .key_for_char(c.to_ascii_lowercase())
+                .map(|(key, modifier)| (key, modifier | SHIFT));
+        }
+        let mapped = match self {
+            HostLayout::Us => None,
+            HostLayout::German => german(c),
+            HostLayout::French => french(c),
+            HostLayout::Colemak => colemak(c),
+        };
+        // Characters which aren't listed are on the same key as the US layout
+        mapped.or_else(|| {
+            let (key, shifted) = KeyCode::from_ascii(c);
+            (key != KeyCode::No).then_some((key, if shifted { SHIFT } else { 0 }))
+        })
+    }
+}
+
+/// Translate a key of the keymap to the key and modifiers of the current host layout.
+///
+/// Returns `None` if the host layout is US or the key doesn't type a character.
+pub(crate) fn translate(key: KeyCode, shifted: bool) -> Option<(KeyCode, u8)> {
+    translate_to(host_layout(), key, shifted)
+}
+
+fn translate_to(layout: HostLayout, key: KeyCode, shifted: bool) -> Option<(KeyCode, u8)> {
+    if layout == HostLayout::Us {
+        return None;
+    }
+    layout.key_for_char(key.to_ascii(shifted)?)
+}
+
+fn german(c: u8) -> Option<(KeyCode, u8)> {
+    let mapped = match c {
+        b'y' => (KeyCode::Z, 0),
+        b'z' => (KeyCode::Y, 0),
+        b'^' => (KeyCode::Grave, 0),
+        b'`' => (KeyCode::Equal, SHIFT),
+        b'"' => (KeyCode::Kc2, SHIFT),
+        b'&' => (KeyCode::Kc6, SHIFT),
+        b'/' => (KeyCode::Kc7, SHIFT),
+        b'(' => (KeyCode::Kc8, SHIFT),
+        b')' => (KeyCode::Kc9, SHIFT),
+        b'=' => (KeyCode::Kc0, SHIFT),
+        b'?' => (KeyCode::Minus, SHIFT),
+        b'{' => (KeyCode::Kc7, ALTGR),
+        b'[' => (KeyCode::Kc8, ALTGR),
+        b']' => (KeyCode::Kc9, ALTGR),
+        b'}' => (KeyCode::Kc0, ALTGR),
+        b'\\' => (KeyCode::Minus, ALTGR),
+        b'@' => (KeyCode::Q, ALTGR),
+        b'+' => (KeyCode::RightBracket, 0),
+        b'*' => (KeyCode::RightBracket, SHIFT),
+        b'~' => (KeyCode::RightBracket, ALTGR),
+        b'#' => (KeyCode::NonusHash, 0),
+        b'\'' => (KeyCode::NonusHash, SHIFT),
+        b'<' => (KeyCode::NonusBackslash, 0),
+        b'>' => (KeyCode::NonusBackslash, SHIFT),
+        b'|' => (KeyCode::NonusBackslash, ALTGR),
+        b';' => (KeyCode::Comma, SHIFT),
+        b':' => (KeyCode::Dot, SHIFT),
+        b'-' => (KeyCode::Slash, 0),
+        b'_' => (KeyCode::Slash, SHIFT),
+        _ => return None,
+    };
+    Some(mapped)
+}
+
+fn french(c: u8) -> Option<(KeyCode, u8)> {
+    let mapped = match c {
+        b'a' => (KeyCode::Q, 0),
+        b'q' => (KeyCode::A, 0),
+        b'z' => (KeyCode::W, 0),
+        b'w' => (KeyCode::Z, 0),
+        b'm' => (KeyCode::Semicolon, 0),
+        b'1' => (KeyCode::Kc1, SHIFT),
+        b'2' => (KeyCode::Kc2, SHIFT),
+        b'3' => (KeyCode::Kc3, SHIFT),
+        b'4' => (KeyCode::Kc4, SHIFT),
+        b'5' => (KeyCode::Kc5, SHIFT),
+        b'6' => (KeyCode::Kc6, SHIFT),
+        b'7' => (KeyCode::Kc7, SHIFT),
+        b'8' => (KeyCode::Kc8, SHIFT),
+        b'9' => (KeyCode::Kc9, SHIFT),
+        b'0' => (KeyCode::Kc0, SHIFT),
+        b'&' => (KeyCode::Kc1, 0),
+        b'"' => (KeyCode::Kc3, 0),
+        b'\'' => (KeyCode::Kc4, 0),
+        b'(' => (KeyCode::Kc5, 0),
+        b'-' => (KeyCode::Kc6, 0),
+        b'_' => (KeyCode::Kc8, 0),
+        b')' => (KeyCode::Minus, 0),
+        b'=' => (KeyCode::Equal, 0),
+        b'+' => (KeyCode::Equal, SHIFT),
+        b'~' => (KeyCode::Kc2, ALTGR),
+        b'#' => (KeyCode::Kc3, ALTGR),
+        b'{' => (KeyCode::Kc4, ALTGR),
+        b'[' => (KeyCode::Kc5, ALTGR),
+        b'|' => (KeyCode::Kc6, ALTGR),
+        b'`' => (KeyCode::Kc7, ALTGR),
+        b'\\' => (KeyCode::Kc8, ALTGR),
+        b'^' => (KeyCode::Kc9, ALTGR),
+        b'@' => (KeyCode::Kc0, ALTGR),
+        b']' => (KeyCode::Minus, ALTGR),
+        b'}' => (KeyCode::Equal, ALTGR),
+        b'$' => (KeyCode::RightBracket, 0),
+        b'%' => (KeyCode::Quote, SHIFT),
+        b'*' => (KeyCode::NonusHash, 0),
+        b'<' => (KeyCode::NonusBackslash, 0),
+        b'>' => (KeyCode::NonusBackslash, SHIFT),
+        b',' => (KeyCode::M, 0),
+        b'?' => (KeyCode::M, SHIFT),
+        b';' => (KeyCode::Comma, 0),
+        b'.' => (KeyCode::Comma, SHIFT),
+        b':' => (KeyCode::Dot, 0),
+        b'/' => (KeyCode::Dot, SHIFT),
+        b'!' => (KeyCode::Slash, 0),
+        _ => return None,
+    };
+    Some(mapped)
+}
+
+fn colemak(c: u8) -> Option<(KeyCode, u8)> {
+    let key = match c {
+        b'f' => KeyCode::E,
+        b'p' => KeyCode::R,
+        b'g' => KeyCode::T,
+        b'j' => KeyCode::Y,
+        b'l' => KeyCode::U,
+        b'u' => KeyCode::I,
+        b'y' => KeyCode::O,
+        b';' => KeyCode::P,
+        b':' => return Some((KeyCode::P, SHIFT)),
+        b'r' => KeyCode::S,
+        b's' => KeyCode::D,
+        b't' => KeyCode::F,
+        b'd' => KeyCode::G,
+        b'n' => KeyCode::J,
+        b'e' => KeyCode::K,
+        b'i' => KeyCode::L,
+        b'o' => KeyCode::Semicolon,
+        b'k' => KeyCode::N,
+        _ => return None,
+    };
+    Some((key, 0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_for_char() {
+        let cases = [
+            // US keys are unchanged
+            (HostLayout::Us, b'y', Some((KeyCode::Y, 0))),
+            (HostLayout::Us, b'@', Some((KeyCode::Kc2, SHIFT))),
+            // QWERTZ swaps y and z
+            (HostLayout::German, b'y', Some((KeyCode::Z, 0))),
+            (HostLayout::German, b'z', Some((KeyCode::Y, 0))),
+            (HostLayout::German, b'Y', Some((KeyCode::Z, SHIFT))),
+            (HostLayout::German, b'Z', Some((KeyCode::Y, SHIFT))),
+            (HostLayout::German, b'@', Some((KeyCode::Q, ALTGR))),
+            (HostLayout::German, b'-', Some((KeyCode::Slash, 0))),
+            (HostLayout::German, b'!', Some((KeyCode::Kc1, SHIFT))),
+            (HostLayout::German, b'a', Some((KeyCode::A, 0))),
+            // AZERTY digits are shifted
+            (HostLayout::French, b'1', Some((KeyCode::Kc1, SHIFT))),
+            (HostLayout::French, b'5', Some((KeyCode::Kc5, SHIFT))),
+            (HostLayout::French, b'0', Some((KeyCode::Kc0, SHIFT))),
+            (HostLayout::French, b'&', Some((KeyCode::Kc1, 0))),
+            (HostLayout::French, b'a', Some((KeyCode::Q, 0))),
+            (HostLayout::French, b'A', Some((KeyCode::Q, SHIFT))),
+            (HostLayout::French, b'm', Some((KeyCode::Semicolon, 0))),
+            (HostLayout::French, b'b', Some((KeyCode::B, 0))),
+            (HostLayout::Colemak, b'f', Some((KeyCode::E, 0))),
+            (HostLayout::Colemak, b'F', Some((KeyCode::E, SHIFT))),
+            (HostLayout::Colemak, b':', Some((KeyCode::P, SHIFT))),
+            (HostLayout::Colemak, b'a', Some((KeyCode::A, 0))),
+            // Whitespace is on the same key, other control characters have no key
+            (HostLayout::German, b' ', Some((KeyCode::Space, 0))),
+            (HostLayout::French, b'\x07', None),
+        ];
+        for (layout, c, expected) in cases {
+            assert_eq!(
+                layout.key_for_char(c),
+                expected,
+                "{:?} {}",
+                layout,
+                c as char
+            );
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let cases = [
+            (HostLayout::German, KeyCode::Y, false, Some((KeyCode::Z, 0))),
+            (
+                HostLayout::German,
+                KeyCode::Z,
+                true,
+                Some((KeyCode::Y, SHIFT)),
+            ),
+            (
+                HostLayout::German,
+                KeyCode::Slash,
+                false,
+                Some((KeyCode::Kc7, SHIFT)),
+            ),
+            (
+                HostLayout::French,
+                KeyCode::Kc1,
+                false,
+                Some((KeyCode::Kc1, SHIFT)),
+            ),
+            (
+                HostLayout::French,
+                KeyCode::Kc1,
+                true,
+                Some((KeyCode::Slash, 0)),
+            ),
+            (HostLayout::French, KeyCode::Q, false, Some((KeyCode::A, 0))),
+            (
+                HostLayout::Colemak,
+                KeyCode::E,
+                false,
+                Some((KeyCode::K, 0)),
+            ),
+            // Keys are sent unchanged on US, and keys which don't type a character are never translated
+            (HostLayout::Us, KeyCode::Y, false, None),
+            (HostLayout::German, KeyCode::F1, false, None),
+            (HostLayout::French, KeyCode::Enter, false, None),
+        ];
+        for (layout, key, shifted, expected) in cases {
+            assert_eq!(
+                translate_to(layout, key, shifted),
+                expected,
+                "{:?} {:?} {}",
+                layout,
+                key,
+                shifted
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Indicators of the lock state of the host and the active layer
+//!
+//! The host sets its lock LEDs, like CapsLock and NumLock, by the output report of the keyboard, and the active layer
+//! is changed by the keymap. [`indicator_state`] returns the latest state of both, and every change is published to
+//! [`INDICATOR_CHANNEL`], so a GPIO LED, specific RGB LEDs or a display can follow them.
+//!
+//! GPIO LEDs can be driven by [`run_indicator_leds`]:
+//!
+//! ```rust
+//! let leds = [
+//!     IndicatorLed::new(Indicator::CapsLock, LightPinConfig { pin: caps_pin, low_active: false }),
+//!     IndicatorLed::new(Indicator::Layer(1), LightPinConfig { pin: layer_pin, low_active: false }),
+//! ];
+//! join(run_rmk(...), run_indicator_leds(leds)).await;
+//! ```
+//!
+//! For RGB LEDs, see `indicators` of [`RGBLightConfig`](crate::config::RGBLightConfig).
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embedded_hal::digital::{OutputPin, PinState};
+
+use crate::config::LightPinConfig;
+use crate::keymap::ACTIVE_LAYER;
+pub use crate::light::LedIndicator;
+
+/// Lock LEDs set by the host
+static HOST_LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// Max number of subscribers of indicator changes
+pub const INDICATOR_SUBSCRIBERS: usize = 4;
+
+/// Channel which publishes the new state on every change of the host lock LEDs or the active layer.
+///
+/// If a subscriber lags behind, the oldest states are dropped, only the latest one matters.
+pub static INDICATOR_CHANNEL: PubSubChannel<
+    CriticalSectionRawMutex,
+    IndicatorState,
+    2,
+    INDICATOR_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+
+/// What an indicator shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Indicator {
+    NumLock,
+    CapsLock,
+    ScrollLock,
+    Compose,
+    Kana,
+    /// On while the given layer is the highest active layer
+    Layer(u8),
+}
+
+/// Lock LEDs of the host and the active layer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IndicatorState {
+    pub host_leds: LedIndicator,
+    /// The highest active layer
+    pub active_layer: u8,
+}
+
+impl IndicatorState {
+    /// Whether the indicator should be on
+    pub fn is_on(&self, indicator: Indicator) -> bool {
+        match indicator {
+            Indicator::NumLock => self.host_leds.numslock(),
+            Indicator::CapsLock => self.host_leds.capslock(),
+            Indicator::ScrollLock => self.host_leds.scrolllock(),
+            Indicator::Compose => self.host_leds.compose(),
+            Indicator::Kana => self.host_leds.kana(),
+            Indicator::Layer(layer) => self.active_layer == layer,
+        }
+    }
+}
+
+/// Get current state of indicators
+pub fn indicator_state() -> IndicatorState {
+    IndicatorState {
+        host_leds: LedIndicator::from_bits(HOST_LEDS.load(Ordering::Relaxed)),
+        active_layer: ACTIVE_LAYER.load(Ordering::Relaxed),
+    }
+}
+
+fn publish_indicator_state(state: IndicatorState) {
+    INDICATOR_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(state);
+}
+
+/// The host sets its lock LEDs
+pub(crate) fn set_host_leds(leds: LedIndicator) {
+    if HOST_LEDS.swap(leds.into_bits(), Ordering::Relaxed) != leds.into_bits() {
+        publish_indicator_state(indicator_state());
+    }
+}
+
+/// The active layer is changed by the keymap
+pub(crate) fn notify_layer_changed(active_layer: u8) {
+    publish_indicator_state(IndicatorState {
+        active_layer,
+        ..indicator_state()
+    });
+}
+
+/// A GPIO LED which shows an indicator
+pub struct IndicatorLed<P: OutputPin> {
+    pub indicator: Indicator,
+    pub pin: LightPinConfig<P>,
+}
+
+impl<P: OutputPin> IndicatorLed<P> {
+    pub fn new(indicator: Indicator, pin: LightPinConfig<P>) -> Self {
+        Self { indicator, pin }
+    }
+
+    fn set(&mut self, state: &IndicatorState) {
+        let on = self.pin.low_active != state.is_on(self.indicator);
+        if self.pin.pin.set_state(PinState::from(on)).is_err() {
+            error!("Failed to set indicator LED {:?}", self.indicator);
+        }
+    }
+}
+
+/// Drive GPIO LEDs by indicators, this function never returns.
+///
+/// It should be run concurrently with the keyboard. At most [`INDICATOR_SUBSCRIBERS`] tasks can follow the indicators.
+pub async fn run_indicator_leds<P: OutputPin, const N: usize>(mut leds: [IndicatorLed<P>; N]) -> ! {
+    let Ok(mut subscriber) = INDICATOR_CHANNEL.subscriber() else {
+        error!("Too many indicator subscribers, indicator LEDs are disabled");
+        loop {
+            embassy_time::Timer::after_secs(u32::MAX as u64).await;
+        }
+    };
+    let mut state = indicator_state();
+    loop {
+        for led in leds.iter_mut() {
+            led.set(&state);
+        }
+        state = subscriber.next_message_pure().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_indicator_state() {
+        let state = IndicatorState {
+            host_leds: LedIndicator::new().with_capslock(true),
+            active_layer: 2,
+        };
+        assert!(state.is_on(Indicator::CapsLock));
+        assert!(!state.is_on(Indicator::NumLock));
+        assert!(state.is_on(Indicator::Layer(2)));
+        assert!(!state.is_on(Indicator::Layer(0)));
+    }
+}
@@ -0,0 +1,158 @@
+//! External switches outside the matrix, such as foot pedals
+//!
+//! [`ExternalSwitches`] reads switches connected to their own GPIO pins. Every switch is debounced independently,
+//! and is bound to a [`KeyAction`] directly, so it doesn't take a position in the keymap, and the action doesn't change with layers.
+//! The action is held while the switch is pressed, for example `k!(LShift)` makes a shift pedal and `mo!(1)` a layer pedal.
+//! Tap-hold actions have no hold for external switches, the tap action is used.
+//!
+//! ```rust,ignore
+//! let pedals = [
+//!     Input::new(p.P0_02, Pull::Up),
+//!     Input::new(p.P0_03, Pull::Up),
+//! ];
+//! let mut switches = ExternalSwitches::new(pedals, [k!(LShift), mo!(1)]);
+//! join(run_rmk(...), run_devices!(switches)).await;
+//! ```
+//!
+//! Use a single [`ExternalSwitches`] for all external switches, switches are identified by their index.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Sender;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::InputPin;
+
+use crate::action::KeyAction;
+use crate::keyboard::SWITCH_EVENT_CHANNEL;
+
+use super::{InputDevice, EVENT_CHANNEL_SIZE};
+
+/// State change of an external switch
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SwitchEvent {
+    /// Index of the switch
+    pub id: u8,
+    /// Action bound to the switch
+    pub action: KeyAction,
+    pub pressed: bool,
+}
+
+/// Debouncer of a single switch, a change is accepted after the switch is stable for the debounce time
+#[derive(Clone, Copy, Default)]
+struct SwitchDebouncer {
+    pressed: bool,
+    /// When the reading started to differ from `pressed`
+    changed_at: Option<Instant>,
+}
+
+impl SwitchDebouncer {
+    /// Update with a new reading, returns the new state if it's changed
+    fn update(&mut self, pressed: bool, now: Instant, debounce: Duration) -> Option<bool> {
+        if pressed == self.pressed {
+            self.changed_at = None;
+            return None;
+        }
+        match self.changed_at {
+            Some(t) if now.saturating_duration_since(t) >= debounce => {
+                self.pressed = pressed;
+                self.changed_at = None;
+                Some(pressed)
+            }
+            Some(_) => None,
+            None => {
+                self.changed_at = Some(now);
+                None
+            }
+        }
+    }
+}
+
+/// Switches connected to GPIO pins, outside the matrix
+pub struct ExternalSwitches<P: InputPin, const N: usize> {
+    pins: [P; N],
+    actions: [KeyAction; N],
+    debouncers: [SwitchDebouncer; N],
+    debounce: Duration,
+    /// Whether the pin is high when the switch is pressed
+    active_high: bool,
+}
+
+impl<P: InputPin, const N: usize> ExternalSwitches<P, N> {
+    /// Create external switches, `actions[i]` is triggered by `pins[i]`.
+    ///
+    /// The pins are low when pressed by default, like switches to the ground with pull-up pins.
+    pub fn new(pins: [P; N], actions: [KeyAction; N]) -> Self {
+        Self {
+            pins,
+            actions,
+            debouncers: [SwitchDebouncer::default(); N],
+            debounce: Duration::from_millis(20),
+            active_high: false,
+        }
+    }
+
+    /// Set the debounce time. Default is 20ms, mechanical pedals might need a longer one.
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// The pins are high when the switches are pressed
+    pub fn with_active_high(mut self) -> Self {
+        self.active_high = true;
+        self
+    }
+}
+
+impl<P: InputPin, const N: usize> InputDevice for ExternalSwitches<P, N> {
+    type EventType = SwitchEvent;
+
+    async fn run(&mut self) {
+        loop {
+            // Switches are polled, there are only a few of them
+            Timer::after_millis(1).await;
+            let now = Instant::now();
+            for id in 0..N {
+                let Ok(high) = self.pins[id].is_high() else {
+                    continue;
+                };
+                if let Some(pressed) =
+                    self.debouncers[id].update(high == self.active_high, now, self.debounce)
+                {
+                    self.event_sender()
+                        .send(SwitchEvent {
+                            id: id as u8,
+                            action: self.actions[id],
+                            pressed,
+                        })
+                        .await;
+                }
+            }
+        }
+    }
+
+    fn event_sender(&self) -> Sender<CriticalSectionRawMutex, Self::EventType, EVENT_CHANNEL_SIZE> {
+        SWITCH_EVENT_CHANNEL.sender()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_switch_debouncer() {
+        let debounce = Duration::from_millis(20);
+        let mut debouncer = SwitchDebouncer::default();
+        let at = |ms| Instant::from_millis(ms);
+        // Bounces are ignored
+        assert_eq!(debouncer.update(true, at(0), debounce), None);
+        assert_eq!(debouncer.update(false, at(5), debounce), None);
+        assert_eq!(debouncer.update(true, at(10), debounce), None);
+        assert_eq!(debouncer.update(true, at(29), debounce), None);
+        assert_eq!(debouncer.update(true, at(30), debounce), Some(true));
+        assert_eq!(debouncer.update(true, at(100), debounce), None);
+        assert_eq!(debouncer.update(false, at(100), debounce), None);
+        assert_eq!(debouncer.update(false, at(120), debounce), Some(false));
+    }
+}
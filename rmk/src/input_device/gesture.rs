@@ -0,0 +1,379 @@
+//! Gesture recognition of touchpads
+//!
+//! [`PointingProcessor`](super::pointing::PointingProcessor) recognizes gestures from absolute finger positions of
+//! [`Event::Touchpad`](crate::event::Event::Touchpad) if it's created with a [`GestureConfig`]:
+//!
+//! - One finger moves the cursor
+//! - Two fingers scroll
+//! - A short touch without moving is a tap, which taps `tap_action`, the left button by default
+//! - Touching again shortly after a tap holds `tap_action` until the finger is lifted, so a double-tap-drag drags
+//! - Moving a finger inwards from an edge taps the swipe action of the edge. Edges without an action move the cursor
+//!
+//! Touchpad drivers send an event for each finger slot whenever it changes, with absolute `X` and `Y` axes,
+//! and `Z` of 0 when the finger is lifted. Only the first two slots are used.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::action::{Action, KeyAction};
+use crate::event::{Axis, AxisValType, TouchpadEvent};
+use crate::keycode::KeyCode;
+
+use super::pointing::divide_motion;
+
+/// Number of finger slots which are tracked
+const MAX_FINGERS: usize = 2;
+
+/// Edge of the touchpad
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Edge {
+    Left = 0,
+    Right = 1,
+    Top = 2,
+    Bottom = 3,
+}
+
+/// Thresholds and actions of touchpad gestures, distances are in the units of the touchpad
+#[derive(Clone, Copy, Debug)]
+pub struct GestureConfig {
+    /// Width of the touchpad
+    pub width: i16,
+    /// Height of the touchpad
+    pub height: i16,
+    /// Max duration of a tap
+    pub tap_time: Duration,
+    /// Max distance moved by a tap
+    pub tap_distance: i16,
+    /// Max time between a tap and the next touch to start a drag
+    pub drag_time: Duration,
+    /// Distance moved by two fingers for a step of the wheel
+    pub scroll_divisor: u8,
+    /// Width of the edge areas where swipes start
+    pub edge_width: i16,
+    /// Distance moved inwards from the edge to trigger a swipe
+    pub swipe_distance: i16,
+    /// Action tapped by a tap and held by a drag
+    pub tap_action: KeyAction,
+    /// Actions of swipes from the left, right, top and bottom edges, `KeyAction::No` disables the swipe
+    pub swipe_actions: [KeyAction; 4],
+}
+
+impl GestureConfig {
+    /// Create a config with thresholds relative to the size of the touchpad
+    pub fn new(width: i16, height: i16) -> Self {
+        Self {
+            width,
+            height,
+            tap_time: Duration::from_millis(200),
+            tap_distance: width / 32,
+            drag_time: Duration::from_millis(250),
+            scroll_divisor: (width / 64).clamp(1, u8::MAX as i16) as u8,
+            edge_width: width / 16,
+            swipe_distance: width / 8,
+            tap_action: KeyAction::Single(Action::Key(KeyCode::MouseBtn1)),
+            swipe_actions: [KeyAction::No; 4],
+        }
+    }
+
+    /// Set the action of swipes from the edge
+    pub fn with_swipe(mut self, edge: Edge, action: KeyAction) -> Self {
+        self.swipe_actions[edge as usize] = action;
+        self
+    }
+}
+
+/// Output of the gesture engine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Gesture {
+    /// Cursor motion
+    Move(i16, i16),
+    /// Scroll steps, positive `y` is moving down
+    Scroll(i16, i16),
+    /// Press or release an action, `id` is 0 for the tap action, and 1 + edge for swipes
+    Action {
+        id: u8,
+        action: KeyAction,
+        pressed: bool,
+    },
+}
+
+/// A touch from the first finger down to the last finger up
+struct Touch {
+    start: Instant,
+    /// Start position of the first finger
+    start_pos: (i16, i16),
+    /// Max number of fingers during the touch
+    max_fingers: u8,
+    /// Max distance of the first finger from the start position
+    moved: i16,
+    /// Edge where the touch started, if the edge has a swipe action
+    edge: Option<Edge>,
+    swiped: bool,
+    /// The tap action is held by a drag
+    dragging: bool,
+}
+
+pub(crate) struct GestureEngine {
+    config: GestureConfig,
+    /// Last position of each finger slot, `None` if it's lifted
+    fingers: [Option<(i16, i16)>; MAX_FINGERS],
+    touch: Option<Touch>,
+    /// Time of the last tap, for double-tap-drag
+    last_tap: Option<Instant>,
+    scroll_remainder: (i16, i16),
+}
+
+impl GestureEngine {
+    pub(crate) fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            fingers: [None; MAX_FINGERS],
+            touch: None,
+            last_tap: None,
+            scroll_remainder: (0, 0),
+        }
+    }
+
+    fn tap_action(&self, pressed: bool) -> Gesture {
+        Gesture::Action {
+            id: 0,
+            action: self.config.tap_action,
+            pressed,
+        }
+    }
+
+    /// Edge with a swipe action at the position
+    fn edge_at(&self, pos: (i16, i16)) -> Option<Edge> {
+        let c = &self.config;
+        [
+            (Edge::Left, pos.0 < c.edge_width),
+            (Edge::Right, pos.0 >= c.width - c.edge_width),
+            (Edge::Top, pos.1 < c.edge_width),
+            (Edge::Bottom, pos.1 >= c.height - c.edge_width),
+        ]
+        .into_iter()
+        .find(|&(edge, at)| at && c.swipe_actions[edge as usize] != KeyAction::No)
+        .map(|(edge, _)| edge)
+    }
+
+    /// Update a finger, returns the recognized gestures
+    pub(crate) fn update(&mut self, event: &TouchpadEvent, now: Instant) -> Vec<Gesture, 2> {
+        let mut gestures = Vec::new();
+        let slot = event.finger as usize;
+        if slot >= MAX_FINGERS {
+            return gestures;
+        }
+        let mut pos = self.fingers[slot].unwrap_or_default();
+        let mut touching = true;
+        for a in event
+            .axis
+            .iter()
+            .filter(|a| matches!(a.typ, AxisValType::Abs))
+        {
+            match a.axis {
+                Axis::X => pos.0 = a.value,
+                Axis::Y => pos.1 = a.value,
+                Axis::Z => touching = a.value > 0,
+                _ => (),
+            }
+        }
+
+        if !touching {
+            if self.fingers[slot].take().is_some() && self.fingers.iter().all(Option::is_none) {
+                self.end_touch(now, &mut gestures);
+            }
+            return gestures;
+        }
+
+        let last = self.fingers[slot].replace(pos);
+        let fingers = self.fingers.iter().flatten().count() as u8;
+        if self.touch.is_none() {
+            let dragging = self
+                .last_tap
+                .take()
+                .is_some_and(|tap| now.saturating_duration_since(tap) <= self.config.drag_time);
+            if dragging {
+                gestures.push(self.tap_action(true)).ok();
+            }
+            self.scroll_remainder = (0, 0);
+            self.touch = Some(Touch {
+                start: now,
+                start_pos: pos,
+                max_fingers: 0,
+                moved: 0,
+                edge: if dragging { None } else { self.edge_at(pos) },
+                swiped: false,
+                dragging,
+            });
+        }
+        let Some(touch) = self.touch.as_mut() else {
+            return gestures;
+        };
+        touch.max_fingers = touch.max_fingers.max(fingers);
+
+        // Only the first finger moves, a new finger has no motion
+        let first = self.fingers.iter().position(Option::is_some);
+        let Some(last) = last.filter(|_| first == Some(slot)) else {
+            return gestures;
+        };
+        let (dx, dy) = (pos.0 - last.0, pos.1 - last.1);
+        touch.moved = touch
+            .moved
+            .max((pos.0 - touch.start_pos.0).abs())
+            .max((pos.1 - touch.start_pos.1).abs());
+
+        if touch.max_fingers > 1 {
+            // Lifting one of two fingers stops scrolling, but doesn't move the cursor
+            if fingers > 1 {
+                let divisor = self.config.scroll_divisor;
+                let x = divide_motion(&mut self.scroll_remainder.0, dx, divisor);
+                let y = divide_motion(&mut self.scroll_remainder.1, dy, divisor);
+                if x != 0 || y != 0 {
+                    gestures.push(Gesture::Scroll(x, y)).ok();
+                }
+            }
+        } else if let Some(edge) = touch.edge {
+            let inward = match edge {
+                Edge::Left => pos.0 - touch.start_pos.0,
+                Edge::Right => touch.start_pos.0 - pos.0,
+                Edge::Top => pos.1 - touch.start_pos.1,
+                Edge::Bottom => touch.start_pos.1 - pos.1,
+            };
+            if !touch.swiped && inward >= self.config.swipe_distance {
+                touch.swiped = true;
+                let action = self.config.swipe_actions[edge as usize];
+                let id = 1 + edge as u8;
+                for pressed in [true, false] {
+                    gestures
+                        .push(Gesture::Action {
+                            id,
+                            action,
+                            pressed,
+                        })
+                        .ok();
+                }
+            }
+        } else if dx != 0 || dy != 0 {
+            gestures.push(Gesture::Move(dx, dy)).ok();
+        }
+        gestures
+    }
+
+    /// All fingers are lifted
+    fn end_touch(&mut self, now: Instant, gestures: &mut Vec<Gesture, 2>) {
+        let Some(touch) = self.touch.take() else {
+            return;
+        };
+        if touch.dragging {
+            gestures.push(self.tap_action(false)).ok();
+        } else if touch.max_fingers == 1
+            && !touch.swiped
+            && touch.moved <= self.config.tap_distance
+            && now.saturating_duration_since(touch.start) <= self.config.tap_time
+        {
+            gestures.push(self.tap_action(true)).ok();
+            gestures.push(self.tap_action(false)).ok();
+            self.last_tap = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::AxisEvent;
+
+    fn finger(finger: u8, x: i16, y: i16, z: i16) -> TouchpadEvent {
+        let abs = |axis, value| AxisEvent {
+            typ: AxisValType::Abs,
+            axis,
+            value,
+        };
+        TouchpadEvent {
+            finger,
+            axis: [abs(Axis::X, x), abs(Axis::Y, y), abs(Axis::Z, z)],
+        }
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn pressed(gestures: &[Gesture]) -> Vec<(u8, bool), 2> {
+        gestures
+            .iter()
+            .filter_map(|g| match g {
+                Gesture::Action { id, pressed, .. } => Some((*id, *pressed)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tap_and_drag() {
+        let mut engine = GestureEngine::new(GestureConfig::new(1024, 1024));
+        engine.update(&finger(0, 500, 500, 10), at(0));
+        assert_eq!(
+            engine.update(&finger(0, 510, 500, 10), at(50)).as_slice(),
+            [Gesture::Move(10, 0)]
+        );
+        let tap = engine.update(&finger(0, 510, 500, 0), at(100));
+        assert_eq!(pressed(&tap), [(0, true), (0, false)]);
+
+        // Touching again shortly after the tap holds the button while moving
+        assert_eq!(
+            pressed(&engine.update(&finger(0, 500, 500, 10), at(200))),
+            [(0, true)]
+        );
+        assert_eq!(
+            engine.update(&finger(0, 600, 550, 10), at(300)).as_slice(),
+            [Gesture::Move(100, 50)]
+        );
+        assert_eq!(
+            pressed(&engine.update(&finger(0, 600, 550, 0), at(400))),
+            [(0, false)]
+        );
+
+        // A long touch isn't a tap
+        engine.update(&finger(0, 500, 500, 10), at(1000));
+        assert!(engine.update(&finger(0, 500, 500, 0), at(1500)).is_empty());
+    }
+
+    #[test]
+    fn test_scroll_and_swipe() {
+        let config = GestureConfig {
+            scroll_divisor: 10,
+            ..GestureConfig::new(1024, 1024)
+                .with_swipe(Edge::Right, KeyAction::Single(Action::Key(KeyCode::A)))
+        };
+        let mut engine = GestureEngine::new(config);
+
+        // Two fingers scroll, the second finger doesn't move the cursor
+        engine.update(&finger(0, 500, 500, 10), at(0));
+        assert!(engine.update(&finger(1, 600, 500, 10), at(10)).is_empty());
+        assert!(engine.update(&finger(0, 500, 505, 10), at(20)).is_empty());
+        assert_eq!(
+            engine.update(&finger(0, 500, 530, 10), at(30)).as_slice(),
+            [Gesture::Scroll(0, 3)]
+        );
+        engine.update(&finger(1, 600, 500, 0), at(40));
+        // No tap after scrolling
+        assert!(engine.update(&finger(0, 500, 530, 0), at(50)).is_empty());
+
+        // Swipe from the right edge, the left edge has no action, so it moves the cursor
+        engine.update(&finger(0, 1020, 500, 10), at(1000));
+        assert!(engine
+            .update(&finger(0, 1000, 500, 10), at(1010))
+            .is_empty());
+        let swipe = engine.update(&finger(0, 880, 500, 10), at(1020));
+        assert_eq!(pressed(&swipe), [(2, true), (2, false)]);
+        assert!(engine.update(&finger(0, 700, 500, 10), at(1030)).is_empty());
+        engine.update(&finger(0, 700, 500, 0), at(1040));
+        engine.update(&finger(0, 10, 500, 10), at(2000));
+        assert_eq!(
+            engine.update(&finger(0, 200, 500, 10), at(2010)).as_slice(),
+            [Gesture::Move(190, 0)]
+        );
+    }
+}
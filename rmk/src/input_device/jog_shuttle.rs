@@ -0,0 +1,216 @@
+//! Jog/shuttle controls, used in video-editing macropads
+//!
+//! A jog/shuttle has two rings: the inner jog ring is an incremental encoder, which is run as a normal
+//! [`RotaryEncoder`](super::rotary_encoder::RotaryEncoder) or [`QuadratureEncoder`](super::quadrature::QuadratureEncoder).
+//! The outer shuttle ring is spring-loaded and returns to the center when it's released, its absolute position is read by an ADC.
+//!
+//! [`Shuttle`] divides the travel of each side into `zones`, and sends its own stream of encoder events with `encoder_id`:
+//! one clockwise event per zone when the ring is turned further clockwise, and one counterclockwise event per zone when it's turned back.
+//! So the actions are set in the encoder map, for example `(k!(L), k!(J))`, which speeds up and slows down playback in most editors.
+//! When the ring returns to the center, the key at `center_key` is tapped, such as `K` to stop playback.
+//!
+//! ```rust,ignore
+//! struct ShuttleSaadc<'a>(saadc::Saadc<'a, 1>);
+//!
+//! impl ShuttleAdc for ShuttleSaadc<'_> {
+//!     async fn read(&mut self) -> Option<u16> {
+//!         let mut buf = [0i16; 1];
+//!         self.0.sample(&mut buf).await;
+//!         Some(buf[0].max(0) as u16)
+//!     }
+//! }
+//!
+//! let mut jog = RotaryEncoder::new(pin_a, pin_b, 0);
+//! let mut shuttle = Shuttle::new(ShuttleSaadc(saadc), ShuttleConfig { encoder_id: 1, ..Default::default() });
+//! join(run_rmk(...), run_devices!(jog, shuttle)).await;
+//! ```
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_time::{Duration, Timer};
+
+use crate::event::{Event, KeyEvent, RotaryEncoderEvent};
+use crate::keyboard::EVENT_CHANNEL;
+
+use super::rotary_encoder::Direction;
+use super::{InputDevice, EVENT_CHANNEL_SIZE};
+
+/// Number of samples averaged to get the center
+const CALIBRATION_SAMPLES: u32 = 16;
+
+/// ADC channel of the shuttle ring
+pub trait ShuttleAdc {
+    /// Sample the position of the ring, returns `None` if the reading fails
+    async fn read(&mut self) -> Option<u16>;
+}
+
+/// Config of the shuttle ring
+#[derive(Clone, Copy, Debug)]
+pub struct ShuttleConfig {
+    /// Id of the encoder events, which selects the actions in the encoder map
+    pub encoder_id: u8,
+    /// Number of zones of each side
+    pub zones: u8,
+    /// ADC value when the ring is released, it's measured at startup if it's `None`
+    pub center: Option<u16>,
+    /// Distance from the center in ADC value, which is still the center
+    pub deadzone: u16,
+    /// Distance from the center in ADC value, when the ring is turned to the end
+    pub range: u16,
+    /// The key tapped when the ring returns to the center, `(row, col)` in the keymap
+    pub center_key: Option<(u8, u8)>,
+    /// Interval of sampling the ADC
+    pub poll_interval: Duration,
+    /// Invert the direction, for rings whose ADC value decreases clockwise
+    pub invert: bool,
+}
+
+impl Default for ShuttleConfig {
+    fn default() -> Self {
+        Self {
+            encoder_id: 0,
+            zones: 7,
+            center: None,
+            // For 12-bit ADCs
+            deadzone: 150,
+            range: 2048,
+            center_key: None,
+            poll_interval: Duration::from_millis(10),
+            invert: false,
+        }
+    }
+}
+
+/// Spring-loaded shuttle ring read by an ADC
+pub struct Shuttle<A: ShuttleAdc> {
+    adc: A,
+    config: ShuttleConfig,
+    center: u16,
+    /// Current zone, negative for counterclockwise
+    zone: i8,
+    /// Zone of the last sample, a zone is accepted after it's read twice in a row
+    pending: i8,
+}
+
+impl<A: ShuttleAdc> Shuttle<A> {
+    pub fn new(adc: A, config: ShuttleConfig) -> Self {
+        Self {
+            adc,
+            config,
+            center: config.center.unwrap_or_default(),
+            zone: 0,
+            pending: 0,
+        }
+    }
+
+    /// Measure the center by averaging samples, the ring should be released at startup
+    async fn calibrate(&mut self) {
+        let mut sum = 0u32;
+        let mut n = 0;
+        while n < CALIBRATION_SAMPLES {
+            if let Some(s) = self.adc.read().await {
+                sum += s as u32;
+                n += 1;
+            }
+            Timer::after_millis(1).await;
+        }
+        self.center = (sum / n) as u16;
+        info!("Shuttle center: {}", self.center);
+    }
+
+    async fn send_encoder(&self, direction: Direction) {
+        self.event_sender()
+            .send(Event::RotaryEncoder(RotaryEncoderEvent {
+                id: self.config.encoder_id,
+                direction,
+            }))
+            .await;
+    }
+
+    async fn tap_center_key(&self) {
+        let Some((row, col)) = self.config.center_key else {
+            return;
+        };
+        for pressed in [true, false] {
+            self.event_sender()
+                .send(Event::Key(KeyEvent { row, col, pressed }))
+                .await;
+        }
+    }
+}
+
+/// Zone of an ADC sample, in `-zones..=zones`
+fn zone(value: u16, center: u16, config: &ShuttleConfig) -> i8 {
+    let offset = value as i32 - center as i32;
+    let deadzone = config.deadzone as i32;
+    if offset.abs() <= deadzone {
+        return 0;
+    }
+    let zones = config.zones.clamp(1, i8::MAX as u8) as i32;
+    let span = (config.range as i32 - deadzone).max(1);
+    let zone = ((offset.abs() - deadzone) * zones / span + 1).min(zones);
+    let zone = (zone * offset.signum()) as i8;
+    if config.invert {
+        -zone
+    } else {
+        zone
+    }
+}
+
+impl<A: ShuttleAdc> InputDevice for Shuttle<A> {
+    type EventType = Event;
+
+    async fn run(&mut self) {
+        if self.config.center.is_none() {
+            self.calibrate().await;
+        }
+        loop {
+            Timer::after(self.config.poll_interval).await;
+            let Some(sample) = self.adc.read().await else {
+                continue;
+            };
+            let zone = zone(sample, self.center, &self.config);
+            // Debounce, ignore zones passed through in a single sample
+            let last = core::mem::replace(&mut self.pending, zone);
+            if zone != last || zone == self.zone {
+                continue;
+            }
+            // Moving away from the center is clockwise on the clockwise side, and vice versa
+            let (direction, steps) = if zone > self.zone {
+                (Direction::Clockwise, zone - self.zone)
+            } else {
+                (Direction::CounterClockwise, self.zone - zone)
+            };
+            self.zone = zone;
+            for _ in 0..steps {
+                self.send_encoder(direction.clone()).await;
+            }
+            if zone == 0 {
+                self.tap_center_key().await;
+            }
+        }
+    }
+
+    fn event_sender(&self) -> Sender<CriticalSectionRawMutex, Self::EventType, EVENT_CHANNEL_SIZE> {
+        EVENT_CHANNEL.sender()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_zone() {
+        let config = ShuttleConfig {
+            zones: 4,
+            deadzone: 100,
+            range: 500,
+            ..Default::default()
+        };
+        assert_eq!(zone(2000 + 100, 2000, &config), 0);
+        assert_eq!(zone(2000 + 101, 2000, &config), 1);
+        assert_eq!(zone(2000 + 300, 2000, &config), 3);
+        assert_eq!(zone(2000 + 1000, 2000, &config), 4);
+        assert_eq!(zone(2000 - 300, 2000, &config), -3);
+    }
+}
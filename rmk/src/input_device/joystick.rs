@@ -0,0 +1,285 @@
+//! Analog joystick, such as the thumb joysticks used in some keyboards
+//!
+//! The joystick is sampled by two ADC channels, the deadzone and response curve are applied to the samples,
+//! then they're mapped to one of [`JoystickMode`]:
+//!
+//! - mouse motion: [`Event::Pointer`] is sent, [`PointingProcessor`](super::pointing::PointingProcessor) converts it to mouse reports
+//! - keys: keys at the given matrix positions are pressed, so that they're processed with the keymap, e.g. arrow keys
+//! - gamepad: [`Event::Joystick`] is sent, [`PointingProcessor`](super::pointing::PointingProcessor) converts it to gamepad reports
+//!
+//! embedded-hal 1.0 doesn't have ADC traits, the ADC is read by the [`JoystickAdc`] trait instead:
+//!
+//! ```rust,ignore
+//! struct Saadc<'a>(saadc::Saadc<'a, 2>);
+//!
+//! impl JoystickAdc for Saadc<'_> {
+//!     async fn read(&mut self) -> Option<[u16; 2]> {
+//!         let mut buf = [0i16; 2];
+//!         self.0.sample(&mut buf).await;
+//!         Some(buf.map(|v| v.max(0) as u16))
+//!     }
+//! }
+//!
+//! let mut joystick = Joystick::new(Saadc(saadc), JoystickConfig::default());
+//! let mut pointing_processor = PointingProcessor::new();
+//!
+//! join3(
+//!     run_rmk(...),
+//!     run_devices!(joystick),
+//!     run_processors!(pointing_processor),
+//! )
+//! .await;
+//! ```
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_time::{Duration, Timer};
+
+use crate::event::{Axis, AxisEvent, AxisValType, Event, KeyEvent, PointerEvent};
+use crate::keyboard::EVENT_CHANNEL;
+
+use super::{InputDevice, EVENT_CHANNEL_SIZE};
+
+/// Max value of a normalized axis
+const AXIS_MAX: i32 = 127;
+
+/// Number of samples averaged to get the center
+const CALIBRATION_SAMPLES: u32 = 16;
+
+/// Keys are pressed when the axis is over this value, and released when it's under `KEY_RELEASE_THRESHOLD`
+const KEY_PRESS_THRESHOLD: i8 = 64;
+const KEY_RELEASE_THRESHOLD: i8 = 48;
+
+/// ADC channels of the joystick
+pub trait JoystickAdc {
+    /// Sample x and y axes, returns `None` if the reading fails
+    async fn read(&mut self) -> Option<[u16; 2]>;
+}
+
+/// Response curve of the joystick, applied after the deadzone
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JoystickCurve {
+    #[default]
+    Linear,
+    /// Finer control near the center, faster near the edge
+    Quadratic,
+}
+
+/// What the joystick is mapped to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JoystickMode {
+    /// Move the mouse cursor, `speed` is the motion per poll when the joystick is pushed to the edge
+    Mouse { speed: u8 },
+    /// Press keys at the (row, col) of the matrix, the actions of the keys are defined in the keymap
+    Keys {
+        up: (u8, u8),
+        down: (u8, u8),
+        left: (u8, u8),
+        right: (u8, u8),
+    },
+    /// X and Y axes of a HID gamepad, USB only
+    Gamepad,
+}
+
+/// Config of the analog joystick
+#[derive(Clone, Copy, Debug)]
+pub struct JoystickConfig {
+    pub mode: JoystickMode,
+    /// ADC value of x and y when the joystick is released, it's measured at startup if it's `None`
+    pub center: Option<[u16; 2]>,
+    /// Distance from the center in ADC value, which is ignored
+    pub deadzone: u16,
+    /// Distance from the center in ADC value, when the joystick is pushed to the edge
+    pub range: u16,
+    pub curve: JoystickCurve,
+    /// Interval of sampling the ADC
+    pub poll_interval: Duration,
+    /// Swap x and y axes, for joysticks which are rotated by 90 degrees
+    pub swap_xy: bool,
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl Default for JoystickConfig {
+    fn default() -> Self {
+        Self {
+            mode: JoystickMode::Mouse { speed: 8 },
+            center: None,
+            // For 12-bit ADCs
+            deadzone: 100,
+            range: 2048,
+            curve: JoystickCurve::Linear,
+            poll_interval: Duration::from_millis(10),
+            swap_xy: false,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+}
+
+/// Analog joystick input device
+pub struct Joystick<A: JoystickAdc> {
+    adc: A,
+    config: JoystickConfig,
+    center: [u16; 2],
+    /// Sub-pixel remainders of the mouse motion, in 1/`AXIS_MAX` pixel
+    remainder: [i32; 2],
+    /// Pressed state of up, down, left and right keys
+    pressed: [bool; 4],
+    /// Last sent axes in gamepad mode
+    last_axes: [i8; 2],
+}
+
+impl<A: JoystickAdc> Joystick<A> {
+    pub fn new(adc: A, config: JoystickConfig) -> Self {
+        Self {
+            adc,
+            config,
+            center: config.center.unwrap_or_default(),
+            remainder: [0; 2],
+            pressed: [false; 4],
+            last_axes: [0; 2],
+        }
+    }
+
+    /// Measure the center by averaging samples, the joystick should be released at startup
+    async fn calibrate(&mut self) {
+        let mut sum = [0u32; 2];
+        let mut n = 0;
+        while n < CALIBRATION_SAMPLES {
+            if let Some(s) = self.adc.read().await {
+                sum[0] += s[0] as u32;
+                sum[1] += s[1] as u32;
+                n += 1;
+            }
+            Timer::after_millis(1).await;
+        }
+        self.center = sum.map(|s| (s / n) as u16);
+        info!("Joystick center: {:?}", self.center);
+    }
+
+    /// Apply the deadzone and response curve to a sample, the result is in `-AXIS_MAX..=AXIS_MAX`
+    fn normalize(&self, value: u16, center: u16) -> i8 {
+        let offset = value as i32 - center as i32;
+        let deadzone = self.config.deadzone as i32;
+        if offset.abs() <= deadzone {
+            return 0;
+        }
+        let span = (self.config.range as i32 - deadzone).max(1);
+        let v = ((offset.abs() - deadzone) * AXIS_MAX / span).min(AXIS_MAX);
+        let v = match self.config.curve {
+            JoystickCurve::Linear => v,
+            JoystickCurve::Quadratic => v * v / AXIS_MAX,
+        };
+        (v * offset.signum()) as i8
+    }
+
+    /// Read the joystick, returns normalized x and y
+    async fn read_axes(&mut self) -> Option<[i8; 2]> {
+        let sample = self.adc.read().await?;
+        let mut x = self.normalize(sample[0], self.center[0]);
+        let mut y = self.normalize(sample[1], self.center[1]);
+        if self.config.swap_xy {
+            core::mem::swap(&mut x, &mut y);
+        }
+        if self.config.invert_x {
+            x = -x;
+        }
+        if self.config.invert_y {
+            y = -y;
+        }
+        Some([x, y])
+    }
+
+    async fn send_motion(&mut self, axes: [i8; 2], speed: u8) {
+        let mut motion = [0i16; 2];
+        for i in 0..2 {
+            self.remainder[i] += axes[i] as i32 * speed as i32;
+            let m = self.remainder[i] / AXIS_MAX;
+            self.remainder[i] -= m * AXIS_MAX;
+            motion[i] = m as i16;
+        }
+        if axes == [0, 0] {
+            self.remainder = [0; 2];
+        }
+        if motion != [0, 0] {
+            self.event_sender()
+                .send(Event::Pointer(PointerEvent {
+                    x: motion[0],
+                    y: motion[1],
+                }))
+                .await;
+        }
+    }
+
+    async fn send_keys(&mut self, axes: [i8; 2], positions: [(u8, u8); 4]) {
+        // Up, down, left and right
+        let values = [-axes[1], axes[1], -axes[0], axes[0]];
+        for (i, v) in values.into_iter().enumerate() {
+            let pressed = if self.pressed[i] {
+                v > KEY_RELEASE_THRESHOLD
+            } else {
+                v > KEY_PRESS_THRESHOLD
+            };
+            if pressed != self.pressed[i] {
+                self.pressed[i] = pressed;
+                let (row, col) = positions[i];
+                self.event_sender()
+                    .send(Event::Key(KeyEvent { row, col, pressed }))
+                    .await;
+            }
+        }
+    }
+
+    async fn send_gamepad(&mut self, axes: [i8; 2]) {
+        if axes == self.last_axes {
+            return;
+        }
+        self.last_axes = axes;
+        let axis = |axis, value: i8| AxisEvent {
+            typ: AxisValType::Abs,
+            axis,
+            value: value as i16,
+        };
+        self.event_sender()
+            .send(Event::Joystick([
+                axis(Axis::X, axes[0]),
+                axis(Axis::Y, axes[1]),
+                axis(Axis::Z, 0),
+            ]))
+            .await;
+    }
+}
+
+impl<A: JoystickAdc> InputDevice for Joystick<A> {
+    type EventType = Event;
+
+    async fn run(&mut self) {
+        if self.config.center.is_none() {
+            self.calibrate().await;
+        }
+        loop {
+            Timer::after(self.config.poll_interval).await;
+
+            let Some(axes) = self.read_axes().await else {
+                warn!("Failed to read joystick");
+                continue;
+            };
+            match self.config.mode {
+                JoystickMode::Mouse { speed } => self.send_motion(axes, speed).await,
+                JoystickMode::Keys {
+                    up,
+                    down,
+                    left,
+                    right,
+                } => self.send_keys(axes, [up, down, left, right]).await,
+                JoystickMode::Gamepad => self.send_gamepad(axes).await,
+            }
+        }
+    }
+
+    fn event_sender(&self) -> Sender<CriticalSectionRawMutex, Self::EventType, EVENT_CHANNEL_SIZE> {
+        EVENT_CHANNEL.sender()
+    }
+}
@@ -14,7 +14,16 @@ use embassy_sync::{
 
 use crate::keyboard::{EVENT_CHANNEL_SIZE, REPORT_CHANNEL_SIZE};
 
+pub mod external_switch;
+pub mod gesture;
+pub mod jog_shuttle;
+pub mod joystick;
+#[cfg(feature = "pmw33xx")]
+pub mod pmw33xx;
+pub mod pointing;
+pub mod quadrature;
 pub mod rotary_encoder;
+pub mod touch;
 
 /// The trait for input devices.
 ///
@@ -158,8 +167,8 @@ macro_rules! run_devices {
 ///
 /// # Example
 /// ```rust
-/// // `RotaryEncoderProcessor` and `TouchpadProcessor` should implement `InputProcessor` trait
-/// let d1 = RotaryEncoderProcessor{};
+/// // `TrackballProcessor` and `TouchpadProcessor` should implement `InputProcessor` trait
+/// let d1 = TrackballProcessor{};
 /// let d2 = TouchpadProcessor{};
 ///
 /// // Run all input devices concurrently
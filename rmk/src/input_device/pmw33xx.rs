@@ -0,0 +1,340 @@
+//! Driver of PMW3360/PMW3389 optical sensors, which are used in most trackballs.
+//!
+//! The sensor is connected via SPI, mode 3, up to 2MHz. The CS pin is driven by the driver instead of the SPI device,
+//! because motion bursts and SROM download need delays while CS is asserted.
+//!
+//! The driver sends [`Event::Pointer`] events, [`PointingProcessor`](super::pointing::PointingProcessor) converts them to mouse reports:
+//!
+//! ```rust,ignore
+//! let mut spi_config = spim::Config::default();
+//! spi_config.frequency = spim::Frequency::M2;
+//! spi_config.mode = spim::MODE_3;
+//! let spi = Spim::new(p.SPI3, Irqs, p.P0_13, p.P0_15, p.P0_14, spi_config);
+//! let cs = Output::new(p.P0_16, Level::High, OutputDrive::Standard);
+//! let mut trackball = Pmw33xx::new(spi, cs, Pmw33xxConfig::default());
+//! let mut pointing_processor = PointingProcessor::new();
+//!
+//! join3(
+//!     run_rmk(...),
+//!     run_devices!(trackball),
+//!     run_processors!(pointing_processor),
+//! )
+//! .await;
+//! ```
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::spi::SpiBus;
+
+use crate::event::{Event, PointerEvent};
+use crate::keyboard::EVENT_CHANNEL;
+
+use super::{InputDevice, EVENT_CHANNEL_SIZE};
+
+// Registers
+const PRODUCT_ID: u8 = 0x00;
+const MOTION: u8 = 0x02;
+const DELTA_X_L: u8 = 0x03;
+const DELTA_X_H: u8 = 0x04;
+const DELTA_Y_L: u8 = 0x05;
+const DELTA_Y_H: u8 = 0x06;
+/// CPI of PMW3360, or high byte of the resolution of PMW3389
+const CONFIG1: u8 = 0x0F;
+/// Low byte of the resolution of PMW3389
+const RESOLUTION_L: u8 = 0x0E;
+const CONFIG2: u8 = 0x10;
+const SROM_ENABLE: u8 = 0x13;
+const SROM_ID: u8 = 0x2A;
+const POWER_UP_RESET: u8 = 0x3A;
+const MOTION_BURST: u8 = 0x50;
+const SROM_LOAD_BURST: u8 = 0x62;
+
+/// Motion is detected since the last read
+const MOTION_MOT: u8 = 0x80;
+/// The sensor is lifted from the surface
+const MOTION_LIFT: u8 = 0x08;
+
+/// CPI requested by [`set_cpi`]
+static CPI_SIGNAL: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+/// Current CPI of the sensor
+static CPI: AtomicU16 = AtomicU16::new(0);
+
+/// Change the CPI of the sensor at runtime, it's rounded to a multiple of the CPI step of the sensor
+pub fn set_cpi(cpi: u16) {
+    CPI_SIGNAL.signal(cpi);
+}
+
+/// Get the current CPI of the sensor, 0 if the sensor isn't initialized
+pub fn cpi() -> u16 {
+    CPI.load(Ordering::Relaxed)
+}
+
+/// Supported sensors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pmw33xxSensor {
+    /// PMW3360, 100 ~ 12000 CPI
+    Pmw3360,
+    /// PMW3389, 50 ~ 16000 CPI
+    Pmw3389,
+}
+
+impl Pmw33xxSensor {
+    fn product_id(self) -> u8 {
+        match self {
+            Pmw33xxSensor::Pmw3360 => 0x42,
+            Pmw33xxSensor::Pmw3389 => 0x47,
+        }
+    }
+
+    /// (min, max, step) of CPI
+    fn cpi_range(self) -> (u16, u16, u16) {
+        match self {
+            Pmw33xxSensor::Pmw3360 => (100, 12000, 100),
+            Pmw33xxSensor::Pmw3389 => (50, 16000, 50),
+        }
+    }
+}
+
+/// Config of PMW3360/PMW3389
+#[derive(Clone, Copy, Debug)]
+pub struct Pmw33xxConfig {
+    pub sensor: Pmw33xxSensor,
+    /// CPI of the sensor, can be changed at runtime by [`set_cpi`]
+    pub cpi: u16,
+    /// Interval of reading the motion
+    pub poll_interval: Duration,
+    /// Swap x and y axes, for sensors which are rotated by 90 degrees
+    pub swap_xy: bool,
+    pub invert_x: bool,
+    pub invert_y: bool,
+    /// SROM firmware of the sensor, provided by the sensor vendor.
+    /// The sensor works without it, but the tracking is worse
+    pub srom: Option<&'static [u8]>,
+}
+
+impl Default for Pmw33xxConfig {
+    fn default() -> Self {
+        Self {
+            sensor: Pmw33xxSensor::Pmw3360,
+            cpi: 1600,
+            poll_interval: Duration::from_millis(1),
+            swap_xy: false,
+            invert_x: false,
+            invert_y: false,
+            srom: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pmw33xxError {
+    /// SPI or CS pin error
+    Bus,
+    /// The product id read from the sensor doesn't match the configured sensor
+    InvalidProductId(u8),
+    /// SROM firmware isn't running after the download
+    SromDownloadFailed,
+}
+
+/// PMW3360/PMW3389 optical sensor
+pub struct Pmw33xx<SPI: SpiBus, CS: OutputPin> {
+    spi: SPI,
+    cs: CS,
+    config: Pmw33xxConfig,
+}
+
+impl<SPI: SpiBus, CS: OutputPin> Pmw33xx<SPI, CS> {
+    pub fn new(spi: SPI, cs: CS, config: Pmw33xxConfig) -> Self {
+        Self { spi, cs, config }
+    }
+
+    /// Power up the sensor, download the SROM firmware and set the CPI
+    pub async fn init(&mut self) -> Result<(), Pmw33xxError> {
+        // Reset the SPI port of the sensor
+        self.cs.set_high().map_err(|_| Pmw33xxError::Bus)?;
+        self.cs.set_low().map_err(|_| Pmw33xxError::Bus)?;
+        self.cs.set_high().map_err(|_| Pmw33xxError::Bus)?;
+
+        self.write_register(POWER_UP_RESET, 0x5A).await?;
+        Timer::after_millis(50).await;
+        // Clear the motion registers
+        for reg in [MOTION, DELTA_X_L, DELTA_X_H, DELTA_Y_L, DELTA_Y_H] {
+            self.read_register(reg).await?;
+        }
+
+        let product_id = self.read_register(PRODUCT_ID).await?;
+        if product_id != self.config.sensor.product_id() {
+            return Err(Pmw33xxError::InvalidProductId(product_id));
+        }
+
+        if let Some(srom) = self.config.srom {
+            self.download_srom(srom).await?;
+        }
+        // Disable rest mode, so that the sensor responds without delay
+        self.write_register(CONFIG2, 0x00).await?;
+        self.set_cpi(self.config.cpi).await?;
+
+        info!("{:?} initialized, CPI: {}", self.config.sensor, cpi());
+        Ok(())
+    }
+
+    /// Set the CPI of the sensor
+    pub async fn set_cpi(&mut self, cpi: u16) -> Result<(), Pmw33xxError> {
+        let (min, max, step) = self.config.sensor.cpi_range();
+        let value = cpi.clamp(min, max) / step;
+        match self.config.sensor {
+            Pmw33xxSensor::Pmw3360 => self.write_register(CONFIG1, (value - 1) as u8).await?,
+            Pmw33xxSensor::Pmw3389 => {
+                self.write_register(RESOLUTION_L, value as u8).await?;
+                self.write_register(CONFIG1, (value >> 8) as u8).await?;
+            }
+        }
+        self.config.cpi = value * step;
+        CPI.store(self.config.cpi, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Read the motion since the last read, `None` if there's no motion or the sensor is lifted
+    pub async fn read_motion(&mut self) -> Result<Option<(i16, i16)>, Pmw33xxError> {
+        // Any value written to `MOTION_BURST` starts a burst
+        self.write_register(MOTION_BURST, 0x00).await?;
+
+        let mut burst = [0u8; 6];
+        self.cs.set_low().map_err(|_| Pmw33xxError::Bus)?;
+        let result: Result<(), SPI::Error> = async {
+            self.spi.write(&[MOTION_BURST]).await?;
+            // tSRAD_MOTBR
+            Timer::after_micros(35).await;
+            self.spi.read(&mut burst).await?;
+            self.spi.flush().await
+        }
+        .await;
+        self.cs.set_high().map_err(|_| Pmw33xxError::Bus)?;
+        result.map_err(|_| Pmw33xxError::Bus)?;
+        // tBEXIT
+        Timer::after_micros(1).await;
+
+        let motion = burst[0];
+        if motion & MOTION_MOT == 0 || motion & MOTION_LIFT != 0 {
+            return Ok(None);
+        }
+        let mut x = i16::from_le_bytes([burst[2], burst[3]]);
+        let mut y = i16::from_le_bytes([burst[4], burst[5]]);
+        if self.config.swap_xy {
+            core::mem::swap(&mut x, &mut y);
+        }
+        if self.config.invert_x {
+            x = x.saturating_neg();
+        }
+        if self.config.invert_y {
+            y = y.saturating_neg();
+        }
+        if x == 0 && y == 0 {
+            return Ok(None);
+        }
+        Ok(Some((x, y)))
+    }
+
+    async fn download_srom(&mut self, srom: &[u8]) -> Result<(), Pmw33xxError> {
+        self.write_register(CONFIG2, 0x00).await?;
+        self.write_register(SROM_ENABLE, 0x1D).await?;
+        Timer::after_millis(10).await;
+        self.write_register(SROM_ENABLE, 0x18).await?;
+
+        self.cs.set_low().map_err(|_| Pmw33xxError::Bus)?;
+        let result: Result<(), SPI::Error> = async {
+            self.spi.write(&[SROM_LOAD_BURST | 0x80]).await?;
+            // Every byte of the firmware is sent after tLOAD
+            for byte in srom {
+                self.spi.flush().await?;
+                Timer::after_micros(15).await;
+                self.spi.write(&[*byte]).await?;
+            }
+            self.spi.flush().await
+        }
+        .await;
+        self.cs.set_high().map_err(|_| Pmw33xxError::Bus)?;
+        result.map_err(|_| Pmw33xxError::Bus)?;
+        Timer::after_micros(200).await;
+
+        // SROM id is 0 if the firmware isn't running
+        if self.read_register(SROM_ID).await? == 0 {
+            return Err(Pmw33xxError::SromDownloadFailed);
+        }
+        Ok(())
+    }
+
+    async fn read_register(&mut self, reg: u8) -> Result<u8, Pmw33xxError> {
+        let mut buf = [0u8; 1];
+        self.cs.set_low().map_err(|_| Pmw33xxError::Bus)?;
+        let result: Result<(), SPI::Error> = async {
+            self.spi.write(&[reg & 0x7F]).await?;
+            // tSRAD
+            Timer::after_micros(160).await;
+            self.spi.read(&mut buf).await?;
+            self.spi.flush().await
+        }
+        .await;
+        self.cs.set_high().map_err(|_| Pmw33xxError::Bus)?;
+        result.map_err(|_| Pmw33xxError::Bus)?;
+        // tSRR/tSRW
+        Timer::after_micros(20).await;
+        Ok(buf[0])
+    }
+
+    async fn write_register(&mut self, reg: u8, value: u8) -> Result<(), Pmw33xxError> {
+        self.cs.set_low().map_err(|_| Pmw33xxError::Bus)?;
+        let result: Result<(), SPI::Error> = async {
+            self.spi.write(&[reg | 0x80, value]).await?;
+            self.spi.flush().await?;
+            // tSCLK-NCS
+            Timer::after_micros(35).await;
+            Ok(())
+        }
+        .await;
+        self.cs.set_high().map_err(|_| Pmw33xxError::Bus)?;
+        result.map_err(|_| Pmw33xxError::Bus)?;
+        // tSWW/tSWR
+        Timer::after_micros(180).await;
+        Ok(())
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin> InputDevice for Pmw33xx<SPI, CS> {
+    type EventType = Event;
+
+    async fn run(&mut self) {
+        if let Err(e) = self.init().await {
+            error!("Failed to initialize {:?}: {:?}", self.config.sensor, e);
+            return;
+        }
+        loop {
+            Timer::after(self.config.poll_interval).await;
+
+            if let Some(cpi) = CPI_SIGNAL.try_take() {
+                if let Err(e) = self.set_cpi(cpi).await {
+                    error!("Failed to set CPI: {:?}", e);
+                }
+            }
+
+            match self.read_motion().await {
+                Ok(Some((x, y))) => {
+                    self.event_sender()
+                        .send(Event::Pointer(PointerEvent { x, y }))
+                        .await
+                }
+                Ok(None) => (),
+                Err(e) => error!("Failed to read motion: {:?}", e),
+            }
+        }
+    }
+
+    fn event_sender(&self) -> Sender<CriticalSectionRawMutex, Self::EventType, EVENT_CHANNEL_SIZE> {
+        EVENT_CHANNEL.sender()
+    }
+}
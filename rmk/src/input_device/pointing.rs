@@ -0,0 +1,294 @@
+//! Processor of pointing devices, such as trackballs and mouse sensors
+
+use core::sync::atomic::Ordering;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::Instant;
+
+use crate::event::{Axis, AxisValType, Event, PointerEvent};
+use crate::input_device::external_switch::SwitchEvent;
+use crate::keyboard::{
+    KeyboardReportMessage, GAMEPAD_AXES, GAMEPAD_BUTTONS, KEYBOARD_REPORT_CHANNEL, MOUSE_BUTTONS,
+    POINTER_REPORT_CHANNEL, POINTING_EVENT_CHANNEL, SWITCH_EVENT_CHANNEL,
+};
+use crate::keyboard_macro::record_pointer_motion;
+use crate::keymap::ACTIVE_LAYER_MASK;
+use crate::scroll::{apply_scroll_direction, ScrollDevice};
+use crate::usb::descriptor::{CompositeReport, CompositeReportType};
+use crate::REPORT_CHANNEL_SIZE;
+
+use super::gesture::{Gesture, GestureConfig, GestureEngine};
+use super::{InputProcessor, EVENT_CHANNEL_SIZE};
+
+/// Actions of touchpad gestures are sent as external switches from this id, so that they don't overlap with real switches
+const GESTURE_SWITCH_ID: u8 = 0xF0;
+
+/// Behavior of pointing devices
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PointerMode {
+    /// Move the cursor
+    #[default]
+    Move,
+    /// Scroll vertically and horizontally, every `divisor` counts of the motion is a step of the wheel
+    Scroll { divisor: u8 },
+    /// Move the cursor precisely, the motion is divided by `divisor`
+    Sniping { divisor: u8 },
+}
+
+/// Mode of pointing devices while the layer is active
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PointerLayer {
+    pub layer: u8,
+    pub mode: PointerMode,
+}
+
+/// Resolve the pointer mode of the active layers.
+///
+/// Like keys, the highest active layer with a mode wins, layers without a mode are transparent,
+/// and the cursor is moved if no layer has a mode.
+pub(crate) fn resolve_pointer_mode(layers: &[PointerLayer], layer_mask: u32) -> PointerMode {
+    layers
+        .iter()
+        .filter(|l| layer_mask & 1u32.checked_shl(l.layer as u32).unwrap_or(0) != 0)
+        .max_by_key(|l| l.layer)
+        .map_or(PointerMode::Move, |l| l.mode)
+}
+
+/// Divide the motion and keep the remainder for the next motion, so that slow motion isn't lost
+pub(crate) fn divide_motion(remainder: &mut i16, value: i16, divisor: u8) -> i16 {
+    let divisor = divisor.max(1) as i32;
+    let total = *remainder as i32 + value as i32;
+    *remainder = (total % divisor) as i16;
+    (total / divisor).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+/// Converts [`Event::Pointer`] to mouse reports, and absolute axes of [`Event::Joystick`] to gamepad reports.
+///
+/// Mouse buttons held by mouse keys in the keymap are sent along with the motion, so that dragging works.
+/// Gamepad buttons held by joystick button keys are sent along with the axes as well.
+/// The motion can be converted to scrolling or slowed down on some layers, see [`PointingProcessor::with_layer_modes`].
+/// Touchpads are supported if gestures are enabled by [`PointingProcessor::with_gestures`].
+#[derive(Default)]
+pub struct PointingProcessor {
+    layer_modes: &'static [PointerLayer],
+    gestures: Option<GestureEngine>,
+    /// Mode of the last motion
+    mode: PointerMode,
+    /// Motion which isn't sent yet in scroll and sniping modes
+    remainder: (i16, i16),
+}
+
+impl PointingProcessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set modes of pointing devices on layers, which are resolved from the layer state of the keymap
+    pub fn with_layer_modes(mut self, layer_modes: &'static [PointerLayer]) -> Self {
+        self.layer_modes = layer_modes;
+        self
+    }
+
+    /// Recognize gestures of [`Event::Touchpad`], touchpad events are ignored without gestures
+    pub fn with_gestures(mut self, config: GestureConfig) -> Self {
+        self.gestures = Some(GestureEngine::new(config));
+        self
+    }
+
+    async fn process_gesture(&mut self, gesture: Gesture) {
+        match gesture {
+            Gesture::Move(x, y) => self.process_motion(x, y).await,
+            Gesture::Scroll(x, y) => self.send_motion(x, y, Some(ScrollDevice::Touchpad)).await,
+            Gesture::Action {
+                id,
+                action,
+                pressed,
+            } => {
+                SWITCH_EVENT_CHANNEL
+                    .send(SwitchEvent {
+                        id: GESTURE_SWITCH_ID + id,
+                        action,
+                        pressed,
+                    })
+                    .await
+            }
+        }
+    }
+
+    async fn process_motion(&mut self, x: i16, y: i16) {
+        let mode =
+            resolve_pointer_mode(self.layer_modes, ACTIVE_LAYER_MASK.load(Ordering::Relaxed));
+        if mode != self.mode {
+            debug!("Pointer mode: {:?}", mode);
+            self.mode = mode;
+            self.remainder = (0, 0);
+        }
+        match mode {
+            PointerMode::Move => {
+                record_pointer_motion(x, y);
+                self.send_motion(x, y, None).await
+            }
+            PointerMode::Sniping { divisor } => {
+                let x = divide_motion(&mut self.remainder.0, x, divisor);
+                let y = divide_motion(&mut self.remainder.1, y, divisor);
+                record_pointer_motion(x, y);
+                self.send_motion(x, y, None).await
+            }
+            // Dynamic macros don't have scroll operations, scrolling isn't recorded
+            PointerMode::Scroll { divisor } => {
+                let x = divide_motion(&mut self.remainder.0, x, divisor);
+                let y = divide_motion(&mut self.remainder.1, y, divisor);
+                self.send_motion(x, y, Some(ScrollDevice::Pointer)).await
+            }
+        }
+    }
+
+    /// Send the motion, split into several reports if it's out of the range of a mouse report.
+    ///
+    /// If `scroll` is set, the motion is sent as wheel and pan in the scroll direction of the device, moving up scrolls up by default.
+    async fn send_motion(&mut self, mut x: i16, mut y: i16, scroll: Option<ScrollDevice>) {
+        while x != 0 || y != 0 {
+            let dx = x.clamp(i8::MIN as i16 + 1, i8::MAX as i16);
+            let dy = y.clamp(i8::MIN as i16 + 1, i8::MAX as i16);
+            x -= dx;
+            y -= dy;
+            let buttons = MOUSE_BUTTONS.load(Ordering::Relaxed);
+            let report = if let Some(device) = scroll {
+                let (wheel, pan) = apply_scroll_direction(device, -dy as i8, dx as i8);
+                CompositeReport {
+                    buttons,
+                    wheel,
+                    pan,
+                    ..Default::default()
+                }
+            } else {
+                CompositeReport {
+                    buttons,
+                    x: dx as i8,
+                    y: dy as i8,
+                    ..Default::default()
+                }
+            };
+            POINTER_REPORT_CHANNEL
+                .send(KeyboardReportMessage::CompositeReport(
+                    report,
+                    CompositeReportType::Mouse,
+                ))
+                .await;
+        }
+    }
+
+    async fn send_gamepad(&mut self, x: i8, y: i8) {
+        GAMEPAD_AXES[0].store(x, Ordering::Relaxed);
+        GAMEPAD_AXES[1].store(y, Ordering::Relaxed);
+        let report = CompositeReport {
+            gamepad_buttons: GAMEPAD_BUTTONS.load(Ordering::Relaxed),
+            gamepad_x: x,
+            gamepad_y: y,
+            ..Default::default()
+        };
+        POINTER_REPORT_CHANNEL
+            .send(KeyboardReportMessage::CompositeReport(
+                report,
+                CompositeReportType::Gamepad,
+            ))
+            .await;
+    }
+}
+
+impl InputProcessor for PointingProcessor {
+    type EventType = Event;
+
+    type ReportType = KeyboardReportMessage;
+
+    async fn process(&mut self, event: Self::EventType) {
+        match event {
+            Event::Pointer(PointerEvent { x, y }) => {
+                crate::display::notify_key_activity();
+                self.process_motion(x, y).await
+            }
+            Event::Touchpad(touchpad) => {
+                let Some(engine) = self.gestures.as_mut() else {
+                    return;
+                };
+                crate::display::notify_key_activity();
+                for gesture in engine.update(&touchpad, Instant::now()) {
+                    self.process_gesture(gesture).await;
+                }
+            }
+            Event::Joystick(axes) => {
+                let mut xy = [
+                    GAMEPAD_AXES[0].load(Ordering::Relaxed),
+                    GAMEPAD_AXES[1].load(Ordering::Relaxed),
+                ];
+                for a in axes.iter().filter(|a| matches!(a.typ, AxisValType::Abs)) {
+                    let value = a.value.clamp(i8::MIN as i16 + 1, i8::MAX as i16) as i8;
+                    match a.axis {
+                        Axis::X => xy[0] = value,
+                        Axis::Y => xy[1] = value,
+                        _ => (),
+                    }
+                }
+                self.send_gamepad(xy[0], xy[1]).await;
+            }
+            _ => (),
+        }
+    }
+
+    fn event_receiver(
+        &self,
+    ) -> Receiver<CriticalSectionRawMutex, Self::EventType, EVENT_CHANNEL_SIZE> {
+        POINTING_EVENT_CHANNEL.receiver()
+    }
+
+    // Mouse reports are sent to `POINTER_REPORT_CHANNEL` in `process`
+    fn report_sender(
+        &self,
+    ) -> Sender<CriticalSectionRawMutex, Self::ReportType, REPORT_CHANNEL_SIZE> {
+        KEYBOARD_REPORT_CHANNEL.sender()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pointer_layer_modes() {
+        const LAYERS: [PointerLayer; 2] = [
+            PointerLayer {
+                layer: 1,
+                mode: PointerMode::Scroll { divisor: 8 },
+            },
+            PointerLayer {
+                layer: 3,
+                mode: PointerMode::Sniping { divisor: 4 },
+            },
+        ];
+        assert_eq!(resolve_pointer_mode(&LAYERS, 0b1), PointerMode::Move);
+        assert_eq!(
+            resolve_pointer_mode(&LAYERS, 0b11),
+            PointerMode::Scroll { divisor: 8 }
+        );
+        // Layer 2 doesn't have a mode, it's transparent
+        assert_eq!(
+            resolve_pointer_mode(&LAYERS, 0b111),
+            PointerMode::Scroll { divisor: 8 }
+        );
+        assert_eq!(
+            resolve_pointer_mode(&LAYERS, 0b1011),
+            PointerMode::Sniping { divisor: 4 }
+        );
+
+        // Slow motion is accumulated
+        let mut remainder = 0;
+        assert_eq!(divide_motion(&mut remainder, 3, 4), 0);
+        assert_eq!(divide_motion(&mut remainder, 3, 4), 1);
+        assert_eq!(remainder, 2);
+        assert_eq!(divide_motion(&mut remainder, -7, 4), -1);
+        assert_eq!(remainder, -1);
+    }
+}
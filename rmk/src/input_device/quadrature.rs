@@ -0,0 +1,122 @@
+//! Rotary encoders decoded by a hardware quadrature decoder, such as nRF QDEC, STM32 timers in encoder mode or a RP2040 PIO program.
+//!
+//! [`RotaryEncoder`](super::rotary_encoder::RotaryEncoder) reads the pins in software, which misses transitions of high-resolution
+//! optical or TMR encoders spinning fast. A hardware decoder counts every transition, [`QuadratureEncoder`] only reads the accumulated count.
+//!
+//! The decoder is abstracted by [`QuadratureDecoder`]. It's implemented for `embassy_nrf::qdec::Qdec` on nRF chips,
+//! other decoders can be used by implementing it:
+//!
+//! ```rust,ignore
+//! struct TimerDecoder<'d> {
+//!     timer: Qei<'d, TIM2>,
+//!     last: u16,
+//! }
+//!
+//! impl QuadratureDecoder for TimerDecoder<'_> {
+//!     async fn read(&mut self) -> i16 {
+//!         loop {
+//!             Timer::after_millis(1).await;
+//!             let count = self.timer.count();
+//!             if count != self.last {
+//!                 let delta = count.wrapping_sub(self.last) as i16;
+//!                 self.last = count;
+//!                 return delta;
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let mut encoder = QuadratureEncoder::new(TimerDecoder { timer, last: 0 }, 0).with_resolution(24);
+//! join(run_rmk(...), run_devices!(encoder)).await;
+//! ```
+
+use core::future::Future;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Sender;
+use embassy_time::Instant;
+
+use crate::event::{Event, RotaryEncoderEvent};
+use crate::keyboard::EVENT_CHANNEL;
+
+use super::rotary_encoder::{EncoderAcceleration, PulseCounter};
+use super::{InputDevice, EVENT_CHANNEL_SIZE};
+
+/// A hardware quadrature decoder
+pub trait QuadratureDecoder {
+    /// Wait until the encoder moves, returns the number of counts since the last read, positive for clockwise
+    fn read(&mut self) -> impl Future<Output = i16>;
+}
+
+#[cfg(feature = "_nrf_ble")]
+impl<T: embassy_nrf::qdec::Instance> QuadratureDecoder for embassy_nrf::qdec::Qdec<'_, T> {
+    async fn read(&mut self) -> i16 {
+        embassy_nrf::qdec::Qdec::read(self).await
+    }
+}
+
+/// Rotary encoder decoded by a [`QuadratureDecoder`].
+///
+/// The counts of the decoder are always accumulated like [`RotaryEncoder::with_free_spin`](super::rotary_encoder::RotaryEncoder::with_free_spin),
+/// counts of the opposite direction cancel each other, and the counts left after an event are kept.
+pub struct QuadratureEncoder<Q: QuadratureDecoder> {
+    decoder: Q,
+    /// The index of the rotary encoder
+    id: u8,
+    counter: PulseCounter,
+}
+
+impl<Q: QuadratureDecoder> QuadratureEncoder<Q> {
+    pub fn new(decoder: Q, id: u8) -> Self {
+        let mut counter = PulseCounter::new();
+        counter.free_spin = true;
+        Self {
+            decoder,
+            id,
+            counter,
+        }
+    }
+
+    /// Set the number of counts per event. Default is 1.
+    ///
+    /// High-resolution encoders report hundreds of counts per revolution, set it to get a practical number of events per revolution.
+    pub fn with_resolution(mut self, resolution: u8) -> Self {
+        self.counter.resolution = resolution.max(1);
+        self
+    }
+
+    /// Enable velocity-based acceleration, so that spinning the encoder fast emits more events
+    pub fn with_acceleration(mut self, acceleration: EncoderAcceleration) -> Self {
+        self.counter.acceleration = Some(acceleration);
+        self
+    }
+
+    /// Smooth the speed used by the acceleration over the last events
+    pub fn with_smoothing(mut self) -> Self {
+        self.counter.smoothing = true;
+        self
+    }
+}
+
+impl<Q: QuadratureDecoder> InputDevice for QuadratureEncoder<Q> {
+    type EventType = Event;
+
+    async fn run(&mut self) {
+        loop {
+            let counts = self.decoder.read().await;
+            let (direction, events) = self.counter.count(counts, Instant::now());
+            for _ in 0..events {
+                self.event_sender()
+                    .send(Event::RotaryEncoder(RotaryEncoderEvent {
+                        id: self.id,
+                        direction: direction.clone(),
+                    }))
+                    .await;
+            }
+        }
+    }
+
+    fn event_sender(&self) -> Sender<CriticalSectionRawMutex, Self::EventType, EVENT_CHANNEL_SIZE> {
+        EVENT_CHANNEL.sender()
+    }
+}
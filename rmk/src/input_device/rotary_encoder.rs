@@ -2,14 +2,18 @@
 
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::{Duration, Instant};
 use embedded_hal::digital::InputPin;
 #[cfg(feature = "async_matrix")]
 use embedded_hal_async::digital::Wait;
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
+use crate::action::KeyAction;
 use crate::event::{Event, RotaryEncoderEvent};
-use crate::keyboard::{KeyboardReportMessage, EVENT_CHANNEL, KEYBOARD_REPORT_CHANNEL};
+use crate::keyboard::{
+    dispatch_event, KeyboardReportMessage, EVENT_CHANNEL, KEYBOARD_REPORT_CHANNEL,
+};
 use crate::REPORT_CHANNEL_SIZE;
 
 use super::{InputDevice, InputProcessor, EVENT_CHANNEL_SIZE};
@@ -24,6 +28,110 @@ pub struct RotaryEncoder<A, B, P> {
     phase: P,
     /// The index of the rotary encoder
     id: u8,
+    counter: PulseCounter,
+    /// Direction of the last pulse, positive for clockwise
+    last_pulse: i8,
+}
+
+/// Converts quadrature pulses to encoder events, shared by [`RotaryEncoder`] and [`QuadratureEncoder`](super::quadrature::QuadratureEncoder)
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct PulseCounter {
+    /// Number of pulses per detent
+    pub(crate) resolution: u8,
+    /// Pulses since the last detent, positive for clockwise
+    pulses: i16,
+    pub(crate) acceleration: Option<EncoderAcceleration>,
+    /// Time of the last detent
+    last_detent: Option<Instant>,
+    /// Decode as a detent-less encoder, see [`RotaryEncoder::with_free_spin`]
+    pub(crate) free_spin: bool,
+    /// Smooth the speed of acceleration, see [`RotaryEncoder::with_smoothing`]
+    pub(crate) smoothing: bool,
+    /// Smoothed interval between detents in microseconds
+    smoothed_interval: Option<u64>,
+}
+
+impl PulseCounter {
+    pub(crate) fn new() -> Self {
+        Self {
+            resolution: 1,
+            pulses: 0,
+            acceleration: None,
+            last_detent: None,
+            free_spin: false,
+            smoothing: false,
+            smoothed_interval: None,
+        }
+    }
+
+    /// Accumulate the pulses, returns the direction and the number of events to emit when a detent is reached
+    pub(crate) fn count(&mut self, pulses: i16, now: Instant) -> (Direction, u8) {
+        if pulses == 0 {
+            return (Direction::None, 0);
+        }
+        // Drop pulses of the other direction
+        if !self.free_spin && self.pulses.signum() == -pulses.signum() {
+            self.pulses = 0;
+        }
+        self.pulses = self.pulses.saturating_add(pulses);
+        let resolution = self.resolution.max(1) as i16;
+        if self.pulses.abs() < resolution {
+            return (Direction::None, 0);
+        }
+        let direction = if self.pulses > 0 {
+            Direction::Clockwise
+        } else {
+            Direction::CounterClockwise
+        };
+        let detents = (self.pulses.abs() / resolution) as u64;
+        self.pulses = if self.free_spin {
+            self.pulses % resolution
+        } else {
+            0
+        };
+
+        let multiplier = match (self.acceleration, self.last_detent) {
+            (Some(acc), Some(last)) => {
+                let elapsed = ((now - last).as_micros() / detents).max(1);
+                let interval = match self.smoothed_interval {
+                    // Restart smoothing after a pause
+                    Some(smoothed) if self.smoothing && elapsed < acc.interval.as_micros() => {
+                        (smoothed * 3 + elapsed) / 4
+                    }
+                    _ => elapsed,
+                };
+                self.smoothed_interval = Some(interval);
+                (acc.interval.as_micros() / interval.max(1))
+                    .clamp(1, acc.max_multiplier.max(1) as u64)
+            }
+            _ => 1,
+        };
+        self.last_detent = Some(now);
+        (direction, (detents * multiplier).min(u8::MAX as u64) as u8)
+    }
+}
+
+/// Velocity-based acceleration of a [`RotaryEncoder`].
+///
+/// When two detents are closer than `interval`, the second detent emits `interval / elapsed` events, up to `max_multiplier`.
+/// For example, with `interval` of 100ms, turning a detent every 25ms emits 4 events per detent.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EncoderAcceleration {
+    /// Detents closer than this interval are accelerated
+    pub interval: Duration,
+    /// Max number of events emitted by one detent
+    pub max_multiplier: u8,
+}
+
+impl Default for EncoderAcceleration {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(100),
+            max_multiplier: 8,
+        }
+    }
 }
 
 /// The encoder direction is either `Clockwise`, `CounterClockwise`, or `None`
@@ -38,6 +146,59 @@ pub enum Direction {
     None,
 }
 
+/// Actions of a rotary encoder on one layer, `(Clockwise, CounterClockwise)`
+pub type EncoderAction = (KeyAction, KeyAction);
+
+/// Layer-aware actions of all rotary encoders.
+///
+/// The encoder map is indexed by `[layer][encoder id]`, like the keymap, a `KeyAction::Transparent` falls through to lower active layers.
+/// Encoders in the encoder map don't need positions in the keyboard matrix.
+pub struct EncoderMap<'a> {
+    /// Actions of all layers, flattened
+    actions: &'a mut [EncoderAction],
+    /// Number of encoders
+    num_encoder: usize,
+}
+
+impl<'a> EncoderMap<'a> {
+    /// Create an encoder map from `[[(Clockwise, CounterClockwise); NUM_ENCODER]; NUM_LAYER]`
+    pub fn new<const NUM_ENCODER: usize, const NUM_LAYER: usize>(
+        map: &'a mut [[EncoderAction; NUM_ENCODER]; NUM_LAYER],
+    ) -> Self {
+        Self {
+            actions: map.as_flattened_mut(),
+            num_encoder: NUM_ENCODER,
+        }
+    }
+
+    /// Number of encoders
+    pub fn num_encoder(&self) -> usize {
+        self.num_encoder
+    }
+
+    /// Number of layers
+    pub fn num_layer(&self) -> usize {
+        self.actions
+            .len()
+            .checked_div(self.num_encoder)
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn get(&self, layer: usize, id: usize) -> Option<EncoderAction> {
+        if id >= self.num_encoder {
+            return None;
+        }
+        self.actions.get(layer * self.num_encoder + id).copied()
+    }
+
+    pub(crate) fn get_mut(&mut self, layer: usize, id: usize) -> Option<&mut EncoderAction> {
+        if id >= self.num_encoder {
+            return None;
+        }
+        self.actions.get_mut(layer * self.num_encoder + id)
+    }
+}
+
 /// Allows customizing which Quadrature Phases should be considered movements
 /// and in which direction or ignored.
 pub trait Phase {
@@ -68,13 +229,7 @@ where
 {
     /// Accepts two [`InputPin`](https://docs.rs/embedded-hal/latest/embedded_hal/digital/trait.InputPin.html)s, these will be read on every `update()`.
     pub fn new(pin_a: A, pin_b: B, id: u8) -> Self {
-        Self {
-            pin_a,
-            pin_b,
-            state: 0u8,
-            phase: DefaultPhase,
-            id,
-        }
+        Self::with_phase(pin_a, pin_b, DefaultPhase, id)
     }
 }
 
@@ -87,9 +242,65 @@ impl<A: InputPin, B: InputPin, P: Phase> RotaryEncoder<A, B, P> {
             state: 0u8,
             phase,
             id,
+            counter: PulseCounter::new(),
+            last_pulse: 0,
+        }
+    }
+
+    /// Set the number of pulses per detent, an event is emitted per detent. Default is 1.
+    ///
+    /// With [`DefaultPhase`], most EC11 encoders generate 4 pulses per detent.
+    pub fn with_resolution(mut self, resolution: u8) -> Self {
+        self.counter.resolution = resolution.max(1);
+        self
+    }
+
+    /// Enable velocity-based acceleration, so that spinning the encoder fast emits more events
+    pub fn with_acceleration(mut self, acceleration: EncoderAcceleration) -> Self {
+        self.counter.acceleration = Some(acceleration);
+        self
+    }
+
+    /// Decode as a detent-less or optical encoder, which has no rest position between events.
+    ///
+    /// Every `resolution` pulses emit an event. Pulses of the opposite direction cancel each other
+    /// instead of restarting the count, so jitter around a position emits nothing,
+    /// and the pulses left after an event are kept for the next one.
+    /// When the encoder turns so fast that both pins change between two reads, it's counted as two pulses in the last direction.
+    pub fn with_free_spin(mut self) -> Self {
+        self.counter.free_spin = true;
+        self
+    }
+
+    /// Smooth the speed used by the acceleration over the last detents,
+    /// so that the acceleration doesn't jump with uneven turning speed, which is common with free-spinning encoders
+    pub fn with_smoothing(mut self) -> Self {
+        self.counter.smoothing = true;
+        self
+    }
+
+    /// Read the pins, returns the number of pulses, positive for clockwise
+    fn read_pulses(&mut self) -> i8 {
+        let last_state = self.state;
+        match self.update() {
+            Direction::Clockwise => 1,
+            Direction::CounterClockwise => -1,
+            // Both pins are changed, a state is skipped
+            Direction::None if self.counter.free_spin && last_state ^ self.state == 0b11 => {
+                self.last_pulse * 2
+            }
+            Direction::None => 0,
         }
     }
 
+    /// Accumulate the pulses, returns the direction and the number of events to emit when a detent is reached
+    fn detent(&mut self, pulses: i8, now: Instant) -> (Direction, u8) {
+        if pulses != 0 {
+            self.last_pulse = pulses.signum();
+        }
+        self.counter.count(pulses as i16, now)
+    }
+
     /// Call `update` to evaluate the next state of the encoder, propagates errors from `InputPin` read
     pub fn update(&mut self) -> Direction {
         // use mask to get previous state value
@@ -161,14 +372,17 @@ impl<
                 .await;
             }
 
-            let direction = self.update();
+            let pulses = self.read_pulses();
+            let (direction, events) = self.detent(pulses, Instant::now());
 
-            self.event_sender()
-                .send(Event::RotaryEncoder(RotaryEncoderEvent {
-                    id: self.id,
-                    direction,
-                }))
-                .await;
+            for _ in 0..events {
+                self.event_sender()
+                    .send(Event::RotaryEncoder(RotaryEncoderEvent {
+                        id: self.id,
+                        direction: direction.clone(),
+                    }))
+                    .await;
+            }
         }
     }
 
@@ -177,26 +391,23 @@ impl<
     }
 }
 
+/// Processor of rotary encoder events.
+///
+/// Rotary encoder events are routed to the keyboard by RMK, this processor isn't needed anymore.
+/// Events received by it are routed in the same way.
+#[deprecated(
+    note = "rotary encoder events are routed to the keyboard by RMK, remove the processor"
+)]
 pub struct RotaryEncoderProcessor {}
 
+#[allow(deprecated)]
 impl InputProcessor for RotaryEncoderProcessor {
     type EventType = Event;
 
     type ReportType = KeyboardReportMessage;
 
     async fn process(&mut self, event: Self::EventType) {
-        match event {
-            Event::RotaryEncoder(RotaryEncoderEvent { id, direction }) => match direction {
-                Direction::Clockwise => {
-                    debug!("Encoder {} - Clockwise", id);
-                }
-                Direction::CounterClockwise => {
-                    debug!("Encoder {} - CounterClockwise", id);
-                }
-                Direction::None => (),
-            },
-            _ => {}
-        }
+        dispatch_event(event).await;
     }
 
     fn event_receiver(
@@ -211,3 +422,50 @@ impl InputProcessor for RotaryEncoderProcessor {
         KEYBOARD_REPORT_CHANNEL.sender()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::convert::Infallible;
+
+    struct NoPin;
+
+    impl embedded_hal::digital::ErrorType for NoPin {
+        type Error = Infallible;
+    }
+
+    impl InputPin for NoPin {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn test_free_spin() {
+        let now = Instant::from_millis(0);
+        let mut encoder = RotaryEncoder::new(NoPin, NoPin, 0)
+            .with_resolution(4)
+            .with_free_spin();
+        // Jitter cancels
+        assert_eq!(encoder.detent(2, now).1, 0);
+        assert_eq!(encoder.detent(-1, now).1, 0);
+        assert!(matches!(encoder.detent(3, now), (Direction::Clockwise, 1)));
+        assert!(matches!(encoder.detent(-2, now), (Direction::None, 0)));
+        assert!(matches!(
+            encoder.detent(-4, now),
+            (Direction::CounterClockwise, 1)
+        ));
+        // 2 pulses are kept after the event
+        assert_eq!(encoder.detent(-2, now).1, 1);
+
+        // The pulses of the other direction restart the count of a detented encoder
+        let mut encoder = RotaryEncoder::new(NoPin, NoPin, 0).with_resolution(4);
+        encoder.detent(3, now);
+        assert_eq!(encoder.detent(-1, now).1, 0);
+        assert_eq!(encoder.detent(3, now).1, 0);
+    }
+}
@@ -0,0 +1,150 @@
+//! Touch-sensitive encoder knobs
+//!
+//! [`TouchKnob`] presses a key at a position of the keymap while the knob is touched, so touching the knob can trigger any action.
+//! For example, put `MO(2)` at the position, and make layer 2 a volume layer of the encoder map,
+//! which is shown on the display as soon as the knob is touched, before it's turned.
+//!
+//! The position should be in the keymap, such as an unused position of the matrix, so that it can be changed in Vial as well.
+//! The touch is read by a [`TouchSensor`]. [`DigitalTouchSensor`] reads a touch IC with a digital output, like TTP223 or AT42QT1010,
+//! other sensors, like touch-capable pins or I2C touch ICs, can be used by implementing [`TouchSensor`]:
+//!
+//! ```rust,ignore
+//! let sensor = DigitalTouchSensor::new(Input::new(p.P0_29, Pull::None), true);
+//! let mut knob = TouchKnob::new(sensor, (4, 7));
+//! join(run_rmk(...), run_devices!(encoder, knob)).await;
+//! ```
+
+use core::future::Future;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Sender;
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::InputPin;
+#[cfg(feature = "async_matrix")]
+use embedded_hal_async::digital::Wait;
+
+use crate::event::{Event, KeyEvent};
+use crate::keyboard::EVENT_CHANNEL;
+
+use super::{InputDevice, EVENT_CHANNEL_SIZE};
+
+/// A sensor which detects whether the knob is touched
+pub trait TouchSensor {
+    /// Wait until the touch state changes, returns `true` if it's touched
+    fn wait_for_change(&mut self) -> impl Future<Output = bool>;
+}
+
+/// Touch IC with a digital output pin
+pub struct DigitalTouchSensor<P> {
+    pin: P,
+    /// Whether the output is high when touched
+    active_high: bool,
+    touched: bool,
+}
+
+impl<P: InputPin> DigitalTouchSensor<P> {
+    pub fn new(pin: P, active_high: bool) -> Self {
+        Self {
+            pin,
+            active_high,
+            touched: false,
+        }
+    }
+
+    fn is_touched(&mut self) -> bool {
+        self.pin
+            .is_high()
+            .is_ok_and(|high| high == self.active_high)
+    }
+}
+
+impl<
+        #[cfg(feature = "async_matrix")] P: InputPin + Wait,
+        #[cfg(not(feature = "async_matrix"))] P: InputPin,
+    > TouchSensor for DigitalTouchSensor<P>
+{
+    async fn wait_for_change(&mut self) -> bool {
+        loop {
+            // If not using async_matrix feature, read the pin with 50HZ frequency
+            #[cfg(not(feature = "async_matrix"))]
+            Timer::after_millis(20).await;
+
+            #[cfg(feature = "async_matrix")]
+            let _ = self.pin.wait_for_any_edge().await;
+
+            let touched = self.is_touched();
+            if touched != self.touched {
+                self.touched = touched;
+                return touched;
+            }
+        }
+    }
+}
+
+/// A knob which presses the key at `pos` while it's touched
+pub struct TouchKnob<T: TouchSensor> {
+    sensor: T,
+    /// Position of the key in the keymap, `(row, col)`
+    pos: (u8, u8),
+    /// The key is released only if the knob isn't touched again within this time, so regripping the knob doesn't release it
+    release_delay: Duration,
+}
+
+impl<T: TouchSensor> TouchKnob<T> {
+    pub fn new(sensor: T, pos: (u8, u8)) -> Self {
+        Self {
+            sensor,
+            pos,
+            release_delay: Duration::from_millis(300),
+        }
+    }
+
+    /// Set the delay of releasing the key after the knob isn't touched. Default is 300ms.
+    pub fn with_release_delay(mut self, release_delay: Duration) -> Self {
+        self.release_delay = release_delay;
+        self
+    }
+
+    async fn send_key(&self, pressed: bool) {
+        self.event_sender()
+            .send(Event::Key(KeyEvent {
+                row: self.pos.0,
+                col: self.pos.1,
+                pressed,
+            }))
+            .await;
+    }
+}
+
+impl<T: TouchSensor> InputDevice for TouchKnob<T> {
+    type EventType = Event;
+
+    async fn run(&mut self) {
+        loop {
+            if !self.sensor.wait_for_change().await {
+                continue;
+            }
+            self.send_key(true).await;
+            // Wait until the knob is left for `release_delay`
+            loop {
+                while self.sensor.wait_for_change().await {}
+                match select(
+                    Timer::after(self.release_delay),
+                    self.sensor.wait_for_change(),
+                )
+                .await
+                {
+                    Either::First(_) => break,
+                    // Touched again
+                    Either::Second(_) => (),
+                }
+            }
+            self.send_key(false).await;
+        }
+    }
+
+    fn event_sender(&self) -> Sender<CriticalSectionRawMutex, Self::EventType, EVENT_CHANNEL_SIZE> {
+        EVENT_CHANNEL.sender()
+    }
+}
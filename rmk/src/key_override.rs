@@ -0,0 +1,145 @@
+//! Key overrides: a key sends another key when it's pressed with some modifiers, like QMK's key overrides
+//!
+//! Overrides are defined in a compile-time table, for example Shift+Backspace → Delete:
+//!
+//! ```rust,ignore
+//! const SHIFT: ModifierCombination = ModifierCombination::new_from(false, false, false, true, false);
+//! const NONE: ModifierCombination = ModifierCombination::new();
+//!
+//! const KEY_OVERRIDES: &[KeyOverride] = &[
+//!     KeyOverride::new(SHIFT, KeyCode::Backspace, NONE, KeyCode::Delete, ALL_LAYERS),
+//!     // Only on layer 0 and 1
+//!     KeyOverride::new(SHIFT, KeyCode::Escape, NONE, KeyCode::Grave, 0b11),
+//! ];
+//!
+//! let behavior_config = BehaviorConfig {
+//!     key_override: KeyOverrideConfig {
+//!         overrides: KEY_OVERRIDES,
+//!     },
+//!     ..Default::default()
+//! };
+//! ```
+//!
+//! Trigger modifiers match held modifiers on either side, so `LShift` in the trigger matches both shift keys.
+//! Pending one-shot modifiers are held until the next key is released, so they trigger overrides as well.
+//! Weak modifiers applied by Caps Word or Shift Word aren't held modifiers, they never trigger an override.
+//!
+//! When the trigger key is pressed, the replacement key is registered instead, and the held trigger modifiers
+//! are replaced by the replacement modifiers in the report. The override lasts until the key is released,
+//! keys pressed meanwhile are also sent with the replaced modifiers, so the replacement key is never resent with the trigger modifiers.
+//! After that, the held modifiers are sent as usual. Overrides are checked against the highest active layer.
+
+use crate::keycode::{KeyCode, ModifierCombination};
+
+/// All layers of [`KeyOverride::layers`]
+pub const ALL_LAYERS: u32 = u32::MAX;
+
+/// An override of a key which is pressed with modifiers
+#[derive(Clone, Copy, Debug)]
+pub struct KeyOverride {
+    /// Modifiers which should be held, the side of modifiers is ignored
+    pub trigger_mods: ModifierCombination,
+    /// The key which is overridden
+    pub trigger: KeyCode,
+    /// Modifiers which replace the trigger modifiers
+    pub replacement_mods: ModifierCombination,
+    /// The key which is sent instead, only basic keys which aren't modifiers are supported
+    pub replacement: KeyCode,
+    /// Layers where the override is enabled, bit n is layer n
+    pub layers: u32,
+}
+
+impl KeyOverride {
+    pub const fn new(
+        trigger_mods: ModifierCombination,
+        trigger: KeyCode,
+        replacement_mods: ModifierCombination,
+        replacement: KeyCode,
+        layers: u32,
+    ) -> Self {
+        Self {
+            trigger_mods,
+            trigger,
+            replacement_mods,
+            replacement,
+            layers,
+        }
+    }
+
+    /// Modifier bits of the held trigger modifiers, `None` if the override isn't triggered
+    fn triggered_modifiers(&self, key: KeyCode, held: u8, layer: u8) -> Option<u8> {
+        if key != self.trigger || layer >= 32 || self.layers & (1 << layer) == 0 {
+            return None;
+        }
+        // The lower 4 bits are ctrl, shift, alt and gui in both the combination and hid modifier bits
+        let trigger = self.trigger_mods.into_bits() & 0x0F;
+        let held_kinds = (held | (held >> 4)) & 0x0F;
+        if held_kinds & trigger != trigger {
+            return None;
+        }
+        Some(held & (trigger | (trigger << 4)))
+    }
+}
+
+/// Find the override of the pressed key with the held modifier bits on the layer.
+///
+/// Returns the replacement key, the held modifier bits which are suppressed and the modifier bits which are added
+pub(crate) fn find_override(
+    overrides: &[KeyOverride],
+    key: KeyCode,
+    held: u8,
+    layer: u8,
+) -> Option<(KeyCode, u8, u8)> {
+    overrides.iter().find_map(|o| {
+        o.triggered_modifiers(key, held, layer).map(|suppressed| {
+            (
+                o.replacement,
+                suppressed,
+                o.replacement_mods.to_hid_modifier_bits(),
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_override() {
+        let shift = ModifierCombination::new_from(false, false, false, true, false);
+        let ctrl = ModifierCombination::new_from(false, false, false, false, true);
+        let overrides = [
+            KeyOverride::new(
+                shift,
+                KeyCode::Backspace,
+                ModifierCombination::new(),
+                KeyCode::Delete,
+                ALL_LAYERS,
+            ),
+            KeyOverride::new(shift, KeyCode::Escape, ctrl, KeyCode::Grave, 0b10),
+        ];
+        let lshift = KeyCode::LShift.as_modifier_bit();
+        let rshift = KeyCode::RShift.as_modifier_bit();
+        let lctrl = KeyCode::LCtrl.as_modifier_bit();
+        assert_eq!(
+            find_override(&overrides, KeyCode::Backspace, lshift | lctrl, 0),
+            Some((KeyCode::Delete, lshift, 0))
+        );
+        // Either side of the trigger modifiers
+        assert_eq!(
+            find_override(&overrides, KeyCode::Backspace, rshift, 3),
+            Some((KeyCode::Delete, rshift, 0))
+        );
+        assert_eq!(
+            find_override(&overrides, KeyCode::Backspace, lctrl, 0),
+            None
+        );
+        // Disabled on layer 0
+        assert_eq!(find_override(&overrides, KeyCode::Escape, lshift, 0), None);
+        assert_eq!(
+            find_override(&overrides, KeyCode::Escape, lshift, 1),
+            Some((KeyCode::Grave, lshift, lctrl))
+        );
+    }
+}
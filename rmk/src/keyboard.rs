@@ -1,39 +1,209 @@
-use crate::config::BehaviorConfig;
-use crate::event::{Event, KeyEvent};
+use crate::config::{BehaviorConfig, DoubleTapShift, MouseConfig, TapHoldMode};
+use crate::event::{Event, KeyEvent, RotaryEncoderEvent};
+use crate::input_device::external_switch::SwitchEvent;
 use crate::CONNECTION_STATE;
 use crate::{
     action::{Action, KeyAction},
+    adjust::{
+        adjust_status, set_adjust_target, step_value, update_adjust_value, AdjustTarget,
+        ACTUATION_RANGE, ACTUATION_STEP, CPI_RANGE, CPI_STEP, TAPPING_TERM_RANGE,
+        TAPPING_TERM_STEP,
+    },
+    caps_word::CapsWord,
+    combo::{match_combos, ActiveCombo, MAX_COMBO_KEYS},
+    diagnostic::{key_tester_active, record_key_event, set_key_tester},
     hid::{ConnectionType, HidWriterWrapper},
-    keyboard_macro::{MacroOperation, NUM_MACRO},
+    host_layout::{host_layout, macro_layout, HostLayout},
+    input_device::rotary_encoder::Direction,
+    key_override::find_override,
+    keyboard_macro::{
+        dynamic_macro, parse_macro_operation, save_dynamic_macro, MacroOperation, MacroRecorder,
+        NUM_MACRO,
+    },
     keycode::{KeyCode, ModifierCombination},
-    keymap::KeyMap,
-    usb::descriptor::{CompositeReport, CompositeReportType, ViaReport},
+    keymap::{KeyMap, KEY_EDIT_CHANNEL},
+    report::{nkro_enabled, set_nkro, ReportBuilder},
+    socd::SocdState,
+    tap_dance::{DoubleTapDetector, TapDanceResult},
+    unicode::{process_unicode_mode_key, unicode_mode, unicode_operations},
+    usb::descriptor::{
+        CompositeReport, CompositeReportType, NkroKeyboardReport, ViaReport, NKRO_BITMAP_SIZE,
+        NKRO_REPORT_ID,
+    },
+    user_key::{user_key_function, UserKeyFunction},
     KEYBOARD_STATE,
 };
 use core::cell::RefCell;
-use embassy_futures::{select::select, yield_now};
+use core::sync::atomic::{AtomicI8, AtomicU32, AtomicU8, Ordering};
+use embassy_futures::{
+    select::{select, select3, select4, Either, Either3, Either4},
+    yield_now,
+};
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
     channel::{Channel, Receiver, Sender},
+    signal::Signal,
 };
 use embassy_time::{Instant, Timer};
-use heapless::{FnvIndexMap, Vec};
+use heapless::Vec;
 use usbd_hid::descriptor::KeyboardReport;
 
-pub const EVENT_CHANNEL_SIZE: usize = 32;
+pub const EVENT_CHANNEL_SIZE: usize = crate::ram_profile::EVENT_CHANNEL_SIZE;
 pub static KEY_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, KeyEvent, EVENT_CHANNEL_SIZE> =
     Channel::new();
 
 pub static EVENT_CHANNEL: Channel<CriticalSectionRawMutex, Event, EVENT_CHANNEL_SIZE> =
     Channel::new();
 
-pub const REPORT_CHANNEL_SIZE: usize = 32;
+/// Rotary encoder events routed from `EVENT_CHANNEL`, the keyboard triggers the actions in the encoder map
+pub(crate) static ENCODER_EVENT_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    RotaryEncoderEvent,
+    EVENT_CHANNEL_SIZE,
+> = Channel::new();
+
+/// Events of external switches, which are bound to actions directly instead of keymap positions
+pub(crate) static SWITCH_EVENT_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    SwitchEvent,
+    EVENT_CHANNEL_SIZE,
+> = Channel::new();
+
+/// Events of pointing devices(pointer motion, joystick, touchpad, etc.) routed from `EVENT_CHANNEL`.
+///
+/// Input processors which convert these events to mouse reports, like trackball or touchpad processors, should receive from this channel.
+pub static POINTING_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, Event, EVENT_CHANNEL_SIZE> =
+    Channel::new();
+
+/// Route an event of input devices.
+///
+/// Key and rotary encoder events are processed by the keyboard with the keymap,
+/// events of pointing devices are sent to [`POINTING_EVENT_CHANNEL`].
+pub(crate) async fn dispatch_event(event: Event) {
+    match event {
+        Event::Key(key_event) => KEY_EVENT_CHANNEL.send(key_event).await,
+        Event::RotaryEncoder(encoder_event) => {
+            if !matches!(encoder_event.direction, Direction::None) {
+                ENCODER_EVENT_CHANNEL.send(encoder_event).await
+            }
+        }
+        event => {
+            // Don't block keys when there's no processor of pointing events
+            if POINTING_EVENT_CHANNEL.try_send(event).is_err() {
+                debug!("Pointing event channel is full, event dropped");
+            }
+        }
+    }
+}
+
+/// Dispatcher task, which routes all events sent to `EVENT_CHANNEL` by input devices
+async fn dispatch_events() {
+    loop {
+        let event = EVENT_CHANNEL.receive().await;
+        dispatch_event(event).await;
+    }
+}
+
+/// Encoders aren't in the matrix, keys triggered by encoders use this row in the report, the column is the encoder id
+const ENCODER_ROW: u8 = u8::MAX;
+
+/// Position of the lock shortcut sent by the auto-lock in the report, which isn't in the matrix
+const AUTO_LOCK_POSITION: (u8, u8) = (u8::MAX - 1, 0);
+
+/// External switches aren't in the matrix, keys triggered by them use this row in the report, the column is the switch id
+const SWITCH_ROW: u8 = u8::MAX - 2;
+
+pub const REPORT_CHANNEL_SIZE: usize = crate::ram_profile::REPORT_CHANNEL_SIZE;
 pub(crate) static KEYBOARD_REPORT_CHANNEL: Channel<
     CriticalSectionRawMutex,
     KeyboardReportMessage,
     REPORT_CHANNEL_SIZE,
 > = Channel::new();
 
+pub const POINTER_REPORT_CHANNEL_SIZE: usize = crate::ram_profile::POINTER_REPORT_CHANNEL_SIZE;
+/// Mouse(pointer motion, scroll and buttons) reports are sent via a separate channel,
+/// so that heavy pointer traffic can't delay keystroke reports.
+/// Input processors which generate mouse reports, like trackballs or touchpads, should use this channel as well.
+pub static POINTER_REPORT_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    KeyboardReportMessage,
+    POINTER_REPORT_CHANNEL_SIZE,
+> = Channel::new();
+
+/// Mouse buttons held by mouse keys, pointing devices send them along with the motion
+pub(crate) static MOUSE_BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+/// Gamepad buttons held by joystick button keys, bit n is `JoystickButtonN`
+pub(crate) static GAMEPAD_BUTTONS: AtomicU32 = AtomicU32::new(0);
+
+/// Last x and y axes of the gamepad, sent along with the buttons
+pub(crate) static GAMEPAD_AXES: [AtomicI8; 2] = [const { AtomicI8::new(0) }; 2];
+
+/// Signaled when the connection to the host is established again
+static HOST_RECONNECTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Notify the keyboard that the host is connected again, after a USB resume or a BLE reconnection.
+///
+/// The keyboard sends the current state of held keys again, so that a report lost in the dropout
+/// doesn't leave a key stuck on the host, and keys held across the dropout are still pressed.
+pub(crate) fn notify_host_reconnected() {
+    crate::health::record_reconnect();
+    HOST_RECONNECTED.signal(());
+}
+
+/// Max number of key reports that can be sent in a row while pointer reports are waiting
+const MAX_KEY_REPORT_STREAK: u8 = 4;
+
+/// Scheduler which picks the next report from key report channel and pointer report channel.
+///
+/// Key reports have higher priority, but after `MAX_KEY_REPORT_STREAK` key reports in a row,
+/// a pending pointer report is sent, so that the pointer is never starved.
+pub(crate) struct ReportScheduler {
+    key_streak: u8,
+}
+
+impl ReportScheduler {
+    pub(crate) fn new() -> Self {
+        Self { key_streak: 0 }
+    }
+
+    pub(crate) async fn next<'a>(
+        &mut self,
+        receiver: &Receiver<
+            'a,
+            CriticalSectionRawMutex,
+            KeyboardReportMessage,
+            REPORT_CHANNEL_SIZE,
+        >,
+    ) -> KeyboardReportMessage {
+        if self.key_streak >= MAX_KEY_REPORT_STREAK {
+            if let Ok(report) = POINTER_REPORT_CHANNEL.try_receive() {
+                self.key_streak = 0;
+                return report;
+            }
+        }
+        if let Ok(report) = receiver.try_receive() {
+            self.key_streak = self.key_streak.saturating_add(1);
+            return report;
+        }
+        if let Ok(report) = POINTER_REPORT_CHANNEL.try_receive() {
+            self.key_streak = 0;
+            return report;
+        }
+        // Both channels are empty, wait for the next report
+        match select(receiver.receive(), POINTER_REPORT_CHANNEL.receive()).await {
+            Either::First(report) => {
+                self.key_streak = 1;
+                report
+            }
+            Either::Second(report) => {
+                self.key_streak = 0;
+                report
+            }
+        }
+    }
+}
+
 /// State machine for one shot keys
 #[derive(Default)]
 enum OneShotState<T> {
@@ -62,6 +232,8 @@ impl<T> OneShotState<T> {
 pub enum KeyboardReportMessage {
     /// Normal keyboard hid report
     KeyboardReport(KeyboardReport),
+    /// NKRO keyboard report, it's sent as a boot keyboard report via connections which don't support NKRO
+    NkroReport(NkroKeyboardReport),
     /// Other types of keyboard reports: mouse + media(consumer) + system control
     CompositeReport(CompositeReport, CompositeReportType),
 }
@@ -74,15 +246,42 @@ pub(crate) async fn communication_task<'a, W: HidWriterWrapper, W2: HidWriterWra
 ) {
     // This delay is necessary otherwise this task will stuck at the first send when the USB is suspended
     Timer::after_secs(2).await;
+    let mut scheduler = ReportScheduler::new();
     loop {
-        let report = receiver.receive().await;
+        let report = scheduler.next(receiver).await;
         // Only send the report after the connection is established.
         if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) {
             match report {
                 KeyboardReportMessage::KeyboardReport(report) => {
                     match keybooard_hid_writer.write_serialize(&report).await {
-                        Ok(()) => {}
-                        Err(e) => error!("Send keyboard report error: {:?}", e),
+                        Ok(()) => {
+                            #[cfg(feature = "latency_probe")]
+                            crate::latency::keyboard_report_sent(|k| {
+                                report.keycodes.contains(&(k as u8))
+                            });
+                        }
+                        Err(e) => {
+                            error!("Send keyboard report error: {:?}", e);
+                            crate::health::record_error();
+                        }
+                    };
+                }
+                KeyboardReportMessage::NkroReport(report) => {
+                    // NKRO report is in the composite hid interface
+                    let mut buf = [0; NKRO_BITMAP_SIZE + 2];
+                    buf[0] = NKRO_REPORT_ID;
+                    let size = report.serialize(&mut buf[1..]);
+                    match other_hid_writer.write(&buf[..size + 1]).await {
+                        Ok(()) => {
+                            #[cfg(feature = "latency_probe")]
+                            crate::latency::keyboard_report_sent(|k| {
+                                report.keycodes().any(|code| code == k as u8)
+                            });
+                        }
+                        Err(e) => {
+                            error!("Send NKRO report error: {:?}", e);
+                            crate::health::record_error();
+                        }
                     };
                 }
                 KeyboardReportMessage::CompositeReport(report, report_type) => {
@@ -110,6 +309,7 @@ pub(crate) async fn write_other_report_to_host<W: HidWriterWrapper>(
                 ConnectionType::Ble => other_hid_writer.write(&buf[1..s + 1]).await,
             } {
                 error!("Send other report error: {:?}", e);
+                crate::health::record_error();
             }
         }
         Err(_) => error!("Serialize other report error"),
@@ -145,27 +345,44 @@ pub(crate) struct Keyboard<'a, const ROW: usize, const COL: usize, const NUM_LAY
     /// One shot layer state
     osl_state: OneShotState<u8>,
 
-    /// Keyboard internal hid report buf
-    report: KeyboardReport,
+    /// Caps Word state
+    caps_word: CapsWord,
+
+    /// Recorder of the dynamic macro being recorded
+    macro_recorder: Option<MacroRecorder>,
+
+    /// Whether combos are enabled, toggled by `ComboOn`, `ComboOff` and `ComboToggle`
+    combo_enabled: bool,
+
+    /// Triggered combos whose keys are not all released
+    active_combos: Vec<ActiveCombo, 4>,
+
+    /// Pressed keys of combos which are processed as normal keys, so that they skip the combo detection
+    combo_passed: Vec<(u8, u8), 16>,
+
+    /// Tap dance keys which are resolved as hold, with the held action
+    tap_dance_held: Vec<((u8, u8), Action), 4>,
+
+    /// Double taps of shift and layer-mod keys
+    double_tap: DoubleTapDetector,
+
+    /// Layers locked by double-tapping a layer-mod key, bit n is layer n. Releasing the key doesn't deactivate a locked layer
+    locked_layers: u32,
+
+    /// Held keys of SOCD pairs
+    socd: SocdState,
 
-    /// Registered key position
-    registered_keys: [Option<(u8, u8)>; 6],
+    /// Position of the last layer tap toggle key, number of taps in a row, and the time of the last press or release
+    tap_toggle: Option<((u8, u8), u8, Instant)>,
 
-    /// Internal composite report: mouse + media(consumer) + system control
-    other_report: CompositeReport,
+    /// Internal hid reports: keyboard + mouse + media(consumer) + system control
+    report: ReportBuilder,
 
     /// Via report
     via_report: ViaReport,
 
-    /// Mouse key is different from other keyboard keys, it should be sent continuously while the key is pressed.
-    /// `last_mouse_tick` tracks at most 8 mouse keys, with its recent state.
-    /// It can be used to control the mouse report rate and release mouse key properly.
-    /// The key is mouse keycode, the value is the last action and its timestamp.
-    last_mouse_tick: FnvIndexMap<KeyCode, (bool, Instant), 4>,
-
-    /// The current distance of mouse key moving
-    mouse_key_move_delta: i8,
-    mouse_wheel_move_delta: i8,
+    /// Speed and report rate of mouse keys
+    mouse_config: MouseConfig,
 }
 
 impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
@@ -175,7 +392,16 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
         sender: &'a Sender<'a, CriticalSectionRawMutex, KeyboardReportMessage, REPORT_CHANNEL_SIZE>,
         behavior: BehaviorConfig,
+        mouse_config: MouseConfig,
     ) -> Self {
+        for k in behavior.tap_hold.per_key {
+            if !keymap.borrow().has_tap_hold(k.row as usize, k.col as usize) {
+                warn!(
+                    "Per-key tap hold setting of ({}, {}) is unused, the key has no tap/hold action",
+                    k.row, k.col
+                );
+            }
+        }
         Keyboard {
             keymap,
             sender,
@@ -193,79 +419,135 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             behavior,
             osm_state: OneShotState::default(),
             osl_state: OneShotState::default(),
+            caps_word: CapsWord::default(),
+            macro_recorder: None,
+            combo_enabled: true,
+            active_combos: Vec::new(),
+            combo_passed: Vec::new(),
+            tap_dance_held: Vec::new(),
+            double_tap: DoubleTapDetector::default(),
+            locked_layers: 0,
+            socd: SocdState::default(),
+            tap_toggle: None,
             unprocessed_events: Vec::new(),
-            report: KeyboardReport {
-                modifier: 0,
-                reserved: 0,
-                leds: 0,
-                keycodes: [0; 6],
-            },
-            registered_keys: Default::default(),
-            other_report: CompositeReport::default(),
+            report: ReportBuilder::new(),
             via_report: ViaReport {
                 input_data: [0; 32],
                 output_data: [0; 32],
             },
-            last_mouse_tick: FnvIndexMap::new(),
-            mouse_key_move_delta: 8,
-            mouse_wheel_move_delta: 1,
+            mouse_config,
         }
     }
 
+    /// Send all changed keyboard/media/system control reports
     pub(crate) async fn send_keyboard_report(&mut self) {
-        self.sender
-            .send(KeyboardReportMessage::KeyboardReport(self.report))
-            .await;
+        let one_shot = match self.osm_state {
+            OneShotState::Initial(m) | OneShotState::Single(m) => m.to_hid_modifier_bits(),
+            _ => 0,
+        };
+        self.report
+            .update_indicator(one_shot, self.caps_word.is_active());
+        if !self.report.is_dirty() {
+            return;
+        }
+        for report in self.report.take_pending() {
+            self.sender.send(report).await;
+        }
         // Yield once after sending the report to channel
         yield_now().await;
     }
 
-    /// Send system control report if needed
-    pub(crate) async fn send_system_control_report(&mut self) {
-        self.sender
-            .send(KeyboardReportMessage::CompositeReport(
-                self.other_report,
-                CompositeReportType::System,
-            ))
-            .await;
-        self.other_report.system_usage_id = 0;
-        yield_now().await;
-    }
-
-    /// Send media report if needed
-    pub(crate) async fn send_media_report(&mut self) {
-        self.sender
-            .send(KeyboardReportMessage::CompositeReport(
-                self.other_report,
-                CompositeReportType::Media,
-            ))
-            .await;
-        self.other_report.media_usage_id = 0;
-        yield_now().await;
-    }
-
     /// Send mouse report if needed
     pub(crate) async fn send_mouse_report(&mut self) {
-        // Prevent mouse report flooding, set maximum mouse report rate to 50 HZ
-        self.sender
+        MOUSE_BUTTONS.store(self.report.other.buttons, Ordering::Relaxed);
+        POINTER_REPORT_CHANNEL
             .send(KeyboardReportMessage::CompositeReport(
-                self.other_report,
+                self.report.other,
                 CompositeReportType::Mouse,
             ))
             .await;
         yield_now().await;
     }
 
+    /// Release all held keys, modifiers and mouse buttons, and send the reports to the host.
+    ///
+    /// It's used before the keyboard leaves the current host, like switching BLE profiles or powering down.
+    /// Physical releases of the keys afterwards don't change the reports.
+    async fn release_all_keys(&mut self) {
+        self.osm_state = OneShotState::None;
+        self.caps_word.deactivate();
+        self.report.release_all();
+        self.send_keyboard_report().await;
+        crate::mouse_key::release_all();
+        if self.report.other.buttons != 0 {
+            self.report.other.buttons = 0;
+            self.send_mouse_report().await;
+        }
+    }
+
     /// Main keyboard task, it receives input devices result, processes keys.
     /// The report is sent to communication task via `KEYBOARD_REPORT_CHANNEL`, and finally sent to the host
-    /// TODO: make keyboard an `InputProcessor`
     pub(crate) async fn run(&mut self) {
         KEYBOARD_STATE.store(true, core::sync::atomic::Ordering::Release);
+        // Events of input devices are routed while processing keys, mouse keys are repeated, and the keyboard sleeps with the host
+        let mouse_config = self.mouse_config;
+        let sleep_config = self.behavior.sleep;
+        select4(
+            self.process_events(),
+            dispatch_events(),
+            crate::mouse_key::run_mouse_keys(mouse_config),
+            crate::sleep::run_sleep_monitor(sleep_config),
+        )
+        .await;
+    }
+
+    /// Process key and rotary encoder events
+    async fn process_events(&mut self) {
         loop {
-            let key_event = KEY_EVENT_CHANNEL.receive().await;
+            let key_event = match select4(
+                KEY_EVENT_CHANNEL.receive(),
+                ENCODER_EVENT_CHANNEL.receive(),
+                select3(
+                    crate::auto_lock::wait_auto_lock(&self.behavior.auto_lock),
+                    SWITCH_EVENT_CHANNEL.receive(),
+                    KEY_EDIT_CHANNEL.receive(),
+                ),
+                HOST_RECONNECTED.wait(),
+            )
+            .await
+            {
+                Either4::First(key_event) => key_event,
+                Either4::Second(encoder_event) => {
+                    self.process_encoder_event(encoder_event).await;
+                    continue;
+                }
+                Either4::Third(Either3::First(_)) => {
+                    self.process_auto_lock().await;
+                    continue;
+                }
+                Either4::Third(Either3::Second(switch_event)) => {
+                    self.process_switch_event(switch_event).await;
+                    continue;
+                }
+                Either4::Third(Either3::Third(edit)) => {
+                    self.keymap.borrow_mut().set_action(
+                        edit.layer,
+                        edit.row,
+                        edit.col,
+                        edit.action,
+                    );
+                    continue;
+                }
+                Either4::Fourth(_) => {
+                    debug!("Host reconnected, send the state of held keys again");
+                    self.report.mark_all_dirty();
+                    self.send_keyboard_report().await;
+                    continue;
+                }
+            };
 
             // Process the key change
-            self.process_key_change(key_event).await;
+            self.process_key_event(key_event).await;
 
             // After processing the key change, check if there are unprocessed events
             // This will happen if there's recursion in key processing
@@ -275,16 +557,164 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                 }
                 // Process unprocessed events
                 let e = self.unprocessed_events.remove(0);
-                self.process_key_change(e).await;
+                self.process_key_event(e).await;
             }
         }
     }
 
+    /// Process a key event, combos are detected before the key is processed
+    async fn process_key_event(&mut self, key_event: KeyEvent) {
+        if !self.process_combo(key_event).await {
+            self.process_key_change(key_event).await;
+        }
+    }
+
+    /// Detect combos, returns `true` if the key event is consumed by a combo.
+    ///
+    /// When a key of a combo is pressed, following key events are buffered until the keys match a combo, or the combo timeout expires.
+    /// Buffered events which don't form a combo are put back to `unprocessed_events`.
+    async fn process_combo(&mut self, key_event: KeyEvent) -> bool {
+        let pos = (key_event.row, key_event.col);
+        let combos = self.behavior.combo.combos;
+        if !key_event.pressed {
+            if let Some(i) = self.active_combos.iter().position(|c| {
+                combos[c.index]
+                    .key_index(key_event.row, key_event.col)
+                    .is_some_and(|k| c.held & (1 << k) != 0)
+            }) {
+                let combo = &combos[self.active_combos[i].index];
+                let all_held = ((1u16 << combo.keys.len()) - 1) as u8;
+                if self.active_combos[i].held == all_held {
+                    // The first released key of the combo releases the action
+                    let (row, col) = combo.keys[0];
+                    let release = KeyEvent {
+                        row,
+                        col,
+                        pressed: false,
+                    };
+                    self.process_key_action(combo.action, release).await;
+                }
+                let k = combo.key_index(key_event.row, key_event.col).unwrap_or(0);
+                self.active_combos[i].held &= !(1 << k);
+                if self.active_combos[i].held == 0 {
+                    self.active_combos.swap_remove(i);
+                }
+                return true;
+            }
+            if let Some(i) = self.combo_passed.iter().position(|&p| p == pos) {
+                self.combo_passed.swap_remove(i);
+            }
+            return false;
+        }
+
+        if !self.combo_enabled
+            || combos.is_empty()
+            || key_tester_active()
+            || self.active_combos.is_full()
+            || self.combo_passed.contains(&pos)
+        {
+            return false;
+        }
+        let layer = self.keymap.borrow().get_activated_layer();
+        let mut keys: Vec<(u8, u8), MAX_COMBO_KEYS> = Vec::new();
+        keys.push(pos).ok();
+        if !match_combos(combos, layer, &keys).partial {
+            return false;
+        }
+
+        // Buffer key events until the keys can't form a larger combo
+        let deadline = Instant::now() + self.behavior.combo.timeout;
+        let mut interrupted = None;
+        let matched = loop {
+            let current = match_combos(combos, layer, &keys);
+            if !current.partial {
+                break current.exact;
+            }
+            let next = if !self.unprocessed_events.is_empty() {
+                Some(self.unprocessed_events.remove(0))
+            } else {
+                match select(Timer::at(deadline), KEY_EVENT_CHANNEL.receive()).await {
+                    Either::First(_) => None,
+                    Either::Second(e) => Some(e),
+                }
+            };
+            let Some(e) = next else {
+                // Combo timeout
+                break current.exact;
+            };
+            if e.pressed && !keys.contains(&(e.row, e.col)) && keys.push((e.row, e.col)).is_ok() {
+                let m = match_combos(combos, layer, &keys);
+                if m.exact.is_some() || m.partial {
+                    continue;
+                }
+                keys.pop();
+            }
+            interrupted = Some(e);
+            break current.exact;
+        };
+
+        let mut next_index = 0;
+        let consumed = if let Some(index) = matched {
+            debug!("Combo {} triggered: {:?}", index, combos[index].action);
+            crate::display::notify_key_activity();
+            let combo = &combos[index];
+            let mut held = 0;
+            for &k in keys.iter() {
+                held |= 1 << combo.key_index(k.0, k.1).unwrap_or(0);
+            }
+            self.active_combos.push(ActiveCombo { index, held }).ok();
+            let (row, col) = combo.keys[0];
+            let press = KeyEvent {
+                row,
+                col,
+                pressed: true,
+            };
+            self.process_key_action(combo.action, press).await;
+            true
+        } else {
+            // Not a combo, the first key is processed by the caller, other keys are processed later as usual
+            for &(row, col) in keys.iter() {
+                self.combo_passed.push((row, col)).ok();
+                if (row, col) != pos {
+                    let e = KeyEvent {
+                        row,
+                        col,
+                        pressed: true,
+                    };
+                    self.unprocessed_events.insert(next_index, e).ok();
+                    next_index += 1;
+                }
+            }
+            false
+        };
+        if let Some(e) = interrupted {
+            self.unprocessed_events.insert(next_index, e).ok();
+        }
+        consumed
+    }
+
     /// Process key changes at (row, col)
     async fn process_key_change(&mut self, key_event: KeyEvent) {
+        if key_tester_active() {
+            self.process_key_tester(key_event).await;
+            return;
+        }
+
         // Matrix should process key pressed event first, record the timestamp of key changes
         if key_event.pressed {
-            self.timer[key_event.col as usize][key_event.row as usize] = Some(Instant::now());
+            // Events out of the keymap, like transformed or virtual keys, don't have a timer
+            if let Some(timer) = self
+                .timer
+                .get_mut(key_event.col as usize)
+                .and_then(|col| col.get_mut(key_event.row as usize))
+            {
+                *timer = Some(Instant::now());
+            }
+            crate::display::notify_key_activity();
+            crate::actuator::notify_key_press();
+            crate::rgb::notify_key_press(key_event.row, key_event.col);
+            crate::health::record_key_press();
+            crate::display::record_key_press();
         }
 
         // Process key
@@ -292,6 +722,50 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             .keymap
             .borrow_mut()
             .get_action_with_layer_cache(key_event);
+        let config = &self.behavior.tap_dance;
+        if (config.double_tap_shift != DoubleTapShift::None || config.double_tap_layer_lock)
+            && self
+                .double_tap
+                .update(key_event, Instant::now(), config.tapping_term)
+        {
+            self.process_double_tap(action, key_event).await;
+        }
+        self.process_key_action(action, key_event).await;
+    }
+
+    /// Process the second press of a double-tapped key, before the press itself is processed
+    async fn process_double_tap(&mut self, action: KeyAction, key_event: KeyEvent) {
+        match action {
+            KeyAction::Single(Action::Key(KeyCode::LShift | KeyCode::RShift)) => {
+                match self.behavior.tap_dance.double_tap_shift {
+                    DoubleTapShift::CapsWord => {
+                        self.caps_word.toggle();
+                        self.report.set_weak_modifier(0);
+                    }
+                    DoubleTapShift::CapsLock => {
+                        self.process_key_action_tap(Action::Key(KeyCode::CapsLock), key_event)
+                            .await
+                    }
+                    DoubleTapShift::None => (),
+                }
+            }
+            KeyAction::Single(Action::LayerOn(layer))
+            | KeyAction::WithModifier(Action::LayerOn(layer), _)
+                if self.behavior.tap_dance.double_tap_layer_lock && layer < 32 =>
+            {
+                self.locked_layers ^= 1 << layer;
+                info!(
+                    "Layer {} locked: {}",
+                    layer,
+                    self.locked_layers & (1 << layer) != 0
+                );
+            }
+            _ => (),
+        }
+    }
+
+    /// Process the action of a key change
+    async fn process_key_action(&mut self, action: KeyAction, key_event: KeyEvent) {
         match action {
             KeyAction::No | KeyAction::Transparent => (),
             KeyAction::Single(a) => self.process_key_action_normal(a, key_event).await,
@@ -317,6 +791,8 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                 self.process_key_action_tap_hold(tap_action, modifier_action, key_event)
                     .await;
             }
+            KeyAction::TapDance(index) => self.process_key_action_tap_dance(index, key_event).await,
+            KeyAction::Unicode(c) => self.process_key_action_unicode(c, key_event).await,
         }
 
         // Record release of current key, which will be used in tap/hold processing
@@ -336,6 +812,235 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         if let Some(ref tri_layer) = self.behavior.tri_layer {
             self.keymap.borrow_mut().update_tri_layer(tri_layer);
         }
+
+        // Send all remaining changes of this key event
+        self.send_keyboard_report().await;
+    }
+
+    /// Process a rotary encoder event, the action of the encoder on current layers is tapped.
+    ///
+    /// The press and the release of the tap are resolved with the layer cache, like keys.
+    async fn process_encoder_event(&mut self, event: RotaryEncoderEvent) {
+        if key_tester_active() {
+            return;
+        }
+        if let Some((target, value)) = adjust_status() {
+            crate::display::notify_key_activity();
+            self.adjust_value(target, value, &event.direction);
+            return;
+        }
+        let action = self.keymap.borrow_mut().encoder_action_with_layer_cache(
+            event.id,
+            &event.direction,
+            true,
+        );
+        if action == KeyAction::No {
+            return;
+        }
+        crate::display::notify_key_activity();
+        // Keys triggered by the encoder aren't recorded, the step is replayed with the encoder map instead
+        if let Some(recorder) = self.macro_recorder.as_mut() {
+            let clockwise = matches!(event.direction, Direction::Clockwise);
+            if !recorder.record_encoder(event.id, clockwise) {
+                warn!("Dynamic macro {} is full", recorder.slot());
+                self.stop_macro_recording();
+            }
+        }
+
+        let mut key_event = KeyEvent {
+            row: ENCODER_ROW,
+            col: event.id,
+            pressed: true,
+        };
+        self.process_encoder_action(action, key_event).await;
+        Timer::after_millis(10).await;
+
+        key_event.pressed = false;
+        let action = self.keymap.borrow_mut().encoder_action_with_layer_cache(
+            event.id,
+            &event.direction,
+            false,
+        );
+        self.process_encoder_action(action, key_event).await;
+        self.last_release = (key_event, false, Some(Instant::now()));
+
+        self.send_keyboard_report().await;
+    }
+
+    /// Process the function of a user keycode, see [`crate::user_key`]
+    async fn process_user_key_function(&mut self, function: UserKeyFunction, key_event: KeyEvent) {
+        match function {
+            UserKeyFunction::BatteryCheck => crate::rgb::set_battery_check(key_event.pressed),
+            #[cfg(feature = "_nrf_ble")]
+            UserKeyFunction::Output(mode) => {
+                if !key_event.pressed {
+                    // Don't leave stuck keys on the current host before switching to another one
+                    self.release_all_keys().await;
+                    crate::output::set_output_mode(mode).await;
+                }
+            }
+            // Other functions are triggered on press
+            _ if !key_event.pressed => (),
+            UserKeyFunction::NextDisplayPage => crate::display::next_display_page(),
+            UserKeyFunction::KeyTester => set_key_tester(true),
+            UserKeyFunction::NextPowerProfile => crate::power::next_power_profile(),
+            #[cfg(feature = "calculator")]
+            UserKeyFunction::Calculator => crate::calculator::toggle_calculator(),
+            UserKeyFunction::PomodoroStartPause => crate::pomodoro::start_pause_pomodoro(),
+            UserKeyFunction::PomodoroReset => crate::pomodoro::reset_pomodoro(),
+            UserKeyFunction::ToggleAutoLock => crate::auto_lock::toggle_auto_lock_suppressed(),
+            UserKeyFunction::NextAdjustTarget => self.select_next_adjust_target(),
+            UserKeyFunction::FireActuator(index) => crate::actuator::fire_actuator(index),
+            UserKeyFunction::ToggleKeyPressFeedback => crate::actuator::toggle_key_press_feedback(),
+            UserKeyFunction::ToggleNaturalScrolling => crate::scroll::toggle_natural_scrolling(),
+        }
+    }
+
+    /// Select the next parameter adjusted by encoders, see [`crate::adjust`]
+    fn select_next_adjust_target(&mut self) {
+        let target = match adjust_status() {
+            Some((target, _)) => target.next(),
+            None => Some(AdjustTarget::RgbBrightness),
+        };
+        let value = target.map(|t| match t {
+            AdjustTarget::RgbBrightness => {
+                crate::rgb::active_zone_state().map_or(0, |z| z.color.v as u16)
+            }
+            AdjustTarget::TappingTerm => self.behavior.tap_hold.hold_timeout.as_millis() as u16,
+            #[cfg(feature = "pmw33xx")]
+            AdjustTarget::PointerCpi => crate::input_device::pmw33xx::cpi(),
+            #[cfg(not(feature = "pmw33xx"))]
+            AdjustTarget::PointerCpi => 0,
+            AdjustTarget::ActuationPoint => crate::analog_matrix::actuation_point(),
+        });
+        set_adjust_target(target.zip(value));
+    }
+
+    /// Adjust the selected parameter by one encoder detent
+    fn adjust_value(&mut self, target: AdjustTarget, value: u16, direction: &Direction) {
+        let steps = match direction {
+            Direction::Clockwise => 1,
+            Direction::CounterClockwise => -1,
+            Direction::None => return,
+        };
+        let value = match target {
+            AdjustTarget::RgbBrightness => {
+                // Use the brightness step of RGB keycodes
+                crate::rgb::process_rgb_keycode(if steps > 0 {
+                    KeyCode::RgbVai
+                } else {
+                    KeyCode::RgbVad
+                });
+                crate::rgb::active_zone_state().map_or(value, |z| z.color.v as u16)
+            }
+            AdjustTarget::TappingTerm => {
+                let value = step_value(value, steps, TAPPING_TERM_STEP, TAPPING_TERM_RANGE);
+                self.behavior.tap_hold.hold_timeout =
+                    embassy_time::Duration::from_millis(value as u64);
+                value
+            }
+            AdjustTarget::PointerCpi => {
+                let value = step_value(value, steps, CPI_STEP, CPI_RANGE);
+                #[cfg(feature = "pmw33xx")]
+                crate::input_device::pmw33xx::set_cpi(value);
+                value
+            }
+            AdjustTarget::ActuationPoint => {
+                let value = step_value(value, steps, ACTUATION_STEP, ACTUATION_RANGE);
+                crate::analog_matrix::set_actuation_point(value);
+                value
+            }
+        };
+        debug!("Adjust {:?}: {}", target, value);
+        update_adjust_value(value);
+    }
+
+    /// Press or release the action of an encoder
+    async fn process_encoder_action(&mut self, action: KeyAction, key_event: KeyEvent) {
+        match action {
+            // There's no hold for an encoder, trigger the tap action
+            KeyAction::Single(a)
+            | KeyAction::Tap(a)
+            | KeyAction::TapHold(a, _)
+            | KeyAction::LayerTapHold(a, _)
+            | KeyAction::ModifierTapHold(a, _) => {
+                self.process_key_action_normal(a, key_event).await
+            }
+            KeyAction::WithModifier(a, m) => {
+                self.process_key_action_with_modifier(a, m, key_event).await
+            }
+            KeyAction::No => (),
+            _ => {
+                if key_event.pressed {
+                    warn!("Unsupported encoder action: {:?}", action);
+                }
+            }
+        }
+    }
+
+    /// Process a state change of an external switch, the action bound to the switch is held while it's pressed
+    async fn process_switch_event(&mut self, event: SwitchEvent) {
+        if key_tester_active() {
+            return;
+        }
+        if event.pressed {
+            crate::display::notify_key_activity();
+        }
+        let key_event = KeyEvent {
+            row: SWITCH_ROW,
+            col: event.id,
+            pressed: event.pressed,
+        };
+        // Like encoders, external switches have no timer for tap-hold, so the tap action is used
+        self.process_encoder_action(event.action, key_event).await;
+        if !event.pressed {
+            self.last_release = (key_event, false, Some(Instant::now()));
+        }
+        self.send_keyboard_report().await;
+    }
+
+    /// Tap the lock shortcut of the host OS, after the keyboard is idle for the auto-lock timeout
+    async fn process_auto_lock(&mut self) {
+        let os = self.behavior.auto_lock.os;
+        info!("Keyboard is idle, lock the host: {:?}", os);
+        let (key, modifier) = os.lock_shortcut();
+        let mut key_event = KeyEvent {
+            row: AUTO_LOCK_POSITION.0,
+            col: AUTO_LOCK_POSITION.1,
+            pressed: true,
+        };
+        self.process_key_action_with_modifier(Action::Key(key), modifier, key_event)
+            .await;
+        Timer::after_millis(10).await;
+        key_event.pressed = false;
+        self.process_key_action_with_modifier(Action::Key(key), modifier, key_event)
+            .await;
+        self.send_keyboard_report().await;
+    }
+
+    /// Process key changes in key tester mode, no key is sent to the host.
+    /// The key of [`UserKeyFunction::KeyTester`], `User14` by default, stops the key tester
+    async fn process_key_tester(&mut self, key_event: KeyEvent) {
+        record_key_event(key_event);
+        let stop_key = {
+            let keymap = self.keymap.borrow();
+            let (row, col) = keymap.logical_position(key_event.row, key_event.col);
+            row < ROW
+                && col < COL
+                && match keymap.current_action(row, col) {
+                    KeyAction::Single(Action::Key(key)) => {
+                        user_key_function(self.behavior.user_keys.functions, key)
+                            == Some(UserKeyFunction::KeyTester)
+                    }
+                    _ => false,
+                }
+        };
+        if key_event.pressed && stop_key {
+            set_key_tester(false);
+        }
+        // Release keys which are held when the key tester is started
+        self.report.release_all();
+        self.send_keyboard_report().await;
     }
 
     async fn update_osm(&mut self, key_event: KeyEvent) {
@@ -347,6 +1052,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                     for kc in keycodes.iter().take(n) {
                         self.process_action_keycode(*kc, key_event).await;
                     }
+                    self.send_keyboard_report().await;
                     self.osm_state = OneShotState::None;
                 }
             }
@@ -371,6 +1077,8 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         match action {
             Action::Key(key) => {
                 self.process_action_keycode(key, key_event).await;
+                // Send the key with current modifiers, before one-shot modifiers are released
+                self.send_keyboard_report().await;
                 self.update_osm(key_event).await;
                 self.update_osl(key_event);
             }
@@ -391,29 +1099,63 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             Action::LayerToggleOnly(layer_num) => {
                 // Activate a layer and deactivate all other layers(except default layer)
                 if key_event.pressed {
-                    // Disable all layers except the default layer
-                    let default_layer = self.keymap.borrow().get_default_layer();
-                    for i in 0..NUM_LAYER as u8 {
-                        if i != default_layer {
-                            self.keymap.borrow_mut().deactivate_layer(i);
-                        }
-                    }
-                    // Activate the target layer
-                    self.keymap.borrow_mut().activate_layer(layer_num);
+                    self.keymap.borrow_mut().activate_layer_only(layer_num);
                 }
             }
             Action::DefaultLayer(layer_num) => {
-                // Set the default layer
-                self.keymap.borrow_mut().set_default_layer(layer_num);
+                // Set the default layer, which is saved to the storage
+                if key_event.pressed {
+                    self.keymap.borrow_mut().set_default_layer(layer_num);
+                }
+            }
+            Action::LayerTapToggle(layer_num) => {
+                self.process_action_layer_tap_toggle(layer_num, key_event)
             }
             Action::Modifier(modifier) => {
                 let (keycodes, n) = modifier.to_modifier_keycodes();
                 for kc in keycodes.iter().take(n) {
                     self.process_action_keycode(*kc, key_event).await;
                 }
+                // All modifiers in the combination are sent in a single report
+                self.send_keyboard_report().await;
 
                 self.update_osl(key_event);
             }
+            Action::SoftOff => {
+                if key_event.pressed {
+                    self.process_soft_off(key_event).await;
+                }
+            }
+        }
+    }
+
+    /// Power the keyboard down if the `SoftOff` key is held for `hold_time`.
+    ///
+    /// Other key events during the hold are processed after the hold is finished or cancelled.
+    async fn process_soft_off(&mut self, key_event: KeyEvent) {
+        let hold_time = self.behavior.soft_off.hold_time;
+        let deadline = Instant::now() + hold_time;
+        crate::power::set_soft_off_hold(Some(hold_time));
+        loop {
+            match select(Timer::at(deadline), KEY_EVENT_CHANNEL.receive()).await {
+                embassy_futures::select::Either::First(_) => {
+                    self.release_all_keys().await;
+                    crate::power::enter_soft_off(&self.behavior.soft_off).await;
+                    return;
+                }
+                embassy_futures::select::Either::Second(e) => {
+                    if e.row == key_event.row && e.col == key_event.col && !e.pressed {
+                        debug!("SoftOff key released before hold time");
+                        crate::power::set_soft_off_hold(None);
+                        return;
+                    }
+                    if self.unprocessed_events.push(e).is_err() {
+                        warn!("Too many key events while holding SoftOff, cancelled");
+                        crate::power::set_soft_off_hold(None);
+                        return;
+                    }
+                }
+            }
         }
     }
 
@@ -440,6 +1182,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             for kc in keycodes.iter().take(n) {
                 self.process_action_keycode(*kc, key_event).await;
             }
+            self.send_keyboard_report().await;
         }
     }
 
@@ -465,6 +1208,122 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         }
     }
 
+    /// Process a tap dance key, see [`crate::tap_dance`].
+    ///
+    /// Taps are counted until the tapping term expires or another key is pressed,
+    /// events of other keys are buffered and processed after the tap dance is resolved.
+    async fn process_key_action_tap_dance(&mut self, index: u8, key_event: KeyEvent) {
+        let Some(tap_dance) = self
+            .behavior
+            .tap_dance
+            .tap_dances
+            .get(index as usize)
+            .copied()
+        else {
+            warn!("Tap dance {} is not defined", index);
+            return;
+        };
+        let pos = (key_event.row, key_event.col);
+        if !key_event.pressed {
+            // Only a tap dance resolved as hold is still active when it's released
+            if let Some(i) = self.tap_dance_held.iter().position(|(p, _)| *p == pos) {
+                let (_, action) = self.tap_dance_held.swap_remove(i);
+                self.process_key_action_normal(action, key_event).await;
+            }
+            return;
+        }
+
+        let tapping_term = self.behavior.tap_dance.tapping_term;
+        let max_taps = tap_dance.max_taps();
+        let mut deadline = Instant::now() + tapping_term;
+        let mut taps = 0;
+        let mut pressed = true;
+        let mut buffered = 0;
+        // Same as tap/hold, pending events come after the tap dance key and are kept after buffered events
+        let mut pending = self.unprocessed_events.len();
+        let result = loop {
+            if !pressed && taps >= max_taps {
+                // More taps can't trigger another action
+                break TapDanceResult::Tap(taps);
+            }
+            let next = if pending > 0 {
+                pending -= 1;
+                Some(self.unprocessed_events.remove(buffered))
+            } else {
+                match select(Timer::at(deadline), KEY_EVENT_CHANNEL.receive()).await {
+                    Either::First(_) => None,
+                    Either::Second(e) => Some(e),
+                }
+            };
+            let Some(e) = next else {
+                // Tapping term expired
+                break if pressed {
+                    TapDanceResult::Hold(taps)
+                } else {
+                    TapDanceResult::Tap(taps)
+                };
+            };
+            if (e.row, e.col) == pos {
+                if e.pressed != pressed {
+                    pressed = e.pressed;
+                    if !pressed {
+                        taps += 1;
+                    }
+                    deadline = Instant::now() + tapping_term;
+                }
+                continue;
+            }
+            self.unprocessed_events.insert(buffered, e).ok();
+            buffered += 1;
+            if e.pressed {
+                // Another key is pressed, resolve the tap dance immediately
+                break if pressed {
+                    TapDanceResult::Hold(taps)
+                } else {
+                    TapDanceResult::Tap(taps)
+                };
+            }
+        };
+
+        debug!("Tap dance {} resolved: {:?}", index, result);
+        match result {
+            TapDanceResult::Tap(taps) => {
+                if let Some(action) = tap_dance.tap_action(taps) {
+                    self.process_key_action_tap(action, key_event).await;
+                }
+            }
+            TapDanceResult::Hold(taps) => {
+                if let Some(action) = tap_dance.hold_action(taps) {
+                    self.process_key_action_normal(action, key_event).await;
+                    if self.tap_dance_held.push((pos, action)).is_err() {
+                        warn!("Too many tap dance keys are held, release {:?}", action);
+                        let release = KeyEvent {
+                            pressed: false,
+                            ..key_event
+                        };
+                        self.process_key_action_normal(action, release).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get `hold_timeout` and `mode` of a tap/hold key, the per-key settings take precedence
+    fn tap_hold_settings(&self, key_event: KeyEvent) -> (embassy_time::Duration, TapHoldMode) {
+        let config = &self.behavior.tap_hold;
+        match config
+            .per_key
+            .iter()
+            .find(|k| k.row == key_event.row && k.col == key_event.col)
+        {
+            Some(k) => (
+                k.hold_timeout.unwrap_or(config.hold_timeout),
+                k.mode.unwrap_or(config.mode),
+            ),
+            None => (config.hold_timeout, config.mode),
+        }
+    }
+
     /// Process tap/hold action for home row mods(HRM)
     ///
     /// For HRMs, the "tap" action actually has higher priority, especially when typing fast.
@@ -490,6 +1349,15 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         hold_action: Action,
         key_event: KeyEvent,
     ) {
+        // Keys out of the keymap, like keys of a swapped matrix which are remapped into the keymap, don't have a timer
+        if key_event.row as usize >= ROW || key_event.col as usize >= COL {
+            warn!(
+                "Tap/hold key ({}, {}) is out of the {}x{} keymap, it's processed as tap",
+                key_event.row, key_event.col, ROW, COL
+            );
+            self.process_key_action_normal(tap_action, key_event).await;
+            return;
+        }
         if self.behavior.tap_hold.enable_hrm {
             // If HRM is enabled, check whether it's a different key is in key streak
             if let Some(last_release_time) = self.last_release.2 {
@@ -502,7 +1370,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                         debug!("Key streak detected, trigger tap action");
                         self.process_key_action_tap(tap_action, key_event).await;
                         return;
-                    } else if last_release_time.elapsed() < self.behavior.tap_hold.hold_timeout
+                    } else if last_release_time.elapsed() < self.tap_hold_settings(key_event).0
                         && key_event.row == self.last_release.0.row
                         && key_event.col == self.last_release.0.col
                     {
@@ -524,48 +1392,79 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             // Press
             self.timer[col][row] = Some(Instant::now());
 
-            let hold_timeout =
-                embassy_time::Timer::after_millis(self.behavior.tap_hold.hold_timeout.as_millis());
-            match select(hold_timeout, KEY_EVENT_CHANNEL.receive()).await {
-                embassy_futures::select::Either::First(_) => {
-                    // Timeout, trigger hold
-                    debug!("Hold timeout, got HOLD: {:?}, {:?}", hold_action, key_event);
-                    self.process_key_action_normal(hold_action, key_event).await;
-                }
-                embassy_futures::select::Either::Second(e) => {
-                    if e.row == key_event.row && e.col == key_event.col {
-                        // If it's same key event and releasing within `hold_timeout`, trigger tap
-                        if !e.pressed {
-                            let elapsed = self.timer[col][row].unwrap().elapsed().as_millis();
-                            debug!("TAP action: {:?}, time elapsed: {}ms", tap_action, elapsed);
-                            self.process_key_action_tap(tap_action, key_event).await;
-
-                            // Clear timer
-                            self.timer[col][row] = None;
+            let (hold_timeout, mode) = self.tap_hold_settings(key_event);
+            let deadline = Instant::now() + hold_timeout;
+            // Keys pressed after the tap/hold key, which are buffered until the tap/hold key is resolved
+            let mut pressed_after: Vec<(u8, u8), 8> = Vec::new();
+            let mut buffered = 0;
+            // Events which are already in `unprocessed_events` come after the tap/hold key, such as keys buffered by combos.
+            // Buffered events are kept before pending events, to preserve the order
+            let mut pending = self.unprocessed_events.len();
+            loop {
+                let next = if pending > 0 {
+                    pending -= 1;
+                    embassy_futures::select::Either::Second(
+                        self.unprocessed_events.remove(buffered),
+                    )
+                } else {
+                    select(Timer::at(deadline), KEY_EVENT_CHANNEL.receive()).await
+                };
+                match next {
+                    embassy_futures::select::Either::First(_) => {
+                        // Timeout, trigger hold
+                        debug!("Hold timeout, got HOLD: {:?}, {:?}", hold_action, key_event);
+                        self.process_key_action_normal(hold_action, key_event).await;
+                        break;
+                    }
+                    embassy_futures::select::Either::Second(e) => {
+                        if e.row == key_event.row && e.col == key_event.col {
+                            // If it's same key event and releasing within `hold_timeout`, trigger tap
+                            if !e.pressed {
+                                let elapsed = self.timer[col][row].unwrap().elapsed().as_millis();
+                                debug!("TAP action: {:?}, time elapsed: {}ms", tap_action, elapsed);
+                                self.process_key_action_tap(tap_action, key_event).await;
+
+                                // Clear timer
+                                self.timer[col][row] = None;
+                                break;
+                            }
+                            continue;
                         }
-                    } else {
+
                         // A different key comes
-                        // If it's a release event, the key is pressed BEFORE tap/hold key, so it should be regarded as a normal key
-                        self.unprocessed_events.push(e).ok();
-                        if !e.pressed {
+                        let pressed_before = !e.pressed && !pressed_after.contains(&(e.row, e.col));
+                        let free =
+                            self.unprocessed_events.capacity() - self.unprocessed_events.len();
+                        if pressed_before && buffered == 0 && free >= 2 {
+                            // The key is pressed BEFORE tap/hold key, so it should be regarded as a normal key.
                             // we push the current tap/hold event again, the loop will process the release first, then re-process current tap/hold
-                            self.unprocessed_events.push(key_event).ok();
+                            self.unprocessed_events.insert(0, key_event).ok();
+                            self.unprocessed_events.insert(0, e).ok();
                             return;
                         }
-
-                        // Wait for key release, record all pressed keys during this
-                        loop {
-                            let next_key_event = KEY_EVENT_CHANNEL.receive().await;
-                            self.unprocessed_events.push(next_key_event).ok();
-                            if !next_key_event.pressed {
-                                break;
-                            }
+                        // There's always a free slot here, because the loop is broken once the buffer is full
+                        self.unprocessed_events.insert(buffered, e).ok();
+                        buffered += 1;
+                        if e.pressed {
+                            pressed_after.push((e.row, e.col)).ok();
                         }
 
-                        // Process hold action
-                        self.process_key_action_normal(hold_action, key_event).await;
-
-                        // All other unprocessed events will be processed later
+                        let resolve_hold = if self.unprocessed_events.is_full() {
+                            // No room for more keys, resolve the tap/hold key now rather than dropping the next event
+                            warn!("Too many keys are buffered by a tap/hold key, trigger hold");
+                            true
+                        } else if e.pressed {
+                            mode == TapHoldMode::HoldOnOtherKeyPress
+                        } else {
+                            // A key pressed after the tap/hold key is released
+                            !pressed_before && mode == TapHoldMode::PermissiveHold
+                        };
+                        if resolve_hold {
+                            debug!("HOLD action: {:?}, interrupted by {:?}", hold_action, e);
+                            // All other unprocessed events will be processed later
+                            self.process_key_action_normal(hold_action, key_event).await;
+                            break;
+                        }
                     }
                 }
             }
@@ -740,6 +1639,8 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             self.process_action_system_control(key, key_event).await;
         } else if key.is_mouse_key() {
             self.process_action_mouse(key, key_event).await;
+        } else if key.is_joystick() {
+            self.process_action_gamepad(key, key_event).await;
         } else if key.is_user() {
             #[cfg(feature = "_nrf_ble")]
             use crate::ble::nrf::profile::{BleProfileAction, BLE_PROFILE_CHANNEL};
@@ -747,6 +1648,10 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             if !key_event.pressed {
                 // Get user key id
                 let id = key as u8 - KeyCode::User0 as u8;
+                if id <= 11 {
+                    // Don't leave stuck keys on the current host before switching to another one
+                    self.release_all_keys().await;
+                }
                 if id < 8 {
                     info!("Switch to profile: {}", id);
                     // User0~7: Swtich to the specific profile
@@ -769,22 +1674,80 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                         .send(BleProfileAction::ClearProfile)
                         .await;
                 } else if id == 11 {
-                    // User11:
+                    // User11: Toggle between BLE and automatic output
                     BLE_PROFILE_CHANNEL
                         .send(BleProfileAction::ToggleConnection)
                         .await;
                 }
             }
+            if let Some(function) = user_key_function(self.behavior.user_keys.functions, key) {
+                self.process_user_key_function(function, key_event).await;
+            }
         } else if key.is_basic() {
+            #[cfg(feature = "calculator")]
+            if crate::calculator::calculator_active() && !key.is_modifier() {
+                self.process_calculator_key(key, key_event).await;
+                return;
+            }
+            self.record_macro_key(key, key_event);
+            if self.caps_word.is_active() {
+                self.update_caps_word(key, key_event);
+            }
             if key_event.pressed {
-                self.register_key(key, key_event);
+                self.release_opposing_keys(key, key_event);
+                if !self.register_key_override(key, key_event) {
+                    self.register_key(key, key_event);
+                }
             } else {
-                self.unregister_key(key, key_event);
+                self.unregister_key_and_restore_opposing(key, key_event);
             }
-            self.send_keyboard_report().await;
+        } else if key == KeyCode::CapsWordToggle || key == KeyCode::ShiftWordToggle {
+            if key_event.pressed {
+                if key == KeyCode::CapsWordToggle {
+                    self.caps_word.toggle();
+                } else {
+                    self.caps_word.toggle_shift_word();
+                }
+                self.report.set_weak_modifier(0);
+            }
+        } else if key.is_combo() {
+            if key_event.pressed {
+                self.combo_enabled = match key {
+                    KeyCode::ComboOn => true,
+                    KeyCode::ComboOff => false,
+                    _ => !self.combo_enabled,
+                };
+                info!("Combo enabled: {}", self.combo_enabled);
+            }
+        } else if matches!(
+            key,
+            KeyCode::MagicNkroOn | KeyCode::MagicNkroOff | KeyCode::MagicToggleNkro
+        ) {
+            if key_event.pressed {
+                let enabled = match key {
+                    KeyCode::MagicNkroOn => true,
+                    KeyCode::MagicNkroOff => false,
+                    _ => !nkro_enabled(),
+                };
+                if enabled != nkro_enabled() {
+                    // Keys held in one report shape are released before switching to the other
+                    self.release_all_keys().await;
+                    set_nkro(enabled);
+                }
+            }
+        } else if key.is_dynamic_macro() {
+            self.process_action_dynamic_macro(key, key_event).await;
         } else if key.is_macro() {
             // Process macro
             self.process_action_macro(key, key_event).await;
+        } else if key.is_rgb() {
+            if key_event.pressed {
+                crate::rgb::process_rgb_keycode(key);
+            }
+        } else if key.is_unicode_mode() {
+            if key_event.pressed {
+                process_unicode_mode_key(key);
+            }
         } else {
             warn!("Unsupported key: {:?}", key);
         }
@@ -795,142 +1758,174 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         // Change layer state only when the key's state is changed
         if key_event.pressed {
             self.keymap.borrow_mut().activate_layer(layer_num);
-        } else {
+        } else if layer_num >= 32 || self.locked_layers & (1 << layer_num) == 0 {
+            // Locked layers stay active after the key is released
             self.keymap.borrow_mut().deactivate_layer(layer_num);
         }
     }
 
+    /// Process layer tap toggle action, which works like QMK's `TT(n)`.
+    ///
+    /// The layer is inverted while the key is held. When the key is tapped `tapping_toggle` times in a row,
+    /// the last press doesn't invert the layer, so the layer stays inverted after the release.
+    fn process_action_layer_tap_toggle(&mut self, layer_num: u8, key_event: KeyEvent) {
+        let pos = (key_event.row, key_event.col);
+        let tapping_term = self.behavior.tap_dance.tapping_term;
+        let tapping_toggle = self.behavior.tap_dance.tapping_toggle.max(1);
+        if key_event.pressed {
+            // Taps of the same key before this press
+            let taps = match self.tap_toggle {
+                Some((p, taps, released)) if p == pos && released.elapsed() <= tapping_term => taps,
+                _ => 0,
+            };
+            self.tap_toggle = Some((pos, taps, Instant::now()));
+            if taps + 1 < tapping_toggle {
+                self.keymap.borrow_mut().toggle_layer(layer_num);
+            }
+        } else {
+            let taps = match self.tap_toggle {
+                Some((p, taps, _)) if p == pos => taps,
+                _ => 0,
+            };
+            // Keys which aren't in the matrix, such as encoders, don't have a timer
+            let tapped = self
+                .timer
+                .get(key_event.col as usize)
+                .and_then(|col| col.get(key_event.row as usize))
+                .copied()
+                .flatten()
+                .is_none_or(|t| t.elapsed() <= tapping_term);
+            let taps = if tapped { taps + 1 } else { 0 };
+            if taps >= tapping_toggle {
+                debug!("Layer {} is toggled by tapping", layer_num);
+            }
+            self.tap_toggle = Some((
+                pos,
+                if taps >= tapping_toggle { 0 } else { taps },
+                Instant::now(),
+            ));
+            self.keymap.borrow_mut().toggle_layer(layer_num);
+        }
+    }
+
     /// Process consumer control action. Consumer control keys are keys in hid consumer page, such as media keys.
     async fn process_action_consumer_control(&mut self, key: KeyCode, key_event: KeyEvent) {
         if key.is_consumer() {
-            self.other_report.media_usage_id = if key_event.pressed {
-                key.as_consumer_control_usage_id() as u16
-            } else {
-                0
-            };
-
-            self.send_media_report().await;
+            let usage_id = key.as_consumer_control_usage_id() as u16;
+            if key_event.pressed {
+                self.report.set_media_usage(usage_id);
+            } else if self.report.other.media_usage_id == usage_id {
+                // Only one usage is sent at a time, releasing an earlier key doesn't release the later one
+                self.report.set_media_usage(0);
+            }
         }
     }
 
     /// Process system control action. System control keys are keys in system page, such as power key.
     async fn process_action_system_control(&mut self, key: KeyCode, key_event: KeyEvent) {
         if key.is_system() {
-            if key_event.pressed {
-                if let Some(system_key) = key.as_system_control_usage_id() {
-                    self.other_report.system_usage_id = system_key as u8;
-                    self.send_system_control_report().await;
+            if let Some(system_key) = key.as_system_control_usage_id() {
+                if key_event.pressed {
+                    self.report.set_system_usage(system_key as u8);
+                } else if self.report.other.system_usage_id == system_key as u8 {
+                    self.report.set_system_usage(0);
                 }
-            } else {
-                self.other_report.system_usage_id = 0;
-                self.send_system_control_report().await;
             }
         }
     }
 
+    /// Process joystick button action, buttons are sent in gamepad reports along with the axes of the joystick
+    async fn process_action_gamepad(&mut self, key: KeyCode, key_event: KeyEvent) {
+        let bit = 1u32 << (key as u16 - KeyCode::JoystickButton0 as u16);
+        let mut buttons = GAMEPAD_BUTTONS.load(Ordering::Relaxed);
+        if key_event.pressed {
+            buttons |= bit;
+        } else {
+            buttons &= !bit;
+        }
+        GAMEPAD_BUTTONS.store(buttons, Ordering::Relaxed);
+        let report = CompositeReport {
+            gamepad_buttons: buttons,
+            gamepad_x: GAMEPAD_AXES[0].load(Ordering::Relaxed),
+            gamepad_y: GAMEPAD_AXES[1].load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        POINTER_REPORT_CHANNEL
+            .send(KeyboardReportMessage::CompositeReport(
+                report,
+                CompositeReportType::Gamepad,
+            ))
+            .await;
+    }
+
     /// Process mouse key action.
+    ///
+    /// Buttons are sent immediately, movement and wheel keys are repeated by [`crate::mouse_key::run_mouse_keys`].
     async fn process_action_mouse(&mut self, key: KeyCode, key_event: KeyEvent) {
-        if key.is_mouse_key() {
-            // Check whether the key is held, or it's released within the time interval
-            if let Some((pressed, last_tick)) = self.last_mouse_tick.get(&key) {
-                if !pressed && last_tick.elapsed().as_millis() <= 30 {
-                    // The key is just released, ignore the key event, ues a slightly longer time interval
-                    self.last_mouse_tick.remove(&key);
-                    return;
-                }
-            }
-            // Reference(qmk): https://github.com/qmk/qmk_firmware/blob/382c3bd0bd49fc0d53358f45477c48f5ae47f2ff/quantum/mousekey.c#L410
-            // https://github.com/qmk/qmk_firmware/blob/fb598e7e617692be0bf562afaf3c852c8db1c349/quantum/action.c#L332
+        if (KeyCode::MouseBtn1..=KeyCode::MouseBtn8).contains(&key) {
+            // The motion of mouse keys is recorded by `run_mouse_keys`, only buttons are recorded as keys
+            self.record_macro_key(key, key_event);
+            let button = 1 << (key as u16 - KeyCode::MouseBtn1 as u16);
             if key_event.pressed {
-                match key {
-                    // TODO: Add accerated mode when pressing the mouse key
-                    // https://github.com/qmk/qmk_firmware/blob/master/docs/feature_mouse_keys.md#accelerated-mode
-                    KeyCode::MouseUp => {
-                        self.other_report.y = -self.mouse_key_move_delta;
-                    }
-                    KeyCode::MouseDown => {
-                        self.other_report.y = self.mouse_key_move_delta;
-                    }
-                    KeyCode::MouseLeft => {
-                        self.other_report.x = -self.mouse_key_move_delta;
-                    }
-                    KeyCode::MouseRight => {
-                        self.other_report.x = self.mouse_key_move_delta;
-                    }
-                    KeyCode::MouseWheelUp => {
-                        self.other_report.wheel = self.mouse_wheel_move_delta;
-                    }
-                    KeyCode::MouseWheelDown => {
-                        self.other_report.wheel = -self.mouse_wheel_move_delta;
-                    }
-                    KeyCode::MouseBtn1 => self.other_report.buttons |= 0b1,
-                    KeyCode::MouseBtn2 => self.other_report.buttons |= 0b10,
-                    KeyCode::MouseBtn3 => self.other_report.buttons |= 0b100,
-                    KeyCode::MouseBtn4 => self.other_report.buttons |= 0b1000,
-                    KeyCode::MouseBtn5 => self.other_report.buttons |= 0b10000,
-                    KeyCode::MouseBtn6 => self.other_report.buttons |= 0b100000,
-                    KeyCode::MouseBtn7 => self.other_report.buttons |= 0b1000000,
-                    KeyCode::MouseBtn8 => self.other_report.buttons |= 0b10000000,
-                    KeyCode::MouseWheelLeft => {
-                        self.other_report.pan = -self.mouse_wheel_move_delta;
-                    }
-                    KeyCode::MouseWheelRight => {
-                        self.other_report.pan = self.mouse_wheel_move_delta;
-                    }
-                    KeyCode::MouseAccel0 => {}
-                    KeyCode::MouseAccel1 => {}
-                    KeyCode::MouseAccel2 => {}
-                    _ => {}
-                }
+                self.report.other.buttons |= button;
             } else {
-                match key {
-                    KeyCode::MouseUp | KeyCode::MouseDown => {
-                        self.other_report.y = 0;
-                    }
-                    KeyCode::MouseLeft | KeyCode::MouseRight => {
-                        self.other_report.x = 0;
-                    }
-                    KeyCode::MouseWheelUp | KeyCode::MouseWheelDown => {
-                        self.other_report.wheel = 0;
-                    }
-                    KeyCode::MouseWheelLeft | KeyCode::MouseWheelRight => {
-                        self.other_report.pan = 0;
-                    }
-                    KeyCode::MouseBtn1 => self.other_report.buttons &= 0b0,
-                    KeyCode::MouseBtn2 => self.other_report.buttons &= 0b01,
-                    KeyCode::MouseBtn3 => self.other_report.buttons &= 0b011,
-                    KeyCode::MouseBtn4 => self.other_report.buttons &= 0b0111,
-                    KeyCode::MouseBtn5 => self.other_report.buttons &= 0b01111,
-                    KeyCode::MouseBtn6 => self.other_report.buttons &= 0b011111,
-                    KeyCode::MouseBtn7 => self.other_report.buttons &= 0b0111111,
-                    KeyCode::MouseBtn8 => self.other_report.buttons &= 0b01111111,
-                    _ => {}
-                }
+                self.report.other.buttons &= !button;
             }
             self.send_mouse_report().await;
+        } else if key_event.pressed {
+            crate::mouse_key::press(key);
+        } else {
+            crate::mouse_key::release(key);
+        }
+    }
 
-            if let Err(_) = self
-                .last_mouse_tick
-                .insert(key, (key_event.pressed, Instant::now()))
-            {
-                error!("The buffer for last moust tick is full");
+    /// Type an ASCII character on the given host layout.
+    ///
+    /// The key is released in the report, but the release report isn't sent, the caller should send it.
+    async fn type_char(&mut self, c: u8, layout: HostLayout, key_event: KeyEvent) {
+        if let Some((k, modifier)) = layout.key_for_char(c) {
+            if modifier != 0 {
+                // Send Shift/AltGr first
+                self.register_modifier(modifier);
+                self.send_keyboard_report().await;
             }
+            self.report.register_host_keycode(k, modifier, key_event);
+            self.send_keyboard_report().await;
 
-            // Send the key event back to channel again, to keep processing the mouse key until release
-            if key_event.pressed {
-                // FIXME: The ideal approach is to spawn another task and send the event after 20ms.
-                // But it requires embassy-executor, which is not available for esp-idf-svc.
-                // So now we just block for 20ms for mouse keys.
-                // In the future, we're going to use esp-hal once it have good support for BLE
-                embassy_time::Timer::after_millis(20).await;
-                KEY_EVENT_CHANNEL.try_send(key_event).ok();
+            self.unregister_keycode(k, key_event);
+            if modifier != 0 {
+                self.send_keyboard_report().await;
+                self.unregister_modifier(modifier);
             }
+        } else {
+            warn!("Character {} can't be typed on {:?} layout", c, layout);
+        }
+    }
+
+    /// Process a key pressed while the calculator is active, the result is typed on the host layout
+    #[cfg(feature = "calculator")]
+    async fn process_calculator_key(&mut self, key: KeyCode, key_event: KeyEvent) {
+        if !key_event.pressed {
+            return;
+        }
+        let shifted = self.report.is_shifted();
+        if let Some(result) = crate::calculator::process_calculator_key(key, shifted) {
+            // Release held shift, or the result is typed with shift
+            let modifier = self.report.modifier;
+            self.unregister_modifier(modifier);
+            for c in result.bytes() {
+                self.type_char(c, host_layout(), key_event).await;
+                self.send_keyboard_report().await;
+            }
+            // Press the held modifiers again
+            self.register_modifier(modifier);
+            self.send_keyboard_report().await;
         }
     }
 
     async fn process_action_macro(&mut self, key: KeyCode, key_event: KeyEvent) {
         // Execute the macro only when releasing the key
-        if !!key_event.pressed {
+        if key_event.pressed {
             return;
         }
 
@@ -940,65 +1935,307 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                 error!("Macro idx invalid: {}", macro_idx);
                 return;
             }
-            // Read macro operations untill the end of the macro
-            let macro_idx = self.keymap.borrow().get_macro_start(macro_idx);
-            if let Some(macro_start_idx) = macro_idx {
-                let mut offset = 0;
-                loop {
-                    // First, get the next macro operation
-                    let (operation, new_offset) = self
-                        .keymap
-                        .borrow()
-                        .get_next_macro_operation(macro_start_idx, offset);
-                    // Execute the operation
-                    match operation {
-                        MacroOperation::Press(k) => {
-                            self.register_key(k, key_event);
-                        }
-                        MacroOperation::Release(k) => {
-                            self.unregister_key(k, key_event);
-                        }
-                        MacroOperation::Tap(k) => {
-                            self.register_key(k, key_event);
-                            self.send_keyboard_report().await;
-                            embassy_time::Timer::after_millis(2).await;
-                            self.unregister_key(k, key_event);
-                        }
-                        MacroOperation::Text(k, is_cap) => {
-                            if is_cap {
-                                // If it's a capital letter, send shift first
-                                self.register_modifier(KeyCode::LShift.as_modifier_bit());
-                                self.send_keyboard_report().await;
-                            }
-                            self.register_keycode(k, key_event);
-                            self.send_keyboard_report().await;
+            // Text of the macro is typed on the host layout of the macro
+            let layout = macro_layout(macro_idx);
+            let macro_start_idx = self.keymap.borrow().get_macro_start(macro_idx);
+            if let Some(macro_start_idx) = macro_start_idx {
+                // Macros might be updated by Vial while playing, so play a copy
+                let macros = self.keymap.borrow().macro_cache;
+                self.play_macro(&macros, macro_start_idx, layout, key_event)
+                    .await;
+            } else {
+                error!("Macro not found");
+            }
+        }
+    }
 
-                            self.unregister_keycode(k, key_event);
-                            if is_cap {
-                                self.send_keyboard_report().await;
-                                self.unregister_modifier(KeyCode::LShift.as_modifier_bit());
-                            }
-                        }
-                        MacroOperation::Delay(t) => {
-                            embassy_time::Timer::after_millis(t as u64).await;
-                        }
-                        MacroOperation::End => {
-                            self.send_keyboard_report().await;
-                            break;
-                        }
-                    };
+    /// Play the macro in the Vial format which starts at `start` of `data`
+    async fn play_macro(
+        &mut self,
+        data: &[u8],
+        start: usize,
+        layout: HostLayout,
+        key_event: KeyEvent,
+    ) {
+        let mut offset = start;
+        loop {
+            // First, get the next macro operation
+            let (operation, new_offset) = parse_macro_operation(data, offset);
+            if operation == MacroOperation::End {
+                self.send_keyboard_report().await;
+                break;
+            }
+            self.run_macro_operation(operation, layout, key_event).await;
 
-                    // Send the item in the macro sequence
-                    self.send_keyboard_report().await;
+            offset = new_offset;
+            if offset >= data.len() {
+                break;
+            }
+        }
+    }
 
-                    offset = new_offset;
-                    if offset > self.keymap.borrow().macro_cache.len() {
-                        break;
+    /// Execute an operation of a macro, then send the report
+    async fn run_macro_operation(
+        &mut self,
+        operation: MacroOperation,
+        layout: HostLayout,
+        key_event: KeyEvent,
+    ) {
+        match operation {
+            MacroOperation::Press(k) | MacroOperation::Release(k) if k.is_mouse_key() => {
+                let pressed = matches!(operation, MacroOperation::Press(_));
+                self.process_action_mouse(
+                    k,
+                    KeyEvent {
+                        pressed,
+                        ..key_event
+                    },
+                )
+                .await;
+            }
+            MacroOperation::Press(k) => {
+                self.register_key(k, key_event);
+            }
+            MacroOperation::Release(k) => {
+                self.unregister_key(k, key_event);
+            }
+            MacroOperation::Tap(k) => {
+                self.register_key(k, key_event);
+                self.send_keyboard_report().await;
+                embassy_time::Timer::after_millis(2).await;
+                self.unregister_key(k, key_event);
+            }
+            MacroOperation::Text(c) => self.type_char(c, layout, key_event).await,
+            MacroOperation::Delay(t) => {
+                embassy_time::Timer::after_millis(t as u64).await;
+            }
+            MacroOperation::Encoder { id, clockwise } => {
+                self.tap_encoder_in_macro(id, clockwise).await
+            }
+            MacroOperation::Pointer(x, y) => {
+                let report = CompositeReport {
+                    buttons: self.report.other.buttons,
+                    x,
+                    y,
+                    ..Default::default()
+                };
+                POINTER_REPORT_CHANNEL
+                    .send(KeyboardReportMessage::CompositeReport(
+                        report,
+                        CompositeReportType::Mouse,
+                    ))
+                    .await;
+            }
+            MacroOperation::End => (),
+        };
+
+        // Send the item in the macro sequence
+        self.send_keyboard_report().await;
+    }
+
+    /// Type a character by the Unicode input method of the host, see [`crate::unicode`]
+    async fn process_key_action_unicode(&mut self, c: char, key_event: KeyEvent) {
+        if !key_event.pressed {
+            return;
+        }
+        let mode = unicode_mode();
+        let Some(operations) = unicode_operations(c, mode) else {
+            warn!("Character {} can't be typed in Unicode mode {:?}", c, mode);
+            return;
+        };
+        // Release held modifiers, or they're combined with the input sequence
+        let modifier = self.report.modifier;
+        self.unregister_modifier(modifier);
+        for operation in operations {
+            self.run_macro_operation(operation, host_layout(), key_event)
+                .await;
+        }
+        // Press the held modifiers again
+        self.register_modifier(modifier);
+        self.send_keyboard_report().await;
+    }
+
+    /// Tap the action of an encoder step in a macro.
+    ///
+    /// Macros can't be nested, so only keys, media keys and mouse keys are supported.
+    async fn tap_encoder_in_macro(&mut self, id: u8, clockwise: bool) {
+        let direction = if clockwise {
+            Direction::Clockwise
+        } else {
+            Direction::CounterClockwise
+        };
+        for pressed in [true, false] {
+            let action = self
+                .keymap
+                .borrow_mut()
+                .encoder_action_with_layer_cache(id, &direction, pressed);
+            let key_event = KeyEvent {
+                row: ENCODER_ROW,
+                col: id,
+                pressed,
+            };
+            match action {
+                KeyAction::Single(Action::Key(k)) | KeyAction::Tap(Action::Key(k)) => {
+                    if k.is_consumer() {
+                        self.process_action_consumer_control(k, key_event).await;
+                    } else if k.is_mouse_key() {
+                        self.process_action_mouse(k, key_event).await;
+                    } else if pressed {
+                        self.register_key(k, key_event);
+                    } else {
+                        self.unregister_key(k, key_event);
                     }
                 }
-            } else {
-                error!("Macro not found");
+                KeyAction::No => (),
+                _ => {
+                    if pressed {
+                        warn!("Encoder action {:?} isn't supported in macros", action);
+                    }
+                }
+            }
+            self.send_keyboard_report().await;
+            if pressed {
+                Timer::after_millis(10).await;
+            }
+        }
+    }
+
+    /// Record, stop recording or play dynamic macros
+    async fn process_action_dynamic_macro(&mut self, key: KeyCode, key_event: KeyEvent) {
+        if !key_event.pressed {
+            return;
+        }
+        match key {
+            KeyCode::DynamicMacroRecordStart1 | KeyCode::DynamicMacroRecordStart2 => {
+                let slot = (key as u16 - KeyCode::DynamicMacroRecordStart1 as u16) as u8;
+                // Pressing a record key while recording stops the recording, like QMK
+                if self.macro_recorder.is_some() {
+                    self.stop_macro_recording();
+                } else {
+                    info!("Start recording dynamic macro {}", slot);
+                    self.macro_recorder = Some(MacroRecorder::new(slot));
+                }
+            }
+            KeyCode::DynamicMacroRecordStop => self.stop_macro_recording(),
+            KeyCode::DynamicMacroPlay1 | KeyCode::DynamicMacroPlay2 => {
+                if self.macro_recorder.is_some() {
+                    warn!("Dynamic macros can't be played while recording");
+                    return;
+                }
+                let slot = (key as u16 - KeyCode::DynamicMacroPlay1 as u16) as u8;
+                let macros = dynamic_macro(slot);
+                self.play_macro(&macros, 0, host_layout(), key_event).await;
+            }
+            _ => (),
+        }
+    }
+
+    /// Record a key to the dynamic macro which is being recorded
+    fn record_macro_key(&mut self, key: KeyCode, key_event: KeyEvent) {
+        // Keys triggered by encoders are recorded as encoder steps
+        if key_event.row == ENCODER_ROW {
+            return;
+        }
+        if let Some(recorder) = self.macro_recorder.as_mut() {
+            if !recorder.record(key, key_event.pressed) {
+                warn!("Dynamic macro {} is full", recorder.slot());
+                self.stop_macro_recording();
+            }
+        }
+    }
+
+    fn stop_macro_recording(&mut self) {
+        if let Some(recorder) = self.macro_recorder.take() {
+            info!("Stop recording dynamic macro {}", recorder.slot());
+            let (slot, data) = recorder.finish();
+            save_dynamic_macro(slot, data);
+        }
+    }
+
+    /// Apply shift to keys in a word, end Caps Word or Shift Word when a key out of the word is pressed
+    fn update_caps_word(&mut self, key: KeyCode, key_event: KeyEvent) {
+        if key.is_modifier() {
+            return;
+        }
+        let shift = KeyCode::LShift.as_modifier_bit();
+        if !key_event.pressed {
+            if self.caps_word.should_shift(key) {
+                self.report.set_weak_modifier(0);
+            }
+        } else if self.caps_word.ends_word(key) {
+            self.caps_word.deactivate();
+            self.report.set_weak_modifier(0);
+        } else if self.caps_word.should_shift(key) {
+            self.report.set_weak_modifier(shift);
+        } else {
+            self.report.set_weak_modifier(0);
+        }
+    }
+
+    /// Release keys which are held and opposing to the pressed key, see [`crate::socd`]
+    fn release_opposing_keys(&mut self, key: KeyCode, key_event: KeyEvent) {
+        let pairs = self.behavior.socd.pairs;
+        if pairs.is_empty() {
+            return;
+        }
+        for (k, (row, col)) in self.socd.press(pairs, key, (key_event.row, key_event.col)) {
+            debug!("SOCD: {:?} is released by {:?}", k, key);
+            let release = KeyEvent {
+                row,
+                col,
+                pressed: false,
+            };
+            self.unregister_keycode(k, release);
+        }
+    }
+
+    /// Unregister a released key, then press its opposing keys again if they're still held, see [`crate::socd`]
+    fn unregister_key_and_restore_opposing(&mut self, key: KeyCode, key_event: KeyEvent) {
+        let pairs = self.behavior.socd.pairs;
+        if pairs.is_empty() {
+            self.unregister_key(key, key_event);
+            return;
+        }
+        let (suppressed, restored) = self.socd.release(
+            pairs,
+            (key_event.row, key_event.col),
+            self.behavior.socd.restore,
+        );
+        // The key is already released by an opposing key
+        if !suppressed {
+            self.unregister_key(key, key_event);
+        }
+        for (k, (row, col)) in restored {
+            let press = KeyEvent {
+                row,
+                col,
+                pressed: true,
+            };
+            self.register_keycode(k, press);
+        }
+    }
+
+    /// Register the replacement key if the pressed key is overridden with held modifiers, see [`crate::key_override`].
+    ///
+    /// Returns whether the key is overridden
+    fn register_key_override(&mut self, key: KeyCode, key_event: KeyEvent) -> bool {
+        let overrides = self.behavior.key_override.overrides;
+        if overrides.is_empty() || key.is_modifier() {
+            return false;
+        }
+        let layer = self.keymap.borrow().get_activated_layer();
+        match find_override(overrides, key, self.report.modifier, layer) {
+            Some((replacement, _, _)) if !replacement.is_basic() || replacement.is_modifier() => {
+                warn!("Unsupported replacement of key override: {:?}", replacement);
+                false
             }
+            Some((replacement, suppressed, added)) => {
+                debug!("Key override: {:?} is sent as {:?}", key, replacement);
+                self.report
+                    .register_override_keycode(replacement, suppressed, added, key_event);
+                true
+            }
+            None => false,
         }
     }
 
@@ -1022,61 +2259,21 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
 
     /// Register a key to be sent in hid report.
     fn register_keycode(&mut self, key: KeyCode, key_event: KeyEvent) {
-        // First, find the key event slot according to the position
-        let slot = self.registered_keys.iter().enumerate().find_map(|(i, k)| {
-            if let Some((row, col)) = k {
-                if key_event.row == *row && key_event.col == *col {
-                    return Some(i);
-                }
-            }
-            return None;
-        });
-
-        // If the slot is found, update the key in the slot
-        if let Some(index) = slot {
-            self.report.keycodes[index] = key as u8;
-            self.registered_keys[index] = Some((key_event.row, key_event.col));
-        } else {
-            // Otherwise, find the first free slot
-            if let Some(index) = self.report.keycodes.iter().position(|&k| k == 0) {
-                self.report.keycodes[index] = key as u8;
-                self.registered_keys[index] = Some((key_event.row, key_event.col));
-            }
-        }
+        self.report.register_keycode(key, key_event);
     }
 
     /// Unregister a key from hid report.
     fn unregister_keycode(&mut self, key: KeyCode, key_event: KeyEvent) {
-        // First, find the key event slot according to the position
-        let slot = self.registered_keys.iter().enumerate().find_map(|(i, k)| {
-            if let Some((row, col)) = k {
-                if key_event.row == *row && key_event.col == *col {
-                    return Some(i);
-                }
-            }
-            return None;
-        });
-
-        // If the slot is found, update the key in the slot
-        if let Some(index) = slot {
-            self.report.keycodes[index] = 0;
-            self.registered_keys[index] = None;
-        } else {
-            // Otherwise, release the first same key
-            if let Some(index) = self.report.keycodes.iter().position(|&k| k == key as u8) {
-                self.report.keycodes[index] = 0;
-                self.registered_keys[index] = None;
-            }
-        }
+        self.report.unregister_keycode(key, key_event);
     }
 
     /// Register a modifier to be sent in hid report.
     fn register_modifier(&mut self, modifier_bit: u8) {
-        self.report.modifier |= modifier_bit;
+        self.report.register_modifier(modifier_bit);
     }
 
     /// Unregister a modifier from hid report.
     fn unregister_modifier(&mut self, modifier_bit: u8) {
-        self.report.modifier &= !modifier_bit;
+        self.report.unregister_modifier(modifier_bit);
     }
 }
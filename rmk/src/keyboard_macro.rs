@@ -1,4 +1,23 @@
+//! Keyboard macros
+//!
+//! Macros are sequences of [`MacroOperation`]s played by `Macro0` ~ `Macro31`, e.g. `k!(Macro0)`.
+//! They're kept in the Vial format, so they can be edited in Vial and are saved to the storage.
+//! Default macros are set in `macros` of [`RmkConfig`](crate::config::RmkConfig), they're used until macros are saved by Vial.
+//!
+//! Besides, two dynamic macros can be recorded at runtime, like QMK's dynamic macros:
+//! `DynamicMacroRecordStart1/2` starts recording key presses and releases, `DynamicMacroRecordStop` stops it,
+//! and `DynamicMacroPlay1/2` plays the recorded keys. Encoder steps, pointer motion and mouse buttons are recorded as well,
+//! motion between two other operations is recorded as a single move. Recorded macros are saved to the storage.
+
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::Vec;
+use num_enum::FromPrimitive;
+
+use crate::generation::{bump_generation, ConfigItem};
 use crate::keycode::KeyCode;
+use crate::storage::{FlashOperationMessage, FLASH_CHANNEL};
 
 // Default macro space size
 pub(crate) const MACRO_SPACE_SIZE: usize = 256;
@@ -6,11 +25,440 @@ pub(crate) const MACRO_SPACE_SIZE: usize = 256;
 // Default number of keyboard macros
 pub(crate) const NUM_MACRO: usize = 8;
 
-pub(crate) enum MacroOperation {
+/// Number of dynamic macros
+pub const NUM_DYNAMIC_MACRO: usize = 2;
+
+/// Bytes of a dynamic macro, a key press or release takes 3 bytes
+pub(crate) const DYNAMIC_MACRO_SIZE: usize = crate::ram_profile::DYNAMIC_MACRO_SIZE;
+
+/// Max number of keys held at the same time while recording a dynamic macro
+const MAX_RECORDED_HELD_KEYS: usize = 16;
+
+/// Recorded dynamic macros in the Vial format
+static DYNAMIC_MACROS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[[u8; DYNAMIC_MACRO_SIZE]; NUM_DYNAMIC_MACRO]>,
+> = Mutex::new(RefCell::new([[0; DYNAMIC_MACRO_SIZE]; NUM_DYNAMIC_MACRO]));
+
+/// Pointer motion accumulated since the last recorded operation, `None` if no dynamic macro is being recorded
+static RECORDED_MOTION: Mutex<CriticalSectionRawMutex, Cell<Option<(i32, i32)>>> =
+    Mutex::new(Cell::new(None));
+
+/// A step of a macro
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MacroOperation {
     Press(KeyCode),
     Release(KeyCode),
     Tap(KeyCode),
-    Text(KeyCode, bool),
+    /// An ASCII character, typed on the host layout of the macro
+    Text(u8),
+    /// Delay in ms
     Delay(u16),
+    /// Tap the action of an encoder step, it's an RMK extension which can't be edited in Vial
+    Encoder {
+        id: u8,
+        clockwise: bool,
+    },
+    /// Relative pointer motion, it's an RMK extension which can't be edited in Vial
+    Pointer(i8, i8),
     End,
 }
+
+impl MacroOperation {
+    /// Encode the operation in the Vial format, returns the number of bytes.
+    ///
+    /// Only basic keycodes and printable ASCII characters can be encoded. Like delays, bytes of RMK extensions are never 0.
+    fn encode(self, buf: &mut [u8; 4]) -> Option<usize> {
+        let keycode = |k: KeyCode| (k as u16 <= 0xFF).then_some(k as u16 as u8);
+        match self {
+            MacroOperation::Tap(k) => {
+                *buf = [1, 1, keycode(k)?, 0];
+                Some(3)
+            }
+            MacroOperation::Press(k) => {
+                *buf = [1, 2, keycode(k)?, 0];
+                Some(3)
+            }
+            MacroOperation::Release(k) => {
+                *buf = [1, 3, keycode(k)?, 0];
+                Some(3)
+            }
+            MacroOperation::Delay(ms) => {
+                // Both bytes are offset by 1, so that they're never 0
+                let ms = ms.min(255 * 255 - 1);
+                *buf = [1, 4, (ms % 255 + 1) as u8, (ms / 255 + 1) as u8];
+                Some(4)
+            }
+            MacroOperation::Encoder { id, clockwise } if id < u8::MAX => {
+                *buf = [1, 0x10, id + 1, if clockwise { 1 } else { 2 }];
+                Some(4)
+            }
+            MacroOperation::Pointer(x, y) if x != i8::MIN && y != i8::MIN => {
+                *buf = [1, 0x11, (x as i16 + 128) as u8, (y as i16 + 128) as u8];
+                Some(4)
+            }
+            MacroOperation::Text(c) if c.is_ascii() && c > 1 => {
+                buf[0] = c;
+                Some(1)
+            }
+            MacroOperation::Text(_)
+            | MacroOperation::Encoder { .. }
+            | MacroOperation::Pointer(..)
+            | MacroOperation::End => None,
+        }
+    }
+}
+
+/// Encode macros in the Vial format to `buf`, every macro ends with 0. Returns the number of used bytes.
+///
+/// Operations which can't be encoded are skipped, macros which don't fit in `buf` are truncated.
+pub(crate) fn serialize_macros(macros: &[&[MacroOperation]], buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    for (i, operations) in macros.iter().enumerate() {
+        for operation in operations.iter() {
+            if *operation == MacroOperation::End {
+                break;
+            }
+            let mut encoded = [0; 4];
+            let Some(n) = operation.encode(&mut encoded) else {
+                warn!("Macro operation {:?} isn't supported", operation);
+                continue;
+            };
+            // Keep a byte for the end of the macro
+            if len + n + 1 > buf.len() {
+                warn!("Macro space is full, macro {} is truncated", i);
+                buf[len] = 0;
+                return len + 1;
+            }
+            buf[len..len + n].copy_from_slice(&encoded[..n]);
+            len += n;
+        }
+        if len >= buf.len() {
+            break;
+        }
+        buf[len] = 0;
+        len += 1;
+    }
+    len
+}
+
+/// Parse the operation at `idx` of macros in the Vial format, returns the operation and the index of the next operation
+pub(crate) fn parse_macro_operation(data: &[u8], idx: usize) -> (MacroOperation, usize) {
+    if idx + 1 >= data.len() {
+        return (MacroOperation::End, idx);
+    }
+    let keycode = |i: usize| KeyCode::from_primitive(data[i] as u16);
+    match (data[idx], data[idx + 1]) {
+        (0, _) => (MacroOperation::End, idx),
+        (1, 1) => {
+            // SS_QMK_PREFIX + SS_TAP_CODE
+            if idx + 2 < data.len() {
+                (MacroOperation::Tap(keycode(idx + 2)), idx + 3)
+            } else {
+                (MacroOperation::End, idx + 3)
+            }
+        }
+        (1, 2) => {
+            // SS_QMK_PREFIX + SS_DOWN_CODE
+            if idx + 2 < data.len() {
+                (MacroOperation::Press(keycode(idx + 2)), idx + 3)
+            } else {
+                (MacroOperation::End, idx + 3)
+            }
+        }
+        (1, 3) => {
+            // SS_QMK_PREFIX + SS_UP_CODE
+            if idx + 2 < data.len() {
+                (MacroOperation::Release(keycode(idx + 2)), idx + 3)
+            } else {
+                (MacroOperation::End, idx + 3)
+            }
+        }
+        (1, 4) => {
+            // SS_QMK_PREFIX + SS_DELAY_CODE
+            if idx + 3 < data.len() {
+                let delay_ms = (data[idx + 2] as u16 - 1) + (data[idx + 3] as u16 - 1) * 255;
+                (MacroOperation::Delay(delay_ms), idx + 4)
+            } else {
+                (MacroOperation::End, idx + 4)
+            }
+        }
+        (1, 0x10) => {
+            // RMK extension: encoder step
+            if idx + 3 < data.len() {
+                let operation = MacroOperation::Encoder {
+                    id: data[idx + 2].wrapping_sub(1),
+                    clockwise: data[idx + 3] == 1,
+                };
+                (operation, idx + 4)
+            } else {
+                (MacroOperation::End, idx + 4)
+            }
+        }
+        (1, 0x11) => {
+            // RMK extension: pointer motion
+            if idx + 3 < data.len() {
+                let axis = |i: usize| (data[i] as i16 - 128) as i8;
+                (
+                    MacroOperation::Pointer(axis(idx + 2), axis(idx + 3)),
+                    idx + 4,
+                )
+            } else {
+                (MacroOperation::End, idx + 4)
+            }
+        }
+        (1, 5) | (1, 6) | (1, 7) => {
+            warn!("VIAL_MACRO_EXT is not supported");
+            (MacroOperation::Delay(0), idx + 4)
+        }
+        _ => {
+            // Current byte is the ascii code, it's converted to keycode on the host layout when typing
+            (MacroOperation::Text(data[idx]), idx + 1)
+        }
+    }
+}
+
+/// Add pointer motion to the dynamic macro being recorded, it's ignored if no macro is being recorded
+pub(crate) fn record_pointer_motion(x: i16, y: i16) {
+    RECORDED_MOTION.lock(|m| {
+        if let Some((mx, my)) = m.get() {
+            m.set(Some((mx + x as i32, my + y as i32)));
+        }
+    });
+}
+
+/// Records key presses and releases, encoder steps and pointer motion to a dynamic macro
+pub(crate) struct MacroRecorder {
+    slot: u8,
+    data: Vec<u8, DYNAMIC_MACRO_SIZE>,
+    /// Keys pressed during the recording which aren't released yet
+    held: Vec<u8, MAX_RECORDED_HELD_KEYS>,
+}
+
+impl MacroRecorder {
+    pub(crate) fn new(slot: u8) -> Self {
+        RECORDED_MOTION.lock(|m| m.set(Some((0, 0))));
+        Self {
+            slot,
+            data: Vec::new(),
+            held: Vec::new(),
+        }
+    }
+
+    /// Append an operation, returns `false` if it doesn't fit.
+    ///
+    /// Space for releasing all held keys, including `new_held` keys held by the operation, is always kept,
+    /// so that the recorded macro doesn't leave keys pressed.
+    fn push(&mut self, operation: MacroOperation, new_held: usize) -> bool {
+        let mut encoded = [0; 4];
+        let Some(n) = operation.encode(&mut encoded) else {
+            return true;
+        };
+        let reserved = (self.held.len() + new_held) * 3 + 1;
+        if self.data.len() + n + reserved > DYNAMIC_MACRO_SIZE {
+            return false;
+        }
+        self.data.extend_from_slice(&encoded[..n]).ok();
+        true
+    }
+
+    /// Append the pointer motion accumulated since the last operation, returns `false` if the macro is full
+    fn record_motion(&mut self) -> bool {
+        let (mut x, mut y) = RECORDED_MOTION
+            .lock(|m| m.replace(Some((0, 0))))
+            .unwrap_or_default();
+        while x != 0 || y != 0 {
+            let dx = x.clamp(-(i8::MAX as i32), i8::MAX as i32);
+            let dy = y.clamp(-(i8::MAX as i32), i8::MAX as i32);
+            if !self.push(MacroOperation::Pointer(dx as i8, dy as i8), 0) {
+                return false;
+            }
+            x -= dx;
+            y -= dy;
+        }
+        true
+    }
+
+    /// Record a key press or release, returns `false` if the macro is full
+    pub(crate) fn record(&mut self, key: KeyCode, pressed: bool) -> bool {
+        if key as u16 > 0xFF {
+            return true;
+        }
+        if !self.record_motion() {
+            return false;
+        }
+        let k = key as u16 as u8;
+        if pressed {
+            if self.held.contains(&k) {
+                return true;
+            }
+            if self.held.is_full() || !self.push(MacroOperation::Press(key), 1) {
+                return false;
+            }
+            self.held.push(k).ok();
+        } else {
+            // Releases of keys pressed before the recording are ignored
+            let Some(i) = self.held.iter().position(|&h| h == k) else {
+                return true;
+            };
+            self.held.swap_remove(i);
+            self.push(MacroOperation::Release(key), 0);
+        }
+        true
+    }
+
+    /// Record a step of an encoder, returns `false` if the macro is full
+    pub(crate) fn record_encoder(&mut self, id: u8, clockwise: bool) -> bool {
+        self.record_motion() && self.push(MacroOperation::Encoder { id, clockwise }, 0)
+    }
+
+    /// Finish the recording, keys which are still held are released at the end of the macro
+    pub(crate) fn finish(mut self) -> (u8, [u8; DYNAMIC_MACRO_SIZE]) {
+        // Motion after the last operation is kept if it fits
+        self.record_motion();
+        RECORDED_MOTION.lock(|m| m.set(None));
+        let mut data = self.data;
+        for &k in self.held.iter() {
+            data.extend_from_slice(&[1, 3, k]).ok();
+        }
+        let mut buf = [0; DYNAMIC_MACRO_SIZE];
+        buf[..data.len()].copy_from_slice(&data);
+        (self.slot, buf)
+    }
+
+    pub(crate) fn slot(&self) -> u8 {
+        self.slot
+    }
+}
+
+/// Get a recorded dynamic macro
+pub(crate) fn dynamic_macro(slot: u8) -> [u8; DYNAMIC_MACRO_SIZE] {
+    DYNAMIC_MACROS.lock(|m| {
+        m.borrow()
+            .get(slot as usize)
+            .copied()
+            .unwrap_or([0; DYNAMIC_MACRO_SIZE])
+    })
+}
+
+/// Set a recorded dynamic macro and save it to the storage
+pub(crate) fn save_dynamic_macro(slot: u8, data: [u8; DYNAMIC_MACRO_SIZE]) {
+    restore_dynamic_macro(slot, data);
+    bump_generation(ConfigItem::Macros);
+    if FLASH_CHANNEL
+        .try_send(FlashOperationMessage::DynamicMacro(slot, data))
+        .is_err()
+    {
+        warn!("Failed to save dynamic macro, storage channel is full");
+    }
+}
+
+/// Restore a dynamic macro saved in the storage
+pub(crate) fn restore_dynamic_macro(slot: u8, data: [u8; DYNAMIC_MACRO_SIZE]) {
+    DYNAMIC_MACROS.lock(|m| {
+        if let Some(m) = m.borrow_mut().get_mut(slot as usize) {
+            *m = data;
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize_macros() {
+        let macros: &[&[MacroOperation]] = &[
+            &[
+                MacroOperation::Press(KeyCode::LShift),
+                MacroOperation::Tap(KeyCode::A),
+                MacroOperation::Release(KeyCode::LShift),
+                MacroOperation::Delay(300),
+                MacroOperation::Text(b'b'),
+            ],
+            &[MacroOperation::Text(b'c')],
+        ];
+        let mut buf = [0; 32];
+        let len = serialize_macros(macros, &mut buf);
+        assert_eq!(len, 17);
+        let mut operations = [MacroOperation::End; 6];
+        let mut idx = 0;
+        for op in operations.iter_mut() {
+            (*op, idx) = parse_macro_operation(&buf, idx);
+        }
+        assert_eq!(
+            operations,
+            [
+                MacroOperation::Press(KeyCode::LShift),
+                MacroOperation::Tap(KeyCode::A),
+                MacroOperation::Release(KeyCode::LShift),
+                MacroOperation::Delay(300),
+                MacroOperation::Text(b'b'),
+                MacroOperation::End,
+            ]
+        );
+        // The second macro starts after the end of the first one
+        assert_eq!(
+            parse_macro_operation(&buf, idx + 1).0,
+            MacroOperation::Text(b'c')
+        );
+    }
+
+    #[test]
+    fn test_macro_recorder() {
+        let mut recorder = MacroRecorder::new(1);
+        // Released before the recording
+        assert!(recorder.record(KeyCode::B, false));
+        assert!(recorder.record(KeyCode::LShift, true));
+        assert!(recorder.record(KeyCode::A, true));
+        assert!(recorder.record(KeyCode::A, false));
+        let (slot, data) = recorder.finish();
+        assert_eq!(slot, 1);
+        assert_eq!(
+            data[..13],
+            [1, 2, 0xE1, 1, 2, 0x04, 1, 3, 0x04, 1, 3, 0xE1, 0]
+        );
+
+        // The recording stops before releases of held keys don't fit
+        let mut recorder = MacroRecorder::new(0);
+        let mut presses = 0;
+        while recorder.record(KeyCode::A, true) && recorder.record(KeyCode::A, false) {
+            presses += 1;
+        }
+        assert!(presses * 6 + 7 > DYNAMIC_MACRO_SIZE);
+        let (_, data) = recorder.finish();
+        assert_eq!(data[DYNAMIC_MACRO_SIZE - 1], 0);
+
+        // Motion is recorded before the next operation, split into steps of a mouse report
+        let mut recorder = MacroRecorder::new(0);
+        record_pointer_motion(100, -20);
+        record_pointer_motion(100, 0);
+        assert!(recorder.record(KeyCode::MouseBtn1, true));
+        assert!(recorder.record_encoder(2, false));
+        assert!(recorder.record(KeyCode::MouseBtn1, false));
+        let (_, data) = recorder.finish();
+        let mut operations = [MacroOperation::End; 6];
+        let mut idx = 0;
+        for op in operations.iter_mut() {
+            (*op, idx) = parse_macro_operation(&data, idx);
+        }
+        assert_eq!(
+            operations,
+            [
+                MacroOperation::Pointer(127, -20),
+                MacroOperation::Pointer(73, 0),
+                MacroOperation::Press(KeyCode::MouseBtn1),
+                MacroOperation::Encoder {
+                    id: 2,
+                    clockwise: false
+                },
+                MacroOperation::Release(KeyCode::MouseBtn1),
+                MacroOperation::End,
+            ]
+        );
+        // Motion isn't recorded after the recording
+        record_pointer_motion(10, 10);
+        assert_eq!(RECORDED_MOTION.lock(|m| m.get()), None);
+    }
+}
@@ -135,6 +135,7 @@ pub enum ConsumerKey {
     VolumeDecrement = 0xEA,
     Reserved = 0xEB,
     // 15.15 Application Launch Buttons
+    ConsumerControlConfig = 0x183,
     Email = 0x18A,
     Calculator = 0x192,
     LocalBrowser = 0x194,
@@ -167,6 +168,7 @@ pub enum ConsumerKey {
     NextKeyboardLayoutSelect = 0x29D,
     DesktopShowAllWindows = 0x29F,
     AcSoftKeyLeft = 0x2A0,
+    DesktopShowAllApplications = 0x2A2,
 }
 
 /// Keys in `Generic Desktop Page`, generally used for system control
@@ -835,6 +837,8 @@ pub enum KeyCode {
     TriLayerUpper = 0x778,
     RepeatKey = 0x779,
     AltRepeatKey = 0x77A,
+    /// Shift the next word, until a word boundary like space, see also `CapsWordToggle`
+    ShiftWordToggle = 0x77B,
     // Kb keycodes, use 0x800 ~ 0x81F
     Kb0 = 0x800,
     Kb1 = 0x801,
@@ -944,6 +948,11 @@ impl KeyCode {
         KeyCode::MagicSwapControlCapsLock <= self && self <= KeyCode::MagicToggleEscapeCapsLock
     }
 
+    /// Returns `true` if the keycode is a combo keycode
+    pub(crate) fn is_combo(self) -> bool {
+        KeyCode::ComboOn <= self && self <= KeyCode::ComboToggle
+    }
+
     /// Returns `true` if the keycode is a midi keycode
     pub(crate) fn is_midi(self) -> bool {
         KeyCode::MidiOn <= self && self <= KeyCode::MidiPitchBendUp
@@ -980,6 +989,16 @@ impl KeyCode {
         KeyCode::Macro0 <= self && self <= KeyCode::Macro31
     }
 
+    /// Returns `true` if the keycode selects a Unicode input mode
+    pub(crate) fn is_unicode_mode(self) -> bool {
+        KeyCode::UnicodeModeNext <= self && self <= KeyCode::UnicodeModeEmacs
+    }
+
+    /// Returns `true` if the keycode is a dynamic macro keycode
+    pub(crate) fn is_dynamic_macro(self) -> bool {
+        KeyCode::DynamicMacroRecordStart1 <= self && self <= KeyCode::DynamicMacroPlay2
+    }
+
     /// Returns `true` if the keycode is a backlight keycode
     pub(crate) fn is_backlight(self) -> bool {
         KeyCode::BacklightOn <= self && self <= KeyCode::BacklightToggleBreathing
@@ -992,7 +1011,7 @@ impl KeyCode {
 
     /// Returns `true` if the keycode is defined by rmk to achieve special functionalities, such as reboot keyboard, goto bootloader, etc.
     pub(crate) fn is_rmk(self) -> bool {
-        KeyCode::Bootloader <= self && self <= KeyCode::AltRepeatKey
+        KeyCode::Bootloader <= self && self <= KeyCode::ShiftWordToggle
     }
 
     /// Returns `true` if the keycode is a kb keycode
@@ -1024,7 +1043,7 @@ impl KeyCode {
             KeyCode::MediaPrevTrack => ConsumerKey::PrevTrack,
             KeyCode::MediaStop => ConsumerKey::StopPlay,
             KeyCode::MediaPlayPause => ConsumerKey::PlayPause,
-            KeyCode::MediaSelect => ConsumerKey::Record,
+            KeyCode::MediaSelect => ConsumerKey::ConsumerControlConfig,
             KeyCode::MediaEject => ConsumerKey::Eject,
             KeyCode::Mail => ConsumerKey::Email,
             KeyCode::Calculator => ConsumerKey::Calculator,
@@ -1043,7 +1062,7 @@ impl KeyCode {
             KeyCode::ControlPanel => ConsumerKey::ControlPanel,
             KeyCode::Assistant => ConsumerKey::Assistant,
             KeyCode::MissionControl => ConsumerKey::DesktopShowAllWindows,
-            KeyCode::Launchpad => ConsumerKey::AcSoftKeyLeft,
+            KeyCode::Launchpad => ConsumerKey::DesktopShowAllApplications,
             _ => ConsumerKey::Zero,
         }
     }
@@ -1058,6 +1077,39 @@ impl KeyCode {
         }
     }
 
+    /// Get the ascii char typed by the keycode on the US layout, `None` if the key doesn't type a printable char
+    pub(crate) fn to_ascii(self, shifted: bool) -> Option<u8> {
+        let (lower, upper) = match self {
+            k if k >= KeyCode::A && k <= KeyCode::Z => {
+                let c = b'a' + (self as u8 - KeyCode::A as u8);
+                (c, c.to_ascii_uppercase())
+            }
+            KeyCode::Kc1 => (b'1', b'!'),
+            KeyCode::Kc2 => (b'2', b'@'),
+            KeyCode::Kc3 => (b'3', b'#'),
+            KeyCode::Kc4 => (b'4', b'$'),
+            KeyCode::Kc5 => (b'5', b'%'),
+            KeyCode::Kc6 => (b'6', b'^'),
+            KeyCode::Kc7 => (b'7', b'&'),
+            KeyCode::Kc8 => (b'8', b'*'),
+            KeyCode::Kc9 => (b'9', b'('),
+            KeyCode::Kc0 => (b'0', b')'),
+            KeyCode::Minus => (b'-', b'_'),
+            KeyCode::Equal => (b'=', b'+'),
+            KeyCode::LeftBracket => (b'[', b'{'),
+            KeyCode::RightBracket => (b']', b'}'),
+            KeyCode::Backslash => (b'\\', b'|'),
+            KeyCode::Semicolon => (b';', b':'),
+            KeyCode::Quote => (b'\'', b'"'),
+            KeyCode::Grave => (b'`', b'~'),
+            KeyCode::Comma => (b',', b'<'),
+            KeyCode::Dot => (b'.', b'>'),
+            KeyCode::Slash => (b'/', b'?'),
+            _ => return None,
+        };
+        Some(if shifted { upper } else { lower })
+    }
+
     /// Convert a ascii chat to keycode
     pub(crate) fn from_ascii(ascii: u8) -> (Self, bool) {
         match ascii {
@@ -1147,14 +1199,14 @@ impl KeyCode {
             b'"' => (KeyCode::Quote, true),
             b'`' => (KeyCode::Grave, false),
             b'~' => (KeyCode::Grave, true),
-            b'\\' => (KeyCode::Backslash, true),
-            b'|' => (KeyCode::Backslash, false),
+            b'\\' => (KeyCode::Backslash, false),
+            b'|' => (KeyCode::Backslash, true),
             b',' => (KeyCode::Comma, false),
             b'<' => (KeyCode::Comma, true),
             b'.' => (KeyCode::Dot, false),
             b'>' => (KeyCode::Dot, true),
             b'/' => (KeyCode::Slash, false),
-            b'?' => (KeyCode::Slash, false),
+            b'?' => (KeyCode::Slash, true),
             b' ' => (KeyCode::Space, false),
             b'\n' => (KeyCode::Enter, false),
             b'\t' => (KeyCode::Tab, false),
@@ -1,13 +1,72 @@
 use crate::{
     action::KeyAction,
-    event::KeyEvent,
-    keyboard_macro::{MacroOperation, MACRO_SPACE_SIZE},
-    keycode::KeyCode,
+    config::KeyRemap,
+    event::{publish_layer_event, KeyEvent, LayerChange, LayerChangeCause, LayerEvent},
+    generation::{bump_generation, config_generations, ConfigItem},
+    input_device::rotary_encoder::{Direction, EncoderMap},
+    keyboard_macro::{serialize_macros, MacroOperation, MACRO_SPACE_SIZE},
     reboot_keyboard,
-    storage::Storage,
+    safe_mode::safe_mode_active,
+    storage::{FlashOperationMessage, Storage, FLASH_CHANNEL},
 };
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embedded_storage_async::nor_flash::NorFlash;
-use num_enum::FromPrimitive;
+use heapless::{Deque, LinearMap};
+
+/// The highest activated layer, updated whenever the layer state changes
+pub(crate) static ACTIVE_LAYER: AtomicU8 = AtomicU8::new(0);
+
+/// Bit mask of activated layers including the default layer, updated along with [`ACTIVE_LAYER`]
+pub(crate) static ACTIVE_LAYER_MASK: AtomicU32 = AtomicU32::new(1);
+
+/// Bit of the layer in [`ACTIVE_LAYER_MASK`], layers above 31 aren't tracked
+fn layer_bit(layer: usize) -> u32 {
+    1u32.checked_shl(layer as u32).unwrap_or(0)
+}
+
+/// Key edits sent by [`set_key_action`], which are applied by the keyboard task
+pub(crate) static KEY_EDIT_CHANNEL: Channel<CriticalSectionRawMutex, KeyEdit, 4> = Channel::new();
+
+/// An edit of a key in the keymap
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct KeyEdit {
+    pub(crate) layer: u8,
+    pub(crate) row: u8,
+    pub(crate) col: u8,
+    pub(crate) action: KeyAction,
+}
+
+/// Change the action of a key on the layer from the firmware, the edit is written through to the storage,
+/// so that it's loaded at next boot.
+///
+/// The edit is applied by the keyboard task with [`KeyMap::set_action`]. It's dropped with a warning if the key is
+/// out of the keymap, or the storage is too busy to save it.
+pub async fn set_key_action(layer: u8, row: u8, col: u8, action: KeyAction) {
+    KEY_EDIT_CHANNEL
+        .send(KeyEdit {
+            layer,
+            row,
+            col,
+            action,
+        })
+        .await;
+}
+
+/// Max number of rotary encoder taps whose layers are cached at the same time
+const MAX_ENCODER_LAYER_CACHE: usize = 4;
+
+/// Max number of recent key changes kept for host tools to sync the keymap
+const KEY_CHANGE_LOG_SIZE: usize = crate::ram_profile::KEY_CHANGE_LOG_SIZE;
+
+/// A key of the keymap is changed at the generation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct KeyChange {
+    pub(crate) generation: u32,
+    pub(crate) layer: u8,
+    pub(crate) row: u8,
+    pub(crate) col: u8,
+}
 
 /// Keymap represents the stack of layers.
 ///
@@ -18,38 +77,67 @@ use num_enum::FromPrimitive;
 pub(crate) struct KeyMap<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
     /// Layers
     pub(crate) layers: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
-    // TODO: Rotary encoders, each rotary encoder is represented as (Clockwise, CounterClockwise)
-    // pub(crate) encoders: Option<&'a mut [[(KeyAction, KeyAction); 2]; NUM_LAYER]>,
+    /// Rotary encoders, each rotary encoder is represented as (Clockwise, CounterClockwise)
+    pub(crate) encoders: Option<EncoderMap<'a>>,
     /// Current state of each layer
     layer_state: [bool; NUM_LAYER],
     /// Default layer number, max: 32
     default_layer: u8,
-    /// Layer cache
-    layer_cache: [[u8; COL]; ROW],
+    /// Layer where the action of each pressed key is found, `None` if the key isn't pressed.
+    ///
+    /// The release of a key is resolved on the same layer as its press, even if the layers are changed in between.
+    layer_cache: [[Option<u8>; COL]; ROW],
+    /// Layer cache of pressed rotary encoders, by encoder id
+    encoder_layer_cache: LinearMap<u8, u8, MAX_ENCODER_LAYER_CACHE>,
     /// Macro cache
     pub(crate) macro_cache: [u8; MACRO_SPACE_SIZE],
+    /// Recent key changes, oldest first
+    key_changes: Deque<KeyChange, KEY_CHANGE_LOG_SIZE>,
+    /// Generation of the latest change which is dropped from `key_changes`
+    dropped_generation: u32,
+    /// Keys whose matrix positions are remapped to other positions of the keymap
+    key_remap: &'a [KeyRemap],
 }
 
 impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
     KeyMap<'a, ROW, COL, NUM_LAYER>
 {
-    pub(crate) async fn new(action_map: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER]) -> Self {
+    pub(crate) async fn new(
+        action_map: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+        encoder_map: Option<EncoderMap<'a>>,
+        macros: &[&[MacroOperation]],
+        key_remap: &'a [KeyRemap],
+    ) -> Self {
+        let mut macro_cache = [0; MACRO_SPACE_SIZE];
+        serialize_macros(macros, &mut macro_cache);
         KeyMap {
             layers: action_map,
+            encoders: encoder_map,
             layer_state: [false; NUM_LAYER],
             default_layer: 0,
-            layer_cache: [[0; COL]; ROW],
-            macro_cache: [0; MACRO_SPACE_SIZE],
+            layer_cache: [[None; COL]; ROW],
+            encoder_layer_cache: LinearMap::new(),
+            macro_cache,
+            key_changes: Deque::new(),
+            dropped_generation: 0,
+            key_remap,
         }
     }
 
     pub(crate) async fn new_from_storage<F: NorFlash>(
         action_map: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+        mut encoder_map: Option<EncoderMap<'a>>,
+        macros: &[&[MacroOperation]],
+        key_remap: &'a [KeyRemap],
         storage: Option<&mut Storage<F, ROW, COL, NUM_LAYER>>,
     ) -> Self {
-        // If the storage is initialized, read keymap from storage
+        // Default macros are replaced by macros saved in storage
         let mut macro_cache = [0; MACRO_SPACE_SIZE];
-        if let Some(storage) = storage {
+        serialize_macros(macros, &mut macro_cache);
+        // If the storage is initialized, read keymap from storage
+        let mut default_layer = 0;
+        // The keymap and macros in storage are ignored in safe mode
+        if let Some(storage) = storage.filter(|_| !safe_mode_active()) {
             // Read keymap to `action_map`
             if storage.read_keymap(action_map).await.is_err() {
                 error!("Keymap reading aborted by an error, clearing the storage...");
@@ -73,14 +161,29 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                     reboot_keyboard();
                 }
             }
+            if let Some(encoders) = encoder_map.as_mut() {
+                storage.read_encoder_map(encoders).await;
+            }
+            if let Some(layer) = storage.read_default_layer().await {
+                if (layer as usize) < NUM_LAYER {
+                    default_layer = layer;
+                }
+            }
         }
+        ACTIVE_LAYER.store(default_layer, Ordering::Relaxed);
+        ACTIVE_LAYER_MASK.store(layer_bit(default_layer as usize), Ordering::Relaxed);
 
         KeyMap {
             layers: action_map,
+            encoders: encoder_map,
             layer_state: [false; NUM_LAYER],
-            default_layer: 0,
-            layer_cache: [[0; COL]; ROW],
+            default_layer,
+            layer_cache: [[None; COL]; ROW],
+            encoder_layer_cache: LinearMap::new(),
             macro_cache,
+            key_changes: Deque::new(),
+            dropped_generation: 0,
+            key_remap,
         }
     }
 
@@ -95,69 +198,31 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
 
     /// Set the default layer number
     pub(crate) fn set_default_layer(&mut self, layer_num: u8) {
-        self.default_layer = layer_num;
-    }
-
-    /// Get the next macro operation starting from given index and offset
-    /// Return current macro operation and the next operations's offset
-    pub(crate) fn get_next_macro_operation(
-        &self,
-        macro_start_idx: usize,
-        offset: usize,
-    ) -> (MacroOperation, usize) {
-        let idx = macro_start_idx + offset;
-        if idx >= self.macro_cache.len() - 1 {
-            return (MacroOperation::End, offset);
+        if layer_num as usize >= NUM_LAYER {
+            warn!(
+                "Not a valid layer {}, keyboard supports only {} layers",
+                layer_num, NUM_LAYER
+            );
+            return;
         }
-        match (self.macro_cache[idx], self.macro_cache[idx + 1]) {
-            (0, _) => (MacroOperation::End, offset),
-            (1, 1) => {
-                // SS_QMK_PREFIX + SS_TAP_CODE
-                if idx + 2 < self.macro_cache.len() {
-                    let keycode = KeyCode::from_primitive(self.macro_cache[idx + 2] as u16);
-                    (MacroOperation::Tap(keycode), offset + 3)
-                } else {
-                    (MacroOperation::End, offset + 3)
-                }
-            }
-            (1, 2) => {
-                // SS_QMK_PREFIX + SS_DOWN_CODE
-                if idx + 2 < self.macro_cache.len() {
-                    let keycode = KeyCode::from_primitive(self.macro_cache[idx + 2] as u16);
-                    (MacroOperation::Press(keycode), offset + 3)
-                } else {
-                    (MacroOperation::End, offset + 3)
-                }
-            }
-            (1, 3) => {
-                // SS_QMK_PREFIX + SS_UP_CODE
-                if idx + 2 < self.macro_cache.len() {
-                    let keycode = KeyCode::from_primitive(self.macro_cache[idx + 2] as u16);
-                    (MacroOperation::Release(keycode), offset + 3)
-                } else {
-                    (MacroOperation::End, offset + 3)
-                }
-            }
-            (1, 4) => {
-                // SS_QMK_PREFIX + SS_DELAY_CODE
-                if idx + 3 < self.macro_cache.len() {
-                    let delay_ms = (self.macro_cache[idx + 2] as u16 - 1)
-                        + (self.macro_cache[idx + 3] as u16 - 1) * 255;
-                    (MacroOperation::Delay(delay_ms), offset + 4)
-                } else {
-                    (MacroOperation::End, offset + 4)
-                }
-            }
-            (1, 5) | (1, 6) | (1, 7) => {
-                warn!("VIAL_MACRO_EXT is not supported");
-                (MacroOperation::Delay(0), offset + 4)
-            }
-            _ => {
-                // Current byte is the ascii code, convert it to keyboard keycode(with caps state)
-                let (keycode, is_caps) = KeyCode::from_ascii(self.macro_cache[idx]);
-                (MacroOperation::Text(keycode, is_caps), offset + 1)
-            }
+        if self.default_layer == layer_num {
+            return;
         }
+        self.default_layer = layer_num;
+        bump_generation(ConfigItem::Settings);
+        // The default layer is restored from the storage at boot
+        if FLASH_CHANNEL
+            .try_send(FlashOperationMessage::DefaultLayer(layer_num))
+            .is_err()
+        {
+            warn!("Failed to save default layer, storage channel is full");
+        }
+        self.update_active_layer();
+        publish_layer_event(LayerEvent {
+            change: LayerChange::DefaultLayer(layer_num),
+            cause: LayerChangeCause::KeyAction,
+            active_layer: self.get_activated_layer(),
+        });
     }
 
     pub(crate) fn get_macro_start(&self, mut macro_idx: u8) -> Option<usize> {
@@ -187,22 +252,258 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         layer_num: usize,
         action: KeyAction,
     ) {
+        if self.layers[layer_num][row][col] == action {
+            return;
+        }
         self.layers[layer_num][row][col] = action;
+        let generation = bump_generation(ConfigItem::Keymap);
+        if self.key_changes.is_full() {
+            if let Some(dropped) = self.key_changes.pop_front() {
+                self.dropped_generation = dropped.generation;
+            }
+        }
+        self.key_changes
+            .push_back(KeyChange {
+                generation,
+                layer: layer_num as u8,
+                row: row as u8,
+                col: col as u8,
+            })
+            .ok();
+    }
+
+    /// Current generation of the keymap
+    pub(crate) fn generation(&self) -> u32 {
+        config_generations().keymap
+    }
+
+    /// Keys changed after the given generation, oldest first.
+    ///
+    /// Returns `None` if some of the changes are dropped from the log, or the generation is newer than the keymap,
+    /// then the host should read the whole keymap.
+    pub(crate) fn key_changes_since(
+        &self,
+        generation: u32,
+    ) -> Option<impl Iterator<Item = &KeyChange>> {
+        if generation < self.dropped_generation || generation > self.generation() {
+            return None;
+        }
+        Some(
+            self.key_changes
+                .iter()
+                .filter(move |change| change.generation > generation),
+        )
+    }
+
+    /// Set the action at the given position, and write it through to the storage, so that it's loaded at next boot.
+    ///
+    /// Returns `false` if the position is out of the keymap, or the storage channel is full, the keymap isn't changed then.
+    pub fn set_action(&mut self, layer_num: u8, row: u8, col: u8, action: KeyAction) -> bool {
+        if self
+            .action_on_layer(layer_num as usize, row as usize, col as usize)
+            .is_none()
+        {
+            warn!(
+                "Key ({},{}), layer {} is out of the keymap",
+                row, col, layer_num
+            );
+            return false;
+        }
+        if FLASH_CHANNEL
+            .try_send(FlashOperationMessage::KeymapKey {
+                layer: layer_num,
+                col,
+                row,
+                action,
+            })
+            .is_err()
+        {
+            warn!(
+                "Failed to save key ({},{}), storage channel is full",
+                row, col
+            );
+            return false;
+        }
+        self.set_action_at(row as usize, col as usize, layer_num as usize, action);
+        true
+    }
+
+    /// Set the action of the encoder turning clockwise or counterclockwise on the given layer,
+    /// and write it through to the storage.
+    ///
+    /// Returns `false` if the encoder isn't in the encoder map.
+    pub(crate) fn set_encoder_action(
+        &mut self,
+        layer_num: u8,
+        id: u8,
+        clockwise: bool,
+        action: KeyAction,
+    ) -> bool {
+        let Some(encoder) = self
+            .encoders
+            .as_mut()
+            .and_then(|encoders| encoders.get_mut(layer_num as usize, id as usize))
+        else {
+            warn!(
+                "Encoder {}, layer {} is out of the encoder map",
+                id, layer_num
+            );
+            return false;
+        };
+        if clockwise {
+            encoder.0 = action;
+        } else {
+            encoder.1 = action;
+        }
+        bump_generation(ConfigItem::Keymap);
+        if FLASH_CHANNEL
+            .try_send(FlashOperationMessage::EncoderKey {
+                layer: layer_num,
+                id,
+                action: *encoder,
+            })
+            .is_err()
+        {
+            warn!("Failed to save encoder {}, storage channel is full", id);
+        }
+        true
+    }
+
+    /// Get the action at the given position of the given layer, `None` if the position is out of the keymap
+    pub(crate) fn action_on_layer(
+        &self,
+        layer_num: usize,
+        row: usize,
+        col: usize,
+    ) -> Option<KeyAction> {
+        self.layers.get(layer_num)?.get(row)?.get(col).copied()
     }
 
-    /// Fetch the action in keymap, with layer cache
-    pub(crate) fn get_action_at(&mut self, row: usize, col: usize, layer_num: usize) -> KeyAction {
-        self.layers[layer_num][row][col]
+    /// Get the action which would be triggered by pressing the key now, the layer cache isn't changed
+    pub(crate) fn current_action(&self, row: usize, col: usize) -> KeyAction {
+        for (layer_idx, layer) in self.layers.iter().enumerate().rev() {
+            if self.layer_state[layer_idx] || layer_idx as u8 == self.default_layer {
+                let action = layer[row][col];
+                if action != KeyAction::Transparent && action != KeyAction::No {
+                    return action;
+                }
+            }
+            if layer_idx as u8 == self.default_layer {
+                break;
+            }
+        }
+        KeyAction::No
+    }
+
+    /// Get the action of the encoder turning in `direction` on the given layer
+    fn encoder_action_on_layer(
+        &self,
+        layer_num: usize,
+        id: u8,
+        direction: &Direction,
+    ) -> KeyAction {
+        let Some((clockwise, counter_clockwise)) = self
+            .encoders
+            .as_ref()
+            .and_then(|encoders| encoders.get(layer_num, id as usize))
+        else {
+            return KeyAction::No;
+        };
+        match direction {
+            Direction::Clockwise => clockwise,
+            Direction::CounterClockwise => counter_clockwise,
+            Direction::None => KeyAction::No,
+        }
+    }
+
+    /// Fetch the action of an encoder turn, which is tapped as a press and a release, with layer cache.
+    ///
+    /// Like keys, the release is resolved on the layer of the press, so that a turn whose action switches layers
+    /// doesn't send a press and a release of different actions.
+    pub(crate) fn encoder_action_with_layer_cache(
+        &mut self,
+        id: u8,
+        direction: &Direction,
+        pressed: bool,
+    ) -> KeyAction {
+        if !pressed {
+            return match self.encoder_layer_cache.remove(&id) {
+                Some(layer) => self.encoder_action_on_layer(layer as usize, id, direction),
+                None => KeyAction::No,
+            };
+        }
+        for layer_idx in (0..NUM_LAYER).rev() {
+            if self.layer_state[layer_idx] || layer_idx as u8 == self.default_layer {
+                let action = self.encoder_action_on_layer(layer_idx, id, direction);
+                if action != KeyAction::Transparent && action != KeyAction::No {
+                    if self
+                        .encoder_layer_cache
+                        .insert(id, layer_idx as u8)
+                        .is_err()
+                    {
+                        warn!("Encoder layer cache is full");
+                    }
+                    return action;
+                }
+            }
+            if layer_idx as u8 == self.default_layer {
+                break;
+            }
+        }
+        KeyAction::No
+    }
+
+    /// Whether the key has a tap/hold action on any layer
+    pub(crate) fn has_tap_hold(&self, row: usize, col: usize) -> bool {
+        (0..NUM_LAYER).any(|layer| {
+            self.keys_on_layer(layer)
+                .any(|(r, c, action)| (r, c) == (row, col) && action.is_tap_hold())
+        })
+    }
+
+    /// Iterate over keys of the given layer which are neither `Transparent` nor `No`, yields `(row, col, action)`
+    pub(crate) fn keys_on_layer(
+        &self,
+        layer_num: usize,
+    ) -> impl Iterator<Item = (usize, usize, KeyAction)> + '_ {
+        self.layers
+            .get(layer_num)
+            .into_iter()
+            .flat_map(|layer| layer.iter().enumerate())
+            .flat_map(|(row, keys)| {
+                keys.iter()
+                    .enumerate()
+                    .map(move |(col, action)| (row, col, *action))
+            })
+            .filter(|(_, _, action)| *action != KeyAction::Transparent && *action != KeyAction::No)
+    }
+
+    /// Position of the key in the keymap from its position in the matrix, see [`KeyRemap`]
+    pub(crate) fn logical_position(&self, row: u8, col: u8) -> (usize, usize) {
+        let (row, col) = self
+            .key_remap
+            .iter()
+            .find(|remap| remap.physical == (row, col))
+            .map_or((row, col), |remap| remap.logical);
+        (row as usize, col as usize)
     }
 
-    /// Fetch the action in keymap, with layer cache
+    /// Fetch the action in keymap, with layer cache.
+    ///
+    /// The position of the key event is remapped first, see [`KeyRemap`].
+    /// Events out of the matrix, like virtual keys of encoders, have no action.
     pub(crate) fn get_action_with_layer_cache(&mut self, key_event: KeyEvent) -> KeyAction {
-        let row = key_event.row as usize;
-        let col = key_event.col as usize;
+        let (row, col) = self.logical_position(key_event.row, key_event.col);
+        if row >= ROW || col >= COL {
+            return KeyAction::No;
+        }
         if !key_event.pressed {
-            // Releasing a pressed key, use cached layer and restore the cache
-            let layer = self.pop_layer_from_cache(row, col);
-            return self.layers[layer as usize][row][col];
+            // Releasing a pressed key, use cached layer and clear the cache.
+            // A key which is pressed before the keymap is running isn't cached, release it on current layers
+            return match self.pop_layer_from_cache(row, col) {
+                Some(layer) => self.layers[layer as usize][row][col],
+                None => self.current_action(row, col),
+            };
         }
 
         // Iterate from higher layer to lower layer, the lowest checked layer is the default layer
@@ -221,7 +522,8 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             }
 
             if layer_idx as u8 == self.default_layer {
-                // No action
+                // No action, the release is resolved on the default layer as well
+                self.save_layer_cache(row, col, layer_idx as u8);
                 break;
             }
         }
@@ -229,7 +531,21 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         KeyAction::No
     }
 
-    fn get_activated_layer(&self) -> u8 {
+    fn update_active_layer(&self) {
+        ACTIVE_LAYER.store(self.get_activated_layer(), Ordering::Relaxed);
+        let mask = self
+            .layer_state
+            .iter()
+            .enumerate()
+            .filter(|(_, &active)| active)
+            .fold(
+                layer_bit(self.default_layer as usize),
+                |mask, (layer, _)| mask | layer_bit(layer),
+            );
+        ACTIVE_LAYER_MASK.store(mask, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get_activated_layer(&self) -> u8 {
         for (layer_idx, _) in self.layers.iter().enumerate().rev() {
             if self.layer_state[layer_idx] || layer_idx as u8 == self.default_layer {
                 return layer_idx as u8;
@@ -239,25 +555,53 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         self.default_layer
     }
 
-    fn get_layer_from_cache(&self, row: usize, col: usize) -> u8 {
+    fn get_layer_from_cache(&self, row: usize, col: usize) -> Option<u8> {
         self.layer_cache[row][col]
     }
 
-    fn pop_layer_from_cache(&mut self, row: usize, col: usize) -> u8 {
-        let layer = self.layer_cache[row][col];
-        self.layer_cache[row][col] = self.default_layer;
-
-        layer
+    fn pop_layer_from_cache(&mut self, row: usize, col: usize) -> Option<u8> {
+        self.layer_cache[row][col].take()
     }
 
     fn save_layer_cache(&mut self, row: usize, col: usize, layer_num: u8) {
-        self.layer_cache[row][col] = layer_num;
+        self.layer_cache[row][col] = Some(layer_num);
+    }
+
+    /// Set the state of a layer, publish a layer event if the state is changed
+    fn set_layer_state(&mut self, layer_num: u8, active: bool, cause: LayerChangeCause) {
+        if self.layer_state[layer_num as usize] == active {
+            return;
+        }
+        self.layer_state[layer_num as usize] = active;
+        self.update_active_layer();
+        publish_layer_event(LayerEvent {
+            change: if active {
+                LayerChange::Activated(layer_num)
+            } else {
+                LayerChange::Deactivated(layer_num)
+            },
+            cause,
+            active_layer: self.get_activated_layer(),
+        });
+    }
+
+    /// Activate or deactivate a layer by a host command
+    pub(crate) fn set_host_layer(&mut self, layer_num: u8, active: bool) {
+        if layer_num as usize >= NUM_LAYER {
+            warn!(
+                "Not a valid layer {}, keyboard supports only {} layers",
+                layer_num, NUM_LAYER
+            );
+            return;
+        }
+        self.set_layer_state(layer_num, active, LayerChangeCause::Host);
     }
 
     /// Update given Tri Layer state
     pub(crate) fn update_tri_layer(&mut self, tri_layer: &[u8; 3]) {
-        self.layer_state[tri_layer[2] as usize] =
+        let active =
             self.layer_state[tri_layer[0] as usize] && self.layer_state[tri_layer[1] as usize];
+        self.set_layer_state(tri_layer[2], active, LayerChangeCause::TriLayer);
     }
 
     /// Activate given layer
@@ -269,7 +613,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             );
             return;
         }
-        self.layer_state[layer_num as usize] = true;
+        self.set_layer_state(layer_num, true, LayerChangeCause::KeyAction);
     }
 
     /// Deactivate given layer
@@ -281,7 +625,24 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             );
             return;
         }
-        self.layer_state[layer_num as usize] = false;
+        self.set_layer_state(layer_num, false, LayerChangeCause::KeyAction);
+    }
+
+    /// Activate given layer and deactivate all other layers, except the default layer
+    pub(crate) fn activate_layer_only(&mut self, layer_num: u8) {
+        if layer_num as usize >= NUM_LAYER {
+            warn!(
+                "Not a valid layer {}, keyboard supports only {} layers",
+                layer_num, NUM_LAYER
+            );
+            return;
+        }
+        for i in 0..NUM_LAYER as u8 {
+            if i != layer_num && i != self.default_layer {
+                self.set_layer_state(i, false, LayerChangeCause::KeyAction);
+            }
+        }
+        self.set_layer_state(layer_num, true, LayerChangeCause::KeyAction);
     }
 
     /// Toggle given layer
@@ -293,7 +654,108 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             );
             return;
         }
+        let active = !self.layer_state[layer_num as usize];
+        self.set_layer_state(layer_num, active, LayerChangeCause::KeyAction);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::{
+        action::Action,
+        keycode::{KeyCode, ModifierCombination},
+    };
 
-        self.layer_state[layer_num as usize] = !self.layer_state[layer_num as usize];
+    const A: KeyAction = KeyAction::Single(Action::Key(KeyCode::A));
+    const B: KeyAction = KeyAction::Single(Action::Key(KeyCode::B));
+    const TRNS: KeyAction = KeyAction::Transparent;
+    const NO: KeyAction = KeyAction::No;
+
+    fn test_layers() -> [[[KeyAction; 2]; 2]; 2] {
+        let mt = KeyAction::ModifierTapHold(
+            Action::Key(KeyCode::F),
+            ModifierCombination::new_from(false, false, false, false, true),
+        );
+        [[[A, mt], [NO, TRNS]], [[TRNS, B], [A, NO]]]
+    }
+
+    #[test]
+    fn test_keys_on_layer() {
+        let mut layers = test_layers();
+        let keymap = block_on(KeyMap::new(&mut layers, None, &[], &[]));
+        // Transparent and empty keys are skipped
+        let keys: heapless::Vec<_, 4> = keymap.keys_on_layer(1).collect();
+        assert_eq!(keys, [(0, 1, B), (1, 0, A)]);
+        assert_eq!(keymap.keys_on_layer(0).count(), 2);
+        assert_eq!(keymap.keys_on_layer(2).count(), 0);
+    }
+
+    #[test]
+    fn test_action_queries() {
+        let mut layers = test_layers();
+        let keymap = block_on(KeyMap::new(&mut layers, None, &[], &[]));
+        assert_eq!(keymap.action_on_layer(1, 0, 0), Some(TRNS));
+        assert_eq!(keymap.action_on_layer(2, 0, 0), None);
+        assert_eq!(keymap.action_on_layer(0, 0, 2), None);
+        // Only the default layer is active, transparent keys fall through to `No`
+        assert_eq!(keymap.current_action(0, 0), A);
+        assert_eq!(keymap.current_action(1, 1), NO);
+        assert!(keymap.has_tap_hold(0, 1));
+        assert!(!keymap.has_tap_hold(0, 0));
+        assert!(!keymap.has_tap_hold(1, 1));
+    }
+
+    // Generations are shared by all keymaps, only the generations of recorded changes are compared
+    #[test]
+    fn test_key_changes_since() {
+        let mut layers = test_layers();
+        let mut keymap = block_on(KeyMap::new(&mut layers, None, &[], &[]));
+        let base = keymap.generation();
+        assert_eq!(keymap.key_changes_since(base).unwrap().count(), 0);
+        // A generation newer than the keymap can't be synced
+        assert!(keymap.key_changes_since(u32::MAX).is_none());
+
+        keymap.set_action_at(0, 0, 0, B);
+        // Unchanged keys aren't logged
+        keymap.set_action_at(0, 0, 0, B);
+        keymap.set_action_at(1, 1, 1, A);
+        let changes: heapless::Vec<KeyChange, 4> =
+            keymap.key_changes_since(base).unwrap().copied().collect();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            (changes[0].layer, changes[0].row, changes[0].col),
+            (0, 0, 0)
+        );
+        assert_eq!(
+            (changes[1].layer, changes[1].row, changes[1].col),
+            (1, 1, 1)
+        );
+        assert!(changes[0].generation < changes[1].generation);
+        let since_first: heapless::Vec<KeyChange, 4> = keymap
+            .key_changes_since(changes[0].generation)
+            .unwrap()
+            .copied()
+            .collect();
+        assert_eq!(since_first, [changes[1]]);
+        assert_eq!(
+            keymap
+                .key_changes_since(changes[1].generation)
+                .unwrap()
+                .count(),
+            0
+        );
+
+        // The log overflows, the oldest changes are dropped
+        for i in 0..KEY_CHANGE_LOG_SIZE {
+            keymap.set_action_at(0, 1, 1, if i % 2 == 0 { A } else { B });
+        }
+        assert!(keymap.key_changes_since(base).is_none());
+        assert!(keymap.key_changes_since(changes[0].generation).is_none());
+        // Changes after the latest dropped one are complete
+        let kept = keymap.key_changes_since(changes[1].generation).unwrap();
+        assert_eq!(kept.count(), KEY_CHANGE_LOG_SIZE);
     }
 }
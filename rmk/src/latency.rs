@@ -0,0 +1,322 @@
+//! End-to-end latency self-measurement
+//!
+//! A test rig toggles a GPIO, [`run_latency_probe`] timestamps the edge and sends a key event of the test key,
+//! as if the key is pressed or released in the matrix. When the resulting keyboard report, which has the keycode of the test key
+//! after a press and doesn't have it after a release, is written to the USB endpoint, the latency is recorded.
+//! The distribution is printed every [`LATENCY_REPORT_INTERVAL`] samples via the debug log,
+//! so that performance regressions of the key processing pipeline can be tracked.
+//!
+//! Only one measurement is in flight at a time, edges which arrive before the previous report is sent are skipped.
+//! Edges whose reports aren't sent in [`MEASUREMENT_TIMEOUT`] are discarded.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+use crate::{event::KeyEvent, keyboard::KEY_EVENT_CHANNEL, keycode::KeyCode};
+
+/// Number of samples between two distribution reports
+pub const LATENCY_REPORT_INTERVAL: u32 = 100;
+
+/// Upper bounds of histogram buckets in microseconds, the last bucket has no upper bound
+const BUCKET_BOUNDS_US: [u64; 7] = [250, 500, 1000, 2000, 4000, 8000, 16000];
+
+/// A measurement is dropped if the report isn't sent in this duration, e.g. the test key is mapped to another keycode
+pub const MEASUREMENT_TIMEOUT: Duration = Duration::from_millis(100);
+
+// The pending test key edge
+static PENDING: Mutex<CriticalSectionRawMutex, Cell<Option<PendingEdge>>> =
+    Mutex::new(Cell::new(None));
+
+/// An edge of the test key whose report isn't sent yet
+#[derive(Clone, Copy, Debug)]
+struct PendingEdge {
+    start: Instant,
+    key: KeyCode,
+    pressed: bool,
+}
+
+impl PendingEdge {
+    fn is_stale(&self, now: Instant) -> bool {
+        now.checked_duration_since(self.start)
+            .is_some_and(|elapsed| elapsed >= MEASUREMENT_TIMEOUT)
+    }
+}
+
+/// Resolve the pending edge by a sent keyboard report, returns the latency in microseconds if the report is caused by the edge.
+///
+/// A stale edge is discarded. The edge is kept if the report is for other keys, i.e. it doesn't have the test key after a press,
+/// or still has it after a release.
+fn resolve_edge(
+    pending: &mut Option<PendingEdge>,
+    now: Instant,
+    report_has_key: impl Fn(KeyCode) -> bool,
+) -> Option<u64> {
+    let edge = (*pending)?;
+    if edge.is_stale(now) {
+        debug!("Latency measurement timed out, discard");
+        *pending = None;
+        return None;
+    }
+    if report_has_key(edge.key) != edge.pressed {
+        return None;
+    }
+    *pending = None;
+    Some(now.checked_duration_since(edge.start)?.as_micros())
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, Cell<LatencyStats>> =
+    Mutex::new(Cell::new(LatencyStats::new()));
+
+/// Distribution of measured latencies
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LatencyStats {
+    pub samples: u32,
+    pub min_us: u64,
+    pub max_us: u64,
+    pub total_us: u64,
+    /// Number of samples in each bucket: <250us, <500us, <1ms, <2ms, <4ms, <8ms, <16ms and the rest
+    pub buckets: [u32; BUCKET_BOUNDS_US.len() + 1],
+}
+
+impl LatencyStats {
+    const fn new() -> Self {
+        Self {
+            samples: 0,
+            min_us: u64::MAX,
+            max_us: 0,
+            total_us: 0,
+            buckets: [0; BUCKET_BOUNDS_US.len() + 1],
+        }
+    }
+
+    fn record(&mut self, latency_us: u64) {
+        if self.samples == u32::MAX {
+            // Start over instead of wrapping around, which would break the average
+            *self = Self::new();
+        }
+        self.samples += 1;
+        self.min_us = self.min_us.min(latency_us);
+        self.max_us = self.max_us.max(latency_us);
+        self.total_us = self.total_us.saturating_add(latency_us);
+        let bucket = BUCKET_BOUNDS_US
+            .iter()
+            .position(|&bound| latency_us < bound)
+            .unwrap_or(BUCKET_BOUNDS_US.len());
+        self.buckets[bucket] += 1;
+    }
+
+    /// Average latency in microseconds
+    pub fn average_us(&self) -> u64 {
+        self.total_us / self.samples.max(1) as u64
+    }
+
+    /// Smallest bucket bound which covers the given percentage of samples, `None` if it's in the last bucket
+    pub fn percentile_bound_us(&self, percent: u32) -> Option<u64> {
+        let target = (self.samples as u64 * percent as u64).div_ceil(100);
+        let mut count = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            count += *n as u64;
+            if count >= target {
+                return BUCKET_BOUNDS_US.get(i).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Get the latency distribution measured so far
+pub fn latency_stats() -> LatencyStats {
+    STATS.lock(|s| s.get())
+}
+
+/// Run the latency probe, this function never returns.
+///
+/// The test rig drives `pin` high to press the test key at (`row`, `col`), and low to release it.
+/// The test key should be mapped to the basic keycode `key`, so that every edge produces a keyboard report with or without `key`.
+pub async fn run_latency_probe<P: Wait + InputPin>(
+    mut pin: P,
+    row: u8,
+    col: u8,
+    key: KeyCode,
+) -> ! {
+    info!("Latency probe started, test key: ({},{})", row, col);
+    loop {
+        let pressed = pin.is_high().unwrap_or_default();
+        let edge = if pressed {
+            pin.wait_for_low().await
+        } else {
+            pin.wait_for_high().await
+        };
+        if edge.is_err() {
+            error!("Latency probe pin error");
+            Timer::after_secs(1).await;
+            continue;
+        }
+        let now = Instant::now();
+        let started = PENDING.lock(|p| match p.get() {
+            Some(edge) if !edge.is_stale(now) => false,
+            _ => {
+                p.set(Some(PendingEdge {
+                    start: now,
+                    key,
+                    pressed: !pressed,
+                }));
+                true
+            }
+        });
+        if !started {
+            debug!("Previous latency measurement is in flight, skip");
+        }
+        KEY_EVENT_CHANNEL
+            .send(KeyEvent {
+                row,
+                col,
+                pressed: !pressed,
+            })
+            .await;
+    }
+}
+
+/// Record the latency of the pending measurement, should be called after a keyboard report is written to the host.
+///
+/// `report_has_key` tells whether a keycode is pressed in the report
+pub(crate) fn keyboard_report_sent(report_has_key: impl Fn(KeyCode) -> bool) {
+    let now = Instant::now();
+    let Some(latency_us) = PENDING.lock(|p| {
+        let mut pending = p.get();
+        let latency_us = resolve_edge(&mut pending, now, &report_has_key);
+        p.set(pending);
+        latency_us
+    }) else {
+        return;
+    };
+    let stats = STATS.lock(|s| {
+        let mut stats = s.get();
+        stats.record(latency_us);
+        s.set(stats);
+        stats
+    });
+    debug!("Latency: {}us", latency_us);
+    if stats.samples % LATENCY_REPORT_INTERVAL == 0 {
+        print_latency_stats(&stats);
+    }
+}
+
+fn print_latency_stats(stats: &LatencyStats) {
+    info!(
+        "Latency of {} samples: min {}us, avg {}us, max {}us",
+        stats.samples,
+        stats.min_us,
+        stats.average_us(),
+        stats.max_us
+    );
+    match (stats.percentile_bound_us(50), stats.percentile_bound_us(99)) {
+        (Some(p50), Some(p99)) => info!("Latency p50 < {}us, p99 < {}us", p50, p99),
+        (Some(p50), None) => info!(
+            "Latency p50 < {}us, p99 >= {}us",
+            p50,
+            BUCKET_BOUNDS_US[BUCKET_BOUNDS_US.len() - 1]
+        ),
+        _ => info!(
+            "Latency p50 >= {}us",
+            BUCKET_BOUNDS_US[BUCKET_BOUNDS_US.len() - 1]
+        ),
+    }
+    info!(
+        "Latency histogram(<250us, <500us, <1ms, <2ms, <4ms, <8ms, <16ms, more): {:?}",
+        stats.buckets
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latency_stats() {
+        let mut stats = LatencyStats::new();
+        assert_eq!(stats.average_us(), 0);
+        for latency_us in [300, 100, 1200, 20000] {
+            stats.record(latency_us);
+        }
+        assert_eq!(stats.samples, 4);
+        assert_eq!(stats.min_us, 100);
+        assert_eq!(stats.max_us, 20000);
+        assert_eq!(stats.average_us(), 5400);
+        assert_eq!(stats.buckets, [1, 1, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(stats.percentile_bound_us(50), Some(500));
+        assert_eq!(stats.percentile_bound_us(99), None);
+    }
+
+    #[test]
+    fn test_latency_stats_wraparound() {
+        let mut stats = LatencyStats::new();
+        stats.record(1000);
+        stats.samples = u32::MAX;
+        stats.total_us = u64::MAX - 10;
+        // Stats start over instead of overflowing
+        stats.record(400);
+        assert_eq!(stats.samples, 1);
+        assert_eq!(
+            (stats.min_us, stats.max_us, stats.average_us()),
+            (400, 400, 400)
+        );
+        // The total saturates
+        stats.total_us = u64::MAX - 10;
+        stats.record(400);
+        assert_eq!(stats.total_us, u64::MAX);
+    }
+
+    #[test]
+    fn test_resolve_edge() {
+        let start = Instant::from_millis(1000);
+        let press = PendingEdge {
+            start,
+            key: KeyCode::A,
+            pressed: true,
+        };
+        let has_a = |k: KeyCode| k == KeyCode::A;
+        let no_key = |_: KeyCode| false;
+
+        // Reports of other keys don't resolve the edge
+        let mut pending = Some(press);
+        assert_eq!(
+            resolve_edge(&mut pending, start + Duration::from_micros(500), no_key),
+            None
+        );
+        assert!(pending.is_some());
+        assert_eq!(
+            resolve_edge(&mut pending, start + Duration::from_micros(800), has_a),
+            Some(800)
+        );
+        assert!(pending.is_none());
+        assert_eq!(resolve_edge(&mut pending, start, has_a), None);
+
+        // A release is resolved by a report without the key
+        let mut pending = Some(PendingEdge {
+            pressed: false,
+            ..press
+        });
+        assert_eq!(
+            resolve_edge(&mut pending, start + Duration::from_micros(300), has_a),
+            None
+        );
+        assert_eq!(
+            resolve_edge(&mut pending, start + Duration::from_micros(600), no_key),
+            Some(600)
+        );
+
+        // Stale edges are discarded
+        let mut pending = Some(press);
+        assert_eq!(
+            resolve_edge(&mut pending, start + MEASUREMENT_TIMEOUT, has_a),
+            None
+        );
+        assert!(pending.is_none());
+    }
+}
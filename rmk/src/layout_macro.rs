@@ -106,11 +106,11 @@ macro_rules! tg {
     };
 }
 
-/// Create a layer activate or tap toggle action
+/// Create a layer activate or tap toggle action, the layer is active while the key is held, and toggled after tapping `tapping_toggle` times
 #[macro_export]
 macro_rules! tt {
     ($x: literal) => {
-        $crate::action::KeyAction::LayerTapHold($crate::action::Action::LayerToggle($x), $x)
+        $crate::action::KeyAction::Single($crate::action::Action::LayerTapToggle($x))
     };
 }
 
@@ -122,6 +122,14 @@ macro_rules! to {
     };
 }
 
+/// Create a soft off action, which powers the keyboard down after the key is held for a while
+#[macro_export]
+macro_rules! soft_off {
+    () => {
+        $crate::action::KeyAction::Single($crate::action::Action::SoftOff)
+    };
+}
+
 /// create a switch default layer action, `n` is the layer number
 #[macro_export]
 macro_rules! df {
@@ -129,3 +137,19 @@ macro_rules! df {
         $crate::action::KeyAction::Single($crate::action::Action::DefaultLayer($x))
     };
 }
+
+/// Create an action which types a character by the Unicode input method of the host, e.g. `uc!('é')`
+#[macro_export]
+macro_rules! uc {
+    ($x: literal) => {
+        $crate::action::KeyAction::Unicode($x)
+    };
+}
+
+/// Create a tap dance action, `n` is the index of the tap dance in `TapDanceConfig`
+#[macro_export]
+macro_rules! td {
+    ($x: literal) => {
+        $crate::action::KeyAction::TapDance($x)
+    };
+}
@@ -19,10 +19,6 @@ use crate::ble::esp::initialize_esp_ble_keyboard_with_config_and_run;
 #[cfg(feature = "_nrf_ble")]
 use crate::ble::nrf::initialize_nrf_ble_keyboard_and_run;
 use crate::config::RmkConfig;
-#[cfg(not(feature = "rapid_debouncer"))]
-use crate::debounce::default_bouncer::DefaultDebouncer;
-#[cfg(feature = "rapid_debouncer")]
-use crate::debounce::fast_debouncer::RapidDebouncer;
 use crate::{
     light::{led_hid_task, LightService},
     via::vial_task,
@@ -32,7 +28,7 @@ use core::{
     cell::RefCell,
     sync::atomic::{AtomicBool, AtomicU8},
 };
-use debounce::DebouncerTrait;
+use debounce::{Debouncer, MatrixDebouncer};
 #[cfg(not(feature = "_esp_ble"))]
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, select4, Either4};
@@ -48,49 +44,108 @@ use embedded_storage::nor_flash::NorFlash;
 pub use flash::EmptyFlashWrapper;
 use futures::pin_mut;
 use keyboard::{communication_task, Keyboard, KeyboardReportMessage, KEYBOARD_REPORT_CHANNEL};
-pub use keyboard::{EVENT_CHANNEL, EVENT_CHANNEL_SIZE, REPORT_CHANNEL_SIZE};
+pub use keyboard::{
+    EVENT_CHANNEL, EVENT_CHANNEL_SIZE, POINTER_REPORT_CHANNEL, POINTER_REPORT_CHANNEL_SIZE,
+    POINTING_EVENT_CHANNEL, REPORT_CHANNEL_SIZE,
+};
+pub use keymap::set_key_action;
 use keymap::KeyMap;
 use matrix::{Matrix, MatrixTrait};
 pub use rmk_macro as macros;
+#[cfg(feature = "ch32v")]
+pub use storage::nor_flash::ch32_flash::Ch32Flash;
 use usb::KeyboardUsbDevice;
 use via::process::VialService;
 #[cfg(any(feature = "_nrf_ble", not(feature = "_no_external_storage")))]
 use {embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash, storage::Storage};
 
 pub mod action;
+pub mod actuator;
+pub mod adjust;
+pub mod analog_matrix;
+pub mod auto_lock;
 #[cfg(feature = "_ble")]
 pub mod ble;
+pub mod bus;
+#[cfg(feature = "calculator")]
+pub mod calculator;
+mod caps_word;
+pub mod combo;
 pub mod config;
 pub mod debounce;
+pub mod diagnostic;
 pub mod direct_pin;
+pub mod display;
+pub mod dyn_keymap;
 pub mod event;
+#[cfg(feature = "firmware_update")]
+pub mod firmware_update;
 mod flash;
+pub mod generation;
+pub mod health;
 mod hid;
+pub mod host_layout;
+pub mod indicator;
 pub mod input_device;
+pub mod key_override;
 pub mod keyboard;
-mod keyboard_macro;
+pub mod keyboard_macro;
 pub mod keycode;
 mod keymap;
+#[cfg(feature = "latency_probe")]
+pub mod latency;
 mod layout_macro;
 mod light;
 pub mod matrix;
+mod mouse_key;
+pub mod output;
+pub mod pomodoro;
+pub mod power;
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+pub mod priority;
+mod ram_profile;
+mod report;
+pub mod rgb;
+pub mod safe_mode;
+pub mod scroll;
+pub mod shift_register;
+pub mod sleep;
+pub mod socd;
 #[cfg(feature = "split")]
 pub mod split;
 mod storage;
+pub mod tap_dance;
+pub mod thermal;
+pub mod unicode;
 mod usb;
+pub mod user_key;
 mod via;
 
 /// Keyboard state, true for started, false for stopped
 pub(crate) static KEYBOARD_STATE: AtomicBool = AtomicBool::new(false);
-/// Current connection type:
-/// - 0: USB
+/// Current connection type, see [`output::OutputMode`]:
+/// - 0: USB when it's connected, BLE otherwise
 /// - 1: BLE
+/// - 2: USB only
 /// - Other: reserved
 pub(crate) static CONNECTION_TYPE: AtomicU8 = AtomicU8::new(0);
 /// Whether the connection is ready.
 /// After the connection is ready, the matrix starts scanning
 pub(crate) static CONNECTION_STATE: AtomicBool = AtomicBool::new(false);
 
+// The memory report of `rmk-macro/src/memory.rs` is printed at build time with these sizes, update both when the types are changed
+#[cfg(target_pointer_width = "32")]
+const _: () = {
+    use core::mem::size_of;
+    assert!(size_of::<action::KeyAction>() == 8);
+    assert!(size_of::<event::KeyEvent>() == 3);
+    assert!(size_of::<event::Event>() == 16);
+    assert!(size_of::<keyboard::KeyboardReportMessage>() == 32);
+    assert!(size_of::<rgb::Rgb>() == 3);
+    #[cfg(not(feature = "_nrf_ble"))]
+    assert!(size_of::<storage::FlashOperationMessage>() == 260);
+};
+
 /// Run RMK keyboard service. This function should never return.
 ///
 /// # Arguments
@@ -177,20 +232,17 @@ pub async fn run_rmk_with_async_flash<
     #[cfg(not(feature = "_esp_ble"))] spawner: Spawner,
 ) -> ! {
     // Create the debouncer, use COL2ROW by default
-    #[cfg(all(feature = "col2row", feature = "rapid_debouncer"))]
-    let debouncer = RapidDebouncer::<ROW, COL>::new();
-    #[cfg(all(feature = "col2row", not(feature = "rapid_debouncer")))]
-    let debouncer = DefaultDebouncer::<ROW, COL>::new();
-    #[cfg(all(not(feature = "col2row"), feature = "rapid_debouncer"))]
-    let debouncer = RapidDebouncer::<COL, ROW>::new();
-    #[cfg(all(not(feature = "col2row"), not(feature = "rapid_debouncer")))]
-    let debouncer = DefaultDebouncer::<COL, ROW>::new();
+    #[cfg(feature = "col2row")]
+    let debouncer = MatrixDebouncer::<ROW, COL>::new(keyboard_config.debounce_config);
+    #[cfg(not(feature = "col2row"))]
+    let debouncer = MatrixDebouncer::<COL, ROW>::new(keyboard_config.debounce_config);
 
     // Keyboard matrix, use COL2ROW by default
     #[cfg(feature = "col2row")]
     let matrix = Matrix::<_, _, _, ROW, COL>::new(input_pins, output_pins, debouncer);
     #[cfg(not(feature = "col2row"))]
     let matrix = Matrix::<_, _, _, COL, ROW>::new(input_pins, output_pins, debouncer);
+    let matrix = matrix.with_transform(keyboard_config.matrix_transform);
 
     run_rmk_with_async_flash_and_matrix(
         matrix,
@@ -286,16 +338,35 @@ pub async fn initialize_usb_keyboard_and_run<
 
     keyboard_config: RmkConfig<'static, Out>,
 ) -> ! {
+    safe_mode::check_safe_mode(&mut matrix, keyboard_config.storage_config.safe_mode_key).await;
+
     // Initialize storage and keymap
     // For USB keyboard, the "external" storage means the storage initialized by the user.
     #[cfg(any(feature = "_nrf_ble", not(feature = "_no_external_storage")))]
     let (mut storage, keymap) = {
         let mut s = Storage::new(flash, default_keymap, keyboard_config.storage_config).await;
-        let keymap = RefCell::new(KeyMap::new_from_storage(default_keymap, Some(&mut s)).await);
+        let keymap = RefCell::new(
+            KeyMap::new_from_storage(
+                default_keymap,
+                keyboard_config.encoder_map,
+                keyboard_config.macros,
+                keyboard_config.key_remap,
+                Some(&mut s),
+            )
+            .await,
+        );
         (s, keymap)
     };
     #[cfg(all(not(feature = "_nrf_ble"), feature = "_no_external_storage"))]
-    let keymap = RefCell::new(KeyMap::<ROW, COL, NUM_LAYER>::new(default_keymap).await);
+    let keymap = RefCell::new(
+        KeyMap::<ROW, COL, NUM_LAYER>::new(
+            default_keymap,
+            keyboard_config.encoder_map,
+            keyboard_config.macros,
+            keyboard_config.key_remap,
+        )
+        .await,
+    );
 
     let keyboard_report_sender = KEYBOARD_REPORT_CHANNEL.sender();
     let keyboard_report_receiver = KEYBOARD_REPORT_CHANNEL.receiver();
@@ -306,6 +377,7 @@ pub async fn initialize_usb_keyboard_and_run<
             &keymap,
             &keyboard_report_sender,
             keyboard_config.behavior_config,
+            keyboard_config.mouse_config,
         ),
         KeyboardUsbDevice::new(usb_driver, keyboard_config.usb_config),
         VialService::new(&keymap, keyboard_config.vial_config),
@@ -359,7 +431,7 @@ pub(crate) async fn run_usb_keyboard<
 ) -> ! {
     loop {
         CONNECTION_STATE.store(false, core::sync::atomic::Ordering::Release);
-        let usb_fut = usb_device.device.run();
+        let usb_fut = usb::run_usb_device(&mut usb_device.device);
         let keyboard_fut = keyboard.run();
         let matrix_fut = matrix.run();
         let communication_fut = communication_task(
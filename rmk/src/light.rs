@@ -1,24 +1,60 @@
 use crate::config::{LightConfig, LightPinConfig};
 use crate::hid::HidReaderWrapper;
+use crate::indicator::set_host_leds;
+use crate::power::active_power_settings;
 use bitfield_struct::bitfield;
-use embassy_futures::select::select;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
 use embedded_hal::digital::{Error, OutputPin, PinState};
 
 pub(crate) static LED_CHANNEL: Channel<CriticalSectionRawMutex, LedIndicator, 8> = Channel::new();
 
+/// Upper limit of LED brightness, 255 means no limit.
+/// It's lowered by the thermal guard when the board is too hot.
+pub(crate) static BRIGHTNESS_LIMIT: AtomicU8 = AtomicU8::new(u8::MAX);
+
+// Signaled when the brightness limit is changed, so that lock LEDs are updated without a new host LED report
+static BRIGHTNESS_LIMIT_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Set the brightness limit, returns the previous limit
+pub(crate) fn update_brightness_limit(limit: u8) -> u8 {
+    let last_limit = BRIGHTNESS_LIMIT.swap(limit, Ordering::Relaxed);
+    if limit != last_limit {
+        BRIGHTNESS_LIMIT_CHANGED.signal(());
+    }
+    last_limit
+}
+
+/// Scale the given brightness by current brightness limit and the LED budget of the active power profile
+pub(crate) fn limit_brightness(brightness: u8) -> u8 {
+    let limit = BRIGHTNESS_LIMIT
+        .load(Ordering::Relaxed)
+        .min(active_power_settings().led_brightness_limit);
+    ((brightness as u16 * limit as u16) / u8::MAX as u16) as u8
+}
+
 /// LED control task
 pub(crate) async fn led_service_task<P: OutputPin>(light_service: &mut LightService<P>) {
+    let mut led_indicator = LedIndicator::new();
     loop {
-        let led_indicator = LED_CHANNEL.receive().await;
+        match select(LED_CHANNEL.receive(), BRIGHTNESS_LIMIT_CHANGED.wait()).await {
+            Either::First(indicator) => {
+                led_indicator = indicator;
+                // The host LED state is tracked for indicators even if there's no lock LED
+                set_host_leds(led_indicator);
+            }
+            // Apply the new brightness limit to the current LED state
+            Either::Second(_) => (),
+        }
         if light_service.enabled {
             if let Err(e) = light_service.set_leds(led_indicator) {
                 error!("Set led error {:?}", e.kind());
                 // If there's an error, wait for a while
                 embassy_time::Timer::after_millis(500).await;
             }
-        } else {
-            warn!("Led service is not enabled but led_service_task is started, this should not happen!");
         }
     }
 }
@@ -48,35 +84,30 @@ pub(crate) async fn led_hid_task<R: HidReaderWrapper, Out: OutputPin>(
     keyboard_hid_reader: &mut R,
     light_service: &mut LightService<Out>,
 ) {
-    if !light_service.enabled {
-        loop {
-            embassy_time::Timer::after_secs(u32::MAX as u64).await;
-        }
-    } else {
-        select(
-            hid_read_led(keyboard_hid_reader),
-            led_service_task(light_service),
-        )
-        .await;
-    }
+    select(
+        hid_read_led(keyboard_hid_reader),
+        led_service_task(light_service),
+    )
+    .await;
 }
 
+/// Lock LEDs in the keyboard output report of the host
 #[bitfield(u8)]
 #[derive(Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) struct LedIndicator {
+pub struct LedIndicator {
     #[bits(1)]
-    numslock: bool,
+    pub numslock: bool,
     #[bits(1)]
-    capslock: bool,
+    pub capslock: bool,
     #[bits(1)]
-    scrolllock: bool,
+    pub scrolllock: bool,
     #[bits(1)]
-    compose: bool,
+    pub compose: bool,
     #[bits(1)]
-    kana: bool,
+    pub kana: bool,
     #[bits(1)]
-    shift: bool,
+    pub shift: bool,
     #[bits(2)]
     _reserved: u8,
 }
@@ -145,12 +176,12 @@ pub(crate) struct LightService<P: OutputPin> {
     numslock: Option<SingleLED<P>>,
 }
 
-// Implement on/off function for LightService
+// Implement on/off function for LightService, LEDs are kept off while the brightness is limited to 0
 macro_rules! impl_led_on_off {
     ($n:ident, $fn_name:ident) => {
         pub(crate) fn $fn_name(&mut self, state: bool) -> Result<(), P::Error> {
             if let Some(led) = &mut self.$n {
-                if state {
+                if state && limit_brightness(led.brightness) > 0 {
                     led.on()?
                 } else {
                     led.off()?
@@ -1,5 +1,6 @@
 use crate::{
-    debounce::{DebounceState, DebouncerTrait},
+    debounce::{DebounceState, Debouncer},
+    diagnostic::{key_tester_active, record_pin_state},
     event::KeyEvent,
     keyboard::KEY_EVENT_CHANNEL,
     CONNECTION_STATE,
@@ -71,6 +72,8 @@ pub struct KeyState {
     pub pressed: bool,
     // True if the key's state is just changed
     // pub changed: bool,
+    // Travel of analog keys in 0.01mm, it's always 0 for switches of digital matrices, see `AnalogMatrix`
+    pub travel: u16,
 }
 
 impl Default for KeyState {
@@ -81,7 +84,10 @@ impl Default for KeyState {
 
 impl KeyState {
     pub fn new() -> Self {
-        KeyState { pressed: false }
+        KeyState {
+            pressed: false,
+            travel: 0,
+        }
     }
 
     pub fn toggle_pressed(&mut self) {
@@ -97,12 +103,67 @@ impl KeyState {
     }
 }
 
+/// Transform of key positions reported by a matrix, which is applied by the scanner before key events reach the keymap.
+///
+/// For example, a PCB of the left half can be reused as the right half of a split keyboard by mirroring it.
+/// Rows and columns are swapped first, then flipped, so that a swapped matrix has `COL` rows and `ROW` columns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MatrixTransform {
+    /// Swap rows and columns
+    pub swap: bool,
+    /// Reverse the order of rows
+    pub flip_rows: bool,
+    /// Reverse the order of columns
+    pub flip_cols: bool,
+}
+
+impl MatrixTransform {
+    /// Positions are not changed
+    pub const IDENTITY: Self = Self {
+        swap: false,
+        flip_rows: false,
+        flip_cols: false,
+    };
+    /// Mirror the matrix horizontally, the order of columns is reversed
+    pub const MIRROR: Self = Self {
+        swap: false,
+        flip_rows: false,
+        flip_cols: true,
+    };
+    /// Rotate the matrix by 180 degrees
+    pub const ROTATE_180: Self = Self {
+        swap: false,
+        flip_rows: true,
+        flip_cols: true,
+    };
+    /// Swap rows and columns
+    pub const SWAP: Self = Self {
+        swap: true,
+        flip_rows: false,
+        flip_cols: false,
+    };
+
+    /// Transform the position of a key in a matrix of `rows` × `cols`
+    pub fn apply(&self, row: usize, col: usize, rows: usize, cols: usize) -> (usize, usize) {
+        let (row, col, rows, cols) = if self.swap {
+            (col, row, cols, rows)
+        } else {
+            (row, col, rows, cols)
+        };
+        (
+            if self.flip_rows { rows - 1 - row } else { row },
+            if self.flip_cols { cols - 1 - col } else { col },
+        )
+    }
+}
+
 /// Matrix is the physical pcb layout of the keyboard matrix.
 pub struct Matrix<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
     Out: OutputPin,
-    D: DebouncerTrait,
+    D: Debouncer,
     const INPUT_PIN_NUM: usize,
     const OUTPUT_PIN_NUM: usize,
 > {
@@ -116,13 +177,15 @@ pub struct Matrix<
     key_states: [[KeyState; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
     /// Start scanning
     scan_start: Option<Instant>,
+    /// Transform of reported key positions
+    transform: MatrixTransform,
 }
 
 impl<
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
         Out: OutputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
     > Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
@@ -139,15 +202,22 @@ impl<
             debouncer,
             key_states: [[KeyState::new(); INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
             scan_start: None,
+            transform: MatrixTransform::IDENTITY,
         }
     }
+
+    /// Transform the positions of key events, see [`MatrixTransform`]
+    pub fn with_transform(mut self, transform: MatrixTransform) -> Self {
+        self.transform = transform;
+        self
+    }
 }
 
 impl<
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
         Out: OutputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
     > MatrixTrait for Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
@@ -204,23 +274,33 @@ impl<
                 out_pin.set_high().ok();
                 Timer::after_micros(1).await;
                 for (in_idx, in_pin) in self.input_pins.iter_mut().enumerate() {
+                    #[cfg(feature = "col2row")]
+                    let (row, col) = (in_idx, out_idx);
+                    #[cfg(not(feature = "col2row"))]
+                    let (row, col) = (out_idx, in_idx);
+                    let (row, col) = self.transform.apply(row, col, Self::ROW, Self::COL);
+
                     // Check input pins and debounce
+                    let pin_state = in_pin.is_high().ok().unwrap_or_default();
+                    if key_tester_active() {
+                        record_pin_state(
+                            row as u8,
+                            col as u8,
+                            pin_state,
+                            self.key_states[out_idx][in_idx].pressed,
+                        );
+                    }
                     let debounce_state = self.debouncer.detect_change_with_debounce(
                         in_idx,
                         out_idx,
-                        in_pin.is_high().ok().unwrap_or_default(),
+                        pin_state,
                         &self.key_states[out_idx][in_idx],
                     );
 
                     match debounce_state {
                         DebounceState::Debounced => {
                             self.key_states[out_idx][in_idx].toggle_pressed();
-                            #[cfg(feature = "col2row")]
-                            let (row, col, key_state) =
-                                (in_idx, out_idx, self.key_states[out_idx][in_idx]);
-                            #[cfg(not(feature = "col2row"))]
-                            let (row, col, key_state) =
-                                (out_idx, in_idx, self.key_states[out_idx][in_idx]);
+                            let key_state = self.key_states[out_idx][in_idx];
 
                             KEY_EVENT_CHANNEL
                                 .send(KeyEvent {
@@ -242,7 +322,7 @@ impl<
                 out_pin.set_low().ok();
             }
 
-            embassy_time::Timer::after_micros(100).await;
+            embassy_time::Timer::after(crate::power::scan_interval()).await;
         }
     }
 
@@ -267,3 +347,23 @@ impl<
         f(&mut self.key_states[row][col]);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matrix_transform() {
+        assert_eq!(MatrixTransform::IDENTITY.apply(1, 2, 4, 6), (1, 2));
+        assert_eq!(MatrixTransform::MIRROR.apply(1, 2, 4, 6), (1, 3));
+        assert_eq!(MatrixTransform::ROTATE_180.apply(1, 2, 4, 6), (2, 3));
+        assert_eq!(MatrixTransform::SWAP.apply(1, 2, 4, 6), (2, 1));
+        let transform = MatrixTransform {
+            swap: true,
+            flip_rows: true,
+            flip_cols: false,
+        };
+        // The swapped matrix has 6 rows
+        assert_eq!(transform.apply(1, 2, 4, 6), (3, 1));
+    }
+}
@@ -0,0 +1,268 @@
+//! Mouse keys, moving the cursor and scrolling from the keymap
+//!
+//! Movement and wheel keys only update the held keys here, [`run_mouse_keys`] sends mouse reports repeatedly while they're held.
+//! The first report is sent as soon as a key is pressed, then one report every `mouse_key_interval`, or `mouse_wheel_interval` for the wheel.
+//! The movement of each report starts at `move_delta`, and grows to `max_move_delta` along the [`MouseKeyCurve`] after `move_delay`.
+//! While `MouseAccel0`, `MouseAccel1` or `MouseAccel2` is held, the speed is fixed to slow, medium or max speed instead.
+//!
+//! Mouse buttons are sent by the keyboard directly, they're not repeated.
+//! The movement is recorded to dynamic macros as pointer motion, scrolling isn't recorded.
+
+use core::cell::Cell;
+use core::sync::atomic::Ordering;
+
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::config::{MouseConfig, MouseKeyCurve};
+use crate::keyboard::{KeyboardReportMessage, MOUSE_BUTTONS, POINTER_REPORT_CHANNEL};
+use crate::keyboard_macro::record_pointer_motion;
+use crate::keycode::KeyCode;
+use crate::scroll::{apply_scroll_direction, ScrollDevice};
+use crate::usb::descriptor::{CompositeReport, CompositeReportType};
+
+/// Bits of `MouseUp`, `MouseDown`, `MouseLeft` and `MouseRight`
+const MOVE_KEYS: u32 = 0b1111;
+/// Bits of `MouseWheelUp`, `MouseWheelDown`, `MouseWheelLeft` and `MouseWheelRight`
+const WHEEL_KEYS: u32 = 0b1111 << 12;
+
+#[derive(Clone, Copy, Default)]
+struct MouseKeyState {
+    /// Held mouse keys, bit n is the n-th keycode from `MouseUp`
+    held: u32,
+    /// Keys pressed since the last report, so that a quick tap still moves once
+    tapped: u32,
+    /// When the cursor started moving
+    move_since: Option<Instant>,
+    /// When the wheel started scrolling
+    wheel_since: Option<Instant>,
+}
+
+static MOUSE_KEYS: Mutex<CriticalSectionRawMutex, Cell<MouseKeyState>> =
+    Mutex::new(Cell::new(MouseKeyState {
+        held: 0,
+        tapped: 0,
+        move_since: None,
+        wheel_since: None,
+    }));
+
+/// Signaled when a mouse key is pressed or released
+static MOUSE_KEY_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn bit(key: KeyCode) -> u32 {
+    1 << (key as u16 - KeyCode::MouseUp as u16)
+}
+
+/// A movement, wheel or acceleration key is pressed
+pub(crate) fn press(key: KeyCode) {
+    let now = Instant::now();
+    MOUSE_KEYS.lock(|s| {
+        let mut state = s.get();
+        state.held |= bit(key);
+        state.tapped |= bit(key);
+        if state.held & MOVE_KEYS != 0 && state.move_since.is_none() {
+            state.move_since = Some(now);
+        }
+        if state.held & WHEEL_KEYS != 0 && state.wheel_since.is_none() {
+            state.wheel_since = Some(now);
+        }
+        s.set(state);
+    });
+    MOUSE_KEY_CHANGED.signal(());
+}
+
+/// A movement, wheel or acceleration key is released
+pub(crate) fn release(key: KeyCode) {
+    MOUSE_KEYS.lock(|s| {
+        let mut state = s.get();
+        state.held &= !bit(key);
+        if state.held & MOVE_KEYS == 0 {
+            state.move_since = None;
+        }
+        if state.held & WHEEL_KEYS == 0 {
+            state.wheel_since = None;
+        }
+        s.set(state);
+    });
+    MOUSE_KEY_CHANGED.signal(());
+}
+
+/// Stop all movement and scrolling
+pub(crate) fn release_all() {
+    MOUSE_KEYS.lock(|s| s.set(MouseKeyState::default()));
+    MOUSE_KEY_CHANGED.signal(());
+}
+
+/// Direction of an axis, -1, 0 or 1
+fn axis(keys: u32, negative: KeyCode, positive: KeyCode) -> i8 {
+    (keys & bit(positive) != 0) as i8 - (keys & bit(negative) != 0) as i8
+}
+
+/// Fixed speed while an acceleration key is held
+fn fixed_speed(keys: u32, delta: u8, max: u8) -> Option<u8> {
+    if keys & bit(KeyCode::MouseAccel2) != 0 {
+        Some(max)
+    } else if keys & bit(KeyCode::MouseAccel1) != 0 {
+        Some(((delta as u16 + max as u16) / 2) as u8)
+    } else if keys & bit(KeyCode::MouseAccel0) != 0 {
+        Some((delta / 2).max(1))
+    } else {
+        None
+    }
+}
+
+/// Speed after the key is held for `held`, it grows from `delta` to `max` during `time_to_max` after `delay`
+fn accelerated(
+    delta: u8,
+    max: u8,
+    delay: u32,
+    time_to_max: u32,
+    curve: MouseKeyCurve,
+    held: Duration,
+) -> u8 {
+    let held = held.as_millis();
+    if held <= delay as u64 || max <= delta {
+        return delta;
+    }
+    let t = held - delay as u64;
+    if t >= time_to_max as u64 {
+        return max;
+    }
+    let range = (max - delta) as u64;
+    let time_to_max = time_to_max as u64;
+    let increase = match curve {
+        MouseKeyCurve::Linear => range * t / time_to_max,
+        MouseKeyCurve::Quadratic => range * t * t / (time_to_max * time_to_max),
+    };
+    delta + increase as u8
+}
+
+async fn send_report(report: CompositeReport) {
+    POINTER_REPORT_CHANNEL
+        .send(KeyboardReportMessage::CompositeReport(
+            report,
+            CompositeReportType::Mouse,
+        ))
+        .await;
+}
+
+/// Send mouse reports while movement or wheel keys are held
+pub(crate) async fn run_mouse_keys(config: MouseConfig) {
+    let mut next_move: Option<Instant> = None;
+    let mut next_wheel: Option<Instant> = None;
+    loop {
+        let now = Instant::now();
+        let state = MOUSE_KEYS.lock(|s| s.get());
+        let keys = state.held | state.tapped;
+        let mut reported = 0;
+
+        let x = axis(keys, KeyCode::MouseLeft, KeyCode::MouseRight);
+        let y = axis(keys, KeyCode::MouseUp, KeyCode::MouseDown);
+        if x == 0 && y == 0 {
+            next_move = None;
+        } else if next_move.is_none_or(|t| now >= t) {
+            let held = now.saturating_duration_since(state.move_since.unwrap_or(now));
+            let mut speed = fixed_speed(keys, config.move_delta, config.max_move_delta)
+                .unwrap_or_else(|| {
+                    accelerated(
+                        config.move_delta,
+                        config.max_move_delta,
+                        config.move_delay,
+                        config.move_time_to_max,
+                        config.curve,
+                        held,
+                    )
+                });
+            if x != 0 && y != 0 {
+                // Keep the speed of diagonal movement, 181 / 256 is about 1 / sqrt(2)
+                speed = ((speed as u16 * 181 / 256) as u8).max(1);
+            }
+            let speed = speed.min(i8::MAX as u8) as i8;
+            record_pointer_motion((x * speed) as i16, (y * speed) as i16);
+            send_report(CompositeReport {
+                buttons: MOUSE_BUTTONS.load(Ordering::Relaxed),
+                x: x * speed,
+                y: y * speed,
+                ..Default::default()
+            })
+            .await;
+            reported |= MOVE_KEYS;
+            next_move = Some(now + Duration::from_millis(config.mouse_key_interval as u64));
+        }
+
+        let wheel = axis(keys, KeyCode::MouseWheelDown, KeyCode::MouseWheelUp);
+        let pan = axis(keys, KeyCode::MouseWheelLeft, KeyCode::MouseWheelRight);
+        if wheel == 0 && pan == 0 {
+            next_wheel = None;
+        } else if next_wheel.is_none_or(|t| now >= t) {
+            let held = now.saturating_duration_since(state.wheel_since.unwrap_or(now));
+            let speed = fixed_speed(keys, config.wheel_delta, config.max_wheel_delta)
+                .unwrap_or_else(|| {
+                    accelerated(
+                        config.wheel_delta,
+                        config.max_wheel_delta,
+                        config.wheel_delay,
+                        config.wheel_time_to_max,
+                        config.curve,
+                        held,
+                    )
+                });
+            let speed = speed.min(i8::MAX as u8) as i8;
+            let (wheel, pan) =
+                apply_scroll_direction(ScrollDevice::Keys, wheel * speed, pan * speed);
+            send_report(CompositeReport {
+                buttons: MOUSE_BUTTONS.load(Ordering::Relaxed),
+                wheel,
+                pan,
+                ..Default::default()
+            })
+            .await;
+            reported |= WHEEL_KEYS;
+            next_wheel = Some(now + Duration::from_millis(config.mouse_wheel_interval as u64));
+        }
+
+        if reported != 0 {
+            MOUSE_KEYS.lock(|s| {
+                let mut state = s.get();
+                state.tapped &= !reported;
+                s.set(state);
+            });
+        }
+
+        let next = match (next_move, next_wheel) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        match next {
+            Some(t) => {
+                select(MOUSE_KEY_CHANGED.wait(), Timer::at(t)).await;
+            }
+            None => MOUSE_KEY_CHANGED.wait().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mouse_key_speed() {
+        let speed = |curve, ms| accelerated(8, 40, 200, 1000, curve, Duration::from_millis(ms));
+        assert_eq!(speed(MouseKeyCurve::Linear, 0), 8);
+        assert_eq!(speed(MouseKeyCurve::Linear, 200), 8);
+        assert_eq!(speed(MouseKeyCurve::Linear, 700), 24);
+        assert_eq!(speed(MouseKeyCurve::Quadratic, 700), 16);
+        assert_eq!(speed(MouseKeyCurve::Linear, 1200), 40);
+        assert_eq!(speed(MouseKeyCurve::Quadratic, 5000), 40);
+
+        let keys = bit(KeyCode::MouseUp) | bit(KeyCode::MouseAccel0);
+        assert_eq!(fixed_speed(keys, 8, 40), Some(4));
+        assert_eq!(fixed_speed(bit(KeyCode::MouseAccel1), 8, 40), Some(24));
+        assert_eq!(fixed_speed(bit(KeyCode::MouseUp), 8, 40), None);
+        assert_eq!(axis(keys, KeyCode::MouseUp, KeyCode::MouseDown), -1);
+    }
+}
@@ -0,0 +1,75 @@
+//! Output selection of boards which support both USB and BLE
+//!
+//! By default the output is selected automatically: USB is used whenever it's connected, BLE otherwise.
+//! The output can be forced to USB or BLE by `User22` and `User23`, `User21` goes back to automatic selection,
+//! and `User11` toggles between BLE and automatic selection. The selected mode is saved to the storage.
+//!
+//! Before the output is changed, all held keys are released on the current host.
+//! After the new host is connected, keys which are still held are sent to it, so no key is dropped or stuck.
+//!
+//! In automatic selection, the output is switched to BLE as soon as USB is unplugged, which is published to
+//! [`crate::power::POWER_SOURCE_CHANNEL`], without waiting for USB to be suspended.
+
+use core::sync::atomic::Ordering;
+
+use crate::CONNECTION_TYPE;
+
+/// How the output is selected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum OutputMode {
+    /// Use USB when it's connected, BLE otherwise
+    Auto = 0,
+    /// Use BLE even when USB is connected
+    Ble = 1,
+    /// Use USB only, BLE isn't advertised
+    Usb = 2,
+}
+
+impl From<u8> for OutputMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => OutputMode::Ble,
+            2 => OutputMode::Usb,
+            _ => OutputMode::Auto,
+        }
+    }
+}
+
+/// Current output mode
+pub fn output_mode() -> OutputMode {
+    CONNECTION_TYPE.load(Ordering::Relaxed).into()
+}
+
+/// Wait until the USB output should be left, when USB is suspended, or unplugged
+#[cfg(all(feature = "_nrf_ble", not(feature = "_no_usb")))]
+pub(crate) async fn wait_for_usb_output_end() {
+    use embassy_futures::select::select;
+
+    use crate::{
+        power::{PowerSource, POWER_SOURCE_CHANNEL},
+        usb::wait_for_usb_suspend,
+    };
+
+    let unplugged = async {
+        match POWER_SOURCE_CHANNEL.subscriber() {
+            Ok(mut subscriber) => {
+                while subscriber.next_message_pure().await != PowerSource::Battery {}
+                info!("USB is unplugged, leave USB output");
+            }
+            // Only the USB state is checked then
+            Err(_) => core::future::pending().await,
+        }
+    };
+    select(wait_for_usb_suspend(), unplugged).await;
+}
+
+/// Set the output mode, it's applied by the connection loop and saved to the storage
+#[cfg(feature = "_nrf_ble")]
+pub(crate) async fn set_output_mode(mode: OutputMode) {
+    use crate::ble::nrf::profile::{BleProfileAction, BLE_PROFILE_CHANNEL};
+    BLE_PROFILE_CHANNEL
+        .send(BleProfileAction::SetOutput(mode))
+        .await;
+}
@@ -0,0 +1,232 @@
+//! Pomodoro timer
+//!
+//! The timer runs on the keyboard, no host software is needed. Work sessions and breaks alternate,
+//! every `long_break_interval` work sessions are followed by a long break instead of a short one.
+//!
+//! Press a key with `User17` to start or pause the timer, and `User18` to reset it.
+//! The remaining time is shown on the stats page of the display, and on the LEDs of `timer_gauge` in [`RGBLightConfig`](crate::config::RGBLightConfig).
+//! When a session is done, all LEDs flash and the [`Alarm`] rings, for example a buzzer or a vibration motor.
+
+use core::cell::Cell;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+
+use crate::config::PomodoroConfig;
+
+/// How long all LEDs flash when a session is done
+pub(crate) const ALARM_FLASH_DURATION: Duration = Duration::from_secs(3);
+
+/// Phase of the pomodoro timer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PomodoroPhase {
+    /// The timer isn't started
+    #[default]
+    Idle,
+    Work,
+    ShortBreak,
+    LongBreak,
+}
+
+/// Snapshot of the pomodoro timer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PomodoroStatus {
+    pub phase: PomodoroPhase,
+    /// Whether the timer is counting down, `false` if it's paused
+    pub running: bool,
+    /// Remaining time of the current phase
+    pub remaining: Duration,
+    /// Total time of the current phase
+    pub total: Duration,
+    /// Number of completed work sessions since the timer is reset
+    pub completed: u8,
+}
+
+#[derive(Clone, Copy)]
+enum PomodoroCommand {
+    StartPause,
+    Reset,
+}
+
+#[derive(Clone, Copy, Default)]
+struct PomodoroState {
+    status: PomodoroStatus,
+    /// When the current phase ends, `None` if the timer isn't running
+    ends_at: Option<Instant>,
+    /// When the last alarm is rung
+    alarm_at: Option<Instant>,
+}
+
+static POMODORO: Mutex<CriticalSectionRawMutex, Cell<PomodoroState>> =
+    Mutex::new(Cell::new(PomodoroState {
+        status: PomodoroStatus {
+            phase: PomodoroPhase::Idle,
+            running: false,
+            remaining: Duration::from_ticks(0),
+            total: Duration::from_ticks(0),
+            completed: 0,
+        },
+        ends_at: None,
+        alarm_at: None,
+    }));
+
+static POMODORO_COMMAND: Signal<CriticalSectionRawMutex, PomodoroCommand> = Signal::new();
+
+/// Alarm which rings when a session is done
+pub trait Alarm {
+    async fn ring(&mut self);
+}
+
+/// No alarm, only LEDs flash
+impl Alarm for () {
+    async fn ring(&mut self) {}
+}
+
+/// Alarm driven by an output pin, such as an active buzzer or a vibration motor.
+///
+/// The pin is set high for `duration` for `times` times, with the same interval between them.
+pub struct PinAlarm<P: OutputPin> {
+    pin: P,
+    duration: Duration,
+    times: u8,
+}
+
+impl<P: OutputPin> PinAlarm<P> {
+    pub fn new(pin: P, duration: Duration, times: u8) -> Self {
+        Self {
+            pin,
+            duration,
+            times,
+        }
+    }
+}
+
+impl<P: OutputPin> Alarm for PinAlarm<P> {
+    async fn ring(&mut self) {
+        for _ in 0..self.times {
+            self.pin.set_high().ok();
+            Timer::after(self.duration).await;
+            self.pin.set_low().ok();
+            Timer::after(self.duration).await;
+        }
+    }
+}
+
+/// Start the pomodoro timer, or pause/resume it if it's started
+pub fn start_pause_pomodoro() {
+    POMODORO_COMMAND.signal(PomodoroCommand::StartPause);
+}
+
+/// Stop the pomodoro timer and clear completed sessions
+pub fn reset_pomodoro() {
+    POMODORO_COMMAND.signal(PomodoroCommand::Reset);
+}
+
+/// Get current status of the pomodoro timer
+pub fn pomodoro_status() -> PomodoroStatus {
+    let state = POMODORO.lock(|p| p.get());
+    let mut status = state.status;
+    if let Some(ends_at) = state.ends_at {
+        status.remaining = ends_at.saturating_duration_since(Instant::now());
+    }
+    status
+}
+
+/// Whether the alarm of a completed session is being shown on the LEDs
+pub(crate) fn pomodoro_alarm_active() -> bool {
+    POMODORO
+        .lock(|p| p.get().alarm_at)
+        .is_some_and(|t| t.elapsed() < ALARM_FLASH_DURATION)
+}
+
+fn update_state(f: impl FnOnce(&mut PomodoroState)) {
+    POMODORO.lock(|p| {
+        let mut state = p.get();
+        f(&mut state);
+        p.set(state);
+    });
+    crate::display::request_redraw();
+}
+
+/// Prepare the next phase after `phase` is done, the next phase is paused
+fn next_phase(state: &mut PomodoroState, config: &PomodoroConfig) {
+    let status = &mut state.status;
+    let (phase, total) = match status.phase {
+        PomodoroPhase::Work => {
+            status.completed = status.completed.saturating_add(1);
+            if config.long_break_interval > 0 && status.completed % config.long_break_interval == 0
+            {
+                (PomodoroPhase::LongBreak, config.long_break)
+            } else {
+                (PomodoroPhase::ShortBreak, config.short_break)
+            }
+        }
+        _ => (PomodoroPhase::Work, config.work),
+    };
+    status.phase = phase;
+    status.total = total;
+    status.remaining = total;
+    status.running = false;
+    state.ends_at = None;
+}
+
+/// Run the pomodoro timer, this function never returns.
+///
+/// `alarm` rings when a session is done, use `()` if there's no buzzer or vibration motor.
+pub async fn run_pomodoro<A: Alarm>(mut alarm: A, config: PomodoroConfig) -> ! {
+    loop {
+        let ends_at = POMODORO.lock(|p| p.get().ends_at);
+        let timeout = async {
+            match ends_at {
+                Some(t) => Timer::at(t).await,
+                None => core::future::pending().await,
+            }
+        };
+        match select(POMODORO_COMMAND.wait(), timeout).await {
+            Either::First(PomodoroCommand::StartPause) => update_state(|state| {
+                let status = &mut state.status;
+                if status.phase == PomodoroPhase::Idle {
+                    status.phase = PomodoroPhase::Work;
+                    status.total = config.work;
+                    status.remaining = config.work;
+                }
+                match state.ends_at.take() {
+                    Some(ends_at) => {
+                        // Pause
+                        status.remaining = ends_at.saturating_duration_since(Instant::now());
+                        status.running = false;
+                    }
+                    None => {
+                        // Start or resume
+                        state.ends_at = Some(Instant::now() + status.remaining);
+                        status.running = true;
+                    }
+                }
+                info!("Pomodoro {:?}, running: {}", status.phase, status.running);
+            }),
+            Either::First(PomodoroCommand::Reset) => {
+                info!("Pomodoro is reset");
+                update_state(|state| *state = PomodoroState::default());
+            }
+            Either::Second(_) => {
+                update_state(|state| {
+                    info!("Pomodoro {:?} is done", state.status.phase);
+                    next_phase(state, &config);
+                    state.alarm_at = Some(Instant::now());
+                    if config.auto_start {
+                        state.ends_at = Some(Instant::now() + state.status.remaining);
+                        state.status.running = true;
+                    }
+                });
+                alarm.ring().await;
+            }
+        }
+    }
+}
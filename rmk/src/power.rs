@@ -0,0 +1,607 @@
+//! Power source detection
+//!
+//! RMK tracks whether the keyboard is powered by USB(VBUS present) or by battery.
+//! VBUS can be detected either by the USB peripheral, or by a dedicated pin, see [`VbusDetect`].
+//! Every change of power source is published to [`POWER_SOURCE_CHANNEL`],
+//! so that other services like output selection, lighting and sleep can adjust their behavior.
+//!
+//! Battery level is sampled by a [`BatteryAdc`] and converted by the discharge curve of the battery, or read from a [`FuelGauge`].
+//! A hook can be set by [`set_low_battery_hook`] to indicate low battery, and the level is reported to BLE hosts by the Battery Service.
+//!
+//! Scan rate, BLE connection interval, LED brightness and display timeout are adjusted together by [`PowerProfile`].
+//! The saver profile is selected automatically when the battery is low.
+//!
+//! The keyboard can be powered down to deep sleep by holding a `SoftOff` key, see [`SOFT_OFF_SIGNAL`].
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    pubsub::PubSubChannel,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::InputPin;
+
+use crate::{config::SoftOffConfig, storage::POWER_LOSS_SIGNAL};
+
+/// Max number of subscribers of power source events
+pub const POWER_SOURCE_SUBSCRIBERS: usize = 4;
+
+/// Channel which publishes power source changes
+pub static POWER_SOURCE_CHANNEL: PubSubChannel<
+    CriticalSectionRawMutex,
+    PowerSource,
+    2,
+    POWER_SOURCE_SUBSCRIBERS,
+    2,
+> = PubSubChannel::new();
+
+static POWER_SOURCE: AtomicU8 = AtomicU8::new(PowerSource::Battery as u8);
+
+// Battery level in percent, `BATTERY_LEVEL_UNKNOWN` if it's not sampled yet
+static BATTERY_LEVEL: AtomicU8 = AtomicU8::new(BATTERY_LEVEL_UNKNOWN);
+const BATTERY_LEVEL_UNKNOWN: u8 = 0xFF;
+
+/// Signaled when the battery level is changed, it's used to notify the BLE host
+pub(crate) static BATTERY_LEVEL_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Called once when the battery level drops to `LOW_BATTERY_THRESHOLD`
+static LOW_BATTERY_HOOK: Mutex<CriticalSectionRawMutex, Cell<Option<fn(u8)>>> =
+    Mutex::new(Cell::new(None));
+
+// Whether the low battery hook is called, it's called again after the battery is charged above `LOW_BATTERY_RECOVER_LEVEL`
+static LOW_BATTERY_NOTIFIED: AtomicBool = AtomicBool::new(false);
+
+static LAST_SHUTDOWN: AtomicU8 = AtomicU8::new(ShutdownReason::Unknown as u8);
+
+/// Power source of the keyboard
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerSource {
+    /// No VBUS detected, the keyboard is powered by battery
+    Battery = 0,
+    /// VBUS detected, the keyboard is powered by USB
+    Usb = 1,
+}
+
+impl From<u8> for PowerSource {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PowerSource::Usb,
+            _ => PowerSource::Battery,
+        }
+    }
+}
+
+/// Get current power source
+pub fn current_power_source() -> PowerSource {
+    POWER_SOURCE.load(Ordering::Acquire).into()
+}
+
+/// Update current power source, publish the change if the power source is changed
+pub(crate) fn update_power_source(vbus_present: bool) {
+    let source = if vbus_present {
+        PowerSource::Usb
+    } else {
+        PowerSource::Battery
+    };
+    if POWER_SOURCE.swap(source as u8, Ordering::AcqRel) != source as u8 {
+        info!("Power source changed: {:?}", source);
+        POWER_SOURCE_CHANNEL
+            .immediate_publisher()
+            .publish_immediate(source);
+        update_low_battery_saver();
+        if source == PowerSource::Usb {
+            notify_power_restored();
+        }
+    }
+}
+
+/// Get the latest sampled battery level in percent, `None` if the battery level is not available
+pub fn battery_level() -> Option<u8> {
+    match BATTERY_LEVEL.load(Ordering::Relaxed) {
+        BATTERY_LEVEL_UNKNOWN => None,
+        level => Some(level),
+    }
+}
+
+/// Update the battery level, in percent
+pub(crate) fn update_battery_level(level: u8) {
+    let level = level.min(100);
+    if BATTERY_LEVEL.swap(level, Ordering::Relaxed) != level {
+        BATTERY_LEVEL_CHANGED.signal(());
+    }
+    if level <= LOW_BATTERY_THRESHOLD {
+        if !LOW_BATTERY_NOTIFIED.swap(true, Ordering::Relaxed) {
+            warn!("Battery is low: {}%", level);
+            if let Some(hook) = LOW_BATTERY_HOOK.lock(|h| h.get()) {
+                hook(level);
+            }
+        }
+    } else if level > LOW_BATTERY_RECOVER_LEVEL {
+        LOW_BATTERY_NOTIFIED.store(false, Ordering::Relaxed);
+    }
+    update_low_battery_saver();
+}
+
+/// Set a function which is called with the battery level when the battery drops to [`LOW_BATTERY_THRESHOLD`],
+/// for example to blink a LED or show a warning on the display.
+///
+/// It's called once until the battery is charged above 20%. It runs in the task which samples the battery, so it shouldn't block.
+pub fn set_low_battery_hook(hook: fn(u8)) {
+    LOW_BATTERY_HOOK.lock(|h| h.set(Some(hook)));
+}
+
+/// Battery level in percent, at or below which the saver profile is selected automatically
+pub const LOW_BATTERY_THRESHOLD: u8 = 15;
+
+/// The automatically selected saver profile is left after the battery is charged above this level
+const LOW_BATTERY_RECOVER_LEVEL: u8 = 20;
+
+// Profile selected by the user
+static POWER_PROFILE: AtomicU8 = AtomicU8::new(PowerProfile::Performance as u8);
+
+// Whether the saver profile is forced because of low battery
+static LOW_BATTERY_SAVER: AtomicBool = AtomicBool::new(false);
+
+/// Signaled when the active power profile is changed or the keyboard sleeps, it's used to update the BLE connection interval
+pub(crate) static POWER_PROFILE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Power profile, which trades responsiveness and lighting for battery life
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerProfile {
+    /// Fastest scanning and shortest connection interval, no limit of LEDs and display
+    Performance = 0,
+    Balanced = 1,
+    /// Slowest scanning, dimmed LEDs and a short display timeout
+    Saver = 2,
+}
+
+impl From<u8> for PowerProfile {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PowerProfile::Balanced,
+            2 => PowerProfile::Saver,
+            _ => PowerProfile::Performance,
+        }
+    }
+}
+
+/// Settings adjusted by a [`PowerProfile`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PowerProfileSettings {
+    /// Delay between two matrix scans
+    pub scan_interval: Duration,
+    /// BLE connection interval, in 1.25ms units
+    pub ble_conn_interval: u16,
+    /// Upper limit of LED brightness, 255 means no limit
+    pub led_brightness_limit: u8,
+    /// The display is turned off when no key is pressed for this duration, `None` keeps the display on
+    pub display_timeout: Option<Duration>,
+}
+
+impl PowerProfile {
+    /// Settings of the profile
+    pub const fn settings(self) -> PowerProfileSettings {
+        match self {
+            PowerProfile::Performance => PowerProfileSettings {
+                scan_interval: Duration::from_micros(100),
+                ble_conn_interval: 6,
+                led_brightness_limit: u8::MAX,
+                display_timeout: None,
+            },
+            PowerProfile::Balanced => PowerProfileSettings {
+                scan_interval: Duration::from_millis(1),
+                ble_conn_interval: 12,
+                led_brightness_limit: 160,
+                display_timeout: Some(Duration::from_secs(60)),
+            },
+            PowerProfile::Saver => PowerProfileSettings {
+                scan_interval: Duration::from_millis(5),
+                ble_conn_interval: 24,
+                led_brightness_limit: 48,
+                display_timeout: Some(Duration::from_secs(10)),
+            },
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            PowerProfile::Performance => PowerProfile::Balanced,
+            PowerProfile::Balanced => PowerProfile::Saver,
+            PowerProfile::Saver => PowerProfile::Performance,
+        }
+    }
+}
+
+/// Get the active power profile, it's the saver profile if the battery is low
+pub fn active_power_profile() -> PowerProfile {
+    if LOW_BATTERY_SAVER.load(Ordering::Relaxed) {
+        PowerProfile::Saver
+    } else {
+        POWER_PROFILE.load(Ordering::Relaxed).into()
+    }
+}
+
+/// Select a power profile.
+///
+/// When the battery is low, the saver profile is kept until the battery is charged, the selected profile is applied after that.
+pub fn set_power_profile(profile: PowerProfile) {
+    let last = active_power_profile();
+    POWER_PROFILE.store(profile as u8, Ordering::Relaxed);
+    info!("Power profile selected: {:?}", profile);
+    if active_power_profile() != last {
+        POWER_PROFILE_CHANGED.signal(());
+    }
+}
+
+/// Select the next power profile, wraps around
+pub fn next_power_profile() {
+    let profile: PowerProfile = POWER_PROFILE.load(Ordering::Relaxed).into();
+    set_power_profile(profile.next());
+}
+
+/// Settings while the keyboard sleeps, see [`crate::sleep`]
+const SLEEP_SETTINGS: PowerProfileSettings = PowerProfileSettings {
+    scan_interval: Duration::from_millis(10),
+    ble_conn_interval: 48,
+    led_brightness_limit: 0,
+    display_timeout: Some(Duration::from_secs(0)),
+};
+
+/// Settings in effect, which are the settings of the active power profile, or the settings of sleep while the keyboard sleeps
+pub fn active_power_settings() -> PowerProfileSettings {
+    if crate::sleep::keyboard_sleeping() {
+        SLEEP_SETTINGS
+    } else {
+        active_power_profile().settings()
+    }
+}
+
+/// Delay between two matrix scans of the active power profile
+pub(crate) fn scan_interval() -> Duration {
+    active_power_settings().scan_interval
+}
+
+/// Force the saver profile when running on a low battery, release it when the battery is charged or USB is plugged
+fn update_low_battery_saver() {
+    let low = match (current_power_source(), battery_level()) {
+        (PowerSource::Usb, _) | (_, None) => false,
+        (PowerSource::Battery, Some(level)) => {
+            if LOW_BATTERY_SAVER.load(Ordering::Relaxed) {
+                level <= LOW_BATTERY_RECOVER_LEVEL
+            } else {
+                level <= LOW_BATTERY_THRESHOLD
+            }
+        }
+    };
+    if LOW_BATTERY_SAVER.swap(low, Ordering::Relaxed) != low {
+        if low {
+            warn!("Battery is low, switch to saver power profile");
+        } else {
+            info!(
+                "Leave low battery saver, power profile: {:?}",
+                active_power_profile()
+            );
+        }
+        POWER_PROFILE_CHANGED.signal(());
+    }
+}
+
+/// VBUS detection abstraction.
+///
+/// On chips whose USB peripheral reports VBUS, RMK updates the power source automatically.
+/// For other chips, or keyboards which wire VBUS to a GPIO, implement this trait(or use [`PinVbusDetect`]) and run it with [`run_vbus_detect`].
+pub trait VbusDetect {
+    /// Returns true if VBUS is present
+    fn is_vbus_present(&mut self) -> bool;
+
+    /// Wait until VBUS state changes, returns the new state
+    async fn wait_for_vbus_change(&mut self) -> bool {
+        let current = self.is_vbus_present();
+        loop {
+            Timer::after_millis(100).await;
+            let present = self.is_vbus_present();
+            if present != current {
+                return present;
+            }
+        }
+    }
+}
+
+/// VBUS detection using a dedicated pin
+pub struct PinVbusDetect<I: InputPin> {
+    pin: I,
+    low_active: bool,
+}
+
+impl<I: InputPin> PinVbusDetect<I> {
+    pub fn new(pin: I, low_active: bool) -> Self {
+        Self { pin, low_active }
+    }
+}
+
+impl<I: InputPin> VbusDetect for PinVbusDetect<I> {
+    fn is_vbus_present(&mut self) -> bool {
+        match self.pin.is_high() {
+            Ok(high) => high != self.low_active,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Run the VBUS detection, update the power source when VBUS changes
+pub async fn run_vbus_detect<D: VbusDetect>(mut detector: D) -> ! {
+    update_power_source(detector.is_vbus_present());
+    loop {
+        let present = detector.wait_for_vbus_change().await;
+        update_power_source(present);
+    }
+}
+
+/// Fuel gauge abstraction, for keyboards which measure the battery by a dedicated chip(e.g. MAX17048) instead of ADC
+pub trait FuelGauge {
+    type Error;
+
+    /// Read the state of charge, in percent
+    async fn state_of_charge(&mut self) -> Result<u8, Self::Error>;
+}
+
+/// Run the fuel gauge, update the battery level every `interval`
+pub async fn run_fuel_gauge<G: FuelGauge>(mut gauge: G, interval: Duration) -> ! {
+    loop {
+        match gauge.state_of_charge().await {
+            Ok(level) => update_battery_level(level),
+            Err(_) => error!("Read fuel gauge error"),
+        }
+        Timer::after(interval).await;
+    }
+}
+
+/// ADC which samples the battery voltage, for keyboards which don't have a fuel gauge
+pub trait BatteryAdc {
+    type Error;
+
+    /// Sample the voltage at the ADC pin, in millivolts
+    async fn read_millivolts(&mut self) -> Result<u16, Self::Error>;
+}
+
+/// Voltage divider between the battery and the ADC pin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VoltageDivider {
+    /// Resistance between the ADC pin and ground
+    pub measured: u32,
+    /// Total resistance of the divider
+    pub total: u32,
+}
+
+impl VoltageDivider {
+    /// The ADC pin is connected to the battery directly
+    pub const NONE: Self = Self::new(1, 1);
+
+    /// For example, nice!nano has 806K + 2M resistors and the ADC measures the voltage on the 2M resistor, it's `new(2000, 2806)`
+    pub const fn new(measured: u32, total: u32) -> Self {
+        Self { measured, total }
+    }
+
+    /// Battery voltage of the voltage at the ADC pin, in millivolts
+    pub fn battery_millivolts(&self, adc_millivolts: u16) -> u16 {
+        (adc_millivolts as u32 * self.total / self.measured.max(1)).min(u16::MAX as u32) as u16
+    }
+}
+
+/// Discharge curve of single cell LiPo batteries, voltage in millivolts and remaining capacity in percent, from full to empty
+pub const LIPO_CURVE: &[(u16, u8)] = &[
+    (4200, 100),
+    (4100, 90),
+    (4000, 80),
+    (3920, 70),
+    (3860, 60),
+    (3820, 50),
+    (3790, 40),
+    (3760, 30),
+    (3730, 20),
+    (3690, 10),
+    (3600, 5),
+    (3300, 0),
+];
+
+/// Convert the battery voltage to percent by a discharge curve, points between the curve are interpolated linearly.
+///
+/// The curve is sorted from full to empty, like [`LIPO_CURVE`].
+pub fn battery_percent(millivolts: u16, curve: &[(u16, u8)]) -> u8 {
+    let Some(&(full_mv, full)) = curve.first() else {
+        return 0;
+    };
+    if millivolts >= full_mv {
+        return full;
+    }
+    for pair in curve.windows(2) {
+        let (high_mv, high) = pair[0];
+        let (low_mv, low) = pair[1];
+        if millivolts >= low_mv {
+            let span = (high_mv - low_mv).max(1) as u32;
+            return low + ((millivolts - low_mv) as u32 * (high - low) as u32 / span) as u8;
+        }
+    }
+    curve.last().map_or(0, |&(_, empty)| empty)
+}
+
+/// Config of [`run_battery_monitor`]
+#[derive(Clone, Copy, Debug)]
+pub struct BatteryMonitorConfig {
+    pub divider: VoltageDivider,
+    /// Discharge curve of the battery, [`LIPO_CURVE`] by default
+    pub curve: &'static [(u16, u8)],
+    /// Interval of sampling, 120s by default
+    pub interval: Duration,
+}
+
+impl Default for BatteryMonitorConfig {
+    fn default() -> Self {
+        Self {
+            divider: VoltageDivider::NONE,
+            curve: LIPO_CURVE,
+            interval: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Number of ADC samples averaged in every sampling, to filter out the noise of scanning and radio
+const BATTERY_SAMPLES: u32 = 4;
+
+/// Run the battery monitor, sample the battery by ADC and update the battery level every `interval` of the config
+pub async fn run_battery_monitor<A: BatteryAdc>(mut adc: A, config: BatteryMonitorConfig) -> ! {
+    loop {
+        let mut sum = 0;
+        let mut samples = 0;
+        for _ in 0..BATTERY_SAMPLES {
+            match adc.read_millivolts().await {
+                Ok(mv) => {
+                    sum += mv as u32;
+                    samples += 1;
+                }
+                Err(_) => error!("Read battery ADC error"),
+            }
+        }
+        if samples > 0 {
+            let millivolts = config.divider.battery_millivolts((sum / samples) as u16);
+            let level = battery_percent(millivolts, config.curve);
+            debug!("Battery voltage: {}mV, level: {}%", millivolts, level);
+            update_battery_level(level);
+        }
+        Timer::after(config.interval).await;
+    }
+}
+
+/// Reason of the last shutdown, read from storage at boot
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ShutdownReason {
+    /// No shutdown marker found, for example the first boot after flashing or storage is disabled
+    Unknown = 0,
+    /// Power loss was detected and pending writes were flushed before shutdown
+    PowerLoss = 1,
+    /// The keyboard stopped without a detected power loss, it might be crashed
+    Crash = 2,
+}
+
+impl From<u8> for ShutdownReason {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ShutdownReason::PowerLoss,
+            2 => ShutdownReason::Crash,
+            _ => ShutdownReason::Unknown,
+        }
+    }
+}
+
+/// Get the reason of the last shutdown
+pub fn last_shutdown() -> ShutdownReason {
+    LAST_SHUTDOWN.load(Ordering::Acquire).into()
+}
+
+pub(crate) fn set_last_shutdown(reason: ShutdownReason) {
+    info!("Last shutdown: {:?}", reason);
+    LAST_SHUTDOWN.store(reason as u8, Ordering::Release);
+}
+
+/// Notify RMK that the supply is browning out, or the battery is about to be cut off.
+///
+/// All pending settings writes are flushed and a clean shutdown marker is saved,
+/// so that the next boot can distinguish power loss from crash.
+/// This function can be called from interrupt context.
+pub fn notify_power_loss() {
+    POWER_LOSS_SIGNAL.signal(true);
+}
+
+/// Notify RMK that the brown-out condition notified by [`notify_power_loss`] is cleared.
+///
+/// If the clean shutdown marker has been saved, the keyboard is marked as running again,
+/// so that a crash after that isn't reported as a power loss.
+/// RMK calls it when USB power is plugged in, or when the battery level recovers on nRF52.
+/// This function can be called from interrupt context.
+pub fn notify_power_restored() {
+    POWER_LOSS_SIGNAL.signal(false);
+}
+
+// Time when the `SoftOff` key is pressed and the hold time, `None` if the key isn't held
+static SOFT_OFF_HOLD: Mutex<CriticalSectionRawMutex, Cell<Option<(Instant, Duration)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Signaled right before the keyboard is powered down by the `SoftOff` key, or by the deep sleep of [`crate::sleep`].
+///
+/// On nRF52, RMK powers the chip off by itself. On other chips, user code should wait for this signal
+/// and put the chip into deep sleep.
+pub static SOFT_OFF_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Start or cancel the hold of the `SoftOff` key
+pub(crate) fn set_soft_off_hold(hold_time: Option<Duration>) {
+    SOFT_OFF_HOLD.lock(|h| h.set(hold_time.map(|t| (Instant::now(), t))));
+}
+
+/// Progress of holding the `SoftOff` key in percent, `None` if the key isn't held
+pub fn soft_off_progress() -> Option<u8> {
+    SOFT_OFF_HOLD.lock(|h| h.get()).map(|(start, hold_time)| {
+        let total = hold_time.as_millis().max(1);
+        (start.elapsed().as_millis().min(total) * 100 / total) as u8
+    })
+}
+
+/// Flush pending settings, then power the keyboard down until the wake pin is triggered
+pub(crate) async fn enter_soft_off(config: &SoftOffConfig) {
+    info!("Soft off, powering down");
+    set_soft_off_hold(None);
+    power_down(
+        config.wake_pin.as_slice(),
+        config.drive_pin.as_slice(),
+        config.wake_high,
+    )
+    .await;
+}
+
+/// Flush pending settings, then power the keyboard down until any of `wake_pins` reaches the wake level.
+///
+/// `drive_pins` are kept driven to the wake level while the keyboard is off. Pins are numbered as `32 * port + pin` on nRF52.
+pub(crate) async fn power_down(wake_pins: &[u8], drive_pins: &[u8], wake_high: bool) {
+    // Pending writes are flushed as if the power is lost, the storage task needs a while to finish
+    notify_power_loss();
+    Timer::after_millis(200).await;
+    SOFT_OFF_SIGNAL.signal(());
+
+    #[cfg(feature = "_nrf_ble")]
+    crate::ble::nrf::system_off(wake_pins, drive_pins, wake_high);
+
+    #[cfg(not(feature = "_nrf_ble"))]
+    {
+        let _ = (wake_pins, drive_pins, wake_high);
+        warn!("Powering down isn't supported on this chip, it should be handled by waiting `SOFT_OFF_SIGNAL`");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_battery_percent() {
+        assert_eq!(battery_percent(4250, LIPO_CURVE), 100);
+        assert_eq!(battery_percent(4050, LIPO_CURVE), 85);
+        assert_eq!(battery_percent(3820, LIPO_CURVE), 50);
+        assert_eq!(battery_percent(3450, LIPO_CURVE), 2);
+        assert_eq!(battery_percent(3000, LIPO_CURVE), 0);
+        // nice!nano: 2M / (806K + 2M)
+        let divider = VoltageDivider::new(2000, 2806);
+        assert_eq!(divider.battery_millivolts(2850), 3998);
+    }
+}
@@ -0,0 +1,180 @@
+//! Run subsystems at interrupt priority
+//!
+//! All futures of RMK, lighting and display are polled by the thread mode executor by default,
+//! so a heavy animation frame delays matrix scanning and increases typing latency on single-core chips.
+//!
+//! [`PriorityRunner`] polls a future in an interrupt handler instead, so that it preempts everything in thread mode
+//! and runners at lower priorities. Each runner needs a spare interrupt, for example an unused `SWI`/`EGU` on nRF52:
+//!
+//! ```rust,ignore
+//! use rmk::priority::PriorityRunner;
+//!
+//! static MATRIX_PRIORITY: PriorityRunner = PriorityRunner::new();
+//!
+//! #[interrupt]
+//! fn SWI1_EGU1() {
+//!     MATRIX_PRIORITY.on_interrupt();
+//! }
+//!
+//! // RMK preempts the lighting and display, which are still polled in thread mode.
+//! // Safety: `run_rmk` only shares RMK's channels with the lighting and display, and the runner isn't forgotten
+//! join3(
+//!     unsafe { MATRIX_PRIORITY.run(Interrupt::SWI1_EGU1, Priority::P6 as u8, run_rmk(...)) },
+//!     run_rgb_lighting(...),
+//!     run_display(...),
+//! )
+//! .await;
+//! ```
+//!
+//! Futures running at different priorities can only share state which is guarded by `CriticalSectionRawMutex`,
+//! such as RMK's channels. `run_rmk` should be run as a whole, its internal tasks share the keymap by `RefCell`.
+
+use core::{
+    cell::Cell,
+    future::Future,
+    pin::{pin, Pin},
+    ptr::NonNull,
+    task::{Context, RawWaker, RawWakerVTable, Waker},
+};
+
+use cortex_m::{interrupt::InterruptNumber, peripheral::NVIC};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+
+#[derive(Clone, Copy)]
+struct Irq(u16);
+
+unsafe impl InterruptNumber for Irq {
+    fn number(self) -> u16 {
+        self.0
+    }
+}
+
+// Future polled by the interrupt handler
+#[derive(Clone, Copy)]
+struct TaskPtr(NonNull<dyn Future<Output = ()>>);
+
+// The future is only polled in the interrupt handler, and the pointer is cleared before the future is dropped
+unsafe impl Send for TaskPtr {}
+
+/// Polls a future in an interrupt handler, at the priority of the interrupt
+pub struct PriorityRunner {
+    task: Mutex<CriticalSectionRawMutex, Cell<Option<TaskPtr>>>,
+    irq: Mutex<CriticalSectionRawMutex, Cell<u16>>,
+    done: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl PriorityRunner {
+    pub const fn new() -> Self {
+        Self {
+            task: Mutex::new(Cell::new(None)),
+            irq: Mutex::new(Cell::new(0)),
+            done: Signal::new(),
+        }
+    }
+
+    /// Run `fut` in the interrupt handler of `irq`, returns when `fut` is completed.
+    ///
+    /// The interrupt handler of `irq` should call [`PriorityRunner::on_interrupt`].
+    /// `priority` is the raw NVIC priority, lower value is higher priority, e.g. `embassy_nrf::interrupt::Priority::P6 as u8`.
+    /// On nRF52 with softdevice, priorities 0, 1 and 4 are reserved by the softdevice.
+    ///
+    /// If the returned future is dropped, `fut` is dropped too and won't be polled anymore.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the runner is already running another future.
+    ///
+    /// # Safety
+    ///
+    /// `fut` is polled in the interrupt handler, out of the borrow checker's sight:
+    ///
+    /// - The returned future must be awaited to completion or dropped, it must not be leaked by `mem::forget`,
+    ///   otherwise `fut` might be polled after the data it borrows is gone.
+    /// - `fut` is not required to be `Send`, but it's moved to the interrupt context.
+    ///   Everything it shares with code at other priorities must be synchronized by `CriticalSectionRawMutex`,
+    ///   e.g. a `RefCell` must not be shared between `fut` and futures polled in thread mode.
+    /// - Only the interrupt handler of `irq` may call [`PriorityRunner::on_interrupt`] of this runner.
+    pub async unsafe fn run<I: InterruptNumber, F: Future<Output = ()>>(
+        &'static self,
+        irq: I,
+        priority: u8,
+        fut: F,
+    ) {
+        let mut fut = pin!(fut);
+        let task: &mut (dyn Future<Output = ()> + '_) = unsafe { fut.as_mut().get_unchecked_mut() };
+        let task = NonNull::from(task);
+        // The pointer is cleared by `_guard` before `fut` goes out of scope
+        let task: NonNull<dyn Future<Output = ()>> = unsafe { core::mem::transmute(task) };
+
+        // Check and set in one critical section, so that concurrent `run`s can't overwrite the task of each other
+        let in_use = self.task.lock(|t| {
+            if t.get().is_some() {
+                return true;
+            }
+            t.set(Some(TaskPtr(task)));
+            false
+        });
+        assert!(!in_use, "PriorityRunner is already running a future");
+        let _guard = TaskGuard(self);
+        self.done.reset();
+        self.irq.lock(|i| i.set(irq.number()));
+
+        unsafe {
+            let mut nvic = cortex_m::Peripherals::steal().NVIC;
+            nvic.set_priority(irq, priority);
+            NVIC::unmask(irq);
+        }
+        NVIC::pend(irq);
+
+        self.done.wait().await;
+    }
+
+    /// Poll the future, this function should be called in the interrupt handler
+    pub fn on_interrupt(&'static self) {
+        let Some(task) = self.task.lock(|t| t.get()) else {
+            return;
+        };
+        let waker = unsafe { Waker::from_raw(raw_waker(self)) };
+        let mut cx = Context::from_waker(&waker);
+        // The future is pinned in `run`, and it can't be dropped while the interrupt handler is running
+        let fut = unsafe { Pin::new_unchecked(&mut *task.0.as_ptr()) };
+        if fut.poll(&mut cx).is_ready() {
+            self.task.lock(|t| t.set(None));
+            self.done.signal(());
+        }
+    }
+
+    fn pend(&self) {
+        NVIC::pend(Irq(self.irq.lock(|i| i.get())));
+    }
+}
+
+impl Default for PriorityRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Clears the future when `PriorityRunner::run` is completed or dropped
+struct TaskGuard(&'static PriorityRunner);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.task.lock(|t| t.set(None));
+    }
+}
+
+// Waking the future pends the interrupt of the runner
+static VTABLE: RawWakerVTable = RawWakerVTable::new(
+    |data| raw_waker(unsafe { &*(data as *const PriorityRunner) }),
+    |data| unsafe { &*(data as *const PriorityRunner) }.pend(),
+    |data| unsafe { &*(data as *const PriorityRunner) }.pend(),
+    |_| (),
+);
+
+fn raw_waker(runner: &'static PriorityRunner) -> RawWaker {
+    RawWaker::new(runner as *const PriorityRunner as *const (), &VTABLE)
+}
@@ -0,0 +1,70 @@
+//! Sizes of channels, static buffers and softdevice links
+//!
+//! All sizes which are shrunk by the `low_ram` feature are defined here, so that the profile is decided in one place.
+//! Modules re-export them with their own docs, for example [`crate::keyboard::EVENT_CHANNEL_SIZE`].
+
+pub(crate) use profile::*;
+
+#[cfg(not(feature = "low_ram"))]
+mod profile {
+    pub(crate) const EVENT_CHANNEL_SIZE: usize = 32;
+    pub(crate) const REPORT_CHANNEL_SIZE: usize = 32;
+    pub(crate) const POINTER_REPORT_CHANNEL_SIZE: usize = 8;
+    pub(crate) const KEY_CHANGE_LOG_SIZE: usize = 32;
+    pub(crate) const DYNAMIC_MACRO_SIZE: usize = 128;
+    pub(crate) const MAX_BOUNCE_STATS: usize = 64;
+    pub(crate) const MAX_BLOB_SIZE: usize = 4096;
+
+    // RGB lighting and display statics are referenced by the key processing,
+    // so they're linked even if their tasks don't run
+    pub(crate) const MAX_LED_ZONES: usize = 4;
+    pub(crate) const KEY_HIT_QUEUE_SIZE: usize = 8;
+    pub(crate) const MAX_CUSTOM_PAGES: usize = 4;
+    pub(crate) const WPM_WINDOW_SECS: usize = 10;
+
+    // Softdevice RAM grows with the number of links, the ATT MTU and the TX queues
+    pub(crate) const SD_CONN_COUNT: u8 = 6;
+    pub(crate) const SD_ATT_MTU: u16 = 256;
+    pub(crate) const SD_PERIPH_ROLE_COUNT: u8 = 4;
+    pub(crate) const SD_CENTRAL_ROLE_COUNT: u8 = 4;
+    pub(crate) const SD_CENTRAL_SEC_COUNT: u8 = 2;
+    pub(crate) const SD_TX_QUEUE_SIZE: u8 = 4;
+    // The split peripheral only connects to the central
+    pub(crate) const SPLIT_PERIPHERAL_SD_CONN_COUNT: u8 = 6;
+    pub(crate) const SPLIT_PERIPHERAL_SD_PERIPH_ROLE_COUNT: u8 = 4;
+    pub(crate) const SPLIT_PERIPHERAL_SD_CENTRAL_ROLE_COUNT: u8 = 4;
+    pub(crate) const SPLIT_PERIPHERAL_SD_CENTRAL_SEC_COUNT: u8 = 4;
+}
+
+#[cfg(feature = "low_ram")]
+mod profile {
+    pub(crate) const EVENT_CHANNEL_SIZE: usize = 8;
+    pub(crate) const REPORT_CHANNEL_SIZE: usize = 8;
+    pub(crate) const POINTER_REPORT_CHANNEL_SIZE: usize = 4;
+    pub(crate) const KEY_CHANGE_LOG_SIZE: usize = 8;
+    pub(crate) const DYNAMIC_MACRO_SIZE: usize = 64;
+    pub(crate) const MAX_BOUNCE_STATS: usize = 16;
+    pub(crate) const MAX_BLOB_SIZE: usize = 2048;
+    /// Max number of layers staged in a Via transaction, all layers are staged without `low_ram`
+    pub(crate) const MAX_STAGED_LAYERS: usize = 1;
+
+    // RGB lighting and display statics are referenced by the key processing,
+    // so they're linked even if their tasks don't run
+    pub(crate) const MAX_LED_ZONES: usize = 2;
+    pub(crate) const KEY_HIT_QUEUE_SIZE: usize = 2;
+    pub(crate) const MAX_CUSTOM_PAGES: usize = 2;
+    pub(crate) const WPM_WINDOW_SECS: usize = 5;
+
+    // Only one host and one split peripheral can be connected at the same time
+    pub(crate) const SD_CONN_COUNT: u8 = 2;
+    pub(crate) const SD_ATT_MTU: u16 = 128;
+    pub(crate) const SD_PERIPH_ROLE_COUNT: u8 = 1;
+    pub(crate) const SD_CENTRAL_ROLE_COUNT: u8 = 1;
+    pub(crate) const SD_CENTRAL_SEC_COUNT: u8 = 1;
+    pub(crate) const SD_TX_QUEUE_SIZE: u8 = 2;
+    // The split peripheral only connects to the central, so a single link is kept
+    pub(crate) const SPLIT_PERIPHERAL_SD_CONN_COUNT: u8 = 1;
+    pub(crate) const SPLIT_PERIPHERAL_SD_PERIPH_ROLE_COUNT: u8 = 1;
+    pub(crate) const SPLIT_PERIPHERAL_SD_CENTRAL_ROLE_COUNT: u8 = 0;
+    pub(crate) const SPLIT_PERIPHERAL_SD_CENTRAL_SEC_COUNT: u8 = 0;
+}
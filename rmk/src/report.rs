@@ -0,0 +1,432 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use heapless::Vec;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::{
+    display::{publish_modifier_indicator, ModifierIndicator},
+    event::KeyEvent,
+    generation::{bump_generation, ConfigItem},
+    host_layout::{translate, ALTGR, RSHIFT, SHIFT},
+    keyboard::KeyboardReportMessage,
+    keycode::KeyCode,
+    storage::{FlashOperationMessage, FLASH_CHANNEL},
+    usb::descriptor::{CompositeReport, CompositeReportType, NkroKeyboardReport},
+};
+
+/// Number of keycode slots of the boot keyboard report
+const BOOT_KEYS: usize = 6;
+
+/// Max number of keycodes held at the same time when NKRO is enabled
+const NKRO_KEYS: usize = 32;
+
+/// Whether keycodes are sent in the NKRO report instead of the 6KRO boot keyboard report
+static NKRO_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether NKRO is enabled
+pub fn nkro_enabled() -> bool {
+    NKRO_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enable or disable NKRO and save it to storage.
+///
+/// Held keys should be released before it's changed, keys held in NKRO might not fit in 6 slots.
+pub(crate) fn set_nkro(enabled: bool) {
+    info!("NKRO enabled: {}", enabled);
+    NKRO_ENABLED.store(enabled, Ordering::Relaxed);
+    bump_generation(ConfigItem::Settings);
+    if FLASH_CHANNEL
+        .try_send(FlashOperationMessage::Nkro(enabled))
+        .is_err()
+    {
+        warn!("Failed to save NKRO, storage channel is full");
+    }
+}
+
+/// Restore NKRO saved in storage
+pub(crate) fn restore_nkro(enabled: bool) {
+    NKRO_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Report builder collects all changes of the hid reports caused by a key event.
+///
+/// The keyboard updates modifiers, keycodes and consumer/system usages in the builder,
+/// then sends all changed reports at once via [`ReportBuilder::take_pending`].
+/// In this way, every report sent to the host reflects a consistent state,
+/// there won't be a report which has only part of the modifiers of a key applied.
+///
+/// Keycodes are sent in the 6KRO boot keyboard report, or in the NKRO report if [`nkro_enabled`].
+pub(crate) struct ReportBuilder {
+    /// Held modifiers
+    pub(crate) modifier: u8,
+    /// Keycode slots, only the first 6 slots are used when NKRO is disabled
+    keycodes: [u8; NKRO_KEYS],
+    /// Registered key position of each keycode slot
+    registered_keys: [Option<(u8, u8)>; NKRO_KEYS],
+    /// Composite report: mouse + media(consumer) + system control
+    pub(crate) other: CompositeReport,
+    /// Modifiers applied by features like Caps Word, which are combined with the held modifiers when sending
+    weak_modifier: u8,
+    /// Slot and modifiers of the latest key translated by the host layout.
+    /// The modifiers replace held Shift and AltGr while the key is held
+    layout_modifier: Option<(usize, u8)>,
+    /// Slot of the key registered by a key override, held modifiers which are suppressed and modifiers which are added.
+    /// They're applied until the key in the slot is released, so that the replacement key isn't resent with the suppressed modifiers
+    override_modifier: Option<(usize, u8, u8)>,
+    /// Latest published modifier state
+    indicator: ModifierIndicator,
+    keyboard_dirty: bool,
+    media_dirty: bool,
+    system_dirty: bool,
+}
+
+impl ReportBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            modifier: 0,
+            keycodes: [0; NKRO_KEYS],
+            registered_keys: [None; NKRO_KEYS],
+            other: CompositeReport::default(),
+            weak_modifier: 0,
+            layout_modifier: None,
+            override_modifier: None,
+            indicator: ModifierIndicator::default(),
+            keyboard_dirty: false,
+            media_dirty: false,
+            system_dirty: false,
+        }
+    }
+
+    /// Whether there are changes not sent to the host
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.keyboard_dirty || self.media_dirty || self.system_dirty
+    }
+
+    /// Mark all reports as changed, so that the current state is sent to the host again
+    pub(crate) fn mark_all_dirty(&mut self) {
+        self.keyboard_dirty = true;
+        self.media_dirty = true;
+        self.system_dirty = true;
+    }
+
+    /// Take all changed reports, in the order of keyboard, media, system control
+    pub(crate) fn take_pending(&mut self) -> Vec<KeyboardReportMessage, 3> {
+        let mut reports = Vec::new();
+        if self.keyboard_dirty {
+            let mut modifier = self.held_modifier() | self.weak_modifier;
+            if let Some((_, layout_modifier)) = self.layout_modifier {
+                modifier = (modifier & !(SHIFT | RSHIFT | ALTGR)) | layout_modifier;
+            }
+            let report = if nkro_enabled() {
+                let mut nkro = NkroKeyboardReport {
+                    modifier,
+                    ..Default::default()
+                };
+                // 0 is an empty slot
+                self.keycodes
+                    .iter()
+                    .filter(|&&k| k != 0)
+                    .for_each(|&k| nkro.set_key(k));
+                KeyboardReportMessage::NkroReport(nkro)
+            } else {
+                let mut keycodes = [0; BOOT_KEYS];
+                keycodes.copy_from_slice(&self.keycodes[..BOOT_KEYS]);
+                KeyboardReportMessage::KeyboardReport(KeyboardReport {
+                    modifier,
+                    reserved: 0,
+                    leds: 0,
+                    keycodes,
+                })
+            };
+            reports.push(report).ok();
+        }
+        if self.media_dirty {
+            reports
+                .push(KeyboardReportMessage::CompositeReport(
+                    self.other,
+                    CompositeReportType::Media,
+                ))
+                .ok();
+        }
+        if self.system_dirty {
+            reports
+                .push(KeyboardReportMessage::CompositeReport(
+                    self.other,
+                    CompositeReportType::System,
+                ))
+                .ok();
+        }
+        self.keyboard_dirty = false;
+        self.media_dirty = false;
+        self.system_dirty = false;
+        reports
+    }
+
+    /// Find the keycode slot which is registered by the key at the given position
+    fn find_slot(&self, key_event: KeyEvent) -> Option<usize> {
+        self.registered_keys.iter().position(|k| {
+            if let Some((row, col)) = k {
+                key_event.row == *row && key_event.col == *col
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Find the keycode slot of the key at the given position, or the first free slot
+    fn find_free_slot(&self, key_event: KeyEvent) -> Option<usize> {
+        let slots = if nkro_enabled() { NKRO_KEYS } else { BOOT_KEYS };
+        self.find_slot(key_event)
+            .or_else(|| self.keycodes[..slots].iter().position(|&k| k == 0))
+    }
+
+    /// Held modifiers with the modifiers of the key override applied
+    fn held_modifier(&self) -> u8 {
+        match self.override_modifier {
+            Some((_, suppressed, added)) => (self.modifier & !suppressed) | added,
+            None => self.modifier,
+        }
+    }
+
+    /// Whether shift is held or set as a weak modifier
+    pub(crate) fn is_shifted(&self) -> bool {
+        (self.held_modifier() | self.weak_modifier) & (SHIFT | RSHIFT) != 0
+    }
+
+    /// Register a key to be sent in hid report.
+    pub(crate) fn register_keycode(&mut self, key: KeyCode, key_event: KeyEvent) {
+        if let Some(index) = self.find_free_slot(key_event) {
+            self.register_translated_keycode(index, key, key_event);
+        }
+    }
+
+    /// Register the replacement key of a key override, the `suppressed` held modifiers are replaced by `added` while it's held
+    pub(crate) fn register_override_keycode(
+        &mut self,
+        key: KeyCode,
+        suppressed: u8,
+        added: u8,
+        key_event: KeyEvent,
+    ) {
+        if let Some(index) = self.find_free_slot(key_event) {
+            self.override_modifier = Some((index, suppressed, added));
+            self.register_translated_keycode(index, key, key_event);
+        }
+    }
+
+    /// Register a key in the slot, the key is translated to the key which types the same character on the host layout
+    fn register_translated_keycode(&mut self, index: usize, key: KeyCode, key_event: KeyEvent) {
+        let (key, layout_modifier) = match translate(key, self.is_shifted()) {
+            Some((key, modifier)) => (key, Some((index, modifier))),
+            None => (key, None),
+        };
+        self.layout_modifier = layout_modifier;
+        self.keycodes[index] = key as u8;
+        self.registered_keys[index] = Some((key_event.row, key_event.col));
+        self.keyboard_dirty = true;
+    }
+
+    /// Register a key and its Shift/AltGr modifiers which are already on the host layout, the key isn't translated
+    pub(crate) fn register_host_keycode(
+        &mut self,
+        key: KeyCode,
+        modifier: u8,
+        key_event: KeyEvent,
+    ) {
+        if let Some(index) = self.find_free_slot(key_event) {
+            self.layout_modifier = Some((index, modifier));
+            self.keycodes[index] = key as u8;
+            self.registered_keys[index] = Some((key_event.row, key_event.col));
+            self.keyboard_dirty = true;
+        }
+    }
+
+    /// Unregister a key from hid report.
+    pub(crate) fn unregister_keycode(&mut self, key: KeyCode, key_event: KeyEvent) {
+        // First, find the key event slot according to the position.
+        // Otherwise, release the first same key
+        let slot = self
+            .find_slot(key_event)
+            .or_else(|| self.keycodes.iter().position(|&k| k == key as u8));
+
+        if let Some(index) = slot {
+            if self.layout_modifier.is_some_and(|(i, _)| i == index) {
+                self.layout_modifier = None;
+            }
+            if self.override_modifier.is_some_and(|(i, _, _)| i == index) {
+                self.override_modifier = None;
+            }
+            self.keycodes[index] = 0;
+            self.registered_keys[index] = None;
+            self.keyboard_dirty = true;
+        }
+    }
+
+    /// Register a modifier to be sent in hid report.
+    pub(crate) fn register_modifier(&mut self, modifier_bit: u8) {
+        self.modifier |= modifier_bit;
+        self.keyboard_dirty = true;
+    }
+
+    /// Unregister a modifier from hid report.
+    pub(crate) fn unregister_modifier(&mut self, modifier_bit: u8) {
+        self.modifier &= !modifier_bit;
+        self.keyboard_dirty = true;
+    }
+
+    /// Set the weak modifiers, which are sent along with held modifiers but don't change them
+    pub(crate) fn set_weak_modifier(&mut self, modifier_bits: u8) {
+        if self.weak_modifier != modifier_bits {
+            self.weak_modifier = modifier_bits;
+            self.keyboard_dirty = true;
+        }
+    }
+
+    /// Publish the modifier state if it's changed
+    pub(crate) fn update_indicator(&mut self, one_shot: u8, caps_word: bool) {
+        let indicator = ModifierIndicator {
+            held: self.modifier | self.weak_modifier,
+            one_shot,
+            caps_word,
+        };
+        if indicator != self.indicator {
+            self.indicator = indicator;
+            publish_modifier_indicator(indicator);
+        }
+    }
+
+    /// Set the usage id of consumer control report, 0 means release
+    pub(crate) fn set_media_usage(&mut self, usage_id: u16) {
+        self.other.media_usage_id = usage_id;
+        self.media_dirty = true;
+    }
+
+    /// Set the usage id of system control report, 0 means release
+    pub(crate) fn set_system_usage(&mut self, usage_id: u8) {
+        self.other.system_usage_id = usage_id;
+        self.system_dirty = true;
+    }
+
+    /// Release all keys, modifiers and consumer/system usages which are not released yet
+    pub(crate) fn release_all(&mut self) {
+        if self.modifier != 0 || self.weak_modifier != 0 || self.keycodes.iter().any(|&k| k != 0) {
+            self.modifier = 0;
+            self.weak_modifier = 0;
+            self.layout_modifier = None;
+            self.override_modifier = None;
+            self.keycodes = [0; NKRO_KEYS];
+            self.registered_keys = [None; NKRO_KEYS];
+            self.keyboard_dirty = true;
+        }
+        if self.other.media_usage_id != 0 {
+            self.set_media_usage(0);
+        }
+        if self.other.system_usage_id != 0 {
+            self.set_system_usage(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            row: 0,
+            col,
+            pressed,
+        }
+    }
+
+    fn sent_modifier(builder: &mut ReportBuilder) -> u8 {
+        match builder.take_pending().first() {
+            Some(KeyboardReportMessage::KeyboardReport(report)) => report.modifier,
+            _ => panic!("Keyboard report isn't changed"),
+        }
+    }
+
+    #[test]
+    fn test_take_pending() {
+        let shift = KeyCode::LShift.as_modifier_bit();
+        let mut builder = ReportBuilder::new();
+        assert!(!builder.is_dirty());
+        assert!(builder.take_pending().is_empty());
+
+        // The modifier and the key of a key event are sent in a single report
+        builder.register_modifier(shift);
+        builder.register_keycode(KeyCode::A, event(0, true));
+        builder.set_media_usage(0xE9);
+        assert!(builder.is_dirty());
+        let reports = builder.take_pending();
+        assert_eq!(reports.len(), 2);
+        match &reports[0] {
+            KeyboardReportMessage::KeyboardReport(report) => {
+                assert_eq!(report.modifier, shift);
+                assert_eq!(report.keycodes, [KeyCode::A as u8, 0, 0, 0, 0, 0]);
+            }
+            _ => panic!("Keyboard report isn't sent first"),
+        }
+        assert!(matches!(
+            &reports[1],
+            KeyboardReportMessage::CompositeReport(report, CompositeReportType::Media)
+                if report.media_usage_id == 0xE9
+        ));
+
+        // Reports are taken only once
+        assert!(!builder.is_dirty());
+        assert!(builder.take_pending().is_empty());
+
+        // Only the changed reports are sent
+        builder.set_system_usage(0x81);
+        let reports = builder.take_pending();
+        assert_eq!(reports.len(), 1);
+        assert!(matches!(
+            &reports[0],
+            KeyboardReportMessage::CompositeReport(report, CompositeReportType::System)
+                if report.system_usage_id == 0x81
+        ));
+
+        // Everything is released in the order of keyboard, media, system control
+        builder.release_all();
+        let reports = builder.take_pending();
+        assert_eq!(reports.len(), 3);
+        match &reports[0] {
+            KeyboardReportMessage::KeyboardReport(report) => {
+                assert_eq!(report.modifier, 0);
+                assert_eq!(report.keycodes, [0; BOOT_KEYS]);
+            }
+            _ => panic!("Keyboard report isn't sent first"),
+        }
+        assert!(matches!(
+            &reports[1],
+            KeyboardReportMessage::CompositeReport(report, CompositeReportType::Media)
+                if report.media_usage_id == 0
+        ));
+        assert!(matches!(
+            &reports[2],
+            KeyboardReportMessage::CompositeReport(report, CompositeReportType::System)
+                if report.system_usage_id == 0
+        ));
+    }
+
+    #[test]
+    fn test_override_modifier() {
+        let shift = KeyCode::LShift.as_modifier_bit();
+        let mut builder = ReportBuilder::new();
+        builder.register_modifier(shift);
+        // Shift + Backspace is overridden as Delete
+        builder.register_override_keycode(KeyCode::Delete, shift, 0, event(0, true));
+        assert_eq!(sent_modifier(&mut builder), 0);
+
+        // Shift is still suppressed while Delete is held, another key doesn't end the override
+        builder.register_keycode(KeyCode::A, event(1, true));
+        assert_eq!(sent_modifier(&mut builder), 0);
+        builder.unregister_keycode(KeyCode::A, event(1, false));
+        assert_eq!(sent_modifier(&mut builder), 0);
+
+        // The override ends when Delete is released
+        builder.unregister_keycode(KeyCode::Delete, event(0, false));
+        assert_eq!(sent_modifier(&mut builder), shift);
+    }
+}
@@ -0,0 +1,68 @@
+/// RGB color, 8 bits per channel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Rgb = Rgb { r: 0, g: 0, b: 0 };
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Scale all channels by `scale`, 255 keeps the color unchanged
+    pub fn scale(self, scale: u8) -> Self {
+        let s = |c: u8| ((c as u16 * scale as u16) / u8::MAX as u16) as u8;
+        Self {
+            r: s(self.r),
+            g: s(self.g),
+            b: s(self.b),
+        }
+    }
+}
+
+/// HSV color, all components use range 0 ~ 255
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Hsv {
+    pub h: u8,
+    pub s: u8,
+    pub v: u8,
+}
+
+impl Hsv {
+    pub const fn new(h: u8, s: u8, v: u8) -> Self {
+        Self { h, s, v }
+    }
+}
+
+impl From<Hsv> for Rgb {
+    fn from(hsv: Hsv) -> Self {
+        if hsv.s == 0 {
+            return Rgb::new(hsv.v, hsv.v, hsv.v);
+        }
+
+        // Split the hue circle into 6 regions, each region has 43 steps
+        let region = hsv.h / 43;
+        let remainder = (hsv.h - region * 43) as u16 * 6;
+
+        let v = hsv.v as u16;
+        let s = hsv.s as u16;
+        let p = ((v * (255 - s)) >> 8) as u8;
+        let q = ((v * (255 - ((s * remainder) >> 8))) >> 8) as u8;
+        let t = ((v * (255 - ((s * (255 - remainder)) >> 8))) >> 8) as u8;
+
+        match region {
+            0 => Rgb::new(hsv.v, t, p),
+            1 => Rgb::new(q, hsv.v, p),
+            2 => Rgb::new(p, hsv.v, t),
+            3 => Rgb::new(p, q, hsv.v),
+            4 => Rgb::new(t, p, hsv.v),
+            _ => Rgb::new(hsv.v, p, q),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! SPI drivers of addressable LEDs
+
+use embedded_hal_async::spi::SpiDevice;
+
+use super::{color::Rgb, LedDriver};
+
+/// Bytes of a WS2812 LED, every data bit takes 4 SPI bits
+const WS2812_LED_BYTES: usize = 12;
+
+/// Low time after a frame, which latches the colors. It's 373us at 3MHz, newer WS2812B needs at least 280us
+const WS2812_RESET_BYTES: usize = 140;
+
+/// Size of the buffer of [`Ws2812Spi`] for `num_leds` LEDs
+pub const fn ws2812_buffer_size(num_leds: usize) -> usize {
+    num_leds * WS2812_LED_BYTES + WS2812_RESET_BYTES
+}
+
+/// Encode a byte of WS2812 to 4 bytes, bit 1 is `0b1110` and bit 0 is `0b1000`, MSB first
+fn encode_ws2812_byte(byte: u8, out: &mut [u8]) {
+    for (i, o) in out.iter_mut().enumerate().take(4) {
+        let bits = byte << (i * 2);
+        let high = if bits & 0x80 != 0 { 0b1110 } else { 0b1000 };
+        let low = if bits & 0x40 != 0 { 0b1110 } else { 0b1000 };
+        *o = (high << 4) | low;
+    }
+}
+
+/// WS2812 and SK6812(RGB) LEDs driven by the MOSI pin of a SPI at 3MHz, colors are sent in GRB order.
+///
+/// A frame is encoded into a buffer of `BUF` bytes and sent in one transfer, because a gap in the transfer latches the colors.
+/// Use [`ws2812_buffer_size`] to get `BUF` for the number of LEDs, for example `Ws2812Spi::<_, { ws2812_buffer_size(24) }>::new(spi)`.
+/// Nothing else should be on the bus.
+pub struct Ws2812Spi<S: SpiDevice, const BUF: usize> {
+    spi: S,
+    buffer: [u8; BUF],
+}
+
+impl<S: SpiDevice, const BUF: usize> Ws2812Spi<S, BUF> {
+    pub fn new(spi: S) -> Self {
+        Self {
+            spi,
+            buffer: [0; BUF],
+        }
+    }
+}
+
+impl<S: SpiDevice, const BUF: usize> LedDriver for Ws2812Spi<S, BUF> {
+    type Error = S::Error;
+
+    async fn write(&mut self, colors: &[Rgb]) -> Result<(), Self::Error> {
+        let capacity = BUF.saturating_sub(WS2812_RESET_BYTES) / WS2812_LED_BYTES;
+        if colors.len() > capacity {
+            warn!(
+                "WS2812 buffer is too small, only {} of {} LEDs are written",
+                capacity,
+                colors.len()
+            );
+        }
+        let n = colors.len().min(capacity);
+        for (color, out) in colors[..n]
+            .iter()
+            .zip(self.buffer.chunks_exact_mut(WS2812_LED_BYTES))
+        {
+            encode_ws2812_byte(color.g, &mut out[0..4]);
+            encode_ws2812_byte(color.r, &mut out[4..8]);
+            encode_ws2812_byte(color.b, &mut out[8..12]);
+        }
+        let end = n * WS2812_LED_BYTES;
+        self.buffer[end..(end + WS2812_RESET_BYTES).min(BUF)].fill(0);
+        self.spi
+            .write(&self.buffer[..(end + WS2812_RESET_BYTES).min(BUF)])
+            .await
+    }
+}
+
+/// APA102 and SK9822 LEDs driven by a SPI, with clock and data lines.
+///
+/// The global brightness of every LED is set to the max, the brightness is controlled by the colors.
+pub struct Apa102Spi<S: SpiDevice> {
+    spi: S,
+}
+
+impl<S: SpiDevice> Apa102Spi<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi }
+    }
+}
+
+impl<S: SpiDevice> LedDriver for Apa102Spi<S> {
+    type Error = S::Error;
+
+    async fn write(&mut self, colors: &[Rgb]) -> Result<(), Self::Error> {
+        // LED frames are sent in chunks, the clock of APA102 can pause between transfers
+        let mut buf = [0u8; 64];
+        // Start frame
+        self.spi.write(&[0; 4]).await?;
+        for chunk in colors.chunks(buf.len() / 4) {
+            for (color, out) in chunk.iter().zip(buf.chunks_exact_mut(4)) {
+                out.copy_from_slice(&[0xE0 | 31, color.b, color.g, color.r]);
+            }
+            self.spi.write(&buf[..chunk.len() * 4]).await?;
+        }
+        // Reset frame of SK9822, then the end frame, which has at least a clock edge per 2 LEDs to push data to the last LED
+        self.spi.write(&[0; 4]).await?;
+        for _ in 0..colors.len().div_ceil(64) {
+            self.spi.write(&[0xFF; 4]).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ws2812_encode() {
+        let mut out = [0; 4];
+        encode_ws2812_byte(0b1010_0011, &mut out);
+        assert_eq!(out, [0b1110_1000, 0b1110_1000, 0b1000_1000, 0b1110_1110]);
+    }
+}
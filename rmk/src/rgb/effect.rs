@@ -0,0 +1,135 @@
+use super::{
+    color::{Hsv, Rgb},
+    palette::Palette,
+};
+
+/// Built-in lighting effects
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Effect {
+    /// All LEDs show the same static color
+    #[default]
+    Solid,
+    /// All LEDs fade in and out
+    Breathing,
+    /// All LEDs cycle through the hue circle together
+    RainbowCycle,
+    /// The hue circle is spread over the LEDs and rotates
+    RainbowSwirl,
+    /// Colors of the palette are spread over the LEDs and rotate
+    PaletteGradient,
+    /// All LEDs fade through colors of the palette together
+    PaletteCycle,
+    /// All LEDs show the palette color of the current active layer
+    LayerIndicator,
+    /// LEDs light up proportionally to the battery level
+    BatteryGauge,
+    /// LEDs under pressed keys light up and fade out, see `key_leds` of [`RGBLightConfig`](crate::config::RGBLightConfig)
+    Reactive,
+}
+
+/// Inputs of rendering an effect besides the zone's color
+pub(crate) struct RenderContext<'a> {
+    /// Animation progress, which is advanced according to the effect speed rather than the frame rate,
+    /// so that the animation looks the same at any FPS
+    pub(crate) step: u32,
+    pub(crate) palette: &'a Palette,
+    pub(crate) layer: u8,
+    /// Battery level in percent
+    pub(crate) battery_level: Option<u8>,
+    /// Brightness of the key press of each LED in the zone, which fades out after the key is pressed
+    pub(crate) key_hits: &'a [u8],
+}
+
+impl Effect {
+    const ALL: [Effect; 9] = [
+        Effect::Solid,
+        Effect::Breathing,
+        Effect::RainbowCycle,
+        Effect::RainbowSwirl,
+        Effect::PaletteGradient,
+        Effect::PaletteCycle,
+        Effect::LayerIndicator,
+        Effect::BatteryGauge,
+        Effect::Reactive,
+    ];
+
+    /// Convert the effect index to the effect
+    pub fn from_u8(index: u8) -> Option<Self> {
+        Self::ALL.get(index as usize).copied()
+    }
+
+    /// Next effect, wraps around
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|e| *e == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+
+    /// Previous effect, wraps around
+    pub fn previous(self) -> Self {
+        let idx = Self::ALL.iter().position(|e| *e == self).unwrap_or(0);
+        Self::ALL[(idx + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+
+    /// Render the effect into `frame`.
+    ///
+    /// Palette effects and battery gauge use the value of `color` as the brightness.
+    pub(crate) fn render(&self, frame: &mut [Rgb], color: Hsv, ctx: &RenderContext) {
+        let (step, palette) = (ctx.step, ctx.palette);
+        // Apply brightness to a palette color
+        let dim = |c: Hsv| Hsv::new(c.h, c.s, ((c.v as u16 * color.v as u16) / 255) as u8);
+        match self {
+            Effect::Solid => frame.fill(color.into()),
+            Effect::Breathing => {
+                // Triangle wave of the brightness
+                let phase = (step % 512) as u16;
+                let level = if phase < 256 { phase } else { 511 - phase } as u8;
+                let v = ((color.v as u16 * level as u16) / 255) as u8;
+                frame.fill(Hsv::new(color.h, color.s, v).into());
+            }
+            Effect::RainbowCycle => {
+                let h = color.h.wrapping_add(step as u8);
+                frame.fill(Hsv::new(h, color.s, color.v).into());
+            }
+            Effect::RainbowSwirl => {
+                let n = frame.len().max(1);
+                for (i, led) in frame.iter_mut().enumerate() {
+                    let offset = ((i * 256) / n) as u8;
+                    let h = color.h.wrapping_add(offset).wrapping_add(step as u8);
+                    *led = Hsv::new(h, color.s, color.v).into();
+                }
+            }
+            Effect::PaletteGradient => {
+                let n = frame.len().max(1);
+                for (i, led) in frame.iter_mut().enumerate() {
+                    let position = (((i * 256) / n) as u8).wrapping_add(step as u8);
+                    *led = dim(palette.sample(position)).into();
+                }
+            }
+            Effect::PaletteCycle => frame.fill(dim(palette.sample(step as u8)).into()),
+            Effect::LayerIndicator => frame.fill(dim(palette.color(ctx.layer as usize)).into()),
+            Effect::BatteryGauge => {
+                frame.fill(Rgb::OFF);
+                let Some(level) = ctx.battery_level else {
+                    return;
+                };
+                // Red when the battery is low, green when it's high
+                let h = match level {
+                    0..=20 => 0,
+                    21..=50 => 32,
+                    _ => 85,
+                };
+                let lit = (frame.len() * level as usize).div_ceil(100);
+                frame[..lit.min(frame.len())].fill(Hsv::new(h, 255, color.v).into());
+            }
+            Effect::Reactive => {
+                frame.fill(Rgb::OFF);
+                for (led, hit) in frame.iter_mut().zip(ctx.key_hits) {
+                    let v = ((color.v as u16 * *hit as u16) / 255) as u8;
+                    *led = Hsv::new(color.h, color.s, v).into();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use core::cell::RefCell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+
+use super::color::Rgb;
+
+/// Latest LED frame shared by the render task and the transmit task.
+///
+/// The render task draws into its own back buffer, then presents it by copying it here.
+/// The transmit task copies the frame out, so the lock is only held for the copies and the render task never waits for a transfer to finish.
+pub(crate) struct FrameBuffer<const N: usize> {
+    // Frame which is ready to be transmitted
+    front: Mutex<CriticalSectionRawMutex, RefCell<[Rgb; N]>>,
+    ready: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<const N: usize> FrameBuffer<N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            front: Mutex::new(RefCell::new([Rgb::OFF; N])),
+            ready: Signal::new(),
+        }
+    }
+
+    /// Copy a rendered frame to the front buffer and notify the transmit task
+    pub(crate) fn present(&self, frame: &[Rgb; N]) {
+        self.front.lock(|f| f.borrow_mut().copy_from_slice(frame));
+        self.ready.signal(());
+    }
+
+    /// Wait for a new frame, then copy it to `out`.
+    ///
+    /// If several frames are presented during a transfer, only the latest one is transmitted.
+    pub(crate) async fn wait_frame(&self, out: &mut [Rgb; N]) {
+        self.ready.wait().await;
+        self.front.lock(|f| out.copy_from_slice(&*f.borrow()));
+    }
+}
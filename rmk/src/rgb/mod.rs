@@ -0,0 +1,617 @@
+//! RGB lighting
+//!
+//! RGB lighting is split into two parts:
+//!
+//! - The render task, which draws the current effect into a frame at the configured frame rate
+//! - The transmit task, which sends the latest frame to the LEDs using a [`LedDriver`], typically backed by DMA
+//!
+//! LEDs can be divided into [`LedZone`]s, such as per-key LEDs and underglow, each zone runs its own effect
+//! and all zones are composited into one frame.
+//!
+//! Frames are double-buffered, so that rendering never waits for a transfer, and a slow transfer only drops frames
+//! instead of blocking other tasks like matrix scanning. Frames are rendered outside of the critical section,
+//! which is only held to copy a finished frame.
+//!
+//! The switch, effects, colors and speeds of zones are saved to the storage a few seconds after they're changed.
+//! LEDs are turned off while the keyboard sleeps, the host is suspended, or no key is pressed for `idle_timeout` of [`RGBLightConfig`].
+
+mod color;
+#[cfg(feature = "rgb_spi")]
+mod driver;
+mod effect;
+mod frame;
+mod palette;
+mod zone;
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_futures::join::join;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+
+pub use color::{Hsv, Rgb};
+#[cfg(feature = "rgb_spi")]
+pub use driver::{ws2812_buffer_size, Apa102Spi, Ws2812Spi};
+pub use effect::Effect;
+use effect::RenderContext;
+use frame::FrameBuffer;
+use heapless::Vec;
+pub use palette::{Palette, BUILTIN_PALETTES};
+pub use zone::{KeyLed, LedZone, RgbIndicator, ZoneState, MAX_LED_ZONES};
+
+use crate::{
+    config::RGBLightConfig,
+    diagnostic::{key_tester_active, last_key_press_at},
+    display::last_key_activity,
+    generation::{bump_generation, ConfigItem},
+    indicator::indicator_state,
+    keycode::KeyCode,
+    keymap::ACTIVE_LAYER,
+    light::limit_brightness,
+    pomodoro::{pomodoro_alarm_active, pomodoro_status, PomodoroPhase},
+    power::{
+        battery_level, current_power_source, soft_off_progress, PowerSource, POWER_SOURCE_CHANNEL,
+    },
+    sleep::{host_suspended, keyboard_sleeping},
+    storage::{FlashOperationMessage, FLASH_CHANNEL},
+};
+
+/// Upper limit of the animation frame rate
+pub(crate) const MAX_FPS: u8 = 120;
+
+/// How long LEDs are lit after a key press in key tester mode
+const KEY_TESTER_FLASH_DURATION: Duration = Duration::from_millis(150);
+
+/// How long a LED fades out after its key is pressed in the reactive effect
+const REACTIVE_FADE_TIME: Duration = Duration::from_millis(500);
+
+/// Settings are saved after they're unchanged for this time, so that holding an adjustment key doesn't write the storage repeatedly
+const SETTINGS_SAVE_DELAY: Duration = Duration::from_secs(3);
+
+/// How often the lighting is checked while LEDs are turned off
+const LIGHTS_OUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Key presses at (row, col) for the reactive effect
+static KEY_HITS: Channel<
+    CriticalSectionRawMutex,
+    (u8, u8),
+    { crate::ram_profile::KEY_HIT_QUEUE_SIZE },
+> = Channel::new();
+
+/// Driver which transmits a frame to the LEDs.
+///
+/// The driver should use DMA(or PIO, PWM sequence, etc.) so that the transfer doesn't block the executor.
+pub trait LedDriver {
+    type Error;
+
+    /// Write colors of all LEDs
+    async fn write(&mut self, colors: &[Rgb]) -> Result<(), Self::Error>;
+}
+
+/// Global state of RGB lighting
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RgbState {
+    /// Master switch of all zones
+    pub enabled: bool,
+    /// Frame rate of the animation, 1 ~ [`MAX_FPS`]
+    pub fps: u8,
+    /// Index of the zone which is adjusted by RGB keycodes and via
+    pub active_zone: u8,
+    /// Index of the palette used by palette effects and layer indication
+    pub palette: u8,
+}
+
+impl RgbState {
+    const fn new() -> Self {
+        Self {
+            enabled: true,
+            fps: 60,
+            active_zone: 0,
+            palette: 0,
+        }
+    }
+}
+
+impl Default for RgbState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Settings of RGB lighting which are saved to the storage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct RgbSettings {
+    pub(crate) enabled: bool,
+    pub(crate) num_zones: u8,
+    pub(crate) zones: [ZoneState; MAX_LED_ZONES],
+}
+
+struct Lighting {
+    state: RgbState,
+    zones: Vec<ZoneState, MAX_LED_ZONES>,
+    /// Settings saved in the storage
+    saved: Option<RgbSettings>,
+    palettes: &'static [Palette],
+    hue_step: u8,
+    sat_step: u8,
+    val_step: u8,
+    speed_step: u8,
+}
+
+static LIGHTING: Mutex<CriticalSectionRawMutex, RefCell<Lighting>> =
+    Mutex::new(RefCell::new(Lighting {
+        state: RgbState::new(),
+        zones: Vec::new(),
+        saved: None,
+        palettes: &BUILTIN_PALETTES,
+        hue_step: 8,
+        sat_step: 17,
+        val_step: 17,
+        speed_step: 16,
+    }));
+
+impl Lighting {
+    fn settings(&self) -> RgbSettings {
+        let mut zones = [ZoneState::default(); MAX_LED_ZONES];
+        zones[..self.zones.len()].copy_from_slice(&self.zones);
+        RgbSettings {
+            enabled: self.state.enabled,
+            num_zones: self.zones.len() as u8,
+            zones,
+        }
+    }
+
+    fn apply_settings(&mut self, settings: &RgbSettings) {
+        self.state.enabled = settings.enabled;
+        for (zone, saved) in self
+            .zones
+            .iter_mut()
+            .zip(settings.zones.iter().take(settings.num_zones as usize))
+        {
+            *zone = *saved;
+        }
+    }
+}
+
+/// Get current RGB lighting state
+pub fn rgb_state() -> RgbState {
+    LIGHTING.lock(|l| l.borrow().state)
+}
+
+/// Update RGB lighting state, the change takes effect from the next frame
+pub fn update_rgb_state(f: impl FnOnce(&mut RgbState)) {
+    LIGHTING.lock(|l| {
+        let mut l = l.borrow_mut();
+        let num_zones = l.zones.len().max(1) as u8;
+        let state = &mut l.state;
+        f(state);
+        state.fps = state.fps.clamp(1, MAX_FPS);
+        if state.active_zone >= num_zones {
+            state.active_zone = 0;
+        }
+    });
+}
+
+/// Get the state of the zone at `index`
+pub fn zone_state(index: usize) -> Option<ZoneState> {
+    LIGHTING.lock(|l| l.borrow().zones.get(index).copied())
+}
+
+/// Update the state of the zone at `index`, the change takes effect from the next frame
+pub fn update_zone_state(index: usize, f: impl FnOnce(&mut ZoneState)) {
+    LIGHTING.lock(|l| {
+        if let Some(zone) = l.borrow_mut().zones.get_mut(index) {
+            f(zone);
+        }
+    });
+}
+
+/// Get the state of the active zone
+pub fn active_zone_state() -> Option<ZoneState> {
+    zone_state(rgb_state().active_zone as usize)
+}
+
+/// Update the state of the active zone
+pub fn update_active_zone_state(f: impl FnOnce(&mut ZoneState)) {
+    update_zone_state(rgb_state().active_zone as usize, f)
+}
+
+// Whether the battery check key is held
+static BATTERY_CHECK: AtomicBool = AtomicBool::new(false);
+
+/// Show or hide the battery gauge, the gauge is shown while the battery check key is held
+pub(crate) fn set_battery_check(active: bool) {
+    BATTERY_CHECK.store(active, Ordering::Relaxed);
+}
+
+/// Get all available palettes
+pub fn palettes() -> &'static [Palette] {
+    LIGHTING.lock(|l| l.borrow().palettes)
+}
+
+/// Switch to the palette at `index` and save it to storage
+pub fn set_palette(index: u8) {
+    if index as usize >= palettes().len() {
+        warn!("Invalid palette index: {}", index);
+        return;
+    }
+    update_rgb_state(|state| state.palette = index);
+    bump_generation(ConfigItem::Settings);
+    if FLASH_CHANNEL
+        .try_send(FlashOperationMessage::RgbPalette(index))
+        .is_err()
+    {
+        warn!("Failed to save palette, storage channel is full");
+    }
+}
+
+/// Restore the palette saved in storage
+pub(crate) fn restore_palette(index: u8) {
+    update_rgb_state(|state| state.palette = index);
+}
+
+/// Get current settings of RGB lighting
+pub(crate) fn rgb_settings() -> RgbSettings {
+    LIGHTING.lock(|l| l.borrow().settings())
+}
+
+/// Restore the settings saved in storage, they're applied when the zones are created if the lighting isn't running yet
+pub(crate) fn restore_rgb_settings(settings: RgbSettings) {
+    LIGHTING.lock(|l| {
+        let mut l = l.borrow_mut();
+        l.apply_settings(&settings);
+        l.saved = Some(settings);
+    });
+}
+
+/// Save the settings if they're changed and then unchanged for [`SETTINGS_SAVE_DELAY`].
+///
+/// `pending` holds the changed settings and when they're changed.
+fn save_changed_settings(pending: &mut Option<(RgbSettings, Instant)>) {
+    let changed = LIGHTING.lock(|l| {
+        let mut l = l.borrow_mut();
+        let current = l.settings();
+        // Settings at startup are treated as saved
+        let saved = *l.saved.get_or_insert(current);
+        (current != saved).then_some(current)
+    });
+    let Some(current) = changed else {
+        *pending = None;
+        return;
+    };
+    match pending {
+        Some((settings, at)) if *settings == current => {
+            if at.elapsed() < SETTINGS_SAVE_DELAY {
+                return;
+            }
+            if FLASH_CHANNEL
+                .try_send(FlashOperationMessage::RgbSettings(current))
+                .is_err()
+            {
+                // Retry at the next frame
+                warn!("Failed to save RGB settings, storage channel is full");
+                return;
+            }
+            LIGHTING.lock(|l| l.borrow_mut().saved = Some(current));
+            bump_generation(ConfigItem::Settings);
+            *pending = None;
+        }
+        _ => *pending = Some((current, Instant::now())),
+    }
+}
+
+/// A key is pressed, light up its LEDs in the reactive effect
+pub(crate) fn notify_key_press(row: u8, col: u8) {
+    // Presses are dropped if the lighting isn't running
+    KEY_HITS.try_send((row, col)).ok();
+}
+
+/// Process RGB keycodes, should be called when the key is pressed.
+///
+/// `RgbTog` toggles all zones, other keycodes adjust the active zone.
+pub(crate) fn process_rgb_keycode(key: KeyCode) {
+    LIGHTING.lock(|l| {
+        let mut l = l.borrow_mut();
+        if key == KeyCode::RgbTog {
+            l.state.enabled = !l.state.enabled;
+            debug!("RGB lighting enabled: {}", l.state.enabled);
+            return;
+        }
+        let (hue_step, sat_step, val_step, speed_step) =
+            (l.hue_step, l.sat_step, l.val_step, l.speed_step);
+        let active = l.state.active_zone as usize;
+        let Some(zone) = l.zones.get_mut(active) else {
+            return;
+        };
+        match key {
+            KeyCode::RgbModeForward => zone.effect = zone.effect.next(),
+            KeyCode::RgbModeReverse => zone.effect = zone.effect.previous(),
+            KeyCode::RgbHui => zone.color.h = zone.color.h.wrapping_add(hue_step),
+            KeyCode::RgbHud => zone.color.h = zone.color.h.wrapping_sub(hue_step),
+            KeyCode::RgbSai => zone.color.s = zone.color.s.saturating_add(sat_step),
+            KeyCode::RgbSad => zone.color.s = zone.color.s.saturating_sub(sat_step),
+            KeyCode::RgbVai => zone.color.v = zone.color.v.saturating_add(val_step),
+            KeyCode::RgbVad => zone.color.v = zone.color.v.saturating_sub(val_step),
+            KeyCode::RgbSpi => zone.speed = zone.speed.saturating_add(speed_step),
+            KeyCode::RgbSpd => zone.speed = zone.speed.saturating_sub(speed_step),
+            KeyCode::RgbModePlain => zone.effect = Effect::Solid,
+            KeyCode::RgbModeBreathe => zone.effect = Effect::Breathing,
+            KeyCode::RgbModeRainbow => zone.effect = Effect::RainbowCycle,
+            KeyCode::RgbModeSwirl => zone.effect = Effect::RainbowSwirl,
+            _ => warn!("Unsupported RGB keycode: {:?}", key),
+        }
+        debug!("RGB zone {} state: {:?}", active, zone);
+    });
+}
+
+/// Run RGB lighting with `N` LEDs, this function never returns.
+///
+/// LEDs are divided into `zones`, each zone has its own effect. If `zones` is empty, all LEDs are in one zone.
+/// At most [`MAX_LED_ZONES`] zones are supported. It should be run concurrently with the keyboard, for example:
+///
+/// ```rust
+/// let zones = [LedZone::new("per_key", 0, 84), LedZone::new("underglow", 84, 20)];
+/// join(run_rgb_lighting::<_, 104>(ws2812_driver, RGBLightConfig::default(), &zones), run_rmk(...)).await;
+/// ```
+pub async fn run_rgb_lighting<D: LedDriver, const N: usize>(
+    mut driver: D,
+    config: RGBLightConfig,
+    zones: &[LedZone],
+) -> ! {
+    let default_zone = [LedZone::new("all", 0, N)];
+    let zones = if zones.is_empty() {
+        &default_zone[..]
+    } else {
+        if zones.len() > MAX_LED_ZONES {
+            warn!(
+                "Too many LED zones, only the first {} are used",
+                MAX_LED_ZONES
+            );
+        }
+        &zones[..zones.len().min(MAX_LED_ZONES)]
+    };
+
+    LIGHTING.lock(|l| {
+        let mut l = l.borrow_mut();
+        l.state.enabled = config.enabled;
+        l.state.fps = config.fps.clamp(1, MAX_FPS);
+        l.state.active_zone = 0;
+        l.zones.clear();
+        for _ in zones {
+            l.zones.push(ZoneState::new(config.effect_speed)).ok();
+        }
+        if let Some(saved) = l.saved {
+            l.apply_settings(&saved);
+        }
+        l.hue_step = config.rgb_hue_step.min(u8::MAX as u32) as u8;
+        l.sat_step = config.rgb_sat_step.min(u8::MAX as u32) as u8;
+        l.val_step = config.rgb_val_step.min(u8::MAX as u32) as u8;
+        l.speed_step = config.rgb_speed_step;
+        l.palettes = config.palettes;
+    });
+
+    let frame_buffer = FrameBuffer::<N>::new();
+
+    let render = async {
+        let mut fps = rgb_state().fps;
+        let mut ticker = Ticker::every(frame_interval(fps));
+        // Animation progress of each zone, in 1/256 steps
+        let mut progress = [0u32; MAX_LED_ZONES];
+        let mut last = Instant::now();
+        // Brightness of key presses of each LED, for the reactive effect
+        let mut key_hits = [0u8; N];
+        let mut pending_settings = None;
+        let mut lights_out = false;
+        // Frames are rendered here without holding the lock of the frame buffer
+        let mut back = [Rgb::OFF; N];
+        // LEDs are limited by `battery_brightness_limit` on battery, the power source is followed by its change events
+        let mut power_sources = POWER_SOURCE_CHANNEL.subscriber().ok();
+        let mut on_battery = current_power_source() == PowerSource::Battery;
+        loop {
+            save_changed_settings(&mut pending_settings);
+
+            let (state, zone_states, palettes) = LIGHTING.lock(|l| {
+                let l = l.borrow();
+                (l.state, l.zones.clone(), l.palettes)
+            });
+            let palette = palettes
+                .get(state.palette as usize)
+                .or(palettes.first())
+                .copied()
+                .unwrap_or(Palette::new("empty", &[]));
+            let layer = ACTIVE_LAYER.load(Ordering::Relaxed);
+            let battery = battery_level();
+            if state.fps != fps {
+                fps = state.fps;
+                ticker = Ticker::every(frame_interval(fps));
+            }
+
+            // Advance the animation by the elapsed time, at default speed 128 it's 1 step per 16ms
+            let now = Instant::now();
+            let elapsed = (now - last).as_millis() as u32;
+            last = now;
+            for (p, zone) in progress.iter_mut().zip(zone_states.iter()) {
+                *p = p.wrapping_add(zone.speed as u32 * elapsed / 8);
+            }
+            let fade = (elapsed as u64 * 255 / REACTIVE_FADE_TIME.as_millis()).min(255) as u8;
+            for hit in key_hits.iter_mut() {
+                *hit = hit.saturating_sub(fade);
+            }
+            while let Ok((row, col)) = KEY_HITS.try_receive() {
+                for key in config
+                    .key_leds
+                    .iter()
+                    .filter(|k| (k.row, k.col) == (row, col))
+                {
+                    if let Some(hit) = key_hits.get_mut(key.led as usize) {
+                        *hit = u8::MAX;
+                    }
+                }
+            }
+
+            let idle = config
+                .idle_timeout
+                .is_some_and(|t| last_key_activity().elapsed() >= t);
+            if idle || keyboard_sleeping() || host_suspended() {
+                // Turn LEDs off once, then stop rendering until they're turned on
+                if !lights_out {
+                    debug!("RGB lighting is turned off");
+                    back.fill(Rgb::OFF);
+                    frame_buffer.present(&back);
+                    lights_out = true;
+                }
+                Timer::after(LIGHTS_OUT_POLL_INTERVAL).await;
+                continue;
+            }
+            if lights_out {
+                // Don't catch up the frames skipped while LEDs are off
+                ticker = Ticker::every(frame_interval(fps));
+                lights_out = false;
+            }
+
+            back.fill(Rgb::OFF);
+            for ((zone, zone_state), p) in zones.iter().zip(zone_states.iter()).zip(progress) {
+                if !state.enabled || !zone_state.enabled {
+                    continue;
+                }
+                let end = (zone.start + zone.len).min(N);
+                if let Some(leds) = back.get_mut(zone.start..end) {
+                    let ctx = RenderContext {
+                        step: p >> 8,
+                        palette: &palette,
+                        layer,
+                        battery_level: battery,
+                        key_hits: &key_hits[zone.start..end],
+                    };
+                    zone_state.effect.render(leds, zone_state.color, &ctx);
+                }
+            }
+            // Lock state and layer indicators are shown even if the lighting is off
+            let indicators = indicator_state();
+            for indicator in config.indicators {
+                if indicators.is_on(indicator.indicator) {
+                    if let Some(led) = back.get_mut(indicator.led as usize) {
+                        *led = indicator.color.into();
+                    }
+                }
+            }
+            // In key tester mode, all LEDs flash on every key press instead of showing effects
+            if key_tester_active() {
+                let flash =
+                    last_key_press_at().is_some_and(|t| t.elapsed() < KEY_TESTER_FLASH_DURATION);
+                back.fill(if flash {
+                    Hsv::new(85, 255, 255).into()
+                } else {
+                    Rgb::OFF
+                });
+            }
+            // While the `SoftOff` key is held, LEDs are lit in red one by one until the keyboard is powered down
+            if let Some(percent) = soft_off_progress() {
+                let lit = N * percent as usize / 100;
+                for (i, led) in back.iter_mut().enumerate() {
+                    *led = if i < lit {
+                        Hsv::new(0, 255, 255).into()
+                    } else {
+                        Rgb::OFF
+                    };
+                }
+            }
+            // Remaining time of the pomodoro timer, red for work sessions and green for breaks
+            if let Some(gauge) = config.timer_gauge {
+                let timer = pomodoro_status();
+                if timer.phase != PomodoroPhase::Idle {
+                    let end = (gauge.start + gauge.len).min(N);
+                    if let Some(leds) = back.get_mut(gauge.start..end) {
+                        let total = timer.total.as_millis().max(1);
+                        let lit = (leds.len() as u64 * timer.remaining.as_millis()).div_ceil(total)
+                            as usize;
+                        let hue = if timer.phase == PomodoroPhase::Work {
+                            0
+                        } else {
+                            85
+                        };
+                        // Dimmed while the timer is paused
+                        let val = if timer.running { 255 } else { 64 };
+                        for (i, led) in leds.iter_mut().enumerate() {
+                            *led = if i < lit {
+                                Hsv::new(hue, 255, val).into()
+                            } else {
+                                Rgb::OFF
+                            };
+                        }
+                    }
+                }
+            }
+            // All LEDs flash when a pomodoro session is done
+            if pomodoro_alarm_active() {
+                let on = (Instant::now().as_millis() / 250) % 2 == 0;
+                back.fill(if on {
+                    Hsv::new(0, 0, 255).into()
+                } else {
+                    Rgb::OFF
+                });
+            }
+            // Draw the battery gauge over all zones, it's shown even if the lighting is off
+            if let Some(gauge) = config.battery_gauge {
+                if BATTERY_CHECK.load(Ordering::Relaxed) {
+                    let end = (gauge.start + gauge.len).min(N);
+                    if let Some(leds) = back.get_mut(gauge.start..end) {
+                        let ctx = RenderContext {
+                            step: 0,
+                            palette: &palette,
+                            layer,
+                            battery_level: battery,
+                            key_hits: &[],
+                        };
+                        Effect::BatteryGauge.render(leds, Hsv::new(0, 255, 255), &ctx);
+                    }
+                }
+            }
+            match power_sources.as_mut() {
+                Some(subscriber) => {
+                    while let Some(source) = subscriber.try_next_message_pure() {
+                        on_battery = source == PowerSource::Battery;
+                    }
+                }
+                // All subscribers are taken, read the power source directly
+                None => on_battery = current_power_source() == PowerSource::Battery,
+            }
+            let budget = if on_battery {
+                config.battery_brightness_limit
+            } else {
+                u8::MAX
+            };
+            let brightness = limit_brightness(budget);
+            for led in back.iter_mut() {
+                *led = led.scale(brightness);
+            }
+            frame_buffer.present(&back);
+            ticker.next().await;
+        }
+    };
+
+    let transmit = async {
+        let mut colors = [Rgb::OFF; N];
+        loop {
+            frame_buffer.wait_frame(&mut colors).await;
+            if driver.write(&colors).await.is_err() {
+                error!("Write LED frame error");
+            }
+        }
+    };
+
+    join(render, transmit).await;
+    unreachable!()
+}
+
+fn frame_interval(fps: u8) -> Duration {
+    Duration::from_millis(1000 / fps.max(1) as u64)
+}
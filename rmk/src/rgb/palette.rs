@@ -0,0 +1,82 @@
+use super::color::Hsv;
+
+/// A named set of colors, used by palette effects and layer indication
+#[derive(Clone, Copy, Debug)]
+pub struct Palette {
+    pub name: &'static str,
+    pub colors: &'static [Hsv],
+}
+
+impl Palette {
+    pub const fn new(name: &'static str, colors: &'static [Hsv]) -> Self {
+        Self { name, colors }
+    }
+
+    /// Get the color at `index`, wraps around
+    pub fn color(&self, index: usize) -> Hsv {
+        if self.colors.is_empty() {
+            Hsv::default()
+        } else {
+            self.colors[index % self.colors.len()]
+        }
+    }
+
+    /// Get the color at `position` of the whole palette, 0 ~ 255,
+    /// colors between two palette entries are interpolated
+    pub fn sample(&self, position: u8) -> Hsv {
+        let n = self.colors.len();
+        if n < 2 {
+            return self.color(0);
+        }
+        let scaled = position as usize * n;
+        let (index, frac) = (scaled / 256, (scaled % 256) as u16);
+        let from = self.colors[index];
+        let to = self.colors[(index + 1) % n];
+        let lerp = |a: u8, b: u8| ((a as u16 * (256 - frac) + b as u16 * frac) / 256) as u8;
+        // Hue is circular, go through the shorter way
+        let dh = to.h.wrapping_sub(from.h) as i8;
+        let h = from.h.wrapping_add(((dh as i16 * frac as i16) / 256) as u8);
+        Hsv::new(h, lerp(from.s, to.s), lerp(from.v, to.v))
+    }
+}
+
+/// Built-in palettes
+pub const BUILTIN_PALETTES: [Palette; 5] = [
+    Palette::new(
+        "rainbow",
+        &[
+            Hsv::new(0, 255, 255),
+            Hsv::new(43, 255, 255),
+            Hsv::new(85, 255, 255),
+            Hsv::new(128, 255, 255),
+            Hsv::new(170, 255, 255),
+            Hsv::new(213, 255, 255),
+        ],
+    ),
+    Palette::new(
+        "ocean",
+        &[
+            Hsv::new(128, 255, 255),
+            Hsv::new(150, 255, 255),
+            Hsv::new(170, 200, 255),
+        ],
+    ),
+    Palette::new(
+        "sunset",
+        &[
+            Hsv::new(0, 255, 255),
+            Hsv::new(16, 255, 255),
+            Hsv::new(32, 230, 255),
+            Hsv::new(232, 200, 255),
+        ],
+    ),
+    Palette::new(
+        "forest",
+        &[
+            Hsv::new(64, 255, 200),
+            Hsv::new(85, 255, 255),
+            Hsv::new(100, 200, 160),
+        ],
+    ),
+    Palette::new("mono", &[Hsv::new(0, 0, 255), Hsv::new(0, 0, 96)]),
+];
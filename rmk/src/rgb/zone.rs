@@ -0,0 +1,87 @@
+use super::{color::Hsv, effect::Effect};
+use crate::indicator::Indicator;
+
+/// Max number of LED zones
+pub const MAX_LED_ZONES: usize = crate::ram_profile::MAX_LED_ZONES;
+
+/// A named range of LEDs, such as underglow, per-key LEDs or an indicator bar.
+///
+/// Each zone runs its own effect, zones are drawn in the declared order,
+/// so if zones overlap, the latter one wins.
+#[derive(Clone, Copy, Debug)]
+pub struct LedZone {
+    pub name: &'static str,
+    /// Index of the first LED of the zone
+    pub start: usize,
+    /// Number of LEDs in the zone
+    pub len: usize,
+}
+
+impl LedZone {
+    pub const fn new(name: &'static str, start: usize, len: usize) -> Self {
+        Self { name, start, len }
+    }
+}
+
+/// A LED under a key, which lights up on key presses in [`Effect::Reactive`]
+#[derive(Clone, Copy, Debug)]
+pub struct KeyLed {
+    pub row: u8,
+    pub col: u8,
+    /// Index of the LED
+    pub led: u16,
+}
+
+impl KeyLed {
+    pub const fn new(row: u8, col: u8, led: u16) -> Self {
+        Self { row, col, led }
+    }
+}
+
+/// A LED which is lit in `color` while the indicator is on, over the effect of its zone
+#[derive(Clone, Copy, Debug)]
+pub struct RgbIndicator {
+    pub indicator: Indicator,
+    /// Index of the LED
+    pub led: u16,
+    pub color: Hsv,
+}
+
+impl RgbIndicator {
+    pub const fn new(indicator: Indicator, led: u16, color: Hsv) -> Self {
+        Self {
+            indicator,
+            led,
+            color,
+        }
+    }
+}
+
+/// Runtime state of a LED zone
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ZoneState {
+    pub enabled: bool,
+    pub effect: Effect,
+    /// Color of the zone, the value component is used as the brightness of the zone
+    pub color: Hsv,
+    /// Speed of the effect, 0 ~ 255
+    pub speed: u8,
+}
+
+impl ZoneState {
+    pub(crate) const fn new(speed: u8) -> Self {
+        Self {
+            enabled: true,
+            effect: Effect::Solid,
+            color: Hsv::new(0, 255, 128),
+            speed,
+        }
+    }
+}
+
+impl Default for ZoneState {
+    fn default() -> Self {
+        Self::new(128)
+    }
+}
@@ -0,0 +1,54 @@
+//! Safe mode
+//!
+//! If the key at `safe_mode_key` of [`StorageConfig`](crate::config::StorageConfig) is held while the keyboard is powered up,
+//! the keyboard boots into safe mode. The keymap, macros and settings saved in the storage are ignored,
+//! only the compiled-in defaults are used, and the host can't activate layers by the application context.
+//! So a bad remap can't make the keyboard unusable.
+//!
+//! The storage isn't cleared in safe mode, changes made by Vial are still saved, which can be used to fix the saved keymap.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_futures::select::select;
+use embassy_time::{Duration, Timer};
+
+use crate::keyboard::KEY_EVENT_CHANNEL;
+use crate::matrix::MatrixTrait;
+
+/// How long the matrix is scanned at boot to detect the safe mode key, which covers the debounce time
+const SAFE_MODE_SCAN_TIME: Duration = Duration::from_millis(100);
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the keyboard is booted into safe mode
+pub fn safe_mode_active() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
+/// Scan the matrix for a short time, enter safe mode if the key at `key` is held.
+///
+/// It should be called before the keymap is loaded from the storage. Key events during the scan are discarded.
+pub(crate) async fn check_safe_mode<M: MatrixTrait>(matrix: &mut M, key: Option<(u8, u8)>) {
+    let Some(key) = key else {
+        return;
+    };
+    let mut held = false;
+    let detect = async {
+        loop {
+            let e = KEY_EVENT_CHANNEL.receive().await;
+            if (e.row, e.col) == key {
+                held = e.pressed;
+            }
+        }
+    };
+    select(
+        matrix.scan(),
+        select(detect, Timer::after(SAFE_MODE_SCAN_TIME)),
+    )
+    .await;
+
+    if held {
+        warn!("Safe mode key is held, ignore the keymap and settings in storage");
+        SAFE_MODE.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,182 @@
+//! Scroll direction
+//!
+//! The wheel and the pan of each [`ScrollDevice`] can be inverted on each host OS, for example natural scrolling of a trackball on macOS,
+//! while the wheel keys of encoders scroll as usual. The host OS follows the [Unicode mode](crate::unicode), so switching the
+//! Unicode mode by a key switches the scroll directions as well.
+//!
+//! Directions are changed by [`set_scroll_direction`], or `User29`, which toggles natural scrolling of all devices on the current OS.
+//! They're saved to the storage.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::auto_lock::HostOs;
+use crate::storage::{FlashOperationMessage, FLASH_CHANNEL};
+use crate::unicode::{unicode_mode, UnicodeMode};
+
+/// Bits of inverted axes, 2 bits of each device on each OS
+static SCROLL_INVERTED: AtomicU32 = AtomicU32::new(0);
+
+const NUM_DEVICES: usize = 3;
+
+/// Source of scrolling
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScrollDevice {
+    /// Trackballs and mouse sensors in the scroll mode of a layer
+    Pointer = 0,
+    /// Two-finger scrolling of touchpads
+    Touchpad = 1,
+    /// Mouse wheel keys, including keys triggered by encoders
+    Keys = 2,
+}
+
+/// Direction of the wheel and the pan
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScrollDirection {
+    pub invert_wheel: bool,
+    pub invert_pan: bool,
+}
+
+/// OS of the host, resolved from the Unicode mode
+fn current_os() -> HostOs {
+    match unicode_mode() {
+        UnicodeMode::Linux => HostOs::Linux,
+        UnicodeMode::MacOs => HostOs::MacOs,
+        UnicodeMode::Windows | UnicodeMode::WinCompose => HostOs::Windows,
+    }
+}
+
+fn shift(device: ScrollDevice, os: HostOs) -> u32 {
+    let os = match os {
+        HostOs::Windows => 0,
+        HostOs::MacOs => 1,
+        HostOs::Linux => 2,
+    };
+    ((os * NUM_DEVICES + device as usize) * 2) as u32
+}
+
+fn direction_of(bits: u32, device: ScrollDevice, os: HostOs) -> ScrollDirection {
+    let bits = bits >> shift(device, os);
+    ScrollDirection {
+        invert_wheel: bits & 0b01 != 0,
+        invert_pan: bits & 0b10 != 0,
+    }
+}
+
+fn with_direction(bits: u32, device: ScrollDevice, os: HostOs, direction: ScrollDirection) -> u32 {
+    let shift = shift(device, os);
+    let value = (direction.invert_wheel as u32) | ((direction.invert_pan as u32) << 1);
+    (bits & !(0b11 << shift)) | (value << shift)
+}
+
+/// Get the scroll direction of the device on the OS
+pub fn scroll_direction(device: ScrollDevice, os: HostOs) -> ScrollDirection {
+    direction_of(SCROLL_INVERTED.load(Ordering::Relaxed), device, os)
+}
+
+/// Set the scroll direction of the device on the OS, it's saved to the storage
+pub fn set_scroll_direction(device: ScrollDevice, os: HostOs, direction: ScrollDirection) {
+    info!(
+        "Scroll direction of {:?} on {:?}: {:?}",
+        device, os, direction
+    );
+    let bits = with_direction(
+        SCROLL_INVERTED.load(Ordering::Relaxed),
+        device,
+        os,
+        direction,
+    );
+    save_scroll_directions(bits);
+}
+
+/// Toggle natural scrolling of all devices on the current OS, it's on if the wheel of any device isn't inverted
+pub(crate) fn toggle_natural_scrolling() {
+    let os = current_os();
+    let mut bits = SCROLL_INVERTED.load(Ordering::Relaxed);
+    let natural = [
+        ScrollDevice::Pointer,
+        ScrollDevice::Touchpad,
+        ScrollDevice::Keys,
+    ]
+    .iter()
+    .any(|&device| !direction_of(bits, device, os).invert_wheel);
+    info!("Natural scrolling on {:?}: {}", os, natural);
+    for device in [
+        ScrollDevice::Pointer,
+        ScrollDevice::Touchpad,
+        ScrollDevice::Keys,
+    ] {
+        let direction = ScrollDirection {
+            invert_wheel: natural,
+            invert_pan: natural,
+        };
+        bits = with_direction(bits, device, os, direction);
+    }
+    save_scroll_directions(bits);
+}
+
+fn save_scroll_directions(bits: u32) {
+    SCROLL_INVERTED.store(bits, Ordering::Relaxed);
+    if FLASH_CHANNEL
+        .try_send(FlashOperationMessage::ScrollDirections(bits))
+        .is_err()
+    {
+        warn!("Failed to save scroll directions, storage channel is full");
+    }
+}
+
+/// Restore scroll directions saved in the storage
+pub(crate) fn restore_scroll_directions(bits: u32) {
+    SCROLL_INVERTED.store(bits, Ordering::Relaxed);
+}
+
+/// Apply the direction of the device on the current OS to the wheel and the pan
+pub(crate) fn apply_scroll_direction(device: ScrollDevice, wheel: i8, pan: i8) -> (i8, i8) {
+    let direction = scroll_direction(device, current_os());
+    (
+        if direction.invert_wheel {
+            wheel.saturating_neg()
+        } else {
+            wheel
+        },
+        if direction.invert_pan {
+            pan.saturating_neg()
+        } else {
+            pan
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scroll_direction_bits() {
+        let natural = ScrollDirection {
+            invert_wheel: true,
+            invert_pan: false,
+        };
+        let bits = with_direction(0, ScrollDevice::Touchpad, HostOs::MacOs, natural);
+        assert_eq!(
+            direction_of(bits, ScrollDevice::Touchpad, HostOs::MacOs),
+            natural
+        );
+        assert_eq!(
+            direction_of(bits, ScrollDevice::Touchpad, HostOs::Windows),
+            ScrollDirection::default()
+        );
+        assert_eq!(
+            direction_of(bits, ScrollDevice::Keys, HostOs::MacOs),
+            ScrollDirection::default()
+        );
+        let bits = with_direction(
+            bits,
+            ScrollDevice::Touchpad,
+            HostOs::MacOs,
+            ScrollDirection::default(),
+        );
+        assert_eq!(bits, 0);
+    }
+}
@@ -0,0 +1,417 @@
+//! Shift registers of the matrix
+//!
+//! Boards without enough pins can drive the output pins of the matrix by 74HC595 and read the input pins by 74HC165.
+//! Chained registers act as one register of `8 * N` pins, pin 0 is `Q0`/`D0` of the register next to the MCU.
+//!
+//! A register is shared by its pins, [`ShiftRegisterOutputPin`] and [`ShiftRegisterInputPin`], which are used as the pins of
+//! [`Matrix`](crate::matrix::Matrix), so they can be mixed with GPIO pins and the matrix is scanned as usual:
+//!
+//! ```rust,ignore
+//! // Columns are driven by a 74HC595 on SPI, whose latch is the CS pin, rows are read by GPIO pins
+//! let cols = OutputShiftRegister::<_, 2>::new(SpiShiftOut::new(spi_device));
+//! let output_pins: [_; 14] = cols.pins();
+//! run_rmk(input_pins, output_pins, driver, flash, &mut keymap, keyboard_config, spawner).await;
+//! ```
+//!
+//! Registers are bit-banged by GPIO pins by [`BitBangShiftOut`] and [`BitBangShiftIn`], or driven by blocking SPI.
+//! Other drivers, such as PIO programs, can be used by implementing [`ShiftOut`] or [`ShiftIn`].
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+use embedded_hal::spi::{SpiBus, SpiDevice};
+
+/// Writes bytes to chained output shift registers, such as 74HC595
+pub trait ShiftOut {
+    /// Shift the bytes out, the first byte is for the register at the end of the chain, and latch them.
+    /// Bytes are shifted out MSB first
+    fn shift_out(&mut self, bytes: &[u8]);
+}
+
+/// Reads bytes from chained input shift registers, such as 74HC165
+pub trait ShiftIn {
+    /// Load the inputs and shift them in, the first byte is from the register next to the MCU.
+    /// Bytes are shifted in MSB first
+    fn shift_in(&mut self, bytes: &mut [u8]);
+}
+
+/// Output shift registers driven by GPIO pins, the data pin is connected to `SER`, the clock pin to `SRCLK` and the latch pin to `RCLK`
+pub struct BitBangShiftOut<Data: OutputPin, Clock: OutputPin, Latch: OutputPin> {
+    data: Data,
+    clock: Clock,
+    latch: Latch,
+}
+
+impl<Data: OutputPin, Clock: OutputPin, Latch: OutputPin> BitBangShiftOut<Data, Clock, Latch> {
+    pub fn new(data: Data, mut clock: Clock, mut latch: Latch) -> Self {
+        clock.set_low().ok();
+        latch.set_low().ok();
+        Self { data, clock, latch }
+    }
+}
+
+impl<Data: OutputPin, Clock: OutputPin, Latch: OutputPin> ShiftOut
+    for BitBangShiftOut<Data, Clock, Latch>
+{
+    fn shift_out(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            for bit in (0..8).rev() {
+                self.data.set_state(((byte >> bit) & 1 != 0).into()).ok();
+                self.clock.set_high().ok();
+                self.clock.set_low().ok();
+            }
+        }
+        self.latch.set_high().ok();
+        self.latch.set_low().ok();
+    }
+}
+
+/// Output shift registers on SPI, `MOSI` is connected to `SER`, `SCK` to `SRCLK` and `CS` to `RCLK`,
+/// so the outputs are latched when `CS` goes high after the transfer
+pub struct SpiShiftOut<S: SpiDevice> {
+    spi: S,
+}
+
+impl<S: SpiDevice> SpiShiftOut<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi }
+    }
+}
+
+impl<S: SpiDevice> ShiftOut for SpiShiftOut<S> {
+    fn shift_out(&mut self, bytes: &[u8]) {
+        if self.spi.write(bytes).is_err() {
+            error!("Failed to write output shift registers");
+        }
+    }
+}
+
+/// Input shift registers driven by GPIO pins, the load pin is connected to `SH/LD`, the clock pin to `CLK` and the data pin to `QH`
+pub struct BitBangShiftIn<Load: OutputPin, Clock: OutputPin, Data: InputPin> {
+    load: Load,
+    clock: Clock,
+    data: Data,
+}
+
+impl<Load: OutputPin, Clock: OutputPin, Data: InputPin> BitBangShiftIn<Load, Clock, Data> {
+    pub fn new(mut load: Load, mut clock: Clock, data: Data) -> Self {
+        load.set_high().ok();
+        clock.set_low().ok();
+        Self { load, clock, data }
+    }
+}
+
+impl<Load: OutputPin, Clock: OutputPin, Data: InputPin> ShiftIn
+    for BitBangShiftIn<Load, Clock, Data>
+{
+    fn shift_in(&mut self, bytes: &mut [u8]) {
+        self.load.set_low().ok();
+        self.load.set_high().ok();
+        for byte in bytes.iter_mut() {
+            *byte = 0;
+            for _ in 0..8 {
+                *byte = (*byte << 1) | self.data.is_high().unwrap_or_default() as u8;
+                self.clock.set_high().ok();
+                self.clock.set_low().ok();
+            }
+        }
+    }
+}
+
+/// Input shift registers on SPI, `SCK` is connected to `CLK` and `MISO` to `QH`.
+/// `SH/LD` is connected to a GPIO pin, because the registers can't shift while it's low
+pub struct SpiShiftIn<S: SpiBus, Load: OutputPin> {
+    spi: S,
+    load: Load,
+}
+
+impl<S: SpiBus, Load: OutputPin> SpiShiftIn<S, Load> {
+    pub fn new(spi: S, mut load: Load) -> Self {
+        load.set_high().ok();
+        Self { spi, load }
+    }
+}
+
+impl<S: SpiBus, Load: OutputPin> ShiftIn for SpiShiftIn<S, Load> {
+    fn shift_in(&mut self, bytes: &mut [u8]) {
+        self.load.set_low().ok();
+        self.load.set_high().ok();
+        if self.spi.read(bytes).and_then(|_| self.spi.flush()).is_err() {
+            error!("Failed to read input shift registers");
+        }
+    }
+}
+
+struct OutputState<S: ShiftOut, const N: usize> {
+    driver: S,
+    /// Byte `i` is the state of register `i` in the chain
+    bytes: [u8; N],
+}
+
+/// `N` chained output shift registers, see [`OutputShiftRegister::pins`]
+pub struct OutputShiftRegister<S: ShiftOut, const N: usize> {
+    state: RefCell<OutputState<S, N>>,
+}
+
+impl<S: ShiftOut, const N: usize> OutputShiftRegister<S, N> {
+    /// Create the registers, all outputs are set low
+    pub fn new(driver: S) -> Self {
+        let register = Self {
+            state: RefCell::new(OutputState {
+                driver,
+                bytes: [0; N],
+            }),
+        };
+        register.state.borrow_mut().flush();
+        register
+    }
+
+    /// Get the output pin at the index
+    pub fn pin(&self, index: usize) -> ShiftRegisterOutputPin<'_, S, N> {
+        assert!(index < N * 8, "Shift registers don't have pin {}", index);
+        ShiftRegisterOutputPin {
+            register: &self.state,
+            index,
+        }
+    }
+
+    /// Get the first `PINS` output pins, which are used as the output pins of the matrix
+    pub fn pins<const PINS: usize>(&self) -> [ShiftRegisterOutputPin<'_, S, N>; PINS] {
+        core::array::from_fn(|index| self.pin(index))
+    }
+}
+
+impl<S: ShiftOut, const N: usize> OutputState<S, N> {
+    fn set(&mut self, index: usize, high: bool) {
+        let byte = &mut self.bytes[index / 8];
+        let mask = 1 << (index % 8);
+        if (*byte & mask != 0) != high {
+            *byte ^= mask;
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        // The first byte shifted out goes to the end of the chain
+        let mut bytes = self.bytes;
+        bytes.reverse();
+        self.driver.shift_out(&bytes);
+    }
+}
+
+/// An output pin of [`OutputShiftRegister`], the register is written when the pin changes
+pub struct ShiftRegisterOutputPin<'a, S: ShiftOut, const N: usize> {
+    register: &'a RefCell<OutputState<S, N>>,
+    index: usize,
+}
+
+impl<S: ShiftOut, const N: usize> ErrorType for ShiftRegisterOutputPin<'_, S, N> {
+    type Error = Infallible;
+}
+
+impl<S: ShiftOut, const N: usize> OutputPin for ShiftRegisterOutputPin<'_, S, N> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.register.borrow_mut().set(self.index, false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.register.borrow_mut().set(self.index, true);
+        Ok(())
+    }
+}
+
+struct InputState<S: ShiftIn, const N: usize> {
+    driver: S,
+    /// Byte `i` is read from register `i` in the chain
+    bytes: [u8; N],
+    /// Pins read since the last load
+    read: [u8; N],
+}
+
+impl<S: ShiftIn, const N: usize> InputState<S, N> {
+    fn load(&mut self) {
+        self.driver.shift_in(&mut self.bytes);
+        self.read = [0; N];
+    }
+
+    /// Read the pin, the registers are loaded again when a pin is read twice,
+    /// so all pins are loaded once in a pass of the matrix scanning, whatever the order is
+    fn read(&mut self, index: usize) -> bool {
+        let mask = 1 << (index % 8);
+        if self.read[index / 8] & mask != 0 {
+            self.load();
+        }
+        self.read[index / 8] |= mask;
+        self.bytes[index / 8] & mask != 0
+    }
+}
+
+/// `N` chained input shift registers, see [`InputShiftRegister::pins`]
+pub struct InputShiftRegister<S: ShiftIn, const N: usize> {
+    state: RefCell<InputState<S, N>>,
+}
+
+impl<S: ShiftIn, const N: usize> InputShiftRegister<S, N> {
+    pub fn new(driver: S) -> Self {
+        Self {
+            state: RefCell::new(InputState {
+                driver,
+                bytes: [0; N],
+                // The first read loads the registers
+                read: [0xFF; N],
+            }),
+        }
+    }
+
+    /// Get the input pin at the index
+    pub fn pin(&self, index: usize) -> ShiftRegisterInputPin<'_, S, N> {
+        assert!(index < N * 8, "Shift registers don't have pin {}", index);
+        ShiftRegisterInputPin {
+            register: &self.state,
+            index,
+        }
+    }
+
+    /// Get the first `PINS` input pins, which are used as the input pins of the matrix
+    pub fn pins<const PINS: usize>(&self) -> [ShiftRegisterInputPin<'_, S, N>; PINS] {
+        core::array::from_fn(|index| self.pin(index))
+    }
+}
+
+/// An input pin of [`InputShiftRegister`].
+///
+/// The registers can't interrupt the MCU, with `async_matrix`, waiting for a pin polls the registers every millisecond.
+pub struct ShiftRegisterInputPin<'a, S: ShiftIn, const N: usize> {
+    register: &'a RefCell<InputState<S, N>>,
+    index: usize,
+}
+
+impl<S: ShiftIn, const N: usize> ErrorType for ShiftRegisterInputPin<'_, S, N> {
+    type Error = Infallible;
+}
+
+impl<S: ShiftIn, const N: usize> InputPin for ShiftRegisterInputPin<'_, S, N> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.register.borrow_mut().read(self.index))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+#[cfg(feature = "async_matrix")]
+impl<S: ShiftIn, const N: usize> ShiftRegisterInputPin<'_, S, N> {
+    /// Load the registers and read the pin
+    fn level(&mut self) -> bool {
+        let mut register = self.register.borrow_mut();
+        register.load();
+        register.read(self.index)
+    }
+
+    async fn wait_for_level(&mut self, high: bool) {
+        loop {
+            if self.level() == high {
+                return;
+            }
+            embassy_time::Timer::after_millis(1).await;
+        }
+    }
+}
+
+#[cfg(feature = "async_matrix")]
+impl<S: ShiftIn, const N: usize> embedded_hal_async::digital::Wait
+    for ShiftRegisterInputPin<'_, S, N>
+{
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(true).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(false).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(false).await;
+        self.wait_for_level(true).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(true).await;
+        self.wait_for_level(false).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        let high = self.level();
+        self.wait_for_level(!high).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct FakeShiftOut<'a>(&'a RefCell<heapless::Vec<u8, 4>>);
+
+    impl ShiftOut for FakeShiftOut<'_> {
+        fn shift_out(&mut self, bytes: &[u8]) {
+            let mut latched = self.0.borrow_mut();
+            latched.clear();
+            latched.extend_from_slice(bytes).unwrap();
+        }
+    }
+
+    struct FakeShiftIn<'a> {
+        inputs: &'a RefCell<[u8; 2]>,
+        loads: usize,
+    }
+
+    impl ShiftIn for FakeShiftIn<'_> {
+        fn shift_in(&mut self, bytes: &mut [u8]) {
+            bytes.copy_from_slice(&*self.inputs.borrow());
+            self.loads += 1;
+        }
+    }
+
+    #[test]
+    fn test_output_shift_register() {
+        let latched = RefCell::new(heapless::Vec::new());
+        let register = OutputShiftRegister::<_, 2>::new(FakeShiftOut(&latched));
+        let mut pins: [_; 12] = register.pins();
+        assert_eq!(latched.borrow().as_slice(), &[0, 0]);
+        pins[1].set_high().unwrap();
+        pins[9].set_high().unwrap();
+        // The register at the end of the chain goes first
+        assert_eq!(latched.borrow().as_slice(), &[0b10, 0b10]);
+        pins[1].set_low().unwrap();
+        assert_eq!(latched.borrow().as_slice(), &[0b10, 0]);
+    }
+
+    #[test]
+    fn test_input_shift_register() {
+        let inputs = RefCell::new([0b0000_0100, 0b0000_0001]);
+        let register = InputShiftRegister::<_, 2>::new(FakeShiftIn {
+            inputs: &inputs,
+            loads: 0,
+        });
+        let mut pins: [_; 10] = register.pins();
+        let levels: [bool; 10] = core::array::from_fn(|i| pins[i].is_high().unwrap());
+        assert_eq!(
+            levels,
+            [false, false, true, false, false, false, false, false, true, false]
+        );
+        // The next pass loads the registers again, only once
+        *inputs.borrow_mut() = [0, 0b0000_0010];
+        assert!(!pins[2].is_high().unwrap());
+        assert!(!pins[8].is_high().unwrap());
+        assert!(pins[9].is_high().unwrap());
+        assert_eq!(register.state.borrow().driver.loads, 2);
+    }
+}
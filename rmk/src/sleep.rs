@@ -0,0 +1,206 @@
+//! Keyboard sleep, which follows the sleep of the host
+//!
+//! While the keyboard sleeps, the matrix is scanned slowly, LEDs and the display are turned off and the BLE connection interval is longer,
+//! see [`crate::power::active_power_settings`]. A key press or any activity of the host wakes the keyboard up immediately.
+//!
+//! The keyboard sleeps after no key is pressed for `idle_timeout` of [`SleepConfig`](crate::config::SleepConfig).
+//! It sleeps sooner when the host is sleeping:
+//! - `host_suspend_timeout` after the host suspends the USB bus, the keyboard wakes up as soon as the bus is resumed.
+//! - `ble_idle_timeout` after the last key press or the last write of the BLE host, such as a LED indicator report.
+//!
+//! Suspends and resumes of the USB bus are published to [`HOST_SUSPEND_CHANNEL`]. RGB lighting and the display are turned off
+//! as soon as the bus is suspended, and a key press while the bus is suspended wakes the host up by USB remote wakeup,
+//! if the host allows it.
+//!
+//! When running on battery, the keyboard powers down to deep sleep after no key is pressed for `deep_sleep_timeout`.
+//! On nRF52, the chip is put into system off mode, all output pins of the matrix are driven high and all input pins sense high,
+//! so any key resets the chip. BLE advertising stops with the chip and starts again after the reset.
+//! On other chips, [`crate::power::SOFT_OFF_SIGNAL`] is signaled instead.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
+
+use crate::config::SleepConfig;
+use crate::power::{
+    current_power_source, power_down, PowerSource, POWER_PROFILE_CHANGED, POWER_SOURCE_CHANNEL,
+};
+use crate::usb::{UsbState, USB_STATE};
+
+/// Max number of subscribers of host suspend events
+pub const HOST_SUSPEND_SUBSCRIBERS: usize = 4;
+
+/// Channel which publishes suspends(`true`) and resumes(`false`) of the USB bus by the host.
+///
+/// Subscribe it to turn custom lighting or other peripherals off while the host sleeps.
+pub static HOST_SUSPEND_CHANNEL: PubSubChannel<
+    CriticalSectionRawMutex,
+    bool,
+    2,
+    HOST_SUSPEND_SUBSCRIBERS,
+    2,
+> = PubSubChannel::new();
+
+static SLEEPING: AtomicBool = AtomicBool::new(false);
+
+// When the USB bus is suspended by the host, `None` if it isn't suspended
+static HOST_SUSPENDED_AT: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+// Time of the last write from the BLE host
+static LAST_HOST_ACTIVITY: Mutex<CriticalSectionRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::from_ticks(0)));
+
+// Signaled when the host suspends or resumes, or the keyboard wakes up
+static SLEEP_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the keyboard is sleeping
+pub fn keyboard_sleeping() -> bool {
+    SLEEPING.load(Ordering::Relaxed)
+}
+
+/// Whether the USB bus is suspended by the host
+pub fn host_suspended() -> bool {
+    HOST_SUSPENDED_AT.lock(|s| s.get()).is_some()
+}
+
+fn set_sleeping(sleeping: bool) {
+    if SLEEPING.swap(sleeping, Ordering::Relaxed) != sleeping {
+        info!("Keyboard sleeping: {}", sleeping);
+        // Apply the settings of sleep, or restore the settings of the power profile
+        POWER_PROFILE_CHANGED.signal(());
+        crate::display::request_redraw();
+        SLEEP_CHANGED.signal(());
+    }
+}
+
+/// Wake the keyboard up
+pub(crate) fn wake_up() {
+    set_sleeping(false);
+}
+
+/// Wake the keyboard up on a key press or pointer motion, and the host as well if the USB bus is suspended
+pub(crate) fn wake_up_with_host() {
+    if host_suspended() {
+        crate::usb::request_remote_wakeup();
+    }
+    wake_up();
+}
+
+/// The USB bus is suspended or resumed by the host
+pub(crate) fn notify_host_suspended(suspended: bool) {
+    HOST_SUSPENDED_AT.lock(|s| s.set(suspended.then(Instant::now)));
+    HOST_SUSPEND_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(suspended);
+    // The display is turned off while the bus is suspended
+    crate::display::request_redraw();
+    if suspended {
+        SLEEP_CHANGED.signal(());
+    } else {
+        wake_up();
+    }
+}
+
+/// The BLE host writes to the keyboard or reconnects, which means the host is awake
+pub(crate) fn notify_host_activity() {
+    LAST_HOST_ACTIVITY.lock(|t| t.set(Instant::now()));
+    wake_up();
+    // The BLE idle timeout starts after connected
+    SLEEP_CHANGED.signal(());
+}
+
+/// When the keyboard should sleep, `None` if it never sleeps in current state
+fn sleep_deadline(config: &SleepConfig) -> Option<Instant> {
+    let last_key = crate::display::last_key_activity();
+    let mut deadline = config.idle_timeout.map(|t| last_key + t);
+    let mut sooner = |t: Option<Instant>| {
+        if let Some(t) = t {
+            deadline = Some(deadline.map_or(t, |d| d.min(t)));
+        }
+    };
+
+    // Keys pressed while the host is suspended, for example to wake the host, delay the sleep as well
+    if let Some(suspended_at) = HOST_SUSPENDED_AT.lock(|s| s.get()) {
+        sooner(
+            config
+                .host_suspend_timeout
+                .map(|t| suspended_at.max(last_key) + t),
+        );
+    }
+
+    // Only when the keyboard is connected via BLE
+    if cfg!(any(feature = "_nrf_ble", feature = "_esp_ble"))
+        && !usb_configured()
+        && crate::CONNECTION_STATE.load(Ordering::Acquire)
+    {
+        let last_host = LAST_HOST_ACTIVITY.lock(|t| t.get());
+        sooner(config.ble_idle_timeout.map(|t| last_host.max(last_key) + t));
+    }
+    deadline
+}
+
+fn usb_configured() -> bool {
+    UsbState::from(USB_STATE.load(Ordering::Acquire)) == UsbState::Configured
+}
+
+/// When the keyboard should power down to deep sleep, `None` if it doesn't in current state
+fn deep_sleep_deadline(config: &SleepConfig, on_battery_since: Option<Instant>) -> Option<Instant> {
+    // Keyboards without VBUS detection are always on battery, so USB connection is checked as well
+    if usb_configured() {
+        return None;
+    }
+    let last_key = crate::display::last_key_activity();
+    Some(last_key.max(on_battery_since?) + config.deep_sleep_timeout?)
+}
+
+/// Put the keyboard to sleep when the host is sleeping or the keyboard is idle,
+/// and power it down when it's idle for a long time on battery
+pub(crate) async fn run_sleep_monitor(config: SleepConfig) {
+    let mut power_sources = POWER_SOURCE_CHANNEL.subscriber().ok();
+    let mut on_battery_since = (current_power_source() == PowerSource::Battery).then(Instant::now);
+    loop {
+        let deep_sleep = deep_sleep_deadline(&config, on_battery_since);
+        if deep_sleep.is_some_and(|deadline| Instant::now() >= deadline) {
+            info!("Keyboard is idle, entering deep sleep");
+            set_sleeping(true);
+            power_down(config.wake_pins, config.drive_pins, true).await;
+            // Chips which are powered down by user code may return, the next key press wakes the keyboard up
+            SLEEP_CHANGED.wait().await;
+            continue;
+        }
+        let sleep = if keyboard_sleeping() {
+            None
+        } else {
+            sleep_deadline(&config)
+        };
+        if sleep.is_some_and(|deadline| Instant::now() >= deadline) {
+            set_sleeping(true);
+            continue;
+        }
+
+        // Key presses and host activity move the deadlines, they're checked again after waking up
+        let timer = async {
+            match [sleep, deep_sleep].into_iter().flatten().min() {
+                Some(deadline) => Timer::at(deadline).await,
+                None => core::future::pending().await,
+            }
+        };
+        let power_source_changed = async {
+            match power_sources.as_mut() {
+                Some(subscriber) => subscriber.next_message_pure().await,
+                None => core::future::pending().await,
+            }
+        };
+        if let Either3::Third(source) =
+            select3(timer, SLEEP_CHANGED.wait(), power_source_changed).await
+        {
+            on_battery_since = (source == PowerSource::Battery).then(Instant::now);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Instant release of opposing keys(SOCD, simultaneous opposing cardinal directions)
+//!
+//! Keys are paired by keycode in [`SocdConfig`](crate::config::SocdConfig), for example `A` and `D` for moving left and right in games.
+//! When a key is pressed while its opposing key is held, the opposing key is released immediately, so that the host sees only the latest direction.
+//! If `restore` is set, the opposing key is pressed again when the latest key is released while the opposing key is still held.
+
+use heapless::Vec;
+
+use crate::keycode::KeyCode;
+
+/// Max number of held keys which are tracked
+const MAX_HELD_KEYS: usize = 8;
+
+/// A held key which is in a SOCD pair
+struct HeldKey {
+    key: KeyCode,
+    pos: (u8, u8),
+    /// Whether the key is released by its opposing key
+    suppressed: bool,
+}
+
+/// Held keys of SOCD pairs
+#[derive(Default)]
+pub(crate) struct SocdState {
+    held: Vec<HeldKey, MAX_HELD_KEYS>,
+}
+
+/// The opposing key of `key` in `pair`
+fn opposing(pair: &(KeyCode, KeyCode), key: KeyCode) -> Option<KeyCode> {
+    if pair.0 == key {
+        Some(pair.1)
+    } else if pair.1 == key {
+        Some(pair.0)
+    } else {
+        None
+    }
+}
+
+impl SocdState {
+    /// A key is pressed, returns the opposing keys and their positions which should be released
+    pub(crate) fn press(
+        &mut self,
+        pairs: &[(KeyCode, KeyCode)],
+        key: KeyCode,
+        pos: (u8, u8),
+    ) -> Vec<(KeyCode, (u8, u8)), MAX_HELD_KEYS> {
+        let mut released = Vec::new();
+        if !pairs.iter().any(|p| opposing(p, key).is_some()) {
+            return released;
+        }
+        for held in self.held.iter_mut() {
+            if !held.suppressed && pairs.iter().any(|p| opposing(p, key) == Some(held.key)) {
+                held.suppressed = true;
+                released.push((held.key, held.pos)).ok();
+            }
+        }
+        if self
+            .held
+            .push(HeldKey {
+                key,
+                pos,
+                suppressed: false,
+            })
+            .is_err()
+        {
+            warn!("Too many SOCD keys are held");
+        }
+        released
+    }
+
+    /// A key is released.
+    ///
+    /// Returns whether the key is already released by an opposing key, and the opposing keys to be pressed again if `restore` is set
+    pub(crate) fn release(
+        &mut self,
+        pairs: &[(KeyCode, KeyCode)],
+        pos: (u8, u8),
+        restore: bool,
+    ) -> (bool, Vec<(KeyCode, (u8, u8)), MAX_HELD_KEYS>) {
+        let mut restored = Vec::new();
+        let Some(index) = self.held.iter().position(|k| k.pos == pos) else {
+            return (false, restored);
+        };
+        let released = self.held.swap_remove(index);
+        if released.suppressed {
+            return (true, restored);
+        }
+        // Restore opposing keys only if no other key of their pairs is active
+        if restore {
+            for i in 0..self.held.len() {
+                let key = self.held[i].key;
+                if !self.held[i].suppressed
+                    || !pairs.iter().any(|p| opposing(p, released.key) == Some(key))
+                {
+                    continue;
+                }
+                let blocked = self.held.iter().any(|k| {
+                    !k.suppressed && pairs.iter().any(|p| opposing(p, key) == Some(k.key))
+                });
+                if !blocked {
+                    self.held[i].suppressed = false;
+                    restored.push((key, self.held[i].pos)).ok();
+                }
+            }
+        }
+        (false, restored)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAIRS: &[(KeyCode, KeyCode)] = &[(KeyCode::A, KeyCode::D), (KeyCode::W, KeyCode::S)];
+    const A: (u8, u8) = (0, 0);
+    const D: (u8, u8) = (0, 2);
+
+    #[test]
+    fn test_socd() {
+        let mut socd = SocdState::default();
+        assert!(socd.press(PAIRS, KeyCode::A, A).is_empty());
+        // Unpaired key
+        assert!(socd.press(PAIRS, KeyCode::B, (1, 0)).is_empty());
+        assert_eq!(
+            socd.press(PAIRS, KeyCode::D, D).as_slice(),
+            &[(KeyCode::A, A)]
+        );
+        // A is pressed again when D is released
+        let (suppressed, restored) = socd.release(PAIRS, D, true);
+        assert!(!suppressed);
+        assert_eq!(restored.as_slice(), &[(KeyCode::A, A)]);
+
+        // Release the suppressed key
+        socd.press(PAIRS, KeyCode::D, D);
+        let (suppressed, restored) = socd.release(PAIRS, A, false);
+        assert!(suppressed && restored.is_empty());
+        let (suppressed, _) = socd.release(PAIRS, D, false);
+        assert!(!suppressed);
+    }
+}
@@ -11,16 +11,13 @@ use crate::action::KeyAction;
 #[cfg(feature = "_nrf_ble")]
 use crate::ble::nrf::initialize_nrf_ble_keyboard_and_run;
 use crate::config::RmkConfig;
-#[cfg(not(feature = "rapid_debouncer"))]
-use crate::debounce::default_bouncer::DefaultDebouncer;
-#[cfg(feature = "rapid_debouncer")]
-use crate::debounce::fast_debouncer::RapidDebouncer;
-use crate::debounce::{DebounceState, DebouncerTrait};
+use crate::debounce::{DebounceState, Debouncer, MatrixDebouncer};
+use crate::diagnostic::{key_tester_active, record_pin_state};
 use crate::event::KeyEvent;
 use crate::keyboard::{Keyboard, KEYBOARD_REPORT_CHANNEL, KEY_EVENT_CHANNEL};
 use crate::keymap::KeyMap;
 use crate::light::LightService;
-use crate::matrix::{KeyState, MatrixTrait};
+use crate::matrix::{KeyState, MatrixTrait, MatrixTransform};
 use crate::run_usb_keyboard;
 use crate::usb::KeyboardUsbDevice;
 use crate::via::process::VialService;
@@ -71,14 +68,12 @@ pub async fn run_rmk_split_central<
     #[cfg(not(feature = "_esp_ble"))] spawner: Spawner,
 ) -> ! {
     // Create the debouncer, use COL2ROW by default
-    #[cfg(all(feature = "col2row", feature = "rapid_debouncer"))]
-    let debouncer: RapidDebouncer<CENTRAL_ROW, CENTRAL_COL> = RapidDebouncer::new();
-    #[cfg(all(not(feature = "col2row"), feature = "rapid_debouncer"))]
-    let debouncer: RapidDebouncer<CENTRAL_COL, CENTRAL_ROW> = RapidDebouncer::new();
-    #[cfg(all(feature = "col2row", not(feature = "rapid_debouncer")))]
-    let debouncer: DefaultDebouncer<CENTRAL_ROW, CENTRAL_COL> = DefaultDebouncer::new();
-    #[cfg(all(not(feature = "col2row"), not(feature = "rapid_debouncer")))]
-    let debouncer: DefaultDebouncer<CENTRAL_COL, CENTRAL_ROW> = DefaultDebouncer::new();
+    #[cfg(feature = "col2row")]
+    let debouncer: MatrixDebouncer<CENTRAL_ROW, CENTRAL_COL> =
+        MatrixDebouncer::new(keyboard_config.debounce_config);
+    #[cfg(not(feature = "col2row"))]
+    let debouncer: MatrixDebouncer<CENTRAL_COL, CENTRAL_ROW> =
+        MatrixDebouncer::new(keyboard_config.debounce_config);
 
     // Keyboard matrix, use COL2ROW by default
     #[cfg(feature = "col2row")]
@@ -101,6 +96,7 @@ pub async fn run_rmk_split_central<
         CENTRAL_COL,
         CENTRAL_ROW,
     >::new(input_pins, output_pins, debouncer);
+    let matrix = matrix.with_transform(keyboard_config.matrix_transform);
 
     run_rmk_split_central_with_matrix(
         matrix,
@@ -159,10 +155,8 @@ pub async fn run_rmk_split_central_direct_pin<
 ) -> ! {
     info!("Debouncer");
     // Create the debouncer, use COL2ROW by default
-    #[cfg(feature = "rapid_debouncer")]
-    let debouncer: RapidDebouncer<CENTRAL_COL, CENTRAL_ROW> = RapidDebouncer::new();
-    #[cfg(not(feature = "rapid_debouncer"))]
-    let debouncer: DefaultDebouncer<CENTRAL_COL, CENTRAL_ROW> = DefaultDebouncer::new();
+    let debouncer: MatrixDebouncer<CENTRAL_COL, CENTRAL_ROW> =
+        MatrixDebouncer::new(keyboard_config.debounce_config);
 
     // Keyboard matrix, use COL2ROW by default
     let matrix = CentralDirectPinMatrix::<
@@ -173,7 +167,8 @@ pub async fn run_rmk_split_central_direct_pin<
         CENTRAL_ROW,
         CENTRAL_COL,
         SIZE,
-    >::new(direct_pins, debouncer, low_active);
+    >::new(direct_pins, debouncer, low_active)
+    .with_transform(keyboard_config.matrix_transform);
 
     run_rmk_split_central_with_matrix(
         matrix,
@@ -293,6 +288,9 @@ pub async fn initialize_usb_split_central_and_run<
 
     keyboard_config: RmkConfig<'static, Out>,
 ) -> ! {
+    crate::safe_mode::check_safe_mode(&mut matrix, keyboard_config.storage_config.safe_mode_key)
+        .await;
+
     // Initialize storage and keymap
     // For USB keyboard, the "external" storage means the storage initialized by the user.
     #[cfg(any(feature = "_nrf_ble", not(feature = "_no_external_storage")))]
@@ -301,6 +299,9 @@ pub async fn initialize_usb_split_central_and_run<
         let keymap = RefCell::new(
             KeyMap::<TOTAL_ROW, TOTAL_COL, NUM_LAYER>::new_from_storage(
                 default_keymap,
+                keyboard_config.encoder_map,
+                keyboard_config.macros,
+                keyboard_config.key_remap,
                 Some(&mut s),
             )
             .await,
@@ -309,7 +310,15 @@ pub async fn initialize_usb_split_central_and_run<
     };
 
     #[cfg(all(not(feature = "_nrf_ble"), feature = "_no_external_storage"))]
-    let keymap = RefCell::new(KeyMap::<TOTAL_ROW, TOTAL_COL, NUM_LAYER>::new(default_keymap).await);
+    let keymap = RefCell::new(
+        KeyMap::<TOTAL_ROW, TOTAL_COL, NUM_LAYER>::new(
+            default_keymap,
+            keyboard_config.encoder_map,
+            keyboard_config.macros,
+            keyboard_config.key_remap,
+        )
+        .await,
+    );
 
     let keyboard_report_sender = KEYBOARD_REPORT_CHANNEL.sender();
     let keyboard_report_receiver = KEYBOARD_REPORT_CHANNEL.receiver();
@@ -320,6 +329,7 @@ pub async fn initialize_usb_split_central_and_run<
             &keymap,
             &keyboard_report_sender,
             keyboard_config.behavior_config,
+            keyboard_config.mouse_config,
         ),
         KeyboardUsbDevice::new(usb_driver, keyboard_config.usb_config),
         VialService::new(&keymap, keyboard_config.vial_config),
@@ -345,7 +355,7 @@ pub(crate) struct CentralMatrix<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
     Out: OutputPin,
-    D: DebouncerTrait,
+    D: Debouncer,
     const ROW_OFFSET: usize,
     const COL_OFFSET: usize,
     const INPUT_PIN_NUM: usize,
@@ -361,13 +371,15 @@ pub(crate) struct CentralMatrix<
     key_states: [[KeyState; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
     /// Start scanning
     scan_start: Option<Instant>,
+    /// Transform of key positions of the central, applied before adding offsets
+    transform: MatrixTransform,
 }
 
 impl<
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         Out: OutputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const ROW_OFFSET: usize,
         const COL_OFFSET: usize,
         const INPUT_PIN_NUM: usize,
@@ -396,29 +408,34 @@ impl<
                 out_pin.set_high().ok();
                 Timer::after_micros(1).await;
                 for (in_idx, in_pin) in self.input_pins.iter_mut().enumerate() {
+                    #[cfg(feature = "col2row")]
+                    let (row, col) = (in_idx, out_idx);
+                    #[cfg(not(feature = "col2row"))]
+                    let (row, col) = (out_idx, in_idx);
+                    let (row, col) = self.transform.apply(row, col, Self::ROW, Self::COL);
+                    let (row, col) = ((row + ROW_OFFSET) as u8, (col + COL_OFFSET) as u8);
+
                     // Check input pins and debounce
+                    let pin_state = in_pin.is_high().ok().unwrap_or_default();
+                    if key_tester_active() {
+                        record_pin_state(
+                            row,
+                            col,
+                            pin_state,
+                            self.key_states[out_idx][in_idx].pressed,
+                        );
+                    }
                     let debounce_state = self.debouncer.detect_change_with_debounce(
                         in_idx,
                         out_idx,
-                        in_pin.is_high().ok().unwrap_or_default(),
+                        pin_state,
                         &self.key_states[out_idx][in_idx],
                     );
 
                     match debounce_state {
                         DebounceState::Debounced => {
                             self.key_states[out_idx][in_idx].toggle_pressed();
-                            #[cfg(feature = "col2row")]
-                            let (row, col, key_state) = (
-                                (in_idx + ROW_OFFSET) as u8,
-                                (out_idx + COL_OFFSET) as u8,
-                                self.key_states[out_idx][in_idx],
-                            );
-                            #[cfg(not(feature = "col2row"))]
-                            let (row, col, key_state) = (
-                                (out_idx + ROW_OFFSET) as u8,
-                                (in_idx + COL_OFFSET) as u8,
-                                self.key_states[out_idx][in_idx],
-                            );
+                            let key_state = self.key_states[out_idx][in_idx];
 
                             KEY_EVENT_CHANNEL
                                 .send(KeyEvent {
@@ -440,7 +457,7 @@ impl<
                 out_pin.set_low().ok();
             }
 
-            embassy_time::Timer::after_micros(100).await;
+            embassy_time::Timer::after(crate::power::scan_interval()).await;
         }
     }
 
@@ -491,7 +508,7 @@ impl<
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         Out: OutputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const ROW_OFFSET: usize,
         const COL_OFFSET: usize,
         const INPUT_PIN_NUM: usize,
@@ -510,15 +527,21 @@ impl<
             debouncer,
             key_states: [[KeyState::default(); INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
             scan_start: None,
+            transform: MatrixTransform::IDENTITY,
         }
     }
+
+    pub(crate) fn with_transform(mut self, transform: MatrixTransform) -> Self {
+        self.transform = transform;
+        self
+    }
 }
 
 /// DirectPinMartex only has input pins.
 pub(crate) struct CentralDirectPinMatrix<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
-    D: DebouncerTrait,
+    D: Debouncer,
     const ROW_OFFSET: usize,
     const COL_OFFSET: usize,
     const ROW: usize,
@@ -535,12 +558,14 @@ pub(crate) struct CentralDirectPinMatrix<
     scan_start: Option<Instant>,
     /// Pin active level
     low_active: bool,
+    /// Transform of key positions of the central, applied before adding offsets
+    transform: MatrixTransform,
 }
 
 impl<
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const ROW_OFFSET: usize,
         const COL_OFFSET: usize,
         const ROW: usize,
@@ -560,14 +585,20 @@ impl<
             key_states: [[KeyState::new(); COL]; ROW],
             scan_start: None,
             low_active,
+            transform: MatrixTransform::IDENTITY,
         }
     }
+
+    pub(crate) fn with_transform(mut self, transform: MatrixTransform) -> Self {
+        self.transform = transform;
+        self
+    }
 }
 
 impl<
         #[cfg(not(feature = "async_matrix"))] In: InputPin,
         #[cfg(feature = "async_matrix")] In: Wait + InputPin,
-        D: DebouncerTrait,
+        D: Debouncer,
         const ROW_OFFSET: usize,
         const COL_OFFSET: usize,
         const ROW: usize,
@@ -634,6 +665,16 @@ impl<
                             direct_pin.is_high().ok().unwrap_or_default()
                         };
 
+                        let (row, col) = self.transform.apply(row_idx, col_idx, ROW, COL);
+                        let (row, col) = ((row + ROW_OFFSET) as u8, (col + COL_OFFSET) as u8);
+                        if key_tester_active() {
+                            record_pin_state(
+                                row,
+                                col,
+                                pin_state,
+                                self.key_states[row_idx][col_idx].pressed,
+                            );
+                        }
                         let debounce_state = self.debouncer.detect_change_with_debounce(
                             col_idx,
                             row_idx,
@@ -644,11 +685,7 @@ impl<
                         match debounce_state {
                             DebounceState::Debounced => {
                                 self.key_states[row_idx][col_idx].toggle_pressed();
-                                let (col, row, key_state) = (
-                                    (col_idx + COL_OFFSET) as u8,
-                                    (row_idx + ROW_OFFSET) as u8,
-                                    self.key_states[row_idx][col_idx],
-                                );
+                                let key_state = self.key_states[row_idx][col_idx];
 
                                 KEY_EVENT_CHANNEL
                                     .send(KeyEvent {
@@ -670,7 +707,7 @@ impl<
                 }
             }
 
-            Timer::after_micros(100).await;
+            Timer::after(crate::power::scan_interval()).await;
         }
     }
 
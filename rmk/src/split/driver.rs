@@ -2,10 +2,14 @@ use core::sync::atomic::Ordering;
 
 ///! The abstracted driver layer of the split keyboard.
 ///!
-use super::SplitMessage;
+use super::{SplitMessage, SplitStatus};
+use crate::display::update_peer_battery_level;
 use crate::CONNECTION_STATE;
-use crate::{event::KeyEvent, keyboard::KEY_EVENT_CHANNEL};
-use embassy_futures::select::select;
+use crate::{
+    event::{KeyEvent, LAYER_EVENT_CHANNEL},
+    keyboard::KEY_EVENT_CHANNEL,
+};
+use embassy_futures::select::{select3, Either3};
 
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -14,7 +18,11 @@ pub(crate) enum SplitDriverError {
     EmptyMessage,
     DeserializeError,
     SerializeError,
+    /// The CRC of a received frame doesn't match, the frame is dropped
+    CrcError,
     BleError(u8),
+    /// The link to the other half is lost
+    Disconnected,
 }
 
 /// Split message reader from other split devices
@@ -46,6 +54,8 @@ pub(crate) struct PeripheralMatrixMonitor<
     receiver: R,
     /// Peripheral id
     id: usize,
+    /// Keys of the peripheral which are pressed in the central
+    held: [[bool; COL]; ROW],
 }
 
 impl<
@@ -57,14 +67,22 @@ impl<
     > PeripheralMatrixMonitor<ROW, COL, ROW_OFFSET, COL_OFFSET, R>
 {
     pub(crate) fn new(receiver: R, id: usize) -> Self {
-        Self { receiver, id }
+        Self {
+            receiver,
+            id,
+            held: [[false; COL]; ROW],
+        }
     }
 
     /// Run the monitor.
     ///
     /// The monitor receives from the peripheral and forward the message to `KEY_EVENT_CHANNEL`.
+    /// It also syncs the status of the central to the peripheral, when the status changes.
+    /// Layer changes are synced immediately, other status is checked every 500ms.
     pub(crate) async fn run(mut self) -> ! {
         let mut conn_state = CONNECTION_STATE.load(Ordering::Acquire);
+        let mut status: Option<SplitStatus> = None;
+        let mut layer_events = LAYER_EVENT_CHANNEL.subscriber().ok();
         // Send once on start
         if let Err(e) = self
             .receiver
@@ -74,19 +92,40 @@ impl<
             error!("SplitDriver write error: {:?}", e);
         }
         loop {
-            match select(self.receiver.read(), embassy_time::Timer::after_millis(500)).await {
-                embassy_futures::select::Either::First(read_result) => match read_result {
+            let layer_changed = async {
+                match layer_events.as_mut() {
+                    Some(sub) => {
+                        sub.next_message_pure().await;
+                    }
+                    None => core::future::pending().await,
+                }
+            };
+            match select3(
+                self.receiver.read(),
+                embassy_time::Timer::after_millis(500),
+                layer_changed,
+            )
+            .await
+            {
+                Either3::First(read_result) => match read_result {
                     Ok(received_message) => {
                         debug!("Received peripheral message: {:?}", received_message);
-                        if let SplitMessage::Key(e) = received_message {
+                        if let SplitMessage::BatteryLevel(level) = received_message {
+                            update_peer_battery_level(level);
+                        } else if let SplitMessage::Key(e) = received_message {
                             // Check row/col
-                            if e.row as usize > ROW || e.col as usize > COL {
+                            if e.row as usize >= ROW || e.col as usize >= COL {
                                 error!("Invalid peripheral row/col: {} {}", e.row, e.col);
                                 continue;
                             }
 
-                            if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) {
-                                // Only when the connection is established, send the key event.
+                            let held = &mut self.held[e.row as usize][e.col as usize];
+                            // Only when the connection is established, send the key event.
+                            // The release of a key which is already sent is always sent.
+                            if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire)
+                                || (*held && !e.pressed)
+                            {
+                                *held = e.pressed;
                                 KEY_EVENT_CHANNEL
                                     .send(KeyEvent {
                                         row: e.row + ROW_OFFSET as u8,
@@ -99,9 +138,13 @@ impl<
                             }
                         }
                     }
+                    Err(SplitDriverError::Disconnected) => {
+                        warn!("Peripheral {} is disconnected", self.id);
+                        self.release_held_keys().await;
+                    }
                     Err(e) => error!("Peripheral message read error: {:?}", e),
                 },
-                embassy_futures::select::Either::Second(_) => {
+                Either3::Second(_) => {
                     // Sync ConnectionState every 500ms
                     conn_state = CONNECTION_STATE.load(Ordering::Acquire);
                     if let Err(e) = self
@@ -111,8 +154,39 @@ impl<
                     {
                         error!("SplitDriver write error: {}", e);
                     };
+                    self.sync_status(&mut status).await;
+                }
+                Either3::Third(_) => self.sync_status(&mut status).await,
+            }
+        }
+    }
+
+    /// Release all keys of the peripheral pressed in the central, so that no key is stuck when the peripheral is lost
+    async fn release_held_keys(&mut self) {
+        for (row, cols) in self.held.iter_mut().enumerate() {
+            for (col, held) in cols.iter_mut().enumerate() {
+                if *held {
+                    *held = false;
+                    KEY_EVENT_CHANNEL
+                        .send(KeyEvent {
+                            row: (row + ROW_OFFSET) as u8,
+                            col: (col + COL_OFFSET) as u8,
+                            pressed: false,
+                        })
+                        .await;
                 }
             }
         }
     }
+
+    /// Sync the status to the peripheral only if it's changed
+    async fn sync_status(&mut self, status: &mut Option<SplitStatus>) {
+        let current = SplitStatus::current();
+        if *status != Some(current) {
+            match self.receiver.write(&SplitMessage::Status(current)).await {
+                Ok(_) => *status = Some(current),
+                Err(e) => error!("SplitDriver write error: {}", e),
+            }
+        }
+    }
 }
@@ -1,7 +1,11 @@
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
-use crate::event::KeyEvent;
+use crate::{
+    display::{CentralStatus, DisplayStatus},
+    event::KeyEvent,
+    generation::{config_generations, ConfigGenerations},
+};
 
 pub mod central;
 /// Common abstraction layer of split driver
@@ -27,4 +31,46 @@ pub(crate) enum SplitMessage {
     /// The central connection state, true if central has been connected to host.
     /// This message is sync from central to peripheral
     ConnectionState(bool),
+    /// Status of the central, which is shown on the peripheral's display
+    Status(SplitStatus),
+    /// Battery level of the peripheral in percent, from peripheral to central
+    BatteryLevel(u8),
+}
+
+/// Compact status of the central synced to peripherals
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, MaxSize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct SplitStatus {
+    pub(crate) layer: u8,
+    /// Battery level of the central, 0xFF if unknown
+    pub(crate) battery_level: u8,
+    pub(crate) ble: bool,
+    pub(crate) connected: bool,
+    /// Generations of the config of the central
+    pub(crate) generations: ConfigGenerations,
+}
+
+impl SplitStatus {
+    /// Current status of the central
+    pub(crate) fn current() -> Self {
+        let status = DisplayStatus::current();
+        Self {
+            layer: status.layer,
+            battery_level: status.battery_level.unwrap_or(0xFF),
+            ble: status.ble,
+            connected: status.connected,
+            generations: config_generations(),
+        }
+    }
+}
+
+impl From<SplitStatus> for CentralStatus {
+    fn from(status: SplitStatus) -> Self {
+        Self {
+            layer: status.layer,
+            battery_level: (status.battery_level <= 100).then_some(status.battery_level),
+            ble: status.ble,
+            connected: status.connected,
+        }
+    }
 }
@@ -8,6 +8,7 @@ use embassy_sync::{
 use nrf_softdevice::ble::{central, gatt_client, Address, AddressType};
 
 use crate::{
+    ble::{diagnostics::SPLIT_LINK_STATS, nrf::sample_rssi},
     split::{
         driver::{PeripheralMatrixMonitor, SplitDriverError, SplitReader, SplitWriter},
         SplitMessage, SPLIT_MESSAGE_MAX_SIZE,
@@ -34,8 +35,12 @@ pub(crate) async fn run_ble_peripheral_monitor<
     id: usize,
     addr: [u8; 6],
 ) {
-    // Channel is used to receive messages from peripheral
-    let receive_channel: Channel<CriticalSectionRawMutex, SplitMessage, 8> = Channel::new();
+    // Channel is used to receive messages from peripheral, and the disconnection of the peripheral
+    let receive_channel: Channel<
+        CriticalSectionRawMutex,
+        Result<SplitMessage, SplitDriverError>,
+        8,
+    > = Channel::new();
     // Channel is used to notify messages to peripheral
     let notify_channel: Channel<CriticalSectionRawMutex, SplitMessage, 8> = Channel::new();
 
@@ -64,9 +69,10 @@ static CONNECTING_CLIENT: AtomicBool = AtomicBool::new(false);
 /// Run a single ble client, which receives split message from the ble peripheral.
 ///
 /// All received messages are sent to the sender, those message are received in `SplitBleCentralDriver`.
+/// When the peripheral is disconnected, `SplitDriverError::Disconnected` is sent, so that its held keys are released.
 /// Split driver will take `SplitBleCentralDriver` as the reader, process the message in matrix scanning.
 pub(crate) async fn run_ble_client(
-    receive_sender: Sender<'_, CriticalSectionRawMutex, SplitMessage, 8>,
+    receive_sender: Sender<'_, CriticalSectionRawMutex, Result<SplitMessage, SplitDriverError>, 8>,
     notify_receiver: Receiver<'_, CriticalSectionRawMutex, SplitMessage, 8>,
     addr: [u8; 6],
 ) -> ! {
@@ -125,7 +131,7 @@ pub(crate) async fn run_ble_client(
                 match postcard::from_bytes(&message) {
                     Ok(split_message) => {
                         info!("Received split message from peripheral: {}", split_message);
-                        if let Err(e) = receive_sender.try_send(split_message) {
+                        if let Err(e) = receive_sender.try_send(Ok(split_message)) {
                             error!("BLE_SYNC_CHANNEL send message error: {:?}", e);
                         }
                     }
@@ -143,7 +149,9 @@ pub(crate) async fn run_ble_client(
                 let message = notify_receiver.receive().await;
                 match postcard::to_slice(&message, &mut buf) {
                     Ok(_bytes) => {
-                        if let Err(e) = ble_client.message_to_peripheral_write(&buf).await {
+                        let result = ble_client.message_to_peripheral_write(&buf).await;
+                        SPLIT_LINK_STATS.record(&result);
+                        if let Err(e) = result {
                             error!("BLE message_to_peripheral_write error: {:?}", e);
                         }
                     }
@@ -152,12 +160,22 @@ pub(crate) async fn run_ble_client(
             }
         };
 
-        match select(receive_peripheral, notify_peripheral).await {
+        SPLIT_LINK_STATS.on_connected();
+        match select(
+            receive_peripheral,
+            select(notify_peripheral, sample_rssi(&conn, &SPLIT_LINK_STATS)),
+        )
+        .await
+        {
             embassy_futures::select::Either::First(e) => {
                 error!("BLE peripheral disconnect error: {:?}", e);
             }
             embassy_futures::select::Either::Second(_) => (),
         }
+        SPLIT_LINK_STATS.on_disconnected();
+        receive_sender
+            .send(Err(SplitDriverError::Disconnected))
+            .await;
 
         // Wait for 1s before trying to connect (again)
         embassy_time::Timer::after_secs(1).await;
@@ -172,7 +190,8 @@ pub(crate) async fn run_ble_client(
 /// so we need this wrapper to forward split message to channel.
 pub(crate) struct BleSplitCentralDriver<'a> {
     // Receiver that receives message from peripheral
-    pub(crate) receiver: Receiver<'a, CriticalSectionRawMutex, SplitMessage, 8>,
+    pub(crate) receiver:
+        Receiver<'a, CriticalSectionRawMutex, Result<SplitMessage, SplitDriverError>, 8>,
     // Sender that send message to peripherals
     pub(crate) sender: Sender<'a, CriticalSectionRawMutex, SplitMessage, 8>,
     // Cached connection state
@@ -181,7 +200,7 @@ pub(crate) struct BleSplitCentralDriver<'a> {
 
 impl<'a> SplitReader for BleSplitCentralDriver<'a> {
     async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
-        Ok(self.receiver.receive().await)
+        self.receiver.receive().await
     }
 }
 
@@ -1,4 +1,9 @@
-use crate::ble::nrf::softdevice_task;
+use crate::ble::diagnostics::SPLIT_LINK_STATS;
+use crate::ble::nrf::{sample_rssi, softdevice_task};
+use crate::ram_profile::{
+    SD_ATT_MTU, SPLIT_PERIPHERAL_SD_CENTRAL_ROLE_COUNT, SPLIT_PERIPHERAL_SD_CENTRAL_SEC_COUNT,
+    SPLIT_PERIPHERAL_SD_CONN_COUNT, SPLIT_PERIPHERAL_SD_PERIPH_ROLE_COUNT,
+};
 use crate::split::driver::{SplitDriverError, SplitReader, SplitWriter};
 use crate::split::peripheral::SplitPeripheral;
 use crate::split::{SplitMessage, SPLIT_MESSAGE_MAX_SIZE};
@@ -64,12 +69,13 @@ impl<'a> SplitWriter for BleSplitPeripheralDriver<'a> {
             error!("Postcard serialize split message error: {}", e);
             SplitDriverError::SerializeError
         })?;
-        gatt_server::notify_value(
+        let result = gatt_server::notify_value(
             &self.conn,
             self.server.service.message_to_central_value_handle,
             bytes,
-        )
-        .map_err(|e| {
+        );
+        SPLIT_LINK_STATS.record(&result);
+        result.map_err(|e| {
             error!("BLE notify error: {:?}", e);
             SplitDriverError::BleError(1)
         })?;
@@ -94,7 +100,7 @@ pub async fn initialize_nrf_ble_split_peripheral_and_run<
     peripheral_addr: [u8; 6],
     spawner: Spawner,
 ) -> ! {
-    use embassy_futures::select::select3;
+    use embassy_futures::select::select4;
     use nrf_softdevice::ble::gatt_server;
 
     use crate::{
@@ -118,18 +124,20 @@ pub async fn initialize_nrf_ble_split_peripheral_and_run<
             // accuracy: raw::NRF_CLOCK_LF_ACCURACY_20_PPM as u8,
         }),
         conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 6,
+            conn_count: SPLIT_PERIPHERAL_SD_CONN_COUNT,
             event_length: 24,
         }),
-        conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 256 }),
+        conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
+            att_mtu: SD_ATT_MTU,
+        }),
         gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
             attr_tab_size: raw::BLE_GATTS_ATTR_TAB_SIZE_DEFAULT,
         }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: 1,
-            periph_role_count: 4,
-            central_role_count: 4,
-            central_sec_count: 4,
+            periph_role_count: SPLIT_PERIPHERAL_SD_PERIPH_ROLE_COUNT,
+            central_role_count: SPLIT_PERIPHERAL_SD_CENTRAL_ROLE_COUNT,
+            central_sec_count: SPLIT_PERIPHERAL_SD_CENTRAL_SEC_COUNT,
             _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
         }),
         gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
@@ -222,6 +230,9 @@ pub async fn initialize_nrf_ble_split_peripheral_and_run<
             SplitPeripheral::new(BleSplitPeripheralDriver::new(&server, &conn, receiver));
         let peripheral_fut = peripheral.run();
         let matrix_fut = matrix.run();
-        select3(matrix_fut, server_fut, peripheral_fut).await;
+        let rssi_fut = sample_rssi(&conn, &SPLIT_LINK_STATS);
+        SPLIT_LINK_STATS.on_connected();
+        select4(matrix_fut, server_fut, peripheral_fut, rssi_fut).await;
+        SPLIT_LINK_STATS.on_disconnected();
     }
 }
@@ -1,23 +1,36 @@
 use super::driver::{SplitReader, SplitWriter};
 use super::SplitMessage;
-#[cfg(not(feature = "rapid_debouncer"))]
-use crate::debounce::default_bouncer::DefaultDebouncer;
-#[cfg(feature = "rapid_debouncer")]
-use crate::debounce::fast_debouncer::RapidDebouncer;
-use crate::debounce::DebouncerTrait;
+use crate::config::DebounceConfig;
+use crate::debounce::{Debouncer, MatrixDebouncer};
 use crate::direct_pin::DirectPinMatrix;
+use crate::display::update_central_status;
+use crate::generation::ConfigGenerations;
 use crate::keyboard::KEY_EVENT_CHANNEL;
 use crate::matrix::{Matrix, MatrixTrait};
 use crate::CONNECTION_STATE;
+use core::cell::Cell;
 #[cfg(feature = "_nrf_ble")]
 use embassy_executor::Spawner;
-use embassy_futures::select::select;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Timer;
 use embedded_hal::digital::{InputPin, OutputPin};
 #[cfg(feature = "async_matrix")]
 use embedded_hal_async::digital::Wait;
 #[cfg(not(feature = "_nrf_ble"))]
 use embedded_io_async::{Read, Write};
 
+/// Config generations of the central, received in the status
+static CENTRAL_CONFIG_GENERATIONS: Mutex<CriticalSectionRawMutex, Cell<Option<ConfigGenerations>>> =
+    Mutex::new(Cell::new(None));
+
+/// Epoch and generations of the config of the central, `None` before the first status is received.
+///
+/// Compare them with the generations of the last sync by [`ConfigGenerations::changed_since`] to find changed parts of the config.
+pub fn central_config_generations() -> Option<ConfigGenerations> {
+    CENTRAL_CONFIG_GENERATIONS.lock(|g| g.get())
+}
+
 /// Run the split peripheral service.
 ///
 /// # Arguments
@@ -28,6 +41,9 @@ use embedded_io_async::{Read, Write};
 /// * `peripheral_addr` - (optional) peripheral's BLE static address. This argument is enabled only for nRF BLE split now
 /// * `serial` - (optional) serial port used to send peripheral split message. This argument is enabled only for serial split now
 /// * `spawner`: (optional) embassy spawner used to spawn async tasks. This argument is enabled for non-esp microcontrollers
+///
+/// Keys are debounced with the default debounce time, create the matrix and run it by `run_rmk_split_peripheral_with_matrix`
+/// to use another debouncer or debounce time, or to transform positions of keys by `Matrix::with_transform`.
 pub async fn run_rmk_split_peripheral<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
@@ -46,14 +62,10 @@ pub async fn run_rmk_split_peripheral<
     #[cfg(feature = "_nrf_ble")] spawner: Spawner,
 ) {
     // Create the debouncer, use COL2ROW by default
-    #[cfg(all(feature = "col2row", feature = "rapid_debouncer"))]
-    let debouncer = RapidDebouncer::<ROW, COL>::new();
-    #[cfg(all(feature = "col2row", not(feature = "rapid_debouncer")))]
-    let debouncer = DefaultDebouncer::<ROW, COL>::new();
-    #[cfg(all(not(feature = "col2row"), feature = "rapid_debouncer"))]
-    let debouncer = RapidDebouncer::<COL, ROW>::new();
-    #[cfg(all(not(feature = "col2row"), not(feature = "rapid_debouncer")))]
-    let debouncer = DefaultDebouncer::<COL, ROW>::new();
+    #[cfg(feature = "col2row")]
+    let debouncer = MatrixDebouncer::<ROW, COL>::new(DebounceConfig::default());
+    #[cfg(not(feature = "col2row"))]
+    let debouncer = MatrixDebouncer::<COL, ROW>::new(DebounceConfig::default());
 
     // Keyboard matrix, use COL2ROW by default
     #[cfg(feature = "col2row")]
@@ -62,21 +74,16 @@ pub async fn run_rmk_split_peripheral<
     let matrix = Matrix::<_, _, _, COL, ROW>::new(input_pins, output_pins, debouncer);
 
     #[cfg(feature = "_nrf_ble")]
-    run_rmk_split_peripheral_with_matrix::<
-        _,
-        ROW,
-        COL,
-    >(matrix, central_addr, peripheral_addr, spawner)
+    run_rmk_split_peripheral_with_matrix::<_, ROW, COL>(
+        matrix,
+        central_addr,
+        peripheral_addr,
+        spawner,
+    )
     .await;
 
     #[cfg(not(feature = "_nrf_ble"))]
-    run_rmk_split_peripheral_with_matrix::<
-        _,
-        S,
-        ROW,
-        COL,
-    >(matrix, serial)
-    .await;
+    run_rmk_split_peripheral_with_matrix::<_, S, ROW, COL>(matrix, serial).await;
 }
 
 /// Run the split peripheral service with direct pin matrix.
@@ -89,6 +96,9 @@ pub async fn run_rmk_split_peripheral<
 /// * `low_active`: pin active level
 /// * `serial` - (optional) serial port used to send peripheral split message. This argument is enabled only for serial split now
 /// * `spawner`: (optional) embassy spawner used to spawn async tasks. This argument is enabled for non-esp microcontrollers
+///
+/// Keys are debounced with the default debounce time, create the matrix and run it by `run_rmk_split_peripheral_with_matrix`
+/// to use another debouncer or debounce time.
 pub async fn run_rmk_split_peripheral_direct_pin<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
@@ -106,30 +116,22 @@ pub async fn run_rmk_split_peripheral_direct_pin<
     #[cfg(feature = "_nrf_ble")] spawner: Spawner,
 ) {
     // Create the debouncer, use COL2ROW by default
-    #[cfg(feature = "rapid_debouncer")]
-    let debouncer = RapidDebouncer::<COL, ROW>::new();
-    #[cfg(not(feature = "rapid_debouncer"))]
-    let debouncer = DefaultDebouncer::<COL, ROW>::new();
+    let debouncer = MatrixDebouncer::<COL, ROW>::new(DebounceConfig::default());
 
     // Keyboard matrix
     let matrix = DirectPinMatrix::<_, _, ROW, COL, SIZE>::new(direct_pins, debouncer, low_active);
 
     #[cfg(feature = "_nrf_ble")]
-    run_rmk_split_peripheral_with_matrix::<
-        _,
-        ROW,
-        COL,
-    >(matrix, central_addr, peripheral_addr, spawner)
+    run_rmk_split_peripheral_with_matrix::<_, ROW, COL>(
+        matrix,
+        central_addr,
+        peripheral_addr,
+        spawner,
+    )
     .await;
 
     #[cfg(not(feature = "_nrf_ble"))]
-    run_rmk_split_peripheral_with_matrix::<
-        _,
-        S,
-        ROW,
-        COL,
-    >(matrix, serial)
-    .await;
+    run_rmk_split_peripheral_with_matrix::<_, S, ROW, COL>(matrix, serial).await;
 }
 
 /// Run the split peripheral service.
@@ -184,28 +186,56 @@ impl<S: SplitWriter + SplitReader> SplitPeripheral<S> {
     /// The peripheral uses the general matrix, does scanning and send the key events through `SplitWriter`.
     /// If also receives split messages from the central through `SplitReader`.
     pub(crate) async fn run(&mut self) -> ! {
+        let mut battery_level = None;
         loop {
-            match select(self.split_driver.read(), KEY_EVENT_CHANNEL.receive()).await {
-                embassy_futures::select::Either::First(m) => match m {
+            match select3(
+                self.split_driver.read(),
+                KEY_EVENT_CHANNEL.receive(),
+                Timer::after_secs(10),
+            )
+            .await
+            {
+                Either3::First(m) => match m {
                     // Currently only handle the central state message
                     Ok(split_message) => match split_message {
                         SplitMessage::ConnectionState(state) => {
                             info!("Received connection state update: {}", state);
                             CONNECTION_STATE.store(state, core::sync::atomic::Ordering::Release);
                         }
+                        SplitMessage::Status(status) => {
+                            CENTRAL_CONFIG_GENERATIONS.lock(|g| g.set(Some(status.generations)));
+                            update_central_status(status.into());
+                        }
                         _ => (),
                     },
                     Err(e) => {
                         error!("Split message read error: {:?}", e);
                     }
                 },
-                embassy_futures::select::Either::Second(e) => {
-                    // Only send the key event if the connection is established
-                    if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) {
+                Either3::Second(e) => {
+                    // Only send the key event if the connection is established.
+                    // Releases are always sent, the key may be pressed before the connection is lost
+                    if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) || !e.pressed {
                         info!("Writing split message to central");
                         self.split_driver.write(&SplitMessage::Key(e)).await.ok();
                     }
                 }
+                Either3::Third(_) => {
+                    // Report the battery level to the central when it's changed
+                    let current = crate::power::battery_level();
+                    if current != battery_level {
+                        if let Some(level) = current {
+                            if self
+                                .split_driver
+                                .write(&SplitMessage::BatteryLevel(level))
+                                .await
+                                .is_ok()
+                            {
+                                battery_level = current;
+                            }
+                        }
+                    }
+                }
             }
         }
     }
@@ -1,16 +1,23 @@
-use embedded_io_async::{Read, Write};
+use embedded_io_async::{ErrorType, Read, Write};
+use postcard::experimental::max_size::MaxSize;
 
 use crate::{
     matrix::MatrixTrait,
     split::{
         driver::{PeripheralMatrixMonitor, SplitReader, SplitWriter},
         peripheral::SplitPeripheral,
-        SplitMessage, SPLIT_MESSAGE_MAX_SIZE,
+        SplitMessage,
     },
 };
 
 use super::driver::SplitDriverError;
 
+/// End of a COBS encoded frame
+const SENTINEL: u8 = 0x00;
+
+/// Maximum size of a frame on the serial link, the split message with its CRC and the COBS overhead
+const SERIAL_FRAME_MAX_SIZE: usize = SplitMessage::POSTCARD_MAX_SIZE + u16::POSTCARD_MAX_SIZE + 4;
+
 // Receive split message from peripheral via serial and process it
 ///
 /// Generic parameters:
@@ -41,7 +48,7 @@ pub(crate) async fn run_serial_peripheral_monitor<
 /// Serial driver for BOTH split central and peripheral
 pub(crate) struct SerialSplitDriver<S: Read + Write> {
     serial: S,
-    buffer: [u8; SPLIT_MESSAGE_MAX_SIZE],
+    buffer: [u8; SERIAL_FRAME_MAX_SIZE],
     n_bytes_part: usize,
 }
 
@@ -49,7 +56,7 @@ impl<S: Read + Write> SerialSplitDriver<S> {
     pub(crate) fn new(serial: S) -> Self {
         Self {
             serial,
-            buffer: [0_u8; SPLIT_MESSAGE_MAX_SIZE],
+            buffer: [0_u8; SERIAL_FRAME_MAX_SIZE],
             n_bytes_part: 0,
         }
     }
@@ -57,7 +64,6 @@ impl<S: Read + Write> SerialSplitDriver<S> {
 
 impl<S: Read + Write> SplitReader for SerialSplitDriver<S> {
     async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
-        const SENTINEL: u8 = 0x00;
         while self.n_bytes_part < self.buffer.len() {
             let n_bytes = self
                 .serial
@@ -77,17 +83,15 @@ impl<S: Read + Write> SplitReader for SerialSplitDriver<S> {
             }
         }
 
-        let (result, n_bytes_unused) = match postcard::take_from_bytes_cobs::<SplitMessage>(
-            &mut self.buffer.clone()[..self.n_bytes_part],
-        ) {
-            Ok((message, unused_bytes)) => (Ok(message), unused_bytes.len()),
+        let (result, n_bytes_unused) = match decode_frame(&self.buffer[..self.n_bytes_part]) {
+            Ok((message, n_bytes_unused)) => (Ok(message), n_bytes_unused),
             Err(e) => {
-                error!("Postcard deserialize split message error: {}", e);
+                // Drop the broken frame
                 let n_bytes_unused = self.buffer[..self.n_bytes_part]
                     .iter()
                     .position(|&x| x == SENTINEL)
                     .map_or(0, |index| self.n_bytes_part - index - 1);
-                (Err(SplitDriverError::SerializeError), n_bytes_unused)
+                (Err(e), n_bytes_unused)
             }
         };
 
@@ -101,11 +105,8 @@ impl<S: Read + Write> SplitReader for SerialSplitDriver<S> {
 
 impl<S: Read + Write> SplitWriter for SerialSplitDriver<S> {
     async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
-        let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE];
-        let bytes = postcard::to_slice_cobs(message, &mut buf).map_err(|e| {
-            error!("Postcard serialize split message error: {}", e);
-            SplitDriverError::SerializeError
-        })?;
+        let mut buf = [0_u8; SERIAL_FRAME_MAX_SIZE];
+        let bytes = encode_frame(message, &mut buf)?;
         let mut remaining_bytes = bytes.len();
         while remaining_bytes > 0 {
             let sent_bytes = self
@@ -119,6 +120,110 @@ impl<S: Read + Write> SplitWriter for SerialSplitDriver<S> {
     }
 }
 
+/// CRC-16/CCITT-FALSE of the serialized message, which is appended to every frame
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC of the message, computed over its postcard serialization
+fn message_crc(message: &SplitMessage) -> Result<u16, SplitDriverError> {
+    let mut buf = [0_u8; SplitMessage::POSTCARD_MAX_SIZE];
+    let bytes = postcard::to_slice(message, &mut buf).map_err(|e| {
+        error!("Postcard serialize split message error: {}", e);
+        SplitDriverError::SerializeError
+    })?;
+    Ok(crc16(bytes))
+}
+
+/// Encode a message to a frame in `buf`: the message and its CRC, COBS encoded and terminated by [`SENTINEL`]
+fn encode_frame<'b>(
+    message: &SplitMessage,
+    buf: &'b mut [u8; SERIAL_FRAME_MAX_SIZE],
+) -> Result<&'b mut [u8], SplitDriverError> {
+    let crc = message_crc(message)?;
+    postcard::to_slice_cobs(&(message, crc), buf).map_err(|e| {
+        error!("Postcard serialize split message error: {}", e);
+        SplitDriverError::SerializeError
+    })
+}
+
+/// Decode the first frame in `bytes`, returns the message and the number of bytes after the frame
+fn decode_frame(bytes: &[u8]) -> Result<(SplitMessage, usize), SplitDriverError> {
+    let mut buf = [0_u8; SERIAL_FRAME_MAX_SIZE];
+    let buf = &mut buf[..bytes.len()];
+    buf.copy_from_slice(bytes);
+    let ((message, crc), unused_bytes) = postcard::take_from_bytes_cobs::<(SplitMessage, u16)>(buf)
+        .map_err(|e| {
+            error!("Postcard deserialize split message error: {}", e);
+            SplitDriverError::DeserializeError
+        })?;
+    let n_bytes_unused = unused_bytes.len();
+    if message_crc(&message)? != crc {
+        error!("Split message CRC mismatch");
+        return Err(SplitDriverError::CrcError);
+    }
+    Ok((message, n_bytes_unused))
+}
+
+/// A single-wire(half-duplex) serial port, which reads back every byte it writes.
+///
+/// Wrap the serial port with it when TX and RX of the UART share one wire, so that the echo of written bytes is discarded.
+/// Both halves may start writing at the same time on a single wire,
+/// the frames corrupted by such collision are dropped by the CRC check.
+pub struct HalfDuplexSerial<S> {
+    serial: S,
+    /// Number of echoed bytes which are not read back yet
+    echo: usize,
+}
+
+impl<S> HalfDuplexSerial<S> {
+    pub fn new(serial: S) -> Self {
+        Self { serial, echo: 0 }
+    }
+}
+
+impl<S: ErrorType> ErrorType for HalfDuplexSerial<S> {
+    type Error = S::Error;
+}
+
+impl<S: Read> Read for HalfDuplexSerial<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut discard = [0_u8; 16];
+        while self.echo > 0 {
+            let n = self.echo.min(discard.len());
+            let n_bytes = self.serial.read(&mut discard[..n]).await?;
+            if n_bytes == 0 {
+                return Ok(0);
+            }
+            self.echo -= n_bytes;
+        }
+        self.serial.read(buf).await
+    }
+}
+
+impl<S: Write> Write for HalfDuplexSerial<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n_bytes = self.serial.write(buf).await?;
+        self.echo += n_bytes;
+        Ok(n_bytes)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.serial.flush().await
+    }
+}
+
 /// Initialize and run the peripheral keyboard service via serial.
 ///
 /// # Arguments
@@ -142,3 +247,42 @@ pub async fn initialize_serial_split_peripheral_and_run<
         select(matrix.run(), peripheral.run()).await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::KeyEvent;
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn test_frame() {
+        let message = SplitMessage::Key(KeyEvent {
+            row: 1,
+            col: 2,
+            pressed: true,
+        });
+        let mut buf = [0_u8; SERIAL_FRAME_MAX_SIZE];
+        let frame = encode_frame(&message, &mut buf).unwrap();
+        assert_eq!(frame.last(), Some(&SENTINEL));
+        let len = frame.len();
+        assert!(matches!(
+            decode_frame(&buf[..len]),
+            Ok((
+                SplitMessage::Key(KeyEvent {
+                    row: 1,
+                    col: 2,
+                    pressed: true
+                }),
+                0
+            ))
+        ));
+
+        // Flip a bit of the payload
+        buf[2] ^= 0x04;
+        assert!(decode_frame(&buf[..len]).is_err());
+    }
+}
@@ -31,7 +31,7 @@ pub(crate) struct EeKeymapConfig {
     #[bits(1)]
     swap_backslash_backspace: bool,
     #[bits(1)]
-    nkro: bool,
+    pub(crate) nkro: bool,
     #[bits(1)]
     swap_lctl_lgui: bool,
     #[bits(1)]
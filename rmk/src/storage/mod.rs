@@ -2,11 +2,16 @@ mod eeconfig;
 pub mod nor_flash;
 
 use crate::config::StorageConfig;
+#[cfg(feature = "host_auth")]
+use crate::via::auth::{restore_auth_secret, AuthSecret, MAX_SECRET_LEN};
 use byteorder::{BigEndian, ByteOrder};
 use core::fmt::Debug;
 use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
 use embedded_storage::nor_flash::NorFlash;
 use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
 use sequential_storage::{
@@ -17,9 +22,16 @@ use sequential_storage::{
 #[cfg(feature = "_nrf_ble")]
 use {crate::ble::nrf::bonder::BondInfo, core::mem};
 
-use crate::keyboard_macro::MACRO_SPACE_SIZE;
+use crate::keyboard_macro::{
+    restore_dynamic_macro, DYNAMIC_MACRO_SIZE, MACRO_SPACE_SIZE, NUM_DYNAMIC_MACRO,
+};
 use crate::{
     action::KeyAction,
+    generation::set_config_epoch,
+    health::{restore_health_counters, HealthCounters},
+    input_device::rotary_encoder::{EncoderAction, EncoderMap},
+    power::{set_last_shutdown, ShutdownReason},
+    rgb::{Effect, Hsv, RgbSettings, ZoneState, MAX_LED_ZONES},
     via::keycode_convert::{from_via_keycode, to_via_keycode},
 };
 
@@ -29,6 +41,13 @@ use self::eeconfig::EeKeymapConfig;
 pub(crate) static FLASH_CHANNEL: Channel<CriticalSectionRawMutex, FlashOperationMessage, 4> =
     Channel::new();
 
+// Signal sent when a power loss is detected(`true`), the storage task flushes all pending writes then.
+// `false` is sent when the power is back, then the keyboard is marked as running again
+pub(crate) static POWER_LOSS_SIGNAL: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+// Size of the flash used as storage in bytes, 0 if the storage is not initialized
+pub(crate) static STORAGE_SIZE: AtomicU32 = AtomicU32::new(0);
+
 // Message send from bonder to flash task, which will do saving or clearing operation
 #[derive(Clone, Copy, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -55,8 +74,31 @@ pub(crate) enum FlashOperationMessage {
         row: u8,
         action: KeyAction,
     },
+    // Actions of an encoder on a layer
+    EncoderKey {
+        layer: u8,
+        id: u8,
+        action: EncoderAction,
+    },
     // Current saved connection type
     ConnectionType(u8),
+    // Index of the RGB palette
+    RgbPalette(u8),
+    // Index of the active display page
+    DisplayPage(u8),
+    // Whether NKRO is enabled
+    Nkro(bool),
+    // Lifetime totals of health counters
+    HealthCounters(HealthCounters),
+    // Recorded dynamic macro of a slot
+    DynamicMacro(u8, [u8; DYNAMIC_MACRO_SIZE]),
+    // Switch, effects, colors and speeds of RGB lighting
+    RgbSettings(RgbSettings),
+    // Inverted scroll axes of devices on each host OS
+    ScrollDirections(u32),
+    // Secret of host authentication, an empty secret removes the saved secret
+    #[cfg(feature = "host_auth")]
+    AuthSecret(AuthSecret),
 }
 
 #[repr(u32)]
@@ -69,6 +111,16 @@ pub(crate) enum StorageKeys {
     KeymapKeys,
     MacroData,
     ConnectionType,
+    ShutdownMarker,
+    DisplayConfig,
+    EncoderKeys,
+    HealthCounters,
+    DynamicMacro,
+    RgbSettings,
+    ConfigEpoch,
+    ScrollDirections,
+    #[cfg(feature = "host_auth")]
+    AuthSecret,
     #[cfg(feature = "_nrf_ble")]
     ActiveBleProfile = 0xEE,
     #[cfg(feature = "_nrf_ble")]
@@ -85,6 +137,16 @@ impl StorageKeys {
             4 => Some(StorageKeys::LayoutConfig),
             5 => Some(StorageKeys::KeymapKeys),
             6 => Some(StorageKeys::MacroData),
+            8 => Some(StorageKeys::ShutdownMarker),
+            9 => Some(StorageKeys::DisplayConfig),
+            10 => Some(StorageKeys::EncoderKeys),
+            11 => Some(StorageKeys::HealthCounters),
+            12 => Some(StorageKeys::DynamicMacro),
+            13 => Some(StorageKeys::RgbSettings),
+            14 => Some(StorageKeys::ConfigEpoch),
+            15 => Some(StorageKeys::ScrollDirections),
+            #[cfg(feature = "host_auth")]
+            16 => Some(StorageKeys::AuthSecret),
             #[cfg(feature = "_nrf_ble")]
             0xEF => Some(StorageKeys::BleBondInfo),
             _ => None,
@@ -98,8 +160,19 @@ pub(crate) enum StorageData {
     LayoutConfig(LayoutConfig),
     KeymapConfig(EeKeymapConfig),
     KeymapKey(KeymapKey),
+    EncoderKey(EncoderKey),
     MacroData([u8; MACRO_SPACE_SIZE]),
     ConnectionType(u8),
+    ShutdownMarker(ShutdownMarker),
+    RgbPalette(u8),
+    DisplayPage(u8),
+    HealthCounters(HealthCounters),
+    DynamicMacro(u8, [u8; DYNAMIC_MACRO_SIZE]),
+    RgbSettings(RgbSettings),
+    ConfigEpoch(u32),
+    ScrollDirections(u32),
+    #[cfg(feature = "host_auth")]
+    AuthSecret(AuthSecret),
     #[cfg(feature = "_nrf_ble")]
     BondInfo(BondInfo),
     #[cfg(feature = "_nrf_ble")]
@@ -118,6 +191,14 @@ pub(crate) fn get_keymap_key<const ROW: usize, const COL: usize, const NUM_LAYER
     (0x1000 + layer * COL * ROW + row * COL + col) as u32
 }
 
+pub(crate) fn get_encoder_key(layer: u8, id: u8) -> u32 {
+    0x3000 + ((layer as u32) << 8) + id as u32
+}
+
+pub(crate) fn get_dynamic_macro_key(slot: u8) -> u32 {
+    0x4000 + slot as u32
+}
+
 impl Value<'_> for StorageData {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
         if buffer.len() < 6 {
@@ -154,6 +235,14 @@ impl Value<'_> for StorageData {
                 buffer[5] = k.row as u8;
                 Ok(6)
             }
+            StorageData::EncoderKey(k) => {
+                buffer[0] = StorageKeys::EncoderKeys as u8;
+                BigEndian::write_u16(&mut buffer[1..3], to_via_keycode(k.action.0));
+                BigEndian::write_u16(&mut buffer[3..5], to_via_keycode(k.action.1));
+                buffer[5] = k.layer;
+                buffer[6] = k.id;
+                Ok(7)
+            }
             StorageData::MacroData(d) => {
                 if buffer.len() < MACRO_SPACE_SIZE + 1 {
                     return Err(SerializationError::BufferTooSmall);
@@ -167,6 +256,89 @@ impl Value<'_> for StorageData {
                 buffer[1] = *ty;
                 Ok(2)
             }
+            StorageData::ShutdownMarker(marker) => {
+                buffer[0] = StorageKeys::ShutdownMarker as u8;
+                buffer[1] = *marker as u8;
+                Ok(2)
+            }
+            StorageData::RgbPalette(index) => {
+                buffer[0] = StorageKeys::RgbLightConfig as u8;
+                buffer[1] = *index;
+                Ok(2)
+            }
+            StorageData::DisplayPage(index) => {
+                buffer[0] = StorageKeys::DisplayConfig as u8;
+                buffer[1] = *index;
+                Ok(2)
+            }
+            StorageData::ConfigEpoch(epoch) => {
+                if buffer.len() < 5 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = StorageKeys::ConfigEpoch as u8;
+                BigEndian::write_u32(&mut buffer[1..5], *epoch);
+                Ok(5)
+            }
+            StorageData::ScrollDirections(bits) => {
+                if buffer.len() < 5 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = StorageKeys::ScrollDirections as u8;
+                BigEndian::write_u32(&mut buffer[1..5], *bits);
+                Ok(5)
+            }
+            #[cfg(feature = "host_auth")]
+            StorageData::AuthSecret(secret) => {
+                let secret = secret.as_slice();
+                if buffer.len() < secret.len() + 2 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = StorageKeys::AuthSecret as u8;
+                buffer[1] = secret.len() as u8;
+                buffer[2..2 + secret.len()].copy_from_slice(secret);
+                Ok(secret.len() + 2)
+            }
+            StorageData::HealthCounters(c) => {
+                if buffer.len() < 17 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = StorageKeys::HealthCounters as u8;
+                BigEndian::write_u32(&mut buffer[1..5], c.uptime_secs);
+                BigEndian::write_u32(&mut buffer[5..9], c.key_presses);
+                BigEndian::write_u32(&mut buffer[9..13], c.reconnects);
+                BigEndian::write_u32(&mut buffer[13..17], c.errors);
+                Ok(17)
+            }
+            StorageData::DynamicMacro(slot, d) => {
+                if buffer.len() < DYNAMIC_MACRO_SIZE + 2 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = StorageKeys::DynamicMacro as u8;
+                buffer[1] = *slot;
+                buffer[2..DYNAMIC_MACRO_SIZE + 2].copy_from_slice(d);
+                Ok(DYNAMIC_MACRO_SIZE + 2)
+            }
+            StorageData::RgbSettings(s) => {
+                // 6 bytes per zone: enabled, effect, hue, saturation, value, speed
+                let len = 3 + s.num_zones as usize * 6;
+                if buffer.len() < len {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = StorageKeys::RgbSettings as u8;
+                buffer[1] = s.enabled as u8;
+                buffer[2] = s.num_zones;
+                for (zone, buf) in s.zones.iter().zip(buffer[3..len].chunks_exact_mut(6)) {
+                    buf.copy_from_slice(&[
+                        zone.enabled as u8,
+                        zone.effect as u8,
+                        zone.color.h,
+                        zone.color.s,
+                        zone.color.v,
+                        zone.speed,
+                    ]);
+                }
+                Ok(len)
+            }
             #[cfg(feature = "_nrf_ble")]
             StorageData::BondInfo(b) => {
                 if buffer.len() < 121 {
@@ -211,7 +383,7 @@ impl Value<'_> for StorageData {
                     }
                 }
                 StorageKeys::LedLightConfig => Err(SerializationError::Custom(0)),
-                StorageKeys::RgbLightConfig => Err(SerializationError::Custom(0)),
+                StorageKeys::RgbLightConfig => Ok(StorageData::RgbPalette(buffer[1])),
                 StorageKeys::KeymapConfig => Ok(StorageData::KeymapConfig(
                     EeKeymapConfig::from_bits(BigEndian::read_u16(&buffer[1..3])),
                 )),
@@ -237,6 +409,19 @@ impl Value<'_> for StorageData {
                         action,
                     }))
                 }
+                StorageKeys::EncoderKeys => {
+                    if buffer.len() < 7 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    Ok(StorageData::EncoderKey(EncoderKey {
+                        layer: buffer[5],
+                        id: buffer[6],
+                        action: (
+                            from_via_keycode(BigEndian::read_u16(&buffer[1..3])),
+                            from_via_keycode(BigEndian::read_u16(&buffer[3..5])),
+                        ),
+                    }))
+                }
                 StorageKeys::MacroData => {
                     if buffer.len() < MACRO_SPACE_SIZE + 1 {
                         return Err(SerializationError::InvalidData);
@@ -246,6 +431,80 @@ impl Value<'_> for StorageData {
                     Ok(StorageData::MacroData(buf))
                 }
                 StorageKeys::ConnectionType => Ok(StorageData::ConnectionType(buffer[1])),
+                StorageKeys::ShutdownMarker => {
+                    Ok(StorageData::ShutdownMarker(ShutdownMarker::from(buffer[1])))
+                }
+                StorageKeys::DisplayConfig => Ok(StorageData::DisplayPage(buffer[1])),
+                StorageKeys::ConfigEpoch => {
+                    if buffer.len() < 5 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    Ok(StorageData::ConfigEpoch(BigEndian::read_u32(&buffer[1..5])))
+                }
+                StorageKeys::ScrollDirections => {
+                    if buffer.len() < 5 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    Ok(StorageData::ScrollDirections(BigEndian::read_u32(
+                        &buffer[1..5],
+                    )))
+                }
+                #[cfg(feature = "host_auth")]
+                StorageKeys::AuthSecret => {
+                    let len = buffer[1] as usize;
+                    if len > MAX_SECRET_LEN || buffer.len() < len + 2 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    AuthSecret::new(&buffer[2..2 + len])
+                        .map(StorageData::AuthSecret)
+                        .ok_or(SerializationError::InvalidData)
+                }
+                StorageKeys::HealthCounters => {
+                    if buffer.len() < 17 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    Ok(StorageData::HealthCounters(HealthCounters {
+                        uptime_secs: BigEndian::read_u32(&buffer[1..5]),
+                        key_presses: BigEndian::read_u32(&buffer[5..9]),
+                        reconnects: BigEndian::read_u32(&buffer[9..13]),
+                        errors: BigEndian::read_u32(&buffer[13..17]),
+                    }))
+                }
+                StorageKeys::DynamicMacro => {
+                    if buffer.len() < DYNAMIC_MACRO_SIZE + 2 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    let mut buf = [0_u8; DYNAMIC_MACRO_SIZE];
+                    buf.copy_from_slice(&buffer[2..DYNAMIC_MACRO_SIZE + 2]);
+                    Ok(StorageData::DynamicMacro(buffer[1], buf))
+                }
+                StorageKeys::RgbSettings => {
+                    if buffer.len() < 3 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    let num_zones = buffer[2].min(MAX_LED_ZONES as u8);
+                    if buffer.len() < 3 + num_zones as usize * 6 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    let mut zones = [ZoneState::default(); MAX_LED_ZONES];
+                    for (zone, buf) in zones
+                        .iter_mut()
+                        .zip(buffer[3..].chunks_exact(6))
+                        .take(num_zones as usize)
+                    {
+                        *zone = ZoneState {
+                            enabled: buf[0] != 0,
+                            effect: Effect::from_u8(buf[1]).unwrap_or_default(),
+                            color: Hsv::new(buf[2], buf[3], buf[4]),
+                            speed: buf[5],
+                        };
+                    }
+                    Ok(StorageData::RgbSettings(RgbSettings {
+                        enabled: buffer[1] != 0,
+                        num_zones,
+                        zones,
+                    }))
+                }
                 #[cfg(feature = "_nrf_ble")]
                 StorageKeys::BleBondInfo => {
                     // Make `transmute_copy` happy, because the compiler doesn't know the size of buffer
@@ -273,8 +532,19 @@ impl StorageData {
             StorageData::KeymapKey(_) => {
                 panic!("To get storage key for KeymapKey, use `get_keymap_key` instead");
             }
+            StorageData::EncoderKey(k) => get_encoder_key(k.layer, k.id),
             StorageData::MacroData(_) => StorageKeys::MacroData as u32,
             StorageData::ConnectionType(_) => StorageKeys::ConnectionType as u32,
+            StorageData::ShutdownMarker(_) => StorageKeys::ShutdownMarker as u32,
+            StorageData::RgbPalette(_) => StorageKeys::RgbLightConfig as u32,
+            StorageData::DisplayPage(_) => StorageKeys::DisplayConfig as u32,
+            StorageData::HealthCounters(_) => StorageKeys::HealthCounters as u32,
+            StorageData::DynamicMacro(slot, _) => get_dynamic_macro_key(*slot),
+            StorageData::RgbSettings(_) => StorageKeys::RgbSettings as u32,
+            StorageData::ConfigEpoch(_) => StorageKeys::ConfigEpoch as u32,
+            StorageData::ScrollDirections(_) => StorageKeys::ScrollDirections as u32,
+            #[cfg(feature = "host_auth")]
+            StorageData::AuthSecret(_) => StorageKeys::AuthSecret as u32,
             #[cfg(feature = "_nrf_ble")]
             StorageData::BondInfo(b) => get_bond_info_key(b.slot_num),
             #[cfg(feature = "_nrf_ble")]
@@ -282,6 +552,26 @@ impl StorageData {
         }
     }
 }
+/// Marker saved in storage, which is used to check whether the last shutdown is clean
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum ShutdownMarker {
+    // The keyboard is running
+    Running = 0,
+    // Pending writes are flushed after a power loss is detected
+    Clean = 1,
+}
+
+impl From<u8> for ShutdownMarker {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ShutdownMarker::Clean,
+            _ => ShutdownMarker::Running,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub(crate) struct LocalStorageConfig {
@@ -304,6 +594,14 @@ pub(crate) struct KeymapKey {
     action: KeyAction,
 }
 
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct EncoderKey {
+    layer: u8,
+    id: u8,
+    action: EncoderAction,
+}
+
 pub(crate) struct Storage<
     F: AsyncNorFlash,
     const ROW: usize,
@@ -313,6 +611,8 @@ pub(crate) struct Storage<
     pub(crate) flash: F,
     pub(crate) storage_range: Range<u32>,
     buffer: [u8; get_buffer_size()],
+    // Whether the clean shutdown marker has been written
+    clean_marked: bool,
 }
 
 /// Read out storage config, update and then save back.
@@ -384,10 +684,13 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
             start_addr as u32..(start_addr + config.num_sectors as usize * F::ERASE_SIZE) as u32
         };
 
+        STORAGE_SIZE.store(storage_range.end - storage_range.start, Ordering::Relaxed);
+
         let mut storage = Self {
             flash,
             storage_range,
             buffer: [0; get_buffer_size()],
+            clean_marked: false,
         };
 
         if config.clear_storage {
@@ -419,9 +722,249 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
             }
         }
 
+        storage.check_last_shutdown().await;
+        storage.increase_config_epoch().await;
+        // Restored even in safe mode, so that checkpoints don't overwrite the totals
+        storage.load_health_counters().await;
+        // Restored even in safe mode, so that the saved secret still protects the keyboard
+        #[cfg(feature = "host_auth")]
+        storage.load_auth_secret().await;
+        if !crate::safe_mode::safe_mode_active() {
+            storage.load_rgb_palette().await;
+            storage.load_rgb_settings().await;
+            storage.load_display_page().await;
+            storage.load_scroll_directions().await;
+            storage.load_keymap_config().await;
+            storage.load_dynamic_macros().await;
+        }
+
         storage
     }
 
+    /// Restore the RGB palette saved in storage
+    async fn load_rgb_palette(&mut self) {
+        if let Ok(Some(StorageData::RgbPalette(index))) = fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::RgbLightConfig as u32),
+        )
+        .await
+        {
+            crate::rgb::restore_palette(index);
+        }
+    }
+
+    /// Restore the RGB settings saved in storage
+    async fn load_rgb_settings(&mut self) {
+        if let Ok(Some(StorageData::RgbSettings(settings))) = fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::RgbSettings as u32),
+        )
+        .await
+        {
+            crate::rgb::restore_rgb_settings(settings);
+        }
+    }
+
+    /// Restore the display page saved in storage
+    async fn load_display_page(&mut self) {
+        if let Ok(Some(StorageData::DisplayPage(index))) = fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::DisplayConfig as u32),
+        )
+        .await
+        {
+            crate::display::restore_display_page(index);
+        }
+    }
+
+    /// Restore the secret of host authentication saved in storage
+    #[cfg(feature = "host_auth")]
+    async fn load_auth_secret(&mut self) {
+        if let Ok(Some(StorageData::AuthSecret(secret))) = fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::AuthSecret as u32),
+        )
+        .await
+        {
+            restore_auth_secret(secret);
+        }
+    }
+
+    /// Restore the scroll directions saved in storage
+    async fn load_scroll_directions(&mut self) {
+        if let Ok(Some(StorageData::ScrollDirections(bits))) = fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::ScrollDirections as u32),
+        )
+        .await
+        {
+            crate::scroll::restore_scroll_directions(bits);
+        }
+    }
+
+    /// Restore the keymap config saved in storage, only NKRO is used for now
+    async fn load_keymap_config(&mut self) {
+        if let Ok(Some(StorageData::KeymapConfig(config))) = fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::KeymapConfig as u32),
+        )
+        .await
+        {
+            crate::report::restore_nkro(config.nkro());
+        }
+    }
+
+    /// Restore recorded dynamic macros saved in storage
+    async fn load_dynamic_macros(&mut self) {
+        for slot in 0..NUM_DYNAMIC_MACRO as u8 {
+            if let Ok(Some(StorageData::DynamicMacro(slot, data))) =
+                fetch_item::<u32, StorageData, _>(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    &mut NoCache::new(),
+                    &mut self.buffer,
+                    &get_dynamic_macro_key(slot),
+                )
+                .await
+            {
+                restore_dynamic_macro(slot, data);
+            }
+        }
+    }
+
+    /// Restore lifetime totals of health counters saved in storage
+    async fn load_health_counters(&mut self) {
+        if let Ok(Some(StorageData::HealthCounters(counters))) = fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::HealthCounters as u32),
+        )
+        .await
+        {
+            restore_health_counters(counters);
+        }
+    }
+
+    /// Read the epoch of the config generations saved at last boot, then save the epoch of this boot
+    async fn increase_config_epoch(&mut self) {
+        let epoch = match fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::ConfigEpoch as u32),
+        )
+        .await
+        {
+            Ok(Some(StorageData::ConfigEpoch(epoch))) => epoch.wrapping_add(1),
+            _ => 1,
+        };
+        set_config_epoch(epoch);
+        if let Err(e) = store_item(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::ConfigEpoch as u32),
+            &StorageData::ConfigEpoch(epoch),
+        )
+        .await
+        {
+            print_storage_error::<F>(e);
+        }
+    }
+
+    /// Read the shutdown marker saved at last power off, then mark the keyboard as running
+    async fn check_last_shutdown(&mut self) {
+        let reason = match fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::ShutdownMarker as u32),
+        )
+        .await
+        {
+            Ok(Some(StorageData::ShutdownMarker(ShutdownMarker::Clean))) => {
+                ShutdownReason::PowerLoss
+            }
+            Ok(Some(StorageData::ShutdownMarker(ShutdownMarker::Running))) => {
+                warn!("Last shutdown isn't clean, the keyboard might be crashed");
+                ShutdownReason::Crash
+            }
+            _ => ShutdownReason::Unknown,
+        };
+        set_last_shutdown(reason);
+
+        // The running marker is already saved after a crash, don't wear the flash on every boot
+        if reason != ShutdownReason::Crash {
+            if let Err(e) = self.write_shutdown_marker(ShutdownMarker::Running).await {
+                print_storage_error::<F>(e);
+            }
+        }
+    }
+
+    /// Mark the keyboard as running again after the clean marker is written by a power loss
+    async fn rearm_shutdown_marker(&mut self) {
+        if !self.clean_marked {
+            return;
+        }
+        info!("Power is back, marking the keyboard as running");
+        if let Err(e) = self.write_shutdown_marker(ShutdownMarker::Running).await {
+            print_storage_error::<F>(e);
+        }
+    }
+
+    async fn write_shutdown_marker(
+        &mut self,
+        marker: ShutdownMarker,
+    ) -> Result<(), SSError<F::Error>> {
+        self.clean_marked = marker == ShutdownMarker::Clean;
+        store_item(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::ShutdownMarker as u32),
+            &StorageData::ShutdownMarker(marker),
+        )
+        .await
+    }
+
+    /// Flush all pending flash operations and write the clean shutdown marker.
+    async fn emergency_flush(&mut self, storage_cache: &mut NoCache) {
+        if self.clean_marked {
+            return;
+        }
+        warn!("Power loss detected, flushing storage");
+        while let Ok(info) = FLASH_CHANNEL.try_receive() {
+            self.process_flash_operation(info, storage_cache).await;
+        }
+        if let Err(e) = self.write_shutdown_marker(ShutdownMarker::Clean).await {
+            print_storage_error::<F>(e);
+        }
+    }
+
     // TODO: Is there a way to convert `NorFlash` trait object to `F: AsyncNorFlash`?
     pub(crate) async fn new_from_blocking<BF: NorFlash>(_flash: BF) {
         // Self { flash }
@@ -430,133 +973,251 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
     pub(crate) async fn run(&mut self) {
         let mut storage_cache = NoCache::new();
         loop {
-            let info: FlashOperationMessage = FLASH_CHANNEL.receive().await;
-            debug!("Flash operation: {:?}", info);
-            if let Err(e) = match info {
-                FlashOperationMessage::LayoutOptions(layout_option) => {
-                    // Read out layout options, update layer option and save back
-                    write_storage!(
-                        &mut self.flash,
-                        &mut self.buffer,
-                        &mut storage_cache,
-                        LayoutConfig,
-                        layout_option,
-                        self.storage_range.clone()
-                    )
-                }
-                FlashOperationMessage::Reset => {
-                    sequential_storage::erase_all(&mut self.flash, self.storage_range.clone()).await
-                }
-                FlashOperationMessage::DefaultLayer(default_layer) => {
-                    // Read out layout options, update layer option and save back
-                    write_storage!(
-                        &mut self.flash,
-                        &mut self.buffer,
-                        &mut storage_cache,
-                        LayoutConfig,
-                        default_layer,
-                        self.storage_range.clone()
-                    )
-                }
-                FlashOperationMessage::WriteMacro(macro_data) => {
-                    info!("Saving keyboard macro data");
-                    store_item(
-                        &mut self.flash,
-                        self.storage_range.clone(),
-                        &mut storage_cache,
-                        &mut self.buffer,
-                        &(StorageKeys::MacroData as u32),
-                        &StorageData::MacroData(macro_data),
-                    )
-                    .await
+            match select(FLASH_CHANNEL.receive(), POWER_LOSS_SIGNAL.wait()).await {
+                Either::First(info) => {
+                    // Settings are changed, so the keyboard is still running
+                    self.rearm_shutdown_marker().await;
+                    self.process_flash_operation(info, &mut storage_cache).await;
                 }
-                FlashOperationMessage::KeymapKey {
-                    layer,
-                    col,
-                    row,
+                Either::Second(true) => self.emergency_flush(&mut storage_cache).await,
+                Either::Second(false) => self.rearm_shutdown_marker().await,
+            }
+        }
+    }
+
+    async fn process_flash_operation(
+        &mut self,
+        info: FlashOperationMessage,
+        storage_cache: &mut NoCache,
+    ) {
+        debug!("Flash operation: {:?}", info);
+        if let Err(e) = match info {
+            FlashOperationMessage::LayoutOptions(layout_option) => {
+                // Read out layout options, update layer option and save back
+                write_storage!(
+                    &mut self.flash,
+                    &mut self.buffer,
+                    storage_cache,
+                    LayoutConfig,
+                    layout_option,
+                    self.storage_range.clone()
+                )
+            }
+            FlashOperationMessage::Reset => {
+                sequential_storage::erase_all(&mut self.flash, self.storage_range.clone()).await
+            }
+            FlashOperationMessage::DefaultLayer(default_layer) => {
+                // Read out layout options, update layer option and save back
+                write_storage!(
+                    &mut self.flash,
+                    &mut self.buffer,
+                    storage_cache,
+                    LayoutConfig,
+                    default_layer,
+                    self.storage_range.clone()
+                )
+            }
+            FlashOperationMessage::WriteMacro(macro_data) => {
+                info!("Saving keyboard macro data");
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::MacroData as u32),
+                    &StorageData::MacroData(macro_data),
+                )
+                .await
+            }
+            FlashOperationMessage::KeymapKey {
+                layer,
+                col,
+                row,
+                action,
+            } => {
+                let data = StorageData::KeymapKey(KeymapKey {
+                    row: row as usize,
+                    col: col as usize,
+                    layer: layer as usize,
                     action,
-                } => {
-                    let data = StorageData::KeymapKey(KeymapKey {
-                        row: row as usize,
-                        col: col as usize,
-                        layer: layer as usize,
-                        action,
-                    });
-                    let key = get_keymap_key::<ROW, COL, NUM_LAYER>(
-                        row as usize,
-                        col as usize,
-                        layer as usize,
-                    );
-                    store_item(
-                        &mut self.flash,
-                        self.storage_range.clone(),
-                        &mut storage_cache,
-                        &mut self.buffer,
-                        &key,
-                        &data,
-                    )
-                    .await
-                }
-                FlashOperationMessage::ConnectionType(ty) => {
-                    store_item(
-                        &mut self.flash,
-                        self.storage_range.clone(),
-                        &mut storage_cache,
-                        &mut self.buffer,
-                        &(StorageKeys::ConnectionType as u32),
-                        &StorageData::ConnectionType(ty),
-                    )
-                    .await
-                }
-                #[cfg(feature = "_nrf_ble")]
-                FlashOperationMessage::ActiveBleProfile(profile) => {
-                    let data = StorageData::ActiveBleProfile(profile);
-                    store_item::<u32, StorageData, _>(
-                        &mut self.flash,
-                        self.storage_range.clone(),
-                        &mut storage_cache,
-                        &mut self.buffer,
-                        &data.key(),
-                        &data,
-                    )
-                    .await
-                }
-                #[cfg(feature = "_nrf_ble")]
-                FlashOperationMessage::ClearSlot(key) => {
-                    info!("Clearing bond info slot_num: {}", key);
-                    // Remove item in `sequential-storage` is quite expensive, so just override the item with `removed = true`
-                    let mut empty = BondInfo::default();
-                    empty.removed = true;
-                    let data = StorageData::BondInfo(empty);
-                    store_item::<u32, StorageData, _>(
-                        &mut self.flash,
-                        self.storage_range.clone(),
-                        &mut storage_cache,
-                        &mut self.buffer,
-                        &data.key(),
-                        &data,
-                    )
-                    .await
-                }
-                #[cfg(feature = "_nrf_ble")]
-                FlashOperationMessage::BondInfo(b) => {
-                    info!("Saving bond info: {:?}", b);
-                    let data = StorageData::BondInfo(b);
-                    store_item::<u32, StorageData, _>(
-                        &mut self.flash,
-                        self.storage_range.clone(),
-                        &mut storage_cache,
-                        &mut self.buffer,
-                        &data.key(),
-                        &data,
-                    )
-                    .await
-                }
-                #[cfg(not(feature = "_nrf_ble"))]
-                _ => Ok(()),
-            } {
-                print_storage_error::<F>(e);
+                });
+                let key = get_keymap_key::<ROW, COL, NUM_LAYER>(
+                    row as usize,
+                    col as usize,
+                    layer as usize,
+                );
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &key,
+                    &data,
+                )
+                .await
+            }
+            FlashOperationMessage::EncoderKey { layer, id, action } => {
+                let data = StorageData::EncoderKey(EncoderKey { layer, id, action });
+                store_item::<u32, StorageData, _>(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &data.key(),
+                    &data,
+                )
+                .await
+            }
+            FlashOperationMessage::ConnectionType(ty) => {
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::ConnectionType as u32),
+                    &StorageData::ConnectionType(ty),
+                )
+                .await
+            }
+            FlashOperationMessage::RgbPalette(index) => {
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::RgbLightConfig as u32),
+                    &StorageData::RgbPalette(index),
+                )
+                .await
+            }
+            FlashOperationMessage::DisplayPage(index) => {
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::DisplayConfig as u32),
+                    &StorageData::DisplayPage(index),
+                )
+                .await
+            }
+            FlashOperationMessage::ScrollDirections(bits) => {
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::ScrollDirections as u32),
+                    &StorageData::ScrollDirections(bits),
+                )
+                .await
+            }
+            #[cfg(feature = "host_auth")]
+            FlashOperationMessage::AuthSecret(secret) => {
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::AuthSecret as u32),
+                    &StorageData::AuthSecret(secret),
+                )
+                .await
+            }
+            FlashOperationMessage::Nkro(enabled) => {
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::KeymapConfig as u32),
+                    &StorageData::KeymapConfig(EeKeymapConfig::new().with_nkro(enabled)),
+                )
+                .await
+            }
+            FlashOperationMessage::HealthCounters(counters) => {
+                store_item(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &(StorageKeys::HealthCounters as u32),
+                    &StorageData::HealthCounters(counters),
+                )
+                .await
+            }
+            FlashOperationMessage::RgbSettings(settings) => {
+                let data = StorageData::RgbSettings(settings);
+                store_item::<u32, StorageData, _>(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &data.key(),
+                    &data,
+                )
+                .await
             }
+            FlashOperationMessage::DynamicMacro(slot, data) => {
+                let data = StorageData::DynamicMacro(slot, data);
+                store_item::<u32, StorageData, _>(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &data.key(),
+                    &data,
+                )
+                .await
+            }
+            #[cfg(feature = "_nrf_ble")]
+            FlashOperationMessage::ActiveBleProfile(profile) => {
+                let data = StorageData::ActiveBleProfile(profile);
+                store_item::<u32, StorageData, _>(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &data.key(),
+                    &data,
+                )
+                .await
+            }
+            #[cfg(feature = "_nrf_ble")]
+            FlashOperationMessage::ClearSlot(key) => {
+                info!("Clearing bond info slot_num: {}", key);
+                // Remove item in `sequential-storage` is quite expensive, so just override the item with `removed = true`
+                let mut empty = BondInfo::default();
+                empty.removed = true;
+                let data = StorageData::BondInfo(empty);
+                store_item::<u32, StorageData, _>(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &data.key(),
+                    &data,
+                )
+                .await
+            }
+            #[cfg(feature = "_nrf_ble")]
+            FlashOperationMessage::BondInfo(b) => {
+                info!("Saving bond info: {:?}", b);
+                let data = StorageData::BondInfo(b);
+                store_item::<u32, StorageData, _>(
+                    &mut self.flash,
+                    self.storage_range.clone(),
+                    storage_cache,
+                    &mut self.buffer,
+                    &data.key(),
+                    &data,
+                )
+                .await
+            }
+            #[cfg(not(feature = "_nrf_ble"))]
+            _ => Ok(()),
+        } {
+            print_storage_error::<F>(e);
         }
     }
 
@@ -593,6 +1254,34 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
         Ok(())
     }
 
+    /// Read encoder actions saved by Via/Vial, encoders which are never changed keep the actions in the firmware
+    pub(crate) async fn read_encoder_map(&mut self, encoders: &mut EncoderMap<'_>) {
+        let mut storage_cache = NoCache::new();
+        if let Ok(mut key_iterator) = fetch_all_items::<u32, _, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut storage_cache,
+            &mut self.buffer,
+        )
+        .await
+        {
+            while let Ok(Some((_key, item))) = key_iterator
+                .next::<u32, StorageData>(&mut self.buffer)
+                .await
+            {
+                if let StorageData::EncoderKey(key) = item {
+                    match encoders.get_mut(key.layer as usize, key.id as usize) {
+                        Some(encoder) => *encoder = key.action,
+                        None => warn!(
+                            "Saved encoder {} of layer {} is out of the encoder map",
+                            key.id, key.layer
+                        ),
+                    }
+                }
+            }
+        };
+    }
+
     pub(crate) async fn read_macro_cache(&mut self, macro_cache: &mut [u8]) -> Result<(), ()> {
         // Read storage and send back from send_channel
         let read_data = fetch_item::<u32, StorageData, _>(
@@ -613,6 +1302,22 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
         Ok(())
     }
 
+    /// Read the default layer set by `DF(n)`
+    pub(crate) async fn read_default_layer(&mut self) -> Option<u8> {
+        match fetch_item::<u32, StorageData, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &(StorageKeys::LayoutConfig as u32),
+        )
+        .await
+        {
+            Ok(Some(StorageData::LayoutConfig(config))) => Some(config.default_layer),
+            _ => None,
+        }
+    }
+
     async fn initialize_storage_with_config(
         &mut self,
         keymap: &[[[KeyAction; COL]; ROW]; NUM_LAYER],
@@ -694,6 +1399,7 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
 }
 
 fn print_storage_error<F: AsyncNorFlash>(e: SSError<F::Error>) {
+    crate::health::record_error();
     match e {
         SSError::Storage { value: _ } => error!("Flash error"),
         SSError::FullStorage => error!("Storage is full"),
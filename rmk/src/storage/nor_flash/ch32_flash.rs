@@ -0,0 +1,189 @@
+//! Flash driver of CH32V20x/V30x
+//!
+//! ch32-hal doesn't provide a flash driver yet, so the flash controller is accessed by registers directly.
+//! Data is written half-word by half-word in the standard programming mode, and erased in 4KB pages.
+//!
+//! Erased flash of CH32V reads `0xE339` instead of `0xFFFF`, so data is XORed with the erased value,
+//! then erased flash reads as `0xFF` as the storage expects.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// Start address of the main flash
+const FLASH_BASE: u32 = 0x0800_0000;
+/// Address of the flash controller
+const FLASH_CONTROLLER: u32 = 0x4002_2000;
+const KEYR: u32 = FLASH_CONTROLLER + 0x04;
+const STATR: u32 = FLASH_CONTROLLER + 0x0C;
+const CTLR: u32 = FLASH_CONTROLLER + 0x10;
+const ADDR: u32 = FLASH_CONTROLLER + 0x14;
+/// Flash capacity in KB, in the electronic signature
+const ESIG_FLACAP: u32 = 0x1FFF_F7E0;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const STATR_BSY: u32 = 1 << 0;
+const STATR_WRPRTERR: u32 = 1 << 4;
+const STATR_EOP: u32 = 1 << 5;
+const CTLR_PG: u32 = 1 << 0;
+const CTLR_PER: u32 = 1 << 1;
+const CTLR_STRT: u32 = 1 << 6;
+const CTLR_LOCK: u32 = 1 << 7;
+
+/// Value of an erased half-word
+const ERASED: u16 = 0xE339;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Ch32FlashError {
+    NotAligned,
+    OutOfBounds,
+    /// The flash is write protected
+    WriteProtected,
+    /// The written data doesn't match, the flash isn't erased or it's worn out
+    Verify,
+}
+
+impl NorFlashError for Ch32FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Ch32FlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            Ch32FlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Internal flash of CH32V20x/V30x, offsets are relative to the start of the main flash
+pub struct Ch32Flash {
+    capacity: usize,
+}
+
+impl Default for Ch32Flash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ch32Flash {
+    pub fn new() -> Self {
+        let kb = unsafe { read_volatile(ESIG_FLACAP as *const u16) };
+        Self {
+            capacity: kb as usize * 1024,
+        }
+    }
+
+    fn check_range(&self, offset: u32, len: usize) -> Result<(), Ch32FlashError> {
+        if offset as usize + len > self.capacity {
+            return Err(Ch32FlashError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// Unlock the flash controller, run `f`, then lock it again
+    fn unlocked<T>(
+        &mut self,
+        f: impl FnOnce() -> Result<T, Ch32FlashError>,
+    ) -> Result<T, Ch32FlashError> {
+        unsafe {
+            if read_volatile(CTLR as *const u32) & CTLR_LOCK != 0 {
+                write_volatile(KEYR as *mut u32, KEY1);
+                write_volatile(KEYR as *mut u32, KEY2);
+            }
+        }
+        let result = f();
+        unsafe { write_volatile(CTLR as *mut u32, CTLR_LOCK) };
+        result
+    }
+
+    /// Wait for the current operation, then clear the status flags
+    fn wait_ready() -> Result<(), Ch32FlashError> {
+        let status = loop {
+            let status = unsafe { read_volatile(STATR as *const u32) };
+            if status & STATR_BSY == 0 {
+                break status;
+            }
+        };
+        // Flags are cleared by writing 1
+        unsafe { write_volatile(STATR as *mut u32, STATR_WRPRTERR | STATR_EOP) };
+        if status & STATR_WRPRTERR != 0 {
+            return Err(Ch32FlashError::WriteProtected);
+        }
+        Ok(())
+    }
+}
+
+impl ErrorType for Ch32Flash {
+    type Error = Ch32FlashError;
+}
+
+impl ReadNorFlash for Ch32Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_range(offset, bytes.len())?;
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let address = FLASH_BASE + offset + i as u32;
+            let value = unsafe { read_volatile(address as *const u8) };
+            *byte = value ^ ERASED.to_le_bytes()[address as usize % 2];
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl NorFlash for Ch32Flash {
+    const WRITE_SIZE: usize = 2;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if from % Self::ERASE_SIZE as u32 != 0 || to % Self::ERASE_SIZE as u32 != 0 {
+            return Err(Ch32FlashError::NotAligned);
+        }
+        self.check_range(from, to.saturating_sub(from) as usize)?;
+        self.unlocked(|| {
+            for page in (from..to).step_by(Self::ERASE_SIZE) {
+                unsafe {
+                    write_volatile(CTLR as *mut u32, CTLR_PER);
+                    write_volatile(ADDR as *mut u32, FLASH_BASE + page);
+                    write_volatile(CTLR as *mut u32, CTLR_PER | CTLR_STRT);
+                }
+                let result = Self::wait_ready();
+                unsafe { write_volatile(CTLR as *mut u32, 0) };
+                result?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        if offset % Self::WRITE_SIZE as u32 != 0 || bytes.len() % Self::WRITE_SIZE != 0 {
+            return Err(Ch32FlashError::NotAligned);
+        }
+        self.check_range(offset, bytes.len())?;
+        self.unlocked(|| {
+            for (i, half_word) in bytes.chunks_exact(2).enumerate() {
+                let address = (FLASH_BASE + offset + i as u32 * 2) as *mut u16;
+                let value = u16::from_le_bytes([half_word[0], half_word[1]]) ^ ERASED;
+                unsafe {
+                    write_volatile(CTLR as *mut u32, CTLR_PG);
+                    write_volatile(address, value);
+                }
+                let result = Self::wait_ready();
+                unsafe { write_volatile(CTLR as *mut u32, 0) };
+                result?;
+                if unsafe { read_volatile(address) } != value {
+                    return Err(Ch32FlashError::Verify);
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -1,2 +1,4 @@
+#[cfg(feature = "ch32v")]
+pub mod ch32_flash;
 #[cfg(feature = "_esp_ble")]
 pub mod esp_partition;
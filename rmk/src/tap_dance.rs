@@ -0,0 +1,203 @@
+//! Tap dance: a key which triggers different actions when it's tapped once, twice or three times, held, or tapped then held
+//!
+//! Tap dances are defined in a compile-time table, the key uses `KeyAction::TapDance(index)`, or `td!(index)` in the keymap:
+//!
+//! ```rust,ignore
+//! const TAP_DANCES: &[TapDance] = &[
+//!     // Tap: Escape, double tap: CapsLock, hold: layer 1
+//!     TapDance::new(k!(Escape), k!(CapsLock), a!(No), mo!(1), a!(No)),
+//! ];
+//!
+//! let behavior_config = BehaviorConfig {
+//!     tap_dance: TapDanceConfig {
+//!         tapping_term: Duration::from_millis(200),
+//!         tap_dances: TAP_DANCES,
+//!     },
+//!     ..Default::default()
+//! };
+//! ```
+//!
+//! Taps are counted until the key isn't pressed again within `tapping_term` after the last release.
+//! If the key is still pressed after `tapping_term`, it's a hold: `hold` on the first press, `hold_after_tap` after one tap.
+//! When there's no action for the hold, the tap action of the current count is held instead.
+//! Pressing another key resolves the tap dance immediately, as a hold if the key is still pressed.
+//! The tap dance is resolved as soon as the last defined tap count is reached as well.
+//!
+//! With the same `tapping_term`, modifiers can have double-tap behaviors without a tap dance,
+//! see `double_tap_shift` and `double_tap_layer_lock` of [`TapDanceConfig`](crate::config::TapDanceConfig).
+//! Unlike tap dances, these keys are never delayed.
+
+use embassy_time::{Duration, Instant};
+
+use crate::action::{Action, KeyAction};
+use crate::event::KeyEvent;
+
+/// Actions of a tap dance key, `None` means nothing is triggered
+#[derive(Clone, Copy, Debug)]
+pub struct TapDance {
+    pub tap: Option<Action>,
+    pub double_tap: Option<Action>,
+    pub triple_tap: Option<Action>,
+    pub hold: Option<Action>,
+    pub hold_after_tap: Option<Action>,
+}
+
+/// Convert the `KeyAction` of a tap dance to the inner `Action`, only `Single` actions and `No` are allowed
+const fn single_action(action: KeyAction) -> Option<Action> {
+    match action {
+        KeyAction::No => None,
+        KeyAction::Single(a) => Some(a),
+        _ => panic!("Only single actions can be used in a tap dance"),
+    }
+}
+
+impl TapDance {
+    /// Create a tap dance from `KeyAction`s like `k!(A)` or `mo!(1)`, use `a!(No)` for unused actions.
+    ///
+    /// Only single actions are allowed, it fails to compile in a const otherwise.
+    pub const fn new(
+        tap: KeyAction,
+        double_tap: KeyAction,
+        triple_tap: KeyAction,
+        hold: KeyAction,
+        hold_after_tap: KeyAction,
+    ) -> Self {
+        Self {
+            tap: single_action(tap),
+            double_tap: single_action(double_tap),
+            triple_tap: single_action(triple_tap),
+            hold: single_action(hold),
+            hold_after_tap: single_action(hold_after_tap),
+        }
+    }
+
+    /// The action of `taps` taps
+    pub(crate) fn tap_action(&self, taps: u8) -> Option<Action> {
+        match taps {
+            1 => self.tap,
+            2 => self.double_tap,
+            3 => self.triple_tap,
+            _ => None,
+        }
+    }
+
+    /// The action of holding the key after `taps` taps, the tap action of the next count is used if there's no hold action
+    pub(crate) fn hold_action(&self, taps: u8) -> Option<Action> {
+        let hold = match taps {
+            0 => self.hold,
+            1 => self.hold_after_tap,
+            _ => None,
+        };
+        hold.or(self.tap_action(taps + 1))
+    }
+
+    /// Max number of taps which triggers an action, more taps can't change the result
+    pub(crate) fn max_taps(&self) -> u8 {
+        (1..=3)
+            .rev()
+            .find(|&n| self.tap_action(n).is_some())
+            .unwrap_or(0)
+    }
+}
+
+/// Result of a tap dance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum TapDanceResult {
+    /// The key is tapped for the number of times
+    Tap(u8),
+    /// The key is held after the number of taps
+    Hold(u8),
+}
+
+/// Detects double taps of keys without delaying them
+#[derive(Default)]
+pub(crate) struct DoubleTapDetector {
+    /// The pressed key and its press time
+    pressed: Option<((u8, u8), Instant)>,
+    /// The tapped key and its release time
+    tapped: Option<((u8, u8), Instant)>,
+}
+
+impl DoubleTapDetector {
+    /// Update with a key event, returns `true` if it's the second press of a double tap.
+    ///
+    /// A tap is a press and release within `tapping_term`, and the second press should come within `tapping_term` after the release.
+    /// Pressing another key in between cancels the double tap.
+    pub(crate) fn update(
+        &mut self,
+        key_event: KeyEvent,
+        now: Instant,
+        tapping_term: Duration,
+    ) -> bool {
+        let pos = (key_event.row, key_event.col);
+        if key_event.pressed {
+            let double = self.tapped.take().is_some_and(|(p, released)| {
+                p == pos && now.saturating_duration_since(released) <= tapping_term
+            });
+            // The second press doesn't start another double tap
+            self.pressed = if double { None } else { Some((pos, now)) };
+            double
+        } else {
+            if let Some((p, pressed)) = self.pressed {
+                if p == pos {
+                    self.pressed = None;
+                    if now.saturating_duration_since(pressed) <= tapping_term {
+                        self.tapped = Some((pos, now));
+                    }
+                }
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keycode::KeyCode;
+    use crate::{a, k, mo};
+
+    const TD: TapDance = TapDance::new(k!(A), a!(No), k!(C), mo!(1), a!(No));
+
+    #[test]
+    fn test_tap_dance_actions() {
+        assert_eq!(TD.max_taps(), 3);
+        assert_eq!(TD.tap_action(2), None);
+        assert_eq!(TD.hold_action(0), Some(Action::LayerOn(1)));
+        // No hold after tap, hold the double tap action, which is empty
+        assert_eq!(TD.hold_action(1), None);
+        assert_eq!(TD.hold_action(2), Some(Action::Key(KeyCode::C)));
+    }
+
+    #[test]
+    fn test_double_tap_detector() {
+        let term = Duration::from_millis(200);
+        let event = |col, pressed| KeyEvent {
+            row: 0,
+            col,
+            pressed,
+        };
+        let at = Instant::from_millis;
+        let mut d = DoubleTapDetector::default();
+        assert!(!d.update(event(0, true), at(0), term));
+        assert!(!d.update(event(0, false), at(100), term));
+        assert!(d.update(event(0, true), at(250), term));
+        // The third tap starts a new double tap
+        assert!(!d.update(event(0, false), at(300), term));
+        assert!(!d.update(event(0, true), at(350), term));
+
+        // Interrupted by another key
+        let mut d = DoubleTapDetector::default();
+        d.update(event(0, true), at(0), term);
+        d.update(event(0, false), at(100), term);
+        d.update(event(1, true), at(150), term);
+        assert!(!d.update(event(0, true), at(200), term));
+
+        // Held too long
+        let mut d = DoubleTapDetector::default();
+        d.update(event(0, true), at(0), term);
+        d.update(event(0, false), at(500), term);
+        assert!(!d.update(event(0, true), at(550), term));
+    }
+}
@@ -0,0 +1,179 @@
+//! Thermal guard for LED-heavy boards
+//!
+//! The thermal guard reads a temperature sensor periodically and throttles the LED brightness when the board gets too hot.
+
+use core::sync::atomic::Ordering;
+
+use embassy_time::Timer;
+
+use crate::config::ThermalConfig;
+use crate::light::{update_brightness_limit, BRIGHTNESS_LIMIT};
+
+/// Minimal brightness limit before LEDs are turned off completely
+const MIN_BRIGHTNESS_LIMIT: u8 = 32;
+
+/// Temperature sensor used by the thermal guard, for example an NTC thermistor or the MCU's internal sensor
+pub trait TemperatureSensor {
+    /// Read current temperature in °C, returns `None` if the reading fails
+    async fn read_celsius(&mut self) -> Option<i16>;
+}
+
+/// NTC thermistor read through an ADC.
+///
+/// The ADC value is converted to temperature using a lookup table of `(adc_value, celsius)` pairs,
+/// which can be generated from the datasheet of the thermistor and the voltage divider used.
+/// The table MUST be sorted by ADC value in ascending order.
+/// Values between two entries are interpolated linearly.
+pub struct NtcThermistor<F: FnMut() -> Option<u16>> {
+    read_adc: F,
+    table: &'static [(u16, i16)],
+}
+
+impl<F: FnMut() -> Option<u16>> NtcThermistor<F> {
+    pub fn new(read_adc: F, table: &'static [(u16, i16)]) -> Self {
+        Self { read_adc, table }
+    }
+
+    fn to_celsius(&self, adc: u16) -> Option<i16> {
+        let first = self.table.first()?;
+        if adc <= first.0 {
+            return Some(first.1);
+        }
+        for w in self.table.windows(2) {
+            let ((a0, t0), (a1, t1)) = (w[0], w[1]);
+            if adc <= a1 {
+                let t = t0 as i32 + (adc - a0) as i32 * (t1 - t0) as i32 / (a1 - a0).max(1) as i32;
+                return Some(t as i16);
+            }
+        }
+        self.table.last().map(|l| l.1)
+    }
+}
+
+impl<F: FnMut() -> Option<u16>> TemperatureSensor for NtcThermistor<F> {
+    async fn read_celsius(&mut self) -> Option<i16> {
+        let adc = (self.read_adc)()?;
+        self.to_celsius(adc)
+    }
+}
+
+/// Internal temperature sensor of nRF52, read via softdevice
+#[cfg(feature = "_nrf_ble")]
+pub struct NrfTemperatureSensor;
+
+#[cfg(feature = "_nrf_ble")]
+impl TemperatureSensor for NrfTemperatureSensor {
+    async fn read_celsius(&mut self) -> Option<i16> {
+        let mut temp: i32 = 0;
+        // The unit of the result is 0.25°C
+        let ret = unsafe { nrf_softdevice::raw::sd_temp_get(&mut temp) };
+        if ret == nrf_softdevice::raw::NRF_SUCCESS {
+            Some((temp / 4) as i16)
+        } else {
+            None
+        }
+    }
+}
+
+/// Calculate the brightness limit for the given temperature and the current limit.
+///
+/// The limit is lowered immediately when the temperature rises, but it's raised only after the temperature
+/// drops by `hysteresis`, so that LEDs don't flicker around a threshold.
+fn brightness_limit(temperature: i16, last_limit: u8, config: &ThermalConfig) -> u8 {
+    let limit = throttle(temperature, config);
+    if limit <= last_limit {
+        limit
+    } else {
+        throttle(temperature.saturating_add(config.hysteresis), config).max(last_limit)
+    }
+}
+
+/// Brightness limit of the given temperature, without hysteresis
+fn throttle(temperature: i16, config: &ThermalConfig) -> u8 {
+    if temperature >= config.critical_threshold {
+        0
+    } else if temperature <= config.warning_threshold {
+        u8::MAX
+    } else {
+        let range = (config.critical_threshold - config.warning_threshold) as i32;
+        let over = (temperature - config.warning_threshold) as i32;
+        let span = (u8::MAX - MIN_BRIGHTNESS_LIMIT) as i32;
+        (u8::MAX as i32 - over * span / range) as u8
+    }
+}
+
+/// Run the thermal guard, which updates the LED brightness limit according to the temperature
+pub async fn run_thermal_guard<S: TemperatureSensor>(mut sensor: S, config: ThermalConfig) -> ! {
+    loop {
+        if let Some(temperature) = sensor.read_celsius().await {
+            let last_limit = BRIGHTNESS_LIMIT.load(Ordering::Relaxed);
+            let limit = brightness_limit(temperature, last_limit, &config);
+            if update_brightness_limit(limit) != limit {
+                if limit == 0 {
+                    warn!(
+                        "Temperature {}°C exceeds critical threshold, turn off LEDs",
+                        temperature
+                    );
+                } else if limit < u8::MAX {
+                    warn!(
+                        "Temperature {}°C exceeds warning threshold, limit LED brightness to {}",
+                        temperature, limit
+                    );
+                } else {
+                    info!("Temperature {}°C is back to normal", temperature);
+                }
+            }
+        } else {
+            error!("Read temperature sensor error");
+        }
+        Timer::after(config.poll_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static TABLE: [(u16, i16); 3] = [(1000, 60), (2000, 40), (3000, 20)];
+
+    #[test]
+    fn test_to_celsius() {
+        let ntc = NtcThermistor::new(|| None, &TABLE);
+        // Clamped to the ends of the table
+        assert_eq!(ntc.to_celsius(0), Some(60));
+        assert_eq!(ntc.to_celsius(4000), Some(20));
+        assert_eq!(ntc.to_celsius(1000), Some(60));
+        assert_eq!(ntc.to_celsius(2000), Some(40));
+        // Interpolated linearly
+        assert_eq!(ntc.to_celsius(1500), Some(50));
+        assert_eq!(ntc.to_celsius(2750), Some(25));
+
+        let empty = NtcThermistor::new(|| None, &[]);
+        assert_eq!(empty.to_celsius(1000), None);
+    }
+
+    #[test]
+    fn test_brightness_limit() {
+        let config = ThermalConfig::default();
+        assert_eq!(brightness_limit(25, u8::MAX, &config), u8::MAX);
+        assert_eq!(brightness_limit(45, u8::MAX, &config), u8::MAX);
+        // Throttled linearly between the thresholds
+        let warm = brightness_limit(50, u8::MAX, &config);
+        assert_eq!(warm, 181);
+        assert!(brightness_limit(59, u8::MAX, &config) >= MIN_BRIGHTNESS_LIMIT);
+        assert_eq!(brightness_limit(60, warm, &config), 0);
+        assert_eq!(brightness_limit(70, warm, &config), 0);
+
+        // Raised only after the temperature drops by the hysteresis
+        assert_eq!(brightness_limit(58, 0, &config), 0);
+        assert_eq!(brightness_limit(57, 0, &config), 0);
+        assert_eq!(brightness_limit(56, 0, &config), throttle(59, &config));
+        assert_eq!(brightness_limit(49, warm, &config), warm);
+        assert_eq!(brightness_limit(47, warm, &config), warm);
+        assert_eq!(brightness_limit(46, warm, &config), throttle(49, &config));
+        assert_eq!(brightness_limit(43, warm, &config), throttle(46, &config));
+        assert_eq!(brightness_limit(42, warm, &config), u8::MAX);
+        // Lowered immediately when the temperature rises again
+        assert_eq!(brightness_limit(51, warm, &config), throttle(51, &config));
+    }
+}
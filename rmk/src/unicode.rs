@@ -0,0 +1,243 @@
+//! Unicode input
+//!
+//! `KeyAction::Unicode(c)`, or `uc!('é')` in the keymap, types a character which isn't on any key, like symbols and emoji.
+//! The character is typed by the Unicode input method of the host OS, which is selected by [`set_unicode_mode`],
+//! or `UnicodeModeNext`, `UnicodeModePrevious`, `UnicodeModeLinux`, `UnicodeModeMacos`, `UnicodeModeWindows` and `UnicodeModeWincompose` keys.
+//!
+//! The input method should be enabled on the host, see [`UnicodeMode`].
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use heapless::Vec;
+use num_enum::FromPrimitive;
+
+use crate::keyboard_macro::MacroOperation;
+use crate::keycode::KeyCode;
+
+static UNICODE_MODE: AtomicU8 = AtomicU8::new(UnicodeMode::Linux as u8);
+
+/// Max number of operations to type a character
+pub(crate) const MAX_UNICODE_OPERATIONS: usize = 16;
+
+/// Unicode input method of the host OS
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UnicodeMode {
+    /// `Ctrl + Shift + U`, the code point in hex, then `Space`. It's supported by IBus, which is the default of GNOME
+    #[default]
+    Linux = 0,
+    /// Hold `Option` and type the UTF-16 code units in hex, the "Unicode Hex Input" input source should be selected
+    MacOs = 1,
+    /// Hold `Alt`, tap `+` on the keypad, then the code point in hex.
+    /// Set `EnableHexNumpad` to "1" in `HKEY_CURRENT_USER\Control Panel\Input Method` and log in again to enable it.
+    /// Characters outside of the BMP, like emoji, can't be typed
+    Windows = 2,
+    /// Tap the compose key(`RAlt` by default), `U`, the code point in hex, then `Enter`, [WinCompose](https://github.com/samhocevar/wincompose) should be running
+    WinCompose = 3,
+}
+
+impl From<u8> for UnicodeMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => UnicodeMode::MacOs,
+            2 => UnicodeMode::Windows,
+            3 => UnicodeMode::WinCompose,
+            _ => UnicodeMode::Linux,
+        }
+    }
+}
+
+impl UnicodeMode {
+    const NUM_MODES: u8 = 4;
+
+    fn next(self) -> Self {
+        ((self as u8 + 1) % Self::NUM_MODES).into()
+    }
+
+    fn previous(self) -> Self {
+        ((self as u8 + Self::NUM_MODES - 1) % Self::NUM_MODES).into()
+    }
+}
+
+/// Get current Unicode input mode
+pub fn unicode_mode() -> UnicodeMode {
+    UNICODE_MODE.load(Ordering::Relaxed).into()
+}
+
+/// Set the Unicode input mode, it's `UnicodeMode::Linux` by default
+pub fn set_unicode_mode(mode: UnicodeMode) {
+    info!("Unicode mode: {:?}", mode);
+    UNICODE_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Process the `UnicodeMode*` keys
+pub(crate) fn process_unicode_mode_key(key: KeyCode) {
+    let mode = match key {
+        KeyCode::UnicodeModeNext => unicode_mode().next(),
+        KeyCode::UnicodeModePrevious => unicode_mode().previous(),
+        KeyCode::UnicodeModeLinux => UnicodeMode::Linux,
+        KeyCode::UnicodeModeMacos => UnicodeMode::MacOs,
+        KeyCode::UnicodeModeWindows => UnicodeMode::Windows,
+        KeyCode::UnicodeModeWincompose => UnicodeMode::WinCompose,
+        _ => {
+            warn!("Unicode mode {:?} isn't supported", key);
+            return;
+        }
+    };
+    set_unicode_mode(mode);
+}
+
+/// Key of a hex digit, digits are on the keypad if `keypad` is true
+fn hex_key(digit: u32, keypad: bool) -> KeyCode {
+    let code = match digit {
+        0 if keypad => KeyCode::Kp0 as u16,
+        1..=9 if keypad => KeyCode::Kp1 as u16 + digit as u16 - 1,
+        0 => KeyCode::Kc0 as u16,
+        1..=9 => KeyCode::Kc1 as u16 + digit as u16 - 1,
+        _ => KeyCode::A as u16 + digit as u16 - 10,
+    };
+    KeyCode::from_primitive(code)
+}
+
+/// Tap the hex digits of `value`, at least 4 digits
+fn push_hex(
+    operations: &mut Vec<MacroOperation, MAX_UNICODE_OPERATIONS>,
+    value: u32,
+    keypad: bool,
+) {
+    let digits = ((32 - value.leading_zeros()).div_ceil(4)).max(4);
+    for i in (0..digits).rev() {
+        let digit = (value >> (i * 4)) & 0xF;
+        operations
+            .push(MacroOperation::Tap(hex_key(digit, keypad)))
+            .ok();
+    }
+}
+
+/// Operations which type `c` in the given mode, `None` if the mode can't type it
+pub(crate) fn unicode_operations(
+    c: char,
+    mode: UnicodeMode,
+) -> Option<Vec<MacroOperation, MAX_UNICODE_OPERATIONS>> {
+    let mut operations = Vec::new();
+    let code_point = c as u32;
+    match mode {
+        UnicodeMode::Linux => {
+            operations
+                .extend_from_slice(&[
+                    MacroOperation::Press(KeyCode::LCtrl),
+                    MacroOperation::Press(KeyCode::LShift),
+                    MacroOperation::Tap(KeyCode::U),
+                    MacroOperation::Release(KeyCode::LShift),
+                    MacroOperation::Release(KeyCode::LCtrl),
+                ])
+                .ok();
+            push_hex(&mut operations, code_point, false);
+            operations.push(MacroOperation::Tap(KeyCode::Space)).ok();
+        }
+        UnicodeMode::MacOs => {
+            operations.push(MacroOperation::Press(KeyCode::LAlt)).ok();
+            let mut units = [0; 2];
+            for unit in c.encode_utf16(&mut units) {
+                push_hex(&mut operations, *unit as u32, false);
+            }
+            operations.push(MacroOperation::Release(KeyCode::LAlt)).ok();
+        }
+        UnicodeMode::Windows => {
+            if code_point > 0xFFFF {
+                return None;
+            }
+            operations
+                .extend_from_slice(&[
+                    MacroOperation::Press(KeyCode::LAlt),
+                    MacroOperation::Tap(KeyCode::KpPlus),
+                ])
+                .ok();
+            push_hex(&mut operations, code_point, true);
+            operations.push(MacroOperation::Release(KeyCode::LAlt)).ok();
+        }
+        UnicodeMode::WinCompose => {
+            operations
+                .extend_from_slice(&[
+                    MacroOperation::Tap(KeyCode::RAlt),
+                    MacroOperation::Tap(KeyCode::U),
+                ])
+                .ok();
+            push_hex(&mut operations, code_point, false);
+            operations.push(MacroOperation::Tap(KeyCode::Enter)).ok();
+        }
+    }
+    Some(operations)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unicode_operations() {
+        use MacroOperation::*;
+        // é, U+00E9
+        let ops = unicode_operations('é', UnicodeMode::Linux).unwrap();
+        assert_eq!(
+            ops[..],
+            [
+                Press(KeyCode::LCtrl),
+                Press(KeyCode::LShift),
+                Tap(KeyCode::U),
+                Release(KeyCode::LShift),
+                Release(KeyCode::LCtrl),
+                Tap(KeyCode::Kc0),
+                Tap(KeyCode::Kc0),
+                Tap(KeyCode::E),
+                Tap(KeyCode::Kc9),
+                Tap(KeyCode::Space),
+            ]
+        );
+
+        let ops = unicode_operations('é', UnicodeMode::Windows).unwrap();
+        assert_eq!(
+            ops[..],
+            [
+                Press(KeyCode::LAlt),
+                Tap(KeyCode::KpPlus),
+                Tap(KeyCode::Kp0),
+                Tap(KeyCode::Kp0),
+                Tap(KeyCode::E),
+                Tap(KeyCode::Kp9),
+                Release(KeyCode::LAlt),
+            ]
+        );
+
+        // 😀, U+1F600, which is a surrogate pair of D83D DE00 in UTF-16
+        let ops = unicode_operations('😀', UnicodeMode::MacOs).unwrap();
+        assert_eq!(ops.len(), 10);
+        assert_eq!(
+            ops[1..5],
+            [
+                Tap(KeyCode::D),
+                Tap(KeyCode::Kc8),
+                Tap(KeyCode::Kc3),
+                Tap(KeyCode::D)
+            ]
+        );
+        assert_eq!(
+            ops[5..9],
+            [
+                Tap(KeyCode::D),
+                Tap(KeyCode::E),
+                Tap(KeyCode::Kc0),
+                Tap(KeyCode::Kc0)
+            ]
+        );
+
+        // 5 digits in the other modes
+        let ops = unicode_operations('😀', UnicodeMode::WinCompose).unwrap();
+        assert_eq!(ops.len(), 8);
+        assert!(unicode_operations('😀', UnicodeMode::Windows).is_none());
+
+        assert_eq!(UnicodeMode::Linux.previous(), UnicodeMode::WinCompose);
+        assert_eq!(UnicodeMode::WinCompose.next(), UnicodeMode::Linux);
+    }
+}
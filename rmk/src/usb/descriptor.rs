@@ -45,6 +45,83 @@ pub(crate) struct ViaReport {
     pub(crate) output_data: [u8; 32],
 }
 
+/// Report id of the NKRO keyboard report, which is sent via the composite hid interface
+pub(crate) const NKRO_REPORT_ID: u8 = 0x05;
+
+/// Size of the key bitmap of the NKRO report, which covers keycodes 0x00~0xDF
+pub(crate) const NKRO_BITMAP_SIZE: usize = 28;
+
+/// NKRO keyboard report, every keycode has a bit in the bitmap, so any number of keys can be pressed at the same time.
+///
+/// The report is appended to the composite hid report with report id [`NKRO_REPORT_ID`],
+/// the boot keyboard interface is kept for BIOS and other hosts which only support the boot protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NkroKeyboardReport {
+    pub(crate) modifier: u8,
+    pub(crate) keys: [u8; NKRO_BITMAP_SIZE],
+}
+
+impl NkroKeyboardReport {
+    /// Report descriptor, it's written by hand because `gen_hid_descriptor` can't describe a bitmap of 224 bits
+    #[rustfmt::skip]
+    pub(crate) const DESC: &'static [u8] = &[
+        0x05, 0x01,             // Usage Page (Generic Desktop)
+        0x09, 0x06,             // Usage (Keyboard)
+        0xA1, 0x01,             // Collection (Application)
+        0x85, NKRO_REPORT_ID,   //   Report ID
+        0x05, 0x07,             //   Usage Page (Keyboard)
+        0x19, 0xE0,             //   Usage Minimum (Left Control)
+        0x29, 0xE7,             //   Usage Maximum (Right GUI)
+        0x15, 0x00,             //   Logical Minimum (0)
+        0x25, 0x01,             //   Logical Maximum (1)
+        0x75, 0x01,             //   Report Size (1)
+        0x95, 0x08,             //   Report Count (8)
+        0x81, 0x02,             //   Input (Data, Variable, Absolute)
+        0x19, 0x00,             //   Usage Minimum (0x00)
+        0x29, 0xDF,             //   Usage Maximum (0xDF)
+        0x96, 0xE0, 0x00,       //   Report Count (224)
+        0x81, 0x02,             //   Input (Data, Variable, Absolute)
+        0xC0,                   // End Collection
+    ];
+
+    /// Set the bit of a keycode, keycodes out of the bitmap are ignored
+    pub(crate) fn set_key(&mut self, keycode: u8) {
+        if let Some(byte) = self.keys.get_mut(keycode as usize / 8) {
+            *byte |= 1 << (keycode % 8);
+        }
+    }
+
+    /// Keycodes whose bits are set, in ascending order
+    pub(crate) fn keycodes(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..NKRO_BITMAP_SIZE * 8)
+            .filter(|&k| self.keys[k / 8] & (1 << (k % 8)) != 0)
+            .map(|k| k as u8)
+    }
+
+    /// Convert to a boot keyboard report, only the first 6 keys are kept.
+    ///
+    /// It's used for connections which don't have the NKRO report, like BLE.
+    pub(crate) fn to_boot_report(&self) -> usbd_hid::descriptor::KeyboardReport {
+        let mut report = usbd_hid::descriptor::KeyboardReport {
+            modifier: self.modifier,
+            reserved: 0,
+            leds: 0,
+            keycodes: [0; 6],
+        };
+        for (slot, keycode) in report.keycodes.iter_mut().zip(self.keycodes()) {
+            *slot = keycode;
+        }
+        report
+    }
+
+    /// Serialize the report without report id, returns the size of the report
+    pub(crate) fn serialize(&self, data: &mut [u8]) -> usize {
+        data[0] = self.modifier;
+        data[1..NKRO_BITMAP_SIZE + 1].copy_from_slice(&self.keys);
+        NKRO_BITMAP_SIZE + 1
+    }
+}
+
 /// Predefined report ids for composite hid report.
 /// Should be same with `#[gen_hid_descriptor]`
 /// DO NOT EDIT
@@ -56,6 +133,7 @@ pub enum CompositeReportType {
     Mouse = 0x01,
     Media = 0x02,
     System = 0x03,
+    Gamepad = 0x04,
 }
 
 impl CompositeReportType {
@@ -64,12 +142,35 @@ impl CompositeReportType {
             0x01 => Self::Mouse,
             0x02 => Self::Media,
             0x03 => Self::System,
+            0x04 => Self::Gamepad,
             _ => Self::None,
         }
     }
 }
 
-/// A composite hid report which contains mouse, consumer, system reports.
+/// Gamepad report, 32 buttons and x, y axes
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = 0x05) = {
+        (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = 0x20) = {
+            #[packed_bits 32] #[item_settings data,variable,absolute] buttons=input;
+        };
+        (usage_page = GENERIC_DESKTOP,) = {
+            (usage = X,) = {
+                #[item_settings data,variable,absolute] x=input;
+            };
+            (usage = Y,) = {
+                #[item_settings data,variable,absolute] y=input;
+            };
+        };
+    }
+)]
+pub struct GamepadReport {
+    pub buttons: u32,
+    pub x: i8,
+    pub y: i8,
+}
+
+/// A composite hid report which contains mouse, consumer, system and gamepad reports.
 /// Report id is used to distinguish from them.
 #[gen_hid_descriptor(
     (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = MOUSE) = {
@@ -110,6 +211,21 @@ impl CompositeReportType {
                 #[item_settings data,array,absolute,not_null] system_usage_id=input;
             };
         };
+    },
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = 0x05) = {
+        (report_id = 0x04,) = {
+            (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = 0x20) = {
+                #[packed_bits 32] #[item_settings data,variable,absolute] gamepad_buttons=input;
+            };
+            (usage_page = GENERIC_DESKTOP,) = {
+                (usage = X,) = {
+                    #[item_settings data,variable,absolute] gamepad_x=input;
+                };
+                (usage = Y,) = {
+                    #[item_settings data,variable,absolute] gamepad_y=input;
+                };
+            };
+        };
     }
 )]
 #[derive(Default)]
@@ -121,6 +237,9 @@ pub struct CompositeReport {
     pub(crate) pan: i8,   // Scroll left (negative) or right (positive) this many units
     pub(crate) media_usage_id: u16,
     pub(crate) system_usage_id: u8,
+    pub(crate) gamepad_buttons: u32,
+    pub(crate) gamepad_x: i8,
+    pub(crate) gamepad_y: i8,
 }
 
 impl CompositeReport {
@@ -162,6 +281,40 @@ impl CompositeReport {
                 };
                 Ok(serialize(data, &system_report)?)
             }
+            CompositeReportType::Gamepad => {
+                let gamepad_report = GamepadReport {
+                    buttons: self.gamepad_buttons,
+                    x: self.gamepad_x,
+                    y: self.gamepad_y,
+                };
+                Ok(serialize(data, &gamepad_report)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nkro_report() {
+        let mut report = NkroKeyboardReport::default();
+        for keycode in [0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0xDF, 0xE0] {
+            report.set_key(keycode);
         }
+        report.modifier = 0x02;
+        assert_eq!(report.keys[0], 0xF0);
+        assert_eq!(report.keys[1], 0x07);
+        assert_eq!(report.keys[27], 0x80);
+
+        let boot = report.to_boot_report();
+        assert_eq!(boot.modifier, 0x02);
+        assert_eq!(boot.keycodes, [0x04, 0x05, 0x06, 0x07, 0x08, 0x09]);
+
+        let mut buf = [0; 29];
+        assert_eq!(report.serialize(&mut buf), 29);
+        assert_eq!(buf[0], 0x02);
+        assert_eq!(buf[28], 0x80);
     }
 }
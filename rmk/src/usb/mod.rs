@@ -1,6 +1,8 @@
 pub(crate) mod descriptor;
 
 use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::Timer;
 use embassy_usb::{
     class::hid::{Config, HidReaderWriter, HidWriter, ReportId, RequestHandler, State},
@@ -14,7 +16,8 @@ use usbd_hid::descriptor::SerializedDescriptor;
 use crate::{
     config::KeyboardUsbConfig,
     hid::{UsbHidReader, UsbHidReaderWriter, UsbHidWriter},
-    usb::descriptor::{CompositeReport, ViaReport},
+    power::update_power_source,
+    usb::descriptor::{CompositeReport, NkroKeyboardReport, ViaReport},
     CONNECTION_STATE,
 };
 
@@ -43,6 +46,29 @@ impl From<u8> for UsbState {
     }
 }
 
+/// Signaled by a key press while the USB bus is suspended
+static REMOTE_WAKEUP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Wake the host up, if the USB bus is suspended and the host allows remote wakeup
+pub(crate) fn request_remote_wakeup() {
+    REMOTE_WAKEUP.signal(());
+}
+
+/// Run the USB device, the host is woken up by remote wakeup when requested while the bus is suspended
+pub(crate) async fn run_usb_device<'d, D: Driver<'d>>(device: &mut UsbDevice<'d, D>) -> ! {
+    loop {
+        device.run_until_suspend().await;
+        // Requests before the suspend are outdated
+        REMOTE_WAKEUP.reset();
+        if let Either::Second(_) = select(device.wait_resume(), REMOTE_WAKEUP.wait()).await {
+            info!("Waking up the host");
+            if let Err(e) = device.remote_wakeup().await {
+                warn!("USB remote wakeup failed: {:?}", e);
+            }
+        }
+    }
+}
+
 pub(crate) async fn wait_for_usb_suspend() {
     loop {
         // Check usb suspend state every 500ms
@@ -70,13 +96,13 @@ pub(crate) async fn wait_for_usb_enabled() {
 // In this case, report id should be used.
 // The keyboard usb device should have 3 hid instances:
 // 1. Boot keyboard: 1 endpoint in
-// 2. Other: Mouse + System control + Consumer control: 1 endpoint in
+// 2. Other: Mouse + System control + Consumer control + Gamepad + NKRO keyboard: 1 endpoint in
 // 3. Via: used to communicate with via: 2 endpoints(in/out)
 pub(crate) struct KeyboardUsbDevice<'d, D: Driver<'d>> {
     pub(crate) device: UsbDevice<'d, D>,
     pub(crate) keyboard_hid_writer: UsbHidWriter<'d, D, 8>,
     pub(crate) keyboard_hid_reader: UsbHidReader<'d, D, 1>,
-    pub(crate) other_hid_writer: UsbHidWriter<'d, D, 9>,
+    pub(crate) other_hid_writer: UsbHidWriter<'d, D, 32>,
     pub(crate) via_hid: UsbHidReaderWriter<'d, D, 32, 32>,
 }
 
@@ -134,15 +160,24 @@ impl<D: Driver<'static>> KeyboardUsbDevice<'static, D> {
             keyboard_hid_config,
         );
 
+        // NKRO report is appended to the composite report, so that it doesn't take another endpoint
+        static OTHER_REPORT_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+        let other_report_desc = OTHER_REPORT_DESC.init([0; 256]);
+        let composite_len = CompositeReport::desc().len();
+        let other_report_desc_len = composite_len + NkroKeyboardReport::DESC.len();
+        other_report_desc[..composite_len].copy_from_slice(CompositeReport::desc());
+        other_report_desc[composite_len..other_report_desc_len]
+            .copy_from_slice(NkroKeyboardReport::DESC);
+
         static other_request_handler: StaticCell<UsbRequestHandler> = StaticCell::new();
         let other_hid_config = Config {
-            report_descriptor: CompositeReport::desc(),
+            report_descriptor: &other_report_desc[..other_report_desc_len],
             request_handler: Some(other_request_handler.init(UsbRequestHandler {})),
             poll_ms: 1,
             max_packet_size: 64,
         };
         static OTHER_HID_STATE: StaticCell<State> = StaticCell::new();
-        let other_hid: HidWriter<'_, D, 9> = HidWriter::new(
+        let other_hid: HidWriter<'_, D, 32> = HidWriter::new(
             &mut builder,
             OTHER_HID_STATE.init(State::new()),
             other_hid_config,
@@ -198,6 +233,7 @@ impl Handler for UsbDeviceHandler {
             USB_STATE.store(UsbState::Disabled as u8, Ordering::Relaxed);
             info!("Device disabled");
         }
+        update_power_source(enabled);
     }
 
     fn reset(&mut self) {
@@ -214,6 +250,7 @@ impl Handler for UsbDeviceHandler {
         if configured {
             USB_STATE.store(UsbState::Configured as u8, Ordering::Relaxed);
             CONNECTION_STATE.store(true, Ordering::Release);
+            crate::keyboard::notify_host_reconnected();
             info!("Device configured, it may now draw up to the configured current from Vbus.")
         } else {
             USB_STATE.store(UsbState::Enabled as u8, Ordering::Relaxed);
@@ -223,9 +260,11 @@ impl Handler for UsbDeviceHandler {
 
     fn suspended(&mut self, suspended: bool) {
         USB_STATE.store(UsbState::Enabled as u8, Ordering::Release);
+        crate::sleep::notify_host_suspended(suspended);
         if suspended {
             info!("Device suspended, the Vbus current limit is 500µA (or 2.5mA for high-power devices with remote wakeup enabled).");
         } else {
+            crate::keyboard::notify_host_reconnected();
             info!("Device resumed, the Vbus current limit is 500µA (or 2.5mA for high-power devices with remote wakeup enabled).");
         }
     }
@@ -0,0 +1,113 @@
+//! Keyboard functions triggered by user keycodes
+//!
+//! With BLE, `User0` ~ `User11` switch BLE profiles. Other user keycodes trigger the [`UserKeyFunction`]s assigned in
+//! [`UserKeyConfig`](crate::config::UserKeyConfig), [`DEFAULT_USER_KEYS`] is used by default.
+//! Since host tools like Vial can only assign user keycodes, these functions are mapped to user keycodes rather than dedicated keycodes.
+//! Functions of disabled features, like the calculator without the `calculator` feature, are compiled out.
+
+use crate::keycode::KeyCode;
+#[cfg(feature = "_nrf_ble")]
+use crate::output::OutputMode;
+
+/// A function of the keyboard which is triggered by a user keycode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UserKeyFunction {
+    /// Show the battery level on RGB LEDs while the key is held, see `battery_gauge` of [`RGBLightConfig`](crate::config::RGBLightConfig)
+    BatteryCheck,
+    /// Switch to the next display page, see [`crate::display`]
+    NextDisplayPage,
+    /// Start or stop the key tester, see [`crate::diagnostic`]
+    KeyTester,
+    /// Switch to the next power profile, see [`crate::power`]
+    NextPowerProfile,
+    /// Start or close the calculator, see [`crate::calculator`]
+    #[cfg(feature = "calculator")]
+    Calculator,
+    /// Start or pause the pomodoro timer, see [`crate::pomodoro`]
+    PomodoroStartPause,
+    /// Reset the pomodoro timer
+    PomodoroReset,
+    /// Suppress or resume the auto-lock, see [`crate::auto_lock`]
+    ToggleAutoLock,
+    /// Select the next parameter adjusted by encoders, see [`crate::adjust`]
+    NextAdjustTarget,
+    /// Select the output, see [`crate::output`]
+    #[cfg(feature = "_nrf_ble")]
+    Output(OutputMode),
+    /// Fire the actuator of the index, see [`crate::actuator`]
+    FireActuator(u8),
+    /// Turn the key press feedback of actuators off or on
+    ToggleKeyPressFeedback,
+    /// Toggle natural scrolling on the current OS, see [`crate::scroll`]
+    ToggleNaturalScrolling,
+}
+
+/// Default functions of user keycodes
+pub const DEFAULT_USER_KEYS: &[(KeyCode, UserKeyFunction)] = &[
+    (KeyCode::User12, UserKeyFunction::BatteryCheck),
+    (KeyCode::User13, UserKeyFunction::NextDisplayPage),
+    (KeyCode::User14, UserKeyFunction::KeyTester),
+    (KeyCode::User15, UserKeyFunction::NextPowerProfile),
+    #[cfg(feature = "calculator")]
+    (KeyCode::User16, UserKeyFunction::Calculator),
+    (KeyCode::User17, UserKeyFunction::PomodoroStartPause),
+    (KeyCode::User18, UserKeyFunction::PomodoroReset),
+    (KeyCode::User19, UserKeyFunction::ToggleAutoLock),
+    (KeyCode::User20, UserKeyFunction::NextAdjustTarget),
+    #[cfg(feature = "_nrf_ble")]
+    (KeyCode::User21, UserKeyFunction::Output(OutputMode::Auto)),
+    #[cfg(feature = "_nrf_ble")]
+    (KeyCode::User22, UserKeyFunction::Output(OutputMode::Usb)),
+    #[cfg(feature = "_nrf_ble")]
+    (KeyCode::User23, UserKeyFunction::Output(OutputMode::Ble)),
+    (KeyCode::User24, UserKeyFunction::FireActuator(0)),
+    (KeyCode::User25, UserKeyFunction::FireActuator(1)),
+    (KeyCode::User26, UserKeyFunction::FireActuator(2)),
+    (KeyCode::User27, UserKeyFunction::FireActuator(3)),
+    (KeyCode::User28, UserKeyFunction::ToggleKeyPressFeedback),
+    (KeyCode::User29, UserKeyFunction::ToggleNaturalScrolling),
+];
+
+/// Get the function assigned to a user keycode, the first match wins
+pub(crate) fn user_key_function(
+    functions: &[(KeyCode, UserKeyFunction)],
+    key: KeyCode,
+) -> Option<UserKeyFunction> {
+    functions
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, function)| *function)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_user_key_function() {
+        assert_eq!(
+            user_key_function(DEFAULT_USER_KEYS, KeyCode::User14),
+            Some(UserKeyFunction::KeyTester)
+        );
+        assert_eq!(
+            user_key_function(DEFAULT_USER_KEYS, KeyCode::User25),
+            Some(UserKeyFunction::FireActuator(1))
+        );
+        assert_eq!(user_key_function(DEFAULT_USER_KEYS, KeyCode::User30), None);
+        // Remapped keys
+        let functions = [
+            (KeyCode::User0, UserKeyFunction::PomodoroStartPause),
+            (KeyCode::User14, UserKeyFunction::NextDisplayPage),
+        ];
+        assert_eq!(
+            user_key_function(&functions, KeyCode::User0),
+            Some(UserKeyFunction::PomodoroStartPause)
+        );
+        assert_eq!(
+            user_key_function(&functions, KeyCode::User14),
+            Some(UserKeyFunction::NextDisplayPage)
+        );
+        assert_eq!(user_key_function(&functions, KeyCode::User13), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! RawHID commands of the application context
+//!
+//! Command `0xF8` is an RMK extension, a helper app on the host announces the focused application by it,
+//! so that the layer mapped to the application in [`AppLayer`] is activated. Byte 1 of the request is one of [`AppContextCommand`]:
+//!
+//! | Command | Request | Reply |
+//! |---------|---------|-------|
+//! | `0x00` focus | name length n(byte 2), then n bytes of application name, at most 28 | activated layer(byte 2), `0xFF` if no layer is mapped |
+//! | `0x01` clear | | |
+//! | `0x02` get | | activated layer(byte 2), `0xFF` if no layer is activated by the host |
+//!
+//! The layer activated by the previous application is deactivated when another application is focused, or the context is cleared.
+//! Focus and clear switch layers, so they require an authenticated host with `host_auth` enabled.
+
+use core::cell::RefCell;
+
+use num_enum::TryFromPrimitive;
+
+use super::protocol::ViaCommand;
+use crate::{
+    config::AppLayer, keymap::KeyMap, safe_mode::safe_mode_active, usb::descriptor::ViaReport,
+};
+
+/// Max length of the application name in a request
+pub(crate) const MAX_APP_NAME_LEN: usize = 28;
+
+/// Replied when there's no layer of the application context
+const NO_LAYER: u8 = 0xFF;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum AppContextCommand {
+    Focus = 0x00,
+    Clear = 0x01,
+    Get = 0x02,
+}
+
+/// Whether the sub-command changes the active layers
+pub(crate) fn is_write_command(command: u8) -> bool {
+    command == AppContextCommand::Focus as u8 || command == AppContextCommand::Clear as u8
+}
+
+/// Find the layer mapped to the application
+fn find_app_layer(app_layers: &[AppLayer], app: &[u8]) -> Option<u8> {
+    app_layers
+        .iter()
+        .find(|a| {
+            let name = a.app.as_bytes();
+            name[..name.len().min(MAX_APP_NAME_LEN)] == *app
+        })
+        .map(|a| a.layer)
+}
+
+/// Process application context commands, `app_layer` is the layer currently activated by the host
+pub(crate) fn process_app_context<
+    'a,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+>(
+    report: &mut ViaReport,
+    app_layers: &[AppLayer],
+    app_layer: &mut Option<u8>,
+    keymap: &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+) {
+    let data = &mut report.input_data;
+    match AppContextCommand::try_from_primitive(report.output_data[1]) {
+        Ok(AppContextCommand::Focus) => {
+            let len = (report.output_data[2] as usize).min(MAX_APP_NAME_LEN);
+            let app = &report.output_data[3..3 + len];
+            // The host can't activate layers in safe mode
+            let layer = find_app_layer(app_layers, app)
+                .filter(|&l| (l as usize) < NUM_LAYER && !safe_mode_active());
+            debug!("Focused app: {:?}, layer: {:?}", app, layer);
+            if layer != *app_layer {
+                let mut keymap = keymap.borrow_mut();
+                if let Some(l) = *app_layer {
+                    keymap.set_host_layer(l, false);
+                }
+                if let Some(l) = layer {
+                    keymap.set_host_layer(l, true);
+                }
+                *app_layer = layer;
+            }
+            data[2] = layer.unwrap_or(NO_LAYER);
+        }
+        Ok(AppContextCommand::Clear) => {
+            if let Some(l) = app_layer.take() {
+                keymap.borrow_mut().set_host_layer(l, false);
+            }
+        }
+        Ok(AppContextCommand::Get) => data[2] = app_layer.unwrap_or(NO_LAYER),
+        Err(e) => {
+            warn!("Invalid app context command: {}", e.number);
+            data[0] = ViaCommand::Unhandled as u8;
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Challenge-response authentication of the host
+//!
+//! If a secret is set, commands which change the keymap, macros or settings are rejected until the host proves that it knows the secret:
+//!
+//! 1. The host sends `0xF0`, the keyboard replies a 16-byte challenge in bytes 1..17
+//! 2. The host sends `0xF1` with the first 16 bytes of `HMAC-SHA256(secret, challenge)` in bytes 1..17
+//! 3. The keyboard replies 1 in byte 1 if the response is correct, otherwise 0
+//!
+//! After that, writes are accepted until there's no write for [`AUTH_TIMEOUT`].
+//!
+//! The secret is `auth_secret` of [`VialConfig`](crate::config::VialConfig), or the secret saved in storage, which takes precedence.
+//! The saved secret is provisioned or rotated by `0xFC` with the length in byte 1 and the secret in bytes 2..,
+//! length 0 removes the saved secret. The keyboard replies 1 in byte 1 if the secret is saved.
+//! `0xFC` is a write, so an authenticated session is required once any secret is set.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Length of the challenge and the response
+pub(crate) const CHALLENGE_LEN: usize = 16;
+
+/// Max length of the secret saved in storage, which fits in one report
+pub(crate) const MAX_SECRET_LEN: usize = 30;
+
+/// The host must authenticate again if there's no write for this duration
+pub(crate) const AUTH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Secret saved in storage, it's never printed
+#[derive(Clone, Copy)]
+pub(crate) struct AuthSecret {
+    len: u8,
+    bytes: [u8; MAX_SECRET_LEN],
+}
+
+impl AuthSecret {
+    /// Create a secret, `None` if it's longer than [`MAX_SECRET_LEN`]
+    pub(crate) fn new(secret: &[u8]) -> Option<Self> {
+        if secret.len() > MAX_SECRET_LEN {
+            return None;
+        }
+        let mut bytes = [0; MAX_SECRET_LEN];
+        bytes[..secret.len()].copy_from_slice(secret);
+        Some(Self {
+            len: secret.len() as u8,
+            bytes,
+        })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl core::fmt::Debug for AuthSecret {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AuthSecret({} bytes)", self.len)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AuthSecret {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "AuthSecret({} bytes)", self.len)
+    }
+}
+
+// Secret loaded from storage at startup, an empty secret means no secret is saved
+static STORED_SECRET: Mutex<CriticalSectionRawMutex, Cell<Option<AuthSecret>>> =
+    Mutex::new(Cell::new(None));
+
+/// Restore the secret saved in storage
+pub(crate) fn restore_auth_secret(secret: AuthSecret) {
+    STORED_SECRET.lock(|s| s.set(Some(secret)));
+}
+
+pub(crate) struct HostAuth<'a> {
+    /// Secret of `VialConfig`
+    config_secret: Option<&'a [u8]>,
+    /// Secret saved in storage, it overrides the config secret
+    stored_secret: Option<AuthSecret>,
+    /// Random bytes of this boot, see `auth_seed` of `VialConfig`
+    seed: Option<&'a [u8]>,
+    /// Pending challenge, each challenge can be answered only once
+    challenge: Option<[u8; CHALLENGE_LEN]>,
+    /// Time of the last accepted write, `None` if the host isn't authenticated
+    last_active: Option<Instant>,
+    counter: u32,
+}
+
+impl<'a> HostAuth<'a> {
+    pub(crate) fn new(config_secret: Option<&'a [u8]>, seed: Option<&'a [u8]>) -> Self {
+        let auth = Self {
+            config_secret,
+            seed,
+            stored_secret: STORED_SECRET
+                .lock(|s| s.get())
+                .filter(|s| !s.as_slice().is_empty()),
+            challenge: None,
+            last_active: None,
+            counter: 0,
+        };
+        if auth.enabled() && seed.is_none() {
+            warn!("`auth_seed` is not set, authentication challenges may repeat after a reboot");
+        }
+        auth
+    }
+
+    fn secret(&self) -> Option<&[u8]> {
+        match &self.stored_secret {
+            Some(secret) => Some(secret.as_slice()),
+            None => self.config_secret,
+        }
+    }
+
+    /// Whether a secret is set, hosts needn't authenticate if it's not
+    pub(crate) fn enabled(&self) -> bool {
+        self.secret().is_some()
+    }
+
+    /// Create a new challenge, the current session is ended
+    pub(crate) fn new_challenge(&mut self, now: Instant) -> [u8; CHALLENGE_LEN] {
+        // Not every chip has a RNG, the challenge is derived from the secret, the seed, uptime and a counter.
+        // It's unpredictable for hosts which don't know the secret, and doesn't repeat in a boot.
+        // Without a seed, the same challenge can be created again after a reboot, at the same uptime and counter.
+        self.counter = self.counter.wrapping_add(1);
+        let digest = Sha256::new()
+            .chain_update(self.secret().unwrap_or_default())
+            .chain_update(self.seed.unwrap_or_default())
+            .chain_update(now.as_ticks().to_le_bytes())
+            .chain_update(self.counter.to_le_bytes())
+            .finalize();
+        let mut challenge = [0; CHALLENGE_LEN];
+        challenge.copy_from_slice(&digest[..CHALLENGE_LEN]);
+        self.challenge = Some(challenge);
+        self.last_active = None;
+        challenge
+    }
+
+    /// Verify the response of the pending challenge
+    pub(crate) fn verify(&mut self, response: &[u8], now: Instant) -> bool {
+        let Some(challenge) = self.challenge.take() else {
+            return false;
+        };
+        let Some(Ok(mut mac)) = self.secret().map(Hmac::<Sha256>::new_from_slice) else {
+            return false;
+        };
+        mac.update(&challenge);
+        let ok = mac
+            .verify_truncated_left(&response[..CHALLENGE_LEN])
+            .is_ok();
+        self.last_active = ok.then_some(now);
+        ok
+    }
+
+    /// Check whether a write is allowed now, an allowed write extends the session
+    pub(crate) fn authorize(&mut self, now: Instant) -> bool {
+        match self.last_active {
+            Some(t) if now.saturating_duration_since(t) < AUTH_TIMEOUT => {
+                self.last_active = Some(now);
+                true
+            }
+            _ => {
+                self.last_active = None;
+                false
+            }
+        }
+    }
+
+    /// Replace the secret saved in storage, an empty secret falls back to the config secret.
+    ///
+    /// The current session and the pending challenge are ended, the host should authenticate with the new secret.
+    pub(crate) fn set_secret(&mut self, secret: AuthSecret) {
+        self.stored_secret = (!secret.as_slice().is_empty()).then_some(secret);
+        self.challenge = None;
+        self.last_active = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECRET: &[u8] = b"a long random secret";
+
+    fn respond(secret: &[u8], challenge: &[u8]) -> [u8; CHALLENGE_LEN] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(challenge);
+        let mut response = [0; CHALLENGE_LEN];
+        response.copy_from_slice(&mac.finalize().into_bytes()[..CHALLENGE_LEN]);
+        response
+    }
+
+    #[test]
+    fn test_verify() {
+        let now = Instant::from_secs(10);
+        let mut auth = HostAuth::new(Some(SECRET), None);
+        assert!(auth.enabled());
+        assert!(!auth.authorize(now));
+
+        // No pending challenge
+        assert!(!auth.verify(&[0; CHALLENGE_LEN], now));
+
+        // Wrong response, the challenge is consumed
+        let challenge = auth.new_challenge(now);
+        let mut wrong = respond(SECRET, &challenge);
+        wrong[0] ^= 1;
+        assert!(!auth.verify(&wrong, now));
+        assert!(!auth.verify(&respond(SECRET, &challenge), now));
+        assert!(!auth.authorize(now));
+
+        // Correct response
+        let challenge = auth.new_challenge(now);
+        assert!(auth.verify(&respond(SECRET, &challenge), now));
+        assert!(auth.authorize(now));
+
+        // A replayed response of an answered challenge is rejected, even if a new challenge is pending
+        let replayed = respond(SECRET, &challenge);
+        assert!(!auth.verify(&replayed, now));
+        let next = auth.new_challenge(now);
+        assert_ne!(next, challenge);
+        assert!(!auth.verify(&replayed, now));
+        assert!(!auth.authorize(now));
+    }
+
+    #[test]
+    fn test_challenge_seed() {
+        let now = Instant::from_secs(10);
+        // Without a seed, the first challenge after every boot is the same at the same uptime
+        let first = HostAuth::new(Some(SECRET), None).new_challenge(now);
+        assert_eq!(HostAuth::new(Some(SECRET), None).new_challenge(now), first);
+        // Seeds of different boots give different challenges
+        let seeded = HostAuth::new(Some(SECRET), Some(&[1, 2, 3, 4])).new_challenge(now);
+        assert_ne!(seeded, first);
+        let reseeded = HostAuth::new(Some(SECRET), Some(&[5, 6, 7, 8])).new_challenge(now);
+        assert_ne!(reseeded, seeded);
+
+        // Challenges of a seeded boot are answered as usual
+        let mut auth = HostAuth::new(Some(SECRET), Some(&[1, 2, 3, 4]));
+        let challenge = auth.new_challenge(now);
+        assert!(auth.verify(&respond(SECRET, &challenge), now));
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        let start = Instant::from_secs(10);
+        let mut auth = HostAuth::new(Some(SECRET), None);
+        let challenge = auth.new_challenge(start);
+        assert!(auth.verify(&respond(SECRET, &challenge), start));
+
+        // Each write extends the session
+        let write = start + AUTH_TIMEOUT - Duration::from_secs(1);
+        assert!(auth.authorize(write));
+        assert!(auth.authorize(write + AUTH_TIMEOUT - Duration::from_secs(1)));
+        // Timed out, the session is ended
+        let idle = write + AUTH_TIMEOUT * 2;
+        assert!(!auth.authorize(idle));
+        assert!(!auth.authorize(start));
+    }
+
+    #[test]
+    fn test_set_secret() {
+        let now = Instant::from_secs(10);
+        let mut auth = HostAuth::new(None, None);
+        assert!(!auth.enabled());
+
+        let new_secret = b"rotated";
+        auth.set_secret(AuthSecret::new(new_secret).unwrap());
+        assert!(auth.enabled());
+        let challenge = auth.new_challenge(now);
+        assert!(!auth.verify(&respond(SECRET, &challenge), now));
+        let challenge = auth.new_challenge(now);
+        assert!(auth.verify(&respond(new_secret, &challenge), now));
+
+        // Rotation ends the session, an empty secret falls back to the config secret
+        auth.set_secret(AuthSecret::new(&[]).unwrap());
+        assert!(!auth.enabled());
+        assert!(!auth.authorize(now));
+        assert!(AuthSecret::new(&[0; MAX_SECRET_LEN + 1]).is_none());
+    }
+}
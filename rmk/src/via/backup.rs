@@ -0,0 +1,626 @@
+//! RawHID commands of the config backup
+//!
+//! Command `0xFA` is an RMK extension which exports the whole persisted config, including the keymap, encoders, macros,
+//! dynamic macros and settings, as a single blob, and imports it to the same keyboard or another keyboard with the same layout.
+//! Byte 1 of the request is one of [`ConfigBackupCommand`], multi-byte values are big endian:
+//!
+//! | Command | Request | Reply |
+//! |---------|---------|-------|
+//! | `0x00` export info | | blob size(u32), CRC32 of the blob(u32) |
+//! | `0x01` export read | offset(u32), length n(byte 6), at most 24 | n bytes of the blob from byte 7 |
+//! | `0x02` import begin | blob size(u32) | accepted(byte 2) |
+//! | `0x03` import write | offset(u32), length n(byte 6), then n bytes of the blob, at most 24 | accepted(byte 2) |
+//! | `0x04` import finish | | result(byte 2), see [`ImportResult`] |
+//! | `0x05` import abort | | |
+//!
+//! The blob starts with a 16 bytes header: magic `RMKC`, version, numbers of rows, columns, layers and encoders,
+//! 3 reserved bytes and the blob size(u32). Then the keymap and encoders in the binary format of [`KeyAction::encode`],
+//! the macro buffer, dynamic macros and settings follow, and the blob ends with the CRC32 of all previous bytes.
+//! Unlike via keycodes, the binary format keeps actions like `SoftOff`, `Unicode` and `TapHold` without loss.
+//! BLE bond info and health counters belong to the board, they're never exported.
+//!
+//! Import writes must be sequential. The blob is applied only after it's complete, its CRC is correct, its layout matches
+//! the keyboard and all actions can be decoded, otherwise nothing is changed.
+
+use core::cell::RefCell;
+
+use byteorder::{BigEndian, ByteOrder};
+use heapless::Vec;
+use num_enum::TryFromPrimitive;
+
+use super::protocol::ViaCommand;
+use crate::{
+    action::{ActionCodecError, KeyAction, KEY_ACTION_BYTES},
+    display::{active_display_page, restore_display_page},
+    generation::{bump_generation, ConfigItem},
+    keyboard_macro::{
+        dynamic_macro, restore_dynamic_macro, DYNAMIC_MACRO_SIZE, MACRO_SPACE_SIZE,
+        NUM_DYNAMIC_MACRO,
+    },
+    keymap::KeyMap,
+    report::{nkro_enabled, restore_nkro},
+    rgb::{
+        palettes, restore_palette, restore_rgb_settings, rgb_settings, rgb_state, Effect, Hsv,
+        RgbSettings, ZoneState, MAX_LED_ZONES,
+    },
+    storage::{FlashOperationMessage, FLASH_CHANNEL},
+    usb::descriptor::ViaReport,
+};
+
+const BLOB_MAGIC: &[u8; 4] = b"RMKC";
+const BLOB_VERSION: u8 = 2;
+const HEADER_LEN: usize = 16;
+const CRC_LEN: usize = 4;
+
+/// Default layer, NKRO, palette, display page, RGB switch and number of zones, then 6 bytes per zone
+const SETTINGS_LEN: usize = 6 + MAX_LED_ZONES * 6;
+
+/// Max bytes of the blob in a request
+const MAX_CHUNK_LEN: usize = 24;
+
+/// Max size of an imported blob
+const MAX_BLOB_SIZE: usize = crate::ram_profile::MAX_BLOB_SIZE;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum ConfigBackupCommand {
+    ExportInfo = 0x00,
+    ExportRead = 0x01,
+    ImportBegin = 0x02,
+    ImportWrite = 0x03,
+    ImportFinish = 0x04,
+    ImportAbort = 0x05,
+}
+
+/// Whether the sub-command imports a blob, which requires an authenticated host
+pub(crate) fn is_import_command(command: u8) -> bool {
+    command >= ConfigBackupCommand::ImportBegin as u8
+}
+
+/// Result of an import
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub(crate) enum ImportResult {
+    Applied = 0,
+    /// Not all bytes of the blob are received
+    Incomplete = 1,
+    /// Wrong magic, version, size or CRC
+    InvalidBlob = 2,
+    /// The blob is exported from a keyboard with another layout or firmware config
+    LayoutMismatch = 3,
+    /// A config transaction is active
+    Busy = 4,
+}
+
+/// Size of the blob of a keyboard
+fn blob_size(rows: usize, cols: usize, layers: usize, num_encoder: usize) -> usize {
+    HEADER_LEN
+        + layers * rows * cols * KEY_ACTION_BYTES
+        + layers * num_encoder * 2 * KEY_ACTION_BYTES
+        + MACRO_SPACE_SIZE
+        + NUM_DYNAMIC_MACRO * DYNAMIC_MACRO_SIZE
+        + SETTINGS_LEN
+        + CRC_LEN
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Serializes the blob and copies the bytes in a window to `out`, so that the blob is read in chunks without being buffered
+struct BlobWriter<'b> {
+    pos: usize,
+    /// Offset of the window in the blob
+    start: usize,
+    out: &'b mut [u8],
+    crc: u32,
+}
+
+impl<'b> BlobWriter<'b> {
+    fn new(start: usize, out: &'b mut [u8]) -> Self {
+        Self {
+            pos: 0,
+            start,
+            out,
+            crc: 0xFFFF_FFFF,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.crc = crc32(self.crc, data);
+        self.copy(data);
+    }
+
+    fn write_action(&mut self, action: KeyAction) {
+        let bytes = action.encode().unwrap_or_else(|e| {
+            warn!("Export {:?} as No, it can't be encoded: {:?}", action, e);
+            [0; KEY_ACTION_BYTES]
+        });
+        self.write(&bytes);
+    }
+
+    fn copy(&mut self, data: &[u8]) {
+        let begin = self.pos.max(self.start);
+        let end = (self.pos + data.len()).min(self.start + self.out.len());
+        if begin < end {
+            self.out[begin - self.start..end - self.start]
+                .copy_from_slice(&data[begin - self.pos..end - self.pos]);
+        }
+        self.pos += data.len();
+    }
+
+    /// Append the CRC, returns the size and the CRC of the blob
+    fn finish(mut self) -> (usize, u32) {
+        let crc = !self.crc;
+        self.copy(&crc.to_be_bytes());
+        (self.pos, crc)
+    }
+}
+
+fn num_encoder<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+    keymap: &KeyMap<'_, ROW, COL, NUM_LAYER>,
+) -> usize {
+    keymap
+        .encoders
+        .as_ref()
+        .map_or(0, |encoders| encoders.num_encoder())
+}
+
+fn write_header(w: &mut BlobWriter, rows: usize, cols: usize, layers: usize, num_encoder: usize) {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(BLOB_MAGIC);
+    header[4] = BLOB_VERSION;
+    header[5] = rows as u8;
+    header[6] = cols as u8;
+    header[7] = layers as u8;
+    header[8] = num_encoder as u8;
+    BigEndian::write_u32(
+        &mut header[12..16],
+        blob_size(rows, cols, layers, num_encoder) as u32,
+    );
+    w.write(&header);
+}
+
+fn write_config<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+    w: &mut BlobWriter,
+    keymap: &KeyMap<'_, ROW, COL, NUM_LAYER>,
+) {
+    let num_encoder = num_encoder(keymap);
+    write_header(w, ROW, COL, NUM_LAYER, num_encoder);
+    for action in keymap.layers.iter().flatten().flatten() {
+        w.write_action(*action);
+    }
+    for layer in 0..NUM_LAYER {
+        for id in 0..num_encoder {
+            let (cw, ccw) = keymap
+                .encoders
+                .as_ref()
+                .and_then(|encoders| encoders.get(layer, id))
+                .unwrap_or((KeyAction::No, KeyAction::No));
+            w.write_action(cw);
+            w.write_action(ccw);
+        }
+    }
+    w.write(&keymap.macro_cache);
+    for slot in 0..NUM_DYNAMIC_MACRO {
+        w.write(&dynamic_macro(slot as u8));
+    }
+    let rgb = rgb_settings();
+    w.write(&[
+        keymap.get_default_layer(),
+        nkro_enabled() as u8,
+        rgb_state().palette,
+        active_display_page().index(),
+        rgb.enabled as u8,
+        rgb.num_zones,
+    ]);
+    for zone in rgb.zones.iter() {
+        w.write(&[
+            zone.enabled as u8,
+            zone.effect as u8,
+            zone.color.h,
+            zone.color.s,
+            zone.color.v,
+            zone.speed,
+        ]);
+    }
+}
+
+/// Check the blob before it's applied
+fn check_blob(
+    blob: &[u8],
+    rows: usize,
+    cols: usize,
+    layers: usize,
+    num_encoder: usize,
+) -> Result<(), ImportResult> {
+    if blob.len() < HEADER_LEN + CRC_LEN
+        || &blob[..4] != BLOB_MAGIC
+        || blob[4] != BLOB_VERSION
+        || BigEndian::read_u32(&blob[12..16]) as usize != blob.len()
+    {
+        return Err(ImportResult::InvalidBlob);
+    }
+    let (data, crc) = blob.split_at(blob.len() - CRC_LEN);
+    if !crc32(0xFFFF_FFFF, data) != BigEndian::read_u32(crc) {
+        return Err(ImportResult::InvalidBlob);
+    }
+    if blob[5..9] != [rows as u8, cols as u8, layers as u8, num_encoder as u8]
+        || blob.len() != blob_size(rows, cols, layers, num_encoder)
+    {
+        return Err(ImportResult::LayoutMismatch);
+    }
+    let num_actions = layers * (rows * cols + num_encoder * 2);
+    let actions = &blob[HEADER_LEN..HEADER_LEN + num_actions * KEY_ACTION_BYTES];
+    if actions
+        .chunks_exact(KEY_ACTION_BYTES)
+        .any(|bytes| decode_action(bytes).is_err())
+    {
+        return Err(ImportResult::InvalidBlob);
+    }
+    Ok(())
+}
+
+fn decode_action(bytes: &[u8]) -> Result<KeyAction, ActionCodecError> {
+    let mut buf = [0; KEY_ACTION_BYTES];
+    buf.copy_from_slice(bytes);
+    KeyAction::decode(buf)
+}
+
+/// Read the next action of a checked blob
+fn take_action(data: &mut &[u8]) -> KeyAction {
+    // Actions are checked by `check_blob` before the blob is applied
+    decode_action(take(data, KEY_ACTION_BYTES)).unwrap_or(KeyAction::No)
+}
+
+/// Split the first `n` bytes off `data`
+fn take<'b>(data: &mut &'b [u8], n: usize) -> &'b [u8] {
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    head
+}
+
+/// Apply a checked blob to the keymap and settings, only changed items are written to the storage
+async fn apply_config<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+    blob: &[u8],
+    keymap: &RefCell<KeyMap<'_, ROW, COL, NUM_LAYER>>,
+) {
+    let num_encoder = num_encoder(&*keymap.borrow());
+    let settings_start = blob.len() - CRC_LEN - SETTINGS_LEN;
+    let settings = &blob[settings_start..settings_start + SETTINGS_LEN];
+
+    // Setting the default layer saves it by `try_send`, so it's applied before the storage channel is filled by keys
+    if settings[0] != keymap.borrow().get_default_layer() {
+        keymap.borrow_mut().set_default_layer(settings[0]);
+    }
+
+    let mut data = &blob[HEADER_LEN..settings_start];
+    for layer in 0..NUM_LAYER {
+        for row in 0..ROW {
+            for col in 0..COL {
+                let action = take_action(&mut data);
+                if keymap.borrow().action_on_layer(layer, row, col) == Some(action) {
+                    continue;
+                }
+                keymap.borrow_mut().set_action_at(row, col, layer, action);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::KeymapKey {
+                        layer: layer as u8,
+                        col: col as u8,
+                        row: row as u8,
+                        action,
+                    })
+                    .await;
+            }
+        }
+    }
+
+    for layer in 0..NUM_LAYER {
+        for id in 0..num_encoder {
+            let cw = take_action(&mut data);
+            let ccw = take_action(&mut data);
+            let changed = match keymap
+                .borrow_mut()
+                .encoders
+                .as_mut()
+                .and_then(|encoders| encoders.get_mut(layer, id))
+            {
+                Some(encoder) if *encoder != (cw, ccw) => {
+                    *encoder = (cw, ccw);
+                    true
+                }
+                _ => false,
+            };
+            if changed {
+                bump_generation(ConfigItem::Keymap);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::EncoderKey {
+                        layer: layer as u8,
+                        id: id as u8,
+                        action: (cw, ccw),
+                    })
+                    .await;
+            }
+        }
+    }
+
+    let mut macros = [0; MACRO_SPACE_SIZE];
+    macros.copy_from_slice(take(&mut data, MACRO_SPACE_SIZE));
+    if keymap.borrow().macro_cache != macros {
+        keymap.borrow_mut().macro_cache = macros;
+        bump_generation(ConfigItem::Macros);
+        FLASH_CHANNEL
+            .send(FlashOperationMessage::WriteMacro(macros))
+            .await;
+    }
+
+    for slot in 0..NUM_DYNAMIC_MACRO as u8 {
+        let mut recorded = [0; DYNAMIC_MACRO_SIZE];
+        recorded.copy_from_slice(take(&mut data, DYNAMIC_MACRO_SIZE));
+        if dynamic_macro(slot) != recorded {
+            restore_dynamic_macro(slot, recorded);
+            bump_generation(ConfigItem::Macros);
+            FLASH_CHANNEL
+                .send(FlashOperationMessage::DynamicMacro(slot, recorded))
+                .await;
+        }
+    }
+
+    let nkro = settings[1] != 0;
+    if nkro_enabled() != nkro {
+        restore_nkro(nkro);
+        bump_generation(ConfigItem::Settings);
+        FLASH_CHANNEL.send(FlashOperationMessage::Nkro(nkro)).await;
+    }
+    let palette = settings[2];
+    if rgb_state().palette != palette && (palette as usize) < palettes().len() {
+        restore_palette(palette);
+        bump_generation(ConfigItem::Settings);
+        FLASH_CHANNEL
+            .send(FlashOperationMessage::RgbPalette(palette))
+            .await;
+    }
+    let page = settings[3];
+    if active_display_page().index() != page {
+        restore_display_page(page);
+        bump_generation(ConfigItem::Settings);
+        FLASH_CHANNEL
+            .send(FlashOperationMessage::DisplayPage(page))
+            .await;
+    }
+    let mut zones = [ZoneState::default(); MAX_LED_ZONES];
+    for (zone, buf) in zones.iter_mut().zip(settings[6..].chunks_exact(6)) {
+        *zone = ZoneState {
+            enabled: buf[0] != 0,
+            effect: Effect::from_u8(buf[1]).unwrap_or_default(),
+            color: Hsv::new(buf[2], buf[3], buf[4]),
+            speed: buf[5],
+        };
+    }
+    let rgb = RgbSettings {
+        enabled: settings[4] != 0,
+        num_zones: settings[5].min(MAX_LED_ZONES as u8),
+        zones,
+    };
+    if rgb_settings() != rgb {
+        restore_rgb_settings(rgb);
+        bump_generation(ConfigItem::Settings);
+        FLASH_CHANNEL
+            .send(FlashOperationMessage::RgbSettings(rgb))
+            .await;
+    }
+}
+
+/// A blob being imported
+pub(crate) struct ConfigImport {
+    buffer: Vec<u8, MAX_BLOB_SIZE>,
+    /// Size of the blob announced by the host, 0 if no import is in progress
+    size: usize,
+}
+
+impl ConfigImport {
+    pub(crate) fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            size: 0,
+        }
+    }
+
+    fn begin(&mut self, size: usize) -> bool {
+        self.buffer.clear();
+        self.size = if size <= MAX_BLOB_SIZE { size } else { 0 };
+        self.size > 0
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> bool {
+        if self.size == 0 || offset != self.buffer.len() || offset + data.len() > self.size {
+            return false;
+        }
+        self.buffer.extend_from_slice(data).is_ok()
+    }
+
+    fn abort(&mut self) {
+        self.buffer.clear();
+        self.size = 0;
+    }
+}
+
+pub(crate) async fn process_config_backup<
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+>(
+    report: &mut ViaReport,
+    import: &mut ConfigImport,
+    transaction_active: bool,
+    keymap: &RefCell<KeyMap<'_, ROW, COL, NUM_LAYER>>,
+) {
+    let request = &report.output_data;
+    let data = &mut report.input_data;
+    match ConfigBackupCommand::try_from_primitive(request[1]) {
+        Ok(ConfigBackupCommand::ExportInfo) => {
+            let mut w = BlobWriter::new(0, &mut []);
+            write_config(&mut w, &*keymap.borrow());
+            let (size, crc) = w.finish();
+            BigEndian::write_u32(&mut data[2..6], size as u32);
+            BigEndian::write_u32(&mut data[6..10], crc);
+        }
+        Ok(ConfigBackupCommand::ExportRead) => {
+            let offset = BigEndian::read_u32(&request[2..6]) as usize;
+            let len = (request[6] as usize).min(MAX_CHUNK_LEN);
+            let out = &mut data[7..7 + len];
+            out.fill(0);
+            let mut w = BlobWriter::new(offset, out);
+            write_config(&mut w, &*keymap.borrow());
+            w.finish();
+        }
+        Ok(ConfigBackupCommand::ImportBegin) => {
+            let size = BigEndian::read_u32(&request[2..6]) as usize;
+            info!("Config import begins, blob size: {}", size);
+            data[2] = import.begin(size) as u8;
+        }
+        Ok(ConfigBackupCommand::ImportWrite) => {
+            let offset = BigEndian::read_u32(&request[2..6]) as usize;
+            let len = (request[6] as usize).min(MAX_CHUNK_LEN);
+            data[2] = import.write(offset, &request[7..7 + len]) as u8;
+        }
+        Ok(ConfigBackupCommand::ImportFinish) => {
+            let result = if transaction_active {
+                Err(ImportResult::Busy)
+            } else if import.size == 0 || import.buffer.len() != import.size {
+                Err(ImportResult::Incomplete)
+            } else {
+                check_blob(
+                    &import.buffer,
+                    ROW,
+                    COL,
+                    NUM_LAYER,
+                    num_encoder(&*keymap.borrow()),
+                )
+            };
+            let result = match result {
+                Ok(()) => {
+                    apply_config(&import.buffer, keymap).await;
+                    info!("Config is imported");
+                    ImportResult::Applied
+                }
+                Err(e) => {
+                    warn!("Config import failed: {:?}", e);
+                    e
+                }
+            };
+            // A busy import can be finished again after the transaction ends
+            if result != ImportResult::Busy {
+                import.abort();
+            }
+            data[2] = result as u8;
+        }
+        Ok(ConfigBackupCommand::ImportAbort) => {
+            info!("Config import is aborted");
+            import.abort();
+        }
+        Err(_) => data[0] = ViaCommand::Unhandled as u8,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        action::Action,
+        keycode::{KeyCode, ModifierCombination},
+    };
+
+    fn test_blob(rows: usize, cols: usize, layers: usize) -> [u8; 1024] {
+        let mut blob = [0; 1024];
+        let mut w = BlobWriter::new(0, &mut blob);
+        write_header(&mut w, rows, cols, layers, 0);
+        w.write(&[0; 1024][..blob_size(rows, cols, layers, 0) - HEADER_LEN - CRC_LEN]);
+        w.finish();
+        blob
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(!crc32(0xFFFF_FFFF, b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_check_blob() {
+        let size = blob_size(1, 2, 1, 0);
+        let mut blob = test_blob(1, 2, 1);
+        assert_eq!(check_blob(&blob[..size], 1, 2, 1, 0), Ok(()));
+        assert_eq!(
+            check_blob(&blob[..size], 2, 1, 1, 0),
+            Err(ImportResult::LayoutMismatch)
+        );
+        assert_eq!(
+            check_blob(&blob[..size - 1], 1, 2, 1, 0),
+            Err(ImportResult::InvalidBlob)
+        );
+        blob[HEADER_LEN] ^= 1;
+        assert_eq!(
+            check_blob(&blob[..size], 1, 2, 1, 0),
+            Err(ImportResult::InvalidBlob)
+        );
+
+        // Correct CRC, but an unknown action type
+        let mut blob = [0; 1024];
+        let mut w = BlobWriter::new(0, &mut blob);
+        write_header(&mut w, 1, 2, 1, 0);
+        w.write(&[0xFF; KEY_ACTION_BYTES]);
+        w.write(&[0; 1024][..size - HEADER_LEN - CRC_LEN - KEY_ACTION_BYTES]);
+        w.finish();
+        assert_eq!(
+            check_blob(&blob[..size], 1, 2, 1, 0),
+            Err(ImportResult::InvalidBlob)
+        );
+    }
+
+    #[test]
+    fn test_action_round_trip() {
+        // Actions which are lost by via keycodes
+        let actions = [
+            KeyAction::Single(Action::SoftOff),
+            KeyAction::Unicode('\u{1F600}'),
+            KeyAction::Unicode('\u{8000}'),
+            KeyAction::TapHold(Action::Key(KeyCode::A), Action::LayerOn(2)),
+            KeyAction::TapHold(Action::Key(KeyCode::Space), Action::SoftOff),
+            KeyAction::WithModifier(
+                Action::Key(KeyCode::Kc1),
+                ModifierCombination::new_from(false, false, false, true, false),
+            ),
+        ];
+        let mut buf = [0; 64];
+        let mut w = BlobWriter::new(0, &mut buf);
+        for action in actions {
+            w.write_action(action);
+        }
+        let (size, _) = w.finish();
+        assert_eq!(size, actions.len() * KEY_ACTION_BYTES + CRC_LEN);
+        let mut data = &buf[..actions.len() * KEY_ACTION_BYTES];
+        for action in actions {
+            assert_eq!(take_action(&mut data), action);
+        }
+    }
+
+    #[test]
+    fn test_blob_window() {
+        let blob = test_blob(1, 2, 1);
+        let mut chunk = [0; 5];
+        let mut w = BlobWriter::new(2, &mut chunk);
+        write_header(&mut w, 1, 2, 1, 0);
+        assert_eq!(chunk, blob[2..7]);
+    }
+}
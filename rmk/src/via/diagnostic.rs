@@ -0,0 +1,169 @@
+//! RawHID commands of the diagnostic mode
+//!
+//! Command `0xF6` is an RMK extension which controls the key tester and reads health counters, byte 1 of the request is one of [`DiagnosticCommand`].
+//! Multi-byte values are big endian:
+//!
+//! | Command | Request | Reply |
+//! |---------|---------|-------|
+//! | `0x00` status | | running(byte 2), tested keys(u16), presses(u32) |
+//! | `0x01` start | | |
+//! | `0x02` stop | | |
+//! | `0x03` read events | | number of events n(byte 2), then n events of (row, col, pressed), at most 9 |
+//! | `0x04` tested keys | row(byte 2) | bitmap(u32) of pressed keys of the row, bit `col` is set if the key has been pressed |
+//! | `0x05` bounce stats | start index(byte 2) | number of bouncing keys(byte 2), number of entries n(byte 3), then n entries of (row, col, bounces(u16), worst settle time in us(u16)), at most 4 |
+//! | `0x06` health | 0 for counters since boot, 1 for lifetime totals(byte 2) | uptime in seconds(u32), key presses(u32), reconnects(u32), errors(u32) |
+//! | `0x07` BLE link | 0 for the host link, 1 for the split link(byte 2) | RSSI is valid(byte 2), RSSI(byte 3, i8), averaged RSSI(byte 4, i8), sent(u32), failed(u32), reconnections(u32) |
+//!
+//! Without BLE, `0x07` is replied as unhandled.
+
+use byteorder::{BigEndian, ByteOrder};
+use num_enum::TryFromPrimitive;
+
+use super::protocol::ViaCommand;
+use crate::{
+    diagnostic::{
+        bounce_stats, key_tester_active, key_tester_status, set_key_tester, take_key_event,
+        tested_keys_of_row,
+    },
+    health::health_status,
+    usb::descriptor::ViaReport,
+};
+
+/// Max number of key events in a reply
+const MAX_EVENTS_PER_REPORT: usize = 9;
+
+/// Max number of bounce stats entries in a reply
+const MAX_BOUNCE_STATS_PER_REPORT: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum DiagnosticCommand {
+    Status = 0x00,
+    Start = 0x01,
+    Stop = 0x02,
+    ReadEvents = 0x03,
+    TestedKeys = 0x04,
+    BounceStats = 0x05,
+    Health = 0x06,
+    BleLink = 0x07,
+}
+
+/// Whether the sub-command changes the keyboard state, the key tester suppresses all key reports while it's running
+pub(crate) fn is_write_command(command: u8) -> bool {
+    command == DiagnosticCommand::Start as u8 || command == DiagnosticCommand::Stop as u8
+}
+
+/// Max number of key events in an injection request
+#[cfg(feature = "key_injection")]
+const MAX_INJECTED_EVENTS: usize = 9;
+
+/// Inject key events to the key event channel, as if they're from the matrix.
+///
+/// Byte 1 of the request is the number of events n, followed by n events of (row, col, pressed).
+/// Events out of the matrix are skipped, the reply has the number of injected events in byte 1.
+#[cfg(feature = "key_injection")]
+pub(crate) fn process_key_injection(report: &mut ViaReport, rows: usize, cols: usize) {
+    use crate::{event::KeyEvent, keyboard::KEY_EVENT_CHANNEL};
+
+    let n = (report.output_data[1] as usize).min(MAX_INJECTED_EVENTS);
+    let mut injected = 0;
+    for e in report.output_data[2..2 + n * 3].chunks_exact(3) {
+        if e[0] as usize >= rows || e[1] as usize >= cols {
+            warn!("Injected key ({},{}) is out of the matrix", e[0], e[1]);
+            continue;
+        }
+        let event = KeyEvent {
+            row: e[0],
+            col: e[1],
+            pressed: e[2] != 0,
+        };
+        if KEY_EVENT_CHANNEL.try_send(event).is_err() {
+            warn!("Key event channel is full, injected events are dropped");
+            break;
+        }
+        injected += 1;
+    }
+    debug!("Injected {} key events", injected);
+    report.input_data[1] = injected;
+}
+
+pub(crate) fn process_diagnostic(report: &mut ViaReport) {
+    let data = &mut report.input_data;
+    match DiagnosticCommand::try_from_primitive(report.output_data[1]) {
+        Ok(DiagnosticCommand::Status) => {
+            let status = key_tester_status();
+            data[2] = key_tester_active() as u8;
+            BigEndian::write_u16(&mut data[3..5], status.tested_keys);
+            BigEndian::write_u32(&mut data[5..9], status.presses);
+        }
+        Ok(DiagnosticCommand::Start) => set_key_tester(true),
+        Ok(DiagnosticCommand::Stop) => set_key_tester(false),
+        Ok(DiagnosticCommand::ReadEvents) => {
+            let mut n = 0;
+            while n < MAX_EVENTS_PER_REPORT {
+                let Some(event) = take_key_event() else {
+                    break;
+                };
+                let idx = 3 + n * 3;
+                data[idx] = event.row;
+                data[idx + 1] = event.col;
+                data[idx + 2] = event.pressed as u8;
+                n += 1;
+            }
+            data[2] = n as u8;
+        }
+        Ok(DiagnosticCommand::TestedKeys) => {
+            let bitmap = tested_keys_of_row(report.output_data[2]);
+            BigEndian::write_u32(&mut data[3..7], bitmap);
+        }
+        Ok(DiagnosticCommand::BounceStats) => {
+            let stats = bounce_stats();
+            let start = report.output_data[2] as usize;
+            data[2] = stats.len() as u8;
+            let mut n = 0;
+            for s in stats.iter().skip(start).take(MAX_BOUNCE_STATS_PER_REPORT) {
+                let idx = 4 + n * 6;
+                data[idx] = s.row;
+                data[idx + 1] = s.col;
+                BigEndian::write_u16(&mut data[idx + 2..idx + 4], s.bounces);
+                BigEndian::write_u16(&mut data[idx + 4..idx + 6], s.worst_settle_us);
+                n += 1;
+            }
+            data[3] = n as u8;
+        }
+        Ok(DiagnosticCommand::Health) => {
+            let status = health_status();
+            let counters = if report.output_data[2] == 0 {
+                status.since_boot
+            } else {
+                status.lifetime
+            };
+            BigEndian::write_u32(&mut data[2..6], counters.uptime_secs);
+            BigEndian::write_u32(&mut data[6..10], counters.key_presses);
+            BigEndian::write_u32(&mut data[10..14], counters.reconnects);
+            BigEndian::write_u32(&mut data[14..18], counters.errors);
+        }
+        #[cfg(feature = "_ble")]
+        Ok(DiagnosticCommand::BleLink) => {
+            use crate::ble::diagnostics::{link_diagnostics, BleLink};
+            let link = if report.output_data[2] == 0 {
+                BleLink::Host
+            } else {
+                BleLink::Split
+            };
+            let diagnostics = link_diagnostics(link);
+            data[2] = diagnostics.rssi.is_some() as u8;
+            data[3] = diagnostics.rssi.unwrap_or(0) as u8;
+            data[4] = diagnostics.average_rssi.unwrap_or(0) as u8;
+            BigEndian::write_u32(&mut data[5..9], diagnostics.sent);
+            BigEndian::write_u32(&mut data[9..13], diagnostics.failed);
+            BigEndian::write_u32(&mut data[13..17], diagnostics.reconnections);
+        }
+        #[cfg(not(feature = "_ble"))]
+        Ok(DiagnosticCommand::BleLink) => data[0] = ViaCommand::Unhandled as u8,
+        Err(e) => {
+            warn!("Invalid diagnostic command: {}", e.number);
+            data[0] = ViaCommand::Unhandled as u8;
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! RawHID commands of the firmware update
+//!
+//! Command `0xF9` is an RMK extension which writes a new firmware image to the DFU slot, see [`crate::firmware_update`].
+//! Byte 1 of the request is one of [`FirmwareUpdateCommand`], multi-byte values are big endian:
+//!
+//! | Command | Request | Reply |
+//! |---------|---------|-------|
+//! | `0x00` status | | state(byte 2), image size(u32), received bytes(u32) |
+//! | `0x01` begin | image size(u32) | accepted(byte 2) |
+//! | `0x02` write | offset(u32), length n(byte 6), then n bytes of the image, at most 24 | accepted(byte 2) |
+//! | `0x03` finish | | accepted(byte 2) |
+//! | `0x04` abort | | accepted(byte 2) |
+//! | `0x05` signature | offset(byte 2), length n(byte 3), then n bytes of the signature, at most 24 | accepted(byte 2) |
+//!
+//! The signature is only accepted with the `signed_firmware_update` feature, it should be sent before finish.
+//! Requests are queued and written to the flash in the background. A request isn't accepted if the queue is full,
+//! the host should check the received bytes by status and resend the request. Resent data before the received bytes is ignored.
+
+use byteorder::{BigEndian, ByteOrder};
+use heapless::Vec;
+use num_enum::TryFromPrimitive;
+
+use super::protocol::ViaCommand;
+use crate::{
+    firmware_update::{
+        firmware_update_status, FirmwareUpdateMessage, FIRMWARE_UPDATE_CHANNEL, MAX_CHUNK_LEN,
+    },
+    usb::descriptor::ViaReport,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum FirmwareUpdateCommand {
+    Status = 0x00,
+    Begin = 0x01,
+    Write = 0x02,
+    Finish = 0x03,
+    Abort = 0x04,
+    Signature = 0x05,
+}
+
+pub(crate) fn process_firmware_update(report: &mut ViaReport) {
+    let request = &report.output_data;
+    let message = match FirmwareUpdateCommand::try_from_primitive(request[1]) {
+        Ok(FirmwareUpdateCommand::Status) => {
+            let status = firmware_update_status();
+            let data = &mut report.input_data;
+            data[2] = status.state as u8;
+            BigEndian::write_u32(&mut data[3..7], status.image_size);
+            BigEndian::write_u32(&mut data[7..11], status.received);
+            return;
+        }
+        Ok(FirmwareUpdateCommand::Begin) => {
+            FirmwareUpdateMessage::Begin(BigEndian::read_u32(&request[2..6]))
+        }
+        Ok(FirmwareUpdateCommand::Write) => {
+            let offset = BigEndian::read_u32(&request[2..6]);
+            let len = (request[6] as usize).min(MAX_CHUNK_LEN);
+            let chunk = Vec::from_slice(&request[7..7 + len]).unwrap_or_default();
+            FirmwareUpdateMessage::Write(offset, chunk)
+        }
+        Ok(FirmwareUpdateCommand::Finish) => FirmwareUpdateMessage::Finish,
+        Ok(FirmwareUpdateCommand::Abort) => FirmwareUpdateMessage::Abort,
+        #[cfg(feature = "signed_firmware_update")]
+        Ok(FirmwareUpdateCommand::Signature) => {
+            let len = (request[3] as usize).min(MAX_CHUNK_LEN);
+            let chunk = Vec::from_slice(&request[4..4 + len]).unwrap_or_default();
+            FirmwareUpdateMessage::Signature(request[2], chunk)
+        }
+        #[cfg(not(feature = "signed_firmware_update"))]
+        Ok(FirmwareUpdateCommand::Signature) => {
+            report.input_data[2] = 0;
+            return;
+        }
+        Err(e) => {
+            warn!("Invalid firmware update command: {}", e.number);
+            report.input_data[0] = ViaCommand::Unhandled as u8;
+            return;
+        }
+    };
+    report.input_data[2] = FIRMWARE_UPDATE_CHANNEL.try_send(message).is_ok() as u8;
+}
@@ -0,0 +1,133 @@
+//! Keyboard identity and capability discovery
+//!
+//! Command `0xF5` is an RMK extension which reports what the firmware supports, so that host tools can adapt their UI.
+//! Byte 1 of the request is one of [`RmkInfo`], the reply is written from byte 2, in big endian like via:
+//!
+//! | Info | Reply |
+//! |------|-------|
+//! | `0x00` firmware version | major, minor, patch of RMK, via protocol version(u16) |
+//! | `0x01` matrix | rows, cols, layers |
+//! | `0x02` features | bitmap(u32) of [`RmkFeature`] |
+//! | `0x03` storage | storage size in bytes(u32, 0 if storage is not used), macro space size(u16), number of macros, max keys of a config transaction(u16) |
+//! | `0x04` generations | epoch(u32), generations(u32) of the keymap, macros and settings, see [`crate::generation`] |
+
+use core::sync::atomic::Ordering;
+
+use byteorder::{BigEndian, ByteOrder};
+use num_enum::TryFromPrimitive;
+
+use super::{
+    protocol::{ViaCommand, VIA_PROTOCOL_VERSION},
+    transaction::max_staged_layers,
+};
+use crate::{
+    generation::config_generations,
+    keyboard_macro::{MACRO_SPACE_SIZE, NUM_MACRO},
+    safe_mode::safe_mode_active,
+    storage::STORAGE_SIZE,
+    usb::descriptor::ViaReport,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum RmkInfo {
+    FirmwareVersion = 0x00,
+    Matrix = 0x01,
+    Features = 0x02,
+    Storage = 0x03,
+    Generations = 0x04,
+}
+
+/// Bits of the feature bitmap
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum RmkFeature {
+    Split = 0,
+    Ble = 1,
+    Usb = 2,
+    Col2Row = 3,
+    AsyncMatrix = 4,
+    RapidDebouncer = 5,
+    LowRam = 6,
+    HostAuth = 7,
+    Storage = 8,
+    KeyInjection = 9,
+    /// The keyboard is booted into safe mode, the keymap in storage is ignored
+    SafeMode = 10,
+    FirmwareUpdate = 11,
+    /// Images should be signed, see `0xF9 0x05`
+    SignedFirmwareUpdate = 12,
+    /// Config can be exported and imported by `0xFA`
+    ConfigBackup = 13,
+}
+
+fn enabled_features() -> u32 {
+    [
+        (RmkFeature::Split, cfg!(feature = "split")),
+        (RmkFeature::Ble, cfg!(feature = "_ble")),
+        (RmkFeature::Usb, cfg!(not(feature = "_no_usb"))),
+        (RmkFeature::Col2Row, cfg!(feature = "col2row")),
+        (RmkFeature::AsyncMatrix, cfg!(feature = "async_matrix")),
+        (
+            RmkFeature::RapidDebouncer,
+            cfg!(feature = "rapid_debouncer"),
+        ),
+        (RmkFeature::LowRam, cfg!(feature = "low_ram")),
+        (RmkFeature::HostAuth, cfg!(feature = "host_auth")),
+        (RmkFeature::KeyInjection, cfg!(feature = "key_injection")),
+        (
+            RmkFeature::FirmwareUpdate,
+            cfg!(feature = "firmware_update"),
+        ),
+        (
+            RmkFeature::SignedFirmwareUpdate,
+            cfg!(feature = "signed_firmware_update"),
+        ),
+        (RmkFeature::ConfigBackup, cfg!(feature = "config_backup")),
+        (
+            RmkFeature::Storage,
+            STORAGE_SIZE.load(Ordering::Relaxed) > 0,
+        ),
+        (RmkFeature::SafeMode, safe_mode_active()),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .fold(0, |bits, (feature, _)| bits | 1 << *feature as u8)
+}
+
+/// Write the requested info to the report
+pub(crate) fn process_info(report: &mut ViaReport, rows: usize, cols: usize, layers: usize) {
+    let data = &mut report.input_data;
+    match RmkInfo::try_from_primitive(report.output_data[1]) {
+        Ok(RmkInfo::FirmwareVersion) => {
+            data[2] = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+            data[3] = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+            data[4] = env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0);
+            BigEndian::write_u16(&mut data[5..7], VIA_PROTOCOL_VERSION);
+        }
+        Ok(RmkInfo::Matrix) => {
+            data[2] = rows as u8;
+            data[3] = cols as u8;
+            data[4] = layers as u8;
+        }
+        Ok(RmkInfo::Features) => BigEndian::write_u32(&mut data[2..6], enabled_features()),
+        Ok(RmkInfo::Storage) => {
+            BigEndian::write_u32(&mut data[2..6], STORAGE_SIZE.load(Ordering::Relaxed));
+            BigEndian::write_u16(&mut data[6..8], MACRO_SPACE_SIZE as u16);
+            data[8] = NUM_MACRO as u8;
+            let max_keys = max_staged_layers(layers) * rows * cols;
+            BigEndian::write_u16(&mut data[9..11], max_keys.min(u16::MAX as usize) as u16);
+        }
+        Ok(RmkInfo::Generations) => {
+            let generations = config_generations();
+            BigEndian::write_u32(&mut data[2..6], generations.epoch);
+            BigEndian::write_u32(&mut data[6..10], generations.keymap);
+            BigEndian::write_u32(&mut data[10..14], generations.macros);
+            BigEndian::write_u32(&mut data[14..18], generations.settings);
+        }
+        Err(e) => {
+            warn!("Invalid info: {}", e.number);
+            data[0] = ViaCommand::Unhandled as u8;
+        }
+    }
+}
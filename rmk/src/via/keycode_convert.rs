@@ -14,7 +14,11 @@ pub(crate) fn to_via_keycode(key_action: KeyAction) -> u16 {
                 if k.is_macro() {
                     k as u16 & 0xFF | 0x7700
                 } else if k.is_user() {
-                    k as u16 & 0xF | 0x7E00
+                    k as u16 & 0x1F | 0x7E00
+                } else if k.is_combo() || k.is_dynamic_macro() || k.is_unicode_mode() {
+                    k as u16 & 0xFF | 0x7C00
+                } else if k.is_magic() {
+                    k as u16 & 0xFF | 0x7000
                 } else {
                     k as u16
                 }
@@ -23,6 +27,9 @@ pub(crate) fn to_via_keycode(key_action: KeyAction) -> u16 {
             Action::LayerOn(l) => 0x5220 | l as u16,
             Action::DefaultLayer(l) => 0x5240 | l as u16,
             Action::LayerToggle(l) => 0x5260 | l as u16,
+            Action::LayerTapToggle(l) => 0x52C0 | l as u16,
+            // QK_USER_0, the first keycode of QMK's user range
+            Action::SoftOff => 0x7E40,
             _ => 0x0000,
         },
         KeyAction::Tap(_) => {
@@ -78,6 +85,13 @@ pub(crate) fn to_via_keycode(key_action: KeyAction) -> u16 {
             );
             0
         }
+        KeyAction::TapDance(index) => 0x5700 | index as u16,
+        // QK_UNICODE, which has 15 bits of the code point
+        KeyAction::Unicode(c) if (c as u32) <= 0x7FFF => 0x8000 | c as u16,
+        KeyAction::Unicode(c) => {
+            warn!("Unicode {} is out of the range of via keycodes", c);
+            0
+        }
     }
 }
 
@@ -138,14 +152,18 @@ pub(crate) fn from_via_keycode(via_keycode: u16) -> KeyAction {
             KeyAction::OneShot(Action::Modifier(m))
         }
         0x52C0..=0x52DF => {
-            // TODO: Layer tap toggle
-            warn!("Layer tap toggle {:#X} not supported", via_keycode);
-            KeyAction::No
+            // Layer tap toggle
+            let layer = via_keycode as u8 & 0x1F;
+            KeyAction::Single(Action::LayerTapToggle(layer))
         }
         0x5700..=0x57FF => {
-            // TODO: Tap dance
-            warn!("Tap dance {:#X} not supported", via_keycode);
-            KeyAction::No
+            // Tap dance, the actions are defined in the firmware, see `TapDanceConfig`
+            KeyAction::TapDance(via_keycode as u8)
+        }
+        0x7011..=0x7013 => {
+            // NK_ON, NK_OFF and NK_TOGG
+            let keycode = via_keycode & 0xFF | 0x100;
+            KeyAction::Single(Action::Key(KeyCode::from_primitive(keycode)))
         }
         0x7000..=0x701F => {
             // TODO: QMK functions, such as swap ctrl/caps, gui on, haptic, music, clicky, combo, RGB, etc
@@ -161,21 +179,42 @@ pub(crate) fn from_via_keycode(via_keycode: u16) -> KeyAction {
             warn!("Backlight and RGB configuration key not supported");
             KeyAction::No
         }
+        0x7C30..=0x7C37 => {
+            // QK_UNICODE_MODE_*
+            let keycode = via_keycode & 0xFF | 0x700;
+            KeyAction::Single(Action::Key(KeyCode::from_primitive(keycode)))
+        }
+        0x7C50..=0x7C57 => {
+            // QK_COMBO_ON, QK_COMBO_OFF, QK_COMBO_TOGGLE and QK_DYNAMIC_MACRO_*
+            let keycode = via_keycode & 0xFF | 0x700;
+            KeyAction::Single(Action::Key(KeyCode::from_primitive(keycode)))
+        }
         0x7C00..=0x7C5F => {
-            // TODO: Reset/GESC/Space Cadet/Haptic/Auto shift(AS)/Dynamic macro
+            // TODO: Reset/GESC/Space Cadet/Haptic/Auto shift(AS)
             // - [GESC](https://docs.qmk.fm/#/feature_grave_esc)
             // - [Space Cadet](https://docs.qmk.fm/#/feature_space_cadet)
             warn!(
-                "Reset/GESC/Space Cadet/Haptic/Auto shift(AS)/Dynamic macro not supported: {:#X}",
+                "Reset/GESC/Space Cadet/Haptic/Auto shift(AS) not supported: {:#X}",
                 via_keycode
             );
             KeyAction::No
         }
-        0x7E00..=0x7E0F => {
+        0x7E00..=0x7E1F => {
             // QK_KB_N, aka UserN
             let keycode = via_keycode & 0xFF | 0x840;
             KeyAction::Single(Action::Key(KeyCode::from_primitive(keycode)))
         }
+        0x7E40 => {
+            // QK_USER_0, used as the soft off key
+            KeyAction::Single(Action::SoftOff)
+        }
+        0x8000..=0xFFFF => {
+            // QK_UNICODE
+            match char::from_u32(via_keycode as u32 & 0x7FFF) {
+                Some(c) => KeyAction::Unicode(c),
+                None => KeyAction::No,
+            }
+        }
         _ => {
             warn!("Via keycode {:#X} is not processed", via_keycode);
             KeyAction::No
@@ -319,6 +358,42 @@ mod test {
             ),
             from_via_keycode(via_keycode)
         );
+
+        // QK_COMBO_TOGGLE
+        let via_keycode = 0x7C52;
+        assert_eq!(
+            KeyAction::Single(Action::Key(KeyCode::ComboToggle)),
+            from_via_keycode(via_keycode)
+        );
+
+        // UC(0xE9)
+        let via_keycode = 0x80E9;
+        assert_eq!(KeyAction::Unicode('é'), from_via_keycode(via_keycode));
+
+        // QK_DYNAMIC_MACRO_PLAY_2
+        let via_keycode = 0x7C57;
+        assert_eq!(
+            KeyAction::Single(Action::Key(KeyCode::DynamicMacroPlay2)),
+            from_via_keycode(via_keycode)
+        );
+
+        // TT(2)
+        let via_keycode = 0x52C2;
+        assert_eq!(
+            KeyAction::Single(Action::LayerTapToggle(2)),
+            from_via_keycode(via_keycode)
+        );
+
+        // NK_TOGG
+        let via_keycode = 0x7013;
+        assert_eq!(
+            KeyAction::Single(Action::Key(KeyCode::MagicToggleNkro)),
+            from_via_keycode(via_keycode)
+        );
+
+        // TD(3)
+        let via_keycode = 0x5703;
+        assert_eq!(KeyAction::TapDance(3), from_via_keycode(via_keycode));
     }
 
     #[test]
@@ -408,5 +483,35 @@ mod test {
             ModifierCombination::new_from(false, false, true, true, true),
         );
         assert_eq!(0x2704, to_via_keycode(a));
+
+        // QK_COMBO_ON
+        let a = KeyAction::Single(Action::Key(KeyCode::ComboOn));
+        assert_eq!(0x7C50, to_via_keycode(a));
+
+        // QK_DYNAMIC_MACRO_RECORD_START_1
+        let a = KeyAction::Single(Action::Key(KeyCode::DynamicMacroRecordStart1));
+        assert_eq!(0x7C53, to_via_keycode(a));
+
+        // NK_ON
+        let a = KeyAction::Single(Action::Key(KeyCode::MagicNkroOn));
+        assert_eq!(0x7011, to_via_keycode(a));
+
+        // TD(3)
+        let a = KeyAction::TapDance(3);
+        assert_eq!(0x5703, to_via_keycode(a));
+
+        // QK_USER_0 -> SoftOff
+        let a = KeyAction::Single(Action::SoftOff);
+        assert_eq!(0x7E40, to_via_keycode(a));
+    }
+
+    #[test]
+    fn test_soft_off_round_trip() {
+        let a = KeyAction::Single(Action::SoftOff);
+        assert_eq!(a, from_via_keycode(to_via_keycode(a)));
+        // User31 is right below the soft off key
+        let a = KeyAction::Single(Action::Key(KeyCode::User31));
+        assert_eq!(0x7E1F, to_via_keycode(a));
+        assert_eq!(a, from_via_keycode(to_via_keycode(a)));
     }
 }
@@ -0,0 +1,300 @@
+//! RawHID commands of the keymap sync
+//!
+//! Command `0xFB` is an RMK extension for live editing in host tools, especially over BLE, where every request is slow.
+//! The keymap has a [generation](crate::generation), which is increased on every change of keys and encoders and starts from 0 at boot.
+//! A host tool remembers the generation of its copy of the keymap, then it writes several keys in one request,
+//! and reads only the keys changed by others since then. Byte 1 of the request is one of [`KeymapSyncCommand`],
+//! multi-byte values are big endian:
+//!
+//! | Command | Request | Reply |
+//! |---------|---------|-------|
+//! | `0x00` generation | | generation(u32) |
+//! | `0x01` write | base generation(u32), number of keys n(byte 6), then n keys, at most 5 | result(byte 2), generation(u32) |
+//! | `0x02` changes | generation(u32) | result(byte 2), generation(u32), number of keys n(byte 7), then n keys, at most 4 |
+//!
+//! Each key is 5 bytes: layer, row, col and via keycode(u16).
+//! Keys are written only if the base generation is the current generation, so changes by others are never overwritten.
+//! Changed keys are replied with their current keycodes, if the result is [`SyncResult::More`], the host should request again
+//! with the replied generation.
+
+use core::cell::RefCell;
+
+use byteorder::{BigEndian, ByteOrder};
+use heapless::Vec;
+use num_enum::TryFromPrimitive;
+
+use super::{
+    keycode_convert::{from_via_keycode, to_via_keycode},
+    protocol::ViaCommand,
+};
+use crate::{
+    action::KeyAction,
+    keymap::KeyMap,
+    storage::{FlashOperationMessage, FLASH_CHANNEL},
+    usb::descriptor::ViaReport,
+};
+
+/// Bytes of a key: layer, row, col and keycode(u16)
+const KEY_LEN: usize = 5;
+
+/// Max keys written in a request
+const MAX_WRITE_KEYS: usize = 5;
+
+/// Max changed keys replied in a request
+const MAX_CHANGED_KEYS: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum KeymapSyncCommand {
+    Generation = 0x00,
+    Write = 0x01,
+    Changes = 0x02,
+}
+
+/// Whether the sub-command changes the keymap
+pub(crate) fn is_write_command(command: u8) -> bool {
+    command == KeymapSyncCommand::Write as u8
+}
+
+/// Result of a keymap sync request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub(crate) enum SyncResult {
+    Ok = 0,
+    /// More changed keys than fit in a reply
+    More = 1,
+    /// The keymap is changed since the base generation of a write, or changes since the generation are dropped from the log.
+    /// The host should read the whole keymap
+    Stale = 2,
+    /// A key is out of the keymap
+    Invalid = 3,
+    /// A config transaction is active
+    Busy = 4,
+}
+
+pub(crate) async fn process_keymap_sync<
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+>(
+    report: &mut ViaReport,
+    transaction_active: bool,
+    keymap: &RefCell<KeyMap<'_, ROW, COL, NUM_LAYER>>,
+) {
+    let request = &report.output_data;
+    let data = &mut report.input_data;
+    match KeymapSyncCommand::try_from_primitive(request[1]) {
+        Ok(KeymapSyncCommand::Generation) => {
+            BigEndian::write_u32(&mut data[2..6], keymap.borrow().generation());
+        }
+        Ok(KeymapSyncCommand::Write) => {
+            let base = BigEndian::read_u32(&request[2..6]);
+            let count = (request[6] as usize).min(MAX_WRITE_KEYS);
+            let result = if transaction_active {
+                SyncResult::Busy
+            } else if base != keymap.borrow().generation() {
+                SyncResult::Stale
+            } else {
+                let keys: Vec<&[u8], MAX_WRITE_KEYS> = request[7..7 + count * KEY_LEN]
+                    .chunks_exact(KEY_LEN)
+                    .collect();
+                if keys.iter().any(|key| {
+                    keymap
+                        .borrow()
+                        .action_on_layer(key[0] as usize, key[1] as usize, key[2] as usize)
+                        .is_none()
+                }) {
+                    SyncResult::Invalid
+                } else {
+                    for key in keys {
+                        let action = from_via_keycode(BigEndian::read_u16(&key[3..5]));
+                        keymap.borrow_mut().set_action_at(
+                            key[1] as usize,
+                            key[2] as usize,
+                            key[0] as usize,
+                            action,
+                        );
+                        FLASH_CHANNEL
+                            .send(FlashOperationMessage::KeymapKey {
+                                layer: key[0],
+                                col: key[2],
+                                row: key[1],
+                                action,
+                            })
+                            .await;
+                    }
+                    SyncResult::Ok
+                }
+            };
+            if result != SyncResult::Ok {
+                warn!("Keymap sync write is rejected: {:?}", result);
+            }
+            data[2] = result as u8;
+            BigEndian::write_u32(&mut data[3..7], keymap.borrow().generation());
+        }
+        Ok(KeymapSyncCommand::Changes) => {
+            let since = BigEndian::read_u32(&request[2..6]);
+            let keymap = keymap.borrow();
+            let (result, generation, count) = match keymap.key_changes_since(since) {
+                None => (SyncResult::Stale, keymap.generation(), 0),
+                Some(mut changes) => {
+                    let mut count = 0;
+                    let mut generation = keymap.generation();
+                    // Buffers go first, so that no change is taken after the reply is full
+                    for (buf, change) in data[8..8 + MAX_CHANGED_KEYS * KEY_LEN]
+                        .chunks_exact_mut(KEY_LEN)
+                        .zip(changes.by_ref())
+                    {
+                        let action = keymap
+                            .action_on_layer(
+                                change.layer as usize,
+                                change.row as usize,
+                                change.col as usize,
+                            )
+                            .unwrap_or(KeyAction::No);
+                        buf[..3].copy_from_slice(&[change.layer, change.row, change.col]);
+                        BigEndian::write_u16(&mut buf[3..5], to_via_keycode(action));
+                        generation = change.generation;
+                        count += 1;
+                    }
+                    if changes.next().is_some() {
+                        (SyncResult::More, generation, count)
+                    } else {
+                        (SyncResult::Ok, keymap.generation(), count)
+                    }
+                }
+            };
+            data[2] = result as u8;
+            BigEndian::write_u32(&mut data[3..7], generation);
+            data[7] = count;
+        }
+        Err(_) => data[0] = ViaCommand::Unhandled as u8,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::{action::Action, keycode::KeyCode};
+
+    const A: KeyAction = KeyAction::Single(Action::Key(KeyCode::A));
+    const B: KeyAction = KeyAction::Single(Action::Key(KeyCode::B));
+
+    fn request<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+        keymap: &RefCell<KeyMap<'_, ROW, COL, NUM_LAYER>>,
+        transaction_active: bool,
+        bytes: &[u8],
+    ) -> [u8; 32] {
+        let mut report = ViaReport {
+            input_data: [0; 32],
+            output_data: [0; 32],
+        };
+        report.output_data[0] = ViaCommand::KeymapSync as u8;
+        report.output_data[1..1 + bytes.len()].copy_from_slice(bytes);
+        block_on(process_keymap_sync(&mut report, transaction_active, keymap));
+        report.input_data
+    }
+
+    fn changes_request(since: u32) -> [u8; 5] {
+        let mut bytes = [KeymapSyncCommand::Changes as u8, 0, 0, 0, 0];
+        BigEndian::write_u32(&mut bytes[1..5], since);
+        bytes
+    }
+
+    // Generations are shared by all keymaps, only the generations of recorded changes are compared
+    #[test]
+    fn test_changes_paging() {
+        let mut layers = [[[A; 2]; 2]; 2];
+        let keymap = RefCell::new(block_on(KeyMap::new(&mut layers, None, &[], &[])));
+        let base = keymap.borrow().generation();
+        let keys = [
+            (0, 0, 0),
+            (0, 0, 1),
+            (0, 1, 0),
+            (0, 1, 1),
+            (1, 0, 0),
+            (1, 1, 1),
+        ];
+        for (layer, row, col) in keys {
+            keymap.borrow_mut().set_action_at(row, col, layer, B);
+        }
+        let changes: heapless::Vec<KeyChange, 8> = keymap
+            .borrow()
+            .key_changes_since(base)
+            .unwrap()
+            .copied()
+            .collect();
+
+        // The first page is full, the host should request again from the replied generation
+        let reply = request(&keymap, false, &changes_request(base));
+        assert_eq!(reply[2], SyncResult::More as u8);
+        assert_eq!(BigEndian::read_u32(&reply[3..7]), changes[3].generation);
+        assert_eq!(reply[7] as usize, MAX_CHANGED_KEYS);
+        for (i, (layer, row, col)) in keys[..MAX_CHANGED_KEYS].iter().enumerate() {
+            let key = &reply[8 + i * KEY_LEN..8 + (i + 1) * KEY_LEN];
+            assert_eq!(&key[..3], &[*layer as u8, *row as u8, *col as u8]);
+            assert_eq!(BigEndian::read_u16(&key[3..5]), to_via_keycode(B));
+        }
+
+        // The rest
+        let reply = request(&keymap, false, &changes_request(changes[3].generation));
+        assert_eq!(reply[2], SyncResult::Ok as u8);
+        assert!(BigEndian::read_u32(&reply[3..7]) >= changes[5].generation);
+        assert_eq!(reply[7], 2);
+        assert_eq!(&reply[8..11], &[1, 0, 0]);
+        assert_eq!(&reply[13..16], &[1, 1, 1]);
+
+        // Up to date
+        let reply = request(&keymap, false, &changes_request(changes[5].generation));
+        assert_eq!(reply[2], SyncResult::Ok as u8);
+        assert_eq!(reply[7], 0);
+    }
+
+    #[test]
+    fn test_changes_resync() {
+        let mut layers = [[[A; 2]; 2]; 1];
+        let keymap = RefCell::new(block_on(KeyMap::new(&mut layers, None, &[], &[])));
+        keymap.borrow_mut().set_action_at(0, 0, 0, B);
+        let first = keymap
+            .borrow()
+            .key_changes_since(0)
+            .unwrap()
+            .next()
+            .copied();
+        let first = first.unwrap().generation;
+        // Overflow the change log
+        for i in 0..crate::ram_profile::KEY_CHANGE_LOG_SIZE {
+            keymap
+                .borrow_mut()
+                .set_action_at(0, 1, 0, if i % 2 == 0 { B } else { A });
+        }
+        let reply = request(&keymap, false, &changes_request(first - 1));
+        assert_eq!(reply[2], SyncResult::Stale as u8);
+        assert_eq!(reply[7], 0);
+        // A generation from the future, e.g. of the previous boot
+        let reply = request(&keymap, false, &changes_request(u32::MAX));
+        assert_eq!(reply[2], SyncResult::Stale as u8);
+    }
+
+    #[test]
+    fn test_rejected_writes() {
+        let mut layers = [[[A; 2]; 2]; 1];
+        let keymap = RefCell::new(block_on(KeyMap::new(&mut layers, None, &[], &[])));
+        let generation = keymap.borrow().generation();
+        let mut write = [KeymapSyncCommand::Write as u8, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0];
+        BigEndian::write_u16(&mut write[9..11], to_via_keycode(B));
+
+        // A config transaction is active
+        BigEndian::write_u32(&mut write[1..5], generation);
+        let reply = request(&keymap, true, &write);
+        assert_eq!(reply[2], SyncResult::Busy as u8);
+        // The base generation is stale
+        BigEndian::write_u32(&mut write[1..5], generation.wrapping_sub(1));
+        let reply = request(&keymap, false, &write);
+        assert_eq!(reply[2], SyncResult::Stale as u8);
+        assert_eq!(keymap.borrow().action_on_layer(0, 0, 0), Some(A));
+    }
+}
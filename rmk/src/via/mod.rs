@@ -2,9 +2,20 @@ use self::process::VialService;
 use crate::hid::HidReaderWriterWrapper;
 use embassy_time::Timer;
 
+mod app_context;
+#[cfg(feature = "host_auth")]
+pub(crate) mod auth;
+#[cfg(feature = "config_backup")]
+mod backup;
+mod diagnostic;
+#[cfg(feature = "firmware_update")]
+mod firmware_update;
+mod info;
 pub(crate) mod keycode_convert;
+mod keymap_sync;
 pub(crate) mod process;
 mod protocol;
+pub(crate) mod transaction;
 mod vial;
 
 pub(crate) async fn vial_task<
@@ -1,14 +1,36 @@
-use super::{protocol::*, vial::process_vial};
+#[cfg(feature = "host_auth")]
+use super::auth::{AuthSecret, HostAuth, CHALLENGE_LEN};
+#[cfg(feature = "config_backup")]
+use super::backup::{process_config_backup, ConfigImport};
+#[cfg(feature = "key_injection")]
+use super::diagnostic::process_key_injection;
+#[cfg(feature = "firmware_update")]
+use super::firmware_update::process_firmware_update;
+use super::{
+    app_context::{self, process_app_context},
+    diagnostic::{self, process_diagnostic},
+    info::process_info,
+    keymap_sync::{self, process_keymap_sync},
+    protocol::*,
+    transaction::{ConfigTransaction, StagedKey, WriteRateLimiter},
+    vial::{is_vial_write_command, process_vial},
+};
 use crate::config::VialConfig;
+use crate::generation::{bump_generation, ConfigItem};
 use crate::{
+    action::KeyAction,
     hid::{HidError, HidReaderWriterWrapper},
     keyboard_macro::{MACRO_SPACE_SIZE, NUM_MACRO},
     keymap::KeyMap,
+    rgb::{
+        active_zone_state, rgb_state, set_palette, update_active_zone_state, update_rgb_state,
+        Effect,
+    },
     storage::{FlashOperationMessage, FLASH_CHANNEL},
     usb::descriptor::ViaReport,
     via::keycode_convert::{from_via_keycode, to_via_keycode},
 };
-use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use byteorder::{BigEndian, ByteOrder};
 use core::cell::RefCell;
 use embassy_time::Instant;
 use num_enum::{FromPrimitive, TryFromPrimitive};
@@ -19,6 +41,23 @@ pub(crate) struct VialService<'a, const ROW: usize, const COL: usize, const NUM_
 
     // Vial config
     vial_config: VialConfig<'a>,
+
+    // Host authentication, it's disabled if no auth secret is set
+    #[cfg(feature = "host_auth")]
+    auth: HostAuth<'a>,
+
+    // Keymap and macro writes staged by the host
+    transaction: ConfigTransaction<ROW, COL, NUM_LAYER>,
+
+    // Limits the rate of write commands
+    rate_limiter: WriteRateLimiter,
+
+    // Layer activated by the application focused on the host
+    app_layer: Option<u8>,
+
+    // Config blob being imported by the host
+    #[cfg(feature = "config_backup")]
+    config_import: ConfigImport,
 }
 
 impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
@@ -28,9 +67,20 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
         vial_config: VialConfig<'a>,
     ) -> Self {
+        #[cfg(not(feature = "host_auth"))]
+        if vial_config.auth_secret.is_some() {
+            warn!("`auth_secret` is set but `host_auth` feature is not enabled, host authentication is disabled");
+        }
         Self {
             keymap,
+            #[cfg(feature = "host_auth")]
+            auth: HostAuth::new(vial_config.auth_secret, vial_config.auth_seed),
             vial_config,
+            transaction: ConfigTransaction::new(),
+            rate_limiter: WriteRateLimiter::new(),
+            app_layer: None,
+            #[cfg(feature = "config_backup")]
+            config_import: ConfigImport::new(),
         }
     }
 
@@ -68,7 +118,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
     }
 
     async fn process_via_packet(
-        &self,
+        &mut self,
         report: &mut ViaReport,
         keymap: &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
     ) {
@@ -77,6 +127,23 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         // `report.input_data` is initialized using `report.output_data`
         report.input_data = report.output_data;
         let via_command = ViaCommand::from_primitive(command_id);
+        if !self.authorize(via_command, report.output_data[1]) {
+            warn!(
+                "Host is not authenticated, command 0x{:X} is rejected",
+                command_id
+            );
+            report.input_data[0] = ViaCommand::Unhandled as u8;
+            return;
+        }
+        let is_write = is_write_command(via_command, report.output_data[1]);
+        // An image or a config blob is written in many requests, which don't touch the storage, so they aren't rate limited.
+        // An imported config is saved by awaiting the storage channel, which limits itself
+        if is_write
+            && !via_command.is_firmware_update()
+            && !via_command.is_config_import(report.output_data[1])
+        {
+            self.rate_limiter.take().await;
+        }
         // debug!("Received via command: {}, report: {:02X?}", via_command, report.output_data);
         match via_command {
             ViaCommand::GetProtocolVersion => {
@@ -115,6 +182,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                     Ok(v) => match v {
                         ViaKeyboardInfo::LayoutOptions => {
                             let layout_option = BigEndian::read_u32(&report.output_data[2..6]);
+                            bump_generation(ConfigItem::Settings);
                             FLASH_CHANNEL
                                 .send(FlashOperationMessage::LayoutOptions(layout_option))
                                 .await;
@@ -132,7 +200,10 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                 let layer = report.output_data[1] as usize;
                 let row = report.output_data[2] as usize;
                 let col = report.output_data[3] as usize;
-                let action = keymap.borrow_mut().get_action_at(row, col, layer);
+                let action = keymap
+                    .borrow()
+                    .action_on_layer(layer, row, col)
+                    .unwrap_or(KeyAction::No);
                 let keycode = to_via_keycode(action);
                 info!(
                     "Getting keycode: {:02X} at ({},{}), layer {}",
@@ -150,31 +221,41 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                     "Setting keycode: 0x{:X} at ({},{}), layer {} as {:?}",
                     keycode, row, col, layer, action
                 );
-                keymap.borrow_mut().set_action_at(
-                    row as usize,
-                    col as usize,
-                    layer as usize,
-                    action,
-                );
-                FLASH_CHANNEL
-                    .send(FlashOperationMessage::KeymapKey {
-                        layer,
-                        col,
-                        row,
-                        action,
-                    })
-                    .await;
+                if !self
+                    .write_key(
+                        keymap,
+                        StagedKey {
+                            layer,
+                            row,
+                            col,
+                            action,
+                        },
+                    )
+                    .await
+                {
+                    report.input_data[0] = ViaCommand::Unhandled as u8;
+                }
             }
             ViaCommand::DynamicKeymapReset => {
                 warn!("Dynamic keymap reset -- not supported")
             }
             ViaCommand::CustomSetValue => {
                 // backlight/rgblight/rgb matrix/led matrix/audio settings here
-                warn!("Custom set value -- not supported")
+                match ViaChannel::try_from_primitive(report.output_data[1]) {
+                    Ok(ViaChannel::RgbLight) => {
+                        set_rgb_light_value(report.output_data[2], &report.output_data[3..])
+                    }
+                    _ => warn!("Custom set value -- not supported"),
+                }
             }
             ViaCommand::CustomGetValue => {
                 // backlight/rgblight/rgb matrix/led matrix/audio settings here
-                warn!("Custom get value -- not supported")
+                match ViaChannel::try_from_primitive(report.output_data[1]) {
+                    Ok(ViaChannel::RgbLight) => {
+                        get_rgb_light_value(report.output_data[2], &mut report.input_data[3..])
+                    }
+                    _ => warn!("Custom get value -- not supported"),
+                }
             }
             ViaCommand::CustomSave => {
                 // backlight/rgblight/rgb matrix/led matrix/audio settings here
@@ -220,6 +301,24 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                 // End of current sequence in the macro cache
                 let end = offset + size as u16;
 
+                if size > 28 || end as usize > MACRO_SPACE_SIZE {
+                    report.input_data[0] = 0xFF;
+                    return;
+                }
+
+                if self.transaction.is_active() {
+                    // Stage the macro buffer, it's applied when the transaction is committed
+                    let current = self.keymap.borrow().macro_cache;
+                    let buf = self.transaction.macro_buffer(current);
+                    if offset == 0 {
+                        *buf = [0; MACRO_SPACE_SIZE];
+                    }
+                    buf[offset as usize..end as usize]
+                        .copy_from_slice(&report.output_data[4..4 + size as usize]);
+                    debug!("Staged macro buffer, offset: {}, size: {}", offset, size);
+                    return;
+                }
+
                 // The first sequence, reset the macro cache
                 if offset == 0 {
                     self.keymap.borrow_mut().macro_cache = [0; MACRO_SPACE_SIZE];
@@ -237,6 +336,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                 let num_zero = count_zeros(&self.keymap.borrow_mut().macro_cache[0..end as usize]);
                 if size < 28 || num_zero >= NUM_MACRO {
                     let buf = self.keymap.borrow_mut().macro_cache;
+                    bump_generation(ConfigItem::Macros);
                     FLASH_CHANNEL
                         .send(FlashOperationMessage::WriteMacro(buf))
                         .await;
@@ -271,48 +371,104 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             }
             ViaCommand::DynamicKeymapSetBuffer => {
                 debug!("Dynamic keymap set buffer");
-                let offset = BigEndian::read_u16(&report.output_data[1..3]);
+                // Offset and size are in bytes, each keycode takes 2 bytes
+                let offset = BigEndian::read_u16(&report.output_data[1..3]) as usize;
                 // size <= 28
-                let size = report.output_data[3];
-                let mut idx = 4;
-                let (row_num, col_num, _layer_num) = keymap.borrow().get_keymap_config();
-                keymap
-                    .borrow_mut()
-                    .layers
-                    .iter_mut()
-                    .flatten()
-                    .flatten()
-                    .skip(offset as usize)
-                    .take(size as usize)
-                    .enumerate()
-                    .for_each(|(i, a)| {
-                        let via_keycode = LittleEndian::read_u16(&report.output_data[idx..idx + 2]);
-                        let action: crate::action::KeyAction = from_via_keycode(via_keycode);
-                        *a = action;
-                        idx += 2;
-                        let current_offset = offset as usize + i;
-                        let (row, col, layer) =
-                            get_position_from_offset(current_offset, row_num, col_num);
-                        info!(
-                            "Setting keymap buffer of offset: {}, row,col,layer: {},{},{}",
-                            offset, row, col, layer
-                        );
-                        if let Err(_e) = FLASH_CHANNEL.try_send(FlashOperationMessage::KeymapKey {
-                            layer: layer as u8,
-                            col: col as u8,
-                            row: row as u8,
-                            action,
-                        }) {
-                            error!("Send keymap setting command error")
-                        }
-                    });
+                let size = (report.output_data[3] as usize).min(28);
+                let (row_num, col_num, layer_num) = keymap.borrow().get_keymap_config();
+                for i in 0..size / 2 {
+                    let (row, col, layer) =
+                        get_position_from_offset(offset / 2 + i, row_num, col_num);
+                    if layer >= layer_num {
+                        break;
+                    }
+                    let idx = 4 + i * 2;
+                    let action =
+                        from_via_keycode(BigEndian::read_u16(&report.output_data[idx..idx + 2]));
+                    info!(
+                        "Setting keymap buffer of offset: {}, row,col,layer: {},{},{}",
+                        offset, row, col, layer
+                    );
+                    let key = StagedKey {
+                        layer: layer as u8,
+                        row: row as u8,
+                        col: col as u8,
+                        action,
+                    };
+                    if !self.write_key(keymap, key).await {
+                        report.input_data[0] = ViaCommand::Unhandled as u8;
+                        break;
+                    }
+                }
             }
             ViaCommand::DynamicKeymapGetEncoder => {
-                warn!("Keymap get encoder -- not supported");
+                let layer = report.output_data[1] as usize;
+                let id = report.output_data[2] as usize;
+                let clockwise = report.output_data[3] == 1;
+                let action = keymap
+                    .borrow()
+                    .encoders
+                    .as_ref()
+                    .and_then(|encoders| encoders.get(layer, id))
+                    .map_or(KeyAction::No, |(cw, ccw)| if clockwise { cw } else { ccw });
+                BigEndian::write_u16(&mut report.input_data[4..6], to_via_keycode(action));
             }
             ViaCommand::DynamicKeymapSetEncoder => {
-                warn!("Keymap set encoder -- not supported");
+                let layer = report.output_data[1];
+                let id = report.output_data[2];
+                let clockwise = report.output_data[3] == 1;
+                let action = from_via_keycode(BigEndian::read_u16(&report.output_data[4..6]));
+                info!(
+                    "Setting encoder {} of layer {}, clockwise: {}, action: {:?}",
+                    id, layer, clockwise, action
+                );
+                if !keymap
+                    .borrow_mut()
+                    .set_encoder_action(layer, id, clockwise, action)
+                {
+                    report.input_data[0] = ViaCommand::Unhandled as u8;
+                }
             }
+            #[cfg(feature = "host_auth")]
+            ViaCommand::AuthChallenge | ViaCommand::AuthResponse | ViaCommand::AuthSetSecret => {
+                self.process_auth(via_command, report)
+            }
+            ViaCommand::TransactionBegin => {
+                info!("Config transaction begins");
+                self.transaction.begin();
+            }
+            ViaCommand::TransactionCommit => {
+                report.input_data[1] = self.commit_transaction(keymap).await as u8;
+            }
+            ViaCommand::TransactionAbort => {
+                info!("Config transaction is aborted");
+                self.transaction.abort();
+            }
+            ViaCommand::GetRmkInfo => process_info(report, ROW, COL, NUM_LAYER),
+            ViaCommand::Diagnostic => process_diagnostic(report),
+            #[cfg(feature = "key_injection")]
+            ViaCommand::InjectKeys => process_key_injection(report, ROW, COL),
+            #[cfg(feature = "firmware_update")]
+            ViaCommand::FirmwareUpdate => process_firmware_update(report),
+            #[cfg(feature = "config_backup")]
+            ViaCommand::ConfigBackup => {
+                process_config_backup(
+                    report,
+                    &mut self.config_import,
+                    self.transaction.is_active(),
+                    keymap,
+                )
+                .await
+            }
+            ViaCommand::KeymapSync => {
+                process_keymap_sync(report, self.transaction.is_active(), keymap).await
+            }
+            ViaCommand::AppContext => process_app_context(
+                report,
+                self.vial_config.app_layers,
+                &mut self.app_layer,
+                keymap,
+            ),
             ViaCommand::Vial => process_vial(
                 report,
                 self.vial_config.vial_keyboard_id,
@@ -325,6 +481,193 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             }
         }
     }
+
+    /// Write a key to the keymap and storage, or stage it if there's an active transaction.
+    ///
+    /// Returns false if the key is out of the keymap, or it can't be staged. In the latter case the transaction can't be committed.
+    async fn write_key(
+        &mut self,
+        keymap: &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+        key: StagedKey,
+    ) -> bool {
+        if key.row as usize >= ROW || key.col as usize >= COL || key.layer as usize >= NUM_LAYER {
+            warn!(
+                "Key ({},{}), layer {} is out of the keymap",
+                key.row, key.col, key.layer
+            );
+            return false;
+        }
+        if self.transaction.is_active() {
+            if !self
+                .transaction
+                .stage_key(key, || keymap.borrow().layers[key.layer as usize])
+            {
+                warn!("Too many staged layers, the config transaction will fail");
+                return false;
+            }
+            return true;
+        }
+        keymap.borrow_mut().set_action_at(
+            key.row as usize,
+            key.col as usize,
+            key.layer as usize,
+            key.action,
+        );
+        FLASH_CHANNEL
+            .send(FlashOperationMessage::KeymapKey {
+                layer: key.layer,
+                col: key.col,
+                row: key.row,
+                action: key.action,
+            })
+            .await;
+        true
+    }
+
+    /// Apply all staged writes of the transaction, returns false if there's no transaction to commit.
+    ///
+    /// Staged writes are applied to the keymap all at once, then the changed keys are saved to storage.
+    async fn commit_transaction(
+        &mut self,
+        keymap: &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+    ) -> bool {
+        if !self.transaction.can_commit() {
+            warn!("No valid config transaction to commit");
+            return false;
+        }
+        let (layers, macros) = self.transaction.staged();
+        let macros = macros.copied();
+        info!(
+            "Commit config transaction, {} layers, macros: {}",
+            layers.len(),
+            macros.is_some()
+        );
+        {
+            // Swap the staged layers with the keymap, so the staged layers hold the previous keys afterwards
+            let mut keymap = keymap.borrow_mut();
+            for staged in layers.iter_mut() {
+                let layer = staged.layer as usize;
+                for (row, actions) in staged.actions.iter_mut().enumerate() {
+                    for (col, action) in actions.iter_mut().enumerate() {
+                        let previous = keymap.layers[layer][row][col];
+                        if previous != *action {
+                            keymap.set_action_at(row, col, layer, *action);
+                            *action = previous;
+                        }
+                    }
+                }
+            }
+            if let Some(buf) = macros {
+                keymap.macro_cache = buf;
+            }
+        }
+        // Save only the changed keys
+        for staged in layers.iter() {
+            for (row, actions) in staged.actions.iter().enumerate() {
+                for (col, previous) in actions.iter().enumerate() {
+                    let action = keymap.borrow().layers[staged.layer as usize][row][col];
+                    if action != *previous {
+                        FLASH_CHANNEL
+                            .send(FlashOperationMessage::KeymapKey {
+                                layer: staged.layer,
+                                col: col as u8,
+                                row: row as u8,
+                                action,
+                            })
+                            .await;
+                    }
+                }
+            }
+        }
+        self.transaction.abort();
+        if let Some(buf) = macros {
+            bump_generation(ConfigItem::Macros);
+            FLASH_CHANNEL
+                .send(FlashOperationMessage::WriteMacro(buf))
+                .await;
+        }
+        true
+    }
+
+    /// Check whether the command is allowed, writes require an authenticated host if auth secret is set
+    #[cfg(feature = "host_auth")]
+    fn authorize(&mut self, command: ViaCommand, sub_command: u8) -> bool {
+        authorize_command(&mut self.auth, command, sub_command, Instant::now())
+    }
+
+    #[cfg(not(feature = "host_auth"))]
+    fn authorize(&mut self, _command: ViaCommand, _sub_command: u8) -> bool {
+        true
+    }
+
+    #[cfg(feature = "host_auth")]
+    fn process_auth(&mut self, command: ViaCommand, report: &mut ViaReport) {
+        if command == ViaCommand::AuthSetSecret {
+            // The host is authorized already if a secret is set
+            let len = report.output_data[1] as usize;
+            let saved = match report.output_data.get(2..2 + len).and_then(AuthSecret::new) {
+                None => {
+                    warn!("Invalid length of the host authentication secret: {}", len);
+                    false
+                }
+                Some(secret) => {
+                    if FLASH_CHANNEL
+                        .try_send(FlashOperationMessage::AuthSecret(secret))
+                        .is_ok()
+                    {
+                        info!("Host authentication secret is updated");
+                        self.auth.set_secret(secret);
+                        true
+                    } else {
+                        warn!("Failed to save the host authentication secret, storage channel is full");
+                        false
+                    }
+                }
+            };
+            report.input_data[1] = saved as u8;
+            return;
+        }
+        if !self.auth.enabled() {
+            // Authentication is not enabled
+            report.input_data[0] = ViaCommand::Unhandled as u8;
+            return;
+        }
+        if command == ViaCommand::AuthChallenge {
+            let challenge = self.auth.new_challenge(Instant::now());
+            report.input_data[1..1 + CHALLENGE_LEN].copy_from_slice(&challenge);
+        } else {
+            let ok = self
+                .auth
+                .verify(&report.output_data[1..1 + CHALLENGE_LEN], Instant::now());
+            if ok {
+                info!("Host authenticated");
+            } else {
+                warn!("Host authentication failed");
+            }
+            report.input_data[1] = ok as u8;
+        }
+    }
+}
+
+/// Check whether the command is allowed by host authentication now
+#[cfg(feature = "host_auth")]
+fn authorize_command(
+    auth: &mut HostAuth,
+    command: ViaCommand,
+    sub_command: u8,
+    now: Instant,
+) -> bool {
+    !auth.enabled() || !is_write_command(command, sub_command) || auth.authorize(now)
+}
+
+/// Whether the command writes, `sub_command` is byte 1 of the request
+fn is_write_command(command: ViaCommand, sub_command: u8) -> bool {
+    command.is_write()
+        || (command == ViaCommand::Vial && is_vial_write_command(sub_command))
+        || command.is_config_import(sub_command)
+        || (command == ViaCommand::KeymapSync && keymap_sync::is_write_command(sub_command))
+        || (command == ViaCommand::Diagnostic && diagnostic::is_write_command(sub_command))
+        || (command == ViaCommand::AppContext && app_context::is_write_command(sub_command))
 }
 
 fn get_position_from_offset(
@@ -342,3 +685,108 @@ fn get_position_from_offset(
 fn count_zeros(data: &[u8]) -> usize {
     data.iter().filter(|&&x| x == 0).count()
 }
+
+fn set_rgb_light_value(value_id: u8, data: &[u8]) {
+    match ViaRgbLightValue::try_from_primitive(value_id) {
+        Ok(v) => match v {
+            ViaRgbLightValue::Brightness => update_active_zone_state(|zone| zone.color.v = data[0]),
+            ViaRgbLightValue::Effect => match Effect::from_u8(data[0]) {
+                Some(effect) => update_active_zone_state(|zone| zone.effect = effect),
+                None => warn!("Invalid RGB effect: {}", data[0]),
+            },
+            ViaRgbLightValue::EffectSpeed => update_active_zone_state(|zone| zone.speed = data[0]),
+            ViaRgbLightValue::Color => update_active_zone_state(|zone| {
+                zone.color.h = data[0];
+                zone.color.s = data[1];
+            }),
+            ViaRgbLightValue::FrameRate => update_rgb_state(|state| state.fps = data[0]),
+            ViaRgbLightValue::Zone => update_rgb_state(|state| state.active_zone = data[0]),
+            ViaRgbLightValue::Palette => set_palette(data[0]),
+        },
+        Err(e) => error!("Invalid rgblight value: {}", e.number),
+    }
+}
+
+fn get_rgb_light_value(value_id: u8, data: &mut [u8]) {
+    let state = rgb_state();
+    let zone = active_zone_state().unwrap_or_default();
+    match ViaRgbLightValue::try_from_primitive(value_id) {
+        Ok(v) => match v {
+            ViaRgbLightValue::Brightness => data[0] = zone.color.v,
+            ViaRgbLightValue::Effect => data[0] = zone.effect as u8,
+            ViaRgbLightValue::EffectSpeed => data[0] = zone.speed,
+            ViaRgbLightValue::Color => {
+                data[0] = zone.color.h;
+                data[1] = zone.color.s;
+            }
+            ViaRgbLightValue::FrameRate => data[0] = state.fps,
+            ViaRgbLightValue::Zone => data[0] = state.active_zone,
+            ViaRgbLightValue::Palette => data[0] = state.palette,
+        },
+        Err(e) => error!("Invalid rgblight value: {}", e.number),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::via::{app_context::AppContextCommand, diagnostic::DiagnosticCommand};
+
+    #[test]
+    fn test_is_write_command() {
+        assert!(is_write_command(
+            ViaCommand::Diagnostic,
+            DiagnosticCommand::Start as u8
+        ));
+        assert!(is_write_command(
+            ViaCommand::Diagnostic,
+            DiagnosticCommand::Stop as u8
+        ));
+        assert!(!is_write_command(
+            ViaCommand::Diagnostic,
+            DiagnosticCommand::Status as u8
+        ));
+        assert!(is_write_command(
+            ViaCommand::AppContext,
+            AppContextCommand::Focus as u8
+        ));
+        assert!(is_write_command(
+            ViaCommand::AppContext,
+            AppContextCommand::Clear as u8
+        ));
+        assert!(!is_write_command(
+            ViaCommand::AppContext,
+            AppContextCommand::Get as u8
+        ));
+        assert!(!is_write_command(ViaCommand::GetProtocolVersion, 0));
+    }
+
+    #[cfg(feature = "host_auth")]
+    #[test]
+    fn test_unauthenticated_writes_are_rejected() {
+        let now = Instant::from_secs(10);
+        let mut auth = HostAuth::new(Some(b"secret"), None);
+        let rejected = [
+            (ViaCommand::Diagnostic, DiagnosticCommand::Start as u8),
+            (ViaCommand::Diagnostic, DiagnosticCommand::Stop as u8),
+            (ViaCommand::AppContext, AppContextCommand::Focus as u8),
+            (ViaCommand::AppContext, AppContextCommand::Clear as u8),
+        ];
+        for (command, sub_command) in rejected {
+            assert!(!authorize_command(&mut auth, command, sub_command, now));
+        }
+        // Reads are still allowed
+        assert!(authorize_command(
+            &mut auth,
+            ViaCommand::Diagnostic,
+            DiagnosticCommand::Status as u8,
+            now
+        ));
+
+        // Everything is allowed without a secret
+        let mut auth = HostAuth::new(None, None);
+        for (command, sub_command) in rejected {
+            assert!(authorize_command(&mut auth, command, sub_command, now));
+        }
+    }
+}
@@ -28,11 +28,134 @@ pub(crate) enum ViaCommand {
     DynamicKeymapSetBuffer = 0x13,
     DynamicKeymapGetEncoder = 0x14,
     DynamicKeymapSetEncoder = 0x15,
+    /// RMK extension, get a challenge of host authentication
+    #[cfg(feature = "host_auth")]
+    AuthChallenge = 0xF0,
+    /// RMK extension, send the response of the challenge
+    #[cfg(feature = "host_auth")]
+    AuthResponse = 0xF1,
+    /// RMK extension, begin a config transaction, keymap and macro writes are staged until commit
+    TransactionBegin = 0xF2,
+    /// RMK extension, apply all staged writes of the config transaction
+    TransactionCommit = 0xF3,
+    /// RMK extension, discard all staged writes of the config transaction
+    TransactionAbort = 0xF4,
+    /// RMK extension, get firmware version, matrix size, enabled features or storage capacities
+    GetRmkInfo = 0xF5,
+    /// RMK extension, control the key tester and read its results
+    Diagnostic = 0xF6,
+    /// RMK extension, inject key events to the keyboard
+    #[cfg(feature = "key_injection")]
+    InjectKeys = 0xF7,
+    /// RMK extension, the host announces the focused application
+    AppContext = 0xF8,
+    /// RMK extension, write a new firmware image to the DFU slot
+    #[cfg(feature = "firmware_update")]
+    FirmwareUpdate = 0xF9,
+    /// RMK extension, export or import the whole config
+    #[cfg(feature = "config_backup")]
+    ConfigBackup = 0xFA,
+    /// RMK extension, write several keys or read changed keys by the keymap generation
+    KeymapSync = 0xFB,
+    /// RMK extension, save a new secret of host authentication to the storage
+    #[cfg(feature = "host_auth")]
+    AuthSetSecret = 0xFC,
     Vial = 0xFE,
     #[num_enum(default)]
     Unhandled = 0xFF,
 }
 
+impl ViaCommand {
+    /// Whether the command changes the keymap, macros, settings or the storage, or injects keys
+    pub(crate) fn is_write(self) -> bool {
+        matches!(
+            self,
+            ViaCommand::SetKeyboardValue
+                | ViaCommand::DynamicKeymapSetKeyCode
+                | ViaCommand::DynamicKeymapReset
+                | ViaCommand::CustomSetValue
+                | ViaCommand::CustomSave
+                | ViaCommand::EepromReset
+                | ViaCommand::BootloaderJump
+                | ViaCommand::DynamicKeymapMacroSetBuffer
+                | ViaCommand::DynamicKeymapMacroReset
+                | ViaCommand::DynamicKeymapSetBuffer
+                | ViaCommand::DynamicKeymapSetEncoder
+                | ViaCommand::TransactionCommit
+        ) || self.is_key_injection()
+            || self.is_firmware_update()
+            || self.is_auth_set_secret()
+    }
+
+    #[cfg(feature = "key_injection")]
+    fn is_key_injection(self) -> bool {
+        self == ViaCommand::InjectKeys
+    }
+
+    #[cfg(not(feature = "key_injection"))]
+    fn is_key_injection(self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "host_auth")]
+    fn is_auth_set_secret(self) -> bool {
+        self == ViaCommand::AuthSetSecret
+    }
+
+    #[cfg(not(feature = "host_auth"))]
+    fn is_auth_set_secret(self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "firmware_update")]
+    pub(crate) fn is_firmware_update(self) -> bool {
+        self == ViaCommand::FirmwareUpdate
+    }
+
+    #[cfg(not(feature = "firmware_update"))]
+    pub(crate) fn is_firmware_update(self) -> bool {
+        false
+    }
+
+    /// Whether the command imports a config blob, byte 1 of the request is given as `sub_command`
+    #[cfg(feature = "config_backup")]
+    pub(crate) fn is_config_import(self, sub_command: u8) -> bool {
+        self == ViaCommand::ConfigBackup && super::backup::is_import_command(sub_command)
+    }
+
+    #[cfg(not(feature = "config_backup"))]
+    pub(crate) fn is_config_import(self, _sub_command: u8) -> bool {
+        false
+    }
+}
+
+/// Channel of via custom values
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum ViaChannel {
+    Custom = 0x00,
+    Backlight = 0x01,
+    RgbLight = 0x02,
+    RgbMatrix = 0x03,
+    Audio = 0x04,
+}
+
+/// Values of via rgblight channel.
+///
+/// `FrameRate`, `Zone` and `Palette` are RMK extensions, which can be used in custom via menus.
+/// Other values are applied to the active LED zone
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum ViaRgbLightValue {
+    Brightness = 0x01,
+    Effect = 0x02,
+    EffectSpeed = 0x03,
+    Color = 0x04,
+    FrameRate = 0x80,
+    Zone = 0x81,
+    Palette = 0x82,
+}
+
 /// Information of a via keyboard.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, TryFromPrimitive)]
 #[repr(u8)]
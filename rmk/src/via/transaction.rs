@@ -0,0 +1,229 @@
+//! Staged configuration writes and write rate limiting
+//!
+//! A host can wrap keymap and macro writes in a transaction, so that a half-finished session
+//! never leaves the keyboard with a partially-updated layout:
+//!
+//! 1. The host sends `0xF2` to begin a transaction
+//! 2. Keymap writes(`DynamicKeymapSetKeyCode`, `DynamicKeymapSetBuffer`) and macro writes(`DynamicKeymapMacroSetBuffer`)
+//!    are staged instead of being applied
+//! 3. The host sends `0xF3` to apply all staged writes at once, or `0xF4` to discard them.
+//!    The keyboard replies 1 in byte 1 if the commit succeeds
+//!
+//! Staged writes are discarded if the transaction isn't committed in [`TRANSACTION_TIMEOUT`].
+//! Writes outside of a transaction are applied immediately, as VIA and Vial expect.
+//!
+//! Keys are staged into a copy of each written layer, so a transaction can rewrite the whole keymap.
+//! With `low_ram`, only [`MAX_STAGED_LAYERS`] layers can be written in a transaction.
+//!
+//! Write commands are rate limited by [`WriteRateLimiter`], the reply of a write is delayed when the host writes too fast.
+
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::{action::KeyAction, keyboard_macro::MACRO_SPACE_SIZE};
+
+/// Max number of layers staged in a transaction with `low_ram`
+#[cfg(feature = "low_ram")]
+pub(crate) use crate::ram_profile::MAX_STAGED_LAYERS;
+
+/// Staged writes are discarded if the transaction isn't committed in this duration
+pub(crate) const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max number of write commands in a burst
+const WRITE_BURST: u16 = 64;
+
+/// A token of write command is refilled every interval, aka 50 writes per second
+const WRITE_REFILL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StagedKey {
+    pub(crate) layer: u8,
+    pub(crate) row: u8,
+    pub(crate) col: u8,
+    pub(crate) action: KeyAction,
+}
+
+/// Copy of a layer with staged keys
+pub(crate) struct StagedLayer<const ROW: usize, const COL: usize> {
+    pub(crate) layer: u8,
+    pub(crate) actions: [[KeyAction; COL]; ROW],
+}
+
+/// Max number of layers staged in a transaction
+pub(crate) const fn max_staged_layers(num_layer: usize) -> usize {
+    #[cfg(feature = "low_ram")]
+    if num_layer > MAX_STAGED_LAYERS {
+        return MAX_STAGED_LAYERS;
+    }
+    num_layer
+}
+
+pub(crate) struct ConfigTransaction<const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    /// Start time of the active transaction, `None` if there's no transaction
+    started: Option<Instant>,
+    #[cfg(not(feature = "low_ram"))]
+    layers: Vec<StagedLayer<ROW, COL>, NUM_LAYER>,
+    #[cfg(feature = "low_ram")]
+    layers: Vec<StagedLayer<ROW, COL>, MAX_STAGED_LAYERS>,
+    macros: Option<[u8; MACRO_SPACE_SIZE]>,
+    /// Set if a write can't be staged, the transaction can't be committed then
+    overflow: bool,
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    ConfigTransaction<ROW, COL, NUM_LAYER>
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            started: None,
+            layers: Vec::new(),
+            macros: None,
+            overflow: false,
+        }
+    }
+
+    /// Begin a transaction, staged writes of the previous transaction are discarded
+    pub(crate) fn begin(&mut self) {
+        self.abort();
+        self.started = Some(Instant::now());
+    }
+
+    /// Discard all staged writes
+    pub(crate) fn abort(&mut self) {
+        self.started = None;
+        self.layers.clear();
+        self.macros = None;
+        self.overflow = false;
+    }
+
+    /// Whether there's an active transaction, a timed out transaction is discarded
+    pub(crate) fn is_active(&mut self) -> bool {
+        match self.started {
+            Some(t) if t.elapsed() < TRANSACTION_TIMEOUT => true,
+            Some(_) => {
+                warn!("Config transaction timed out, staged writes are discarded");
+                self.abort();
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Stage a key write, the layer is copied from `current` at the first write to it.
+    ///
+    /// Returns false if no more layers can be staged
+    pub(crate) fn stage_key(
+        &mut self,
+        key: StagedKey,
+        current: impl FnOnce() -> [[KeyAction; COL]; ROW],
+    ) -> bool {
+        let index = match self.layers.iter().position(|l| l.layer == key.layer) {
+            Some(index) => index,
+            None => {
+                let layer = StagedLayer {
+                    layer: key.layer,
+                    actions: current(),
+                };
+                if self.layers.push(layer).is_err() {
+                    self.overflow = true;
+                    return false;
+                }
+                self.layers.len() - 1
+            }
+        };
+        self.layers[index].actions[key.row as usize][key.col as usize] = key.action;
+        true
+    }
+
+    /// Staged macro buffer, it's initialized by `current` at the first macro write
+    pub(crate) fn macro_buffer(
+        &mut self,
+        current: [u8; MACRO_SPACE_SIZE],
+    ) -> &mut [u8; MACRO_SPACE_SIZE] {
+        self.macros.get_or_insert(current)
+    }
+
+    /// Whether the transaction can be committed, an invalid transaction is discarded
+    pub(crate) fn can_commit(&mut self) -> bool {
+        if !self.is_active() || self.overflow {
+            self.abort();
+            return false;
+        }
+        true
+    }
+
+    /// Staged layers and macros of the transaction to be committed
+    pub(crate) fn staged(
+        &mut self,
+    ) -> (
+        &mut [StagedLayer<ROW, COL>],
+        Option<&[u8; MACRO_SPACE_SIZE]>,
+    ) {
+        (&mut self.layers, self.macros.as_ref())
+    }
+}
+
+/// Token bucket which limits the rate of write commands
+pub(crate) struct WriteRateLimiter {
+    tokens: u16,
+    last_refill: Instant,
+}
+
+impl WriteRateLimiter {
+    pub(crate) fn new() -> Self {
+        Self {
+            tokens: WRITE_BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token for a write command, returns the time to wait for a token if the host writes too fast
+    fn try_take(&mut self, now: Instant) -> Option<Duration> {
+        let refill = (now - self.last_refill).as_ticks() / WRITE_REFILL_INTERVAL.as_ticks();
+        if refill > 0 {
+            self.tokens = (self.tokens as u64 + refill).min(WRITE_BURST as u64) as u16;
+            self.last_refill += WRITE_REFILL_INTERVAL * refill as u32;
+        }
+        if self.tokens == 0 {
+            return Some(self.last_refill + WRITE_REFILL_INTERVAL - now);
+        }
+        self.tokens -= 1;
+        None
+    }
+
+    /// Take a token for a write command, wait until a token is refilled if the host writes too fast.
+    ///
+    /// The host waits for the reply of each command, so it's slowed down without losing writes
+    pub(crate) async fn take(&mut self) {
+        while let Some(wait) = self.try_take(Instant::now()) {
+            debug!("Host writes too fast, wait for {}ms", wait.as_millis());
+            Timer::after(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_rate_limiter() {
+        let start = Instant::from_millis(1000);
+        let mut limiter = WriteRateLimiter {
+            tokens: WRITE_BURST,
+            last_refill: start,
+        };
+        for _ in 0..WRITE_BURST {
+            assert_eq!(limiter.try_take(start), None);
+        }
+        // The burst is used up, wait for the next token
+        assert_eq!(limiter.try_take(start), Some(WRITE_REFILL_INTERVAL));
+        let later = start + Duration::from_millis(5);
+        assert_eq!(
+            limiter.try_take(later),
+            Some(WRITE_REFILL_INTERVAL - Duration::from_millis(5))
+        );
+        assert_eq!(limiter.try_take(start + WRITE_REFILL_INTERVAL), None);
+        assert!(limiter.try_take(start + WRITE_REFILL_INTERVAL).is_some());
+    }
+}
@@ -1,9 +1,13 @@
 use core::cell::RefCell;
 
-use byteorder::{ByteOrder, LittleEndian};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use num_enum::FromPrimitive;
 
-use crate::{keymap::KeyMap, usb::descriptor::ViaReport};
+use crate::{
+    keymap::KeyMap,
+    usb::descriptor::ViaReport,
+    via::keycode_convert::{from_via_keycode, to_via_keycode},
+};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, FromPrimitive)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -27,6 +31,14 @@ enum VialCommand {
     Unhandled = 0xFF,
 }
 
+/// Whether the vial command changes the keymap or settings
+pub(crate) fn is_vial_write_command(command: u8) -> bool {
+    matches!(
+        VialCommand::from_primitive(command),
+        VialCommand::SetEncoder | VialCommand::QmkSettingsSet | VialCommand::QmkSettingsReset
+    )
+}
+
 const VIAL_PROTOCOL_VERSION: u32 = 6;
 const VIAL_EP_SIZE: usize = 32;
 
@@ -35,7 +47,7 @@ pub(crate) fn process_vial<'a, const ROW: usize, const COL: usize, const NUM_LAY
     report: &mut ViaReport,
     vial_keyboard_Id: &[u8],
     vial_keyboard_def: &[u8],
-    _keymap: &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+    keymap: &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
 ) {
     // report.output_data[0] == 0xFE -> vial commands
     let vial_command = VialCommand::from_primitive(report.output_data[1]);
@@ -96,17 +108,18 @@ pub(crate) fn process_vial<'a, const ROW: usize, const COL: usize, const NUM_LAY
                 index, layer
             );
             // Get encoder value
-            // if let Some(encoders) = &keymap.borrow().encoders {
-            //     if let Some(encoder_layer) = encoders.get(layer as usize) {
-            //         if let Some(encoder) = encoder_layer.get(index as usize) {
-            //             let clockwise = to_via_keycode(encoder.0);
-            //             BigEndian::write_u16(&mut report.input_data[0..2], clockwise);
-            //             let counter_clockwise = to_via_keycode(encoder.1);
-            //             BigEndian::write_u16(&mut report.input_data[2..4], counter_clockwise);
-            //             return;
-            //         }
-            //     }
-            // }
+            if let Some(encoders) = &keymap.borrow().encoders {
+                if let Some((clockwise, counter_clockwise)) =
+                    encoders.get(layer as usize, index as usize)
+                {
+                    BigEndian::write_u16(&mut report.input_data[0..2], to_via_keycode(clockwise));
+                    BigEndian::write_u16(
+                        &mut report.input_data[2..4],
+                        to_via_keycode(counter_clockwise),
+                    );
+                    return;
+                }
+            }
 
             // Clear returned value, aka `KeyAction::No`
             report.input_data.fill(0x0);
@@ -119,23 +132,12 @@ pub(crate) fn process_vial<'a, const ROW: usize, const COL: usize, const NUM_LAY
                 "Received Vial - SetEncoder, encoder idx: {} clockwise: {} at layer: {}",
                 index, clockwise, layer
             );
-            // if let Some(&mut mut encoders) = keymap.borrow_mut().encoders {
-            //     if let Some(&mut mut encoder_layer) = encoders.get_mut(layer as usize) {
-            //         if let Some(&mut mut encoder) = encoder_layer.get_mut(index as usize) {
-            //             if clockwise == 1 {
-            //                 let keycode = BigEndian::read_u16(&report.output_data[5..7]);
-            //                 let action = from_via_keycode(keycode);
-            //                 info!("Setting clockwise action: {}", action);
-            //                 encoder.0 = action
-            //             } else {
-            //                 let keycode = BigEndian::read_u16(&report.output_data[5..7]);
-            //                 let action = from_via_keycode(keycode);
-            //                 info!("Setting counter-clockwise action: {}", action);
-            //                 encoder.1 = action
-            //             }
-            //         }
-            //     }
-            // }
+            let keycode = BigEndian::read_u16(&report.output_data[5..7]);
+            let action = from_via_keycode(keycode);
+            info!("Setting encoder action: {:?}", action);
+            keymap
+                .borrow_mut()
+                .set_encoder_action(layer, index, clockwise == 1, action);
             debug!("Received Vial - SetEncoder, data: {}", report.output_data);
         }
         _ => (),